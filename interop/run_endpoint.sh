@@ -0,0 +1,74 @@
+#!/usr/bin/env bash
+# The entry point for the QUIC interop runner
+# (https://github.com/marten-seemann/quic-interop-runner).
+#
+# The runner sets these:
+#   ROLE        "client" or "server"
+#   TESTCASE    the test to run
+#   REQUESTS    space-separated URLs for the client to fetch
+#   SSLKEYLOGFILE, QLOGDIR
+# Files are served from /www, downloaded to /downloads, and the server
+# certificate and key are in /certs.
+#
+# Test cases that neqo can't run exit with 127, as the runner expects.
+# That includes keyupdate: this build has no key update.
+#
+# This build speaks QUIC draft-22 with the hq-22 ALPN, so it can only be
+# matched against other draft-22 endpoints, not the QUIC v1 interop matrix.
+
+set -e
+
+bin="${NEQO_BIN:-/neqo/target/release}"
+db=/tmp/neqo-db
+alpn=hq-22
+
+# Keep a qlog trace of each connection if the runner asks for them.
+qlog=()
+if [ -n "$QLOGDIR" ]; then
+    mkdir -p "$QLOGDIR"
+    qlog=(--qlog-dir "$QLOGDIR")
+fi
+
+unsupported() {
+    echo "Unsupported test case: $TESTCASE"
+    exit 127
+}
+
+# Load the certificate and key from /certs into an NSS database.
+make_db() {
+    rm -rf "$db"
+    mkdir -p "$db"
+    certutil -N -d "sql:$db" --empty-password
+    openssl pkcs12 -export -in /certs/cert.pem -inkey /certs/priv.key \
+        -name key -passout pass: -out "$db/cert.p12"
+    pk12util -d "sql:$db" -i "$db/cert.p12" -W ''
+}
+
+case "$ROLE" in
+client)
+    args=(-o -a "$alpn" --omit-read-data --download /downloads "${qlog[@]}")
+    case "$TESTCASE" in
+    handshake|transfer|multiplexing|retry) ;;
+    resumption) args+=(--resume) ;;
+    zerortt) args+=(--zero-rtt) ;;
+    *) unsupported ;;
+    esac
+    # shellcheck disable=SC2086
+    exec "$bin"/neqo-client "${args[@]}" $REQUESTS
+    ;;
+server)
+    args=(-a "$alpn" -d "$db" -k key --www /www "${qlog[@]}")
+    case "$TESTCASE" in
+    handshake|transfer|multiplexing|resumption) ;;
+    zerortt) args+=(--zero-rtt) ;;
+    retry) args+=(--retry) ;;
+    *) unsupported ;;
+    esac
+    make_db
+    exec "$bin"/neqo-server "${args[@]}" 443
+    ;;
+*)
+    echo "Unknown role: $ROLE"
+    exit 1
+    ;;
+esac
@@ -6,16 +6,19 @@
 
 #![deny(warnings)]
 use neqo_common::{matches, Datagram};
-use neqo_crypto::{init, AuthenticationStatus};
+use neqo_crypto::{init, AuthenticationStatus, KeyLogFile};
 use neqo_http3::{Header, Http3Connection, Http3Event, Http3State, Output};
-use neqo_transport::{Connection, FixedConnectionIdManager};
+use neqo_transport::{Connection, FixedConnectionIdManager, Qlog, Role};
 
 use std::cell::RefCell;
-use std::collections::HashSet;
-use std::io::{self, ErrorKind};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 use structopt::StructOpt;
 use url::Url;
@@ -26,13 +29,17 @@ use url::Url;
     about = "A basic QUIC HTTP/0.9 and HTTP3 client."
 )]
 pub struct Args {
-    #[structopt(short = "a", long, default_value = "h3-22")]
-    /// ALPN labels to negotiate.
+    #[structopt(short = "a", long)]
+    /// ALPN labels to negotiate.  The default is "h3-22", or "http/0.9"
+    /// with --use-old-http.
     ///
-    /// This client still only does HTTP3 no matter what the ALPN says.
+    /// This client still only does HTTP3 or HTTP/0.9 no matter what the
+    /// ALPN says.
     alpn: Vec<String>,
 
-    url: Url,
+    #[structopt(raw(required = "true"))]
+    /// The resources to request.  With HTTP3, all requests are sent at once.
+    urls: Vec<Url>,
 
     #[structopt(short = "m", default_value = "GET")]
     method: String,
@@ -40,6 +47,14 @@ pub struct Args {
     #[structopt(short = "h", long, number_of_values = 2)]
     header: Vec<String>,
 
+    #[structopt(short = "d", long)]
+    /// A request body, or @file to send the contents of a file.  HTTP3 only.
+    data: Option<String>,
+
+    #[structopt(short = "r", long)]
+    /// Request a byte range, such as "0-99", with a range header.  HTTP3 only.
+    range: Option<String>,
+
     #[structopt(name = "max-table-size", short = "t", long, default_value = "128")]
     max_table_size: u32,
 
@@ -53,9 +68,49 @@ pub struct Args {
     #[structopt(name = "omit-read-data", long)]
     /// Do not print received data
     omit_read_data: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Save each response in this directory, in a file named for the last
+    /// part of its path.
+    download: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Request the first resource, then resume the session in a new
+    /// connection to request the rest.  HTTP/0.9 only.
+    resume: bool,
+
+    #[structopt(name = "zero-rtt", long = "zero-rtt")]
+    /// Like --resume, but send the requests on the new connection in 0-RTT.
+    zero_rtt: bool,
+
+    #[structopt(name = "qlog-dir", long, parse(from_os_str))]
+    /// Write a qlog trace of each connection to this directory.
+    qlog_dir: Option<PathBuf>,
 }
 
 impl Args {
+    fn url(&self) -> &Url {
+        &self.urls[0]
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match &self.data {
+            None => Vec::new(),
+            Some(d) if d.starts_with('@') => fs::read(&d[1..]).expect("Unable to read body file"),
+            Some(d) => d.clone().into_bytes(),
+        }
+    }
+
+    fn alpn(&self) -> Vec<String> {
+        if !self.alpn.is_empty() {
+            self.alpn.clone()
+        } else if self.use_old_http {
+            vec![String::from("http/0.9")]
+        } else {
+            vec![String::from("h3-22")]
+        }
+    }
+
     fn remote_addr(&self) -> Result<SocketAddr, io::Error> {
         Ok(self.to_socket_addrs()?.next().expect("No remote addresses"))
     }
@@ -73,18 +128,54 @@ impl ToSocketAddrs for Args {
     fn to_socket_addrs(&self) -> ::std::io::Result<Self::Iter> {
         // This is idiotic.  There is no path from hostname: String to IpAddr.
         // And no means of controlling name resolution either.
-        if self.url.port_or_known_default().is_none() {
+        if self.url().port_or_known_default().is_none() {
             return Err(io::Error::new(ErrorKind::InvalidInput, "invalid port"));
         }
         std::fmt::format(format_args!(
             "{}:{}",
-            self.url.host_str().unwrap_or("localhost"),
-            self.url.port_or_known_default().unwrap()
+            self.url().host_str().unwrap_or("localhost"),
+            self.url().port_or_known_default().unwrap()
         ))
         .to_socket_addrs()
     }
 }
 
+/// Create the file for saving the response to `url`, if `--download` is set.
+/// The file is named for the last part of the path.
+fn download_file(args: &Args, url: &Url) -> Option<File> {
+    args.download.as_ref().map(|dir| {
+        let name = url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .filter(|s| !s.is_empty())
+            .unwrap_or("index.html");
+        File::create(dir.join(name)).expect("Unable to create download file")
+    })
+}
+
+/// Log secrets to the file named by SSLKEYLOGFILE, if that is set.
+fn log_keys(c: &mut Connection) {
+    if let Some(log) = KeyLogFile::from_env() {
+        c.set_secret_listener(Rc::new(log));
+    }
+}
+
+/// Start a qlog trace for `c` if --qlog-dir is set.  With --resume, there is
+/// more than one connection, so the files are numbered.
+fn start_qlog(args: &Args, c: &mut Connection) {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    if let Some(dir) = &args.qlog_dir {
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("client-{}.sqlog", n));
+        let qlog = File::create(&path)
+            .and_then(|f| Qlog::new(Box::new(f), "neqo-client", Role::Client, Instant::now()));
+        match qlog {
+            Ok(qlog) => c.set_qlog(qlog),
+            Err(e) => eprintln!("Unable to write qlog to {}: {}", path.display(), e),
+        }
+    }
+}
+
 trait Handler {
     fn handle(&mut self, args: &Args, client: &mut Http3Connection) -> bool;
 }
@@ -171,48 +262,141 @@ impl Handler for PreConnectHandler {
     }
 }
 
+/// The state of one request.
+struct Request {
+    url: Url,
+    /// Request body that hasn't been sent yet.
+    body: Vec<u8>,
+    /// Where to save the response, if anywhere.
+    out: Option<File>,
+    start: Instant,
+    first_byte: Option<Instant>,
+    received: usize,
+}
+
+impl Request {
+    /// Print how long the request took.
+    fn report(&self, stream_id: u64) {
+        let elapsed = self.start.elapsed();
+        let ttfb = self.first_byte.map(|t| t - self.start);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let rate = if secs > 0.0 {
+            self.received as f64 / secs / 1000.0
+        } else {
+            0.0
+        };
+        println!(
+            "DONE[{}] {}: {} bytes in {:?}, TTFB {:?}, {:.1} kB/s",
+            stream_id, self.url, self.received, elapsed, ttfb, rate
+        );
+    }
+}
+
 #[derive(Default)]
 struct PostConnectHandler {
-    streams: HashSet<u64>,
+    requests: HashMap<u64, Request>,
+}
+
+impl PostConnectHandler {
+    /// Send as much of the request body as flow control allows, and end the
+    /// request once it is all sent.
+    fn send_body(&mut self, client: &mut Http3Connection, stream_id: u64) {
+        let req = self.requests.get_mut(&stream_id).unwrap();
+        if req.body.is_empty() {
+            return;
+        }
+        let sent = client
+            .send_request_body(stream_id, &req.body)
+            .expect("Send should succeed");
+        req.body.drain(..sent);
+        if req.body.is_empty() {
+            let _ = client.stream_close_send(stream_id);
+        }
+    }
+
+    /// Read the response body.  Returns true when the response is complete.
+    fn read(&mut self, args: &Args, client: &mut Http3Connection, stream_id: u64) -> bool {
+        let mut data = vec![0; 4000];
+        let req = self.requests.get_mut(&stream_id).unwrap();
+        loop {
+            let (sz, fin) = client
+                .read_response_data(Instant::now(), stream_id, &mut data)
+                .expect("Read should succeed");
+            req.received += sz;
+            if let Some(f) = req.out.as_mut() {
+                f.write_all(&data[..sz]).expect("Write should succeed");
+            }
+            if args.omit_read_data || req.out.is_some() {
+                println!("READ[{}]: {} bytes", stream_id, sz);
+            } else {
+                println!(
+                    "READ[{}]: {}",
+                    stream_id,
+                    String::from_utf8_lossy(&data[..sz])
+                )
+            }
+            if fin {
+                println!("<FIN[{}]>", stream_id);
+                return true;
+            }
+            if sz == 0 {
+                return false;
+            }
+        }
+    }
+
+    fn done(&mut self, client: &mut Http3Connection, stream_id: u64) -> bool {
+        if let Some(req) = self.requests.remove(&stream_id) {
+            req.report(stream_id);
+        }
+        if self.requests.is_empty() {
+            client.close(Instant::now(), 0, "kthxbye!");
+            return false;
+        }
+        true
+    }
 }
 
 // This is a bit fancier than actually needed.
 impl Handler for PostConnectHandler {
     fn handle(&mut self, args: &Args, client: &mut Http3Connection) -> bool {
-        let mut data = vec![0; 4000];
         client.process_http3(Instant::now());
         for event in client.events() {
-            match event {
-                Http3Event::HeaderReady { stream_id } => {
-                    if !self.streams.contains(&stream_id) {
-                        println!("Data on unexpected stream: {}", stream_id);
-                        return false;
-                    }
+            let stream_id = match event {
+                Http3Event::HeaderReady { stream_id, .. }
+                | Http3Event::DataReadable { stream_id }
+                | Http3Event::DataWritable { stream_id }
+                | Http3Event::Reset { stream_id, .. } => stream_id,
+                Http3Event::Trailers {
+                    stream_id,
+                    trailers,
+                } => {
+                    println!("READ TRAILERS[{}]: {:?}", stream_id, trailers);
+                    continue;
+                }
+                _ => continue,
+            };
+            if !self.requests.contains_key(&stream_id) {
+                println!("Data on unexpected stream: {}", stream_id);
+                return false;
+            }
 
+            match event {
+                Http3Event::HeaderReady { .. } => {
+                    let req = self.requests.get_mut(&stream_id).unwrap();
+                    req.first_byte = Some(Instant::now());
                     let headers = client.read_response_headers(stream_id);
                     println!("READ HEADERS[{}]: {:?}", stream_id, headers);
                 }
-                Http3Event::DataReadable { stream_id } => {
-                    if !self.streams.contains(&stream_id) {
-                        println!("Data on unexpected stream: {}", stream_id);
+                Http3Event::DataReadable { .. } => {
+                    if self.read(args, client, stream_id) && !self.done(client, stream_id) {
                         return false;
                     }
-
-                    let (sz, fin) = client
-                        .read_response_data(Instant::now(), stream_id, &mut data)
-                        .expect("Read should succeed");
-                    if args.omit_read_data {
-                        println!("READ[{}]: {} bytes", stream_id, sz);
-                    } else {
-                        println!(
-                            "READ[{}]: {}",
-                            stream_id,
-                            String::from_utf8(data.clone()).unwrap()
-                        )
-                    }
-                    if fin {
-                        println!("<FIN[{}]>", stream_id);
-                        client.close(Instant::now(), 0, "kthxbye!");
+                }
+                Http3Event::DataWritable { .. } => self.send_body(client, stream_id),
+                Http3Event::Reset { error, .. } => {
+                    println!("RESET[{}]: {}", stream_id, error);
+                    if !self.done(client, stream_id) {
                         return false;
                     }
                 }
@@ -240,15 +424,18 @@ fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
 }
 
 fn client(args: Args, socket: UdpSocket, local_addr: SocketAddr, remote_addr: SocketAddr) {
+    let mut transport = Connection::new_client(
+        args.url().host_str().unwrap(),
+        &args.alpn(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        local_addr,
+        remote_addr,
+    )
+    .expect("must succeed");
+    log_keys(&mut transport);
+    start_qlog(&args, &mut transport);
     let mut client = Http3Connection::new(
-        Connection::new_client(
-            args.url.host_str().unwrap(),
-            &args.alpn,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
-            local_addr,
-            remote_addr,
-        )
-        .expect("must succeed"),
+        transport,
         args.max_table_size,
         args.max_blocked_streams,
         None,
@@ -264,23 +451,46 @@ fn client(args: Args, socket: UdpSocket, local_addr: SocketAddr, remote_addr: So
         &args,
     );
 
-    let client_stream_id = client.fetch(
-        &args.method,
-        &args.url.scheme(),
-        &args.url.host_str().unwrap(),
-        &args.url.path(),
-        &to_headers(&args.header),
-    );
-
-    if let Err(err) = client_stream_id {
-        eprintln!("Could not connect: {:?}", err);
-        return;
+    let mut headers = to_headers(&args.header);
+    if let Some(range) = &args.range {
+        headers.push((String::from("range"), format!("bytes={}", range)));
     }
-    let client_stream_id = client_stream_id.unwrap();
-    let _ = client.stream_close_send(client_stream_id);
+    let body = args.body();
 
+    // Send all the requests at once.
     let mut h2 = PostConnectHandler::default();
-    h2.streams.insert(client_stream_id);
+    for url in &args.urls {
+        let client_stream_id = client.fetch(
+            &args.method,
+            &url.scheme(),
+            &url.host_str().unwrap(),
+            &url.path(),
+            &headers,
+        );
+        let client_stream_id = match client_stream_id {
+            Ok(id) => id,
+            Err(err) => {
+                eprintln!("Could not fetch {}: {:?}", url, err);
+                return;
+            }
+        };
+        h2.requests.insert(
+            client_stream_id,
+            Request {
+                url: url.clone(),
+                body: body.clone(),
+                out: download_file(&args, url),
+                start: Instant::now(),
+                first_byte: None,
+                received: 0,
+            },
+        );
+        // Without a body, this ends the request.  Otherwise, the body is
+        // sent once the headers are out.
+        if body.is_empty() {
+            let _ = client.stream_close_send(client_stream_id);
+        }
+    }
     process_loop(
         &local_addr,
         &remote_addr,
@@ -324,18 +534,22 @@ fn main() {
 
 mod old {
     use std::cell::RefCell;
-    use std::collections::HashSet;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
     use std::net::{SocketAddr, UdpSocket};
     use std::process::exit;
     use std::rc::Rc;
     use std::time::Instant;
 
-    use neqo_common::Datagram;
+    use neqo_common::{matches, Datagram};
+    use neqo_crypto::AuthenticationStatus;
     use neqo_transport::{
         Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType,
     };
+    use url::Url;
 
-    use super::{emit_datagram, Args};
+    use super::{download_file, emit_datagram, log_keys, start_qlog, Args};
 
     trait HandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> bool;
@@ -344,43 +558,72 @@ mod old {
     struct PreConnectHandlerOld {}
     impl HandlerOld for PreConnectHandlerOld {
         fn handle(&mut self, _args: &Args, client: &mut Connection) -> bool {
+            let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+            if client.events().any(authentication_needed) {
+                client.authenticated(AuthenticationStatus::Ok, Instant::now());
+            }
             State::Connected != *dbg!(client.state())
         }
     }
 
     #[derive(Default)]
     struct PostConnectHandlerOld {
-        streams: HashSet<u64>,
+        /// Streams that are waiting for a response, and where to save it.
+        streams: HashMap<u64, Option<File>>,
+    }
+
+    impl PostConnectHandlerOld {
+        /// Read from a stream, returning true when the response is complete.
+        fn read(&mut self, args: &Args, client: &mut Connection, stream_id: u64) -> bool {
+            let mut data = vec![0; 4000];
+            let out = self.streams.get_mut(&stream_id).unwrap();
+            loop {
+                let (sz, fin) = client
+                    .stream_recv(stream_id, &mut data)
+                    .expect("Read should succeed");
+                if let Some(f) = out.as_mut() {
+                    f.write_all(&data[..sz]).expect("Write should succeed");
+                }
+                if args.omit_read_data || out.is_some() {
+                    println!("READ[{}]: {} bytes", stream_id, sz);
+                } else {
+                    println!(
+                        "READ[{}]: {}",
+                        stream_id,
+                        String::from_utf8_lossy(&data[..sz])
+                    )
+                }
+                if fin {
+                    println!("<FIN[{}]>", stream_id);
+                    return true;
+                }
+                if sz == 0 {
+                    return false;
+                }
+            }
+        }
     }
 
     // This is a bit fancier than actually needed.
     impl HandlerOld for PostConnectHandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> bool {
-            let mut data = vec![0; 4000];
             for event in client.events() {
                 match event {
+                    // With 0-RTT, the handshake finishes while waiting here.
+                    ConnectionEvent::AuthenticationNeeded => {
+                        client.authenticated(AuthenticationStatus::Ok, Instant::now());
+                    }
                     ConnectionEvent::RecvStreamReadable { stream_id } => {
-                        if !self.streams.contains(&stream_id) {
+                        if !self.streams.contains_key(&stream_id) {
                             println!("Data on unexpected stream: {}", stream_id);
                             return false;
                         }
-
-                        let (sz, fin) = client
-                            .stream_recv(stream_id, &mut data)
-                            .expect("Read should succeed");
-                        if args.omit_read_data {
-                            println!("READ[{}]: {} bytes", stream_id, sz);
-                        } else {
-                            println!(
-                                "READ[{}]: {}",
-                                stream_id,
-                                String::from_utf8(data.clone()).unwrap()
-                            )
-                        }
-                        if fin {
-                            println!("<FIN[{}]>", stream_id);
-                            client.close(Instant::now(), 0, "kthxbye!");
-                            return false;
+                        if self.read(args, client, stream_id) {
+                            self.streams.remove(&stream_id);
+                            if self.streams.is_empty() {
+                                client.close(Instant::now(), 0, "kthxbye!");
+                                return false;
+                            }
                         }
                     }
                     ConnectionEvent::SendStreamWritable { stream_id } => {
@@ -406,7 +649,7 @@ mod old {
     ) -> State {
         let buf = &mut [0u8; 2048];
         loop {
-            if let State::Closed(..) = client.state() {
+            if let State::Closed { .. } = client.state() {
                 return client.state().clone();
             }
 
@@ -437,43 +680,66 @@ mod old {
         }
     }
 
-    pub fn old_client(
-        args: Args,
-        socket: UdpSocket,
+    /// Open a stream for each of `urls` and send the request.
+    fn request(args: &Args, client: &mut Connection, h: &mut PostConnectHandlerOld, urls: &[Url]) {
+        for url in urls {
+            let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
+            let req = format!("GET {}\r\n", url.path());
+            client
+                .stream_send(client_stream_id, req.as_bytes())
+                .unwrap();
+            client.stream_close_send(client_stream_id).unwrap();
+            h.streams.insert(client_stream_id, download_file(args, url));
+        }
+    }
+
+    /// Request `urls` on a new connection, resuming if there is a `token`.
+    /// Returns a token for resuming this connection, if the server sent one.
+    fn old_connection(
+        args: &Args,
+        socket: &UdpSocket,
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
-    ) {
-        dbg!(args.url.host_str().unwrap());
-        dbg!(&args.alpn);
-        dbg!(local_addr);
-        dbg!(remote_addr);
-
+        urls: &[Url],
+        token: Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
         let mut client = Connection::new_client(
-            args.url.host_str().unwrap(),
-            &["http/0.9"],
+            args.url().host_str().unwrap(),
+            &args.alpn(),
             Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
             local_addr,
             remote_addr,
         )
         .expect("must succeed");
-        // Temporary here to help out the type inference engine
-        let mut h = PreConnectHandlerOld {};
-        process_loop_old(
-            &local_addr,
-            &remote_addr,
-            &socket,
-            &mut client,
-            &mut h,
-            &args,
-        );
+        log_keys(&mut client);
+        start_qlog(args, &mut client);
+        let zero_rtt = if let Some(token) = token {
+            client
+                .set_resumption_token(Instant::now(), &token)
+                .expect("Unable to resume");
+            args.zero_rtt
+        } else {
+            false
+        };
 
-        let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
-        let req: String = "GET /10\r\n".to_string();
-        client
-            .stream_send(client_stream_id, req.as_bytes())
-            .unwrap();
         let mut h2 = PostConnectHandlerOld::default();
-        h2.streams.insert(client_stream_id);
+        if zero_rtt {
+            // Send the ClientHello, then the requests go in 0-RTT.
+            emit_datagram(&socket, client.process_output(Instant::now()).dgram());
+            request(args, &mut client, &mut h2, urls);
+        } else {
+            // Temporary here to help out the type inference engine
+            let mut h = PreConnectHandlerOld {};
+            process_loop_old(
+                &local_addr,
+                &remote_addr,
+                &socket,
+                &mut client,
+                &mut h,
+                &args,
+            );
+            request(args, &mut client, &mut h2, urls);
+        }
         process_loop_old(
             &local_addr,
             &remote_addr,
@@ -482,5 +748,34 @@ mod old {
             &mut h2,
             &args,
         );
+        client.resumption_token()
+    }
+
+    pub fn old_client(
+        args: Args,
+        socket: UdpSocket,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) {
+        dbg!(args.url().host_str().unwrap());
+        dbg!(&args.alpn());
+        dbg!(local_addr);
+        dbg!(remote_addr);
+
+        let split = if args.resume || args.zero_rtt {
+            1
+        } else {
+            args.urls.len()
+        };
+        let (first, rest) = args.urls.split_at(split);
+        let token = old_connection(&args, &socket, local_addr, remote_addr, first, None);
+        if rest.is_empty() {
+            return;
+        }
+        if token.is_none() {
+            eprintln!("No resumption token from the first connection");
+            exit(1);
+        }
+        old_connection(&args, &socket, local_addr, remote_addr, rest, token);
     }
 }
@@ -0,0 +1,190 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A pool of buffers, so that each datagram doesn't need a new allocation.
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+use crate::Datagram;
+
+struct PoolInner {
+    /// The capacity of each buffer.
+    size: usize,
+    /// The most buffers that the pool holds on to.
+    limit: usize,
+    free: Vec<Vec<u8>>,
+}
+
+/// A pool of buffers of a fixed capacity.  This can be cloned; all clones
+/// share the same buffers.
+///
+/// Buffers are taken from the pool to receive datagrams into, or to build
+/// datagrams in, and given back once the datagram has been sent or
+/// processed.  Once enough buffers are in circulation, nothing is allocated.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Rc<RefCell<PoolInner>>,
+}
+
+impl BufferPool {
+    /// Make a pool of buffers that can each hold `size` bytes.  The pool
+    /// keeps at most `limit` buffers that aren't in use.
+    pub fn new(size: usize, limit: usize) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(PoolInner {
+                size,
+                limit,
+                free: Vec::with_capacity(limit),
+            })),
+        }
+    }
+
+    /// The capacity of each buffer.
+    pub fn buffer_size(&self) -> usize {
+        self.inner.borrow().size
+    }
+
+    /// The number of buffers that are ready to be taken.
+    pub fn available(&self) -> usize {
+        self.inner.borrow().free.len()
+    }
+
+    /// Take an empty buffer.  Give it back with `recycle()` when done.
+    pub fn take(&self) -> Vec<u8> {
+        let mut inner = self.inner.borrow_mut();
+        let size = inner.size;
+        inner.free.pop().unwrap_or_else(|| Vec::with_capacity(size))
+    }
+
+    /// Take a buffer that goes back to the pool when it is dropped.
+    pub fn get(&self) -> PooledBuffer {
+        PooledBuffer {
+            buf: Some(self.take()),
+            pool: self.clone(),
+        }
+    }
+
+    /// Give a buffer back.  Buffers that aren't the size of the pool's
+    /// buffers, because they didn't come from the pool or because they grew,
+    /// are freed, as are buffers that don't fit because the pool is full.
+    /// That way, every buffer in the pool has the same capacity.
+    pub fn recycle(&self, mut buf: Vec<u8>) {
+        let mut inner = self.inner.borrow_mut();
+        if buf.capacity() == inner.size && inner.free.len() < inner.limit {
+            buf.clear();
+            inner.free.push(buf);
+        }
+    }
+
+    /// Give the buffer of a datagram back.
+    pub fn recycle_datagram(&self, d: Datagram) {
+        self.recycle(d.into_data());
+    }
+}
+
+impl Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        write!(
+            f,
+            "BufferPool {}x{} of {}",
+            inner.free.len(),
+            inner.size,
+            inner.limit
+        )
+    }
+}
+
+/// A buffer from a `BufferPool` that is given back when dropped.
+pub struct PooledBuffer {
+    buf: Option<Vec<u8>>,
+    pool: BufferPool,
+}
+
+impl PooledBuffer {
+    /// Keep the buffer, such as to make a `Datagram` from it.  It can be
+    /// given back to the pool later with `BufferPool::recycle()`.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.buf.take().unwrap()
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.recycle(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(100, 2);
+        let mut b = pool.take();
+        assert!(b.capacity() >= 100);
+        b.extend_from_slice(&[1, 2, 3]);
+        let ptr = b.as_ptr();
+        pool.recycle(b);
+        assert_eq!(pool.available(), 1);
+
+        let b = pool.take();
+        assert!(b.is_empty());
+        assert_eq!(b.as_ptr(), ptr);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn limit() {
+        let pool = BufferPool::new(100, 1);
+        let (a, b) = (pool.take(), pool.take());
+        pool.recycle(a);
+        pool.recycle(b);
+        assert_eq!(pool.available(), 1);
+        // Small buffers aren't kept.
+        let pool = BufferPool::new(100, 1);
+        pool.recycle(Vec::with_capacity(10));
+        assert_eq!(pool.available(), 0);
+        // Neither are buffers that grew.
+        let mut b = pool.take();
+        b.resize(101, 0);
+        pool.recycle(b);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn pooled() {
+        let pool = BufferPool::new(100, 2);
+        {
+            let mut b = pool.get();
+            b.push(1);
+        }
+        assert_eq!(pool.available(), 1);
+        let v = pool.get().into_vec();
+        assert_eq!(pool.available(), 0);
+        pool.recycle(v);
+        assert_eq!(pool.available(), 1);
+    }
+}
@@ -27,6 +27,27 @@ impl<'a> Decoder<'a> {
         self.buf.len() - self.offset
     }
 
+    /// The number of bytes that have been read.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Run `f`, and if it fails, go back to where it started.  Use this to
+    /// decode something that might be cut short, such as a frame that
+    /// continues in the next packet, so that the unread bytes can be kept
+    /// and decoded again once the rest arrives.
+    pub fn try_decode<T, F>(&mut self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Self) -> Option<T>,
+    {
+        let start = self.offset;
+        let res = f(self);
+        if res.is_none() {
+            self.offset = start;
+        }
+        res
+    }
+
     /// Skip n bytes.  Panics if `n` is too large.
     pub fn skip(&mut self, n: usize) {
         assert!(self.remaining() >= n);
@@ -144,6 +165,22 @@ impl<'a> Decoder<'a> {
         let len = self.decode_varint();
         self.decode_checked(len)
     }
+
+    /// Decodes a QUIC varint-length-prefixed buffer that is no longer than
+    /// `max`.  A longer buffer isn't read at all, so the limit applies
+    /// before any of the contents need to arrive.
+    pub fn decode_vvec_max(&mut self, max: usize) -> Option<&[u8]> {
+        let start = self.offset;
+        match self.decode_varint() {
+            Some(len) if len <= u64::try_from(max).unwrap_or(u64::max_value()) => {
+                self.decode_checked(Some(len))
+            }
+            _ => {
+                self.offset = start;
+                None
+            }
+        }
+    }
 }
 
 // Implement `Deref` for `Decoder` so that values can be examined without moving the cursor.
@@ -202,6 +239,18 @@ impl Encoder {
         }
     }
 
+    /// Remove everything that was encoded, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Make room for at least `additional` more bytes, so that encoding
+    /// something of a known size doesn't reallocate along the way.
+    pub fn reserve(&mut self, additional: usize) -> &mut Self {
+        self.buf.reserve(additional);
+        self
+    }
+
     /// Create a view of the current contents of the buffer.
     /// Note: for a view of a slice, use `Decoder::new(&enc[s..e])
     pub fn as_decoder(&self) -> Decoder {
@@ -260,6 +309,52 @@ impl Encoder {
         self
     }
 
+    /// Encode a varint in exactly `n` bytes, which can be 1, 2, 4, or 8.
+    /// QUIC allows a varint to use more bytes than it needs.  Panics if `v`
+    /// doesn't fit.
+    pub fn encode_varint_fixed<T: Into<u64>>(&mut self, n: usize, v: T) -> &mut Self {
+        let v = v.into();
+        let bits = match n {
+            1 => 0,
+            2 => 1 << 14,
+            4 => 2 << 30,
+            8 => 3 << 62,
+            _ => panic!("Varint length must be 1, 2, 4, or 8"),
+        };
+        assert!(Self::varint_len(v) <= n, "Varint value too large");
+        self.encode_uint(n, v | bits)
+    }
+
+    /// Leave `n` bytes for a varint that isn't known yet, such as the length
+    /// of something that follows.  This returns the offset to pass to
+    /// `patch_varint()` once the value is known.
+    pub fn encode_varint_placeholder(&mut self, n: usize) -> usize {
+        let offset = self.buf.len();
+        self.encode_varint_fixed(n, 0_u64);
+        offset
+    }
+
+    /// Write `v` into the `n` bytes at `offset`, which came from
+    /// `encode_varint_placeholder()`.  This doesn't move anything else in the
+    /// buffer.  Panics if `v` doesn't fit.
+    pub fn patch_varint<T: Into<u64>>(&mut self, offset: usize, n: usize, v: T) {
+        let mut tmp = Self::with_capacity(n);
+        tmp.encode_varint_fixed(n, v);
+        self.buf[offset..offset + n].copy_from_slice(&tmp);
+    }
+
+    /// Encode a vector with a varint length of `n` bytes using a closure.
+    /// Unlike `encode_vvec_with()`, nothing is moved once the contents are
+    /// encoded, which suits contents that can be long.  Panics if the
+    /// contents are too long for `n` bytes.
+    pub fn encode_vvec_with_len<F: FnOnce(&mut Self)>(&mut self, n: usize, f: F) -> &mut Self {
+        let offset = self.encode_varint_placeholder(n);
+        f(self);
+        let len = self.buf.len() - offset - n;
+        self.patch_varint(offset, n, u64::try_from(len).unwrap());
+        self
+    }
+
     /// Encode a vector in TLS style.
     pub fn encode_vec(&mut self, n: usize, v: &[u8]) -> &mut Self {
         self.encode_uint(n, u64::try_from(v.len()).unwrap())
@@ -345,6 +440,13 @@ impl From<&[u8]> for Encoder {
     }
 }
 
+/// Encode after what `buf` already holds, reusing its allocation.
+impl From<Vec<u8>> for Encoder {
+    fn from(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+}
+
 impl Into<Vec<u8>> for Encoder {
     fn into(self) -> Vec<u8> {
         self.buf
@@ -682,10 +784,82 @@ mod tests {
         assert_eq!(enc, Encoder::from_hex("ff010234"));
     }
 
+    #[test]
+    fn decode_vvec_max() {
+        let enc = Encoder::from_hex("03010234");
+        let mut dec = enc.as_decoder();
+        assert!(dec.decode_vvec_max(2).is_none());
+        assert_eq!(dec.offset(), 0);
+        assert_eq!(dec.decode_vvec_max(3).unwrap(), &[0x01, 0x02, 0x34]);
+
+        // The limit is checked before the contents are.
+        let enc = Encoder::from_hex("4100");
+        assert!(enc.as_decoder().decode_vvec_max(64).is_none());
+    }
+
+    #[test]
+    fn try_decode() {
+        let enc = Encoder::from_hex("0102");
+        let mut dec = enc.as_decoder();
+        let res = dec.try_decode(|d| {
+            d.decode_byte()?;
+            d.decode_uint(2)
+        });
+        assert!(res.is_none());
+        assert_eq!(dec.offset(), 0);
+        assert_eq!(dec.try_decode(|d| d.decode_uint(2)), Some(0x0102));
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn encode_varint_fixed() {
+        let mut enc = Encoder::default();
+        enc.encode_varint_fixed(4, 37_u64)
+            .encode_varint_fixed(1, 1_u64);
+        assert_eq!(enc, Encoder::from_hex("8000002501"));
+        assert_eq!(enc.as_decoder().decode_varint(), Some(37));
+    }
+
+    #[test]
+    #[should_panic]
+    fn encode_varint_fixed_too_large() {
+        Encoder::default().encode_varint_fixed(1, 64_u64);
+    }
+
+    #[test]
+    fn patch_varint() {
+        let mut enc = Encoder::from_hex("ff");
+        let offset = enc.encode_varint_placeholder(2);
+        enc.encode_byte(0xaa);
+        enc.patch_varint(offset, 2, 300_u64);
+        assert_eq!(enc, Encoder::from_hex("ff412caa"));
+
+        let mut enc = Encoder::default();
+        enc.reserve(70).encode_vvec_with_len(2, |enc_inner| {
+            enc_inner.encode(&[0xa5; 65]);
+        });
+        let mut dec = enc.as_decoder();
+        assert_eq!(dec.decode_vvec().unwrap(), &[0xa5; 65][..]);
+    }
+
     #[test]
     fn encode_mutate() {
         let mut enc = Encoder::from_hex("010234");
         enc[0] = 0xff;
         assert_eq!(enc, Encoder::from_hex("ff0234"));
     }
+
+    #[test]
+    fn encode_reuse() {
+        let mut buf = Vec::with_capacity(10);
+        buf.push(0x01);
+        let ptr = buf.as_ptr();
+        let mut enc = Encoder::from(buf);
+        enc.encode_byte(0x02);
+        assert_eq!(enc, Encoder::from_hex("0102"));
+        enc.clear();
+        assert!(enc.is_empty());
+        let buf: Vec<u8> = enc.into();
+        assert_eq!(buf.as_ptr(), ptr);
+    }
 }
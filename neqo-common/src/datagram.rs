@@ -4,14 +4,65 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::fmt::{self, Debug};
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::time::Instant;
 
-#[derive(Debug, PartialEq, Clone)]
+use crate::{hex, Redact};
+
+/// The Explicit Congestion Notification codepoint of a datagram, which is
+/// the low two bits of the IPv4 TOS or IPv6 Traffic Class field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Default for Ecn {
+    fn default() -> Self {
+        Ecn::NotEct
+    }
+}
+
+impl From<u8> for Ecn {
+    fn from(tos: u8) -> Self {
+        match tos & 0b11 {
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            0b11 => Ecn::Ce,
+            _ => Ecn::NotEct,
+        }
+    }
+}
+
+impl From<Ecn> for u8 {
+    fn from(ecn: Ecn) -> Self {
+        match ecn {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone)]
 pub struct Datagram {
     src: SocketAddr,
     dst: SocketAddr,
     d: Vec<u8>,
+    /// When the datagram is to be sent, if not straight away.
+    release_time: Option<Instant>,
+    ecn: Ecn,
+    dscp: u8,
+    flow_label: Option<u32>,
+    /// When the datagram was received, if the I/O layer knows.
+    received: Option<Instant>,
+    ttl: Option<u8>,
+    interface: Option<u32>,
 }
 
 impl Datagram {
@@ -20,9 +71,110 @@ impl Datagram {
             src,
             dst,
             d: d.into(),
+            release_time: None,
+            ecn: Ecn::NotEct,
+            dscp: 0,
+            flow_label: None,
+            received: None,
+            ttl: None,
+            interface: None,
         }
     }
 
+    /// Make a datagram that holds `d` instead, with the same addresses and
+    /// everything else that goes with them.
+    pub fn copy_with<V: Into<Vec<u8>>>(&self, d: V) -> Self {
+        Self {
+            d: d.into(),
+            ..*self
+        }
+    }
+
+    /// Set the Differentiated Services codepoint that the datagram is to be
+    /// sent with, which is the high six bits of the IPv4 TOS or IPv6 Traffic
+    /// Class field.
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = dscp & 0x3f;
+        self
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Set the IPv6 flow label that the datagram is to be sent with.  Only
+    /// the low 20 bits are used.  This has no effect on IPv4.
+    pub fn with_flow_label(mut self, label: u32) -> Self {
+        self.flow_label = Some(label & 0xf_ffff);
+        self
+    }
+
+    pub fn flow_label(&self) -> Option<u32> {
+        self.flow_label
+    }
+
+    /// The IPv4 TOS or IPv6 Traffic Class byte: the DSCP and ECN codepoints.
+    pub fn tos(&self) -> u8 {
+        (self.dscp << 2) | u8::from(self.ecn)
+    }
+
+    /// Set the ECN codepoint, either that the datagram was received with or
+    /// that it is to be sent with.
+    pub fn with_ecn(mut self, ecn: Ecn) -> Self {
+        self.ecn = ecn;
+        self
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        self.ecn
+    }
+
+    /// Set the time at which the datagram was received.  This can be earlier
+    /// than the time that it is processed, which matters for measuring delays.
+    pub fn with_received_time(mut self, t: Instant) -> Self {
+        self.received = Some(t);
+        self
+    }
+
+    pub fn received_time(&self) -> Option<Instant> {
+        self.received
+    }
+
+    /// Set the IPv4 TTL or IPv6 hop limit that the datagram arrived with.
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn ttl(&self) -> Option<u8> {
+        self.ttl
+    }
+
+    /// Set the index of the local network interface, either that the datagram
+    /// arrived on or that it is to be sent from.  On a host with several
+    /// interfaces, replies need to leave by the one the peer reached.
+    pub fn with_interface(mut self, index: u32) -> Self {
+        self.interface = Some(index);
+        self
+    }
+
+    pub fn interface(&self) -> Option<u32> {
+        self.interface
+    }
+
+    /// Set the time at which the datagram is to be sent.
+    pub fn with_release_time(mut self, t: Instant) -> Self {
+        self.release_time = Some(t);
+        self
+    }
+
+    /// The time at which the datagram is to be sent.  The I/O layer can pass
+    /// this to the kernel (SO_TXTIME on Linux) so that it paces sending.
+    /// If this is `None`, the datagram is to be sent straight away.
+    pub fn release_time(&self) -> Option<Instant> {
+        self.release_time
+    }
+
     pub fn source(&self) -> SocketAddr {
         self.src
     }
@@ -30,6 +182,23 @@ impl Datagram {
     pub fn destination(&self) -> SocketAddr {
         self.dst
     }
+
+    /// Take the buffer that holds the datagram, so that it can be reused.
+    pub fn into_data(self) -> Vec<u8> {
+        self.d
+    }
+}
+
+impl Debug for Datagram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Datagram {:?}->{:?}: {}",
+            Redact(self.src),
+            Redact(self.dst),
+            Redact(hex(&self.d))
+        )
+    }
 }
 
 impl Deref for Datagram {
@@ -38,3 +207,281 @@ impl Deref for Datagram {
         &self.d
     }
 }
+
+/// Datagrams with the same source and destination, held in one buffer for
+/// sending or receiving in one system call, with Generic Segmentation Offload
+/// (UDP_SEGMENT) or Generic Receive Offload (UDP_GRO) on Linux.  Every
+/// datagram is `segment_size` bytes long, except that the last can be
+/// shorter.
+#[derive(PartialEq, Clone)]
+pub struct DatagramBatch {
+    src: SocketAddr,
+    dst: SocketAddr,
+    segment_size: usize,
+    d: Vec<u8>,
+    release_time: Option<Instant>,
+    ecn: Ecn,
+    dscp: u8,
+    flow_label: Option<u32>,
+    received: Option<Instant>,
+    ttl: Option<u8>,
+    interface: Option<u32>,
+}
+
+impl DatagramBatch {
+    /// Start a batch with one datagram, which sets the segment size.
+    pub fn new(first: Datagram) -> Self {
+        assert!(!first.d.is_empty());
+        Self {
+            src: first.src,
+            dst: first.dst,
+            segment_size: first.d.len(),
+            d: first.d,
+            release_time: first.release_time,
+            ecn: first.ecn,
+            dscp: first.dscp,
+            flow_label: first.flow_label,
+            received: first.received,
+            ttl: first.ttl,
+            interface: first.interface,
+        }
+    }
+
+    /// Make a batch from a buffer that was received with GRO.
+    pub fn received<V: Into<Vec<u8>>>(
+        src: SocketAddr,
+        dst: SocketAddr,
+        d: V,
+        segment_size: usize,
+    ) -> Self {
+        assert!(segment_size > 0);
+        Self {
+            src,
+            dst,
+            segment_size,
+            d: d.into(),
+            release_time: None,
+            ecn: Ecn::NotEct,
+            dscp: 0,
+            flow_label: None,
+            received: None,
+            ttl: None,
+            interface: None,
+        }
+    }
+
+    /// Set the ECN codepoint of all the datagrams in the batch.
+    pub fn with_ecn(mut self, ecn: Ecn) -> Self {
+        self.ecn = ecn;
+        self
+    }
+
+    /// Set the time at which the datagrams in the batch were received.
+    pub fn with_received_time(mut self, t: Instant) -> Self {
+        self.received = Some(t);
+        self
+    }
+
+    /// Set the TTL or hop limit that the datagrams in the batch arrived with.
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set the index of the interface that the datagrams in the batch
+    /// arrived on.
+    pub fn with_interface(mut self, index: u32) -> Self {
+        self.interface = Some(index);
+        self
+    }
+
+    /// Add a datagram to the end of the batch.  This fails, returning the
+    /// datagram, if it has a different source, destination, release time,
+    /// ECN or DSCP codepoint, flow label, or interface, if it is longer than
+    /// the segment size, or if the last datagram in the batch is shorter than
+    /// the segment size.
+    pub fn push(&mut self, dgram: Datagram) -> Result<(), Datagram> {
+        if dgram.src != self.src
+            || dgram.dst != self.dst
+            || dgram.release_time != self.release_time
+            || dgram.ecn != self.ecn
+            || dgram.dscp != self.dscp
+            || dgram.flow_label != self.flow_label
+            || dgram.interface != self.interface
+            || dgram.d.len() > self.segment_size
+            || self.d.len() % self.segment_size != 0
+        {
+            return Err(dgram);
+        }
+        self.d.extend_from_slice(&dgram.d);
+        Ok(())
+    }
+
+    pub fn source(&self) -> SocketAddr {
+        self.src
+    }
+
+    pub fn destination(&self) -> SocketAddr {
+        self.dst
+    }
+
+    /// The time at which the datagrams are to be sent, if not straight away.
+    pub fn release_time(&self) -> Option<Instant> {
+        self.release_time
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        self.ecn
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    pub fn flow_label(&self) -> Option<u32> {
+        self.flow_label
+    }
+
+    pub fn received_time(&self) -> Option<Instant> {
+        self.received
+    }
+
+    pub fn ttl(&self) -> Option<u8> {
+        self.ttl
+    }
+
+    pub fn interface(&self) -> Option<u32> {
+        self.interface
+    }
+
+    /// The IPv4 TOS or IPv6 Traffic Class byte: the DSCP and ECN codepoints.
+    pub fn tos(&self) -> u8 {
+        (self.dscp << 2) | u8::from(self.ecn)
+    }
+
+    /// The size of each datagram, which is what UDP_SEGMENT needs.
+    pub fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    /// The number of datagrams in the batch.
+    pub fn count(&self) -> usize {
+        (self.d.len() + self.segment_size - 1) / self.segment_size
+    }
+
+    /// Iterate over the datagrams in the batch, for sending or receiving them
+    /// one at a time.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Datagram> + 'a {
+        self.d.chunks(self.segment_size).map(move |d| {
+            let mut dgram = Datagram::new(self.src, self.dst, d).with_ecn(self.ecn);
+            dgram.dscp = self.dscp;
+            dgram.flow_label = self.flow_label;
+            dgram.received = self.received;
+            dgram.ttl = self.ttl;
+            dgram.interface = self.interface;
+            dgram
+        })
+    }
+}
+
+impl Debug for DatagramBatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DatagramBatch {:?}->{:?} {}x{}: {}",
+            Redact(self.src),
+            Redact(self.dst),
+            self.count(),
+            self.segment_size,
+            Redact(hex(&self.d))
+        )
+    }
+}
+
+impl Deref for DatagramBatch {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Self::Target {
+        &self.d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+    }
+
+    fn dgram(len: usize) -> Datagram {
+        Datagram::new(addr(1), addr(2), vec![len as u8; len])
+    }
+
+    #[test]
+    fn batch() {
+        let mut b = DatagramBatch::new(dgram(10));
+        assert!(b.push(dgram(10)).is_ok());
+        assert!(b.push(dgram(11)).is_err());
+        assert!(b
+            .push(Datagram::new(addr(3), addr(2), vec![0; 10]))
+            .is_err());
+        assert!(b.push(dgram(4)).is_ok());
+        // Nothing can follow a short datagram.
+        assert!(b.push(dgram(4)).is_err());
+        assert_eq!(b.count(), 3);
+        assert_eq!(b.segment_size(), 10);
+        let all: Vec<_> = b.iter().collect();
+        assert_eq!(all, vec![dgram(10), dgram(10), dgram(4)]);
+    }
+
+    #[test]
+    fn received() {
+        let b = DatagramBatch::received(addr(1), addr(2), vec![1; 25], 10);
+        assert_eq!(b.count(), 3);
+        let lens: Vec<_> = b.iter().map(|d| d.len()).collect();
+        assert_eq!(lens, vec![10, 10, 5]);
+    }
+
+    #[test]
+    fn ecn() {
+        assert_eq!(Ecn::from(0xb9), Ecn::Ect1);
+        assert_eq!(u8::from(Ecn::Ce), 3);
+        let mut b = DatagramBatch::new(dgram(10).with_ecn(Ecn::Ect0));
+        assert!(b.push(dgram(10)).is_err());
+        assert!(b.push(dgram(10).with_ecn(Ecn::Ect0)).is_ok());
+        assert!(b.iter().all(|d| d.ecn() == Ecn::Ect0));
+    }
+
+    #[test]
+    fn dscp_and_flow_label() {
+        let d = dgram(10)
+            .with_ecn(Ecn::Ect0)
+            .with_dscp(46)
+            .with_flow_label(0x12_3456);
+        assert_eq!(d.tos(), 0xba);
+        assert_eq!(d.flow_label(), Some(0x2_3456));
+        let mut b = DatagramBatch::new(d.clone());
+        assert!(b.push(dgram(10).with_ecn(Ecn::Ect0)).is_err());
+        assert!(b.push(d.clone()).is_ok());
+        assert_eq!(b.tos(), 0xba);
+        assert!(b.iter().all(|x| x == d));
+    }
+
+    #[test]
+    fn receive_metadata() {
+        let t = Instant::now();
+        let b = DatagramBatch::received(addr(1), addr(2), vec![1; 15], 10)
+            .with_received_time(t)
+            .with_ttl(64)
+            .with_interface(3);
+        for d in b.iter() {
+            assert_eq!(d.received_time(), Some(t));
+            assert_eq!(d.ttl(), Some(64));
+            assert_eq!(d.interface(), Some(3));
+        }
+        let mut b = DatagramBatch::new(dgram(10).with_interface(2));
+        assert!(b.push(dgram(10)).is_err());
+        assert!(b.push(dgram(10).with_interface(2)).is_ok());
+    }
+}
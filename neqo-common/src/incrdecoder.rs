@@ -28,7 +28,7 @@ pub enum IncrementalDecoder {
     Idle,
     BeforeVarint,
     InUint { v: u64, remaining: usize },
-    InBufferLen(Box<IncrementalDecoder>),
+    InBufferLen(Box<IncrementalDecoder>, usize),
     InBuffer { v: Vec<u8>, remaining: usize },
     Ignoring { remaining: usize },
 }
@@ -65,12 +65,19 @@ impl IncrementalDecoder {
 
     /// Decode a vector that as a fixed-sized length prefix.
     pub fn decode_vec(n: usize) -> Self {
-        IncrementalDecoder::InBufferLen(Box::new(Self::decode_uint(n)))
+        IncrementalDecoder::InBufferLen(Box::new(Self::decode_uint(n)), usize::max_value())
     }
 
     /// Decode a vector that as a varint-sized length prefix.
     pub fn decode_vvec() -> Self {
-        IncrementalDecoder::InBufferLen(Box::new(Self::decode_varint()))
+        Self::decode_vvec_max(usize::max_value())
+    }
+
+    /// Decode a vector that as a varint-sized length prefix, which is an
+    /// error if the length is more than `max`.  The error is reported as soon
+    /// as the length is decoded, without waiting for the contents.
+    pub fn decode_vvec_max(max: usize) -> Self {
+        IncrementalDecoder::InBufferLen(Box::new(Self::decode_varint()), max)
     }
 
     /// Ignore a certain number of bytes.
@@ -88,7 +95,7 @@ impl IncrementalDecoder {
             IncrementalDecoder::InUint { remaining, .. }
             | IncrementalDecoder::InBuffer { remaining, .. }
             | IncrementalDecoder::Ignoring { remaining } => *remaining,
-            IncrementalDecoder::InBufferLen(in_len) => in_len.min_remaining(),
+            IncrementalDecoder::InBufferLen(in_len, _) => in_len.min_remaining(),
             _ => 0,
         }
     }
@@ -176,20 +183,21 @@ impl IncrementalDecoder {
                 }
             }
 
-            IncrementalDecoder::InBufferLen(mut len_decoder) => match len_decoder.consume(dv) {
-                IncrementalDecoderResult::InProgress => {
-                    *self = IncrementalDecoder::InBufferLen(len_decoder);
-                    IncrementalDecoderResult::InProgress
-                }
-                IncrementalDecoderResult::Uint(n) => {
-                    if let Ok(len) = usize::try_from(n) {
-                        self.consume_buffer_remainder(Vec::with_capacity(len), len, dv)
-                    } else {
-                        IncrementalDecoderResult::Error
+            IncrementalDecoder::InBufferLen(mut len_decoder, max) => {
+                match len_decoder.consume(dv) {
+                    IncrementalDecoderResult::InProgress => {
+                        *self = IncrementalDecoder::InBufferLen(len_decoder, max);
+                        IncrementalDecoderResult::InProgress
                     }
+                    IncrementalDecoderResult::Uint(n) => match usize::try_from(n) {
+                        Ok(len) if len <= max => {
+                            self.consume_buffer_remainder(Vec::with_capacity(len), len, dv)
+                        }
+                        _ => IncrementalDecoderResult::Error,
+                    },
+                    _ => unreachable!(),
                 }
-                _ => unreachable!(),
-            },
+            }
 
             IncrementalDecoder::InBuffer { v, remaining } => {
                 self.consume_buffer_remainder(v, remaining, dv)
@@ -392,6 +400,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn vvec_max() {
+        for c in buf_tc!["03012345" => "012345"] {
+            c.run(&IncrementalDecoder::decode_vvec_max(3));
+        }
+
+        // The length alone is enough to fail.
+        let enc = Encoder::from_hex("406400");
+        let mut incr = IncrementalDecoder::decode_vvec_max(10);
+        assert_eq!(
+            incr.consume(&mut Decoder::from(&enc[..1])),
+            IncrementalDecoderResult::InProgress
+        );
+        let mut dec = Decoder::from(&enc[1..]);
+        assert_eq!(incr.consume(&mut dec), IncrementalDecoderResult::Error);
+        assert_eq!(dec.remaining(), 1);
+    }
+
     #[test]
     fn zero_len() {
         let enc = Encoder::from_hex("ff");
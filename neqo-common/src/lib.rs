@@ -6,16 +6,21 @@
 
 #![deny(warnings)]
 
+mod bufpool;
 mod codec;
 mod datagram;
 mod incrdecoder;
 pub mod log;
 pub mod once;
 pub mod timer;
+#[cfg(all(feature = "udp", target_os = "linux"))]
+pub mod udp;
 
+pub use self::bufpool::{BufferPool, PooledBuffer};
 pub use self::codec::{Decoder, Encoder};
-pub use self::datagram::Datagram;
+pub use self::datagram::{Datagram, DatagramBatch, Ecn};
 pub use self::incrdecoder::{IncrementalDecoder, IncrementalDecoderResult};
+pub use self::log::Redact;
 
 #[macro_use]
 extern crate lazy_static;
@@ -4,19 +4,274 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+// Logging goes to a `Backend`.  Unless the application sets another one,
+// records go to the `log` crate, with `env_logger` set up to print them the
+// first time something is logged.
+//
+// `enabled()` runs for every record, including those that aren't logged, so
+// it checks a cached maximum level before anything that takes a lock.
+
+pub use ::log::{Level, LevelFilter};
 use env_logger::Builder;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::io::Write;
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Once, RwLock};
 use std::time::Instant;
 
 static INIT_ONCE: Once = Once::new();
+static REDACT: AtomicBool = AtomicBool::new(false);
+static NEXT_LOG_ID: AtomicUsize = AtomicUsize::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// Whether `BACKEND` and `MODULE_LEVELS` have anything in them.
+static HAS_BACKEND: AtomicBool = AtomicBool::new(false);
+static HAS_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+// The most verbose level, as a `LevelFilter`, that the backend set with
+// `set_backend()` or any module level might log at.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
 
 lazy_static! {
     static ref START_TIME: Instant = Instant::now();
+    static ref BACKEND: RwLock<Option<Box<dyn Backend>>> = RwLock::new(None);
+    static ref MODULE_LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+    // `RandomState` is keyed with a random value, so redacted values can't
+    // be matched against a list of guesses, or between runs.
+    static ref REDACT_KEY: RandomState = RandomState::new();
+}
+
+/// A log record.
+pub struct Record<'a> {
+    pub level: Level,
+    /// The module that logged this.
+    pub target: &'a str,
+    /// What the record is about, if it was logged with one, such as
+    /// `[self]` for a connection.  Connections include an identifier that is
+    /// unique within the process.
+    pub context: Option<&'a str>,
+    pub args: fmt::Arguments<'a>,
+}
+
+/// Somewhere to send log records.  Set one with `set_backend()` to send neqo
+/// logs to the application's own logging or telemetry.
+pub trait Backend: Send + Sync {
+    /// Whether records at `level` from `target` are wanted.  Per-module
+    /// levels from `set_module_level()` are applied before this.
+    fn enabled(&self, _level: Level, _target: &str) -> bool {
+        true
+    }
+
+    /// The most verbose level that `enabled()` might accept.  Records that
+    /// are more verbose than this are dropped without asking the backend.
+    fn max_level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    fn log(&self, record: &Record);
+}
+
+/// The default backend, which passes records to the `log` crate.
+pub struct LogBackend;
+
+impl Backend for LogBackend {
+    fn enabled(&self, level: Level, target: &str) -> bool {
+        ::log::log_enabled!(target: target, level)
+    }
+
+    fn log(&self, record: &Record) {
+        match record.context {
+            Some(context) => ::log::log!(
+                target: record.target,
+                record.level,
+                "[{}] {}",
+                context,
+                record.args
+            ),
+            None => ::log::log!(target: record.target, record.level, "{}", record.args),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn tracing_level(level: Level) -> tracing::Level {
+    match level {
+        Level::Error => tracing::Level::ERROR,
+        Level::Warn => tracing::Level::WARN,
+        Level::Info => tracing::Level::INFO,
+        Level::Debug => tracing::Level::DEBUG,
+        Level::Trace => tracing::Level::TRACE,
+    }
+}
+
+/// A backend that passes records to `tracing` as events.  `tracing` needs
+/// targets to be constant, so events have a target of "neqo", with the module
+/// and the context as fields.
+#[cfg(feature = "tracing")]
+pub struct TracingBackend;
+
+#[cfg(feature = "tracing")]
+impl Backend for TracingBackend {
+    fn enabled(&self, level: Level, _target: &str) -> bool {
+        tracing::level_filters::LevelFilter::current() >= tracing_level(level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        match tracing::level_filters::LevelFilter::current().into_level() {
+            None => LevelFilter::Off,
+            Some(tracing::Level::ERROR) => LevelFilter::Error,
+            Some(tracing::Level::WARN) => LevelFilter::Warn,
+            Some(tracing::Level::INFO) => LevelFilter::Info,
+            Some(tracing::Level::DEBUG) => LevelFilter::Debug,
+            Some(tracing::Level::TRACE) => LevelFilter::Trace,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        macro_rules! event {
+            ($lvl:expr) => {
+                tracing::event!(
+                    target: "neqo",
+                    $lvl,
+                    module = record.target,
+                    context = record.context,
+                    "{}",
+                    record.args
+                )
+            };
+        }
+        match record.level {
+            Level::Error => event!(tracing::Level::ERROR),
+            Level::Warn => event!(tracing::Level::WARN),
+            Level::Info => event!(tracing::Level::INFO),
+            Level::Debug => event!(tracing::Level::DEBUG),
+            Level::Trace => event!(tracing::Level::TRACE),
+        }
+    }
+}
+
+// Work out `MAX_LEVEL` again, after the backend or module levels change.
+fn update_max_level(backend: Option<&dyn Backend>, levels: &[(String, LevelFilter)]) {
+    let max = levels
+        .iter()
+        .map(|(_, l)| *l)
+        .chain(backend.map(Backend::max_level))
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    MAX_LEVEL.store(max as usize, Ordering::Relaxed);
+}
+
+/// Send log records to `backend` instead of the `log` crate.  Because the
+/// maximum level of the backend is only read here, call this again if that
+/// changes.
+pub fn set_backend(backend: Box<dyn Backend>) {
+    let mut b = BACKEND.write().unwrap();
+    *b = Some(backend);
+    update_max_level(b.as_ref().map(Box::as_ref), &MODULE_LEVELS.read().unwrap());
+    HAS_BACKEND.store(true, Ordering::Relaxed);
+}
+
+/// Send log records to the `log` crate again.
+pub fn clear_backend() {
+    let mut b = BACKEND.write().unwrap();
+    HAS_BACKEND.store(false, Ordering::Relaxed);
+    *b = None;
+    update_max_level(None, &MODULE_LEVELS.read().unwrap());
+}
+
+/// Log records from `module` and the modules in it at `level` and more
+/// severe only.  The most specific module that has a level applies.
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    // Always lock `BACKEND` first.
+    let backend = BACKEND.read().unwrap();
+    let mut levels = MODULE_LEVELS.write().unwrap();
+    levels.retain(|(m, _)| m != module);
+    levels.push((String::from(module), level));
+    update_max_level(backend.as_ref().map(Box::as_ref), &levels);
+    HAS_MODULE_LEVELS.store(true, Ordering::Relaxed);
+}
+
+/// Remove all of the levels set with `set_module_level()`.
+pub fn clear_module_levels() {
+    let backend = BACKEND.read().unwrap();
+    let mut levels = MODULE_LEVELS.write().unwrap();
+    HAS_MODULE_LEVELS.store(false, Ordering::Relaxed);
+    levels.clear();
+    update_max_level(backend.as_ref().map(Box::as_ref), &levels);
+}
+
+fn module_level(target: &str) -> Option<LevelFilter> {
+    let levels = MODULE_LEVELS.read().unwrap();
+    levels
+        .iter()
+        .filter(|(m, _)| {
+            target.starts_with(m.as_str())
+                && (target.len() == m.len() || target[m.len()..].starts_with("::"))
+        })
+        .max_by_key(|(m, _)| m.len())
+        .map(|(_, l)| *l)
+}
+
+/// An identifier for a connection or something else that logs, which is
+/// unique within the process.
+pub fn next_log_id() -> usize {
+    NEXT_LOG_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Whether records at `level` from `target` are logged.
+pub fn enabled(level: Level, target: &str) -> bool {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        init();
+    }
+    // With the `log` crate, nothing more verbose than its maximum is logged,
+    // whatever the module levels say.
+    let has_backend = HAS_BACKEND.load(Ordering::Relaxed);
+    let max = if has_backend {
+        MAX_LEVEL.load(Ordering::Relaxed)
+    } else {
+        ::log::max_level() as usize
+    };
+    if level as usize > max {
+        return false;
+    }
+    if HAS_MODULE_LEVELS.load(Ordering::Relaxed) {
+        if let Some(l) = module_level(target) {
+            if level > l {
+                return false;
+            }
+        }
+    }
+    if !has_backend {
+        return LogBackend.enabled(level, target);
+    }
+    match &*BACKEND.read().unwrap() {
+        Some(b) => b.enabled(level, target),
+        None => LogBackend.enabled(level, target),
+    }
+}
+
+/// Log a record, if `enabled()` says so.  This is what the `qlog!` family of
+/// macros use.
+pub fn log(level: Level, target: &str, context: Option<&dyn Display>, args: fmt::Arguments) {
+    let context = context.map(ToString::to_string);
+    let record = Record {
+        level,
+        target,
+        context: context.as_ref().map(String::as_str),
+        args,
+    };
+    match &*BACKEND.read().unwrap() {
+        Some(b) => b.log(&record),
+        None => LogBackend.log(&record),
+    }
 }
 
 pub fn init() {
     INIT_ONCE.call_once(|| {
+        if env::var_os("NEQO_LOG_REDACT").is_some() {
+            set_redact(true);
+        }
         let mut builder = Builder::from_env("RUST_LOG");
         builder.format(|buf, record| {
             let elapsed = START_TIME.elapsed();
@@ -34,43 +289,178 @@ pub fn init() {
         } else {
             ::log::log!(::log::Level::Info, "Logging initialized");
         }
+        INITIALIZED.store(true, Ordering::Release);
     });
 }
 
+/// Enable or disable redaction of values that are logged using `Redact`.
+/// This can be changed at any time; it is checked each time a value is
+/// logged.  Redaction is enabled at startup if `NEQO_LOG_REDACT` is set.
+pub fn set_redact(redact: bool) {
+    REDACT.store(redact, Ordering::Relaxed);
+}
+
+/// Whether values that are logged using `Redact` are being redacted.
+pub fn redacting() -> bool {
+    REDACT.load(Ordering::Relaxed)
+}
+
+/// A wrapper for values that might identify someone, such as connection IDs,
+/// addresses, server names, and header values.  This formats normally, unless
+/// redaction is enabled, in which case it is replaced by a keyed hash.  The
+/// key is chosen at random when the process starts, so values can still be
+/// matched up within the logs of one process, but not guessed.
+pub struct Redact<T>(pub T);
+
+/// What a value that is redacted is logged as.
+fn redacted(s: &str) -> String {
+    let mut h = REDACT_KEY.build_hasher();
+    s.hash(&mut h);
+    format!("<redacted {:016x}>", h.finish())
+}
+
+impl<T: Display> Display for Redact<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if redacting() {
+            f.write_str(&redacted(&self.0.to_string()))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: Debug> Debug for Redact<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if redacting() {
+            f.write_str(&redacted(&format!("{:?}", self.0)))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+// The message is formatted before the context is used, so that it can use
+// values that borrow the context mutably.
 #[macro_export]
 macro_rules! qlog {
     ($lvl:expr, $ctx:expr, $($arg:tt)*) => ( {
-        ::neqo_common::log::init();
-        ::log::log!($lvl, "[{}] {}", $ctx, format!($($arg)*));
+        if ::neqo_common::log::enabled($lvl, module_path!()) {
+            let msg = format!($($arg)*);
+            ::neqo_common::log::log($lvl, module_path!(), Some(&$ctx), format_args!("{}", msg));
+        }
+    } )
+}
+#[macro_export]
+macro_rules! qlog_no_context {
+    ($lvl:expr, $($arg:tt)*) => ( {
+        if ::neqo_common::log::enabled($lvl, module_path!()) {
+            ::neqo_common::log::log($lvl, module_path!(), None, format_args!($($arg)*));
+        }
     } )
 }
 #[macro_export]
 macro_rules! qerror {
-    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Error, $ctx, $($arg)*););
-    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Error, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Error, $($arg)*); } );
+    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Error, $ctx, $($arg)*););
+    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Error, $ctx, $($arg)*););
+    ($($arg:tt)*) => (::neqo_common::qlog_no_context!(::neqo_common::log::Level::Error, $($arg)*););
 }
 #[macro_export]
 macro_rules! qwarn {
-    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Warn, $ctx, $($arg)*););
-    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Warn, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Warn, $($arg)*); } );
+    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Warn, $ctx, $($arg)*););
+    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Warn, $ctx, $($arg)*););
+    ($($arg:tt)*) => (::neqo_common::qlog_no_context!(::neqo_common::log::Level::Warn, $($arg)*););
 }
 #[macro_export]
 macro_rules! qinfo {
-    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Info, $ctx, $($arg)*););
-    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Info, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Info, $($arg)*); } );
+    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Info, $ctx, $($arg)*););
+    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Info, $ctx, $($arg)*););
+    ($($arg:tt)*) => (::neqo_common::qlog_no_context!(::neqo_common::log::Level::Info, $($arg)*););
 }
 #[macro_export]
 macro_rules! qdebug {
-    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Debug, $ctx, $($arg)*););
-    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Debug, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Debug, $($arg)*); } );
+    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Debug, $ctx, $($arg)*););
+    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Debug, $ctx, $($arg)*););
+    ($($arg:tt)*) => (::neqo_common::qlog_no_context!(::neqo_common::log::Level::Debug, $($arg)*););
 }
 #[macro_export]
 macro_rules! qtrace {
-    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Trace, $ctx, $($arg)*););
-    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Trace, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Trace, $($arg)*); } );
+    ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Trace, $ctx, $($arg)*););
+    ([$ctx:expr] $($arg:tt)*) => (::neqo_common::qlog!(::neqo_common::log::Level::Trace, $ctx, $($arg)*););
+    ($($arg:tt)*) => (::neqo_common::qlog_no_context!(::neqo_common::log::Level::Trace, $($arg)*););
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // This doesn't change the global setting, which other tests depend on.
+    #[test]
+    fn redact() {
+        let v = Redact("example.com");
+        assert_eq!(format!("{}", v), "example.com");
+        assert_eq!(format!("{:?}", v), "\"example.com\"");
+
+        let r = redacted("example.com");
+        assert!(r.starts_with("<redacted "));
+        assert!(!r.contains("example"));
+        // The same value always produces the same output.
+        assert_eq!(redacted("example.com"), r);
+        assert_ne!(redacted("example.org"), r);
+    }
+
+    // Other tests log while this backend is set, so it keeps only the records
+    // that the test logs itself.
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Backend for Capture {
+        fn log(&self, record: &Record) {
+            if record.target.starts_with("neqo_test") {
+                self.0.lock().unwrap().push(format!(
+                    "{} {} {:?} {}",
+                    record.level, record.target, record.context, record.args
+                ));
+            }
+        }
+    }
+
+    // Put the global settings back, even if the test fails.
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            clear_module_levels();
+            clear_backend();
+        }
+    }
+
+    #[test]
+    fn backend() {
+        let _reset = Reset;
+        let records = Arc::new(Mutex::new(Vec::new()));
+        set_backend(Box::new(Capture(Arc::clone(&records))));
+        set_module_level("neqo_test", LevelFilter::Info);
+        set_module_level("neqo_test::detail", LevelFilter::Trace);
+
+        assert!(!enabled(Level::Debug, "neqo_test"));
+        assert!(!enabled(Level::Debug, "neqo_test::other"));
+        assert!(enabled(Level::Debug, "neqo_test::detail"));
+        assert!(enabled(Level::Debug, "neqo_testing"));
+
+        log(
+            Level::Info,
+            "neqo_test",
+            Some(&"ctx"),
+            format_args!("{}", 1),
+        );
+        log(Level::Warn, "neqo_test", None, format_args!("no context"));
+        assert_eq!(
+            *records.lock().unwrap(),
+            vec![
+                String::from("INFO neqo_test Some(\"ctx\") 1"),
+                String::from("WARN neqo_test None no context"),
+            ]
+        );
+        assert_ne!(next_log_id(), next_log_id());
+    }
 }
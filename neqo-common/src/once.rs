@@ -4,40 +4,63 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cell::UnsafeCell;
 use std::sync::Once;
 
+/// A value that is computed once, the first time that it is asked for.
+/// This can be used in a `static` (not `static mut`), from any thread.
 #[allow(clippy::module_name_repetitions)]
 pub struct OnceResult<T> {
     once: Once,
-    v: Option<T>,
+    v: UnsafeCell<Option<T>>,
 }
 
+// The value is only written inside `Once::call_once()`, which finishes before
+// any caller gets a reference, so sharing this is as safe as sharing `T`.
+unsafe impl<T: Send + Sync> Sync for OnceResult<T> {}
+
 impl<T> OnceResult<T> {
     pub const fn new() -> Self {
         Self {
             once: Once::new(),
-            v: None,
+            v: UnsafeCell::new(None),
         }
     }
 
-    pub fn call_once<F: FnOnce() -> T>(&mut self, f: F) -> &T {
-        let v = &mut self.v;
-        self.once.call_once(|| {
+    /// Get the value, calling `f` to make it if this is the first call.  If
+    /// `f` panics, this and every later call panics.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        let v = self.v.get();
+        self.once.call_once(|| unsafe {
             *v = Some(f());
         });
-        self.v.as_ref().unwrap()
+        unsafe { (*v).as_ref() }.unwrap()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::thread;
 
-    static mut STATIC_ONCE_RESULT: OnceResult<u64> = OnceResult::new();
+    static STATIC_ONCE_RESULT: OnceResult<u64> = OnceResult::new();
 
     #[test]
     fn static_update() {
-        assert_eq!(*unsafe { STATIC_ONCE_RESULT.call_once(|| 23) }, 23);
-        assert_eq!(*unsafe { STATIC_ONCE_RESULT.call_once(|| 24) }, 23);
+        assert_eq!(*STATIC_ONCE_RESULT.call_once(|| 23), 23);
+        assert_eq!(*STATIC_ONCE_RESULT.call_once(|| 24), 23);
+    }
+
+    #[test]
+    fn threads() {
+        static THREADED: OnceResult<usize> = OnceResult::new();
+        let handles = (0..8)
+            .map(|i| thread::spawn(move || *THREADED.call_once(|| i)))
+            .collect::<Vec<_>>();
+        let values = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(values.iter().all(|&v| v == values[0]));
     }
 }
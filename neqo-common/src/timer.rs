@@ -4,32 +4,59 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp::min;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::mem;
 use std::time::{Duration, Instant};
 
+/// Identifies an entry in a `Timer`, so that it can be cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerToken {
+    time: Instant,
+    id: u64,
+}
+
+impl TimerToken {
+    /// The time that the entry was added for.
+    pub fn time(&self) -> Instant {
+        self.time
+    }
+
+    /// The first and last possible tokens for `time`.
+    fn bounds(time: Instant) -> (Self, Self) {
+        (Self { time, id: 0 }, Self { time, id: u64::MAX })
+    }
+}
+
 /// Internal structure for a timer item.
 struct TimerItem<T> {
-    time: Instant,
+    token: TimerToken,
     item: T,
 }
 
 impl<T> TimerItem<T> {
-    fn time(ti: &Self) -> Instant {
-        ti.time
+    fn token(ti: &Self) -> TimerToken {
+        ti.token
     }
 }
 
 /// A timer queue.
 /// This uses a classic timer wheel arrangement, with some characteristics that might be considered peculiar.
 /// Each slot in the wheel is sorted (complexity O(N) insertions, but O(logN) to find cut points).
-/// Time is relative, the wheel has an origin time and it is unable to represent times that are more than
-/// `granularity * capacity` past that time.
+/// Time is relative, the wheel has an origin time and it only holds times that are less than
+/// `granularity * capacity` past that time.  Anything later waits in a second, ordered level
+/// until the wheel moves far enough forward to take it, so that long delays (like idle timeouts)
+/// don't need a wheel that covers them.
+/// Adding an entry returns a token that can be used to cancel it without searching.
 pub struct Timer<T> {
     items: Vec<Vec<TimerItem<T>>>,
+    overflow: BTreeMap<TimerToken, T>,
     now: Instant,
     granularity: Duration,
     cursor: usize,
+    next_id: u64,
+    count: usize,
 }
 
 impl<T> Timer<T> {
@@ -41,9 +68,12 @@ impl<T> Timer<T> {
         items.resize_with(capacity, Default::default);
         Self {
             items,
+            overflow: BTreeMap::new(),
             now,
             granularity,
             cursor: 0,
+            next_id: 0,
+            count: 0,
         }
     }
 
@@ -52,14 +82,24 @@ impl<T> Timer<T> {
         for i in 0..self.items.len() {
             let idx = self.bucket(i);
             if let Some(t) = self.items[idx].first() {
-                return Some(t.time);
+                return Some(t.token.time);
             }
         }
-        None
+        // Everything in the overflow is later than anything in the wheel.
+        self.overflow.keys().next().map(TimerToken::time)
     }
 
-    /// Get the full span of time that this can cover.
-    /// Two timers cannot be more than this far apart.
+    /// The number of pending entries.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Get the full span of time that the wheel covers.
+    /// Entries that are further away than this are kept in the overflow.
     /// In practice, this value is less by one amount of the timer granularity.
     #[inline]
     #[allow(clippy::cast_possible_truncation)] // guarded by assertion
@@ -86,6 +126,31 @@ impl<T> Timer<T> {
         (self.cursor + delta) % self.items.len()
     }
 
+    /// Whether `time` is too far away to be held in the wheel.
+    #[inline]
+    fn is_overflow(&self, time: Instant) -> bool {
+        time >= self.now + self.span()
+    }
+
+    /// Put an item into the wheel, which has to be able to hold it.
+    fn insert(&mut self, ti: TimerItem<T>) {
+        let bucket = self.time_bucket(ti.token.time);
+        let ins = match self.items[bucket].binary_search_by_key(&ti.token, TimerItem::token) {
+            Ok(j) | Err(j) => j,
+        };
+        self.items[bucket].insert(ins, ti);
+    }
+
+    /// Move entries from the overflow into the wheel once it can hold them.
+    fn promote(&mut self) {
+        let (end, _) = TimerToken::bounds(self.now + self.span());
+        let later = self.overflow.split_off(&end);
+        let due = mem::replace(&mut self.overflow, later);
+        for (token, item) in due {
+            self.insert(TimerItem { token, item });
+        }
+    }
+
     /// Slide forward in time by `n * self.granularity`.
     #[allow(clippy::cast_possible_truncation)] // guarded by assertion
     fn tick(&mut self, n: usize) {
@@ -100,131 +165,170 @@ impl<T> Timer<T> {
         }
         self.now += self.granularity * (n as u32);
         self.cursor = new;
+        self.promote();
     }
 
-    /// Asserts if the time given is in the past or too far in the future.
-    pub fn add(&mut self, time: Instant, item: T) {
-        assert!(time >= self.now);
-        // Skip forward quickly if there is too large a gap.
-        let short_span = self.span() - self.granularity;
-        if time >= (self.now + self.span() + short_span) {
-            // Assert that there aren't any items.
-            for i in &self.items {
-                assert!(i.is_empty());
-            }
-            self.now = time - short_span;
+    /// Move the wheel forward toward `until`, as far as empty buckets allow.
+    fn advance(&mut self, until: Instant) {
+        if until <= self.now {
+            return;
+        }
+        let delta = self.delta(until);
+        let empty = (0..min(delta, self.items.len()))
+            .take_while(|&i| self.items[self.bucket(i)].is_empty())
+            .count();
+        if empty == self.items.len() {
+            // The wheel is empty, so it can jump, though not past the overflow.
+            self.now = match self.overflow.keys().next() {
+                Some(t) => min(t.time, until),
+                None => until,
+            };
             self.cursor = 0;
+            self.promote();
+        } else if empty > 0 {
+            self.tick(empty);
         }
+    }
 
-        // Adjust time forward the minimum amount necessary.
-        let mut d = self.delta(time);
-        if d >= self.items.len() {
-            self.tick(1 + d - self.items.len());
-            d = self.items.len() - 1;
+    /// Add an entry, returning a token that can be used to cancel it.
+    /// Asserts if the time given is in the past.
+    pub fn add(&mut self, time: Instant, item: T) -> TimerToken {
+        assert!(time >= self.now);
+        let token = TimerToken {
+            time,
+            id: self.next_id,
+        };
+        self.next_id += 1;
+        self.count += 1;
+        if self.is_overflow(time) {
+            self.overflow.insert(token, item);
+        } else {
+            self.insert(TimerItem { token, item });
         }
+        token
+    }
 
-        let bucket = self.bucket(d);
-        let ins = match self.items[bucket].binary_search_by_key(&time, TimerItem::time) {
-            Ok(j) | Err(j) => j,
+    /// Remove the entry that `token` was returned for, if it is still there.
+    pub fn cancel(&mut self, token: TimerToken) -> Option<T> {
+        let item = if self.is_overflow(token.time) {
+            self.overflow.remove(&token)
+        } else if token.time < self.now {
+            None
+        } else {
+            let bucket = self.time_bucket(token.time);
+            match self.items[bucket].binary_search_by_key(&token, TimerItem::token) {
+                Ok(i) => Some(self.items[bucket].remove(i).item),
+                Err(_) => None,
+            }
         };
-        self.items[bucket].insert(ins, TimerItem { time, item });
+        if item.is_some() {
+            self.count -= 1;
+        }
+        item
     }
 
     /// Given knowledge of the time an item was added, remove it.
     /// This requires use of a predicate that identifies matching items.
+    /// If the token for the item is available, `cancel()` is faster.
     pub fn remove<F>(&mut self, time: Instant, mut selector: F) -> Option<T>
     where
         F: FnMut(&T) -> bool,
     {
-        if time < self.now {
-            return None;
-        }
-        let bucket = self.time_bucket(time);
-        let start_index = match self.items[bucket].binary_search_by_key(&time, TimerItem::time) {
-            Ok(idx) => idx,
-            _ => return None,
+        let (first, last) = TimerToken::bounds(time);
+        let item = if self.is_overflow(time) {
+            let token = self
+                .overflow
+                .range(first..=last)
+                .find(|(_, item)| selector(item))
+                .map(|(token, _)| *token);
+            token.and_then(|t| self.overflow.remove(&t))
+        } else if time < self.now {
+            None
+        } else {
+            let bucket = self.time_bucket(time);
+            let start = match self.items[bucket].binary_search_by_key(&first, TimerItem::token) {
+                Ok(j) | Err(j) => j,
+            };
+            let found = self.items[bucket][start..]
+                .iter()
+                .take_while(|ti| ti.token.time == time)
+                .position(|ti| selector(&ti.item));
+            found.map(|i| self.items[bucket].remove(start + i).item)
         };
-        // start_index is just one of potentially many items with the same time.
-        // Search backwards for a match, ...
-        for i in (0..=start_index).rev() {
-            if self.items[bucket][i].time != time {
-                break;
-            }
-            if selector(&self.items[bucket][i].item) {
-                return Some(self.items[bucket].remove(i).item);
-            }
-        }
-        // ... then forwards.
-        for i in (start_index + 1)..self.items[bucket].len() {
-            if self.items[bucket][i].time != time {
-                break;
-            }
-            if selector(&self.items[bucket][i].item) {
-                return Some(self.items[bucket].remove(i).item);
-            }
+        if item.is_some() {
+            self.count -= 1;
         }
-        None
+        item
     }
 
     /// Take the next item, unless there are no items with
     /// a timeout in the past relative to `until`.
     pub fn take_next(&mut self, until: Instant) -> Option<T> {
-        for i in 0..self.items.len() {
-            let idx = self.bucket(i);
-            if !self.items[idx].is_empty() && self.items[idx][0].time <= until {
-                return Some(self.items[idx].remove(0).item);
+        self.advance(until);
+        let first = (0..self.items.len())
+            .map(|i| self.bucket(i))
+            .find(|&idx| !self.items[idx].is_empty());
+        let item = if let Some(idx) = first {
+            if self.items[idx][0].token.time <= until {
+                Some(self.items[idx].remove(0).item)
+            } else {
+                None
             }
+        } else {
+            let (_, last) = TimerToken::bounds(until);
+            let token = self.overflow.range(..=last).next().map(|(t, _)| *t);
+            token.and_then(|t| self.overflow.remove(&t))
+        };
+        if item.is_some() {
+            self.count -= 1;
         }
-        None
+        item
     }
 
     /// Create an iterator that takes all items until the given time.
-    /// Note: Items might be removed even if the iterator is not fully exhausted.
+    /// Note: Items are removed even if the iterator is not fully exhausted.
     pub fn take_until(&mut self, until: Instant) -> impl Iterator<Item = T> {
-        let get_item = move |x: TimerItem<T>| x.item;
-        if until >= self.now + self.span() {
-            // Drain everything, so a clean sweep.
-            let mut empty_items = Vec::with_capacity(self.items.len());
-            empty_items.resize_with(self.items.len(), Vec::default);
-            let mut items = mem::replace(&mut self.items, empty_items);
+        let mut taken = Vec::new();
+        if until < self.now {
+            return taken.into_iter();
+        }
+        let (_, last) = TimerToken::bounds(until);
+        if self.is_overflow(until) {
+            // Drain the whole wheel and whatever is due from the overflow.
+            for i in 0..self.items.len() {
+                let idx = self.bucket(i);
+                taken.extend(self.items[idx].drain(..).map(|ti| ti.item));
+            }
+            let later = self.overflow.split_off(&last);
+            let due = mem::replace(&mut self.overflow, later);
+            taken.extend(due.into_iter().map(|(_, item)| item));
+            // Only an item at exactly `until` with the largest id can be left.
+            if let Some(item) = self.overflow.remove(&last) {
+                taken.push(item);
+            }
             self.now = until;
             self.cursor = 0;
-
-            let tail = items.split_off(self.cursor);
-            return tail.into_iter().chain(items).flatten().map(get_item);
-        }
-
-        // Only returning a partial span, so do it bucket at a time.
-        let delta = self.delta(until);
-        let mut buckets = Vec::with_capacity(delta + 1);
-
-        // First, the whole buckets.
-        for i in 0..delta {
-            let idx = self.bucket(i);
-            buckets.push(mem::replace(&mut self.items[idx], Vec::default()));
-        }
-        self.tick(delta);
-
-        // Now we need to split the last bucket, because there might be
-        // some items with `item.time > until`.
-        let bucket = &mut self.items[self.cursor];
-        let last_idx = match bucket.binary_search_by_key(&until, TimerItem::time) {
-            Ok(mut m) => {
-                // If there are multiple values, the search will hit any of them.
-                // Make sure to get them all.
-                while m < bucket.len() && bucket[m].time == until {
-                    m += 1;
-                }
-                m
+            self.promote();
+        } else {
+            // Only returning a partial span, so do it bucket at a time.
+            let delta = self.delta(until);
+            for i in 0..delta {
+                let idx = self.bucket(i);
+                taken.extend(self.items[idx].drain(..).map(|ti| ti.item));
             }
-            Err(ins) => ins,
-        };
-        let tail = bucket.split_off(last_idx);
-        buckets.push(mem::replace(bucket, tail));
-        // This tomfoolery with the empty vector ensures that
-        // the returned type here matches the one above precisely
-        // without having to invoke the `either` crate.
-        buckets.into_iter().chain(vec![]).flatten().map(get_item)
+            self.tick(delta);
+
+            // Now we need to split the last bucket, because there might be
+            // some items with `item.time > until`.
+            let bucket = &mut self.items[self.cursor];
+            let end = match bucket.binary_search_by_key(&last, TimerItem::token) {
+                Ok(m) => m + 1,
+                Err(ins) => ins,
+            };
+            taken.extend(bucket.drain(..end).map(|ti| ti.item));
+        }
+        self.count -= taken.len();
+        taken.into_iter()
     }
 }
 
@@ -263,9 +367,11 @@ mod test {
         t.add(*NOW, v1);
         t.add(*NOW, v2);
         assert_eq!(*NOW, t.next_time().expect("should have an entry"));
+        assert_eq!(t.len(), 2);
         let values: Vec<_> = t.take_until(*NOW).collect();
         assert!(values.contains(&v1));
         assert!(values.contains(&v2));
+        assert!(t.is_empty());
     }
 
     #[test]
@@ -354,4 +460,59 @@ mod test {
         }
         assert_eq!(None, t.next_time());
     }
+
+    #[test]
+    fn cancel() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let tokens: Vec<_> = TIMES.iter().map(|time| t.add(*NOW + *time, ())).collect();
+        let v1 = t.add(*NOW + TIMES[0], ());
+        assert_eq!(t.len(), TIMES.len() + 1);
+
+        // Only the entry for the token goes, even with others at the same time.
+        assert_eq!(Some(()), t.cancel(v1));
+        assert_eq!(None, t.cancel(v1));
+        assert_eq!(t.len(), TIMES.len());
+        for token in tokens {
+            assert_eq!(Some(()), t.cancel(token));
+        }
+        assert!(t.is_empty());
+        assert_eq!(None, t.next_time());
+    }
+
+    #[test]
+    fn long_delays() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let soon = t.add(*NOW + Duration::from_millis(5), 1);
+        let later = *NOW + Duration::from_millis(250);
+        let far = t.add(*NOW + Duration::from_secs(30), 3);
+        t.add(later, 2);
+        assert_eq!(t.len(), 3);
+        assert_eq!(Some(soon.time()), t.next_time());
+
+        assert_eq!(Some(1), t.take_next(*NOW + Duration::from_millis(10)));
+        assert_eq!(None, t.take_next(later - Duration::from_millis(1)));
+        assert_eq!(Some(later), t.next_time());
+        assert_eq!(Some(2), t.take_next(later));
+
+        // The long delay can still be cancelled once the wheel has moved.
+        assert_eq!(Some(far.time()), t.next_time());
+        assert_eq!(Some(3), t.cancel(far));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn take_next_moves_wheel() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let mut time = *NOW;
+        for i in 0..100 {
+            time += Duration::from_millis(33);
+            t.add(time + Duration::from_millis(150), i);
+            let due: Vec<_> = t.take_until(time).collect();
+            if i >= 5 {
+                assert_eq!(due, vec![i - 5]);
+            }
+            assert_eq!(None, t.take_next(time));
+        }
+        assert_eq!(t.len(), 5);
+    }
 }
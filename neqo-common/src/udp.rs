@@ -0,0 +1,473 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sending and receiving datagrams along with the ancillary data that QUIC
+// can use: the ECN codepoint, the local address and interface, the TTL,
+// segmentation offload, and release times.  This wraps sendmsg and recvmsg on Linux, so that the unsafe
+// parts only need to be written once.
+
+use crate::{Datagram, DatagramBatch, Ecn};
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+// These aren't in all versions of libc.
+const UDP_SEGMENT: c_int = 103;
+const UDP_GRO: c_int = 104;
+const SCM_TXTIME: c_int = 61;
+const IPV6_FLOWINFO_SEND: c_int = 33;
+
+/// Space for control messages, as `u64` so that it is aligned for `cmsghdr`.
+type ControlBuffer = [u64; 24];
+
+fn check(rv: isize) -> io::Result<usize> {
+    usize::try_from(rv).map_err(|_| io::Error::last_os_error())
+}
+
+fn setsockopt(socket: &UdpSocket, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    check(rv as isize).map(|_| ())
+}
+
+/// Ask the kernel for the ancillary data that `recv()` reports.  This turns
+/// on GRO if the kernel supports it.
+pub fn configure(socket: &UdpSocket) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv4() {
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)?;
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+        setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)?;
+    } else {
+        setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+        // Without this, flow labels aren't sent; see `send()`.
+        let _ = setsockopt(socket, libc::IPPROTO_IPV6, IPV6_FLOWINFO_SEND, 1);
+    }
+    // Older kernels don't have GRO, which only costs some efficiency.
+    let _ = setsockopt(socket, libc::SOL_UDP, UDP_GRO, 1);
+    Ok(())
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(a.ip().octets()),
+            };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: a.ip().octets(),
+            };
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Bind a socket to `addr` with SO_REUSEPORT set, so that several sockets
+/// can share the address.  The kernel spreads datagrams across them by
+/// source address, so each peer consistently reaches the same socket.
+pub fn bind_shared(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd as isize)?;
+    // From here, the socket is closed if anything fails.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    setsockopt(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    let (name, namelen) = to_sockaddr(addr);
+    let rv = unsafe {
+        libc::bind(
+            fd,
+            &name as *const libc::sockaddr_storage as *const libc::sockaddr,
+            namelen,
+        )
+    };
+    check(rv as isize)?;
+    Ok(socket)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Convert `t` to nanoseconds on CLOCK_MONOTONIC, which SO_TXTIME uses.  An
+/// `Instant` can't be converted directly, so this goes by how far ahead of
+/// now it is.
+fn monotonic_ns(t: Instant) -> u64 {
+    let now = Instant::now();
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    let base = (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64);
+    let ahead = if t > now {
+        t - now
+    } else {
+        Duration::new(0, 0)
+    };
+    base + u64::try_from(ahead.as_nanos()).unwrap_or(u64::max_value())
+}
+
+/// The ancillary data that arrived with a datagram.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RecvMeta {
+    pub ecn: Ecn,
+    /// The address that the datagram was sent to, which matters if the
+    /// socket is bound to an unspecified address.
+    pub destination: Option<IpAddr>,
+    /// The size of each datagram, if GRO combined several.
+    pub segment_size: Option<usize>,
+    /// The IPv4 TTL or IPv6 hop limit.
+    pub ttl: Option<u8>,
+    /// The index of the interface that the datagram arrived on.
+    pub interface: Option<u32>,
+    /// When `recv()` returned the datagram.
+    pub received: Option<Instant>,
+}
+
+impl RecvMeta {
+    /// Read the control messages that `recvmsg` put in `msg`.
+    unsafe fn decode(msg: &libc::msghdr) -> Self {
+        let mut meta = Self::default();
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while let Some(c) = cmsg.as_ref() {
+            let data = libc::CMSG_DATA(cmsg);
+            match (c.cmsg_level, c.cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => {
+                    meta.ecn = Ecn::from(*data);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    let tclass = ptr::read_unaligned(data as *const c_int);
+                    meta.ecn = Ecn::from(tclass as u8);
+                }
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    let addr = Ipv4Addr::from(info.ipi_addr.s_addr.to_ne_bytes());
+                    meta.destination = Some(IpAddr::V4(addr));
+                    meta.interface = u32::try_from(info.ipi_ifindex).ok();
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    meta.destination = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                    meta.interface = Some(info.ipi6_ifindex);
+                }
+                (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                    let ttl = ptr::read_unaligned(data as *const c_int);
+                    meta.ttl = u8::try_from(ttl).ok();
+                }
+                (libc::SOL_UDP, UDP_GRO) => {
+                    let size = ptr::read_unaligned(data as *const c_int);
+                    meta.segment_size = usize::try_from(size).ok();
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        meta
+    }
+
+    /// Make the datagrams that arrived in `d`, from `src`, on a socket that
+    /// is bound to `local`.
+    pub fn batch(&self, src: SocketAddr, local: SocketAddr, d: &[u8]) -> DatagramBatch {
+        let dst = SocketAddr::new(self.destination.unwrap_or_else(|| local.ip()), local.port());
+        let segment_size = self.segment_size.unwrap_or_else(|| d.len()).max(1);
+        let mut batch = DatagramBatch::received(src, dst, d, segment_size).with_ecn(self.ecn);
+        if let Some(t) = self.received {
+            batch = batch.with_received_time(t);
+        }
+        if let Some(ttl) = self.ttl {
+            batch = batch.with_ttl(ttl);
+        }
+        if let Some(index) = self.interface {
+            batch = batch.with_interface(index);
+        }
+        batch
+    }
+}
+
+/// Receive into `buf`.  With GRO, this can be several datagrams.
+pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<DatagramBatch> {
+    let local = socket.local_addr()?;
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut control: ControlBuffer = [0; 24];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+    let sz = check(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) })?;
+    let src = from_sockaddr(&name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+    let mut meta = unsafe { RecvMeta::decode(&msg) };
+    meta.received = Some(Instant::now());
+    Ok(meta.batch(src, local, &buf[..sz]))
+}
+
+/// Add a control message to `msg`, at `cmsg`, and move `cmsg` on to the
+/// next one.
+unsafe fn put_cmsg<T>(
+    msg: &libc::msghdr,
+    cmsg: &mut *mut libc::cmsghdr,
+    len: &mut usize,
+    level: c_int,
+    ty: c_int,
+    value: T,
+) {
+    let c = *cmsg;
+    assert!(!c.is_null(), "control buffer is too small");
+    (*c).cmsg_level = level;
+    (*c).cmsg_type = ty;
+    (*c).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(c) as *mut T, value);
+    *len += libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize;
+    *cmsg = libc::CMSG_NXTHDR(msg, c);
+}
+
+/// What goes into the control messages when sending.
+struct SendMeta {
+    src: SocketAddr,
+    dst: SocketAddr,
+    tos: u8,
+    flow_label: Option<u32>,
+    interface: Option<u32>,
+    release_time: Option<Instant>,
+    segment_size: Option<usize>,
+}
+
+impl SendMeta {
+    /// Write control messages to the control buffer of `msg`, and return
+    /// how much of it they use.
+    unsafe fn encode(&self, msg: &libc::msghdr) -> usize {
+        let mut len = 0;
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        if self.tos != 0 {
+            let tos = c_int::from(self.tos);
+            let (level, ty) = if self.dst.is_ipv4() {
+                (libc::IPPROTO_IP, libc::IP_TOS)
+            } else {
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+            };
+            put_cmsg(msg, &mut cmsg, &mut len, level, ty, tos);
+        }
+        // This picks the source address and interface, if either is set.
+        let interface = self.interface.unwrap_or(0);
+        let pick = self.interface.is_some() || !self.src.ip().is_unspecified();
+        match (self.src.ip(), self.dst.is_ipv4()) {
+            (IpAddr::V4(ip), true) if pick => {
+                let info = libc::in_pktinfo {
+                    ipi_ifindex: interface as c_int,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(ip.octets()),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                let (level, ty) = (libc::IPPROTO_IP, libc::IP_PKTINFO);
+                put_cmsg(msg, &mut cmsg, &mut len, level, ty, info);
+            }
+            (IpAddr::V6(ip), false) if pick => {
+                let info = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: ip.octets(),
+                    },
+                    ipi6_ifindex: interface,
+                };
+                let (level, ty) = (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO);
+                put_cmsg(msg, &mut cmsg, &mut len, level, ty, info);
+            }
+            _ => {}
+        }
+        if let Some(size) = self.segment_size {
+            let size = u16::try_from(size).unwrap_or(u16::max_value());
+            put_cmsg(msg, &mut cmsg, &mut len, libc::SOL_UDP, UDP_SEGMENT, size);
+        }
+        if let Some(t) = self.release_time {
+            let (level, ty) = (libc::SOL_SOCKET, SCM_TXTIME);
+            put_cmsg(msg, &mut cmsg, &mut len, level, ty, monotonic_ns(t));
+        }
+        len
+    }
+
+    fn send(&self, socket: &UdpSocket, data: &[u8]) -> io::Result<usize> {
+        let (mut name, namelen) = to_sockaddr(&self.dst);
+        if let (Some(label), SocketAddr::V6(_)) = (self.flow_label, self.dst) {
+            let sin6 = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_flowinfo = label.to_be();
+        }
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let mut control: ControlBuffer = [0; 24];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut _ as *mut c_void;
+        msg.msg_namelen = namelen;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+        let len = unsafe { self.encode(&msg) };
+        if len == 0 {
+            msg.msg_control = ptr::null_mut();
+        }
+        msg.msg_controllen = len as _;
+        check(unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) })
+    }
+}
+
+/// Send a datagram, with its ECN and DSCP codepoints, flow label, source
+/// address, and release time.  The source address is only used to choose the
+/// local address when the socket is bound to an unspecified address; the port
+/// is ignored.  Release times need SO_TXTIME to be enabled on the socket.
+/// Linux only sends flow labels that have been leased on the socket with
+/// IPV6_FLOWLABEL_MGR, and fails to send with others.
+pub fn send(socket: &UdpSocket, d: &Datagram) -> io::Result<usize> {
+    SendMeta {
+        src: d.source(),
+        dst: d.destination(),
+        tos: d.tos(),
+        flow_label: d.flow_label(),
+        interface: d.interface(),
+        release_time: d.release_time(),
+        segment_size: None,
+    }
+    .send(socket, d)
+}
+
+/// Send a batch of datagrams in one call.  This uses GSO for batches of more
+/// than one datagram, which needs Linux 4.18 or later.  See `send()`.
+pub fn send_batch(socket: &UdpSocket, batch: &DatagramBatch) -> io::Result<usize> {
+    SendMeta {
+        src: batch.source(),
+        dst: batch.destination(),
+        tos: batch.tos(),
+        flow_label: batch.flow_label(),
+        interface: batch.interface(),
+        release_time: batch.release_time(),
+        segment_size: if batch.count() > 1 {
+            Some(batch.segment_size())
+        } else {
+            None
+        },
+    }
+    .send(socket, batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind() -> UdpSocket {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        configure(&s).unwrap();
+        s
+    }
+
+    #[test]
+    fn shared_port() {
+        let a = bind_shared(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = a.local_addr().unwrap();
+        let b = bind_shared(&addr).unwrap();
+        assert_eq!(b.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn ecn_and_destination() {
+        let (a, b) = (bind(), bind());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let d = Datagram::new(a_addr, b_addr, vec![1; 100]).with_ecn(Ecn::Ect0);
+        assert_eq!(send(&a, &d).unwrap(), 100);
+
+        let mut buf = [0; 2048];
+        let batch = recv(&b, &mut buf).unwrap();
+        assert_eq!(batch.count(), 1);
+        assert_eq!(batch.source(), a_addr);
+        assert_eq!(batch.destination(), b_addr);
+        assert_eq!(batch.ecn(), Ecn::Ect0);
+        assert!(batch.ttl().is_some());
+        assert!(batch.interface().is_some());
+        assert!(batch.received_time().is_some());
+        assert_eq!(&batch[..], &[1; 100][..]);
+    }
+
+    #[test]
+    fn meta_batch() {
+        let meta = RecvMeta {
+            ecn: Ecn::Ce,
+            destination: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            segment_size: Some(10),
+            ttl: Some(57),
+            interface: Some(2),
+            received: None,
+        };
+        let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443);
+        let batch = meta.batch(src, local, &[0; 25]);
+        assert_eq!(batch.count(), 3);
+        assert_eq!(batch.destination(), "192.0.2.1:443".parse().unwrap());
+        assert!(batch.iter().all(|d| d.ecn() == Ecn::Ce));
+        assert!(batch.iter().all(|d| d.ttl() == Some(57)));
+        assert!(batch.iter().all(|d| d.interface() == Some(2)));
+        assert_eq!(batch.received_time(), None);
+    }
+}
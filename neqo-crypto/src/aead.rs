@@ -5,15 +5,23 @@
 // except according to those terms.
 
 use crate::constants::*;
-use crate::err::{Error, Res};
-use crate::p11::{PK11SymKey, SymKey};
+use crate::err::{secstatus_to_res, Error, Res};
+use crate::p11::{
+    import_sym_key, PK11SymKey, PK11_Decrypt, PK11_Encrypt, PK11_SignWithSymKey, SECItem,
+    SECItemType, SymKey, CKA_DECRYPT, CKA_ENCRYPT, CKA_SIGN, CKM_AES_GCM,
+    CKM_NSS_CHACHA20_POLY1305, CKM_SHA256_HMAC, CKM_SHA384_HMAC, CK_ATTRIBUTE_TYPE,
+    CK_MECHANISM_TYPE,
+};
 use crate::ssl;
 use crate::ssl::{PRUint16, PRUint64, PRUint8, SSLAeadContext};
 
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
+use std::ffi::CString;
 use std::fmt;
+use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::os::raw::{c_char, c_uint};
+use std::os::raw::{c_char, c_uint, c_ulong};
 use std::ptr::{null_mut, NonNull};
 
 experimental_api!(SSL_MakeAead(
@@ -48,22 +56,253 @@ experimental_api!(SSL_AeadDecrypt(
 ));
 experimental_api!(SSL_DestroyAead(ctx: *mut SSLAeadContext));
 scoped_ptr!(AeadContext, SSLAeadContext, SSL_DestroyAead);
+// The context only holds keys and an IV, which NSS allows any thread to use.
+// That makes `Aead` `Send`; `HpKey` is `Send` because `SymKey` is.
+unsafe impl Send for AeadContext {}
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Put the pieces of the AAD together.  The AAD is short, so copying it is
+/// cheap, but the copy is avoided if there is only one piece.
+fn gather(aad: &[&[u8]]) -> Cow<[u8]> {
+    if aad.len() == 1 {
+        Cow::Borrowed(aad[0])
+    } else {
+        Cow::Owned(aad.concat())
+    }
+}
+
+/// Whether NSS has the experimental functions for AEAD.  Some builds of NSS,
+/// like those in some distributions, don't.
+fn experimental_aead_available() -> bool {
+    let name = CString::new("SSL_MakeAead").unwrap();
+    !unsafe { ssl::SSL_GetExperimentalAPI(name.as_ptr()) }.is_null()
+}
+
+/// HKDF-Expand-Label from RFC 8446 with an empty context.  This only makes
+/// outputs that are no longer than the output of the hash function.
+fn expand_label(hmac: CK_MECHANISM_TYPE, secret: &[u8], label: &str, len: usize) -> Res<Vec<u8>> {
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(label.len() + 5);
+    info.extend_from_slice(&u16::try_from(len)?.to_be_bytes());
+    info.push(u8::try_from(label.len())?);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0); // The context is empty.
+    info.push(1); // The counter for the first block of HKDF-Expand.
+
+    let key = import_sym_key(hmac, CK_ATTRIBUTE_TYPE::from(CKA_SIGN), secret)?;
+    let mut out = vec![0; 64];
+    let mut out_item = SECItem {
+        type_: SECItemType::siBuffer,
+        data: out.as_mut_ptr(),
+        len: c_uint::try_from(out.len())?,
+    };
+    let info_item = SECItem {
+        type_: SECItemType::siBuffer,
+        data: info.as_ptr() as *mut u8,
+        len: c_uint::try_from(info.len())?,
+    };
+    secstatus_to_res(unsafe {
+        PK11_SignWithSymKey(*key, hmac, null_mut(), &mut out_item, &info_item)
+    })?;
+    if (out_item.len as usize) < len {
+        return Err(Error::HkdfError);
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// The parameters for AES-GCM, in the form that all versions of NSS accept.
+#[repr(C)]
+struct GcmParams {
+    iv: *mut u8,
+    iv_len: c_ulong,
+    aad: *mut u8,
+    aad_len: c_ulong,
+    tag_bits: c_ulong,
+}
+
+/// The parameters for ChaCha20-Poly1305.
+#[repr(C)]
+struct ChaChaParams {
+    nonce: *mut u8,
+    nonce_len: c_ulong,
+    aad: *mut u8,
+    aad_len: c_ulong,
+    tag_len: c_ulong,
+}
+
+/// An AEAD that only uses the stable PK11 functions of NSS.  This derives the
+/// key and IV itself, then uses a new nonce for each record.
+struct Pk11Aead {
+    mech: CK_MECHANISM_TYPE,
+    encrypt_key: SymKey,
+    decrypt_key: SymKey,
+    iv: [u8; NONCE_LEN],
+}
+
+/// The AEAD mechanism, the HMAC for deriving keys, and the key length, for
+/// `cipher`.
+fn mechanisms(cipher: Cipher) -> Res<(CK_MECHANISM_TYPE, CK_MECHANISM_TYPE, usize)> {
+    let (mech, hmac, key_len) = match cipher {
+        TLS_AES_128_GCM_SHA256 => (CKM_AES_GCM, CKM_SHA256_HMAC, 16),
+        TLS_AES_256_GCM_SHA384 => (CKM_AES_GCM, CKM_SHA384_HMAC, 32),
+        TLS_CHACHA20_POLY1305_SHA256 => (CKM_NSS_CHACHA20_POLY1305, CKM_SHA256_HMAC, 32),
+        _ => return Err(Error::UnsupportedCipher),
+    };
+    Ok((
+        CK_MECHANISM_TYPE::from(mech),
+        CK_MECHANISM_TYPE::from(hmac),
+        key_len,
+    ))
+}
+
+impl Pk11Aead {
+    fn new(version: Version, cipher: Cipher, secret: &SymKey, prefix: &str) -> Res<Self> {
+        if version != TLS_VERSION_1_3 {
+            return Err(Error::UnsupportedVersion);
+        }
+        let (_, hmac, key_len) = mechanisms(cipher)?;
+        let secret = secret.as_bytes()?;
+        let key = expand_label(hmac, secret, &format!("{}key", prefix), key_len)?;
+        let iv = expand_label(hmac, secret, &format!("{}iv", prefix), NONCE_LEN)?;
+        Self::with_key(cipher, &key, &iv)
+    }
+
+    fn with_key(cipher: Cipher, key: &[u8], iv: &[u8]) -> Res<Self> {
+        let (mech, _, key_len) = mechanisms(cipher)?;
+        if key.len() != key_len || iv.len() != NONCE_LEN {
+            return Err(Error::InvalidInput);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(iv);
+        Ok(Self {
+            mech,
+            encrypt_key: import_sym_key(mech, CK_ATTRIBUTE_TYPE::from(CKA_ENCRYPT), key)?,
+            decrypt_key: import_sym_key(mech, CK_ATTRIBUTE_TYPE::from(CKA_DECRYPT), key)?,
+            iv: nonce,
+        })
+    }
+
+    fn nonce(&self, count: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (n, c) in nonce[NONCE_LEN - 8..].iter_mut().zip(&count.to_be_bytes()) {
+            *n ^= c;
+        }
+        nonce
+    }
+
+    /// Encrypt or decrypt.  `input` and `output` can be the same buffer.
+    unsafe fn crypt(
+        &self,
+        encrypt: bool,
+        count: u64,
+        aad: &[u8],
+        input: (*const u8, usize),
+        output: (*mut u8, usize),
+    ) -> Res<usize> {
+        let mut nonce = self.nonce(count);
+        let mut gcm = GcmParams {
+            iv: nonce.as_mut_ptr(),
+            iv_len: NONCE_LEN as c_ulong,
+            aad: aad.as_ptr() as *mut u8,
+            aad_len: c_ulong::try_from(aad.len())?,
+            tag_bits: (TAG_LEN * 8) as c_ulong,
+        };
+        let mut chacha = ChaChaParams {
+            nonce: nonce.as_mut_ptr(),
+            nonce_len: NONCE_LEN as c_ulong,
+            aad: aad.as_ptr() as *mut u8,
+            aad_len: c_ulong::try_from(aad.len())?,
+            tag_len: TAG_LEN as c_ulong,
+        };
+        let mut param = if self.mech == CK_MECHANISM_TYPE::from(CKM_AES_GCM) {
+            SECItem {
+                type_: SECItemType::siBuffer,
+                data: &mut gcm as *mut GcmParams as *mut u8,
+                len: c_uint::try_from(mem::size_of::<GcmParams>())?,
+            }
+        } else {
+            SECItem {
+                type_: SECItemType::siBuffer,
+                data: &mut chacha as *mut ChaChaParams as *mut u8,
+                len: c_uint::try_from(mem::size_of::<ChaChaParams>())?,
+            }
+        };
+
+        let mut l: c_uint = 0;
+        let f = if encrypt { PK11_Encrypt } else { PK11_Decrypt };
+        let key = if encrypt {
+            &self.encrypt_key
+        } else {
+            &self.decrypt_key
+        };
+        secstatus_to_res(f(
+            **key,
+            self.mech,
+            &mut param,
+            output.0,
+            &mut l,
+            c_uint::try_from(output.1)?,
+            input.0,
+            c_uint::try_from(input.1)?,
+        ))?;
+        Ok(l.try_into()?)
+    }
+}
+
+enum AeadImpl {
+    Experimental(AeadContext),
+    Pk11(Pk11Aead),
+}
 
 pub struct Aead {
-    ctx: AeadContext,
+    imp: AeadImpl,
 }
 
 impl Aead {
+    /// Make an AEAD for the record protection keys that are derived from
+    /// `secret` with labels that start with `prefix`.  This uses the
+    /// experimental functions in NSS if they are there, otherwise the same
+    /// thing is done with stable PK11 functions; see `new_pk11()`.
     pub fn new<S: Into<String>>(
         version: Version,
         cipher: Cipher,
         secret: &SymKey,
         prefix: S,
     ) -> Res<Self> {
+        if !experimental_aead_available() {
+            return Self::new_pk11(version, cipher, secret, prefix);
+        }
         let s: *mut PK11SymKey = **secret;
         unsafe { Self::from_raw(version, cipher, s, prefix) }
     }
 
+    /// Like `new()`, but only use the stable PK11 functions in NSS.  This
+    /// needs the value of `secret`, so it doesn't work if that can't be
+    /// extracted, such as in FIPS mode.
+    pub fn new_pk11<S: Into<String>>(
+        version: Version,
+        cipher: Cipher,
+        secret: &SymKey,
+        prefix: S,
+    ) -> Res<Self> {
+        let prefix_str = prefix.into();
+        Ok(Self {
+            imp: AeadImpl::Pk11(Pk11Aead::new(version, cipher, secret, &prefix_str)?),
+        })
+    }
+
+    /// Make an AEAD from a `key` and `iv` that are already known, rather than
+    /// derived from a secret.  QUIC uses this for the Retry Integrity Tag,
+    /// where the key and IV are fixed.
+    pub fn with_key(cipher: Cipher, key: &[u8], iv: &[u8]) -> Res<Self> {
+        Ok(Self {
+            imp: AeadImpl::Pk11(Pk11Aead::with_key(cipher, key, iv)?),
+        })
+    }
+
     unsafe fn from_raw<S: Into<String>>(
         version: Version,
         cipher: Cipher,
@@ -83,12 +322,51 @@ impl Aead {
         )?;
         match NonNull::new(ctx) {
             Some(ctx_ptr) => Ok(Self {
-                ctx: AeadContext::new(ctx_ptr),
+                imp: AeadImpl::Experimental(AeadContext::new(ctx_ptr)),
             }),
             None => Err(Error::InternalError),
         }
     }
 
+    /// The number of bytes that encryption adds to the input.
+    pub fn expansion(&self) -> usize {
+        TAG_LEN
+    }
+
+    /// Encrypt or decrypt with `input` and `output`, which can be the same
+    /// buffer.  This returns the length of the output.
+    unsafe fn crypt(
+        &self,
+        encrypt: bool,
+        count: u64,
+        aad: &[u8],
+        input: (*const u8, usize),
+        output: (*mut u8, usize),
+    ) -> Res<usize> {
+        let ctx = match &self.imp {
+            AeadImpl::Experimental(ctx) => ctx,
+            AeadImpl::Pk11(a) => return a.crypt(encrypt, count, aad, input, output),
+        };
+        let f = if encrypt {
+            SSL_AeadEncrypt
+        } else {
+            SSL_AeadDecrypt
+        };
+        let mut l: c_uint = 0;
+        f(
+            *ctx.deref(),
+            count,
+            aad.as_ptr(),
+            c_uint::try_from(aad.len())?,
+            input.0,
+            c_uint::try_from(input.1)?,
+            output.0,
+            &mut l,
+            c_uint::try_from(output.1)?,
+        )?;
+        Ok(l.try_into()?)
+    }
+
     pub fn encrypt<'a>(
         &self,
         count: u64,
@@ -96,21 +374,16 @@ impl Aead {
         input: &[u8],
         output: &'a mut [u8],
     ) -> Res<&'a [u8]> {
-        let mut l: c_uint = 0;
-        unsafe {
-            SSL_AeadEncrypt(
-                *self.ctx.deref(),
+        let l = unsafe {
+            self.crypt(
+                true,
                 count,
-                aad.as_ptr(),
-                c_uint::try_from(aad.len())?,
-                input.as_ptr(),
-                c_uint::try_from(input.len())?,
-                output.as_mut_ptr(),
-                &mut l,
-                c_uint::try_from(output.len())?,
+                aad,
+                (input.as_ptr(), input.len()),
+                (output.as_mut_ptr(), output.len()),
             )
         }?;
-        Ok(&output[0..(l.try_into().unwrap())])
+        Ok(&output[0..l])
     }
 
     pub fn decrypt<'a>(
@@ -120,21 +393,59 @@ impl Aead {
         input: &[u8],
         output: &'a mut [u8],
     ) -> Res<&'a [u8]> {
-        let mut l: c_uint = 0;
-        unsafe {
-            SSL_AeadDecrypt(
-                *self.ctx.deref(),
+        let l = unsafe {
+            self.crypt(
+                false,
                 count,
-                aad.as_ptr(),
-                c_uint::try_from(aad.len())?,
-                input.as_ptr(),
-                c_uint::try_from(input.len())?,
-                output.as_mut_ptr(),
-                &mut l,
-                c_uint::try_from(output.len())?,
+                aad,
+                (input.as_ptr(), input.len()),
+                (output.as_mut_ptr(), output.len()),
             )
         }?;
-        Ok(&output[0..(l.try_into().unwrap())])
+        Ok(&output[0..l])
+    }
+
+    /// Encrypt `data` in place.  The plaintext is all but the last
+    /// `expansion()` bytes of `data`, which are overwritten with the
+    /// authentication tag.  The AAD is the concatenation of the slices in
+    /// `aad`, so a header that is in pieces doesn't need to be put together
+    /// first.  This returns all of `data`, now the ciphertext.
+    pub fn encrypt_in_place<'a>(
+        &self,
+        count: u64,
+        aad: &[&[u8]],
+        data: &'a mut [u8],
+    ) -> Res<&'a mut [u8]> {
+        if data.len() < TAG_LEN {
+            return Err(Error::AeadError);
+        }
+        let aad = gather(aad);
+        let ptr = data.as_mut_ptr();
+        let l = unsafe {
+            self.crypt(
+                true,
+                count,
+                &aad,
+                (ptr, data.len() - TAG_LEN),
+                (ptr, data.len()),
+            )
+        }?;
+        Ok(&mut data[..l])
+    }
+
+    /// Decrypt `data` in place, with the AAD from the slices in `aad`.  This
+    /// returns the part of `data` that holds the plaintext, which is
+    /// `expansion()` bytes shorter than the ciphertext.
+    pub fn decrypt_in_place<'a>(
+        &self,
+        count: u64,
+        aad: &[&[u8]],
+        data: &'a mut [u8],
+    ) -> Res<&'a mut [u8]> {
+        let aad = gather(aad);
+        let ptr = data.as_mut_ptr();
+        let l = unsafe { self.crypt(false, count, &aad, (ptr, data.len()), (ptr, data.len())) }?;
+        Ok(&mut data[..l])
     }
 }
 
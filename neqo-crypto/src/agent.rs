@@ -9,23 +9,28 @@ pub use crate::agentio::{Record, RecordList};
 use crate::assert_initialized;
 use crate::auth::AuthenticationStatus;
 pub use crate::cert::CertificateInfo;
+use crate::certcomp::{self, CertificateCompressor};
 use crate::constants::*;
-use crate::err::{is_blocked, secstatus_to_res, Error, PRErrorCode, Res};
+use crate::err::{
+    self, is_blocked, secstatus_to_res, Error, PRErrorCode, PR_GetError, PR_SetError, Res,
+};
 use crate::ext::{ExtensionHandler, ExtensionTracker};
 use crate::p11;
+use crate::pem;
 use crate::prio;
 use crate::replay::AntiReplay;
-use crate::secrets::SecretHolder;
+use crate::secrets::{keylog_label, SecretDirection, SecretHolder, SecretListener};
 use crate::ssl::{self, PRBool};
 use crate::time::{PRTime, Time};
 
 use neqo_common::{qdebug, qinfo, qwarn};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::ffi::CString;
 use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::os::raw::{c_uint, c_void};
+use std::os::raw::{c_char, c_uint, c_void};
 use std::ptr::{null, null_mut, NonNull};
 use std::rc::Rc;
 use std::time::Instant;
@@ -140,13 +145,15 @@ pub struct SecretAgentInfo {
     cipher: Cipher,
     group: Group,
     resumed: bool,
+    external_psk: bool,
     early_data: bool,
     alpn: Option<String>,
     signature_scheme: SignatureScheme,
+    client_certificate: Option<Vec<u8>>,
 }
 
 impl SecretAgentInfo {
-    fn new(fd: *mut ssl::PRFileDesc) -> Res<Self> {
+    fn new(fd: *mut ssl::PRFileDesc, is_server: bool) -> Res<Self> {
         let mut info: ssl::SSLChannelInfo = unsafe { mem::uninitialized() };
         secstatus_to_res(unsafe {
             ssl::SSL_GetChannelInfo(
@@ -160,9 +167,15 @@ impl SecretAgentInfo {
             cipher: info.cipherSuite as Cipher,
             group: Group::try_from(info.keaGroup)?,
             resumed: info.resumed != 0,
+            external_psk: info.pskType == ssl::SSLPskType::ssl_psk_external,
             early_data: info.earlyDataAccepted != 0,
             alpn: get_alpn(fd, false)?,
             signature_scheme: SignatureScheme::try_from(info.signatureScheme)?,
+            client_certificate: if is_server {
+                CertificateInfo::new(fd).and_then(|mut certs| (&mut certs).next().map(Vec::from))
+            } else {
+                None
+            },
         })
     }
 
@@ -178,6 +191,10 @@ impl SecretAgentInfo {
     pub fn resumed(&self) -> bool {
         self.resumed
     }
+    /// Whether the handshake used an external pre-shared key.
+    pub fn external_psk(&self) -> bool {
+        self.external_psk
+    }
     pub fn early_data_accepted(&self) -> bool {
         self.early_data
     }
@@ -187,6 +204,69 @@ impl SecretAgentInfo {
     pub fn signature_scheme(&self) -> SignatureScheme {
         self.signature_scheme
     }
+    /// On a server, the certificate that the client authenticated with, if
+    /// it sent one.  This is the DER encoding of the end-entity certificate.
+    pub fn client_certificate(&self) -> Option<&[u8]> {
+        self.client_certificate.as_ref().map(Vec::as_slice)
+    }
+}
+
+/// What a client can learn from a resumption token, such as whether it is
+/// worth using.  The application data that the server put in the session
+/// ticket with `Server::send_ticket()` isn't available: it is encrypted so
+/// that only the server can read it, which it does with a `ZeroRttChecker`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumptionTokenInfo {
+    alpn: Option<String>,
+    max_early_data: u32,
+    expiration: Instant,
+}
+
+impl ResumptionTokenInfo {
+    /// Decode a token from `Client::resumption_token()`.
+    pub fn decode(token: &[u8]) -> Res<Self> {
+        let mut info: ssl::SSLResumptionTokenInfo = unsafe { mem::uninitialized() };
+        unsafe {
+            ssl::SSL_GetResumptionTokenInfo(
+                token.as_ptr(),
+                c_uint::try_from(token.len())?,
+                &mut info,
+                c_uint::try_from(mem::size_of::<ssl::SSLResumptionTokenInfo>())?,
+            )
+        }?;
+        let alpn = if info.alpnSelection.is_null() {
+            None
+        } else {
+            let alpn = unsafe {
+                std::slice::from_raw_parts(info.alpnSelection, info.alpnSelectionLen as usize)
+            };
+            String::from_utf8(alpn.to_vec()).ok()
+        };
+        let max_early_data = info.maxEarlyData;
+        let expiration = Time::try_from(info.expirationTime);
+        unsafe { ssl::SSL_DestroyResumptionTokenInfo(&mut info) }?;
+        Ok(Self {
+            alpn,
+            max_early_data,
+            expiration: expiration?.into(),
+        })
+    }
+
+    /// The application protocol that was used when the ticket was issued.
+    pub fn alpn(&self) -> Option<&String> {
+        self.alpn.as_ref()
+    }
+
+    /// How much early data the server accepts, if any.  0-RTT is only
+    /// possible if this isn't zero.
+    pub fn max_early_data(&self) -> u32 {
+        self.max_early_data
+    }
+
+    /// When the server stops accepting the ticket.
+    pub fn expiration(&self) -> Instant {
+        self.expiration
+    }
 }
 
 /// `SecretAgent` holds the common parts of client and server.
@@ -211,6 +291,15 @@ pub struct SecretAgent {
 
     /// Whether or not EndOfEarlyData should be suppressed.
     no_eoed: bool,
+    /// The number of session tickets that a server sends when the handshake
+    /// completes, and the application data that goes in them.
+    tickets: usize,
+    ticket_extra: Vec<u8>,
+
+    is_server: bool,
+    /// The random value from the ClientHello, which identifies secrets.
+    client_random: Option<[u8; 32]>,
+    secret_listener: Option<Rc<dyn SecretListener>>,
 }
 
 impl SecretAgent {
@@ -230,6 +319,12 @@ impl SecretAgent {
             inf: None,
 
             no_eoed: false,
+            tickets: 0,
+            ticket_extra: Vec::new(),
+
+            is_server: false,
+            client_random: None,
+            secret_listener: None,
         };
         agent.create_fd()?;
         Ok(agent)
@@ -302,6 +397,7 @@ impl SecretAgent {
 
     // Ready this for connecting.
     fn ready(&mut self, is_server: bool) -> Res<()> {
+        self.is_server = is_server;
         secstatus_to_res(unsafe {
             ssl::SSL_AuthCertificateHook(
                 self.fd,
@@ -337,6 +433,7 @@ impl SecretAgent {
         self.set_option(ssl::Opt::Locking, false)?;
         self.set_option(ssl::Opt::Tickets, false)?;
         self.set_option(ssl::Opt::OcspStapling, true)?;
+        self.set_option(ssl::Opt::SignedCertificateTimestamps, true)?;
         Ok(())
     }
 
@@ -348,6 +445,8 @@ impl SecretAgent {
         secstatus_to_res(unsafe { ssl::SSL_VersionRangeSet(self.fd, &range) })
     }
 
+    /// Enable only the cipher suites in `ciphers`.  This doesn't change the order
+    /// of preference, see `set_cipher_order()` for that.
     pub fn enable_ciphers(&mut self, ciphers: &[Cipher]) -> Res<()> {
         let all_ciphers = unsafe { ssl::SSL_GetImplementedCiphers() };
         let cipher_count = unsafe { ssl::SSL_GetNumImplementedCiphers() } as usize;
@@ -366,6 +465,20 @@ impl SecretAgent {
         Ok(())
     }
 
+    /// Enable only the cipher suites in `ciphers`, preferring them in the order given.
+    pub fn set_cipher_order(&mut self, ciphers: &[Cipher]) -> Res<()> {
+        self.enable_ciphers(ciphers)?;
+        unsafe {
+            ssl::SSL_CipherSuiteOrderSet(
+                self.fd,
+                ciphers.as_ptr(),
+                c_uint::try_from(ciphers.len())?,
+            )
+        }
+    }
+
+    /// Set the key exchange groups that are supported, in order of preference.
+    /// A client sends a key share for the first of these.
     pub fn set_groups(&mut self, groups: &[Group]) -> Res<()> {
         // SSLNamedGroup is a different size to Group, so copy one by one.
         let group_vec: Vec<_> = groups
@@ -379,7 +492,52 @@ impl SecretAgent {
         })
     }
 
+    /// Authenticate with an external pre-shared key, which is named by
+    /// `identity`, instead of with certificates.  The key is used with `hash`,
+    /// so only cipher suites that use that hash function can be negotiated.
+    /// Both peers need the same key.  A server that has no certificates only
+    /// completes handshakes that use the key.  Only one key can be added.
+    pub fn add_external_psk(
+        &mut self,
+        identity: &[u8],
+        psk: &p11::SymKey,
+        hash: HashType,
+    ) -> Res<()> {
+        unsafe {
+            ssl::SSL_AddExternalPsk(
+                self.fd,
+                **psk,
+                identity.as_ptr(),
+                c_uint::try_from(identity.len())?,
+                ssl::SSLHashType::Type::from(hash),
+            )
+        }
+    }
+
+    /// Set the signature schemes that are supported, in order of preference.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Res<()> {
+        // Like SSLNamedGroup, SSLSignatureScheme is a different size.
+        let scheme_vec: Vec<_> = schemes
+            .iter()
+            .map(|&s| ssl::SSLSignatureScheme::Type::from(s))
+            .collect();
+
+        let ptr = scheme_vec.as_slice().as_ptr();
+        secstatus_to_res(unsafe {
+            ssl::SSL_SignatureSchemePrefSet(self.fd, ptr, c_uint::try_from(scheme_vec.len())?)
+        })
+    }
+
     /// Set TLS options.
+    /// Enable certificate compression with `T`.  Each side offers the
+    /// algorithms that it has enabled, and a certificate is compressed with
+    /// one that its receiver offered.  Call this for each algorithm, in order
+    /// of preference.  This fails if NSS doesn't support certificate
+    /// compression.
+    pub fn enable_certificate_compression<T: CertificateCompressor>(&mut self) -> Res<()> {
+        certcomp::enable::<T>(self.fd)
+    }
+
     pub fn set_option(&mut self, opt: ssl::Opt, value: bool) -> Res<()> {
         secstatus_to_res(unsafe {
             ssl::SSL_OptionSet(self.fd, opt.as_int(), opt.map_enabled(value))
@@ -455,6 +613,51 @@ impl SecretAgent {
         Ok(())
     }
 
+    /// Pass secrets to `listener` as they become available.  This only works
+    /// with `handshake_raw()`, and needs to be set before the handshake
+    /// starts, or some secrets will be missed.
+    pub fn set_secret_listener(&mut self, listener: Rc<dyn SecretListener>) {
+        self.secret_listener = Some(listener);
+    }
+
+    /// Take the random value from a ClientHello, which starts with the
+    /// message type (1), a three byte length, and legacy_version.
+    fn capture_client_random(&mut self, rec: &Record) {
+        if self.client_random.is_none()
+            && rec.epoch == 0
+            && rec.ct == 22
+            && rec.data.len() >= 38
+            && rec.data[0] == 1
+        {
+            let mut random = [0; 32];
+            random.copy_from_slice(&rec.data[6..38]);
+            self.client_random = Some(random);
+        }
+    }
+
+    /// Tell the listener about any new secrets.
+    fn report_secrets(&mut self) {
+        let new = self.secrets.take_new();
+        let (listener, random) = match (&self.secret_listener, &self.client_random) {
+            (Some(l), Some(r)) => (l, r),
+            _ => return,
+        };
+        for (dir, epoch) in new {
+            let label = match keylog_label(self.is_server, dir, epoch) {
+                Some(label) => label,
+                None => continue,
+            };
+            let key = match dir {
+                SecretDirection::Read => self.secrets.read().get(epoch),
+                SecretDirection::Write => self.secrets.write().get(epoch),
+            };
+            match key.map(p11::SymKey::as_bytes) {
+                Some(Ok(secret)) => listener.secret(label, random, secret),
+                _ => qwarn!([self] "Unable to export {}", label),
+            }
+        }
+    }
+
     // This function tracks whether handshake() or handshake_raw() was used
     // and prevents the other from being used.
     fn set_raw(&mut self, r: bool) -> Res<()> {
@@ -488,6 +691,42 @@ impl SecretAgent {
         SecretAgentPreInfo::new(self.fd)
     }
 
+    /// Export keying material using the TLS exporter (RFC 8446, Section 7.5).
+    /// This only works once the handshake is complete.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        let (has_context, context) = match context {
+            Some(c) => (true, c),
+            None => (false, &[][..]),
+        };
+        let mut out = vec![0; len];
+        secstatus_to_res(unsafe {
+            ssl::SSL_ExportKeyingMaterial(
+                self.fd,
+                label.as_ptr() as *const c_char,
+                c_uint::try_from(label.len())?,
+                has_context as PRBool,
+                context.as_ptr(),
+                c_uint::try_from(context.len())?,
+                out.as_mut_ptr(),
+                c_uint::try_from(out.len())?,
+            )
+        })?;
+        Ok(out)
+    }
+
+    /// Get the `tls-exporter` channel binding value (RFC 9266).
+    /// This only works once the handshake is complete.
+    pub fn channel_binding(&self) -> Res<Vec<u8>> {
+        const CHANNEL_BINDING_LABEL: &str = "EXPORTER-Channel-Binding";
+        const CHANNEL_BINDING_LEN: usize = 32;
+        self.export_keying_material(CHANNEL_BINDING_LABEL, Some(&[]), CHANNEL_BINDING_LEN)
+    }
+
     /// Get the peer's certificate chain.
     pub fn peer_certificate(&self) -> Option<CertificateInfo> {
         CertificateInfo::new(self.fd)
@@ -524,7 +763,7 @@ impl SecretAgent {
             }
         } else {
             self.capture_error(res)?;
-            let info = self.capture_error(SecretAgentInfo::new(self.fd))?;
+            let info = self.capture_error(SecretAgentInfo::new(self.fd, self.is_server))?;
             HandshakeState::Complete(info)
         };
         qinfo!([self] "state -> {:?}", self.state);
@@ -542,6 +781,7 @@ impl SecretAgent {
         *self.now = Time::from(now).try_into()?;
         self.set_raw(false)?;
 
+        let was_connected = self.state.connected();
         let rv = {
             // Within this scope, _h maintains a mutable reference to self.io.
             let _h = self.io.wrap(input);
@@ -554,11 +794,34 @@ impl SecretAgent {
         };
         // Take before updating state so that we leave the output buffer empty
         // even if there is an error.
-        let output = self.io.take_output();
+        let mut output = self.io.take_output();
         self.update_state(secstatus_to_res(rv))?;
+        if !was_connected {
+            let rv = self.send_tickets();
+            output.append(&mut self.io.take_output());
+            rv?;
+        }
         Ok(output)
     }
 
+    /// Send the session tickets that a server sends when the handshake
+    /// completes.  This does nothing if the handshake isn't complete.
+    fn send_tickets(&mut self) -> Res<()> {
+        if !self.is_server || !self.state.connected() {
+            return Ok(());
+        }
+        for _ in 0..self.tickets {
+            unsafe {
+                ssl::SSL_SendSessionTicket(
+                    self.fd,
+                    self.ticket_extra.as_ptr(),
+                    c_uint::try_from(self.ticket_extra.len())?,
+                )
+            }?;
+        }
+        Ok(())
+    }
+
     /// Setup to receive records for raw handshake functions.
     fn setup_raw(&mut self) -> Res<Box<RecordList>> {
         self.set_raw(true)?;
@@ -602,6 +865,7 @@ impl SecretAgent {
     // If you send data from multiple epochs, you might end up being sad.
     pub fn handshake_raw(&mut self, now: Instant, input: Option<Record>) -> Res<RecordList> {
         *self.now = Time::from(now).try_into()?;
+        let was_connected = self.state.connected();
         let mut records = self.setup_raw()?;
 
         // Fire off any authentication we might need to complete.
@@ -615,6 +879,9 @@ impl SecretAgent {
 
         // Feed in any records.
         if let Some(rec) = input {
+            if self.is_server {
+                self.capture_client_random(&rec);
+            }
             if rec.epoch == 2 {
                 self.inject_eoed()?;
             }
@@ -623,7 +890,16 @@ impl SecretAgent {
 
         // Drive the handshake once more.
         let rv = secstatus_to_res(unsafe { ssl::SSL_ForceHandshake(self.fd) });
+        if !self.is_server {
+            if let Some(ch) = records.first() {
+                self.capture_client_random(ch);
+            }
+        }
+        self.report_secrets();
         self.update_state(rv)?;
+        if !was_connected {
+            self.send_tickets()?;
+        }
 
         if self.no_eoed {
             records.remove_eoed();
@@ -656,9 +932,15 @@ impl ::std::fmt::Display for SecretAgent {
 #[derive(Debug)]
 pub struct Client {
     agent: SecretAgent,
+    /// The name that the server certificate is checked against.
+    server_name: String,
 
     /// Records the last resumption token.
     resumption: Box<Option<Vec<u8>>>,
+    /// The certificate to send if the server asks for one.
+    certificate: Box<Option<Credential>>,
+    /// The application policy for checking the server certificate, if any.
+    verify: Option<Box<CertificateVerifyState>>,
 }
 
 impl Client {
@@ -669,7 +951,10 @@ impl Client {
         agent.ready(false)?;
         let mut client = Self {
             agent,
+            server_name: String::from(server_name),
             resumption: Box::new(None),
+            certificate: Box::new(None),
+            verify: None,
         };
         client.ready()?;
         Ok(client)
@@ -690,6 +975,50 @@ impl Client {
         ssl::SECSuccess
     }
 
+    unsafe extern "C" fn client_auth_cb(
+        arg: *mut c_void,
+        fd: *mut ssl::PRFileDesc,
+        _ca_names: *mut ssl::CERTDistNames,
+        cert: *mut *mut ssl::CERTCertificate,
+        key: *mut *mut ssl::SECKEYPrivateKey,
+    ) -> ssl::SECStatus {
+        let p = arg as *mut Option<Credential> as *const Option<Credential>;
+        match p.as_ref().unwrap() {
+            Some(c) => {
+                qdebug!([format!("{:p}", fd)] "Sending a client certificate");
+                // NSS takes ownership of these, so hand over new references.
+                *cert = p11::CERT_DupCertificate(*c.cert.deref()) as *mut ssl::CERTCertificate;
+                *key = p11::SECKEY_CopyPrivateKey(*c.key.deref()) as *mut ssl::SECKEYPrivateKey;
+                ssl::SECSuccess
+            }
+            None => ssl::SECFailure,
+        }
+    }
+
+    unsafe extern "C" fn certificate_verify_cb(
+        arg: *mut c_void,
+        fd: *mut ssl::PRFileDesc,
+        check_sig: ssl::PRBool,
+        is_server: ssl::PRBool,
+    ) -> ssl::SECStatus {
+        let state = (arg as *mut CertificateVerifyState).as_ref().unwrap();
+        let chain = CertificateInfo::new(fd).map_or_else(Vec::new, |c| c.chain());
+        let db = p11::CERT_GetDefaultCertDB() as *mut c_void;
+        let nss = if ssl::SSL_AuthCertificate(db, fd, check_sig, is_server) == ssl::SECSuccess {
+            AuthenticationStatus::Ok
+        } else {
+            AuthenticationStatus::from(PR_GetError())
+        };
+        let status = state.verifier.verify(&chain, nss, &state.server_name);
+        qdebug!([format!("{:p}", fd)] "certificate verified: NSS {:?}, verifier {:?}", nss, status);
+        if status == AuthenticationStatus::Ok {
+            ssl::SECSuccess
+        } else {
+            PR_SetError(status.into(), 0);
+            ssl::SECFailure
+        }
+    }
+
     fn ready(&mut self) -> Res<()> {
         unsafe {
             ssl::SSL_SetResumptionTokenCallback(
@@ -697,7 +1026,53 @@ impl Client {
                 Some(Self::resumption_token_cb),
                 &mut *self.resumption as *mut Option<Vec<u8>> as *mut c_void,
             )
-        }
+        }?;
+        secstatus_to_res(unsafe {
+            ssl::SSL_GetClientAuthDataHook(
+                self.fd,
+                Some(Self::client_auth_cb),
+                &mut *self.certificate as *mut Option<Credential> as *mut c_void,
+            )
+        })
+    }
+
+    /// Authenticate with the certificate that has the nickname `name`, and
+    /// its key, if the server asks for a certificate.  Without this, the
+    /// client doesn't send a certificate.
+    pub fn set_client_certificate(&mut self, name: &str) -> Res<()> {
+        *self.certificate = Some(Credential::load(name)?);
+        Ok(())
+    }
+
+    /// Authenticate with a certificate and key that aren't in the database,
+    /// if the server asks for a certificate.  `cert` is the DER encoding of
+    /// the certificate and `key` the DER encoding of a PKCS#8 PrivateKeyInfo.
+    pub fn set_client_certificate_der(&mut self, cert: &[u8], key: &[u8]) -> Res<()> {
+        *self.certificate = Some(Credential::import(cert, key)?);
+        Ok(())
+    }
+
+    /// The name that the server certificate needs to be valid for.  Check the
+    /// certificates from `peer_certificate()` against this when the handshake
+    /// reaches `HandshakeState::AuthenticationPending`.
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Check the server certificate with `verifier` as soon as it arrives.
+    /// The handshake doesn't stop in `HandshakeState::AuthenticationPending`
+    /// and `authenticated()` isn't needed.  See `CertificateVerifier`.
+    pub fn set_certificate_verifier(&mut self, verifier: Rc<dyn CertificateVerifier>) -> Res<()> {
+        let mut state = Box::new(CertificateVerifyState {
+            verifier,
+            server_name: self.server_name.clone(),
+        });
+        let arg = &mut *state as *mut CertificateVerifyState as *mut c_void;
+        secstatus_to_res(unsafe {
+            ssl::SSL_AuthCertificateHook(self.agent.fd, Some(Self::certificate_verify_cb), arg)
+        })?;
+        self.verify = Some(state);
+        Ok(())
     }
 
     /// Return the resumption token.
@@ -760,43 +1135,402 @@ impl ZeroRttCheckState {
     }
 }
 
+/// An `AlpnSelector` lets a server choose the application protocol for each
+/// connection, rather than using a fixed preference list.
+pub trait AlpnSelector: std::fmt::Debug {
+    /// Choose one of the protocols that the client `offered`, which are in
+    /// the client's order.  `server_name` is the name the client indicated,
+    /// if any.  Returning `None`, or a protocol that the client didn't offer,
+    /// fails the handshake with a no_application_protocol alert.
+    fn select(&self, server_name: Option<&str>, offered: &[String]) -> Option<String>;
+}
+
+/// A `CertificateVerifier` lets a client decide whether to trust the server
+/// certificate itself, such as to trust a certificate that isn't in the NSS
+/// database, or to add its own checks to those that NSS makes.
+pub trait CertificateVerifier: std::fmt::Debug {
+    /// Decide whether the server is trusted.  `chain` has the certificates
+    /// that the server sent, starting with its own, in DER encoding.  `nss`
+    /// is what checking the chain and `server_name` against the NSS database
+    /// found.  Returning anything other than `AuthenticationStatus::Ok`
+    /// fails the handshake with a matching alert.
+    fn verify(
+        &self,
+        chain: &[Vec<u8>],
+        nss: AuthenticationStatus,
+        server_name: &str,
+    ) -> AuthenticationStatus;
+}
+
+#[derive(Debug)]
+struct CertificateVerifyState {
+    verifier: Rc<dyn CertificateVerifier>,
+    server_name: String,
+}
+
+#[derive(Debug)]
+struct AlpnSelectState {
+    selector: Rc<dyn AlpnSelector>,
+    /// Where the SNI callback records the server name.
+    server_name: *const Option<String>,
+    /// Where the agent records the alert it sends.
+    alert: *mut Option<Alert>,
+}
+
+/// What a server sends with its certificate so that clients don't have to
+/// look for it themselves: stapled OCSP responses, which a must-staple
+/// certificate needs, and signed certificate timestamps (SCTs).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CertificateStatus {
+    /// DER-encoded OCSP responses.  Only the first is sent.
+    pub ocsp: Vec<Vec<u8>>,
+    /// A serialized SignedCertificateTimestampList.
+    pub scts: Option<Vec<u8>>,
+}
+
+/// Make a `SECItem` that refers to `data`.
+fn ssl_item(data: &[u8]) -> Res<ssl::SECItem> {
+    Ok(ssl::SECItem {
+        type_: ssl::SECItemType::siBuffer,
+        data: data.as_ptr() as *mut u8,
+        len: c_uint::try_from(data.len())?,
+    })
+}
+
+/// A certificate and its private key.
+struct Credential {
+    cert: p11::Certificate,
+    key: p11::PrivateKey,
+    /// Intermediate certificates, which are kept so that the chain can be
+    /// built each time the certificate is configured.
+    intermediates: Vec<p11::Certificate>,
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Credential")
+    }
+}
+
+impl Credential {
+    /// Find the certificate with the nickname `name`, and its key.
+    fn load(name: &str) -> Res<Self> {
+        let c = CString::new(name)?;
+        let cert =
+            match NonNull::new(unsafe { p11::PK11_FindCertFromNickname(c.as_ptr(), null_mut()) }) {
+                None => return Err(Error::CertificateLoading),
+                Some(ptr) => p11::Certificate::new(ptr),
+            };
+        let key =
+            match NonNull::new(unsafe { p11::PK11_FindKeyByAnyCert(*cert.deref(), null_mut()) }) {
+                None => return Err(Error::CertificateLoading),
+                Some(ptr) => p11::PrivateKey::new(ptr),
+            };
+        Ok(Self {
+            cert,
+            key,
+            intermediates: Vec::new(),
+        })
+    }
+
+    /// Decode a certificate and a PKCS#8 private key.  Neither is stored in
+    /// the database.
+    fn import(cert: &[u8], key: &[u8]) -> Res<Self> {
+        let cert = import_certificate(cert)?;
+        let slot = match NonNull::new(unsafe { p11::PK11_GetInternalSlot() }) {
+            None => return Err(Error::InternalError),
+            Some(ptr) => p11::Slot::new(ptr),
+        };
+        let mut item = p11::SECItem {
+            type_: p11::SECItemType::siBuffer,
+            data: key.as_ptr() as *mut u8,
+            len: c_uint::try_from(key.len())?,
+        };
+        let mut key = null_mut();
+        secstatus_to_res(unsafe {
+            p11::PK11_ImportDERPrivateKeyInfoAndReturnKey(
+                *slot,
+                &mut item,
+                null_mut(),
+                null_mut(),
+                false as p11::PRBool,
+                true as p11::PRBool,
+                p11::KU_DIGITAL_SIGNATURE,
+                &mut key,
+                null_mut(),
+            )
+        })
+        .map_err(|_| Error::CertificateLoading)?;
+        let key = match NonNull::new(key) {
+            None => return Err(Error::CertificateLoading),
+            Some(ptr) => p11::PrivateKey::new(ptr),
+        };
+        Ok(Self {
+            cert,
+            key,
+            intermediates: Vec::new(),
+        })
+    }
+
+    /// The DER encoding of the certificate.
+    fn der(&self) -> &[u8] {
+        let mut item = p11::SECItem {
+            type_: p11::SECItemType::siBuffer,
+            data: null_mut(),
+            len: 0,
+        };
+        secstatus_to_res(unsafe { p11::CERT_GetCertificateDer(*self.cert.deref(), &mut item) })
+            .expect("getting DER from certificate should work");
+        unsafe { std::slice::from_raw_parts(item.data, item.len as usize) }
+    }
+
+    /// Configure `fd` to use this, with any status in `status` that is
+    /// for this certificate.  NSS copies the status.
+    fn configure(
+        &self,
+        fd: *mut ssl::PRFileDesc,
+        status: &HashMap<Vec<u8>, CertificateStatus>,
+    ) -> Res<()> {
+        let status = match status.get(self.der()) {
+            Some(s) => s,
+            None => {
+                return secstatus_to_res(unsafe {
+                    ssl::SSL_ConfigServerCert(fd, *self.cert.deref(), *self.key.deref(), null(), 0)
+                })
+            }
+        };
+        let mut ocsp = status
+            .ocsp
+            .iter()
+            .map(|r| ssl_item(r))
+            .collect::<Res<Vec<_>>>()?;
+        let ocsp_array = ssl::SECItemArray {
+            items: ocsp.as_mut_ptr(),
+            len: c_uint::try_from(ocsp.len())?,
+        };
+        let scts = match &status.scts {
+            Some(s) => Some(ssl_item(s)?),
+            None => None,
+        };
+        let extra = ssl::SSLExtraServerCertData {
+            authType: ssl::SSLAuthType::ssl_auth_null,
+            certChain: null(),
+            stapledOCSPResponses: if ocsp.is_empty() { null() } else { &ocsp_array },
+            signedCertTimestamps: scts.as_ref().map_or(null(), |s| s as *const _),
+            delegCred: null(),
+            delegCredPrivKey: null(),
+        };
+        secstatus_to_res(unsafe {
+            ssl::SSL_ConfigServerCert(
+                fd,
+                *self.cert.deref(),
+                *self.key.deref(),
+                &extra,
+                c_uint::try_from(mem::size_of::<ssl::SSLExtraServerCertData>())?,
+            )
+        })
+    }
+}
+
+/// Decode a certificate, which is available until it is dropped.
+fn import_certificate(der: &[u8]) -> Res<p11::Certificate> {
+    let mut item = p11::SECItem {
+        type_: p11::SECItemType::siBuffer,
+        data: der.as_ptr() as *mut u8,
+        len: c_uint::try_from(der.len())?,
+    };
+    match NonNull::new(unsafe {
+        p11::CERT_NewTempCertificate(
+            p11::CERT_GetDefaultCertDB(),
+            &mut item,
+            null_mut(),
+            false as p11::PRBool,
+            true as p11::PRBool,
+        )
+    }) {
+        None => Err(Error::CertificateLoading),
+        Some(ptr) => Ok(p11::Certificate::new(ptr)),
+    }
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Credential")
+    }
+}
+
+/// The state for the SNI callback.  This records the name that the client
+/// asked for, and holds the certificates to use for particular names.
+#[derive(Default)]
+struct SniState {
+    server_name: Option<String>,
+    /// Certificates to use instead of the defaults, keyed by lowercase name.
+    certificates: HashMap<String, Vec<Credential>>,
+    /// The status to send with each certificate, keyed by its DER encoding.
+    status: HashMap<Vec<u8>, CertificateStatus>,
+}
+
+impl std::fmt::Debug for SniState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "SniState {:?} names {:?}",
+            self.server_name,
+            self.certificates.keys().collect::<Vec<_>>()
+        )
+    }
+}
+
+/// Decode the protocols from an ALPN extension, skipping any that aren't UTF-8.
+fn decode_alpn(mut v: &[u8]) -> Vec<String> {
+    let mut protocols = Vec::new();
+    while let Some((&len, rest)) = v.split_first() {
+        let len = usize::from(len);
+        if rest.len() < len {
+            break;
+        }
+        if let Ok(p) = std::str::from_utf8(&rest[..len]) {
+            protocols.push(String::from(p));
+        }
+        v = &rest[len..];
+    }
+    protocols
+}
+
 #[derive(Debug)]
 pub struct Server {
     agent: SecretAgent,
+    /// The certificates that are used unless the SNI callback picks others.
+    credentials: Vec<Credential>,
     /// This holds the HRR callback context.
     zero_rtt_check: Option<Box<ZeroRttCheckState>>,
+    /// This holds the context for the SNI callback.
+    sni: Box<SniState>,
+    /// This holds the context for the ALPN callback.
+    alpn_select: Option<Box<AlpnSelectState>>,
 }
 
 impl Server {
     pub fn new(certificates: &[impl AsRef<str>]) -> Res<Self> {
         let mut agent = SecretAgent::new()?;
 
-        for n in certificates {
-            let c = CString::new(n.as_ref())?;
-            let cert = match NonNull::new(unsafe {
-                p11::PK11_FindCertFromNickname(c.as_ptr(), null_mut())
-            }) {
-                None => return Err(Error::CertificateLoading),
-                Some(ptr) => p11::Certificate::new(ptr),
-            };
-            let key = match NonNull::new(unsafe {
-                p11::PK11_FindKeyByAnyCert(*cert.deref(), null_mut())
-            }) {
-                None => return Err(Error::CertificateLoading),
-                Some(ptr) => p11::PrivateKey::new(ptr),
-            };
-            secstatus_to_res(unsafe {
-                ssl::SSL_ConfigServerCert(agent.fd, *cert.deref(), *key.deref(), null(), 0)
-            })?;
+        let credentials = certificates
+            .iter()
+            .map(|n| Credential::load(n.as_ref()))
+            .collect::<Res<Vec<_>>>()?;
+        for c in &credentials {
+            c.configure(agent.fd, &HashMap::new())?;
         }
 
+        let mut sni = Box::new(SniState::default());
+        let arg = &mut *sni as *mut SniState as *mut c_void;
+        secstatus_to_res(unsafe {
+            ssl::SSL_SNISocketConfigHook(agent.fd, Some(Self::sni_cb), arg)
+        })?;
+
         agent.ready(true)?;
         Ok(Self {
             agent,
+            credentials,
             zero_rtt_check: None,
+            sni,
+            alpn_select: None,
         })
     }
 
+    /// Use the certificates with the nicknames in `certificates` when the
+    /// client asks for `server_name`, instead of those that the server was
+    /// created with.  Server names are compared without regard to case.
+    /// This replaces any certificates that were set for the name before.
+    pub fn set_server_certificates(
+        &mut self,
+        server_name: impl AsRef<str>,
+        certificates: &[impl AsRef<str>],
+    ) -> Res<()> {
+        let certs = certificates
+            .iter()
+            .map(|n| Credential::load(n.as_ref()))
+            .collect::<Res<Vec<_>>>()?;
+        self.sni
+            .certificates
+            .insert(server_name.as_ref().to_ascii_lowercase(), certs);
+        Ok(())
+    }
+
+    /// The name that the client indicated, if any.  This is available once
+    /// the ClientHello has been processed.
+    pub fn server_name(&self) -> Option<&str> {
+        self.sni.server_name.as_ref().map(String::as_str)
+    }
+
+    /// Ask clients for a certificate.  If `required` is set, the handshake
+    /// fails when a client doesn't send one.  As on a client, the handshake
+    /// stops in `HandshakeState::AuthenticationPending` when a certificate
+    /// arrives, so that it can be checked with `peer_certificate()` before
+    /// calling `authenticated()`.
+    pub fn request_client_certificate(&mut self, required: bool) -> Res<()> {
+        self.set_option(ssl::Opt::RequestCertificate, true)?;
+        self.set_option(ssl::Opt::RequireCertificate, required)
+    }
+
+    /// Use a certificate and key that aren't in a database.  This works
+    /// even if NSS was initialized without a database, using `init()`.
+    /// `chain` has the DER encoding of the server certificate, followed by
+    /// any intermediate certificates that are needed to reach a trust
+    /// anchor.  `key` is the DER encoding of a PKCS#8 PrivateKeyInfo.
+    pub fn add_certificate(&mut self, chain: &[impl AsRef<[u8]>], key: &[u8]) -> Res<()> {
+        let (cert, intermediates) = chain.split_first().ok_or(Error::CertificateLoading)?;
+        // NSS builds the chain when the certificate is configured, so the
+        // intermediate certificates need to be available then.
+        let mut credential = Credential::import(cert.as_ref(), key)?;
+        credential.intermediates = intermediates
+            .iter()
+            .map(|c| import_certificate(c.as_ref()))
+            .collect::<Res<Vec<_>>>()?;
+        credential.configure(self.agent.fd, &self.sni.status)?;
+        self.credentials.push(credential);
+        Ok(())
+    }
+
+    /// Send `status` with the certificate that has the DER encoding
+    /// `certificate`, whether that is one of the defaults or one that is
+    /// chosen by server name.  This replaces any status that was set for the
+    /// certificate before, and applies to any handshake that hasn't yet sent
+    /// the certificate.  This fails if the server has no such certificate.
+    pub fn set_certificate_status(
+        &mut self,
+        certificate: &[u8],
+        status: CertificateStatus,
+    ) -> Res<()> {
+        let known = self
+            .credentials
+            .iter()
+            .chain(self.sni.certificates.values().flatten())
+            .any(|c| c.der() == certificate);
+        if !known {
+            return Err(Error::CertificateLoading);
+        }
+        self.sni.status.insert(certificate.to_vec(), status);
+        for c in &self.credentials {
+            if c.der() == certificate {
+                c.configure(self.agent.fd, &self.sni.status)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The same as `add_certificate()`, except that `chain` and `key` are
+    /// PEM.  `chain` has a CERTIFICATE block for the server certificate,
+    /// followed by one for each intermediate certificate.  `key` has a
+    /// PRIVATE KEY block, which is not encrypted.
+    pub fn add_certificate_pem(&mut self, chain: &str, key: &str) -> Res<()> {
+        let chain = pem::decode(chain, "CERTIFICATE")?;
+        match pem::decode(key, "PRIVATE KEY")?.first() {
+            Some(key) => self.add_certificate(&chain, key),
+            None => Err(Error::CertificateLoading),
+        }
+    }
+
     unsafe extern "C" fn hello_retry_cb(
         first_hello: PRBool,
         client_token: *const u8,
@@ -855,8 +1589,116 @@ impl Server {
         Ok(())
     }
 
-    /// Send a session ticket to the client.
+    unsafe extern "C" fn sni_cb(
+        fd: *mut ssl::PRFileDesc,
+        names: *const ssl::SECItem,
+        count: ssl::PRUint32,
+        arg: *mut c_void,
+    ) -> ssl::PRInt32 {
+        let p = arg as *mut SniState;
+        let state = p.as_mut().unwrap();
+        if count == 0 {
+            return ssl::SSL_SNI_CURRENT_CONFIG_IS_USED;
+        }
+        let name = names.as_ref().unwrap();
+        let name = std::slice::from_raw_parts(name.data, name.len as usize);
+        state.server_name = std::str::from_utf8(name).ok().map(String::from);
+
+        let certs = match &state.server_name {
+            Some(n) => state.certificates.get(&n.to_ascii_lowercase()),
+            None => None,
+        };
+        match certs {
+            Some(certs) => {
+                qdebug!([format!("{:p}", fd)] "certificates for {:?}", state.server_name);
+                if certs.iter().all(|c| c.configure(fd, &state.status).is_ok()) {
+                    0 // The index of the name that was used.
+                } else {
+                    ssl::SSL_SNI_SEND_ALERT
+                }
+            }
+            None => ssl::SSL_SNI_CURRENT_CONFIG_IS_USED,
+        }
+    }
+
+    unsafe extern "C" fn alpn_select_cb(
+        arg: *mut c_void,
+        fd: *mut ssl::PRFileDesc,
+        protos: *const u8,
+        protos_len: c_uint,
+        proto_out: *mut u8,
+        proto_out_len: *mut c_uint,
+        proto_max_out: c_uint,
+    ) -> ssl::SECStatus {
+        let p = arg as *mut AlpnSelectState;
+        let state = p.as_mut().unwrap();
+        let offered = decode_alpn(std::slice::from_raw_parts(protos, protos_len as usize));
+        let server_name = (*state.server_name).as_ref().map(String::as_str);
+        match state.selector.select(server_name, &offered) {
+            Some(proto) if offered.contains(&proto) && proto.len() <= proto_max_out as usize => {
+                let out = std::slice::from_raw_parts_mut(proto_out, proto.len());
+                out.copy_from_slice(proto.as_bytes());
+                *proto_out_len = c_uint::try_from(proto.len()).unwrap();
+                ssl::SECSuccess
+            }
+            _ => {
+                qinfo!([format!("{:p}", fd)] "no application protocol from {:?}", offered);
+                // NSS sends internal_error when this fails.  Record the
+                // right alert first, so that it is the one that is reported.
+                *state.alert.as_mut().unwrap() = Some(120); // no_application_protocol
+                PR_SetError(err::ssl::SSL_ERROR_NEXT_PROTOCOL_NO_PROTOCOL, 0);
+                ssl::SECFailure
+            }
+        }
+    }
+
+    /// Use `selector` to choose the application protocol, instead of the
+    /// list given to `set_alpn()`.  Calling `set_alpn()` afterwards replaces
+    /// the selector.
+    pub fn set_alpn_selector(&mut self, selector: Rc<dyn AlpnSelector>) -> Res<()> {
+        let mut state = Box::new(AlpnSelectState {
+            selector,
+            server_name: &self.sni.server_name as *const Option<String>,
+            alert: &mut *self.agent.alert as *mut Option<Alert>,
+        });
+        let arg = &mut *state as *mut AlpnSelectState as *mut c_void;
+        secstatus_to_res(unsafe {
+            ssl::SSL_SetNextProtoCallback(self.agent.fd, Some(Self::alpn_select_cb), arg)
+        })?;
+        self.alpn_select = Some(state);
+        Ok(())
+    }
+
+    /// Send `count` session tickets, each containing `extra`, as soon as the
+    /// handshake completes; see `send_ticket()`.  By default, no tickets
+    /// are sent until `send_ticket()` is called.
+    pub fn set_tickets(&mut self, count: usize, extra: &[u8]) {
+        self.agent.tickets = count;
+        self.agent.ticket_extra = extra.to_vec();
+    }
+
+    /// Replace the keys that protect session tickets.  Tickets that were
+    /// issued before this is called can't be used to resume any more.
+    ///
+    /// NSS uses the same keys for every server in the process, so this
+    /// affects all of them.  The lifetime of tickets is fixed by NSS, at two
+    /// days; rotating keys more often than that is the way to make tickets
+    /// expire sooner.
+    pub fn rotate_ticket_keys() -> Res<()> {
+        // The key only wraps the keys that tickets are encrypted with, so
+        // the size doesn't need to be any larger.
+        const TICKET_WRAP_KEY_BITS: usize = 2048;
+        let (public, private) = p11::generate_rsa_key_pair(TICKET_WRAP_KEY_BITS)?;
+        secstatus_to_res(unsafe { ssl::SSL_SetSessionTicketKeyPair(*public, *private) })
+    }
+
+    /// Send a session ticket to the client.  This can be done at any time
+    /// after the handshake completes, such as after a client certificate is
+    /// checked, so that resumption carries that state.
     /// This adds |extra| application-specific content into that ticket.
+    /// When the client resumes, |extra| is passed to the `ZeroRttChecker`,
+    /// so it can carry state that 0-RTT depends on, such as authorization.
+    /// The client can't read it; see `ResumptionTokenInfo`.
     /// The records that are sent are captured and returned.
     pub fn send_ticket(&mut self, now: Instant, extra: &[u8]) -> Res<RecordList> {
         *self.agent.now = Time::from(now).try_into()?;
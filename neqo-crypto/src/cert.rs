@@ -94,6 +94,31 @@ impl CertificateInfo {
         // Three stars: one for the reference, one for the wrapper, one to deference the pointer.
         unsafe { &(***certs).list as *const PRCList as *const CERTCertListNode }
     }
+
+    fn der<'a>(node: *const CERTCertListNode) -> &'a [u8] {
+        let mut item = SECItem {
+            type_: SECItemType::siBuffer,
+            data: null_mut(),
+            len: 0,
+        };
+        let cert = unsafe { *node }.cert;
+        secstatus_to_res(unsafe { CERT_GetCertificateDer(cert, &mut item) })
+            .expect("getting DER from certificate should work");
+        unsafe { std::slice::from_raw_parts(item.data, item.len as usize) }
+    }
+
+    /// The DER encoding of every certificate in the chain, starting with the
+    /// end-entity certificate.  Unlike iterating, this doesn't move the cursor.
+    pub fn chain(&self) -> Vec<Vec<u8>> {
+        let head = Self::head(&self.certs);
+        let mut chain = Vec::new();
+        let mut node = unsafe { *head }.links.next as *const CERTCertListNode;
+        while node != head {
+            chain.push(Self::der(node).to_vec());
+            node = unsafe { *node }.links.next as *const CERTCertListNode;
+        }
+        chain
+    }
 }
 
 impl<'a> Iterator for &'a mut CertificateInfo {
@@ -103,15 +128,7 @@ impl<'a> Iterator for &'a mut CertificateInfo {
         if self.cursor == CertificateInfo::head(&self.certs) {
             return None;
         }
-        let mut item = SECItem {
-            type_: SECItemType::siBuffer,
-            data: null_mut(),
-            len: 0,
-        };
-        let cert = unsafe { *self.cursor }.cert;
-        secstatus_to_res(unsafe { CERT_GetCertificateDer(cert, &mut item) })
-            .expect("getting DER from certificate should work");
-        Some(unsafe { std::slice::from_raw_parts(item.data, item.len as usize) })
+        Some(CertificateInfo::der(self.cursor))
     }
 }
 
@@ -0,0 +1,112 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Certificate compression, from RFC 8879.
+
+use crate::err::{Error, Res};
+use crate::ssl::{self, PRFileDesc, SECItem, SECStatus};
+
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_uint};
+use std::ptr::null_mut;
+
+/// This mirrors `SSLCertificateCompressionAlgorithm` from NSS.
+#[repr(C)]
+struct SSLCertificateCompressionAlgorithm {
+    id: u16,
+    name: *const c_char,
+    encode: Option<unsafe extern "C" fn(input: *const SECItem, output: *mut SECItem) -> SECStatus>,
+    decode: Option<
+        unsafe extern "C" fn(
+            input: *const SECItem,
+            output: *mut u8,
+            output_len: usize,
+            used_len: *mut usize,
+        ) -> SECStatus,
+    >,
+}
+
+experimental_api!(SSL_SetCertificateCompressionAlgorithm(
+    fd: *mut PRFileDesc,
+    alg: SSLCertificateCompressionAlgorithm,
+));
+
+/// A certificate compression algorithm, such as zlib (1) or brotli (2).
+/// NSS doesn't include any, so the application provides the codec.
+/// These are used without an instance, because NSS doesn't pass any
+/// context to the codec.
+pub trait CertificateCompressor {
+    /// The code point, from the IANA registry of TLS certificate compression
+    /// algorithms.  This can't be zero.
+    const ID: u16;
+    /// The name of the algorithm, which NSS uses in logging.  This has to
+    /// end with a nul, as in `b"zlib\0"`.
+    const NAME: &'static [u8];
+
+    /// Compress `input`, which is the encoded Certificate message.
+    fn encode(input: &[u8]) -> Res<Vec<u8>>;
+
+    /// Decompress `input` into `output`, returning the number of bytes that
+    /// were written.  `output` is the length that the peer said the
+    /// uncompressed message would be, which is the most it can use.
+    fn decode(input: &[u8], output: &mut [u8]) -> Res<usize>;
+}
+
+unsafe extern "C" fn encode_cb<T: CertificateCompressor>(
+    input: *const SECItem,
+    output: *mut SECItem,
+) -> SECStatus {
+    let input = input.as_ref().unwrap();
+    let input = std::slice::from_raw_parts(input.data, input.len as usize);
+    let encoded = match T::encode(input) {
+        Ok(v) => v,
+        Err(_) => return ssl::SECFailure,
+    };
+    let len = match c_uint::try_from(encoded.len()) {
+        Ok(l) => l,
+        Err(_) => return ssl::SECFailure,
+    };
+    // NSS frees `output`, so it has to be allocated by NSS.
+    if ssl::SECITEM_AllocItem(null_mut(), output, len).is_null() {
+        return ssl::SECFailure;
+    }
+    let output = output.as_mut().unwrap();
+    std::slice::from_raw_parts_mut(output.data, encoded.len()).copy_from_slice(&encoded);
+    ssl::SECSuccess
+}
+
+unsafe extern "C" fn decode_cb<T: CertificateCompressor>(
+    input: *const SECItem,
+    output: *mut u8,
+    output_len: usize,
+    used_len: *mut usize,
+) -> SECStatus {
+    let input = input.as_ref().unwrap();
+    let input = std::slice::from_raw_parts(input.data, input.len as usize);
+    let output = std::slice::from_raw_parts_mut(output, output_len);
+    match T::decode(input, output) {
+        Ok(used) if used <= output_len => {
+            *used_len = used;
+            ssl::SECSuccess
+        }
+        _ => ssl::SECFailure,
+    }
+}
+
+/// Allow `fd` to use `T` for certificates, both those it sends and those it
+/// receives.
+pub(crate) fn enable<T: CertificateCompressor>(fd: *mut PRFileDesc) -> Res<()> {
+    if T::ID == 0 || T::NAME.last() != Some(&0) {
+        return Err(Error::InvalidInput);
+    }
+    let alg = SSLCertificateCompressionAlgorithm {
+        id: T::ID,
+        name: T::NAME.as_ptr() as *const c_char,
+        encode: Some(encode_cb::<T>),
+        decode: Some(decode_cb::<T>),
+    };
+    unsafe { SSL_SetCertificateCompressionAlgorithm(fd, alg) }
+}
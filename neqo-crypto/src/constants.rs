@@ -57,6 +57,20 @@ remap_enum! {
     }
 }
 
+/// The X25519Kyber768 hybrid key exchange (draft-tls-westerbaan-xyber768d00).
+/// Versions of NSS that don't support this ignore it, so list another group after it.
+pub const TLS_GRP_KEM_XYBER768D00: Group = 0x6399;
+/// The X25519 and ML-KEM-768 hybrid key exchange (draft-kwiatkowski-tls-ecdhe-mlkem).
+/// As with `TLS_GRP_KEM_XYBER768D00`, this needs a recent version of NSS.
+pub const TLS_GRP_KEM_MLKEM768X25519: Group = 0x11ec;
+
+remap_enum! {
+    HashType: u8 => ssl::SSLHashType {
+        TLS_HASH_SHA256 = ssl_hash_sha256,
+        TLS_HASH_SHA384 = ssl_hash_sha384,
+    }
+}
+
 remap_enum! {
     HandshakeMessage: u8 => ssl::SSLHandshakeType {
         TLS_HS_HELLO_REQUEST = ssl_hs_hello_request,
@@ -36,6 +36,7 @@ pub enum Error {
     HkdfError,
     InternalError,
     IntegerOverflow,
+    InvalidInput,
     InvalidEpoch,
     MixedHandshakeMethod,
     NoDataAvailable,
@@ -7,13 +7,12 @@
 use crate::constants::*;
 use crate::err::{Error, Res};
 use crate::p11::{
-    PK11Origin, PK11SymKey, PK11_GetInternalSlot, PK11_ImportSymKey, SECItem, SECItemType, Slot,
-    SymKey, CKA_DERIVE, CKM_INVALID_MECHANISM, CKM_NSS_HKDF_SHA256, CKM_NSS_HKDF_SHA384,
-    CK_ATTRIBUTE_TYPE, CK_MECHANISM_TYPE,
+    import_sym_key, PK11SymKey, SymKey, CKA_DERIVE, CKM_INVALID_MECHANISM, CKM_NSS_HKDF_SHA256,
+    CKM_NSS_HKDF_SHA384, CK_ATTRIBUTE_TYPE, CK_MECHANISM_TYPE,
 };
 
 use std::convert::TryFrom;
-use std::os::raw::{c_char, c_uchar, c_uint};
+use std::os::raw::{c_char, c_uint};
 use std::ptr::{null_mut, NonNull};
 
 experimental_api!(SSL_HkdfExtract(
@@ -33,9 +32,21 @@ experimental_api!(SSL_HkdfExpandLabel(
     label_len: c_uint,
     secret: *mut *mut PK11SymKey,
 ));
+experimental_api!(SSL_HkdfExpandLabelWithMech(
+    version: Version,
+    cipher: Cipher,
+    prk: *mut PK11SymKey,
+    handshake_hash: *const u8,
+    handshake_hash_len: c_uint,
+    label: *const c_char,
+    label_len: c_uint,
+    mech: CK_MECHANISM_TYPE,
+    key_size: c_uint,
+    secret: *mut *mut PK11SymKey,
+));
 
-/// Import a symmetric key for use with HKDF.
-pub fn import_key(version: Version, cipher: Cipher, buf: &[u8]) -> Res<SymKey> {
+/// The HKDF mechanism for the hash function of `cipher`.
+fn hkdf_mech(version: Version, cipher: Cipher) -> Res<CK_MECHANISM_TYPE> {
     if version != TLS_VERSION_1_3 {
         return Err(Error::UnsupportedVersion);
     }
@@ -47,30 +58,16 @@ pub fn import_key(version: Version, cipher: Cipher, buf: &[u8]) -> Res<SymKey> {
     if mech == CKM_INVALID_MECHANISM {
         return Err(Error::UnsupportedCipher);
     }
-    let mut item = SECItem {
-        type_: SECItemType::siBuffer,
-        data: buf.as_ptr() as *mut c_uchar,
-        len: c_uint::try_from(buf.len())?,
-    };
-    let slot_ptr = unsafe { PK11_GetInternalSlot() };
-    let slot = match NonNull::new(slot_ptr) {
-        Some(p) => Slot::new(p),
-        None => return Err(Error::InternalError),
-    };
-    let key_ptr = unsafe {
-        PK11_ImportSymKey(
-            *slot,
-            CK_MECHANISM_TYPE::from(mech),
-            PK11Origin::PK11_OriginUnwrap,
-            CK_ATTRIBUTE_TYPE::from(CKA_DERIVE),
-            &mut item,
-            null_mut(),
-        )
-    };
-    match NonNull::new(key_ptr) {
-        Some(p) => Ok(SymKey::new(p)),
-        None => Err(Error::InternalError),
-    }
+    Ok(CK_MECHANISM_TYPE::from(mech))
+}
+
+/// Import a symmetric key for use with HKDF.
+pub fn import_key(version: Version, cipher: Cipher, buf: &[u8]) -> Res<SymKey> {
+    import_sym_key(
+        hkdf_mech(version, cipher)?,
+        CK_ATTRIBUTE_TYPE::from(CKA_DERIVE),
+        buf,
+    )
 }
 
 /// Extract a PRK from the given salt and IKM using the algorithm defined in RFC 5869.
@@ -123,3 +120,46 @@ pub fn expand_label<S: Into<String>>(
         None => Err(Error::HkdfError),
     }
 }
+
+/// Like `expand_label`, but produce `len` bytes of output, rather than a key
+/// that is as long as the output of the hash function.  This can't make more
+/// than 255 times the length of that output.  As this needs the value of the
+/// key that is made, it fails where NSS doesn't allow keys to be extracted,
+/// such as in FIPS mode.
+pub fn expand_label_raw<S: Into<String>>(
+    version: Version,
+    cipher: Cipher,
+    prk: &SymKey,
+    handshake_hash: &[u8],
+    label: S,
+    len: usize,
+) -> Res<Vec<u8>> {
+    let mech = hkdf_mech(version, cipher)?;
+    let label_str = label.into();
+    let l = label_str.as_bytes();
+    let mut secret: *mut PK11SymKey = null_mut();
+
+    unsafe {
+        SSL_HkdfExpandLabelWithMech(
+            version,
+            cipher,
+            **prk,
+            handshake_hash.as_ptr(),
+            c_uint::try_from(handshake_hash.len())?,
+            l.as_ptr() as *const c_char,
+            c_uint::try_from(l.len())?,
+            mech,
+            c_uint::try_from(len)?,
+            &mut secret,
+        )
+    }?;
+    let key = match NonNull::new(secret) {
+        Some(p) => SymKey::new(p),
+        None => return Err(Error::HkdfError),
+    };
+    let bytes = key.as_bytes()?;
+    if bytes.len() != len {
+        return Err(Error::HkdfError);
+    }
+    Ok(bytes.to_vec())
+}
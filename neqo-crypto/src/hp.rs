@@ -6,9 +6,10 @@
 
 use crate::constants::*;
 use crate::err::{secstatus_to_res, Error, Res};
+use crate::hkdf::SSL_HkdfExpandLabelWithMech;
 use crate::p11::{
-    PK11SymKey, PK11_Encrypt, PK11_GetBlockSize, PK11_GetMechanism, SECItem, SECItemType, SymKey,
-    CKM_AES_ECB, CKM_NSS_CHACHA20_CTR, CK_MECHANISM_TYPE,
+    PK11SymKey, PK11_Encrypt, PK11_GetBlockSize, SECItem, SECItemType, SymKey, CKM_AES_ECB,
+    CKM_NSS_CHACHA20_CTR, CK_MECHANISM_TYPE,
 };
 
 use std::convert::TryFrom;
@@ -16,20 +17,20 @@ use std::fmt::{self, Debug};
 use std::os::raw::{c_char, c_uint};
 use std::ptr::{null, null_mut, NonNull};
 
-experimental_api!(SSL_HkdfExpandLabelWithMech(
-    version: Version,
-    cipher: Cipher,
-    prk: *mut PK11SymKey,
-    handshake_hash: *const u8,
-    handshake_hash_len: c_uint,
-    label: *const c_char,
-    label_len: c_uint,
-    mech: CK_MECHANISM_TYPE,
-    key_size: c_uint,
-    secret: *mut *mut PK11SymKey,
-));
+/// The largest mask that `HpKey::mask()` makes, which is one ChaCha20 block.
+pub const MAX_MASK_LEN: usize = 64;
+/// The input for ChaCha20, which is encrypted to make a mask.
+const ZEROS: [u8; MAX_MASK_LEN] = [0; MAX_MASK_LEN];
 
-pub struct HpKey(SymKey);
+/// A header protection key.  This remembers the mechanism and block size of
+/// the key, so that making a mask needs only one call into NSS.  Cloning
+/// this is cheap, as the key is shared.
+#[derive(Clone)]
+pub struct HpKey {
+    key: SymKey,
+    mech: CK_MECHANISM_TYPE,
+    block_size: usize,
+}
 
 impl Debug for HpKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -38,6 +39,7 @@ impl Debug for HpKey {
 }
 
 /// QUIC-specific API for extracting a header-protection key.
+#[allow(clippy::cast_sign_loss)]
 pub fn extract_hp<S: Into<String>>(
     version: Version,
     cipher: Cipher,
@@ -71,51 +73,79 @@ pub fn extract_hp<S: Into<String>>(
             &mut secret,
         )
     }?;
-    match NonNull::new(secret) {
-        None => Err(Error::HkdfError),
-        Some(p) => Ok(HpKey(SymKey::new(p))),
-    }
+    let key = match NonNull::new(secret) {
+        None => return Err(Error::HkdfError),
+        Some(p) => SymKey::new(p),
+    };
+    // Cast is safe because block size is always greater than or equal to 0
+    let block_size = unsafe { PK11_GetBlockSize(mech, null_mut()) } as usize;
+    Ok(HpKey {
+        key,
+        mech,
+        block_size,
+    })
 }
 
 impl HpKey {
-    /// Generate a header protection mask for QUIC.
-    #[allow(clippy::cast_sign_loss)]
-    pub fn mask(&self, sample: &[u8]) -> Res<Vec<u8>> {
-        let k: *mut PK11SymKey = *self.0;
-        let mech = unsafe { PK11_GetMechanism(k) };
-        // Cast is safe because block size is always greater than or equal to 0
-        let block_size = unsafe { PK11_GetBlockSize(mech, null_mut()) } as usize;
+    fn is_aes(&self) -> bool {
+        self.mech == CK_MECHANISM_TYPE::from(CKM_AES_ECB)
+    }
 
-        let mut output = vec![0_u8; block_size];
-        let output_slice = &mut output[..];
+    /// Encrypt `input` into `output`, which has to be the same length.
+    fn encrypt(&self, iv: *mut SECItem, input: &[u8], output: &mut [u8]) -> Res<()> {
         let mut output_len: c_uint = 0;
-
-        let mut item = SECItem {
-            type_: SECItemType::siBuffer,
-            data: sample.as_ptr() as *mut u8,
-            len: c_uint::try_from(sample.len())?,
-        };
-        let zero = vec![0_u8; block_size];
-        let (iv, inbuf) = match () {
-            _ if mech == CK_MECHANISM_TYPE::from(CKM_AES_ECB) => (null_mut(), sample),
-            _ if mech == CK_MECHANISM_TYPE::from(CKM_NSS_CHACHA20_CTR) => {
-                (&mut item as *mut SECItem, &zero[..])
-            }
-            _ => unreachable!(),
-        };
         secstatus_to_res(unsafe {
             PK11_Encrypt(
-                k,
-                mech,
+                *self.key,
+                self.mech,
                 iv,
-                output_slice.as_mut_ptr(),
+                output.as_mut_ptr(),
                 &mut output_len,
                 c_uint::try_from(output.len())?,
-                inbuf.as_ptr() as *const u8,
-                c_uint::try_from(inbuf.len())?,
+                input.as_ptr() as *const u8,
+                c_uint::try_from(input.len())?,
             )
         })?;
-        assert_eq!(output_len as usize, block_size);
+        assert_eq!(output_len as usize, output.len());
+        Ok(())
+    }
+
+    /// Generate a header protection mask for QUIC.
+    pub fn mask(&self, sample: &[u8]) -> Res<Vec<u8>> {
+        let mut output = vec![0_u8; self.block_size];
+        self.mask_into(sample, &mut output)?;
         Ok(output)
     }
+
+    /// Like `mask()`, but without allocating.  The mask is written to the
+    /// start of `output`, which has to be at least `MAX_MASK_LEN` bytes.
+    /// This returns the mask.
+    pub fn mask_into<'a>(&self, sample: &[u8], output: &'a mut [u8]) -> Res<&'a [u8]> {
+        let output = &mut output[..self.block_size];
+        if self.is_aes() {
+            self.encrypt(null_mut(), sample, output)?;
+        } else {
+            let mut item = SECItem {
+                type_: SECItemType::siBuffer,
+                data: sample.as_ptr() as *mut u8,
+                len: c_uint::try_from(sample.len())?,
+            };
+            self.encrypt(&mut item, &ZEROS[..self.block_size], output)?;
+        }
+        Ok(output)
+    }
+
+    /// Generate header protection masks for several samples, in order.  With
+    /// AES, if every sample is one block, this makes all of the masks with
+    /// one call into NSS.  Otherwise, this is the same as calling `mask()`
+    /// for each sample.
+    pub fn masks(&self, samples: &[&[u8]]) -> Res<Vec<Vec<u8>>> {
+        if !self.is_aes() || samples.iter().any(|s| s.len() != self.block_size) {
+            return samples.iter().map(|s| self.mask(s)).collect();
+        }
+        let input = samples.concat();
+        let mut output = vec![0_u8; input.len()];
+        self.encrypt(null_mut(), &input, &mut output)?;
+        Ok(output.chunks(self.block_size).map(<[u8]>::to_vec).collect())
+    }
 }
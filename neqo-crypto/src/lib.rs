@@ -20,11 +20,13 @@ pub mod agent;
 mod agentio;
 mod auth;
 mod cert;
+mod certcomp;
 pub mod constants;
 mod err;
 pub mod ext;
 pub mod hkdf;
 pub mod hp;
+mod pem;
 mod prio;
 mod replay;
 mod secrets;
@@ -32,15 +34,17 @@ mod ssl;
 mod time;
 
 pub use self::agent::{
-    Agent, Client, HandshakeState, Record, RecordList, SecretAgent, SecretAgentInfo,
-    SecretAgentPreInfo, Server, ZeroRttCheckResult, ZeroRttChecker,
+    Agent, AlpnSelector, CertificateStatus, CertificateVerifier, Client, HandshakeState, Record,
+    RecordList, ResumptionTokenInfo, SecretAgent, SecretAgentInfo, SecretAgentPreInfo, Server,
+    ZeroRttCheckResult, ZeroRttChecker,
 };
+pub use self::certcomp::CertificateCompressor;
 pub use self::constants::*;
 pub use self::err::{Error, PRErrorCode, Res};
 pub use self::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
 pub use self::p11::SymKey;
-pub use self::replay::AntiReplay;
-pub use self::secrets::SecretDirection;
+pub use self::replay::{AntiReplay, AntiReplayBuilder};
+pub use self::secrets::{KeyLogFile, KeyLogWriter, SecretDirection, SecretListener};
 pub use auth::AuthenticationStatus;
 
 use neqo_common::once::OnceResult;
@@ -77,7 +81,7 @@ impl Drop for NssLoaded {
     }
 }
 
-static mut INITIALIZED: OnceResult<NssLoaded> = OnceResult::new();
+static INITIALIZED: OnceResult<NssLoaded> = OnceResult::new();
 
 fn already_initialized() -> bool {
     unsafe { nss::NSS_IsInitialized() != 0 }
@@ -87,33 +91,33 @@ fn already_initialized() -> bool {
 pub fn init() {
     // Set time zero.
     time::init();
-    unsafe {
-        INITIALIZED.call_once(|| {
-            if already_initialized() {
-                return NssLoaded::External;
-            }
+    INITIALIZED.call_once(|| {
+        if already_initialized() {
+            return NssLoaded::External;
+        }
 
+        unsafe {
             secstatus_to_res(nss::NSS_NoDB_Init(null())).expect("NSS_NoDB_Init failed");
             secstatus_to_res(nss::NSS_SetDomesticPolicy()).expect("NSS_SetDomesticPolicy failed");
+        }
 
-            NssLoaded::NoDb
-        });
-    }
+        NssLoaded::NoDb
+    });
 }
 
 pub fn init_db<P: Into<PathBuf>>(dir: P) {
     time::init();
-    unsafe {
-        INITIALIZED.call_once(|| {
-            if already_initialized() {
-                return NssLoaded::External;
-            }
-
-            let path = dir.into();
-            assert!(path.is_dir());
-            let pathstr = path.to_str().expect("path converts to string").to_string();
-            let dircstr = CString::new(pathstr).expect("new CString");
-            let empty = CString::new("").expect("new empty CString");
+    INITIALIZED.call_once(|| {
+        if already_initialized() {
+            return NssLoaded::External;
+        }
+
+        let path = dir.into();
+        assert!(path.is_dir());
+        let pathstr = path.to_str().expect("path converts to string").to_string();
+        let dircstr = CString::new(pathstr).expect("new CString");
+        let empty = CString::new("").expect("new empty CString");
+        unsafe {
             secstatus_to_res(nss::NSS_Initialize(
                 dircstr.as_ptr(),
                 empty.as_ptr(),
@@ -131,17 +135,15 @@ pub fn init_db<P: Into<PathBuf>>(dir: P) {
                 dircstr.as_ptr(),
             ))
             .expect("SSL_ConfigServerSessionIDCache failed");
+        }
 
-            NssLoaded::Db(path.to_path_buf().into_boxed_path())
-        });
-    }
+        NssLoaded::Db(path.to_path_buf().into_boxed_path())
+    });
 }
 
 /// Panic if NSS isn't initialized.
 pub fn assert_initialized() {
-    unsafe {
-        INITIALIZED.call_once(|| {
-            panic!("NSS not initialized with init or init_db");
-        });
-    }
+    INITIALIZED.call_once(|| {
+        panic!("NSS not initialized with init or init_db");
+    });
 }
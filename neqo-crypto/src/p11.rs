@@ -9,10 +9,13 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+use crate::constants::{Cipher, Version};
 use crate::err::{secstatus_to_res, Error, Res};
 
+use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
-use std::ptr::NonNull;
+use std::os::raw::{c_int, c_uchar, c_uint, c_void};
+use std::ptr::{null_mut, NonNull};
 
 #[allow(clippy::unreadable_literal)]
 mod nss_p11 {
@@ -57,11 +60,19 @@ macro_rules! scoped_ptr {
 scoped_ptr!(Certificate, CERTCertificate, CERT_DestroyCertificate);
 scoped_ptr!(CertList, CERTCertList, CERT_DestroyCertList);
 scoped_ptr!(PrivateKey, SECKEYPrivateKey, SECKEY_DestroyPrivateKey);
+scoped_ptr!(PublicKey, SECKEYPublicKey, SECKEY_DestroyPublicKey);
 scoped_ptr!(SymKey, PK11SymKey, PK11_FreeSymKey);
 scoped_ptr!(Slot, PK11SlotInfo, PK11_FreeSlot);
 
 impl SymKey {
-    /// You really don't want to use this.
+    /// Import `buf` as a secret for use with `hkdf` and the hash function of
+    /// `cipher`.  This is the same as `hkdf::import_key()`.
+    pub fn import(version: Version, cipher: Cipher, buf: &[u8]) -> Res<Self> {
+        crate::hkdf::import_key(version, cipher, buf)
+    }
+
+    /// The value of the key.  You really don't want to use this.  This fails
+    /// where NSS doesn't allow keys to be extracted, such as in FIPS mode.
     pub fn as_bytes<'a>(&'a self) -> Res<&'a [u8]> {
         secstatus_to_res(unsafe { PK11_ExtractKeyValue(self.ptr) })?;
 
@@ -74,8 +85,84 @@ impl SymKey {
     }
 }
 
+/// Cloning a key only adds a reference to it.
+impl Clone for SymKey {
+    fn clone(&self) -> Self {
+        let ptr = unsafe { PK11_ReferenceSymKey(self.ptr) };
+        Self::new(NonNull::new(ptr).expect("a reference to a key is never null"))
+    }
+}
+
+// NSS keys are reference counted with a lock and can be used from any thread,
+// so a key can move to another thread.  A key isn't `Sync`, because PKCS#11
+// sessions that are attached to a key can't be used concurrently.
+unsafe impl Send for SymKey {}
+
 impl std::fmt::Debug for SymKey {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "SymKey")
     }
 }
+
+/// Import `buf` as a key for `mech`, which can be used for `operation`.
+pub(crate) fn import_sym_key(
+    mech: CK_MECHANISM_TYPE,
+    operation: CK_ATTRIBUTE_TYPE,
+    buf: &[u8],
+) -> Res<SymKey> {
+    let mut item = SECItem {
+        type_: SECItemType::siBuffer,
+        data: buf.as_ptr() as *mut c_uchar,
+        len: c_uint::try_from(buf.len())?,
+    };
+    let slot_ptr = unsafe { PK11_GetInternalSlot() };
+    let slot = match NonNull::new(slot_ptr) {
+        Some(p) => Slot::new(p),
+        None => return Err(Error::InternalError),
+    };
+    let key_ptr = unsafe {
+        PK11_ImportSymKey(
+            *slot,
+            mech,
+            PK11Origin::PK11_OriginUnwrap,
+            operation,
+            &mut item,
+            null_mut(),
+        )
+    };
+    match NonNull::new(key_ptr) {
+        Some(p) => Ok(SymKey::new(p)),
+        None => Err(Error::InternalError),
+    }
+}
+
+/// Generate a new RSA key pair that isn't stored in the database.
+pub(crate) fn generate_rsa_key_pair(bits: usize) -> Res<(PublicKey, PrivateKey)> {
+    let slot = match NonNull::new(unsafe { PK11_GetInternalSlot() }) {
+        Some(p) => Slot::new(p),
+        None => return Err(Error::InternalError),
+    };
+    let mut params = PK11RSAGenParams {
+        keySizeInBits: c_int::try_from(bits)?,
+        pe: 65537,
+    };
+    let mut pub_ptr: *mut SECKEYPublicKey = null_mut();
+    let priv_ptr = unsafe {
+        PK11_GenerateKeyPair(
+            *slot,
+            CK_MECHANISM_TYPE::from(CKM_RSA_PKCS_KEY_PAIR_GEN),
+            &mut params as *mut PK11RSAGenParams as *mut c_void,
+            &mut pub_ptr,
+            0,
+            0,
+            null_mut(),
+        )
+    };
+    // Wrap both before checking, so that neither half leaks.
+    let public = NonNull::new(pub_ptr).map(PublicKey::new);
+    let private = NonNull::new(priv_ptr).map(PrivateKey::new);
+    match (public, private) {
+        (Some(p), Some(k)) => Ok((p, k)),
+        _ => Err(Error::InternalError),
+    }
+}
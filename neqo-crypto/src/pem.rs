@@ -0,0 +1,97 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Decoding PEM (RFC 7468), so that certificates and keys can be loaded from text.
+
+use crate::err::{Error, Res};
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some(u32::from(c - b'A')),
+        b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+        b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decode base64, ignoring whitespace.
+fn base64_decode(text: &str) -> Res<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0;
+    let mut bits = 0;
+    let mut padding = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            padding += 1;
+            continue;
+        }
+        if padding > 0 {
+            // Nothing can follow padding.
+            return Err(Error::CertificateLoading);
+        }
+        acc = (acc << 6) | base64_value(c).ok_or(Error::CertificateLoading)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if padding > 2 || bits >= 6 {
+        return Err(Error::CertificateLoading);
+    }
+    Ok(out)
+}
+
+/// Decode every block in `text` that has the label `label`, in order.
+/// Anything outside of those blocks is ignored.
+pub fn decode(text: &str, label: &str) -> Res<Vec<Vec<u8>>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&begin) {
+        rest = &rest[start + begin.len()..];
+        let len = rest.find(&end).ok_or(Error::CertificateLoading)?;
+        blocks.push(base64_decode(&rest[..len])?);
+        rest = &rest[len + end.len()..];
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+        assert_eq!(base64_decode("Zm8=").unwrap(), b"fo");
+        assert_eq!(base64_decode("Zm9v").unwrap(), b"foo");
+        assert_eq!(base64_decode("Zm9v\nYmFy\r\n").unwrap(), b"foobar");
+        assert_eq!(base64_decode("+/+/").unwrap(), &[0xfb, 0xff, 0xbf]);
+        assert!(base64_decode("Zm9v!").is_err());
+        assert!(base64_decode("Zg==Zg==").is_err());
+        assert!(base64_decode("Z").is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let text = "junk\n-----BEGIN THING-----\nZm9v\n-----END THING-----\n\
+                    -----BEGIN OTHER-----\nYmFy\n-----END OTHER-----\n\
+                    -----BEGIN THING-----\nYmF6\n-----END THING-----\n";
+        assert_eq!(
+            decode(text, "THING").unwrap(),
+            vec![b"foo".to_vec(), b"baz".to_vec()]
+        );
+        assert_eq!(decode(text, "OTHER").unwrap(), vec![b"bar".to_vec()]);
+        assert!(decode(text, "NOTHING").unwrap().is_empty());
+        assert!(decode("-----BEGIN THING-----\nZm9v\n", "THING").is_err());
+    }
+}
@@ -8,6 +8,8 @@ use crate::err::{Error, Res};
 use crate::ssl::PRFileDesc;
 use crate::time::{Interval, PRTime, Time};
 
+use neqo_common::{Decoder, Encoder};
+
 use std::convert::{TryFrom, TryInto};
 use std::ops::{Deref, DerefMut};
 use std::os::raw::c_uint;
@@ -37,6 +39,55 @@ scoped_ptr!(
     SSL_ReleaseAntiReplayContext
 );
 
+/// The version of the format that `AntiReplay::export()` uses.
+const EXPORT_VERSION: u64 = 1;
+
+/// Builds an `AntiReplay` context.  The defaults are a 10 second window and a
+/// filter with 7 hashes of 14 bits each, which suits most servers.
+/// See the documentation in NSS for advice on how to set these values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AntiReplayBuilder {
+    window: Duration,
+    hashes: usize,
+    bits: usize,
+}
+
+impl Default for AntiReplayBuilder {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            hashes: 7,
+            bits: 14,
+        }
+    }
+}
+
+impl AntiReplayBuilder {
+    /// The length of time that a ClientHello is remembered for.  Clients are
+    /// only able to use 0-RTT if their clock is this close to the server's.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The number of hashes that the filter uses for each ClientHello.
+    pub fn hashes(mut self, hashes: usize) -> Self {
+        self.hashes = hashes;
+        self
+    }
+
+    /// The number of bits in each hash; the filter has `2^bits` bits.
+    pub fn bits(mut self, bits: usize) -> Self {
+        self.bits = bits;
+        self
+    }
+
+    /// Make the context.
+    pub fn build(self, now: Instant) -> Res<AntiReplay> {
+        AntiReplay::new(now, self.window, self.hashes, self.bits)
+    }
+}
+
 /// `AntiReplay` is used by servers when processing 0-RTT handshakes.
 /// It limits the exposure of servers to replay attack by rejecting 0-RTT
 /// if it appears to be a replay.  There is a false-positive rate that can be
@@ -44,6 +95,7 @@ scoped_ptr!(
 #[allow(clippy::module_name_repetitions)]
 pub struct AntiReplay {
     ctx: AntiReplayContext,
+    config: AntiReplayBuilder,
 }
 
 impl AntiReplay {
@@ -64,11 +116,61 @@ impl AntiReplay {
         match NonNull::new(ctx) {
             Some(ctx_nn) => Ok(Self {
                 ctx: AntiReplayContext::new(ctx_nn),
+                config: AntiReplayBuilder {
+                    window,
+                    hashes: k,
+                    bits,
+                },
             }),
             None => Err(Error::InternalError),
         }
     }
 
+    /// Configure a new anti-replay context.
+    pub fn builder() -> AntiReplayBuilder {
+        AntiReplayBuilder::default()
+    }
+
+    /// Save the state of this context, so that `import()` can make a context
+    /// that works in the same way, whether in another server process or after
+    /// a restart.
+    ///
+    /// NSS keeps the contents of the filter to itself, so this only saves the
+    /// configuration.  That is still safe for 0-RTT: a new context rejects all
+    /// 0-RTT until one window has passed, so a ClientHello that was accepted
+    /// elsewhere can't be replayed.  Servers that share ticket keys need to
+    /// use the same configuration, so that they all reject ClientHello messages
+    /// that fall outside of the window in the same way.
+    pub fn export(&self) -> Vec<u8> {
+        let window = u64::try_from(self.config.window.as_millis()).unwrap_or(u64::max_value());
+        let mut enc = Encoder::new();
+        enc.encode_varint(EXPORT_VERSION);
+        enc.encode_uint(8, window);
+        enc.encode_varint(self.config.hashes as u64);
+        enc.encode_varint(self.config.bits as u64);
+        enc.into()
+    }
+
+    /// Make a context from the output of `export()`.
+    pub fn import(now: Instant, state: &[u8]) -> Res<Self> {
+        let mut dec = Decoder::from(state);
+        if dec.decode_varint() != Some(EXPORT_VERSION) {
+            return Err(Error::InvalidInput);
+        }
+        let window = dec.decode_uint(8).ok_or(Error::InvalidInput)?;
+        let hashes = dec.decode_varint().ok_or(Error::InvalidInput)?;
+        let bits = dec.decode_varint().ok_or(Error::InvalidInput)?;
+        if dec.remaining() > 0 {
+            return Err(Error::InvalidInput);
+        }
+        Self::new(
+            now,
+            Duration::from_millis(window),
+            usize::try_from(hashes)?,
+            usize::try_from(bits)?,
+        )
+    }
+
     /// Configure the provided socket with this anti-replay context.
     pub(crate) fn config_socket(&self, fd: *mut PRFileDesc) -> Res<()> {
         unsafe { SSL_SetAntiReplayContext(fd, *self.ctx) }
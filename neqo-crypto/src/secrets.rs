@@ -10,8 +10,14 @@ use crate::p11::{PK11SymKey, PK11_ReferenceSymKey, SymKey};
 use crate::ssl::{PRFileDesc, SSLSecretCallback, SSLSecretDirection};
 
 use neqo_common::qdebug;
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::os::raw::c_void;
+use std::path::Path;
 use std::ptr::NonNull;
 
 experimental_api!(SSL_SecretCallback(
@@ -20,7 +26,7 @@ experimental_api!(SSL_SecretCallback(
     arg: *mut c_void,
 ));
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SecretDirection {
     Read,
     Write,
@@ -64,6 +70,8 @@ impl DirectionalSecrets {
 pub struct Secrets {
     r: DirectionalSecrets,
     w: DirectionalSecrets,
+    /// Secrets that have arrived since `SecretHolder::take_new()` was called.
+    new: Vec<(SecretDirection, Epoch)>,
 }
 
 impl Secrets {
@@ -95,6 +103,7 @@ impl Secrets {
             SecretDirection::Write => &mut self.w,
         };
         keys.put(epoch, key);
+        self.new.push((dir, epoch));
     }
 
     pub fn read(&self) -> &DirectionalSecrets {
@@ -118,6 +127,11 @@ impl SecretHolder {
         let p = &*self.secrets as *const Secrets as *const c_void;
         unsafe { SSL_SecretCallback(fd, Some(Secrets::secret_available), p as *mut c_void) }
     }
+
+    /// Take the list of secrets that arrived since the last call.
+    pub fn take_new(&mut self) -> Vec<(SecretDirection, Epoch)> {
+        std::mem::replace(&mut self.secrets.new, Vec::new())
+    }
 }
 
 impl Deref for SecretHolder {
@@ -126,3 +140,90 @@ impl Deref for SecretHolder {
         self.secrets.as_ref()
     }
 }
+
+/// The label that the SSLKEYLOGFILE format uses for a secret, if it has one.
+pub fn keylog_label(is_server: bool, dir: SecretDirection, epoch: Epoch) -> Option<&'static str> {
+    // Whether this is a secret that the client writes with.
+    let client = (dir == SecretDirection::Write) != is_server;
+    match (epoch, client) {
+        (1, true) => Some("CLIENT_EARLY_TRAFFIC_SECRET"),
+        (2, true) => Some("CLIENT_HANDSHAKE_TRAFFIC_SECRET"),
+        (2, false) => Some("SERVER_HANDSHAKE_TRAFFIC_SECRET"),
+        (3, true) => Some("CLIENT_TRAFFIC_SECRET_0"),
+        (3, false) => Some("SERVER_TRAFFIC_SECRET_0"),
+        _ => None,
+    }
+}
+
+/// Something that wants to know the secrets of a connection, such as a tool
+/// for capturing and decrypting traffic.  Secrets are provided as they are
+/// in an SSLKEYLOGFILE: a label, the random value from the ClientHello, and
+/// the secret itself.
+pub trait SecretListener: std::fmt::Debug {
+    fn secret(&self, label: &str, client_random: &[u8], secret: &[u8]);
+}
+
+fn hex_string(v: &[u8]) -> String {
+    v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A `SecretListener` that writes secrets to `W` in the format of
+/// SSLKEYLOGFILE, which Wireshark and similar tools can read.
+pub struct KeyLogWriter<W: Write> {
+    w: RefCell<W>,
+}
+
+impl<W: Write> KeyLogWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self { w: RefCell::new(w) }
+    }
+
+    /// Take back the writer.
+    pub fn into_inner(self) -> W {
+        self.w.into_inner()
+    }
+}
+
+impl<W: Write> fmt::Debug for KeyLogWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyLogWriter")
+    }
+}
+
+impl<W: Write> SecretListener for KeyLogWriter<W> {
+    fn secret(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!(
+            "{} {} {}\n",
+            label,
+            hex_string(client_random),
+            hex_string(secret)
+        );
+        if let Err(e) = self.w.borrow_mut().write_all(line.as_bytes()) {
+            qdebug!("Unable to write to the key log: {}", e);
+        }
+    }
+}
+
+/// A `KeyLogWriter` that appends to a file.
+pub type KeyLogFile = KeyLogWriter<File>;
+
+impl KeyLogWriter<File> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Open the file named by the SSLKEYLOGFILE environment variable, if it
+    /// is set.  This is how other TLS implementations are told to log keys,
+    /// so applications can use this to do the same for debugging.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os("SSLKEYLOGFILE")?;
+        match Self::open(&path) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                qdebug!("Unable to open key log {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+}
@@ -10,6 +10,7 @@
 #![allow(clippy::cognitive_complexity)]
 
 use crate::constants::*;
+use crate::p11::PK11SymKey;
 
 use std::os::raw::{c_uint, c_void};
 
@@ -40,6 +41,8 @@ pub enum Opt {
     RecordSizeLimit,
     Tls13CompatMode,
     HelloDowngradeCheck,
+    RequestCertificate,
+    RequireCertificate,
 }
 
 impl Opt {
@@ -57,6 +60,8 @@ impl Opt {
             Opt::RecordSizeLimit => SSLOption::SSL_RECORD_SIZE_LIMIT,
             Opt::Tls13CompatMode => SSLOption::SSL_ENABLE_TLS13_COMPAT_MODE,
             Opt::HelloDowngradeCheck => SSLOption::SSL_ENABLE_HELLO_DOWNGRADE_CHECK,
+            Opt::RequestCertificate => SSLOption::SSL_REQUEST_CERTIFICATE,
+            Opt::RequireCertificate => SSLOption::SSL_REQUIRE_CERTIFICATE,
         };
         i as PRInt32
     }
@@ -71,11 +76,30 @@ impl Opt {
     }
 }
 
+experimental_api!(SSL_AddExternalPsk(
+    fd: *mut PRFileDesc,
+    psk: *mut PK11SymKey,
+    identity: *const u8,
+    identity_len: c_uint,
+    hash: SSLHashType::Type,
+));
+experimental_api!(SSL_CipherSuiteOrderSet(
+    fd: *mut PRFileDesc,
+    cipher_order: *const u16,
+    count: c_uint,
+));
+experimental_api!(SSL_DestroyResumptionTokenInfo(token: *mut SSLResumptionTokenInfo));
 experimental_api!(SSL_GetCurrentEpoch(
     fd: *mut PRFileDesc,
     read_epoch: *mut u16,
     write_epoch: *mut u16,
 ));
+experimental_api!(SSL_GetResumptionTokenInfo(
+    token_data: *const u8,
+    token_len: c_uint,
+    token: *mut SSLResumptionTokenInfo,
+    version: c_uint,
+));
 experimental_api!(SSL_HelloRetryRequestCallback(
     fd: *mut PRFileDesc,
     cb: SSLHelloRetryRequestCallback,
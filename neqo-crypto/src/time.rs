@@ -51,14 +51,14 @@ impl TimeZero {
     }
 }
 
-static mut BASE_TIME: OnceResult<TimeZero> = OnceResult::new();
+static BASE_TIME: OnceResult<TimeZero> = OnceResult::new();
 
 fn get_base() -> &'static TimeZero {
     let f = || TimeZero {
         instant: Instant::now(),
         prtime: unsafe { PR_Now() },
     };
-    unsafe { BASE_TIME.call_once(f) }
+    BASE_TIME.call_once(f)
 }
 
 pub(crate) fn init() {
@@ -83,7 +83,7 @@ impl From<Instant> for Time {
     fn from(t: Instant) -> Self {
         // Call `TimeZero::baseline(t)` so that time zero can be set.
         let f = || TimeZero::baseline(t);
-        let _ = unsafe { BASE_TIME.call_once(f) };
+        let _ = BASE_TIME.call_once(f);
         Self { t }
     }
 }
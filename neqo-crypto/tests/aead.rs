@@ -3,6 +3,7 @@
 use neqo_crypto::aead::Aead;
 use neqo_crypto::constants::*;
 use neqo_crypto::hkdf;
+use neqo_crypto::SymKey;
 use test_fixture::fixture_init;
 
 const AAD: &[u8] = &[
@@ -19,10 +20,10 @@ const PLAINTEXT: &[u8] = &[
     0x03, 0x04,
 ];
 
-fn make_aead(cipher: Cipher) -> Aead {
+fn make_secret(cipher: Cipher) -> SymKey {
     fixture_init();
 
-    let secret = hkdf::import_key(
+    hkdf::import_key(
         TLS_VERSION_1_3,
         cipher,
         &[
@@ -31,11 +32,14 @@ fn make_aead(cipher: Cipher) -> Aead {
             0x58, 0x1a, 0x38, 0x11,
         ],
     )
-    .expect("make a secret");
+    .expect("make a secret")
+}
+
+fn make_aead(cipher: Cipher) -> Aead {
     Aead::new(
         TLS_VERSION_1_3,
         cipher,
-        &secret,
+        &make_secret(cipher),
         "quic ", // Note the trailing space here.
     )
     .expect("can make an AEAD")
@@ -113,3 +117,111 @@ fn aead_encrypt_decrypt() {
     let res = aead.decrypt(1, &scratch[..], ciphertext, plaintext_buf);
     assert!(res.is_err());
 }
+
+#[test]
+fn pk11_aead() {
+    for &cipher in &[
+        TLS_AES_128_GCM_SHA256,
+        TLS_AES_256_GCM_SHA384,
+        TLS_CHACHA20_POLY1305_SHA256,
+    ] {
+        let aead = make_aead(cipher);
+        let pk11 = Aead::new_pk11(TLS_VERSION_1_3, cipher, &make_secret(cipher), "quic ")
+            .expect("can make a PK11 AEAD");
+
+        let ciphertext_buf = &mut [0; 1024];
+        let ciphertext = aead
+            .encrypt(7, AAD, PLAINTEXT, ciphertext_buf)
+            .expect("encrypt should work");
+        let pk11_buf = &mut [0; 1024];
+        let pk11_ciphertext = pk11
+            .encrypt(7, AAD, PLAINTEXT, pk11_buf)
+            .expect("PK11 encrypt should work");
+        assert_eq!(ciphertext, pk11_ciphertext);
+
+        let plaintext_buf = &mut [0; 1024];
+        let plaintext = pk11
+            .decrypt(7, AAD, ciphertext, plaintext_buf)
+            .expect("PK11 decrypt should work");
+        assert_eq!(plaintext, PLAINTEXT);
+        assert!(pk11.decrypt(8, AAD, ciphertext, plaintext_buf).is_err());
+    }
+}
+
+#[test]
+fn aead_in_place() {
+    for &cipher in &[
+        TLS_AES_128_GCM_SHA256,
+        TLS_AES_256_GCM_SHA384,
+        TLS_CHACHA20_POLY1305_SHA256,
+    ] {
+        let pk11 = Aead::new_pk11(TLS_VERSION_1_3, cipher, &make_secret(cipher), "quic ")
+            .expect("can make a PK11 AEAD");
+        for aead in &[make_aead(cipher), pk11] {
+            let ciphertext_buf = &mut [0; 1024];
+            let ciphertext = aead
+                .encrypt(3, AAD, PLAINTEXT, ciphertext_buf)
+                .expect("encrypt should work");
+
+            // Split the AAD in two to check that the pieces are used in order.
+            let (aad1, aad2) = AAD.split_at(5);
+            let mut data = PLAINTEXT.to_vec();
+            data.resize(PLAINTEXT.len() + aead.expansion(), 0);
+            let res = aead
+                .encrypt_in_place(3, &[aad1, aad2], &mut data)
+                .expect("encrypt in place should work");
+            assert_eq!(&res[..], ciphertext);
+
+            let res = aead
+                .decrypt_in_place(3, &[aad1, aad2], &mut data)
+                .expect("decrypt in place should work");
+            assert_eq!(&res[..], PLAINTEXT);
+
+            let mut data = ciphertext.to_vec();
+            assert!(aead.decrypt_in_place(3, &[aad2, aad1], &mut data).is_err());
+            assert!(aead.encrypt_in_place(3, &[AAD], &mut [0; 3]).is_err());
+        }
+    }
+}
+
+#[test]
+fn aead_send() {
+    let aead = make_aead(TLS_AES_128_GCM_SHA256);
+    let ciphertext_buf = &mut [0; 1024];
+    let ciphertext = aead
+        .encrypt(1, AAD, PLAINTEXT, ciphertext_buf)
+        .expect("encrypt should work")
+        .to_vec();
+
+    // Packet protection can be moved to another thread.
+    let plaintext = std::thread::spawn(move || {
+        let plaintext_buf = &mut [0; 1024];
+        aead.decrypt(1, AAD, &ciphertext, plaintext_buf)
+            .expect("decrypt should work")
+            .to_vec()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(&plaintext[..], PLAINTEXT);
+}
+
+#[test]
+fn aead_with_key() {
+    fixture_init();
+    let key = [7; 16];
+    let iv = [9; 12];
+    let aead = Aead::with_key(TLS_AES_128_GCM_SHA256, &key, &iv).expect("can make an AEAD");
+    let ciphertext_buf = &mut [0; 1024];
+    let ciphertext = aead
+        .encrypt(1, AAD, PLAINTEXT, ciphertext_buf)
+        .expect("encrypt should work");
+    let plaintext_buf = &mut [0; 1024];
+    let plaintext = aead
+        .decrypt(1, AAD, ciphertext, plaintext_buf)
+        .expect("decrypt should work");
+    assert_eq!(plaintext, PLAINTEXT);
+
+    assert!(Aead::with_key(TLS_AES_128_GCM_SHA256, &key[..15], &iv).is_err());
+    assert!(Aead::with_key(TLS_AES_256_GCM_SHA384, &key, &iv).is_err());
+    assert!(Aead::with_key(TLS_AES_128_GCM_SHA256, &key, &iv[..8]).is_err());
+}
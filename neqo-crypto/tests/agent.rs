@@ -6,6 +6,10 @@ mod handshake;
 use crate::handshake::*;
 use test_fixture::{fixture_init, now};
 
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 #[test]
 fn make_client() {
     fixture_init();
@@ -118,8 +122,13 @@ fn raw() {
 
     // The client should have one certificate for the server.
     let mut certs = client.peer_certificate().unwrap();
+    let chain = certs.chain();
     let cert_vec: Vec<&[u8]> = certs.collect();
     assert_eq!(1, cert_vec.len());
+    assert_eq!(chain, vec![cert_vec[0].to_vec()]);
+    assert!(certs.stapled_ocsp_responses().is_none());
+    assert!(certs.signed_cert_timestamp().is_none());
+    assert_eq!(client.server_name(), "server.example");
 
     // The server shouldn't have a client certificate.
     assert!(server.peer_certificate().is_none());
@@ -161,6 +170,128 @@ fn p256_server() {
     assert_eq!(server.info().unwrap().key_exchange(), TLS_GRP_EC_SECP256R1);
 }
 
+#[test]
+fn cipher_order() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let order = [TLS_AES_256_GCM_SHA384, TLS_AES_128_GCM_SHA256];
+    client.set_cipher_order(&order).expect("cipher order set");
+    server.set_cipher_order(&order).expect("cipher order set");
+
+    connect(&mut client, &mut server);
+
+    assert_eq!(
+        client.info().unwrap().cipher_suite(),
+        TLS_AES_256_GCM_SHA384
+    );
+    assert_eq!(
+        server.info().unwrap().cipher_suite(),
+        TLS_AES_256_GCM_SHA384
+    );
+}
+
+#[test]
+fn signature_scheme() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_signature_schemes(&[TLS_SIG_ECDSA_SECP256R1_SHA256])
+        .expect("signature schemes set");
+
+    connect(&mut client, &mut server);
+
+    assert_eq!(
+        client.info().unwrap().signature_scheme(),
+        TLS_SIG_ECDSA_SECP256R1_SHA256
+    );
+    assert_eq!(
+        server.info().unwrap().signature_scheme(),
+        TLS_SIG_ECDSA_SECP256R1_SHA256
+    );
+}
+
+#[test]
+fn mismatched_groups() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_groups(&[TLS_GRP_EC_SECP384R1])
+        .expect("groups set");
+    server
+        .set_groups(&[TLS_GRP_EC_SECP256R1])
+        .expect("groups set");
+
+    connect_fail(&mut client, &mut server);
+}
+
+fn psk(value: u8) -> SymKey {
+    hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &[value; 32]).expect("import PSK")
+}
+
+#[test]
+fn external_psk() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let no_certs: &[&str] = &[];
+    let mut server = Server::new(no_certs).expect("should create server");
+    client
+        .add_external_psk(b"device", &psk(1), TLS_HASH_SHA256)
+        .expect("PSK added");
+    server
+        .add_external_psk(b"device", &psk(1), TLS_HASH_SHA256)
+        .expect("PSK added");
+
+    connect(&mut client, &mut server);
+
+    assert!(client.info().unwrap().external_psk());
+    assert!(server.info().unwrap().external_psk());
+    assert!(!client.info().unwrap().resumed());
+    assert!(client.peer_certificate().is_none());
+}
+
+#[test]
+fn external_psk_mismatch() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let no_certs: &[&str] = &[];
+    let mut server = Server::new(no_certs).expect("should create server");
+    client
+        .add_external_psk(b"device", &psk(1), TLS_HASH_SHA256)
+        .expect("PSK added");
+    server
+        .add_external_psk(b"device", &psk(2), TLS_HASH_SHA256)
+        .expect("PSK added");
+
+    connect_fail(&mut client, &mut server);
+}
+
+#[test]
+fn exporter() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert!(client.channel_binding().is_err());
+
+    connect(&mut client, &mut server);
+
+    let client_cb = client.channel_binding().expect("client channel binding");
+    let server_cb = server.channel_binding().expect("server channel binding");
+    assert_eq!(client_cb.len(), 32);
+    assert_eq!(client_cb, server_cb);
+
+    let client_ekm = client
+        .export_keying_material("EXPORTER-test", Some(b"context"), 20)
+        .expect("client exporter");
+    let server_ekm = server
+        .export_keying_material("EXPORTER-test", Some(b"context"), 20)
+        .expect("server exporter");
+    assert_eq!(client_ekm, server_ekm);
+    assert_ne!(&client_ekm[..], &client_cb[..20]);
+}
+
 #[test]
 fn alpn() {
     fixture_init();
@@ -253,6 +384,366 @@ fn alpn_server_only() {
     assert_eq!(None, server.info().unwrap().alpn());
 }
 
+/// Choose a fixed protocol, if the client offers it, and check the server name.
+#[derive(Debug)]
+struct FixedAlpn(&'static str);
+impl AlpnSelector for FixedAlpn {
+    fn select(&self, server_name: Option<&str>, offered: &[String]) -> Option<String> {
+        assert_eq!(server_name, Some("server.example"));
+        offered.iter().find(|p| *p == self.0).cloned()
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_alpn(&["a", "b"]).expect("should set ALPN");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server.set_alpn(&["a"]).expect("should set ALPN");
+    server
+        .set_alpn_selector(Rc::new(FixedAlpn("b")))
+        .expect("should set ALPN selector");
+
+    connect(&mut client, &mut server);
+
+    let expected = Some(String::from("b"));
+    assert_eq!(expected.as_ref(), client.info().unwrap().alpn());
+    assert_eq!(expected.as_ref(), server.info().unwrap().alpn());
+}
+
+#[test]
+fn alpn_selector_reject() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_alpn(&["a"]).expect("should set ALPN");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .set_alpn_selector(Rc::new(FixedAlpn("b")))
+        .expect("should set ALPN selector");
+
+    connect_fail(&mut client, &mut server);
+    assert_eq!(server.alert(), Some(&120));
+}
+
+/// Connect to `server` using `server_name` and return the server certificate.
+fn server_certificate(server_name: &str, server: &mut Server) -> Vec<u8> {
+    let mut client = Client::new(server_name).expect("should create client");
+    connect(&mut client, server);
+    let mut certs = client.peer_certificate().unwrap();
+    let cert_vec: Vec<&[u8]> = certs.collect();
+    assert_eq!(1, cert_vec.len());
+    cert_vec[0].to_vec()
+}
+
+#[test]
+fn server_certificates_by_name() {
+    fixture_init();
+    let make_server = || {
+        let mut server = Server::new(&["key"]).expect("should create server");
+        server
+            .set_server_certificates("other.example", &["A long cert"])
+            .expect("should set certificates");
+        server
+    };
+
+    let default_cert = server_certificate("server.example", &mut make_server());
+    let other_cert = server_certificate("other.example", &mut make_server());
+    assert_ne!(default_cert, other_cert);
+    // Names are not case-sensitive.
+    assert_eq!(
+        server_certificate("OTHER.example", &mut make_server()),
+        other_cert
+    );
+}
+
+#[test]
+fn server_certificates_unknown() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert_eq!(
+        server.set_server_certificates("other.example", &["no such cert"]),
+        Err(Error::CertificateLoading)
+    );
+}
+
+#[test]
+fn server_certificate_status() {
+    fixture_init();
+    let cert = server_certificate("server.example", &mut Server::new(&["key"]).unwrap());
+    let status = CertificateStatus {
+        ocsp: vec![vec![1, 2, 3]],
+        scts: Some(vec![0, 4, 0, 2, 5, 6]),
+    };
+
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .set_certificate_status(&cert, status.clone())
+        .expect("should set status");
+    let mut client = Client::new("server.example").expect("should create client");
+    connect(&mut client, &mut server);
+    let mut certs = client.peer_certificate().unwrap();
+    assert_eq!(certs.stapled_ocsp_responses(), &Some(status.ocsp));
+    assert_eq!(certs.signed_cert_timestamp(), &status.scts);
+}
+
+#[test]
+fn server_certificate_status_unknown() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert_eq!(
+        server.set_certificate_status(b"no such cert", CertificateStatus::default()),
+        Err(Error::CertificateLoading)
+    );
+}
+
+#[test]
+fn server_certificate_der() {
+    const CERT: &[u8] = include_bytes!("client-cert.der");
+    const KEY: &[u8] = include_bytes!("client-key.der");
+    fixture_init();
+    let no_certs: &[&str] = &[];
+    let mut server = Server::new(no_certs).expect("should create server");
+    server
+        .add_certificate(&[CERT], KEY)
+        .expect("should add certificate");
+    assert_eq!(server_certificate("client.example", &mut server), CERT);
+}
+
+#[test]
+fn server_certificate_pem() {
+    const PEM: &str = include_str!("client.pem");
+    fixture_init();
+    let no_certs: &[&str] = &[];
+    let mut server = Server::new(no_certs).expect("should create server");
+    // The key and the certificate can be in the same file.
+    server
+        .add_certificate_pem(PEM, PEM)
+        .expect("should add certificate");
+    assert_eq!(
+        server_certificate("client.example", &mut server),
+        include_bytes!("client-cert.der").to_vec()
+    );
+}
+
+#[test]
+fn server_certificate_bad() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let no_chain: &[&[u8]] = &[];
+    assert_eq!(
+        server.add_certificate(no_chain, b"key"),
+        Err(Error::CertificateLoading)
+    );
+    assert_eq!(
+        server.add_certificate_pem(include_str!("client.pem"), "no key"),
+        Err(Error::CertificateLoading)
+    );
+}
+
+/// Connect, with the server asking for a client certificate.  Returns the
+/// certificate that the server saw.
+fn client_certificate(client: &mut Client) -> Option<Vec<u8>> {
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .request_client_certificate(false)
+        .expect("should request a certificate");
+    connect(client, &mut server);
+    let info = server.info().unwrap();
+    info.client_certificate().map(Vec::from)
+}
+
+#[test]
+fn client_certificate_from_db() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_client_certificate("key")
+        .expect("should set certificate");
+    let cert = client_certificate(&mut client).expect("should have a client certificate");
+    // This is the same certificate that the server uses.
+    let mut certs = client.peer_certificate().unwrap();
+    assert_eq!(Some(&cert[..]), (&mut certs).next());
+}
+
+#[test]
+fn client_certificate_der() {
+    const CERT: &[u8] = include_bytes!("client-cert.der");
+    const KEY: &[u8] = include_bytes!("client-key.der");
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_client_certificate_der(CERT, KEY)
+        .expect("should import certificate");
+    assert_eq!(client_certificate(&mut client), Some(CERT.to_vec()));
+}
+
+#[test]
+fn client_certificate_none() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    assert_eq!(client_certificate(&mut client), None);
+    // Without a request, a client certificate isn't sent.
+    client = Client::new("server.example").expect("should create client");
+    client
+        .set_client_certificate("key")
+        .expect("should set certificate");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    connect(&mut client, &mut server);
+    assert_eq!(server.info().unwrap().client_certificate(), None);
+}
+
+#[test]
+fn client_certificate_required() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .request_client_certificate(true)
+        .expect("should request a certificate");
+    // The client finishes before it learns that the server failed.
+    handshake(now(), &mut client, &mut server);
+    assert!(!server.state().connected());
+    assert_eq!(server.alert(), Some(&116)); // certificate_required
+}
+
+#[test]
+fn client_certificate_unknown() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    assert_eq!(
+        client.set_client_certificate("no such cert"),
+        Err(Error::CertificateLoading)
+    );
+    assert_eq!(
+        client.set_client_certificate_der(b"not a certificate", b"not a key"),
+        Err(Error::CertificateLoading)
+    );
+}
+
+/// Give a fixed answer, and remember what was checked.
+#[derive(Debug)]
+struct FixedVerifier {
+    result: AuthenticationStatus,
+    checked: RefCell<Option<(usize, AuthenticationStatus, String)>>,
+}
+impl FixedVerifier {
+    fn make(result: AuthenticationStatus) -> Rc<Self> {
+        Rc::new(Self {
+            result,
+            checked: RefCell::new(None),
+        })
+    }
+}
+impl CertificateVerifier for FixedVerifier {
+    fn verify(
+        &self,
+        chain: &[Vec<u8>],
+        nss: AuthenticationStatus,
+        server_name: &str,
+    ) -> AuthenticationStatus {
+        *self.checked.borrow_mut() = Some((chain.len(), nss, String::from(server_name)));
+        self.result
+    }
+}
+
+#[test]
+fn certificate_verifier_accept() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let verifier = FixedVerifier::make(AuthenticationStatus::Ok);
+    client
+        .set_certificate_verifier(verifier.clone())
+        .expect("should set verifier");
+    connect(&mut client, &mut server);
+
+    let checked = verifier.checked.borrow();
+    let (chain_len, _, server_name) = checked.as_ref().expect("verifier was used");
+    assert_eq!(*chain_len, 1);
+    assert_eq!(server_name, "server.example");
+}
+
+#[test]
+fn certificate_verifier_reject() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let verifier = FixedVerifier::make(AuthenticationStatus::CertUntrusted);
+    client
+        .set_certificate_verifier(verifier.clone())
+        .expect("should set verifier");
+    connect_fail(&mut client, &mut server);
+    assert!(verifier.checked.borrow().is_some());
+    assert!(client.alert().is_some());
+}
+
+/// Remember every secret.
+#[derive(Debug, Default)]
+struct SecretLog(RefCell<Vec<(String, Vec<u8>, Vec<u8>)>>);
+impl SecretListener for SecretLog {
+    fn secret(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.0
+            .borrow_mut()
+            .push((label.to_string(), client_random.to_vec(), secret.to_vec()));
+    }
+}
+
+#[test]
+fn secret_listener() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let client_log = Rc::new(SecretLog::default());
+    let server_log = Rc::new(SecretLog::default());
+    client.set_secret_listener(client_log.clone());
+    server.set_secret_listener(server_log.clone());
+
+    connect(&mut client, &mut server);
+
+    let mut client_secrets = client_log.0.borrow().clone();
+    let mut server_secrets = server_log.0.borrow().clone();
+    client_secrets.sort();
+    server_secrets.sort();
+    let labels: Vec<_> = client_secrets.iter().map(|(l, _, _)| l.as_str()).collect();
+    assert_eq!(
+        labels,
+        [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_TRAFFIC_SECRET_0",
+        ]
+    );
+    // Both ends see the same secrets, with the same client random.
+    assert_eq!(client_secrets, server_secrets);
+    assert_eq!(client_secrets[0].1.len(), 32);
+}
+
+#[test]
+fn key_log_writer() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let log = Rc::new(KeyLogWriter::new(Vec::new()));
+    client.set_secret_listener(log.clone());
+
+    connect(&mut client, &mut server);
+    drop(client);
+
+    let log = Rc::try_unwrap(log).expect("only reference").into_inner();
+    let log = String::from_utf8(log).expect("key log is text");
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 4);
+    for line in lines {
+        let fields: Vec<_> = line.split(' ').collect();
+        assert_eq!(fields.len(), 3);
+        assert!(fields[0].ends_with("_SECRET") || fields[0].ends_with("_SECRET_0"));
+        assert_eq!(fields[1].len(), 64); // 32 bytes of client random in hex.
+        assert!(fields[2].chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
+
 #[test]
 fn resume() {
     let (_, token) = resumption_setup(Resumption::WithoutZeroRtt);
@@ -269,6 +760,20 @@ fn resume() {
     assert!(server.info().unwrap().resumed());
 }
 
+#[test]
+fn resumption_token_info() {
+    let (_, token) = resumption_setup(Resumption::WithoutZeroRtt);
+    let info = ResumptionTokenInfo::decode(&token).expect("should decode token");
+    assert_eq!(info.max_early_data(), 0);
+    assert!(info.expiration() > now());
+
+    let (_, token) = resumption_setup(Resumption::WithZeroRtt);
+    let info = ResumptionTokenInfo::decode(&token).expect("should decode token");
+    assert!(info.max_early_data() > 0);
+
+    assert!(ResumptionTokenInfo::decode(&[1, 2, 3]).is_err());
+}
+
 #[test]
 fn zero_rtt() {
     let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
@@ -319,6 +824,62 @@ fn zero_rtt_no_eoed() {
     assert!(server.info().unwrap().early_data_accepted());
 }
 
+fn zero_rtt_with_anti_replay(anti_replay: &AntiReplay, token: &[u8]) -> bool {
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_resumption_token(token)
+        .expect("should accept token");
+    client.enable_0rtt().expect("should enable 0-RTT");
+    server
+        .enable_0rtt(anti_replay, 0xffff_ffff, PermissiveZeroRttChecker::make())
+        .expect("should enable 0-RTT");
+
+    connect(&mut client, &mut server);
+    assert_eq!(
+        client.info().unwrap().early_data_accepted(),
+        server.info().unwrap().early_data_accepted()
+    );
+    server.info().unwrap().early_data_accepted()
+}
+
+#[test]
+fn zero_rtt_imported_anti_replay() {
+    let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
+    let state = anti_replay.unwrap().export();
+
+    // A context that is made now might not know about 0-RTT that was
+    // accepted before it was made, so it rejects 0-RTT for one window.
+    let fresh = AntiReplay::import(now(), &state).expect("should import");
+    assert!(!zero_rtt_with_anti_replay(&fresh, &token));
+
+    let old = AntiReplay::import(now() - test_fixture::ANTI_REPLAY_WINDOW, &state)
+        .expect("should import");
+    assert!(zero_rtt_with_anti_replay(&old, &token));
+}
+
+#[test]
+fn anti_replay_export() {
+    fixture_init();
+    let anti_replay = AntiReplay::builder()
+        .window(test_fixture::ANTI_REPLAY_WINDOW)
+        .hashes(1)
+        .bits(3)
+        .build(now())
+        .expect("should build");
+    let state = anti_replay.export();
+    let imported = AntiReplay::import(now(), &state).expect("should import");
+    assert_eq!(imported.export(), state);
+
+    assert!(AntiReplay::import(now(), &state[..state.len() - 1]).is_err());
+    let mut longer = state.clone();
+    longer.push(0);
+    assert!(AntiReplay::import(now(), &longer).is_err());
+    let mut version = state;
+    version[0] ^= 1;
+    assert!(AntiReplay::import(now(), &version).is_err());
+}
+
 #[derive(Debug)]
 struct RejectZeroRtt {}
 impl ZeroRttChecker for RejectZeroRtt {
@@ -351,3 +912,64 @@ fn reject_zero_rtt() {
     assert!(!client.info().unwrap().early_data_accepted());
     assert!(!server.info().unwrap().early_data_accepted());
 }
+
+static ENCODED: AtomicUsize = AtomicUsize::new(0);
+static DECODED: AtomicUsize = AtomicUsize::new(0);
+
+/// A certificate "compression" algorithm that only reverses the message.
+struct Reverse {}
+impl CertificateCompressor for Reverse {
+    const ID: u16 = 0xff01;
+    const NAME: &'static [u8] = b"reverse\0";
+
+    fn encode(input: &[u8]) -> Res<Vec<u8>> {
+        ENCODED.fetch_add(1, Ordering::SeqCst);
+        Ok(input.iter().rev().cloned().collect())
+    }
+
+    fn decode(input: &[u8], output: &mut [u8]) -> Res<usize> {
+        DECODED.fetch_add(1, Ordering::SeqCst);
+        if input.len() > output.len() {
+            return Err(Error::InvalidInput);
+        }
+        for (o, i) in output.iter_mut().zip(input.iter().rev()) {
+            *o = *i;
+        }
+        Ok(input.len())
+    }
+}
+
+struct Unterminated {}
+impl CertificateCompressor for Unterminated {
+    const ID: u16 = 0xff02;
+    const NAME: &'static [u8] = b"unterminated";
+
+    fn encode(_input: &[u8]) -> Res<Vec<u8>> {
+        unreachable!();
+    }
+
+    fn decode(_input: &[u8], _output: &mut [u8]) -> Res<usize> {
+        unreachable!();
+    }
+}
+
+#[test]
+fn certificate_compression() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .enable_certificate_compression::<Reverse>()
+        .expect("should enable compression");
+    server
+        .enable_certificate_compression::<Reverse>()
+        .expect("should enable compression");
+    assert_eq!(
+        server.enable_certificate_compression::<Unterminated>(),
+        Err(Error::InvalidInput)
+    );
+
+    connect(&mut client, &mut server);
+    assert_eq!(ENCODED.load(Ordering::SeqCst), 1);
+    assert_eq!(DECODED.load(Ordering::SeqCst), 1);
+}
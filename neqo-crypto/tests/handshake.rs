@@ -26,7 +26,7 @@ pub fn forward_records(
     Ok(records_out)
 }
 
-fn handshake(now: Instant, client: &mut SecretAgent, server: &mut SecretAgent) {
+pub fn handshake(now: Instant, client: &mut SecretAgent, server: &mut SecretAgent) {
     let mut a = client;
     let mut b = server;
     let mut records = a.handshake_raw(now, None).unwrap();
@@ -147,3 +147,49 @@ fn expand_label_sha384() {
         ],
     );
 }
+
+#[test]
+fn expand_label_raw() {
+    fixture_init();
+    for &cipher in &[TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384] {
+        let l = cipher_hash_len(cipher);
+        let (prk, _) = import_keys(cipher);
+        let expand = |len| {
+            hkdf::expand_label_raw(
+                TLS_VERSION_1_3,
+                cipher,
+                &prk,
+                &SESSION_HASH[0..l],
+                "master secret",
+                len,
+            )
+            .expect("HKDF-Expand-Label should work")
+        };
+
+        // At the length of the hash, this matches `expand_label`.
+        let secret = hkdf::expand_label(
+            TLS_VERSION_1_3,
+            cipher,
+            &prk,
+            &SESSION_HASH[0..l],
+            "master secret",
+        )
+        .unwrap();
+        assert_eq!(&expand(l)[..], secret.as_bytes().unwrap());
+
+        // The length is part of the input, so a short output isn't a prefix.
+        let short = expand(12);
+        assert_eq!(short.len(), 12);
+        assert_ne!(&short[..], &expand(l)[..12]);
+        assert_eq!(expand(l * 2).len(), l * 2);
+    }
+}
+
+#[test]
+fn import_symkey() {
+    fixture_init();
+    let key = SymKey::import(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &IKM[0..32])
+        .expect("import should work");
+    assert_eq!(key.as_bytes().unwrap(), &IKM[0..32]);
+    assert!(SymKey::import(TLS_VERSION_1_2, TLS_AES_128_GCM_SHA256, &IKM[0..32]).is_err());
+}
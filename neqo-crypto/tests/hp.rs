@@ -37,6 +37,15 @@ fn aes256() {
     assert_eq!(mask, EXPECTED);
 }
 
+#[test]
+fn mask_into() {
+    fixture_init();
+    let hp = make_hp(TLS_AES_128_GCM_SHA256);
+    let mut output = [0; hp::MAX_MASK_LEN];
+    let mask = hp.mask_into(&[1; 16], &mut output).unwrap();
+    assert_eq!(mask, &hp.mask(&[1; 16]).unwrap()[..]);
+}
+
 #[cfg(feature = "chacha")]
 #[test]
 fn chacha20_ctr() {
@@ -53,3 +62,55 @@ fn chacha20_ctr() {
     ];
     assert_eq!(mask, EXPECTED);
 }
+
+fn check_masks(cipher: Cipher) {
+    fixture_init();
+    let hp = make_hp(cipher);
+    let samples: Vec<Vec<u8>> = (0..4_u8).map(|i| vec![i; 16]).collect();
+    let sample_refs: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+    let masks = hp.masks(&sample_refs).expect("should produce masks");
+    assert_eq!(masks.len(), samples.len());
+    for (sample, mask) in samples.iter().zip(&masks) {
+        assert_eq!(&hp.mask(sample).expect("should produce a mask"), mask);
+    }
+
+    // A clone shares the key.
+    let copy = hp.clone();
+    assert_eq!(copy.mask(&samples[1]).unwrap(), masks[1]);
+}
+
+#[test]
+fn aes128_masks() {
+    check_masks(TLS_AES_128_GCM_SHA256);
+}
+
+#[test]
+fn aes256_masks() {
+    check_masks(TLS_AES_256_GCM_SHA384);
+}
+
+#[cfg(feature = "chacha")]
+#[test]
+fn chacha20_masks() {
+    check_masks(TLS_CHACHA20_POLY1305_SHA256);
+}
+
+#[test]
+fn masks_odd_sample() {
+    fixture_init();
+    let hp = make_hp(TLS_AES_128_GCM_SHA256);
+    // A short sample means that the samples can't be done together.
+    assert!(hp.masks(&[&[0; 16], &[0; 15]]).is_err());
+    assert!(hp.masks(&[]).expect("no samples is OK").is_empty());
+}
+
+#[test]
+fn hp_send() {
+    fixture_init();
+    let hp = make_hp(TLS_AES_128_GCM_SHA256);
+    let mask = hp.mask(&[0; 16]).expect("should produce a mask");
+    let moved = std::thread::spawn(move || hp.mask(&[0; 16]).expect("should produce a mask"))
+        .join()
+        .unwrap();
+    assert_eq!(mask, moved);
+}
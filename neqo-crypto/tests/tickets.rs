@@ -0,0 +1,69 @@
+#![deny(warnings)]
+
+// Rotating ticket keys affects every server in the process, so these tests
+// are kept apart from the other tests that use resumption.
+
+use neqo_crypto::*;
+
+mod handshake;
+use crate::handshake::*;
+use test_fixture::{fixture_init, now};
+
+/// Run the handshake until the server has everything it needs to complete,
+/// returning the records that the server sends in response.
+fn handshake_to_completion(client: &mut Client, server: &mut Server) -> RecordList {
+    let records = client.handshake_raw(now(), None).unwrap();
+    let records = forward_records(now(), server, records).unwrap();
+    let records = forward_records(now(), client, records).unwrap();
+    assert_eq!(records.len(), 0);
+    assert_eq!(*client.state(), HandshakeState::AuthenticationPending);
+    client.authenticated(AuthenticationStatus::Ok);
+    let records = client.handshake_raw(now(), None).unwrap();
+    assert!(client.state().connected());
+
+    let records = forward_records(now(), server, records).unwrap();
+    assert!(server.state().connected());
+    records
+}
+
+#[test]
+fn no_tickets_by_default() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let records = handshake_to_completion(&mut client, &mut server);
+    assert_eq!(records.len(), 0);
+}
+
+#[test]
+fn tickets_on_completion() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server.set_tickets(2, &[]);
+    let records = handshake_to_completion(&mut client, &mut server);
+    assert_eq!(records.len(), 2);
+
+    for record in records {
+        let out = client
+            .handshake_raw(now(), Some(record))
+            .expect("records ingested");
+        assert_eq!(out.len(), 0);
+    }
+    assert!(client.resumption_token().is_some());
+}
+
+#[test]
+fn rotate_ticket_keys() {
+    let (_, token) = resumption_setup(Resumption::WithoutZeroRtt);
+    Server::rotate_ticket_keys().expect("should rotate keys");
+
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_resumption_token(&token[..])
+        .expect("should accept token");
+    connect(&mut client, &mut server);
+    assert!(!client.info().unwrap().resumed());
+    assert!(!server.info().unwrap().resumed());
+}
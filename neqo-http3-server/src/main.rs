@@ -6,8 +6,8 @@
 
 #![deny(warnings)]
 
-use neqo_common::{qdebug, qinfo, Datagram};
-use neqo_crypto::{init_db, AntiReplay};
+use neqo_common::{qdebug, qinfo, Datagram, Redact};
+use neqo_crypto::{init_db, AntiReplay, KeyLogFile};
 use neqo_http3::{transaction_server::Response, Header, Http3Connection, Http3State};
 use neqo_transport::{Connection, FixedConnectionIdManager, Output};
 
@@ -18,7 +18,7 @@ use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use structopt::StructOpt;
 
@@ -91,6 +91,7 @@ fn http_serve(request_headers: &[Header], _error: bool) -> Response {
             (String::from("content-length"), response.len().to_string()),
         ],
         response,
+        Vec::new(),
         None,
     )
 }
@@ -111,7 +112,8 @@ fn main() -> Result<(), io::Error> {
     assert!(!args.key.is_empty(), "Need at least one key");
 
     init_db(args.db.clone());
-    let anti_replay = AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
+    let anti_replay = AntiReplay::builder()
+        .build(Instant::now())
         .expect("unable to setup anti-replay");
 
     let poll = Poll::new()?;
@@ -164,6 +166,9 @@ fn main() -> Result<(), io::Error> {
         sockets.push(socket);
     }
 
+    // Log secrets to the file named by SSLKEYLOGFILE, if that is set.
+    let key_log = KeyLogFile::from_env().map(Rc::new);
+
     let buf = &mut [0u8; 2048];
     let mut connections: HashMap<SocketAddr, (Http3Connection, Option<Timeout>)> = HashMap::new();
 
@@ -176,7 +181,7 @@ fn main() -> Result<(), io::Error> {
         for event in &events {
             if event.token() == TIMER_TOKEN {
                 while let Some(remote_addr) = timer.poll() {
-                    qinfo!("Timer expired for {:?}", remote_addr);
+                    qinfo!("Timer expired for {:?}", Redact(remote_addr));
                     // Adds an entry to in_dgrams but doesn't add any
                     // packets. This will cause the Connection to be
                     // process()ed.
@@ -218,15 +223,19 @@ fn main() -> Result<(), io::Error> {
         for (remote_addr, dgrams) in in_dgrams {
             let (server, svr_timeout) = connections.entry(remote_addr).or_insert_with(|| {
                 println!("New connection from {:?}", remote_addr);
+                let mut transport = Connection::new_server(
+                    &[args.key.clone()],
+                    &[args.alpn.clone()],
+                    &anti_replay,
+                    Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+                )
+                .expect("must succeed");
+                if let Some(log) = &key_log {
+                    transport.set_secret_listener(log.clone());
+                }
                 (
                     Http3Connection::new(
-                        Connection::new_server(
-                            &[args.key.clone()],
-                            &[args.alpn.clone()],
-                            &anti_replay,
-                            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
-                        )
-                        .expect("must succeed"),
+                        transport,
                         args.max_table_size,
                         args.max_blocked_streams,
                         Some(Box::new(http_serve)),
@@ -262,7 +271,11 @@ fn main() -> Result<(), io::Error> {
                             timer.cancel_timeout(svr_timeout);
                         }
 
-                        qinfo!("Setting timeout of {:?} for {:?}", new_timeout, remote_addr);
+                        qinfo!(
+                            "Setting timeout of {:?} for {:?}",
+                            new_timeout,
+                            Redact(remote_addr)
+                        );
                         *svr_timeout = Some(timer.set_timeout(new_timeout, remote_addr));
                         break;
                     }
@@ -0,0 +1,119 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// HTTP Datagrams and the Capsule Protocol, from RFC 9297.
+//
+// After a 2xx response to an extended CONNECT request that has a
+// `capsule-protocol` header, or that starts a WebTransport session, the DATA
+// frames on the request stream carry capsules, each of which is a type, a
+// length and a value.  HTTP Datagrams belong to a request stream.  They could
+// be sent in QUIC DATAGRAM frames that start with the quarter stream ID, but
+// the transport doesn't support those, so they are always sent in DATAGRAM
+// capsules.
+
+use crate::{header_value, Header};
+use neqo_common::{Decoder, Encoder};
+
+pub(crate) const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+
+/// The header that says that a request or response uses capsules.
+pub const CAPSULE_PROTOCOL_HEADER: &str = "capsule-protocol";
+
+/// Whether `headers` are for an extended CONNECT request that uses capsules.
+pub(crate) fn is_capsule_request(headers: &[Header]) -> bool {
+    header_value(headers, ":method") == Some("CONNECT")
+        && header_value(headers, ":protocol").is_some()
+        && header_value(headers, CAPSULE_PROTOCOL_HEADER) == Some("?1")
+}
+
+pub(crate) fn encode_capsule(capsule_type: u64, value: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode_varint(capsule_type);
+    enc.encode_vvec(value);
+    enc.into()
+}
+
+/// Collects capsules from a request stream.
+#[derive(Debug, Default)]
+pub(crate) struct CapsuleReader {
+    buf: Vec<u8>,
+    fin: bool,
+}
+
+impl CapsuleReader {
+    pub fn receive(&mut self, data: &[u8], fin: bool) {
+        self.buf.extend_from_slice(data);
+        self.fin |= fin;
+    }
+
+    /// Whether the end of the stream was received.
+    pub fn fin(&self) -> bool {
+        self.fin
+    }
+
+    /// Whether part of a capsule is left over.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The type and value of the next capsule, once all of it is here.
+    pub fn next_capsule(&mut self) -> Option<(u64, Vec<u8>)> {
+        let mut dec = Decoder::new(&self.buf);
+        let capsule_type = dec.decode_varint()?;
+        let value = dec.decode_vvec()?.to_vec();
+        let used = self.buf.len() - dec.remaining();
+        self.buf.drain(..used);
+        Some((capsule_type, value))
+    }
+
+    /// The next HTTP Datagram.  Capsules of other types are skipped, as none
+    /// are understood yet.
+    pub fn next_datagram(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (capsule_type, value) = self.next_capsule()?;
+            if capsule_type == CAPSULE_TYPE_DATAGRAM {
+                return Some(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capsule_request() {
+        let mut headers = vec![
+            (String::from(":method"), String::from("CONNECT")),
+            (String::from(":protocol"), String::from("connect-udp")),
+        ];
+        assert!(!is_capsule_request(&headers));
+        headers.push((String::from("capsule-protocol"), String::from("?1")));
+        assert!(is_capsule_request(&headers));
+        headers.remove(1);
+        assert!(!is_capsule_request(&headers));
+    }
+
+    #[test]
+    fn capsules() {
+        let datagram = encode_capsule(CAPSULE_TYPE_DATAGRAM, &[1, 2, 3]);
+        assert_eq!(datagram, vec![0x00, 0x03, 1, 2, 3]);
+
+        let mut reader = CapsuleReader::default();
+        // An unknown capsule, then a datagram that arrives in two parts.
+        reader.receive(&[0x17, 0x01, 0xff], false);
+        reader.receive(&datagram[..2], false);
+        assert_eq!(reader.next_capsule(), Some((0x17, vec![0xff])));
+        assert_eq!(reader.next_datagram(), None);
+        assert!(!reader.is_empty());
+        reader.receive(&datagram[2..], true);
+        assert_eq!(reader.next_datagram(), Some(vec![1, 2, 3]));
+        assert_eq!(reader.next_datagram(), None);
+        assert!(reader.is_empty());
+        assert!(reader.fin());
+    }
+}
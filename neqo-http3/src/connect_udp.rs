@@ -0,0 +1,120 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Proxying UDP in HTTP, from RFC 9298.
+//
+// A UDP tunnel is an extended CONNECT request with a `:protocol` of
+// `connect-udp`, where the path names the target.  UDP payloads are HTTP
+// Datagrams (RFC 9297) with a context ID of 0, which are sent as capsules on
+// the request stream.
+
+use crate::{header_value, Header};
+use crate::{Error, Res};
+use neqo_common::{Decoder, Encoder};
+
+/// The value of `:protocol` in a request for a UDP tunnel.
+pub const CONNECT_UDP_PROTOCOL: &str = "connect-udp";
+
+const CONTEXT_ID_UDP_PAYLOAD: u64 = 0;
+const UDP_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// The path for a UDP tunnel to `host` and `port`, using the default URI
+/// template of "/.well-known/masque/udp/{target_host}/{target_port}/".
+pub fn connect_udp_path(host: &str, port: u16) -> String {
+    format!("{}{}/{}/", UDP_PATH_PREFIX, host.replace(':', "%3A"), port)
+}
+
+/// The target host and port of a request for a UDP tunnel, if it has one.
+pub fn connect_udp_target(headers: &[Header]) -> Option<(String, u16)> {
+    let path = header_value(headers, ":path")?;
+    if !path.starts_with(UDP_PATH_PREFIX) {
+        return None;
+    }
+    let target = &path[UDP_PATH_PREFIX.len()..];
+    let mut parts = target.splitn(3, '/');
+    let host = parts.next()?.replace("%3A", ":").replace("%3a", ":");
+    let port = parts.next()?.parse().ok()?;
+    if host.is_empty() || parts.next() != Some("") {
+        return None;
+    }
+    Some((host, port))
+}
+
+/// Whether `headers` are for a request that asks for a UDP tunnel.
+pub(crate) fn is_connect_udp_request(headers: &[Header]) -> bool {
+    header_value(headers, ":method") == Some("CONNECT")
+        && header_value(headers, ":protocol") == Some(CONNECT_UDP_PROTOCOL)
+}
+
+/// An HTTP Datagram that carries a UDP payload.
+pub(crate) fn encode_udp_payload(payload: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode_varint(CONTEXT_ID_UDP_PAYLOAD);
+    enc.encode(payload);
+    enc.into()
+}
+
+/// The UDP payload in an HTTP Datagram, or `None` if the datagram has another
+/// context ID.
+pub(crate) fn decode_udp_payload(datagram: &[u8]) -> Res<Option<&[u8]>> {
+    let mut dec = Decoder::new(datagram);
+    match dec.decode_varint() {
+        Some(CONTEXT_ID_UDP_PAYLOAD) => Ok(Some(&datagram[datagram.len() - dec.remaining()..])),
+        Some(_) => Ok(None),
+        None => Err(Error::GeneralProtocolError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &str) -> Vec<Header> {
+        vec![(String::from(":path"), String::from(p))]
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(
+            connect_udp_path("192.0.2.6", 443),
+            "/.well-known/masque/udp/192.0.2.6/443/"
+        );
+        assert_eq!(
+            connect_udp_path("2001:db8::42", 53),
+            "/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/"
+        );
+        assert_eq!(
+            connect_udp_target(&path("/.well-known/masque/udp/2001%3adb8%3A%3A42/53/")),
+            Some((String::from("2001:db8::42"), 53))
+        );
+        assert_eq!(
+            connect_udp_target(&path("/.well-known/masque/udp/example.com/443/")),
+            Some((String::from("example.com"), 443))
+        );
+        assert_eq!(
+            connect_udp_target(&path("/.well-known/masque/udp/example.com/443")),
+            None
+        );
+        assert_eq!(
+            connect_udp_target(&path("/.well-known/masque/udp//443/")),
+            None
+        );
+        assert_eq!(
+            connect_udp_target(&path("/.well-known/masque/udp/example.com/x/")),
+            None
+        );
+        assert_eq!(connect_udp_target(&path("/index.html")), None);
+    }
+
+    #[test]
+    fn udp_payload() {
+        let datagram = encode_udp_payload(&[1, 2, 3]);
+        assert_eq!(datagram, vec![0x00, 1, 2, 3]);
+        assert_eq!(decode_udp_payload(&datagram), Ok(Some(&[1, 2, 3][..])));
+        assert_eq!(decode_udp_payload(&[0x02, 0x09]), Ok(None));
+        assert_eq!(decode_udp_payload(&[]), Err(Error::GeneralProtocolError));
+    }
+}
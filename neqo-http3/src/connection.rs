@@ -6,25 +6,35 @@
 
 use smallvec::SmallVec;
 
-use crate::hframe::{HFrame, HFrameReader, HSettingType, H3_FRAME_TYPE_DATA};
+use crate::capsule::{self, CapsuleReader, CAPSULE_PROTOCOL_HEADER};
+use crate::connect_udp::{self, CONNECT_UDP_PROTOCOL};
+use crate::hframe::{self, HFrame, HFrameReader, HSettingType, H3_FRAME_TYPE_DATA};
+use crate::origin;
 use crate::transaction_client::TransactionClient;
 use crate::transaction_server::{RequestHandler, TransactionServer};
-use crate::Header;
+use crate::tunnel::{self, Tunnel};
+use crate::webtransport::{
+    self, WebTransportSessions, WEBTRANSPORT_BIDI_STREAM_SIGNAL, WEBTRANSPORT_PROTOCOL,
+    WEBTRANSPORT_UNI_STREAM_TYPE,
+};
+use crate::{is_success_response, Header};
 use neqo_common::{
     qdebug, qerror, qinfo, qwarn, Datagram, Decoder, Encoder, IncrementalDecoder,
     IncrementalDecoderResult,
 };
 use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus, SecretAgentInfo};
 use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
-use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
+use neqo_qpack::encoder::{QPackEncoder, QPackEncoderStats, QPACK_UNI_STREAM_TYPE_ENCODER};
 use neqo_transport::{
-    AppError, CloseError, Connection, ConnectionEvent, Output, Role, State, StreamType,
+    AppError, CloseError, Connection, ConnectionError, ConnectionEvent, Error as TransportError,
+    Output, Role, State, StreamType,
 };
 use std::cell::RefCell;
+use std::cmp::max;
 use std::collections::{BTreeSet, HashMap};
 use std::mem;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
@@ -176,18 +186,57 @@ pub struct Http3Connection {
     settings_received: bool,
     streams_are_readable: BTreeSet<u64>,
     streams_have_data_to_send: BTreeSet<u64>,
+    /// Why the connection is ending, once that is known.
+    close_reason: Option<CloseReason>,
+    /// Whether this endpoint enabled extended CONNECT and WebTransport.
+    webtransport: bool,
+    /// Whether the peer enabled extended CONNECT.
+    peer_connect_protocol: bool,
+    /// Whether the peer enabled WebTransport.
+    peer_webtransport: bool,
+    wt_sessions: WebTransportSessions,
+    /// Whether this endpoint enabled extended CONNECT for UDP tunnels.
+    connect_udp: bool,
+    /// Whether this endpoint enabled extended CONNECT for other protocols
+    /// that use capsules.
+    extended_connect: bool,
+    /// Request streams that carry capsules, including UDP tunnels.
+    capsule_streams: HashMap<u64, CapsuleReader>,
+    /// Settings for extensions that the application added.
+    extra_settings: Vec<(u64, u64)>,
+    /// All the settings that the peer sent.
+    peer_settings: HashMap<u64, u64>,
+    /// Bidirectional streams from the peer where the first value hasn't been
+    /// read.  That says whether the stream is a request or in a WebTransport
+    /// session.  This is only used if WebTransport is enabled.
+    new_bidi_streams: HashMap<u64, NewStreamTypeReader>,
+    /// WebTransport streams from the peer where the session ID hasn't been read.
+    wt_new_streams: HashMap<u64, NewStreamTypeReader>,
+    /// The stream ID in the GOAWAY that was sent, on a server, or received,
+    /// on a client.
+    goaway_stream_id: Option<u64>,
+    /// The ID of the first request stream that a server hasn't seen yet.
+    next_request_stream_id: u64,
     // Client only
+    /// The origins from ORIGIN frames.
+    origins: BTreeSet<String>,
     events: Http3Events,
     transactions_client: HashMap<u64, TransactionClient>,
+    /// When requests are cancelled if they haven't finished, by stream ID.
+    deadlines: HashMap<u64, Instant>,
     // Server only
     #[allow(clippy::type_complexity)]
     handler: Option<RequestHandler>,
     transactions_server: HashMap<u64, TransactionServer>,
+    /// CONNECT tunnels, by the ID of the request stream.
+    tunnels: HashMap<u64, Tunnel>,
+    /// The request streams of WebTransport sessions, which carry capsules.
+    wt_session_streams: HashMap<u64, Tunnel>,
 }
 
 impl ::std::fmt::Display for Http3Connection {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "Http3 connection {:?}", self.role())
+        write!(f, "Http3 {}", self.conn)
     }
 }
 
@@ -216,10 +265,28 @@ impl Http3Connection {
             qpack_decoder: QPackDecoder::new(max_table_size, max_blocked_streams),
             new_streams: HashMap::new(),
             transactions_client: HashMap::new(),
+            deadlines: HashMap::new(),
             transactions_server: HashMap::new(),
+            tunnels: HashMap::new(),
+            wt_session_streams: HashMap::new(),
             settings_received: false,
             streams_are_readable: BTreeSet::new(),
             streams_have_data_to_send: BTreeSet::new(),
+            close_reason: None,
+            webtransport: false,
+            peer_connect_protocol: false,
+            peer_webtransport: false,
+            connect_udp: false,
+            extended_connect: false,
+            capsule_streams: HashMap::new(),
+            extra_settings: Vec::new(),
+            peer_settings: HashMap::new(),
+            goaway_stream_id: None,
+            next_request_stream_id: 0,
+            origins: BTreeSet::new(),
+            wt_sessions: WebTransportSessions::default(),
+            new_bidi_streams: HashMap::new(),
+            wt_new_streams: HashMap::new(),
             events: Http3Events::default(),
             handler,
         }
@@ -238,6 +305,151 @@ impl Http3Connection {
         self.conn.authenticated(status, now);
     }
 
+    /// Enable resumption on the client, using a token previously obtained
+    /// from `Connection::resumption_token()`.  If the token allows 0-RTT,
+    /// requests can be made immediately.  If the server then rejects 0-RTT,
+    /// those requests are sent again, which is reported in `Http3Event::HeaderReady`.
+    /// If the server lowered its limits, the requests can't be sent again;
+    /// they end with `Http3Event::Reset` and `Error::RequestRejected` instead.
+    pub fn set_resumption_token(&mut self, now: Instant, token: &[u8]) -> Res<()> {
+        self.conn.set_0rtt_resend(true);
+        self.conn.set_resumption_token(now, token)?;
+        if self.conn.zero_rtt_enabled() {
+            qdebug!([self] "0-RTT enabled, starting HTTP/3 early");
+            self.initialize_http3_connection()?;
+        }
+        Ok(())
+    }
+
+    /// Enable extended CONNECT and WebTransport.  This has to be done before
+    /// SETTINGS are sent, which happens when the connection is established or
+    /// 0-RTT is enabled.  A server that enables WebTransport accepts a session
+    /// when the request handler answers a WebTransport request with a 2xx
+    /// status.
+    pub fn enable_webtransport(&mut self) -> Res<()> {
+        if self.control_stream_local.stream_id.is_some() {
+            return Err(Error::Unavailable);
+        }
+        self.webtransport = true;
+        Ok(())
+    }
+
+    /// Whether WebTransport sessions can be used, which needs both endpoints
+    /// to enable them.  A client also needs the server to enable extended
+    /// CONNECT.
+    pub fn webtransport_enabled(&self) -> bool {
+        self.webtransport
+            && self.peer_webtransport
+            && (self.role() == Role::Server || self.peer_connect_protocol)
+    }
+
+    /// Enable extended CONNECT for UDP tunnels.  Like `enable_webtransport()`,
+    /// this has to be done before SETTINGS are sent.  A server that enables
+    /// this opens a tunnel when the request handler answers a request for one
+    /// with a 2xx status.
+    pub fn enable_connect_udp(&mut self) -> Res<()> {
+        if self.control_stream_local.stream_id.is_some() {
+            return Err(Error::Unavailable);
+        }
+        self.connect_udp = true;
+        Ok(())
+    }
+
+    /// Whether UDP tunnels can be used.  A client needs the server to enable
+    /// extended CONNECT.
+    pub fn connect_udp_enabled(&self) -> bool {
+        self.connect_udp && (self.role() == Role::Server || self.peer_connect_protocol)
+    }
+
+    /// Enable extended CONNECT for any protocol that uses capsules, like
+    /// `enable_connect_udp()` does for UDP tunnels.  A server that enables
+    /// this takes such a request when the request handler answers it with a
+    /// 2xx status.
+    pub fn enable_extended_connect(&mut self) -> Res<()> {
+        if self.control_stream_local.stream_id.is_some() {
+            return Err(Error::Unavailable);
+        }
+        self.extended_connect = true;
+        Ok(())
+    }
+
+    /// Whether extended CONNECT requests with capsules can be used.  A client
+    /// needs the server to enable extended CONNECT.
+    pub fn extended_connect_enabled(&self) -> bool {
+        self.extended_connect && (self.role() == Role::Server || self.peer_connect_protocol)
+    }
+
+    /// Send `value` for the setting `id` in SETTINGS, for an extension that
+    /// this crate doesn't implement.  Settings that this crate uses itself
+    /// can't be set this way.  Like `enable_webtransport()`, this has to be
+    /// done before SETTINGS are sent.
+    pub fn add_setting(&mut self, id: u64, value: u64) -> Res<()> {
+        if self.control_stream_local.stream_id.is_some() {
+            return Err(Error::Unavailable);
+        }
+        match id {
+            // These are only for HTTP/2.
+            0x0 | 0x2..=0x5 => return Err(Error::InvalidInput),
+            _ if HSettingType::from_id(id) != HSettingType::Unknown(id) => {
+                return Err(Error::InvalidInput)
+            }
+            _ => {}
+        }
+        self.extra_settings.retain(|(i, _)| *i != id);
+        self.extra_settings.push((id, value));
+        Ok(())
+    }
+
+    /// Add a setting with a reserved identifier to SETTINGS, which peers
+    /// have to ignore.  This checks that they do.  The identifier and value
+    /// are picked using `seed`, so that a run can be reproduced.
+    pub fn enable_grease(&mut self, seed: u64) -> Res<()> {
+        if self.control_stream_local.stream_id.is_some() {
+            return Err(Error::Unavailable);
+        }
+        let (id, value) = hframe::grease_setting(seed);
+        qinfo!([self] "greased setting {:#x} = {}", id, value);
+        self.extra_settings.push((id, value));
+        Ok(())
+    }
+
+    /// Limit how much the QPACK encoder uses the peer's dynamic table: at most
+    /// `max_capacity` bytes of it, and with at most `max_blocked_streams`
+    /// streams waiting for inserts.  Less blocking means less head-of-line
+    /// blocking but worse compression; 0 means that streams never block.
+    /// This has to be done before the peer's SETTINGS arrive.
+    pub fn set_qpack_encoder_policy(
+        &mut self,
+        max_capacity: u64,
+        max_blocked_streams: u16,
+    ) -> Res<()> {
+        if self.settings_received {
+            return Err(Error::Unavailable);
+        }
+        self.qpack_encoder.set_capacity_limit(max_capacity);
+        self.qpack_encoder
+            .set_blocked_streams_limit(max_blocked_streams);
+        Ok(())
+    }
+
+    /// Never put headers called `name` in the QPACK dynamic table, so that
+    /// their values can't be probed for.  This is already the case for
+    /// authorization, cookie and set-cookie.
+    pub fn never_index_header(&mut self, name: &str) {
+        self.qpack_encoder.never_index(name);
+    }
+
+    /// How well QPACK compression has worked so far.
+    pub fn qpack_encoder_stats(&self) -> QPackEncoderStats {
+        self.qpack_encoder.stats()
+    }
+
+    /// The value of setting `id` from the SETTINGS that the peer sent, if
+    /// they arrived and included it.
+    pub fn peer_setting(&self, id: u64) -> Option<u64> {
+        self.peer_settings.get(&id).cloned()
+    }
+
     fn initialize_http3_connection(&mut self) -> Res<()> {
         qdebug!([self] "initialize_http3_connection");
         self.create_control_stream()?;
@@ -266,18 +478,31 @@ impl Http3Connection {
 
     fn create_settings(&mut self) {
         qdebug!([self] "create_settings.");
-        self.control_stream_local.send_frame(HFrame::Settings {
-            settings: vec![
-                (
-                    HSettingType::MaxTableSize,
-                    self.qpack_decoder.get_max_table_size().into(),
-                ),
-                (
-                    HSettingType::BlockedStreams,
-                    self.qpack_decoder.get_blocked_streams().into(),
-                ),
-            ],
-        });
+        let mut settings = vec![
+            (
+                HSettingType::MaxTableSize,
+                self.qpack_decoder.get_max_table_size().into(),
+            ),
+            (
+                HSettingType::BlockedStreams,
+                self.qpack_decoder.get_blocked_streams().into(),
+            ),
+        ];
+        if self.role() == Role::Server
+            && (self.webtransport || self.connect_udp || self.extended_connect)
+        {
+            settings.push((HSettingType::EnableConnectProtocol, 1));
+        }
+        if self.webtransport {
+            settings.push((HSettingType::EnableWebTransport, 1));
+        }
+        settings.extend(
+            self.extra_settings
+                .iter()
+                .map(|(id, value)| (HSettingType::Unknown(*id), *value)),
+        );
+        self.control_stream_local
+            .send_frame(HFrame::Settings { settings });
     }
 
     // This function takes the provided result and check for an error.
@@ -286,7 +511,7 @@ impl Http3Connection {
         match &res {
             Err(e) => {
                 qinfo!([self] "Connection error: {}.", e);
-                self.close(now, e.code(), &format!("{}", e));
+                self.close_for(now, CloseReason::from(e), e.code(), &format!("{}", e));
                 true
             }
             _ => false,
@@ -328,6 +553,7 @@ impl Http3Connection {
                 if self.check_result(now, res) {
                     return;
                 }
+                self.check_deadlines(now);
                 let res = self.process_reading();
                 if self.check_result(now, res) {
                     return;
@@ -338,14 +564,56 @@ impl Http3Connection {
             Http3State::Closed { .. } => {}
             _ => {
                 let res = self.check_connection_events();
-                let _ = self.check_result(now, res);
+                if self.check_result(now, res) {
+                    return;
+                }
+                // Requests can be sent in 0-RTT.
+                if self.conn.zero_rtt_enabled() {
+                    let res = self.process_sending();
+                    self.check_result(now, res);
+                }
             }
         }
     }
 
     pub fn process_output(&mut self, now: Instant) -> Output {
         qdebug!([self] "Process output.");
-        self.conn.process_output(now)
+        match self.conn.process_output(now) {
+            // Wake up in time for the next request deadline.
+            Output::Callback(delay) => match self.next_deadline() {
+                Some(deadline) if deadline <= now => Output::Callback(Duration::new(0, 0)),
+                Some(deadline) if deadline < now + delay => Output::Callback(deadline - now),
+                _ => Output::Callback(delay),
+            },
+            out => out,
+        }
+    }
+
+    /// The earliest deadline of a request that is still going.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        let transactions = &self.transactions_client;
+        self.deadlines.retain(|id, _| transactions.contains_key(id));
+        self.deadlines.values().min().cloned()
+    }
+
+    /// Cancel the requests that have passed their deadline.
+    fn check_deadlines(&mut self, now: Instant) {
+        let expired = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for stream_id in expired {
+            self.deadlines.remove(&stream_id);
+            if self.transactions_client.contains_key(&stream_id) {
+                qinfo!([self] "Request on stream {} timed out.", stream_id);
+                // Either side of the stream might be closed already.
+                let _ = self.stream_reset(stream_id, Error::RequestCancelled.code());
+                self.events.remove_events_for_stream_id(stream_id);
+                self.events.request_timeout(stream_id);
+            }
+        }
     }
 
     // If this return an error the connection must be closed.
@@ -366,7 +634,8 @@ impl Http3Connection {
             for stream_id in to_send {
                 if let Some(cs) = &mut self.transactions_client.get_mut(&stream_id) {
                     cs.send_request_headers(&mut self.conn, &mut self.qpack_encoder)?;
-                    if cs.is_state_sending_headers() {
+                    cs.send_pending_trailers(&mut self.conn)?;
+                    if cs.is_state_sending_headers() || cs.is_state_sending_trailers() {
                         self.streams_have_data_to_send.insert(stream_id);
                     }
                 }
@@ -376,14 +645,24 @@ impl Http3Connection {
                 let mut remove_stream = false;
                 if let Some(cs) = &mut self.transactions_server.get_mut(&stream_id) {
                     cs.send(&mut self.conn)?;
-                    if cs.is_state_sending() {
+                    if cs.is_state_sending() || cs.has_data_to_send() {
                         self.streams_have_data_to_send.insert(stream_id);
-                    } else {
+                    } else if cs.is_state_streaming() {
+                        // The response headers are out, so the body can follow.
+                        self.events.data_writable(stream_id);
+                    } else if cs.done() {
                         remove_stream = true;
                     }
                 }
                 if remove_stream {
                     self.transactions_server.remove(&stream_id);
+                    // Anything that arrived on a tunnel or session while the
+                    // response was pending wasn't read.
+                    if self.tunnels.contains_key(&stream_id) {
+                        self.events.data_readable(stream_id);
+                    } else if self.wt_sessions.is_session(stream_id) {
+                        self.read_webtransport_session(stream_id)?;
+                    }
                 }
             }
         }
@@ -427,21 +706,35 @@ impl Http3Connection {
                     self.events.authentication_needed();
                 }
                 ConnectionEvent::StateChange(state) => {
+                    if let Some(reason) = CloseReason::from_state(&state) {
+                        self.set_close_reason(reason);
+                    }
                     match state {
                         State::Connected => self.handle_connection_connected()?,
-                        State::Closing { error, .. } => {
+                        State::Closing { error, .. } | State::Draining { error, .. } => {
                             self.handle_connection_closing(error.clone().into())?
                         }
-                        State::Closed(error) => {
+                        State::Closed { error, .. } => {
                             self.handle_connection_closed(error.clone().into())?
                         }
                         _ => {}
                     };
                 }
-                ConnectionEvent::ZeroRttRejected => {
-                    // TODO(mt) work out what to do here.
-                    // Everything will have to be redone: SETTINGS, qpack streams, and requests.
+                ConnectionEvent::ZeroRttRejected => self.handle_zero_rtt_rejected()?,
+                ConnectionEvent::ZeroRttResent => {
+                    for t in self.transactions_client.values_mut() {
+                        t.zero_rtt_resent();
+                    }
                 }
+                ConnectionEvent::ZeroRttAccepted => {
+                    for t in self.transactions_client.values_mut() {
+                        t.zero_rtt_accepted();
+                    }
+                }
+                ConnectionEvent::HandshakeComplete(_) | ConnectionEvent::HandshakeConfirmed => {}
+                // HTTP/3 doesn't put streams in groups.
+                ConnectionEvent::StreamGroupWritable { .. }
+                | ConnectionEvent::StreamGroupReadable { .. } => {}
             }
         }
         Ok(())
@@ -449,7 +742,22 @@ impl Http3Connection {
 
     fn handle_new_stream(&mut self, stream_id: u64, stream_type: StreamType) -> Res<()> {
         qdebug!([self] "A new stream: {:?} {}.", stream_type, stream_id);
+        if stream_type == StreamType::BiDi && self.role() == Role::Server {
+            if self.goaway_stream_id.map_or(false, |id| stream_id >= id) {
+                qdebug!([self] "Rejecting request {} after GOAWAY", stream_id);
+                let err = Error::RequestRejected.code();
+                self.conn.stream_stop_sending(stream_id, err)?;
+                self.conn.stream_reset_send(stream_id, err)?;
+                return Ok(());
+            }
+            self.next_request_stream_id = max(self.next_request_stream_id, stream_id + 4);
+        }
         match stream_type {
+            StreamType::BiDi if self.webtransport => {
+                self.new_bidi_streams
+                    .insert(stream_id, NewStreamTypeReader::new());
+                self.read_new_bidi_stream(stream_id)?;
+            }
             StreamType::BiDi => match self.role() {
                 Role::Server => self.handle_new_client_request(stream_id),
                 Role::Client => {
@@ -488,14 +796,161 @@ impl Http3Connection {
             if cs.is_state_sending_data() {
                 self.events.data_writable(stream_id);
             }
+        } else if let Some(ts) = self.transactions_server.get(&stream_id) {
+            if ts.is_state_streaming() && !ts.has_data_to_send() {
+                self.events.data_writable(stream_id);
+            }
+        } else if self.wt_sessions.session_of(stream_id).is_some()
+            || self.tunnels.contains_key(&stream_id)
+        {
+            self.events.data_writable(stream_id);
+        }
+        Ok(())
+    }
+
+    /// Read the first value on a bidirectional stream from the peer, which
+    /// is either the type of the first frame of a request or the signal for a
+    /// WebTransport stream.
+    fn read_new_bidi_stream(&mut self, stream_id: u64) -> Res<()> {
+        let (first, fin) = match self.new_bidi_streams.get_mut(&stream_id) {
+            Some(ns) => (ns.get_type(&mut self.conn, stream_id), ns.fin),
+            None => return Ok(()),
+        };
+        if fin {
+            self.new_bidi_streams.remove(&stream_id);
+            return Ok(());
+        }
+        let first = match first {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        self.new_bidi_streams.remove(&stream_id);
+        if first == WEBTRANSPORT_BIDI_STREAM_SIGNAL {
+            self.wt_new_streams
+                .insert(stream_id, NewStreamTypeReader::new());
+            self.read_webtransport_session_id(stream_id)
+        } else if self.role() == Role::Server {
+            self.transactions_server.insert(
+                stream_id,
+                TransactionServer::with_frame_type(stream_id, first),
+            );
+            self.read_stream_server(stream_id)?;
+            Ok(())
+        } else {
+            qerror!("Client received a new bidirectional stream!");
+            self.conn.stream_stop_sending(stream_id, 0)?;
+            Ok(())
+        }
+    }
+
+    /// Read the session ID from the start of a WebTransport stream.
+    fn read_webtransport_session_id(&mut self, stream_id: u64) -> Res<()> {
+        let (session_id, fin) = match self.wt_new_streams.get_mut(&stream_id) {
+            Some(ns) => (ns.get_type(&mut self.conn, stream_id), ns.fin),
+            None => return Ok(()),
+        };
+        if fin {
+            self.wt_new_streams.remove(&stream_id);
+            return Ok(());
+        }
+        if let Some(session_id) = session_id {
+            self.wt_new_streams.remove(&stream_id);
+            if self.wt_sessions.add_stream(session_id, stream_id).is_ok() {
+                qdebug!([self] "New WebTransport stream {} in session {}", stream_id, session_id);
+                self.events.webtransport_new_stream(stream_id, session_id);
+            } else {
+                qdebug!([self] "WebTransport stream {} for unknown session {}", stream_id, session_id);
+                self.conn
+                    .stream_stop_sending(stream_id, Error::WrongStream.code())?;
+                // Unidirectional streams can't be reset from this end.
+                let _ = self
+                    .conn
+                    .stream_reset_send(stream_id, Error::WrongStream.code());
+            }
+        }
+        Ok(())
+    }
+
+    /// A server reads the capsules on the stream of a WebTransport session as
+    /// they arrive, so that the session ends when the client closes the
+    /// stream even if the application doesn't read datagrams.  The
+    /// application is told that there are datagrams with `DataReadable`.
+    fn read_webtransport_session(&mut self, session_id: u64) -> Res<()> {
+        let mut buf = [0; 1024];
+        let mut received = false;
+        loop {
+            let t = match self.wt_session_streams.get_mut(&session_id) {
+                Some(t) => t,
+                None => return Ok(()),
+            };
+            let (amount, fin) = match t.recv(&mut self.conn, &mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    qdebug!([self] "Error {} reading WebTransport session {}", e, session_id);
+                    let _ = self.conn.stream_stop_sending(session_id, e.code());
+                    let _ = self.conn.stream_reset_send(session_id, e.code());
+                    self.capsule_streams.remove(&session_id);
+                    if self.end_webtransport_session(session_id) {
+                        self.events.webtransport_session_closed(session_id);
+                    }
+                    return Ok(());
+                }
+            };
+            if let Some(reader) = self.capsule_streams.get_mut(&session_id) {
+                reader.receive(&buf[..amount], fin);
+            }
+            received |= amount > 0;
+            if fin {
+                let _ = self.conn.stream_close_send(session_id);
+                // Keep any datagrams that haven't been read yet.
+                if self
+                    .capsule_streams
+                    .get(&session_id)
+                    .map_or(false, CapsuleReader::is_empty)
+                {
+                    self.capsule_streams.remove(&session_id);
+                }
+                if self.end_webtransport_session(session_id) {
+                    self.events.webtransport_session_closed(session_id);
+                }
+                break;
+            }
+            if amount == 0 {
+                break;
+            }
+        }
+        if received && self.capsule_streams.contains_key(&session_id) {
+            self.events.data_readable(session_id);
         }
         Ok(())
     }
 
+    /// Forget about a WebTransport session, resetting any streams that are
+    /// left in it.  This returns false if there was no such session.
+    fn end_webtransport_session(&mut self, session_id: u64) -> bool {
+        let streams = match self.wt_sessions.remove_session(session_id) {
+            Some(s) => s,
+            None => return false,
+        };
+        qdebug!([self] "WebTransport session {} ended", session_id);
+        self.wt_session_streams.remove(&session_id);
+        for stream_id in streams {
+            self.events.remove_events_for_stream_id(stream_id);
+            // Either side of the stream might be closed already.
+            let _ = self
+                .conn
+                .stream_reset_send(stream_id, Error::RequestCancelled.code());
+            let _ = self
+                .conn
+                .stream_stop_sending(stream_id, Error::RequestCancelled.code());
+        }
+        true
+    }
+
     fn handle_stream_readable(&mut self, stream_id: u64) -> Res<()> {
         qdebug!([self] "Readable stream {}.", stream_id);
 
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -545,6 +1000,16 @@ impl Http3Connection {
                 self.decode_new_stream(t, stream_id)?;
                 self.new_streams.remove(&stream_id);
             }
+        } else if self.new_bidi_streams.contains_key(&stream_id) {
+            self.read_new_bidi_stream(stream_id)?;
+        } else if self.wt_new_streams.contains_key(&stream_id) {
+            self.read_webtransport_session_id(stream_id)?;
+        } else if self.wt_sessions.is_session(stream_id) {
+            self.read_webtransport_session(stream_id)?;
+        } else if self.wt_sessions.session_of(stream_id).is_some() {
+            self.events.webtransport_data_readable(stream_id);
+        } else if self.tunnels.contains_key(&stream_id) {
+            self.events.data_readable(stream_id);
         } else {
             // For a new stream we receive NewStream event and a
             // RecvStreamReadable event.
@@ -572,22 +1037,58 @@ impl Http3Connection {
             // Remove all events for this stream.
             self.events.remove_events_for_stream_id(stream_id);
             // Post the reset event.
-            self.events.reset(stream_id, app_err);
+            self.events
+                .reset(stream_id, app_err, CloseReason::from_peer(app_err));
             // Close both sides of the transaction_client.
             cs.reset_receiving_side();
             cs.stop_sending();
+            self.qpack_decoder.cancel_stream(stream_id);
             // close sending side of the transport stream as well. The server may have done
             // it se well, but just to be sure.
             let _ = self.conn.stream_reset_send(stream_id, app_err);
             // remove the stream
             self.transactions_client.remove(&stream_id);
         }
+        if self.transactions_server.remove(&stream_id).is_some() {
+            self.qpack_decoder.cancel_stream(stream_id);
+            self.events.remove_events_for_stream_id(stream_id);
+            self.events
+                .reset(stream_id, app_err, CloseReason::from_peer(app_err));
+            let _ = self.conn.stream_reset_send(stream_id, app_err);
+        }
+        self.capsule_streams.remove(&stream_id);
+        if self.tunnels.remove(&stream_id).is_some() {
+            self.events.remove_events_for_stream_id(stream_id);
+            self.events
+                .reset(stream_id, app_err, CloseReason::from_peer(app_err));
+            let _ = self.conn.stream_reset_send(stream_id, app_err);
+        }
+        if self.wt_sessions.is_session(stream_id) {
+            let _ = self.conn.stream_reset_send(stream_id, app_err);
+            if self.end_webtransport_session(stream_id) {
+                self.events.webtransport_session_closed(stream_id);
+            }
+        } else if self.wt_sessions.session_of(stream_id).is_some() {
+            self.wt_sessions.remove_stream(stream_id);
+            self.events.remove_events_for_stream_id(stream_id);
+            self.events
+                .reset(stream_id, app_err, CloseReason::from_peer(app_err));
+        }
         Ok(())
     }
 
     fn handle_stream_stop_sending(&mut self, stop_stream_id: u64, app_err: AppError) -> Res<()> {
         qdebug!([self] "handle_stream_stop_sending stream_id={} app_err={}", stop_stream_id, app_err);
 
+        if self.transactions_server.remove(&stop_stream_id).is_some() {
+            // The client doesn't want the rest of the response.
+            let _ = self.conn.stream_reset_send(stop_stream_id, app_err);
+            self.events.remove(&Http3Event::DataWritable {
+                stream_id: stop_stream_id,
+            });
+            self.events.stop_sending(stop_stream_id, app_err);
+        }
+
         if let Some(cs) = self.transactions_client.get_mut(&stop_stream_id) {
             // close sending side.
             cs.stop_sending();
@@ -605,7 +1106,8 @@ impl Http3Connection {
             // if error is not Error::EarlyResponse we will close receiving part as well.
             if app_err != Error::EarlyResponse.code() {
                 self.events.remove_events_for_stream_id(stop_stream_id);
-                self.events.reset(stop_stream_id, app_err);
+                self.events
+                    .reset(stop_stream_id, app_err, CloseReason::from_peer(app_err));
 
                 // The server may close its sending side as well, but just to be sure
                 // we will do it ourselves.
@@ -615,6 +1117,17 @@ impl Http3Connection {
             if cs.done() {
                 self.transactions_client.remove(&stop_stream_id);
             }
+        } else if let Some(t) = self.tunnels.get_mut(&stop_stream_id) {
+            // The peer won't read any more of the tunnel.
+            t.stop_sending();
+            let _ = self.conn.stream_reset_send(stop_stream_id, app_err);
+            self.events.remove(&Http3Event::DataWritable {
+                stream_id: stop_stream_id,
+            });
+            self.events.stop_sending(stop_stream_id, app_err);
+            if t.done() {
+                self.tunnels.remove(&stop_stream_id);
+            }
         }
         Ok(())
     }
@@ -634,6 +1147,33 @@ impl Http3Connection {
         assert_eq!(self.state, Http3State::Initializing);
         self.events.connection_state_change(Http3State::Connected);
         self.state = Http3State::Connected;
+        if self.control_stream_local.stream_id.is_none() {
+            self.initialize_http3_connection()
+        } else {
+            // This was done already for 0-RTT.
+            Ok(())
+        }
+    }
+
+    /// The server rejected 0-RTT and the transport discarded every stream,
+    /// because the server lowered its limits.  The control and QPACK streams
+    /// are opened again, and requests that were sent in 0-RTT are reset with
+    /// `Error::RequestRejected` so that the application can make them again.
+    /// Requests that hadn't been sent yet lost their streams too.
+    fn handle_zero_rtt_rejected(&mut self) -> Res<()> {
+        qinfo!([self] "0-RTT rejected, resetting requests");
+        let err = Error::RequestRejected.code();
+        let rejected = mem::replace(&mut self.transactions_client, HashMap::new());
+        for &stream_id in rejected.keys() {
+            self.deadlines.remove(&stream_id);
+            self.streams_are_readable.remove(&stream_id);
+            self.events.remove_events_for_stream_id(stream_id);
+            self.events
+                .reset(stream_id, err, CloseReason::ZeroRttRejected);
+        }
+        self.control_stream_local = ControlStreamLocal::default();
+        self.qpack_encoder.reset_send_stream();
+        self.qpack_decoder.reset_send_stream();
         self.initialize_http3_connection()
     }
 
@@ -655,7 +1195,7 @@ impl Http3Connection {
         if self.role() != Role::Client {
             return Ok(false);
         }
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -686,7 +1226,7 @@ impl Http3Connection {
         if self.role() != Role::Server {
             return Ok(false);
         }
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -708,13 +1248,69 @@ impl Http3Connection {
                 }
             }
             if transaction.done_reading_request() {
+                let session_request = self.webtransport
+                    && webtransport::is_session_request(transaction.get_request_headers());
+                let tunnel_request = tunnel::is_tunnel_request(transaction.get_request_headers());
+                let udp_target = if self.connect_udp
+                    && connect_udp::is_connect_udp_request(transaction.get_request_headers())
+                {
+                    connect_udp::connect_udp_target(transaction.get_request_headers())
+                } else {
+                    None
+                };
+                let capsule_request = self.extended_connect
+                    && capsule::is_capsule_request(transaction.get_request_headers());
+                if self.handler.is_none() {
+                    qdebug!([label] "Passing request on {} to the application", stream_id);
+                    transaction.wait_for_response();
+                    self.events
+                        .request(stream_id, transaction.get_request_headers().to_vec());
+                }
                 if let Some(ref mut cb) = self.handler {
-                    let (headers, data, close_error) =
+                    let (headers, data, trailers, close_error) =
                         (cb)(transaction.get_request_headers(), false);
+                    if session_request && close_error.is_none() && is_success_response(&headers) {
+                        qdebug!([label] "WebTransport session {} established", stream_id);
+                        transaction.keep_open();
+                        self.wt_sessions.add_session(stream_id);
+                        self.wt_session_streams
+                            .insert(stream_id, Tunnel::new(stream_id));
+                        self.capsule_streams
+                            .insert(stream_id, CapsuleReader::default());
+                        self.events.webtransport_new_session(stream_id);
+                    } else if tunnel_request
+                        && close_error.is_none()
+                        && is_success_response(&headers)
+                    {
+                        qdebug!([label] "Tunnel {} opened", stream_id);
+                        transaction.keep_open();
+                        self.tunnels.insert(stream_id, Tunnel::new(stream_id));
+                        self.events.new_tunnel(stream_id);
+                    } else if let Some((host, port)) = udp_target
+                        .filter(|_| close_error.is_none() && is_success_response(&headers))
+                    {
+                        qdebug!([label] "UDP tunnel {} to {}:{} opened", stream_id, host, port);
+                        transaction.keep_open();
+                        self.tunnels.insert(stream_id, Tunnel::new(stream_id));
+                        self.capsule_streams
+                            .insert(stream_id, CapsuleReader::default());
+                        self.events.new_udp_tunnel(stream_id, host, port);
+                    } else if capsule_request
+                        && close_error.is_none()
+                        && is_success_response(&headers)
+                    {
+                        qdebug!([label] "Capsule stream {} opened", stream_id);
+                        transaction.keep_open();
+                        self.tunnels.insert(stream_id, Tunnel::new(stream_id));
+                        self.capsule_streams
+                            .insert(stream_id, CapsuleReader::default());
+                        self.events.new_capsule_stream(stream_id);
+                    }
                     qdebug!(
-                        "Sending response: {:?} {:?} {:?}",
+                        "Sending response: {:?} {:?} {:?} {:?}",
                         headers,
                         data,
+                        trailers,
                         close_error
                     );
                     match close_error {
@@ -724,10 +1320,20 @@ impl Http3Connection {
                                 self.transactions_client.remove(&stream_id);
                                 let _ = self.conn.stream_reset_send(stream_id, e.code());
                             } else {
-                                transaction.set_response(&headers, data, &mut self.qpack_encoder);
+                                transaction.set_response(
+                                    &headers,
+                                    data,
+                                    &trailers,
+                                    &mut self.qpack_encoder,
+                                );
                             }
                         }
-                        None => transaction.set_response(&headers, data, &mut self.qpack_encoder),
+                        None => transaction.set_response(
+                            &headers,
+                            data,
+                            &trailers,
+                            &mut self.qpack_encoder,
+                        ),
                     };
                 }
                 if transaction.is_state_sending() {
@@ -760,6 +1366,11 @@ impl Http3Connection {
                 }
                 Ok(())
             }
+            WEBTRANSPORT_UNI_STREAM_TYPE if self.webtransport => {
+                self.wt_new_streams
+                    .insert(stream_id, NewStreamTypeReader::new());
+                self.read_webtransport_session_id(stream_id)
+            }
             QPACK_UNI_STREAM_TYPE_ENCODER => {
                 qinfo!([self] "A new remote qpack encoder stream {}", stream_id);
                 if self.qpack_decoder.has_recv_stream() {
@@ -790,7 +1401,26 @@ impl Http3Connection {
     }
 
     pub fn close(&mut self, now: Instant, error: AppError, msg: &str) {
+        self.close_for(now, CloseReason::Local(error), error, msg);
+    }
+
+    /// Why the connection is ending, or `None` if it isn't.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// Record why the connection is ending, the first time that is known.
+    fn set_close_reason(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            qinfo!([self] "Connection ending: {:?}", reason);
+            self.close_reason = Some(reason);
+            self.events.connection_ended(reason);
+        }
+    }
+
+    fn close_for(&mut self, now: Instant, reason: CloseReason, error: AppError, msg: &str) {
         qdebug!([self] "Closed.");
+        self.set_close_reason(reason);
         self.state = Http3State::Closing(CloseError::Application(error));
         if (!self.transactions_client.is_empty() || !self.transactions_server.is_empty())
             && (error == 0)
@@ -799,6 +1429,10 @@ impl Http3Connection {
         }
         self.transactions_client.clear();
         self.transactions_server.clear();
+        self.wt_sessions = WebTransportSessions::default();
+        self.tunnels.clear();
+        self.wt_session_streams.clear();
+        self.capsule_streams.clear();
         self.conn.close(now, error, msg);
     }
 
@@ -818,6 +1452,9 @@ impl Http3Connection {
             host,
             path
         );
+        if self.state == Http3State::GoingAway {
+            return Err(Error::Unavailable);
+        }
         let id = self.conn.stream_create(StreamType::BiDi)?;
         self.transactions_client.insert(
             id,
@@ -827,8 +1464,394 @@ impl Http3Connection {
         Ok(id)
     }
 
+    /// Open a tunnel to `authority`, which is a host and port, with a CONNECT
+    /// request.  The tunnel is open if the response, which is read like any
+    /// other, has a 2xx status.  After that, use `tunnel_send()`,
+    /// `tunnel_recv()` and `tunnel_close_send()`.
+    pub fn connect_tunnel(&mut self, authority: &str, headers: &[Header]) -> Res<u64> {
+        self.fetch("CONNECT", "", authority, "", headers)
+    }
+
+    /// Send bytes through the tunnel on `stream_id`.  On a client, this is
+    /// the same as `send_request_body()`.
+    pub fn tunnel_send(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        match self.tunnels.get_mut(&stream_id) {
+            Some(t) => t.send(&mut self.conn, buf),
+            None if self.role() == Role::Client => self.send_request_body(stream_id, buf),
+            None => Err(Error::InvalidStreamId),
+        }
+    }
+
+    /// Read bytes from the tunnel on `stream_id`.  The peer closed its side
+    /// of the tunnel when this reports the end of the stream.  On a client,
+    /// this is the same as `read_response_data()`.
+    pub fn tunnel_recv(
+        &mut self,
+        now: Instant,
+        stream_id: u64,
+        buf: &mut [u8],
+    ) -> Res<(usize, bool)> {
+        let t = match self.tunnels.get_mut(&stream_id) {
+            Some(t) => t,
+            None if self.role() == Role::Client => {
+                return self.read_response_data(now, stream_id, buf)
+            }
+            None => return Err(Error::InvalidStreamId),
+        };
+        match t.recv(&mut self.conn, buf) {
+            Ok((amount, fin)) => {
+                if t.done() {
+                    self.tunnels.remove(&stream_id);
+                }
+                Ok((amount, fin))
+            }
+            Err(e) => {
+                qdebug!([self] "Error {} reading tunnel {}", e, stream_id);
+                self.tunnels.remove(&stream_id);
+                let _ = self.conn.stream_stop_sending(stream_id, e.code());
+                let _ = self.conn.stream_reset_send(stream_id, e.code());
+                Err(e)
+            }
+        }
+    }
+
+    /// Close this side of the tunnel on `stream_id`.  On a client, this is the
+    /// same as `stream_close_send()`.
+    pub fn tunnel_close_send(&mut self, stream_id: u64) -> Res<()> {
+        let t = match self.tunnels.get_mut(&stream_id) {
+            Some(t) => t,
+            None if self.role() == Role::Client => return self.stream_close_send(stream_id),
+            None => return Err(Error::InvalidStreamId),
+        };
+        t.close_send(&mut self.conn)?;
+        if t.done() {
+            self.tunnels.remove(&stream_id);
+        }
+        Ok(())
+    }
+
+    /// Send an extended CONNECT request for `protocol` that uses capsules.
+    /// This returns the ID of the request stream, which carries capsules and
+    /// HTTP Datagrams if the response, which is read like any other, has a
+    /// 2xx status.  This fails with `Error::Unavailable` unless
+    /// `extended_connect_enabled()`.
+    pub fn extended_connect(
+        &mut self,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        protocol: &str,
+        headers: &[Header],
+    ) -> Res<u64> {
+        if self.role() != Role::Client || !self.extended_connect_enabled() {
+            return Err(Error::Unavailable);
+        }
+        self.fetch_capsule_stream(scheme, host, path, protocol, headers)
+    }
+
+    fn fetch_capsule_stream(
+        &mut self,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        protocol: &str,
+        headers: &[Header],
+    ) -> Res<u64> {
+        let mut h = vec![
+            (String::from(":protocol"), String::from(protocol)),
+            (String::from(CAPSULE_PROTOCOL_HEADER), String::from("?1")),
+        ];
+        h.extend_from_slice(headers);
+        let stream_id = self.fetch("CONNECT", scheme, host, path, &h)?;
+        self.capsule_streams
+            .insert(stream_id, CapsuleReader::default());
+        Ok(stream_id)
+    }
+
+    /// Send an HTTP Datagram on the request stream `stream_id`.  Like any
+    /// datagram, this is dropped if it doesn't fit, and this returns false.
+    pub fn http_datagram_send(&mut self, stream_id: u64, datagram: &[u8]) -> Res<bool> {
+        if !self.capsule_streams.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        let capsule = capsule::encode_capsule(capsule::CAPSULE_TYPE_DATAGRAM, datagram);
+        // Leave room for the largest DATA frame header.
+        let available = self.conn.stream_avail_send_space(stream_id)? as usize;
+        if available < capsule.len() + 9 {
+            qdebug!([self] "Dropping HTTP Datagram on {}", stream_id);
+            return Ok(false);
+        }
+        let sent = match self.wt_session_streams.get_mut(&stream_id) {
+            Some(t) => t.send(&mut self.conn, &capsule)?,
+            None => self.tunnel_send(stream_id, &capsule)?,
+        };
+        assert_eq!(sent, capsule.len());
+        Ok(true)
+    }
+
+    /// Read the next HTTP Datagram from the request stream `stream_id`, if
+    /// there is one.  The peer closed the stream when this reports the end
+    /// of the stream.
+    pub fn http_datagram_recv(
+        &mut self,
+        now: Instant,
+        stream_id: u64,
+    ) -> Res<(Option<Vec<u8>>, bool)> {
+        let mut buf = [0; 4096];
+        loop {
+            let reader = self
+                .capsule_streams
+                .get_mut(&stream_id)
+                .ok_or(Error::InvalidStreamId)?;
+            if let Some(datagram) = reader.next_datagram() {
+                return Ok((Some(datagram), false));
+            }
+            if reader.fin() {
+                let complete = reader.is_empty();
+                self.capsule_streams.remove(&stream_id);
+                return if complete {
+                    Ok((None, true))
+                } else {
+                    Err(Error::GeneralProtocolError)
+                };
+            }
+            // A server reads the stream of a WebTransport session as data
+            // arrives, so everything is in the reader already.
+            if self.wt_session_streams.contains_key(&stream_id) {
+                return Ok((None, false));
+            }
+            let (amount, fin) = match self.tunnel_recv(now, stream_id, &mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    self.capsule_streams.remove(&stream_id);
+                    return Err(e);
+                }
+            };
+            let reader = self
+                .capsule_streams
+                .get_mut(&stream_id)
+                .ok_or(Error::InvalidStreamId)?;
+            reader.receive(&buf[..amount], fin);
+            if amount == 0 && !fin {
+                return Ok((None, false));
+            }
+        }
+    }
+
+    /// Close this side of the request stream `stream_id`, which carries
+    /// capsules.
+    pub fn capsule_stream_close(&mut self, stream_id: u64) -> Res<()> {
+        if !self.capsule_streams.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.tunnel_close_send(stream_id)
+    }
+
+    /// Ask for a tunnel to the UDP port `port` on `host`, through the proxy
+    /// at `authority`.  This returns the ID of the request stream.  The
+    /// tunnel is open if the response, which is read like any other, has a
+    /// 2xx status.  This fails with `Error::Unavailable` unless
+    /// `connect_udp_enabled()`.
+    pub fn connect_udp(
+        &mut self,
+        scheme: &str,
+        authority: &str,
+        host: &str,
+        port: u16,
+        headers: &[Header],
+    ) -> Res<u64> {
+        if self.role() != Role::Client || !self.connect_udp_enabled() {
+            return Err(Error::Unavailable);
+        }
+        let path = connect_udp::connect_udp_path(host, port);
+        self.fetch_capsule_stream(scheme, authority, &path, CONNECT_UDP_PROTOCOL, headers)
+    }
+
+    /// Send a UDP payload through the tunnel on `stream_id`.  Like any
+    /// datagram, this is dropped if it doesn't fit, and this returns false.
+    pub fn connect_udp_send(&mut self, stream_id: u64, payload: &[u8]) -> Res<bool> {
+        self.http_datagram_send(stream_id, &connect_udp::encode_udp_payload(payload))
+    }
+
+    /// Read the next UDP payload from the tunnel on `stream_id`, if there is
+    /// one.  The peer closed the tunnel when this reports the end of the
+    /// stream.
+    pub fn connect_udp_recv(
+        &mut self,
+        now: Instant,
+        stream_id: u64,
+    ) -> Res<(Option<Vec<u8>>, bool)> {
+        loop {
+            match self.http_datagram_recv(now, stream_id)? {
+                (Some(datagram), fin) => {
+                    if let Some(payload) = connect_udp::decode_udp_payload(&datagram)? {
+                        return Ok((Some(payload.to_vec()), fin));
+                    }
+                }
+                (None, fin) => return Ok((None, fin)),
+            }
+        }
+    }
+
+    /// Close this side of the UDP tunnel on `stream_id`.
+    pub fn connect_udp_close(&mut self, stream_id: u64) -> Res<()> {
+        self.capsule_stream_close(stream_id)
+    }
+
+    /// Ask for a WebTransport session with an extended CONNECT request.
+    /// This returns the session ID, which is the ID of the request stream.
+    /// The session is established if the response, which is read like any
+    /// other, has a 2xx status.  It ends when either endpoint closes the
+    /// request stream.  This fails with `Error::Unavailable` unless
+    /// `webtransport_enabled()`.
+    pub fn webtransport_create_session(
+        &mut self,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Res<u64> {
+        if self.role() != Role::Client || !self.webtransport_enabled() {
+            return Err(Error::Unavailable);
+        }
+        let mut h = vec![
+            (
+                String::from(":protocol"),
+                String::from(WEBTRANSPORT_PROTOCOL),
+            ),
+            (String::from(CAPSULE_PROTOCOL_HEADER), String::from("?1")),
+        ];
+        h.extend_from_slice(headers);
+        let session_id = self.fetch("CONNECT", scheme, host, path, &h)?;
+        self.wt_sessions.add_session(session_id);
+        self.capsule_streams
+            .insert(session_id, CapsuleReader::default());
+        Ok(session_id)
+    }
+
+    /// Close a WebTransport session, resetting any streams that are left in it.
+    pub fn webtransport_close_session(&mut self, session_id: u64) -> Res<()> {
+        if !self.wt_sessions.is_session(session_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        if self.role() == Role::Client {
+            self.stream_close_send(session_id)?;
+        } else {
+            self.conn.stream_close_send(session_id)?;
+        }
+        self.end_webtransport_session(session_id);
+        self.capsule_streams.remove(&session_id);
+        Ok(())
+    }
+
+    /// Send a datagram in a WebTransport session.  It goes in a DATAGRAM
+    /// capsule on the request stream of the session, so it is delivered
+    /// reliably and in order.  Like any datagram, it is dropped if it doesn't
+    /// fit, and this returns false.
+    pub fn webtransport_datagram_send(&mut self, session_id: u64, datagram: &[u8]) -> Res<bool> {
+        if !self.wt_sessions.is_session(session_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.http_datagram_send(session_id, datagram)
+    }
+
+    /// Read the next datagram in a WebTransport session, if there is one.
+    /// There might be one when there is a `DataReadable` event for the
+    /// session ID.  The session has ended when this reports the end of the
+    /// stream, and datagrams that arrived before that can still be read.
+    pub fn webtransport_datagram_recv(
+        &mut self,
+        now: Instant,
+        session_id: u64,
+    ) -> Res<(Option<Vec<u8>>, bool)> {
+        self.http_datagram_recv(now, session_id)
+    }
+
+    /// Open a stream in a WebTransport session.
+    pub fn webtransport_create_stream(
+        &mut self,
+        session_id: u64,
+        stream_type: StreamType,
+    ) -> Res<u64> {
+        if !self.wt_sessions.is_session(session_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        let stream_id = self.conn.stream_create(stream_type)?;
+        let header = webtransport::stream_header(session_id, stream_type == StreamType::BiDi);
+        if self.conn.stream_send(stream_id, &header)? != header.len() {
+            // The peer didn't allow enough data for the header.
+            let _ = self
+                .conn
+                .stream_reset_send(stream_id, Error::InternalError.code());
+            return Err(Error::Unavailable);
+        }
+        self.wt_sessions.add_stream(session_id, stream_id)?;
+        Ok(stream_id)
+    }
+
+    fn check_webtransport_stream(&self, stream_id: u64) -> Res<()> {
+        match self.wt_sessions.session_of(stream_id) {
+            Some(_) => Ok(()),
+            None => Err(Error::InvalidStreamId),
+        }
+    }
+
+    /// Send data on a WebTransport stream.
+    pub fn webtransport_send(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        self.check_webtransport_stream(stream_id)?;
+        Ok(self.conn.stream_send(stream_id, buf)?)
+    }
+
+    /// Read data from a WebTransport stream.
+    pub fn webtransport_recv(&mut self, stream_id: u64, buf: &mut [u8]) -> Res<(usize, bool)> {
+        self.check_webtransport_stream(stream_id)?;
+        Ok(self.conn.stream_recv(stream_id, buf)?)
+    }
+
+    /// Close the sending side of a WebTransport stream.
+    pub fn webtransport_close_send(&mut self, stream_id: u64) -> Res<()> {
+        self.check_webtransport_stream(stream_id)?;
+        Ok(self.conn.stream_close_send(stream_id)?)
+    }
+
+    /// Reset a WebTransport stream in both directions.
+    pub fn webtransport_reset(&mut self, stream_id: u64, error: AppError) -> Res<()> {
+        self.check_webtransport_stream(stream_id)?;
+        // Either side of the stream might be closed already.
+        let _ = self.conn.stream_reset_send(stream_id, error);
+        let _ = self.conn.stream_stop_sending(stream_id, error);
+        self.wt_sessions.remove_stream(stream_id);
+        self.events.remove_events_for_stream_id(stream_id);
+        Ok(())
+    }
+
+    /// Cancel the request on `stream_id` if it hasn't finished by `deadline`.
+    /// When that happens, the stream is reset with `H3_REQUEST_CANCELLED`
+    /// and `Http3Event::RequestTimeout` is posted.  This replaces any earlier
+    /// deadline for the request.  The deadline is only checked when the
+    /// connection is processed, which `process_output()` asks for in time.
+    pub fn set_request_deadline(&mut self, stream_id: u64, deadline: Instant) -> Res<()> {
+        if !self.transactions_client.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.deadlines.insert(stream_id, deadline);
+        Ok(())
+    }
+
     pub fn stream_reset(&mut self, stream_id: u64, error: AppError) -> Res<()> {
         qdebug!([self] "reset_stream {}.", stream_id);
+        if self.role() == Role::Server {
+            // Cancel a response.
+            self.transactions_server
+                .remove(&stream_id)
+                .ok_or(Error::InvalidStreamId)?;
+            self.streams_have_data_to_send.remove(&stream_id);
+            self.qpack_decoder.cancel_stream(stream_id);
+            let _ = self.conn.stream_reset_send(stream_id, error);
+            let _ = self.conn.stream_stop_sending(stream_id, error);
+            self.events.remove_events_for_stream_id(stream_id);
+            return Ok(());
+        }
         let mut cs = self
             .transactions_client
             .remove(&stream_id)
@@ -837,14 +1860,47 @@ impl Http3Connection {
         // Stream maybe already be closed and we may get an error here, but we do not care.
         let _ = self.conn.stream_reset_send(stream_id, error);
         cs.reset_receiving_side();
+        self.qpack_decoder.cancel_stream(stream_id);
+        self.capsule_streams.remove(&stream_id);
+        self.deadlines.remove(&stream_id);
         // Stream maybe already be closed and we may get an error here, but we do not care.
         self.conn.stream_stop_sending(stream_id, error)?;
         self.events.remove_events_for_stream_id(stream_id);
         Ok(())
     }
 
+    /// Cancel a request, resetting both directions of the stream with
+    /// `error`, which is normally `Error::RequestCancelled.code()`.  Unlike
+    /// `stream_reset()`, this ends with `Http3Event::Reset` and a reason of
+    /// `CloseReason::Local`, so that code waiting on the request's events
+    /// learns that it is over.
+    pub fn cancel_fetch(&mut self, stream_id: u64, error: AppError) -> Res<()> {
+        qdebug!([self] "cancel_fetch {} error={}.", stream_id, error);
+        if self.role() == Role::Server {
+            return Err(Error::Unavailable);
+        }
+        self.stream_reset(stream_id, error)?;
+        self.events
+            .reset(stream_id, error, CloseReason::Local(error));
+        Ok(())
+    }
+
     pub fn stream_close_send(&mut self, stream_id: u64) -> Res<()> {
         qdebug!([self] "close_stream {}.", stream_id);
+        if self.role() == Role::Server {
+            // End a response that was started with `send_response_headers()`.
+            let ts = self
+                .transactions_server
+                .get_mut(&stream_id)
+                .ok_or(Error::InvalidStreamId)?;
+            ts.close_send(&mut self.conn)?;
+            if ts.is_state_sending() {
+                self.streams_have_data_to_send.insert(stream_id);
+            } else {
+                self.transactions_server.remove(&stream_id);
+            }
+            return Ok(());
+        }
         let cs = self
             .transactions_client
             .get_mut(&stream_id)
@@ -878,6 +1934,10 @@ impl Http3Connection {
                 HFrame::CancelPush { .. } => Ok(()),
                 HFrame::Goaway { stream_id } => self.handle_goaway(stream_id),
                 HFrame::MaxPushId { push_id } => self.handle_max_push_id(push_id),
+                HFrame::Origin { origins } => {
+                    self.handle_origin(&origins);
+                    Ok(())
+                }
                 _ => Err(Error::WrongStream),
             };
         }
@@ -886,18 +1946,28 @@ impl Http3Connection {
 
     fn handle_settings(&mut self, s: &[(HSettingType, u64)]) -> Res<()> {
         qdebug!([self] "Handle SETTINGS frame.");
+        let mut unknown = Vec::new();
         for (t, v) in s {
             qdebug!([self] " {:?} = {:?}", t, v);
+            self.peer_settings.insert(t.id(), *v);
             match t {
                 HSettingType::MaxHeaderListSize => {
                     self.max_header_list_size = *v;
                 }
                 HSettingType::MaxTableSize => self.qpack_encoder.set_max_capacity(*v)?,
-                HSettingType::BlockedStreams => self.qpack_encoder.set_max_blocked_streams(*v)?,
-
-                _ => {}
+                HSettingType::BlockedStreams => self.qpack_encoder.set_max_blocked_streams(*v)?,
+                HSettingType::EnableConnectProtocol => self.peer_connect_protocol = *v == 1,
+                HSettingType::EnableWebTransport => self.peer_webtransport = *v == 1,
+                HSettingType::Unknown(id) => {
+                    if !hframe::is_grease_setting(*id) {
+                        unknown.push((*id, *v));
+                    }
+                }
             }
         }
+        if !unknown.is_empty() {
+            self.events.unknown_settings(unknown);
+        }
         Ok(())
     }
 
@@ -906,15 +1976,35 @@ impl Http3Connection {
         if self.role() == Role::Server {
             return Err(Error::UnexpectedFrame);
         } else {
+            // The ID has to be for a request stream, and a later GOAWAY can't
+            // let more requests in.
+            if goaway_stream_id % 4 != 0
+                || self
+                    .goaway_stream_id
+                    .map_or(false, |id| goaway_stream_id > id)
+            {
+                return Err(Error::GeneralProtocolError);
+            }
+            self.goaway_stream_id = Some(goaway_stream_id);
+
             // Issue reset events for streams >= goaway stream id
-            for id in self
+            let rejected = self
                 .transactions_client
-                .iter()
-                .filter(|(id, _)| **id >= goaway_stream_id)
-                .map(|(id, _)| *id)
-            {
+                .keys()
+                .filter(|id| **id >= goaway_stream_id)
+                .cloned()
+                .collect::<Vec<_>>();
+            for id in rejected {
                 self.events.remove_events_for_stream_id(id);
-                self.events.reset(id, Error::RequestRejected.code())
+                self.events
+                    .reset(id, Error::RequestRejected.code(), CloseReason::Goaway);
+                // The server won't use these streams.
+                let _ = self
+                    .conn
+                    .stream_reset_send(id, Error::RequestCancelled.code());
+                let _ = self
+                    .conn
+                    .stream_stop_sending(id, Error::RequestCancelled.code());
             }
             self.events.remove(&Http3Event::RequestsCreatable);
             self.events.goaway_received();
@@ -940,12 +2030,27 @@ impl Http3Connection {
         Ok(())
     }
 
+    fn handle_origin(&mut self, origins: &[String]) {
+        qdebug!([self] "handle_origin {:?}", origins);
+        // Servers ignore ORIGIN frames.
+        if self.role() == Role::Client {
+            self.origins
+                .extend(origins.iter().filter_map(|o| origin::normalize_origin(o)));
+        }
+    }
+
     pub fn state(&self) -> Http3State {
         self.state.clone()
     }
 
     // API
 
+    /// Send part of the request body on `stream_id`.  This returns how much
+    /// of `buf` was sent, which is limited by flow control; the rest has to be
+    /// sent later.  `Http3Event::DataWritable` signals when more can be sent,
+    /// first when the request headers are out and then whenever flow control
+    /// allows more after a short write.  Call `stream_close_send()` at the
+    /// end of the body.
     pub fn send_request_body(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
         qdebug!([self] "send_request_body from stream {}.", stream_id);
         self.transactions_client
@@ -954,6 +2059,154 @@ impl Http3Connection {
             .send_request_body(&mut self.conn, buf)
     }
 
+    /// Start a graceful shutdown of a server by sending GOAWAY.  Requests that
+    /// arrived already are answered as usual and later ones are rejected with
+    /// `Error::RequestRejected`, which tells the client that it can retry
+    /// them.  Close the connection once the responses are done.
+    pub fn send_goaway(&mut self) -> Res<()> {
+        if self.role() != Role::Server {
+            return Err(Error::Unavailable);
+        }
+        match self.state {
+            Http3State::Connected => {}
+            Http3State::GoingAway => return Ok(()),
+            Http3State::Initializing => return Err(Error::Unavailable),
+            Http3State::Closing(..) | Http3State::Closed(..) => return Err(Error::AlreadyClosed),
+        }
+        qinfo!([self] "Sending GOAWAY for {}", self.next_request_stream_id);
+        self.goaway_stream_id = Some(self.next_request_stream_id);
+        self.control_stream_local.send_frame(HFrame::Goaway {
+            stream_id: self.next_request_stream_id,
+        });
+        self.state = Http3State::GoingAway;
+        Ok(())
+    }
+
+    /// Send an ORIGIN frame that lists origins this server is authoritative
+    /// for, like `https://example.com`, so that clients can send requests for
+    /// them on this connection.  Clients add these to the origins from any
+    /// earlier ORIGIN frames.
+    pub fn send_origins(&mut self, origins: &[&str]) -> Res<()> {
+        if self.role() != Role::Server {
+            return Err(Error::Unavailable);
+        }
+        match self.state {
+            Http3State::Connected | Http3State::GoingAway => {}
+            Http3State::Initializing => return Err(Error::Unavailable),
+            Http3State::Closing(..) | Http3State::Closed(..) => return Err(Error::AlreadyClosed),
+        }
+        if origins
+            .iter()
+            .any(|o| !o.is_ascii() || o.len() > usize::from(u16::max_value()))
+        {
+            return Err(Error::InvalidInput);
+        }
+        qinfo!([self] "Sending ORIGIN {:?}", origins);
+        self.control_stream_local.send_frame(HFrame::Origin {
+            origins: origins.iter().map(|o| String::from(*o)).collect(),
+        });
+        Ok(())
+    }
+
+    /// Whether a client can send requests for `authority` on this connection
+    /// instead of making a new one.  That needs the origin for `authority` to
+    /// be the one that the connection was made for or one that the server
+    /// sent in an ORIGIN frame, and the server certificate has to be valid
+    /// for the host in `authority`.  `certificate_covers` is called with the
+    /// certificate and that host to check this, as checking names in
+    /// certificates is up to the application.
+    pub fn can_coalesce<F>(&self, authority: &str, certificate_covers: F) -> bool
+    where
+        F: FnOnce(&CertificateInfo, &str) -> bool,
+    {
+        if self.role() != Role::Client || self.state != Http3State::Connected {
+            return false;
+        }
+        let wanted = origin::https_origin(authority);
+        let advertised = self.origins.contains(&wanted)
+            || self
+                .conn
+                .server_name()
+                .map_or(false, |name| origin::https_origin(name) == wanted);
+        if !advertised {
+            qdebug!([self] "{} is not an origin of this connection", wanted);
+            return false;
+        }
+        match self.peer_certificate() {
+            Some(cert) => certificate_covers(&cert, origin::authority_host(authority)),
+            None => false,
+        }
+    }
+
+    /// Start the response to a request that a server without a request
+    /// handler got as `Http3Event::Request`.  `Http3Event::DataWritable`
+    /// signals when the body can be sent with `send_response_body()`.  End
+    /// the response with `send_response_trailers()` or `stream_close_send()`,
+    /// or cancel it with `stream_reset()`.
+    pub fn send_response_headers(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        qdebug!([self] "send_response_headers on stream {}.", stream_id);
+        self.transactions_server
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .set_response_headers(headers, &mut self.qpack_encoder)?;
+        self.streams_have_data_to_send.insert(stream_id);
+        Ok(())
+    }
+
+    /// Send an interim response, such as 100 Continue or 103 Early Hints, on
+    /// `stream_id`.  This has to come before `send_response_headers()`.
+    pub fn send_interim_response(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        qdebug!([self] "send_interim_response on stream {}.", stream_id);
+        self.transactions_server
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .set_interim_response(headers, &mut self.qpack_encoder)?;
+        self.streams_have_data_to_send.insert(stream_id);
+        Ok(())
+    }
+
+    /// Send part of a response body on `stream_id`.  Like
+    /// `send_request_body()`, this returns how much of `buf` was sent, and
+    /// `Http3Event::DataWritable` signals when more can be sent.
+    pub fn send_response_body(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        qdebug!([self] "send_response_body on stream {}.", stream_id);
+        self.transactions_server
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .send_response_body(&mut self.conn, buf)
+    }
+
+    /// Send trailers after the response body on `stream_id`, which ends
+    /// the response.
+    pub fn send_response_trailers(&mut self, stream_id: u64, trailers: &[Header]) -> Res<()> {
+        qdebug!([self] "send_response_trailers on stream {}.", stream_id);
+        self.transactions_server
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .set_response_trailers(trailers, &mut self.qpack_encoder)?;
+        self.streams_have_data_to_send.insert(stream_id);
+        Ok(())
+    }
+
+    /// Send trailers on `stream_id` after the request body.  This ends the
+    /// request, like `stream_close_send()` does.  This fails with
+    /// `Error::Unavailable` until the request headers have been sent, which
+    /// `Http3Event::DataWritable` signals.
+    pub fn send_request_trailers(&mut self, stream_id: u64, trailers: &[Header]) -> Res<()> {
+        qdebug!([self] "send_request_trailers on stream {}.", stream_id);
+        let cs = self
+            .transactions_client
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?;
+        cs.send_request_trailers(&mut self.conn, &mut self.qpack_encoder, trailers)?;
+        if cs.is_state_sending_trailers() {
+            self.streams_have_data_to_send.insert(stream_id);
+        } else if cs.done() {
+            self.transactions_client.remove(&stream_id);
+        }
+        Ok(())
+    }
+
     pub fn read_response_headers(&mut self, stream_id: u64) -> Res<(Vec<Header>, bool)> {
         qdebug!([self] "read_response_headers from stream {}.", stream_id);
         let cs = self
@@ -965,6 +2218,9 @@ impl Http3Connection {
                 if cs.done() {
                     self.transactions_client.remove(&stream_id);
                 }
+                if fin && self.end_webtransport_session(stream_id) {
+                    self.events.webtransport_session_closed(stream_id);
+                }
                 Ok((headers, fin))
             }
             Err(e) => Err(e),
@@ -987,6 +2243,9 @@ impl Http3Connection {
             Ok((amount, fin)) => {
                 if fin {
                     self.transactions_client.remove(&stream_id);
+                    if self.end_webtransport_session(stream_id) {
+                        self.events.webtransport_session_closed(stream_id);
+                    }
                 } else if amount > 0 {
                     // Directly call receive instead of adding to
                     // streams_are_readable here. This allows the app to
@@ -999,7 +2258,7 @@ impl Http3Connection {
             }
             Err(e) => {
                 if e == Error::MalformedFrame(H3_FRAME_TYPE_DATA) {
-                    self.close(now, e.code(), "");
+                    self.close_for(now, CloseReason::from(&e), e.code(), "");
                 }
                 Err(e)
             }
@@ -1010,6 +2269,12 @@ impl Http3Connection {
         self.events.events()
     }
 
+    /// Take the next event, in the same order as `events()`, without making
+    /// a new list.  See `Connection::next_event()`.
+    pub fn next_event(&mut self) -> Option<Http3Event> {
+        self.events.next_event()
+    }
+
     // SERVER SIDE ONLY FUNCTIONS
     fn handle_new_client_request(&mut self, stream_id: u64) {
         self.transactions_server
@@ -1020,25 +2285,165 @@ impl Http3Connection {
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub enum Http3Event {
     /// Space available in the buffer for an application write to succeed.
-    HeaderReady { stream_id: u64 },
+    /// `zero_rtt` says whether the request was sent in 0-RTT.
+    HeaderReady {
+        stream_id: u64,
+        zero_rtt: ZeroRttStatus,
+    },
+    /// An interim response, such as 103 Early Hints, arrived before the
+    /// response headers.
+    InterimResponse {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
     /// A stream can accept new data.
     DataWritable { stream_id: u64 },
     /// New bytes available for reading.
     DataReadable { stream_id: u64 },
-    /// Peer reset the stream.
-    Reset { stream_id: u64, error: AppError },
+    /// Trailers arrived after the response body.  The end of the stream is
+    /// still reported by `read_response_data()`.
+    Trailers {
+        stream_id: u64,
+        trailers: Vec<Header>,
+    },
+    /// The request failed, either because the peer reset the stream or
+    /// because of a GOAWAY.  `reason` says which.  A request that ended with
+    /// `CloseReason::Goaway`, `CloseReason::ZeroRttRejected` or
+    /// `Error::RequestRejected` wasn't processed, so
+    /// it can be retried on another connection.  A peer that cancelled the
+    /// request gives `CloseReason::PeerApplication` and leaves the connection
+    /// open; `cancel_fetch()` ends with `CloseReason::Local`.
+    Reset {
+        stream_id: u64,
+        error: AppError,
+        reason: CloseReason,
+    },
     /// Peer has send STOP_SENDING with error code EarlyResponse, other error will post a reset event.
     StopSending { stream_id: u64, error: AppError },
     /// A new push stream
     NewPushStream { stream_id: u64 },
+    /// A request arrived on a server that has no request handler.  Respond
+    /// with `send_response_headers()`.
+    Request {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// A server opened a tunnel in response to a CONNECT request.
+    NewTunnel { stream_id: u64 },
+    /// A server took an extended CONNECT request that uses capsules.
+    NewCapsuleStream { stream_id: u64 },
+    /// A server opened a tunnel to a UDP port on `host`.
+    NewUdpTunnel {
+        stream_id: u64,
+        host: String,
+        port: u16,
+    },
     /// New stream can be created
     RequestsCreatable,
     /// Cert authentication needed
     AuthenticationNeeded,
     /// Client has received a GOAWAY frame
     GoawayReceived,
+    /// The peer sent settings that this crate doesn't use, apart from
+    /// reserved ones.  `peer_setting()` gets the values later.
+    UnknownSettings { settings: Vec<(u64, u64)> },
+    /// The request was cancelled because its deadline passed.
+    RequestTimeout { stream_id: u64 },
     /// Connection state change.
     StateChange(Http3State),
+    /// The connection is ending.  This happens once, when the reason is
+    /// first known, which is usually along with a change to `Closing`.
+    ConnectionEnded { reason: CloseReason },
+    /// A server accepted a WebTransport session.
+    WebTransportNewSession { session_id: u64 },
+    /// The peer opened a stream in a WebTransport session.  The stream might
+    /// have data to read already.
+    WebTransportNewStream { stream_id: u64, session_id: u64 },
+    /// A WebTransport stream has data to read with `webtransport_recv()`.
+    WebTransportDataReadable { stream_id: u64 },
+    /// The peer closed a WebTransport session, or refused it.  Any streams
+    /// that were left in the session are reset.
+    WebTransportSessionClosed { session_id: u64 },
+}
+
+/// Why a connection or a request ended.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+pub enum CloseReason {
+    /// A transport error, found by either endpoint.  This holds the
+    /// transport error code.
+    Transport(u64),
+    /// An HTTP/3 or QPACK protocol error, found by either endpoint.
+    Http3(AppError),
+    /// The peer gave up, with an error code that isn't a protocol error,
+    /// such as `H3_REQUEST_CANCELLED` or `H3_NO_ERROR`.
+    PeerApplication(AppError),
+    /// This endpoint closed the connection, or cancelled a request, using
+    /// the API.
+    Local(AppError),
+    /// Nothing was received for too long.
+    IdleTimeout,
+    /// The server sent GOAWAY, so the request was not processed.
+    Goaway,
+    /// The request was sent in 0-RTT, which the server rejected, so it was
+    /// not processed.
+    ZeroRttRejected,
+}
+
+impl CloseReason {
+    /// Work out the reason for an error code that the peer sent.
+    fn from_peer(error: AppError) -> Self {
+        if Error::from_code(error).is_protocol_error() {
+            CloseReason::Http3(error)
+        } else {
+            CloseReason::PeerApplication(error)
+        }
+    }
+
+    /// Work out the reason that the transport is closing, if it is.
+    /// An endpoint is `Closing` if it closed the connection and `Draining` if
+    /// the peer did.
+    fn from_state(state: &State) -> Option<Self> {
+        match state {
+            State::Closing { error, .. } => Some(match error {
+                ConnectionError::Transport(e) => CloseReason::Transport(e.code()),
+                ConnectionError::Application(e) => CloseReason::Local(*e),
+            }),
+            State::Closed {
+                error: ConnectionError::Transport(TransportError::IdleTimeout),
+                ..
+            } => Some(CloseReason::IdleTimeout),
+            State::Draining { error, .. } | State::Closed { error, .. } => Some(match error {
+                ConnectionError::Transport(e) => CloseReason::Transport(e.code()),
+                ConnectionError::Application(e) => CloseReason::from_peer(*e),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Error> for CloseReason {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::TransportError(e) => CloseReason::Transport(e.code()),
+            e => CloseReason::Http3(e.code()),
+        }
+    }
+}
+
+/// Whether a request was sent in 0-RTT.  Requests in 0-RTT can be replayed,
+/// so this can be used to check that only idempotent requests used 0-RTT.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+pub enum ZeroRttStatus {
+    /// The request was not sent in 0-RTT.
+    NotSent,
+    /// The request was sent in 0-RTT, but the server hasn't accepted or
+    /// rejected 0-RTT yet.
+    Sent,
+    /// The request was sent in 0-RTT and the server accepted 0-RTT.
+    Accepted,
+    /// The request was sent in 0-RTT, but the server rejected 0-RTT,
+    /// so the request was sent again after the handshake completed.
+    Resent,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -1047,8 +2452,15 @@ pub struct Http3Events {
 }
 
 impl Http3Events {
-    pub fn header_ready(&self, stream_id: u64) {
-        self.insert(Http3Event::HeaderReady { stream_id });
+    pub fn interim_response(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3Event::InterimResponse { stream_id, headers });
+    }
+
+    pub fn header_ready(&self, stream_id: u64, zero_rtt: ZeroRttStatus) {
+        self.insert(Http3Event::HeaderReady {
+            stream_id,
+            zero_rtt,
+        });
     }
 
     pub fn data_writable(&self, stream_id: u64) {
@@ -1059,8 +2471,19 @@ impl Http3Events {
         self.insert(Http3Event::DataReadable { stream_id });
     }
 
-    pub fn reset(&self, stream_id: u64, error: AppError) {
-        self.insert(Http3Event::Reset { stream_id, error });
+    pub fn trailers(&self, stream_id: u64, trailers: Vec<Header>) {
+        self.insert(Http3Event::Trailers {
+            stream_id,
+            trailers,
+        });
+    }
+
+    pub fn reset(&self, stream_id: u64, error: AppError, reason: CloseReason) {
+        self.insert(Http3Event::Reset {
+            stream_id,
+            error,
+            reason,
+        });
     }
 
     pub fn stop_sending(&self, stream_id: u64, error: AppError) {
@@ -1071,6 +2494,26 @@ impl Http3Events {
         self.insert(Http3Event::NewPushStream { stream_id });
     }
 
+    pub fn request(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3Event::Request { stream_id, headers });
+    }
+
+    pub fn new_tunnel(&self, stream_id: u64) {
+        self.insert(Http3Event::NewTunnel { stream_id });
+    }
+
+    pub fn new_capsule_stream(&self, stream_id: u64) {
+        self.insert(Http3Event::NewCapsuleStream { stream_id });
+    }
+
+    pub fn new_udp_tunnel(&self, stream_id: u64, host: String, port: u16) {
+        self.insert(Http3Event::NewUdpTunnel {
+            stream_id,
+            host,
+            port,
+        });
+    }
+
     pub fn new_requests_creatable(&self) {
         self.insert(Http3Event::RequestsCreatable);
     }
@@ -1079,18 +2522,56 @@ impl Http3Events {
         self.insert(Http3Event::AuthenticationNeeded);
     }
 
+    pub fn request_timeout(&self, stream_id: u64) {
+        self.insert(Http3Event::RequestTimeout { stream_id });
+    }
+
     pub fn goaway_received(&self) {
         self.insert(Http3Event::GoawayReceived);
     }
 
+    pub fn unknown_settings(&self, settings: Vec<(u64, u64)>) {
+        self.insert(Http3Event::UnknownSettings { settings });
+    }
+
     pub fn connection_state_change(&self, state: Http3State) {
         self.insert(Http3Event::StateChange(state));
     }
 
+    pub fn connection_ended(&self, reason: CloseReason) {
+        self.insert(Http3Event::ConnectionEnded { reason });
+    }
+
+    pub fn webtransport_new_session(&self, session_id: u64) {
+        self.insert(Http3Event::WebTransportNewSession { session_id });
+    }
+
+    pub fn webtransport_new_stream(&self, stream_id: u64, session_id: u64) {
+        self.insert(Http3Event::WebTransportNewStream {
+            stream_id,
+            session_id,
+        });
+    }
+
+    pub fn webtransport_data_readable(&self, stream_id: u64) {
+        self.insert(Http3Event::WebTransportDataReadable { stream_id });
+    }
+
+    pub fn webtransport_session_closed(&self, session_id: u64) {
+        self.insert(Http3Event::WebTransportSessionClosed { session_id });
+    }
+
     pub fn events(&self) -> impl Iterator<Item = Http3Event> {
         self.events.replace(BTreeSet::new()).into_iter()
     }
 
+    /// Take the first event, leaving the rest where they are.
+    pub fn next_event(&self) -> Option<Http3Event> {
+        let mut events = self.events.borrow_mut();
+        let first = events.iter().next()?.clone();
+        events.take(&first)
+    }
+
     fn insert(&self, event: Http3Event) {
         self.events.borrow_mut().insert(event);
     }
@@ -1105,12 +2586,23 @@ impl Http3Events {
             .borrow()
             .iter()
             .filter(|evt| match evt {
-                Http3Event::HeaderReady { stream_id }
+                Http3Event::HeaderReady { stream_id, .. }
+                | Http3Event::InterimResponse { stream_id, .. }
                 | Http3Event::DataWritable { stream_id }
                 | Http3Event::DataReadable { stream_id }
+                | Http3Event::Trailers { stream_id, .. }
                 | Http3Event::NewPushStream { stream_id }
+                | Http3Event::Request { stream_id, .. }
+                | Http3Event::NewTunnel { stream_id }
+                | Http3Event::NewCapsuleStream { stream_id }
+                | Http3Event::NewUdpTunnel { stream_id, .. }
                 | Http3Event::Reset { stream_id, .. }
-                | Http3Event::StopSending { stream_id, .. } => *stream_id == remove_stream_id,
+                | Http3Event::StopSending { stream_id, .. }
+                | Http3Event::RequestTimeout { stream_id }
+                | Http3Event::WebTransportNewStream { stream_id, .. }
+                | Http3Event::WebTransportDataReadable { stream_id } => {
+                    *stream_id == remove_stream_id
+                }
                 _ => false,
             })
             .cloned()
@@ -1136,6 +2628,10 @@ mod tests {
             }
             _ => panic!("Wrong state {:?}", hconn.state()),
         };
+        assert_eq!(
+            hconn.close_reason(),
+            Some(CloseReason::Http3(expected.code()))
+        );
     }
 
     // Start a client/server and check setting frame.
@@ -1754,7 +3250,7 @@ mod tests {
         assert_eq!(http_events.len(), 2);
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -1822,7 +3318,7 @@ mod tests {
         let http_events = hconn.events();
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -1851,79 +3347,282 @@ mod tests {
             }
         }
 
-        // after this stream will be removed from hconn. We will check this by trying to read
-        // from the stream and that should fail.
+        // after this stream will be removed from hconn. We will check this by trying to read
+        // from the stream and that should fail.
+        let mut buf = [0u8; 100];
+        let res = hconn.read_response_data(now(), request_stream_id, &mut buf);
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
+
+        hconn.close(now(), 0, "");
+    }
+
+    // Send a request with the request body.
+    #[test]
+    fn fetch_with_data() {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        let request_stream_id = hconn
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        assert_eq!(request_stream_id, 0);
+
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+
+        // Get DataWritable for the request stream so that we can write the request body.
+        let data_writable = |e| matches!(e, Http3Event::DataWritable { .. });
+        assert!(hconn.events().any(data_writable));
+        let sent = hconn.send_request_body(request_stream_id, &[0x64, 0x65, 0x66]);
+        assert_eq!(sent, Ok(3));
+        let _ = hconn.stream_close_send(request_stream_id);
+
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+
+        // find the new request/response stream and send response on it.
+        let events = neqo_trans_conn.events();
+        for e in events {
+            match e {
+                ConnectionEvent::NewStream {
+                    stream_id,
+                    stream_type,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(stream_type, StreamType::BiDi);
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    let mut buf = [0u8; 100];
+                    let (amount, fin) = neqo_trans_conn.stream_recv(stream_id, &mut buf).unwrap();
+                    assert_eq!(fin, true);
+                    const EXPECTED_RESPONSE_BODY: &[u8] = &[
+                        0x01, 0x10, 0x00, 0x00, 0xd1, 0xd7, 0x50, 0x89, 0x41, 0xe9, 0x2a, 0x67,
+                        0x35, 0x53, 0x2e, 0x43, 0xd3, 0xc1, 0x0, 0x3, 0x64, 0x65, 0x66,
+                    ];
+                    assert_eq!(amount, EXPECTED_RESPONSE_BODY.len());
+                    assert_eq!(&buf[..23], EXPECTED_RESPONSE_BODY);
+
+                    // send response - 200  Content-Length: 3
+                    // with content: 'abc'.
+                    let _ = neqo_trans_conn.stream_send(
+                        stream_id,
+                        &[
+                            // headers
+                            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33,
+                            // a data frame
+                            0x0, 0x3, 0x61, 0x62, 0x63,
+                        ],
+                    );
+                    neqo_trans_conn.stream_close_send(stream_id).unwrap();
+                }
+                _ => {}
+            }
+        }
+
+        read_response(hconn, neqo_trans_conn, request_stream_id);
+    }
+
+    // Send a request body that is larger than the flow control limit.
+    #[test]
+    fn fetch_with_large_body() {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        let request_stream_id = hconn
+            .fetch("POST", "https", "something.com", "/", &[])
+            .unwrap();
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+
+        let data_writable = |e| matches!(e, Http3Event::DataWritable { .. });
+        assert!(hconn.events().any(data_writable));
+
+        // Send until flow control stops us.
+        let body = [0x61; 10_000];
+        let mut total = 0;
+        loop {
+            let sent = hconn.send_request_body(request_stream_id, &body).unwrap();
+            total += sent;
+            if sent < body.len() {
+                break;
+            }
+        }
+        assert!(total < 0xffff);
+        assert_eq!(hconn.send_request_body(request_stream_id, &body), Ok(0));
+
+        // The server reads the request, which gives more credit.
+        let mut t = now();
+        let mut out = None;
+        let mut writable = false;
+        let mut buf = [0u8; 10_000];
+        for _ in 0..100 {
+            out = hconn.process(out, t).dgram();
+            out = neqo_trans_conn.process(out, t).dgram();
+            while let Ok((amount, _)) = neqo_trans_conn.stream_recv(request_stream_id, &mut buf) {
+                if amount == 0 {
+                    break;
+                }
+            }
+            if hconn.events().any(data_writable) {
+                writable = true;
+                break;
+            }
+            t += Duration::from_millis(10);
+        }
+        assert!(writable);
+        assert!(hconn.send_request_body(request_stream_id, &body).unwrap() > 0);
+    }
+
+    // Send a request with a body and trailers.
+    #[test]
+    fn fetch_with_trailers() {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        let request_stream_id = hconn
+            .fetch("POST", "https", "something.com", "/", &[])
+            .unwrap();
+        let trailers = vec![(String::from("age"), String::from("0"))];
+        // Trailers can't be sent before the headers.
+        assert_eq!(
+            hconn.send_request_trailers(request_stream_id, &trailers),
+            Err(Error::Unavailable)
+        );
+
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+
+        let data_writable = |e| matches!(e, Http3Event::DataWritable { .. });
+        assert!(hconn.events().any(data_writable));
+        let sent = hconn.send_request_body(request_stream_id, &[0x64, 0x65, 0x66]);
+        assert_eq!(sent, Ok(3));
+        assert_eq!(
+            hconn.send_request_trailers(request_stream_id, &trailers),
+            Ok(())
+        );
+        // The trailers end the request.
+        assert_eq!(
+            hconn.send_request_body(request_stream_id, &[0x67]),
+            Err(Error::AlreadyClosed)
+        );
+
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+
+        let mut buf = [0u8; 100];
+        let (amount, fin) = neqo_trans_conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert_eq!(fin, true);
+        const EXPECTED_REQUEST: &[u8] = &[
+            // headers
+            0x01, 0x10, 0x00, 0x00, 0xd4, 0xd7, 0x50, 0x89, 0x41, 0xe9, 0x2a, 0x67, 0x35, 0x53,
+            0x2e, 0x43, 0xd3, 0xc1, // a data frame
+            0x0, 0x3, 0x64, 0x65, 0x66, // trailers
+            0x01, 0x03, 0x00, 0x00, 0xc2,
+        ];
+        assert_eq!(&buf[..amount], EXPECTED_REQUEST);
+    }
+
+    // Receive a response with trailers.
+    #[test]
+    fn response_trailers() {
+        let (mut hconn, mut neqo_trans_conn, request_stream_id) = connect_and_send_request();
+        let data = &[
+            // headers
+            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33, // a data frame
+            0x0, 0x3, 0x61, 0x62, 0x63, // trailers
+            0x01, 0x03, 0x00, 0x00, 0xc2,
+        ];
+        let _ = neqo_trans_conn.stream_send(request_stream_id, data);
+        neqo_trans_conn
+            .stream_close_send(request_stream_id)
+            .unwrap();
+
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
         let mut buf = [0u8; 100];
+        let events: Vec<_> = hconn.events().collect();
+        assert!(events.contains(&Http3Event::DataReadable {
+            stream_id: request_stream_id
+        }));
+        assert!(hconn.read_response_headers(request_stream_id).is_ok());
         let res = hconn.read_response_data(now(), request_stream_id, &mut buf);
-        assert!(res.is_err());
-        assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
-
-        hconn.close(now(), 0, "");
+        assert_eq!(res, Ok((3, false)));
+        assert_eq!(&buf[..3], &[0x61, 0x62, 0x63]);
+
+        // The trailers are read along with the end of the body.
+        let events: Vec<_> = hconn.events().collect();
+        assert!(events.contains(&Http3Event::Trailers {
+            stream_id: request_stream_id,
+            trailers: vec![(String::from("age"), String::from("0"))],
+        }));
+        assert!(events.contains(&Http3Event::DataReadable {
+            stream_id: request_stream_id
+        }));
+        let res = hconn.read_response_data(now(), request_stream_id, &mut buf);
+        assert_eq!(res, Ok((0, true)));
     }
 
-    // Send a request with the request body.
+    // Interim responses come before the response headers.
     #[test]
-    fn fetch_with_data() {
-        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
-        let request_stream_id = hconn
-            .fetch("GET", "https", "something.com", "/", &[])
+    fn response_interim() {
+        let (mut hconn, mut neqo_trans_conn, request_stream_id) = connect_and_send_request();
+        let data = &[
+            // 103 Early Hints
+            0x01, 0x04, 0x00, 0x00, 0xd8, 0xc2, // headers
+            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33, // a data frame
+            0x0, 0x3, 0x61, 0x62, 0x63,
+        ];
+        let _ = neqo_trans_conn.stream_send(request_stream_id, data);
+        neqo_trans_conn
+            .stream_close_send(request_stream_id)
             .unwrap();
-        assert_eq!(request_stream_id, 0);
 
-        let out = hconn.process(None, now());
-        neqo_trans_conn.process(out.dgram(), now());
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
 
-        // Get DataWritable for the request stream so that we can write the request body.
-        let data_writable = |e| matches!(e, Http3Event::DataWritable { .. });
-        assert!(hconn.events().any(data_writable));
-        let sent = hconn.send_request_body(request_stream_id, &[0x64, 0x65, 0x66]);
-        assert_eq!(sent, Ok(3));
-        let _ = hconn.stream_close_send(request_stream_id);
+        let events: Vec<_> = hconn.events().collect();
+        assert!(events.contains(&Http3Event::InterimResponse {
+            stream_id: request_stream_id,
+            headers: vec![
+                (String::from(":status"), String::from("103")),
+                (String::from("age"), String::from("0"))
+            ],
+        }));
+        assert!(events.contains(&Http3Event::HeaderReady {
+            stream_id: request_stream_id,
+            zero_rtt: ZeroRttStatus::NotSent,
+        }));
+        let (headers, fin) = hconn.read_response_headers(request_stream_id).unwrap();
+        assert_eq!(headers[0], (String::from(":status"), String::from("200")));
+        assert!(!fin);
+        let mut buf = [0u8; 100];
+        let res = hconn.read_response_data(now(), request_stream_id, &mut buf);
+        assert_eq!(res, Ok((3, true)));
+    }
 
-        let out = hconn.process(None, now());
-        neqo_trans_conn.process(out.dgram(), now());
+    // Nothing may follow the trailers.
+    #[test]
+    fn response_data_after_trailers() {
+        let (mut hconn, mut neqo_trans_conn, request_stream_id) = connect_and_send_request();
+        let data = &[
+            // headers
+            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33, // trailers
+            0x01, 0x03, 0x00, 0x00, 0xc2, // a data frame
+            0x0, 0x3, 0x61, 0x62, 0x63,
+        ];
+        let _ = neqo_trans_conn.stream_send(request_stream_id, data);
 
-        // find the new request/response stream and send response on it.
-        let events = neqo_trans_conn.events();
-        for e in events {
-            match e {
-                ConnectionEvent::NewStream {
-                    stream_id,
-                    stream_type,
-                } => {
-                    assert_eq!(stream_id, request_stream_id);
-                    assert_eq!(stream_type, StreamType::BiDi);
-                }
-                ConnectionEvent::RecvStreamReadable { stream_id } => {
-                    assert_eq!(stream_id, request_stream_id);
-                    let mut buf = [0u8; 100];
-                    let (amount, fin) = neqo_trans_conn.stream_recv(stream_id, &mut buf).unwrap();
-                    assert_eq!(fin, true);
-                    const EXPECTED_RESPONSE_BODY: &[u8] = &[
-                        0x01, 0x10, 0x00, 0x00, 0xd1, 0xd7, 0x50, 0x89, 0x41, 0xe9, 0x2a, 0x67,
-                        0x35, 0x53, 0x2e, 0x43, 0xd3, 0xc1, 0x0, 0x3, 0x64, 0x65, 0x66,
-                    ];
-                    assert_eq!(amount, EXPECTED_RESPONSE_BODY.len());
-                    assert_eq!(&buf[..23], EXPECTED_RESPONSE_BODY);
+        let out = neqo_trans_conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+        neqo_trans_conn.process(out.dgram(), now());
 
-                    // send response - 200  Content-Length: 3
-                    // with content: 'abc'.
-                    let _ = neqo_trans_conn.stream_send(
-                        stream_id,
-                        &[
-                            // headers
-                            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33,
-                            // a data frame
-                            0x0, 0x3, 0x61, 0x62, 0x63,
-                        ],
-                    );
-                    neqo_trans_conn.stream_close_send(stream_id).unwrap();
-                }
-                _ => {}
+        let stop_sending = |e| {
+            e == ConnectionEvent::SendStreamStopSending {
+                stream_id: request_stream_id,
+                app_error: Error::UnexpectedFrame.code(),
             }
-        }
-
-        read_response(hconn, neqo_trans_conn, request_stream_id);
+        };
+        assert!(neqo_trans_conn.events().any(stop_sending));
+        assert_eq!(hconn.state(), Http3State::Connected);
     }
 
     // send a request with request body containing request_body. We expect to receive expected_data_frame_header.
@@ -2313,7 +4012,7 @@ mod tests {
                         hconn.send_request_body(request_stream_id, &[0u8; 10])
                     );
                 }
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -2398,9 +4097,14 @@ mod tests {
                 Http3Event::StopSending { .. } => {
                     panic!("We should not get StopSending.");
                 }
-                Http3Event::Reset { stream_id, error } => {
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
                     assert_eq!(stream_id, request_stream_id);
                     assert_eq!(error, Error::RequestRejected.code());
+                    assert_eq!(reason, CloseReason::PeerApplication(error));
                 }
                 Http3Event::HeaderReady { .. } | Http3Event::DataReadable { .. } => {
                     panic!("We should not get any headers or data");
@@ -2458,9 +4162,14 @@ mod tests {
                 Http3Event::StopSending { .. } => {
                     panic!("We should not get StopSending.");
                 }
-                Http3Event::Reset { stream_id, error } => {
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
                     assert_eq!(stream_id, request_stream_id);
                     assert_eq!(error, Error::RequestRejected.code());
+                    assert_eq!(reason, CloseReason::PeerApplication(error));
                 }
                 Http3Event::HeaderReady { .. } | Http3Event::DataReadable { .. } => {
                     panic!("We should not get any headers or data");
@@ -2537,9 +4246,14 @@ mod tests {
                 Http3Event::StopSending { .. } => {
                     panic!("We should not get StopSending.");
                 }
-                Http3Event::Reset { stream_id, error } => {
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
                     assert_eq!(stream_id, request_stream_id);
                     assert_eq!(error, Error::RequestCancelled.code());
+                    assert_eq!(reason, CloseReason::PeerApplication(error));
                 }
                 Http3Event::HeaderReady { .. } | Http3Event::DataReadable { .. } => {
                     panic!("We should not get any headers or data");
@@ -2613,9 +4327,14 @@ mod tests {
                 Http3Event::StopSending { .. } => {
                     panic!("We should not get StopSending.");
                 }
-                Http3Event::Reset { stream_id, error } => {
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
                     assert_eq!(stream_id, request_stream_id);
                     assert_eq!(error, Error::RequestCancelled.code());
+                    assert_eq!(reason, CloseReason::PeerApplication(error));
                 }
                 Http3Event::HeaderReady { .. } | Http3Event::DataReadable { .. } => {
                     panic!("We should not get any headers or data");
@@ -2672,9 +4391,14 @@ mod tests {
                 Http3Event::StopSending { .. } => {
                     panic!("We should not get StopSending.");
                 }
-                Http3Event::Reset { stream_id, error } => {
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
                     assert_eq!(stream_id, request_stream_id);
                     assert_eq!(error, Error::RequestCancelled.code());
+                    assert_eq!(reason, CloseReason::PeerApplication(error));
                 }
                 Http3Event::HeaderReady { .. } | Http3Event::DataReadable { .. } => {
                     panic!("We should not get any headers or data");
@@ -2851,7 +4575,7 @@ mod tests {
         while !http_events.is_empty() {
             for e in http_events {
                 match e {
-                    Http3Event::HeaderReady { stream_id } => {
+                    Http3Event::HeaderReady { stream_id, .. } => {
                         let h = hconn.read_response_headers(stream_id);
                         assert_eq!(
                             h,
@@ -2874,9 +4598,14 @@ mod tests {
                             .unwrap();
                         assert_eq!(amount, 3);
                     }
-                    Http3Event::Reset { stream_id, error } => {
+                    Http3Event::Reset {
+                        stream_id,
+                        error,
+                        reason,
+                    } => {
                         assert!(stream_id == request_stream_id_3);
                         assert_eq!(error, Error::RequestRejected.code());
+                        assert_eq!(reason, CloseReason::Goaway);
                         stream_reset = true;
                     }
                     _ => {}
@@ -2888,9 +4617,34 @@ mod tests {
 
         assert!(stream_reset);
         assert_eq!(hconn.state(), Http3State::GoingAway);
+        // No more requests can be sent.
+        assert_eq!(
+            hconn.fetch("GET", "https", "something.com", "/", &[]),
+            Err(Error::Unavailable)
+        );
         hconn.close(now(), 0, "");
     }
 
+    fn test_goaway_invalid(goaways: &[u8]) {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        let _ = neqo_trans_conn.stream_send(3, goaways);
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&hconn, Error::GeneralProtocolError);
+    }
+
+    // A GOAWAY has to carry the ID of a request stream.
+    #[test]
+    fn test_goaway_not_request_stream() {
+        test_goaway_invalid(&[0x7, 0x1, 0x3]);
+    }
+
+    // A later GOAWAY can't carry a larger ID.
+    #[test]
+    fn test_goaway_increasing() {
+        test_goaway_invalid(&[0x7, 0x1, 0x8, 0x7, 0x1, 0xc]);
+    }
+
     fn connect_and_send_request() -> (Http3Connection, Connection, u64) {
         let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
         let request_stream_id = hconn
@@ -2948,7 +4702,7 @@ mod tests {
 
         // Recv HeaderReady wo headers with fin.
         let e = hconn.events().next().unwrap();
-        if let Http3Event::HeaderReady { stream_id } = e {
+        if let Http3Event::HeaderReady { stream_id, .. } = e {
             assert_eq!(stream_id, request_stream_id);
             let h = hconn.read_response_headers(stream_id);
             assert_eq!(h, Ok((vec![], true)));
@@ -2981,7 +4735,7 @@ mod tests {
 
         // Recv HeaderReady with headers and fin.
         let e = hconn.events().next().unwrap();
-        if let Http3Event::HeaderReady { stream_id } = e {
+        if let Http3Event::HeaderReady { stream_id, .. } = e {
             assert_eq!(stream_id, request_stream_id);
             let h = hconn.read_response_headers(stream_id);
             assert_eq!(
@@ -3025,7 +4779,7 @@ mod tests {
         let http_events = hconn.events();
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -3101,7 +4855,7 @@ mod tests {
         let http_events = hconn.events();
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -3150,7 +4904,7 @@ mod tests {
         let http_events = hconn.events();
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -3222,7 +4976,7 @@ mod tests {
         let http_events = hconn.events();
         for e in http_events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     let h = hconn.read_response_headers(stream_id);
                     assert_eq!(
@@ -3551,7 +5305,7 @@ mod tests {
         let events = hconn.events();
         for e in events {
             match e {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     assert_eq!(stream_id, request_stream_id);
                     recv_header = true;
                 }
@@ -3567,4 +5321,261 @@ mod tests {
         }
         assert!(recv_header && recv_data);
     }
+
+    #[test]
+    fn zero_rtt_request() {
+        let (mut hconn, mut neqo_trans_conn) = connect(true);
+        neqo_trans_conn
+            .send_ticket(now(), &[])
+            .expect("can send ticket");
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        let token = hconn.conn().resumption_token().expect("should have token");
+
+        let mut hconn = Http3Connection::new(default_client(), 100, 100, None);
+        hconn
+            .set_resumption_token(now(), &token)
+            .expect("should set token");
+        let mut neqo_trans_conn = default_server();
+        let request_stream_id = hconn
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        assert_eq!(hconn.stream_close_send(request_stream_id), Ok(()));
+
+        // The request is sent in 0-RTT, alongside the handshake.
+        let mut out = hconn.process(None, now());
+        for _ in 0..5 {
+            out = neqo_trans_conn.process(out.dgram(), now());
+            out = hconn.process(out.dgram(), now());
+        }
+        assert_eq!(hconn.state(), Http3State::Connected);
+
+        // send response - 200  Content-Length: 3
+        // with content: 'abc'.
+        let _ = neqo_trans_conn.stream_send(
+            request_stream_id,
+            &[
+                // headers
+                0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33, // a data frame
+                0x0, 0x3, 0x61, 0x62, 0x63,
+            ],
+        );
+        neqo_trans_conn
+            .stream_close_send(request_stream_id)
+            .unwrap();
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let header_ready = |e| {
+            e == Http3Event::HeaderReady {
+                stream_id: request_stream_id,
+                zero_rtt: ZeroRttStatus::Accepted,
+            }
+        };
+        assert!(hconn.events().any(header_ready));
+    }
+
+    /// Make a client that has a 0-RTT request outstanding, and a server
+    /// that rejects 0-RTT because it has a fresh anti-replay context.
+    fn zero_rtt_rejected_setup(resend: bool) -> (Http3Connection, Connection, u64) {
+        let (mut hconn, mut neqo_trans_conn) = connect(true);
+        neqo_trans_conn
+            .send_ticket(now(), &[])
+            .expect("can send ticket");
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        let token = hconn.conn().resumption_token().expect("should have token");
+
+        let mut hconn = Http3Connection::new(default_client(), 100, 100, None);
+        hconn
+            .set_resumption_token(now(), &token)
+            .expect("should set token");
+        hconn.conn().set_0rtt_resend(resend);
+        let ar = neqo_crypto::AntiReplay::new(now(), ANTI_REPLAY_WINDOW, 1, 3)
+            .expect("setup anti-replay");
+        let neqo_trans_conn = Connection::new_server(
+            DEFAULT_KEYS,
+            DEFAULT_ALPN,
+            &ar,
+            Rc::new(RefCell::new(neqo_transport::FixedConnectionIdManager::new(
+                5,
+            ))),
+        )
+        .unwrap();
+        let request_stream_id = hconn
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        assert_eq!(hconn.stream_close_send(request_stream_id), Ok(()));
+        (hconn, neqo_trans_conn, request_stream_id)
+    }
+
+    #[test]
+    fn zero_rtt_request_resent() {
+        let (mut hconn, mut neqo_trans_conn, request_stream_id) = zero_rtt_rejected_setup(true);
+        let mut out = hconn.process(None, now());
+        for _ in 0..5 {
+            out = neqo_trans_conn.process(out.dgram(), now());
+            out = hconn.process(out.dgram(), now());
+        }
+        assert_eq!(hconn.state(), Http3State::Connected);
+
+        // The request was sent again in 1-RTT; respond to it.
+        let _ = neqo_trans_conn.stream_send(
+            request_stream_id,
+            &[0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33],
+        );
+        neqo_trans_conn
+            .stream_close_send(request_stream_id)
+            .unwrap();
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let header_ready = |e| {
+            e == Http3Event::HeaderReady {
+                stream_id: request_stream_id,
+                zero_rtt: ZeroRttStatus::Resent,
+            }
+        };
+        assert!(hconn.events().any(header_ready));
+    }
+
+    #[test]
+    fn zero_rtt_request_rejected() {
+        let (mut hconn, mut neqo_trans_conn, request_stream_id) = zero_rtt_rejected_setup(false);
+        let mut out = hconn.process(None, now());
+        for _ in 0..5 {
+            out = neqo_trans_conn.process(out.dgram(), now());
+            out = hconn.process(out.dgram(), now());
+        }
+        assert_eq!(hconn.state(), Http3State::Connected);
+
+        // The request was discarded, so the application is told to retry it.
+        let err = Error::RequestRejected.code();
+        let reset = |e| {
+            e == Http3Event::Reset {
+                stream_id: request_stream_id,
+                error: err,
+                reason: CloseReason::ZeroRttRejected,
+            }
+        };
+        assert!(hconn.events().any(reset));
+
+        // The control and QPACK streams were opened again, so the server
+        // gets SETTINGS and the connection is usable.
+        let new_uni_stream = |e| {
+            matches!(
+                e,
+                ConnectionEvent::NewStream {
+                    stream_type: StreamType::UniDi,
+                    ..
+                }
+            )
+        };
+        assert!(neqo_trans_conn.events().any(new_uni_stream));
+        let request_stream_id = hconn
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        assert_eq!(hconn.stream_close_send(request_stream_id), Ok(()));
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+        let new_request = |e| {
+            e == ConnectionEvent::NewStream {
+                stream_id: request_stream_id,
+                stream_type: StreamType::BiDi,
+            }
+        };
+        assert!(neqo_trans_conn.events().any(new_request));
+    }
+
+    fn connection_ended(hconn: &mut Http3Connection) -> Option<CloseReason> {
+        hconn.events().find_map(|e| match e {
+            Http3Event::ConnectionEnded { reason } => Some(reason),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn close_reason_local() {
+        let (mut hconn, _, _, _) = connect_and_receive_control_stream(true);
+        hconn.close(now(), Error::RequestCancelled.code(), "");
+        let reason = CloseReason::Local(Error::RequestCancelled.code());
+        assert_eq!(connection_ended(&mut hconn), Some(reason));
+        assert_eq!(hconn.close_reason(), Some(reason));
+
+        // The transport closing doesn't change the reason.
+        hconn.process(None, now());
+        assert_eq!(connection_ended(&mut hconn), None);
+        assert_eq!(hconn.close_reason(), Some(reason));
+    }
+
+    fn peer_close(error: Error) -> Option<CloseReason> {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        neqo_trans_conn.close(now(), error.code(), "");
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        connection_ended(&mut hconn)
+    }
+
+    #[test]
+    fn close_reason_peer() {
+        assert_eq!(
+            peer_close(Error::NoError),
+            Some(CloseReason::PeerApplication(Error::NoError.code()))
+        );
+        assert_eq!(
+            peer_close(Error::MissingSettings),
+            Some(CloseReason::Http3(Error::MissingSettings.code()))
+        );
+    }
+
+    #[test]
+    fn close_reason_idle() {
+        let (mut hconn, _, _, _) = connect_and_receive_control_stream(true);
+        let later = now() + Duration::from_secs(60);
+        hconn.process_timer(later);
+        hconn.process_http3(later);
+        assert_eq!(connection_ended(&mut hconn), Some(CloseReason::IdleTimeout));
+    }
+
+    #[test]
+    fn request_deadline() {
+        let (mut hconn, mut neqo_trans_conn, _, _) = connect_and_receive_control_stream(true);
+        let request_stream_id = hconn
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        let deadline = now() + Duration::from_secs(1);
+        assert_eq!(
+            hconn.set_request_deadline(request_stream_id, deadline),
+            Ok(())
+        );
+        assert_eq!(
+            hconn.set_request_deadline(request_stream_id + 4, deadline),
+            Err(Error::InvalidStreamId)
+        );
+
+        let out = hconn.process(None, now());
+        neqo_trans_conn.process(out.dgram(), now());
+        match hconn.process(None, now()) {
+            Output::Callback(t) => assert!(t <= Duration::from_secs(1)),
+            o => panic!("unexpected output {:?}", o),
+        }
+
+        // Once the deadline passes, the request is cancelled.
+        let out = hconn.process(None, deadline);
+        let timeout = Http3Event::RequestTimeout {
+            stream_id: request_stream_id,
+        };
+        assert!(hconn.events().any(|e| e == timeout));
+        assert_eq!(
+            hconn.read_response_headers(request_stream_id),
+            Err(Error::InvalidStreamId)
+        );
+
+        neqo_trans_conn.process(out.dgram(), deadline);
+        let reset = ConnectionEvent::RecvStreamReset {
+            stream_id: request_stream_id,
+            app_error: Error::RequestCancelled.code(),
+        };
+        assert!(neqo_trans_conn.events().any(|e| e == reset));
+    }
 }
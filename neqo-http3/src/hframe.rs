@@ -9,6 +9,7 @@ use neqo_common::{
 };
 use neqo_transport::Connection;
 
+use std::cmp::min;
 use std::mem;
 
 use crate::{Error, Res};
@@ -21,6 +22,7 @@ const H3_FRAME_TYPE_CANCEL_PUSH: HFrameType = 0x3;
 const H3_FRAME_TYPE_SETTINGS: HFrameType = 0x4;
 const H3_FRAME_TYPE_PUSH_PROMISE: HFrameType = 0x5;
 const H3_FRAME_TYPE_GOAWAY: HFrameType = 0x7;
+const H3_FRAME_TYPE_ORIGIN: HFrameType = 0xc;
 const H3_FRAME_TYPE_MAX_PUSH_ID: HFrameType = 0xd;
 const H3_FRAME_TYPE_DUPLICATE_PUSH: HFrameType = 0xe;
 
@@ -29,6 +31,11 @@ type SettingsType = u64;
 const SETTINGS_MAX_HEADER_LIST_SIZE: SettingsType = 0x6;
 const SETTINGS_QPACK_MAX_TABLE_CAPACITY: SettingsType = 0x1;
 const SETTINGS_QPACK_BLOCKED_STREAMS: SettingsType = 0x7;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: SettingsType = 0x8;
+const SETTINGS_ENABLE_WEBTRANSPORT: SettingsType = 0x2b60_3742;
+/// Reserved settings are 0x1f * N + 0x21.
+const SETTINGS_GREASE_BASE: SettingsType = 0x21;
+const SETTINGS_GREASE_STEP: SettingsType = 0x1f;
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum HStreamType {
@@ -37,12 +44,61 @@ pub enum HStreamType {
     Push,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum HSettingType {
     MaxHeaderListSize,
     MaxTableSize,
     BlockedStreams,
-    UnknownType,
+    /// Extended CONNECT, from RFC 8441.
+    EnableConnectProtocol,
+    /// WebTransport, from draft-ietf-webtrans-http3-02.
+    EnableWebTransport,
+    /// A setting that this crate doesn't use itself, with its identifier.
+    Unknown(SettingsType),
+}
+
+impl HSettingType {
+    pub fn from_id(id: SettingsType) -> Self {
+        match id {
+            SETTINGS_MAX_HEADER_LIST_SIZE => HSettingType::MaxHeaderListSize,
+            SETTINGS_QPACK_MAX_TABLE_CAPACITY => HSettingType::MaxTableSize,
+            SETTINGS_QPACK_BLOCKED_STREAMS => HSettingType::BlockedStreams,
+            SETTINGS_ENABLE_CONNECT_PROTOCOL => HSettingType::EnableConnectProtocol,
+            SETTINGS_ENABLE_WEBTRANSPORT => HSettingType::EnableWebTransport,
+            _ => HSettingType::Unknown(id),
+        }
+    }
+
+    pub fn id(self) -> SettingsType {
+        match self {
+            HSettingType::MaxHeaderListSize => SETTINGS_MAX_HEADER_LIST_SIZE,
+            HSettingType::MaxTableSize => SETTINGS_QPACK_MAX_TABLE_CAPACITY,
+            HSettingType::BlockedStreams => SETTINGS_QPACK_BLOCKED_STREAMS,
+            HSettingType::EnableConnectProtocol => SETTINGS_ENABLE_CONNECT_PROTOCOL,
+            HSettingType::EnableWebTransport => SETTINGS_ENABLE_WEBTRANSPORT,
+            HSettingType::Unknown(id) => id,
+        }
+    }
+}
+
+/// Whether `id` is reserved for exercising the requirement that unknown
+/// settings are ignored.
+pub(crate) fn is_grease_setting(id: SettingsType) -> bool {
+    id >= SETTINGS_GREASE_BASE && (id - SETTINGS_GREASE_BASE) % SETTINGS_GREASE_STEP == 0
+}
+
+/// A reserved setting and a value for it, both picked using `seed`.
+pub(crate) fn grease_setting(seed: u64) -> (SettingsType, u64) {
+    // splitmix64, which is enough to spread out the choices.
+    let mix = |x: u64| {
+        let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let n = mix(seed) & 0xffff_ffff;
+    let value = mix(seed.wrapping_add(1)) & 0x3fff_ffff;
+    (SETTINGS_GREASE_STEP * n + SETTINGS_GREASE_BASE, value)
 }
 
 // data for DATA and header blocks for HEADERS anf PUSH_PROMISE are not read into HFrame.
@@ -73,6 +129,9 @@ pub enum HFrame {
     DuplicatePush {
         push_id: u64,
     },
+    Origin {
+        origins: Vec<String>, // ASCII serializations of origins, from RFC 8336
+    },
 }
 
 impl HFrame {
@@ -86,6 +145,7 @@ impl HFrame {
             HFrame::Goaway { .. } => H3_FRAME_TYPE_GOAWAY,
             HFrame::MaxPushId { .. } => H3_FRAME_TYPE_MAX_PUSH_ID,
             HFrame::DuplicatePush { .. } => H3_FRAME_TYPE_DUPLICATE_PUSH,
+            HFrame::Origin { .. } => H3_FRAME_TYPE_ORIGIN,
         }
     }
 
@@ -105,21 +165,8 @@ impl HFrame {
             HFrame::Settings { settings } => {
                 enc.encode_vvec_with(|enc_inner| {
                     for iter in settings.iter() {
-                        match iter.0 {
-                            HSettingType::MaxHeaderListSize => {
-                                enc_inner.encode_varint(SETTINGS_MAX_HEADER_LIST_SIZE as u64);
-                                enc_inner.encode_varint(iter.1);
-                            }
-                            HSettingType::MaxTableSize => {
-                                enc_inner.encode_varint(SETTINGS_QPACK_MAX_TABLE_CAPACITY as u64);
-                                enc_inner.encode_varint(iter.1);
-                            }
-                            HSettingType::BlockedStreams => {
-                                enc_inner.encode_varint(SETTINGS_QPACK_BLOCKED_STREAMS as u64);
-                                enc_inner.encode_varint(iter.1);
-                            }
-                            HSettingType::UnknownType => {}
-                        }
+                        enc_inner.encode_varint(iter.0.id());
+                        enc_inner.encode_varint(iter.1);
                     }
                 });
             }
@@ -144,6 +191,13 @@ impl HFrame {
                     enc_inner.encode_varint(*push_id);
                 });
             }
+            HFrame::Origin { origins } => {
+                enc.encode_vvec_with(|enc_inner| {
+                    for origin in origins {
+                        enc_inner.encode_vec(2, origin.as_bytes());
+                    }
+                });
+            }
         }
     }
 
@@ -157,6 +211,7 @@ impl HFrame {
             HFrame::Goaway { .. } => (s == HStreamType::Control),
             HFrame::MaxPushId { .. } => (s == HStreamType::Control),
             HFrame::DuplicatePush { .. } => (s == HStreamType::Request),
+            HFrame::Origin { .. } => (s == HStreamType::Control),
         }
     }
 }
@@ -172,6 +227,30 @@ enum HFrameReaderState {
     Done,
 }
 
+/// Send as much of `buf` as flow control allows in a DATA frame on
+/// `stream_id`.  This returns how much of `buf` was sent.
+pub(crate) fn send_data_frame(conn: &mut Connection, stream_id: u64, buf: &[u8]) -> Res<usize> {
+    let available = conn.stream_avail_send_space(stream_id)? as usize;
+    // A DATA frame header is a one byte type and a length.
+    if buf.is_empty() || available <= 2 {
+        return Ok(0);
+    }
+    let to_send = min(
+        buf.len(),
+        available - 1 - Encoder::varint_len(available as u64),
+    );
+    qtrace!("Stream {}: DATA frame of {} bytes", stream_id, to_send);
+    let mut enc = Encoder::default();
+    HFrame::Data {
+        len: to_send as u64,
+    }
+    .encode(&mut enc);
+    enc.encode(&buf[..to_send]);
+    let sent = conn.stream_send(stream_id, &enc)?;
+    assert_eq!(sent, enc.len());
+    Ok(to_send)
+}
+
 #[derive(Debug)]
 pub struct HFrameReader {
     state: HFrameReaderState,
@@ -200,6 +279,15 @@ impl HFrameReader {
         }
     }
 
+    /// A reader for a frame with a type that was already read from the stream.
+    pub fn with_frame_type(hframe_type: HFrameType) -> HFrameReader {
+        HFrameReader {
+            state: HFrameReaderState::GetLength,
+            hframe_type,
+            ..HFrameReader::new()
+        }
+    }
+
     pub fn reset(&mut self) {
         self.state = HFrameReaderState::BeforeFrame;
         self.decoder = IncrementalDecoder::decode_varint();
@@ -269,7 +357,8 @@ impl HFrameReader {
                                 | H3_FRAME_TYPE_SETTINGS
                                 | H3_FRAME_TYPE_GOAWAY
                                 | H3_FRAME_TYPE_MAX_PUSH_ID
-                                | H3_FRAME_TYPE_DUPLICATE_PUSH => {
+                                | H3_FRAME_TYPE_DUPLICATE_PUSH
+                                | H3_FRAME_TYPE_ORIGIN => {
                                     if len == 0 {
                                         HFrameReaderState::Done
                                     } else {
@@ -377,19 +466,11 @@ impl HFrameReader {
                         Some(v) => v,
                         _ => return Err(Error::NotEnoughData),
                     };
-                    let st = match st_read {
-                        SETTINGS_MAX_HEADER_LIST_SIZE => HSettingType::MaxHeaderListSize,
-                        SETTINGS_QPACK_MAX_TABLE_CAPACITY => HSettingType::MaxTableSize,
-                        SETTINGS_QPACK_BLOCKED_STREAMS => HSettingType::BlockedStreams,
-                        _ => HSettingType::UnknownType,
-                    };
                     let v = match dec.decode_varint() {
                         Some(v) => v,
                         _ => return Err(Error::NotEnoughData),
                     };
-                    if st != HSettingType::UnknownType {
-                        settings.push((st, v));
-                    }
+                    settings.push((HSettingType::from_id(st_read), v));
                 }
                 HFrame::Settings { settings }
             }
@@ -419,6 +500,20 @@ impl HFrameReader {
                     _ => return Err(Error::NotEnoughData),
                 },
             },
+            H3_FRAME_TYPE_ORIGIN => {
+                let mut origins = Vec::new();
+                while dec.remaining() > 0 {
+                    let origin = match dec.decode_vec(2) {
+                        Some(v) => v,
+                        _ => return Err(Error::NotEnoughData),
+                    };
+                    if !origin.is_ascii() {
+                        return Err(Error::MalformedFrame(H3_FRAME_TYPE_ORIGIN));
+                    }
+                    origins.push(String::from_utf8_lossy(origin).into_owned());
+                }
+                HFrame::Origin { origins }
+            }
             _ => panic!("We should not be in state Done with unknown frame type!"),
         };
         self.reset();
@@ -514,6 +609,41 @@ mod tests {
         enc_dec(&f, "04020604", 0);
     }
 
+    #[test]
+    fn test_settings_frame_webtransport() {
+        let f = HFrame::Settings {
+            settings: vec![
+                (HSettingType::EnableConnectProtocol, 1),
+                (HSettingType::EnableWebTransport, 1),
+            ],
+        };
+        enc_dec(&f, "04070801ab60374201", 0);
+    }
+
+    #[test]
+    fn test_settings_frame_unknown() {
+        let f = HFrame::Settings {
+            settings: vec![
+                (HSettingType::Unknown(0x21), 2),
+                (HSettingType::Unknown(0x4242), 3),
+            ],
+        };
+        enc_dec(&f, "040721028000424203", 0);
+    }
+
+    #[test]
+    fn test_grease_setting() {
+        for seed in 0..100 {
+            let (id, value) = grease_setting(seed);
+            assert!(is_grease_setting(id));
+            assert!(value < (1 << 62));
+            assert_eq!(HSettingType::from_id(id), HSettingType::Unknown(id));
+        }
+        assert!(!is_grease_setting(0x20));
+        assert!(!is_grease_setting(SETTINGS_ENABLE_CONNECT_PROTOCOL));
+        assert!(is_grease_setting(0x21 + 0x1f));
+    }
+
     #[test]
     fn test_push_promise_frame4() {
         let f = HFrame::PushPromise { push_id: 4, len: 4 };
@@ -538,6 +668,22 @@ mod tests {
         enc_dec(&f, "0e0105", 0);
     }
 
+    #[test]
+    fn test_origin_frame() {
+        let f = HFrame::Origin {
+            origins: vec![
+                String::from("https://a.example"),
+                String::from("https://b.example:8443"),
+            ],
+        };
+        enc_dec(
+            &f,
+            "0c2b001168747470733a2f2f612e6578616d706c6500166874\
+             7470733a2f2f622e6578616d706c653a38343433",
+            0,
+        );
+    }
+
     // We have 3 code paths in frame_reader:
     // 1) All frames except DATA, HEADERES and PUSH_PROMISE (here we test SETTING and SETTINGS with larger varints)
     // 2) PUSH_PROMISE and
@@ -1037,6 +1183,15 @@ mod tests {
         f.encode(&mut enc);
         let buf: Vec<_> = enc.into();
         test_complete_and_incomplete_frame(&buf, buf.len());
+
+        // H3_FRAME_TYPE_ORIGIN
+        let f = HFrame::Origin {
+            origins: vec![String::from("https://example.com")],
+        };
+        let mut enc = Encoder::default();
+        f.encode(&mut enc);
+        let buf: Vec<_> = enc.into();
+        test_complete_and_incomplete_frame(&buf, buf.len());
     }
 
     // Test closing a stream before any frame is sent should not cause an error.
@@ -6,10 +6,16 @@
 
 #![deny(warnings)]
 
+mod capsule;
+mod connect_udp;
 pub mod connection;
 pub mod hframe;
+mod origin;
+mod pool;
 mod transaction_client;
 pub mod transaction_server;
+mod tunnel;
+mod webtransport;
 
 use neqo_qpack;
 use neqo_transport;
@@ -17,12 +23,41 @@ pub use neqo_transport::Output;
 
 use self::hframe::HFrameType;
 
-pub use connection::{Http3Connection, Http3Event, Http3State};
+pub use capsule::CAPSULE_PROTOCOL_HEADER;
+pub use connect_udp::{connect_udp_path, connect_udp_target, CONNECT_UDP_PROTOCOL};
+pub use connection::{CloseReason, Http3Connection, Http3Event, Http3State, ZeroRttStatus};
+pub use neqo_qpack::encoder::QPackEncoderStats;
 pub use neqo_qpack::Header;
+pub use pool::{Connector, Http3ConnectionPool, PoolEvent, PoolRequestId};
 pub use transaction_server::TransactionServer;
+pub use webtransport::WEBTRANSPORT_PROTOCOL;
 
 type Res<T> = Result<T, Error>;
 
+/// The value of the first header called `name`.
+pub(crate) fn header_value<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Whether `headers` are for an interim response, which has a 1xx status.
+pub(crate) fn is_interim_response(headers: &[Header]) -> bool {
+    match header_value(headers, ":status") {
+        Some(s) => s.len() == 3 && s.starts_with('1'),
+        None => false,
+    }
+}
+
+/// Whether `headers` are for a response with a 2xx status.
+pub(crate) fn is_success_response(headers: &[Header]) -> bool {
+    match header_value(headers, ":status") {
+        Some(s) => s.len() == 3 && s.starts_with('2'),
+        None => false,
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     NoError,
@@ -55,6 +90,8 @@ pub enum Error {
     InvalidStreamId,
     Unavailable,
     AlreadyClosed,
+    InvalidHeader,
+    InvalidInput,
     // So we can wrap and report these errors.
     TransportError(neqo_transport::Error),
     QpackError(neqo_qpack::Error),
@@ -97,6 +134,8 @@ impl Error {
             | Error::InvalidStreamId
             | Error::Unavailable
             | Error::AlreadyClosed
+            | Error::InvalidHeader
+            | Error::InvalidInput
             | Error::TransportError(..) => 3,
             Error::QpackError(e) => e.code(),
         }
@@ -134,6 +173,22 @@ impl Error {
         }
     }
 
+    /// Whether this error means that an endpoint broke the rules of HTTP/3
+    /// or QPACK, rather than giving up on a request or connection.
+    pub fn is_protocol_error(&self) -> bool {
+        match self {
+            Error::NoError
+            | Error::PushRefused
+            | Error::RequestCancelled
+            | Error::ConnectError
+            | Error::ExcessiveLoad
+            | Error::VersionFallback
+            | Error::EarlyResponse
+            | Error::RequestRejected => false,
+            _ => true,
+        }
+    }
+
     pub fn is_stream_error(&self) -> bool {
         // TODO(mt): check that these are OK.  They all look fatal to me.
         *self == Error::UnexpectedFrame
@@ -0,0 +1,83 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Origins for connection coalescing, from RFC 8336.
+//
+// A server lists the origins that it is authoritative for in ORIGIN frames
+// on its control stream.  A client can send requests for any of those on the
+// same connection, as long as the server certificate covers them too.
+// Origins are compared in lowercase and without the default port for https.
+
+const HTTPS_SCHEME: &str = "https://";
+const DEFAULT_PORT: &str = ":443";
+
+/// The host in `authority`, without any port or the brackets around an IPv6
+/// address.
+pub(crate) fn authority_host(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(end) => &authority[1..end],
+            None => authority,
+        }
+    } else {
+        match authority.rfind(':') {
+            Some(colon) => &authority[..colon],
+            None => authority,
+        }
+    }
+}
+
+/// The https origin for `authority`.
+pub(crate) fn https_origin(authority: &str) -> String {
+    let authority = authority.to_ascii_lowercase();
+    let authority = if authority.ends_with(DEFAULT_PORT) {
+        &authority[..authority.len() - DEFAULT_PORT.len()]
+    } else {
+        &authority[..]
+    };
+    format!("{}{}", HTTPS_SCHEME, authority)
+}
+
+/// The origin from an ORIGIN frame in the form that `https_origin()` uses,
+/// or `None` if it isn't an https origin.
+pub(crate) fn normalize_origin(origin: &str) -> Option<String> {
+    match origin.get(..HTTPS_SCHEME.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(HTTPS_SCHEME) => {
+            let authority = &origin[HTTPS_SCHEME.len()..];
+            if authority.is_empty() {
+                None
+            } else {
+                Some(https_origin(authority))
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts() {
+        assert_eq!(authority_host("example.com"), "example.com");
+        assert_eq!(authority_host("example.com:8443"), "example.com");
+        assert_eq!(authority_host("[::1]:443"), "::1");
+        assert_eq!(authority_host("[::1]"), "::1");
+    }
+
+    #[test]
+    fn origins() {
+        assert_eq!(https_origin("Example.COM:443"), "https://example.com");
+        assert_eq!(https_origin("example.com:8443"), "https://example.com:8443");
+        assert_eq!(
+            normalize_origin("HTTPS://example.com:443"),
+            Some(String::from("https://example.com"))
+        );
+        assert_eq!(normalize_origin("http://example.com"), None);
+        assert_eq!(normalize_origin("https://"), None);
+    }
+}
@@ -0,0 +1,946 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A pool of client connections, keyed by origin, that does no I/O.
+//
+// Requests are made for an authority and the pool finds or makes a
+// connection for its origin.  New connections use alternative services from
+// Alt-Svc (RFC 7838) while they last, then the origin itself.  The addresses
+// for a connection are raced as RFC 8305 describes: a new attempt starts if
+// the last one hasn't connected after a short delay, and the first one to
+// connect is used.  Idempotent requests that a server rejects with GOAWAY are
+// sent again on a new connection, without the application seeing that.
+
+use crate::connection::{CloseReason, Http3Connection, Http3Event, Http3State};
+use crate::{header_value, origin, Error, Header, Res};
+use neqo_common::{qdebug, qinfo, Datagram};
+use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus};
+use neqo_transport::{AppError, Output};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a connection attempt before starting the next one.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long an alternative service lasts if Alt-Svc doesn't say.
+const ALT_SVC_DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_PORT: u16 = 443;
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+/// What an `Http3ConnectionPool` needs from the application to make
+/// connections.
+pub trait Connector {
+    /// The addresses for `host` and `port`, in the order to try them.
+    fn resolve(&mut self, host: &str, port: u16) -> Vec<SocketAddr>;
+    /// Make a client connection to `remote` for `server_name`.
+    fn connect(&mut self, server_name: &str, remote: SocketAddr) -> Result<Http3Connection, Error>;
+    /// Check the certificate of a server for `server_name`.
+    fn authenticate(
+        &mut self,
+        server_name: &str,
+        certificate: Option<CertificateInfo>,
+    ) -> AuthenticationStatus;
+}
+
+/// Identifies a request in an `Http3ConnectionPool`.  This stays the same if
+/// the request is sent again on another connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PoolRequestId(u64);
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PoolEvent {
+    /// Response headers are ready to read.
+    HeaderReady { request: PoolRequestId },
+    /// More of the response body can be read.
+    DataReadable { request: PoolRequestId },
+    /// All of the request body so far has been sent.
+    DataWritable { request: PoolRequestId },
+    /// Trailers arrived after the response body.
+    Trailers {
+        request: PoolRequestId,
+        trailers: Vec<Header>,
+    },
+    /// The server reset the request, or rejected a request that can't be
+    /// sent again.
+    Reset {
+        request: PoolRequestId,
+        error: AppError,
+        reason: CloseReason,
+    },
+    /// No connection could be made for the request, or the connection ended
+    /// before the response did.  `reason` is why the connection ended, if
+    /// one was made.
+    Failed {
+        request: PoolRequestId,
+        reason: Option<CloseReason>,
+    },
+}
+
+/// An alternative service for an origin.
+#[derive(Clone, Debug, PartialEq)]
+struct Alternative {
+    /// The host to connect to, or `None` for the host of the origin.
+    host: Option<String>,
+    port: u16,
+    expires: Instant,
+}
+
+/// What an Alt-Svc header field says.
+#[derive(Debug, PartialEq)]
+enum AltSvc {
+    /// Forget all alternatives.
+    Clear,
+    /// Use these alternatives, each with its protocol.
+    Alternatives(Vec<(String, Alternative)>),
+}
+
+/// Split `s` at each `sep` that isn't in a quoted string.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+/// Parse one alternative, like `h3-23=":443"; ma=3600`.
+fn parse_alternative(entry: &str, now: Instant) -> Option<(String, Alternative)> {
+    let mut params = split_unquoted(entry, ';').into_iter();
+    let alt = params.next()?.trim();
+    let eq = alt.find('=')?;
+    let protocol = alt[..eq].trim();
+    let authority = unquote(alt[eq + 1..].trim());
+    let colon = authority.rfind(':')?;
+    let port = authority[colon + 1..].parse::<u16>().ok()?;
+    let host = origin::authority_host(authority);
+    let mut max_age = ALT_SVC_DEFAULT_MAX_AGE;
+    for param in params {
+        let param = param.trim();
+        if let Some(eq) = param.find('=') {
+            if param[..eq].trim().eq_ignore_ascii_case("ma") {
+                max_age = Duration::from_secs(unquote(param[eq + 1..].trim()).parse().ok()?);
+            }
+        }
+    }
+    Some((
+        String::from(protocol),
+        Alternative {
+            host: if host.is_empty() {
+                None
+            } else {
+                Some(String::from(host))
+            },
+            port,
+            expires: now + max_age,
+        },
+    ))
+}
+
+/// Parse the value of an Alt-Svc header field.  Alternatives that can't be
+/// parsed are left out.
+fn parse_alt_svc(value: &str, now: Instant) -> AltSvc {
+    if value.trim() == "clear" {
+        return AltSvc::Clear;
+    }
+    AltSvc::Alternatives(
+        split_unquoted(value, ',')
+            .into_iter()
+            .filter_map(|entry| parse_alternative(entry, now))
+            .collect(),
+    )
+}
+
+/// The host and port in `authority`.
+fn split_authority(authority: &str) -> Res<(String, u16)> {
+    let host = origin::authority_host(authority);
+    if host.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+    let port = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => authority[colon + 1..]
+            .parse::<u16>()
+            .map_err(|_| Error::InvalidInput)?,
+        _ => DEFAULT_PORT,
+    };
+    Ok((String::from(host), port))
+}
+
+/// Order addresses so that address families alternate, starting with the
+/// family of the first one.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map_or(true, SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut ordered = Vec::new();
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop_front());
+        ordered.extend(second.pop_front());
+    }
+    ordered
+}
+
+#[derive(Debug)]
+struct PoolRequest {
+    origin: String,
+    authority: String,
+    method: String,
+    path: String,
+    headers: Vec<Header>,
+    /// The request body that hasn't been sent yet, or all of it for an
+    /// idempotent request so that it can be sent again.
+    body: Vec<u8>,
+    /// How much of `body` was sent on the current connection.
+    sent: usize,
+    /// Whether the application ended the request body.
+    closed: bool,
+    /// Whether the end of the request body was sent on the current connection.
+    close_sent: bool,
+    response_started: bool,
+    /// The connection and stream that the request is on, if it was sent.
+    stream: Option<(u64, u64)>,
+}
+
+impl PoolRequest {
+    fn is_idempotent(&self) -> bool {
+        IDEMPOTENT_METHODS.contains(&self.method.as_str())
+    }
+}
+
+struct PoolConnection {
+    conn: Http3Connection,
+    origin: String,
+    server_name: String,
+    remote: SocketAddr,
+    /// The local address, which is learned from the first datagram sent.
+    local: Option<SocketAddr>,
+    /// The requests on this connection, by stream ID.
+    requests: HashMap<u64, PoolRequestId>,
+}
+
+#[derive(Debug)]
+struct PoolOrigin {
+    host: String,
+    port: u16,
+    /// The connection that new requests use.
+    active: Option<u64>,
+    /// Connections that are being raced.
+    attempts: Vec<u64>,
+    /// Addresses that haven't been tried yet.
+    endpoints: VecDeque<SocketAddr>,
+    /// When to start the next attempt.
+    next_attempt: Option<Instant>,
+    /// Requests that are waiting for a connection.
+    pending: VecDeque<PoolRequestId>,
+    /// Why the last attempt failed.
+    last_error: Option<CloseReason>,
+}
+
+/// A pool of HTTP/3 client connections.  Like `Http3Connection`, this does
+/// no I/O.  Pass received datagrams to `process()` and send the ones that it
+/// returns; each comes from the local address of the connection that made
+/// it.  The `Connector` finds addresses, makes connections and checks
+/// certificates.
+pub struct Http3ConnectionPool {
+    connector: Box<dyn Connector>,
+    /// The protocols that alternative services can use, like `h3-23`.
+    protocols: Vec<String>,
+    connections: BTreeMap<u64, PoolConnection>,
+    origins: HashMap<String, PoolOrigin>,
+    alt_svc: HashMap<String, Vec<Alternative>>,
+    requests: HashMap<PoolRequestId, PoolRequest>,
+    events: VecDeque<PoolEvent>,
+    next_connection_id: u64,
+    next_request_id: u64,
+}
+
+impl ::std::fmt::Display for Http3ConnectionPool {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Http3 connection pool")
+    }
+}
+
+impl Http3ConnectionPool {
+    /// Make an empty pool.  Alternative services are used if they are for
+    /// one of `protocols`, which should be the ALPN that the `Connector`
+    /// uses.
+    pub fn new(connector: Box<dyn Connector>, protocols: &[impl AsRef<str>]) -> Self {
+        Self {
+            connector,
+            protocols: protocols.iter().map(|p| String::from(p.as_ref())).collect(),
+            connections: BTreeMap::new(),
+            origins: HashMap::new(),
+            alt_svc: HashMap::new(),
+            requests: HashMap::new(),
+            events: VecDeque::new(),
+            next_connection_id: 0,
+            next_request_id: 0,
+        }
+    }
+
+    /// The number of connections, including ones that are being made or are
+    /// closing.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Record an Alt-Svc header field for `authority`, which could have
+    /// arrived over any protocol.  This replaces any alternatives that were
+    /// known for the origin.  Connections that are already made stay.
+    pub fn alt_svc(&mut self, authority: &str, value: &str, now: Instant) {
+        let key = origin::https_origin(authority);
+        qdebug!([self] "Alt-Svc for {}: {}", key, value);
+        match parse_alt_svc(value, now) {
+            AltSvc::Clear => {
+                self.alt_svc.remove(&key);
+            }
+            AltSvc::Alternatives(alts) => {
+                let protocols = &self.protocols;
+                let alts = alts
+                    .into_iter()
+                    .filter(|(p, _)| protocols.contains(p))
+                    .map(|(_, a)| a)
+                    .collect::<Vec<_>>();
+                if !alts.is_empty() {
+                    self.alt_svc.insert(key, alts);
+                }
+            }
+        }
+    }
+
+    /// Start a request for `authority`, which is a host and optional port.
+    /// The scheme is always `https`.  The request goes out once there is a
+    /// connection for its origin.
+    pub fn fetch(
+        &mut self,
+        method: &str,
+        authority: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Res<PoolRequestId> {
+        let (host, port) = split_authority(authority)?;
+        let key = origin::https_origin(authority);
+        let id = PoolRequestId(self.next_request_id);
+        self.next_request_id += 1;
+        qdebug!([self] "Fetch {:?} {} {}{}", id, method, authority, path);
+        self.requests.insert(
+            id,
+            PoolRequest {
+                origin: key.clone(),
+                authority: String::from(authority),
+                method: String::from(method),
+                path: String::from(path),
+                headers: headers.to_vec(),
+                body: Vec::new(),
+                sent: 0,
+                closed: false,
+                close_sent: false,
+                response_started: false,
+                stream: None,
+            },
+        );
+        self.origins
+            .entry(key)
+            .or_insert_with(|| PoolOrigin {
+                host,
+                port,
+                active: None,
+                attempts: Vec::new(),
+                endpoints: VecDeque::new(),
+                next_attempt: None,
+                pending: VecDeque::new(),
+                last_error: None,
+            })
+            .pending
+            .push_back(id);
+        Ok(id)
+    }
+
+    /// Add to the request body.  The pool keeps the body until it is sent,
+    /// and keeps all of it for idempotent requests, so this takes all of
+    /// `buf`.  `PoolEvent::DataWritable` says when everything so far is sent.
+    pub fn send_request_body(&mut self, request: PoolRequestId, buf: &[u8]) -> Res<usize> {
+        let req = self
+            .requests
+            .get_mut(&request)
+            .ok_or(Error::InvalidStreamId)?;
+        if req.closed {
+            return Err(Error::AlreadyClosed);
+        }
+        req.body.extend_from_slice(buf);
+        self.send_body(request)?;
+        Ok(buf.len())
+    }
+
+    /// End the request body.
+    pub fn stream_close_send(&mut self, request: PoolRequestId) -> Res<()> {
+        self.requests
+            .get_mut(&request)
+            .ok_or(Error::InvalidStreamId)?
+            .closed = true;
+        self.send_body(request)
+    }
+
+    /// Cancel a request.
+    pub fn stream_reset(&mut self, request: PoolRequestId, error: AppError) -> Res<()> {
+        let req = self
+            .requests
+            .remove(&request)
+            .ok_or(Error::InvalidStreamId)?;
+        self.events.retain(|e| match e {
+            PoolEvent::HeaderReady { request: r }
+            | PoolEvent::DataReadable { request: r }
+            | PoolEvent::DataWritable { request: r }
+            | PoolEvent::Trailers { request: r, .. }
+            | PoolEvent::Reset { request: r, .. }
+            | PoolEvent::Failed { request: r, .. } => *r != request,
+        });
+        match req.stream {
+            Some((c, stream_id)) => {
+                if let Some(pc) = self.connections.get_mut(&c) {
+                    pc.requests.remove(&stream_id);
+                    pc.conn.stream_reset(stream_id, error)?;
+                }
+            }
+            None => {
+                if let Some(o) = self.origins.get_mut(&req.origin) {
+                    o.pending.retain(|r| *r != request);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the response headers.  An Alt-Svc header field in the response
+    /// is used for later connections to the origin.
+    pub fn read_response_headers(
+        &mut self,
+        now: Instant,
+        request: PoolRequestId,
+    ) -> Res<(Vec<Header>, bool)> {
+        let (c, stream_id) = self.request_stream(request)?;
+        let (headers, fin) = self
+            .connections
+            .get_mut(&c)
+            .ok_or(Error::InvalidStreamId)?
+            .conn
+            .read_response_headers(stream_id)?;
+        if let Some(value) = header_value(&headers, "alt-svc") {
+            let authority = self.requests[&request].authority.clone();
+            self.alt_svc(&authority, value, now);
+        }
+        if fin {
+            self.request_done(request);
+        }
+        Ok((headers, fin))
+    }
+
+    /// Read the response body.
+    pub fn read_response_data(
+        &mut self,
+        now: Instant,
+        request: PoolRequestId,
+        buf: &mut [u8],
+    ) -> Res<(usize, bool)> {
+        let (c, stream_id) = self.request_stream(request)?;
+        let (amount, fin) = self
+            .connections
+            .get_mut(&c)
+            .ok_or(Error::InvalidStreamId)?
+            .conn
+            .read_response_data(now, stream_id, buf)?;
+        if fin {
+            self.request_done(request);
+        }
+        Ok((amount, fin))
+    }
+
+    pub fn events(&mut self) -> impl Iterator<Item = PoolEvent> {
+        self.events.drain(..).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Deliver an optional datagram to the connection that it is for, then
+    /// get the next datagram to send from any connection.  If there is
+    /// nothing to send, this returns how long to wait before calling this
+    /// again, or `Output::None` if nothing is waiting for a timer.
+    pub fn process(&mut self, input: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = input {
+            let dest = self.connections.values_mut().find(|pc| {
+                pc.remote == d.source() && pc.local.map_or(true, |l| l == d.destination())
+            });
+            match dest {
+                Some(pc) => pc.conn.process_input(d, now),
+                None => qdebug!([self] "No connection for datagram from {}", d.source()),
+            }
+        }
+
+        let mut delay = None;
+        for pc in self.connections.values_mut() {
+            pc.conn.process_http3(now);
+        }
+        self.update(now);
+        for pc in self.connections.values_mut() {
+            pc.conn.process_http3(now);
+            match pc.conn.process_output(now) {
+                Output::Datagram(d) => {
+                    pc.local = Some(d.source());
+                    return Output::Datagram(d);
+                }
+                Output::Callback(t) => delay = Some(delay.map_or(t, |d| min(d, t))),
+                Output::None => {}
+            }
+        }
+        for o in self.origins.values() {
+            if let Some(t) = o.next_attempt {
+                let t = if t > now {
+                    t - now
+                } else {
+                    Duration::new(0, 0)
+                };
+                delay = Some(delay.map_or(t, |d| min(d, t)));
+            }
+        }
+        match delay {
+            Some(d) => Output::Callback(d),
+            None => Output::None,
+        }
+    }
+
+    fn request_stream(&self, request: PoolRequestId) -> Res<(u64, u64)> {
+        self.requests
+            .get(&request)
+            .ok_or(Error::InvalidStreamId)?
+            .stream
+            .ok_or(Error::Unavailable)
+    }
+
+    fn request_done(&mut self, request: PoolRequestId) {
+        if let Some(req) = self.requests.remove(&request) {
+            if let Some((c, stream_id)) = req.stream {
+                if let Some(pc) = self.connections.get_mut(&c) {
+                    pc.requests.remove(&stream_id);
+                }
+            }
+        }
+    }
+
+    /// Send as much of the request body as the connection takes, then the
+    /// end of the body if the application ended it.
+    fn send_body(&mut self, request: PoolRequestId) -> Res<()> {
+        let req = match self.requests.get_mut(&request) {
+            Some(req) => req,
+            None => return Ok(()),
+        };
+        let (c, stream_id) = match req.stream {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let conn = match self.connections.get_mut(&c) {
+            Some(pc) => &mut pc.conn,
+            None => return Ok(()),
+        };
+        while req.sent < req.body.len() {
+            let sent = conn.send_request_body(stream_id, &req.body[req.sent..])?;
+            if sent == 0 {
+                return Ok(());
+            }
+            req.sent += sent;
+        }
+        if !req.is_idempotent() {
+            req.body.clear();
+            req.sent = 0;
+        }
+        if req.closed && !req.close_sent {
+            conn.stream_close_send(stream_id)?;
+            req.close_sent = true;
+        }
+        Ok(())
+    }
+
+    /// Handle events from connections, then move requests and connection
+    /// attempts along.
+    fn update(&mut self, now: Instant) {
+        let ids = self.connections.keys().cloned().collect::<Vec<_>>();
+        for c in ids {
+            self.check_events(c, now);
+            self.check_state(c, now);
+        }
+        let keys = self.origins.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.connect(&key, now);
+            self.send_pending(&key);
+        }
+    }
+
+    fn check_events(&mut self, c: u64, now: Instant) {
+        let events = match self.connections.get_mut(&c) {
+            Some(pc) => pc.conn.events().collect::<Vec<_>>(),
+            None => return,
+        };
+        for e in events {
+            let pc = &self.connections[&c];
+            let request_for = |stream_id: u64| pc.requests.get(&stream_id).cloned();
+            match e {
+                Http3Event::HeaderReady { stream_id, .. } => {
+                    if let Some(request) = request_for(stream_id) {
+                        if let Some(req) = self.requests.get_mut(&request) {
+                            req.response_started = true;
+                        }
+                        self.events.push_back(PoolEvent::HeaderReady { request });
+                    }
+                }
+                Http3Event::DataReadable { stream_id } => {
+                    if let Some(request) = request_for(stream_id) {
+                        self.events.push_back(PoolEvent::DataReadable { request });
+                    }
+                }
+                Http3Event::DataWritable { stream_id } => {
+                    if let Some(request) = request_for(stream_id) {
+                        if self.send_body(request).is_ok() {
+                            let req = &self.requests[&request];
+                            if req.sent == req.body.len() && !req.closed {
+                                self.events.push_back(PoolEvent::DataWritable { request });
+                            }
+                        }
+                    }
+                }
+                Http3Event::Trailers {
+                    stream_id,
+                    trailers,
+                } => {
+                    if let Some(request) = request_for(stream_id) {
+                        self.events
+                            .push_back(PoolEvent::Trailers { request, trailers });
+                    }
+                }
+                Http3Event::Reset {
+                    stream_id,
+                    error,
+                    reason,
+                } => {
+                    if let Some(request) = request_for(stream_id) {
+                        self.request_reset(c, stream_id, request, error, reason);
+                    }
+                }
+                Http3Event::AuthenticationNeeded => {
+                    let pc = self.connections.get_mut(&c).unwrap();
+                    let status = self
+                        .connector
+                        .authenticate(&pc.server_name, pc.conn.peer_certificate());
+                    pc.conn.authenticated(status, now);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a request that the server rejected again if that is safe.
+    fn request_reset(
+        &mut self,
+        c: u64,
+        stream_id: u64,
+        request: PoolRequestId,
+        error: AppError,
+        reason: CloseReason,
+    ) {
+        if let Some(pc) = self.connections.get_mut(&c) {
+            pc.requests.remove(&stream_id);
+        }
+        let req = match self.requests.get_mut(&request) {
+            Some(req) => req,
+            None => return,
+        };
+        let rejected = reason == CloseReason::Goaway
+            || reason == CloseReason::ZeroRttRejected
+            || error == Error::RequestRejected.code();
+        if rejected && req.is_idempotent() && !req.response_started {
+            qinfo!([self] "Retry {:?} on a new connection", request);
+            req.stream = None;
+            req.sent = 0;
+            req.close_sent = false;
+            if let Some(o) = self.origins.get_mut(&req.origin) {
+                o.pending.push_back(request);
+            }
+        } else {
+            self.requests.remove(&request);
+            self.events.push_back(PoolEvent::Reset {
+                request,
+                error,
+                reason,
+            });
+        }
+    }
+
+    /// Use a connection that connected, forget one that can't be used for
+    /// new requests, and close one that has nothing left to do.
+    fn check_state(&mut self, c: u64, now: Instant) {
+        let pc = &self.connections[&c];
+        let state = pc.conn.state();
+        let o = self.origins.get_mut(&pc.origin).unwrap();
+        match state {
+            Http3State::Connected => {
+                if o.attempts.contains(&c) {
+                    qinfo!("Connected to {} for {}", pc.remote, pc.origin);
+                    o.active = Some(c);
+                    o.endpoints.clear();
+                    o.next_attempt = None;
+                    let losers = o.attempts.drain(..).filter(|a| *a != c).collect::<Vec<_>>();
+                    for a in losers {
+                        if let Some(l) = self.connections.get_mut(&a) {
+                            l.conn.close(now, Error::NoError.code(), "");
+                        }
+                    }
+                }
+            }
+            Http3State::GoingAway => {
+                if o.active == Some(c) {
+                    o.active = None;
+                }
+                if pc.requests.is_empty() {
+                    self.connections.get_mut(&c).unwrap().conn.close(
+                        now,
+                        Error::NoError.code(),
+                        "",
+                    );
+                }
+            }
+            Http3State::Closing(..) | Http3State::Closed(..) => {
+                let reason = pc.conn.close_reason();
+                if o.active == Some(c) {
+                    o.active = None;
+                }
+                if let Some(i) = o.attempts.iter().position(|a| *a == c) {
+                    // Try the next address straight away.
+                    o.attempts.remove(i);
+                    o.last_error = reason;
+                    o.next_attempt = Some(now);
+                }
+                let requests = self
+                    .connections
+                    .get_mut(&c)
+                    .unwrap()
+                    .requests
+                    .drain()
+                    .map(|(_, r)| r)
+                    .collect::<Vec<_>>();
+                for request in requests {
+                    if self.requests.remove(&request).is_some() {
+                        self.events.push_back(PoolEvent::Failed { request, reason });
+                    }
+                }
+                if let Http3State::Closed(..) = state {
+                    self.connections.remove(&c);
+                }
+            }
+            Http3State::Initializing => {}
+        }
+    }
+
+    /// Find the addresses for a new connection to an origin: those for
+    /// alternative services that haven't expired, then those of the origin.
+    fn endpoints(&mut self, key: &str, now: Instant) -> VecDeque<SocketAddr> {
+        let o = &self.origins[key];
+        let (host, port) = (o.host.clone(), o.port);
+        let mut endpoints = Vec::new();
+        if let Some(alts) = self.alt_svc.get_mut(key) {
+            alts.retain(|a| a.expires > now);
+            for a in alts.iter() {
+                let alt_host = a.host.as_ref().map_or(host.as_str(), String::as_str);
+                endpoints.extend(interleave(self.connector.resolve(alt_host, a.port)));
+            }
+        }
+        endpoints.extend(interleave(self.connector.resolve(&host, port)));
+        let mut unique = VecDeque::new();
+        for e in endpoints {
+            if !unique.contains(&e) {
+                unique.push_back(e);
+            }
+        }
+        unique
+    }
+
+    /// Start connection attempts for an origin that has requests waiting,
+    /// and give up on those requests if every address failed.
+    fn connect(&mut self, key: &str, now: Instant) {
+        let o = self.origins.get_mut(key).unwrap();
+        if o.pending.is_empty() || o.active.is_some() {
+            o.next_attempt = None;
+            return;
+        }
+        if o.attempts.is_empty() && o.next_attempt.is_none() {
+            let endpoints = self.endpoints(key, now);
+            let o = self.origins.get_mut(key).unwrap();
+            o.endpoints = endpoints;
+            o.next_attempt = Some(now);
+            o.last_error = None;
+        }
+
+        loop {
+            let o = self.origins.get_mut(key).unwrap();
+            match o.next_attempt {
+                Some(t) if t <= now => {}
+                _ => return,
+            }
+            let remote = match o.endpoints.pop_front() {
+                Some(remote) => remote,
+                None => {
+                    if o.attempts.is_empty() {
+                        // Nothing worked.
+                        o.next_attempt = None;
+                        let reason = o.last_error;
+                        let failed = o.pending.drain(..).collect::<Vec<_>>();
+                        for request in failed {
+                            self.requests.remove(&request);
+                            self.events.push_back(PoolEvent::Failed { request, reason });
+                        }
+                    } else {
+                        o.next_attempt = None;
+                    }
+                    return;
+                }
+            };
+            let server_name = o.host.clone();
+            qinfo!([self] "Connect to {} for {}", remote, key);
+            match self.connector.connect(&server_name, remote) {
+                Ok(conn) => {
+                    let c = self.next_connection_id;
+                    self.next_connection_id += 1;
+                    self.connections.insert(
+                        c,
+                        PoolConnection {
+                            conn,
+                            origin: String::from(key),
+                            server_name,
+                            remote,
+                            local: None,
+                            requests: HashMap::new(),
+                        },
+                    );
+                    let o = self.origins.get_mut(key).unwrap();
+                    o.attempts.push(c);
+                    o.next_attempt = Some(now + CONNECTION_ATTEMPT_DELAY);
+                }
+                Err(e) => {
+                    qdebug!([self] "Connecting to {} failed: {:?}", remote, e);
+                }
+            }
+        }
+    }
+
+    /// Send the requests that are waiting for a connection.
+    fn send_pending(&mut self, key: &str) {
+        let c = match self.origins[key].active {
+            Some(c) => c,
+            None => return,
+        };
+        let o = self.origins.get_mut(key).unwrap();
+        let pc = self.connections.get_mut(&c).unwrap();
+        let mut sent = Vec::new();
+        while let Some(request) = o.pending.pop_front() {
+            let req = match self.requests.get_mut(&request) {
+                Some(req) => req,
+                None => continue,
+            };
+            match pc.conn.fetch(
+                &req.method,
+                "https",
+                &req.authority,
+                &req.path,
+                &req.headers,
+            ) {
+                Ok(stream_id) => {
+                    pc.requests.insert(stream_id, request);
+                    req.stream = Some((c, stream_id));
+                    sent.push(request);
+                }
+                Err(e) => {
+                    // Probably out of streams; try again later.
+                    qdebug!("Can't send {:?} yet: {:?}", request, e);
+                    o.pending.push_front(request);
+                    break;
+                }
+            }
+        }
+        for request in sent {
+            if let Err(e) = self.send_body(request) {
+                qdebug!([self] "Sending the body of {:?} failed: {:?}", request, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use test_fixture::now;
+
+    #[test]
+    fn alt_svc() {
+        let alts = match parse_alt_svc(
+            "h3-23=\":8443\"; ma=60, h3-23=\"alt.example:443\"; persist=1, bad, h2=\"x\"",
+            now(),
+        ) {
+            AltSvc::Alternatives(alts) => alts,
+            AltSvc::Clear => panic!("not clear"),
+        };
+        assert_eq!(alts.len(), 2);
+        assert_eq!(alts[0].0, "h3-23");
+        assert_eq!(alts[0].1.host, None);
+        assert_eq!(alts[0].1.port, 8443);
+        assert_eq!(alts[0].1.expires, now() + Duration::from_secs(60));
+        assert_eq!(alts[1].1.host, Some(String::from("alt.example")));
+        assert_eq!(alts[1].1.expires, now() + ALT_SVC_DEFAULT_MAX_AGE);
+        assert_eq!(parse_alt_svc(" clear", now()), AltSvc::Clear);
+    }
+
+    #[test]
+    fn authorities() {
+        assert_eq!(
+            split_authority("example.com"),
+            Ok((String::from("example.com"), 443))
+        );
+        assert_eq!(
+            split_authority("[::1]:8443"),
+            Ok((String::from("::1"), 8443))
+        );
+        assert_eq!(split_authority("[::1]"), Ok((String::from("::1"), 443)));
+        assert_eq!(split_authority("example.com:x"), Err(Error::InvalidInput));
+        assert_eq!(split_authority(":443"), Err(Error::InvalidInput));
+    }
+
+    #[test]
+    fn address_families_alternate() {
+        let v4 = |n| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, n)), 443);
+        let v6 = |n| {
+            SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n)),
+                443,
+            )
+        };
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v6(3), v4(1), v4(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2), v6(3)]
+        );
+        assert_eq!(interleave(vec![v4(1), v6(1)]), vec![v4(1), v6(1)]);
+    }
+}
@@ -6,9 +6,9 @@
 
 use crate::hframe::{HFrame, HFrameReader, H3_FRAME_TYPE_DATA, H3_FRAME_TYPE_HEADERS};
 
-use crate::connection::Http3Events;
-use crate::Header;
-use neqo_common::{qdebug, qinfo, qtrace, Encoder};
+use crate::connection::{Http3Events, ZeroRttStatus};
+use crate::{header_value, is_interim_response, Header};
+use neqo_common::{qdebug, qinfo, qtrace, Encoder, Redact};
 use neqo_qpack::decoder::QPackDecoder;
 use neqo_qpack::encoder::QPackEncoder;
 use neqo_transport::Connection;
@@ -44,10 +44,16 @@ impl Request {
             headers: Vec::new(),
             buf: None,
         };
+        // A CONNECT request for a tunnel has no scheme or path.
+        let tunnel = method == "CONNECT" && scheme.is_empty() && path.is_empty();
         r.headers.push((":method".into(), method.to_owned()));
-        r.headers.push((":scheme".into(), r.scheme.clone()));
+        if !tunnel {
+            r.headers.push((":scheme".into(), r.scheme.clone()));
+        }
         r.headers.push((":authority".into(), r.host.clone()));
-        r.headers.push((":path".into(), r.path.clone()));
+        if !tunnel {
+            r.headers.push((":path".into(), r.path.clone()));
+        }
         r.headers.extend_from_slice(headers);
         r
     }
@@ -57,7 +63,7 @@ impl Request {
             return;
         }
 
-        qdebug!([self] "Encoding headers for {}/{}", self.host, self.path);
+        qdebug!([self] "Encoding headers for {}/{}", Redact(&self.host), Redact(&self.path));
         let encoded_headers = encoder.encode_header_block(&self.headers, stream_id);
         let f = HFrame::Headers {
             len: encoded_headers.len() as u64,
@@ -74,7 +80,7 @@ impl Request {
         encoder: &mut QPackEncoder,
         stream_id: u64,
     ) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -100,7 +106,13 @@ impl Request {
 
 impl ::std::fmt::Display for Request {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "Request {} {}/{}", self.method, self.host, self.path)
+        write!(
+            f,
+            "Request {} {}/{}",
+            self.method,
+            Redact(&self.host),
+            Redact(&self.path)
+        )
     }
 }
 
@@ -109,7 +121,9 @@ impl ::std::fmt::Display for Request {
  *    SendingHeaders : sending headers. From here we may switch to SendingData
  *                     or Closed (if the app does not want to send data and
  *                     has alreadyclosed the send stream).
- *    SendingData : We are sending request data until the app closes the stream.
+ *    SendingData : We are sending request data until the app closes the stream
+ *                  or sends trailers.
+ *    SendingTrailers : sending the trailers, after which the stream is closed.
  *    Closed
  */
 
@@ -117,6 +131,7 @@ impl ::std::fmt::Display for Request {
 enum TransactionSendState {
     SendingHeaders { request: Request, fin: bool },
     SendingData,
+    SendingTrailers { buf: Vec<u8> },
     Closed,
 }
 
@@ -126,7 +141,8 @@ enum TransactionSendState {
  *                                also get a PUSH_PROMISE frame.
  *    ReadingHeaders : we have HEADERS frame and now we are reading header
  *                     block. This may block on encoder instructions. In this
- *                     state we do no read from the stream.
+ *                     state we do no read from the stream. This is used for
+ *                     trailers as well.
  *    BlockedDecodingHeaders : Decoding headers is blocked on encoder
  *                             instructions.
  *    WaitingForData : we got HEADERS, we are waiting for one or more data
@@ -135,7 +151,7 @@ enum TransactionSendState {
  *    ReadingData : we got a DATA frame, now we letting the app read payload.
  *                  From here we will go back to WaitingForData state to wait
  *                  for more data frames or to CLosed state
- *    ReadingTrailers : reading trailers.
+ *    WaitingForFin : we got trailers, nothing else may arrive on the stream.
  *    ClosePending : waiting for app to pick up data, after that we can delete
  * the TransactionClient.
  *    Closed
@@ -147,7 +163,7 @@ enum TransactionRecvState {
     BlockedDecodingHeaders { buf: Vec<u8>, fin: bool },
     WaitingForData,
     ReadingData { remaining_data_len: usize },
-    //    ReadingTrailers,
+    WaitingForFin,
     ClosePending, // Close must first be read by application
     Closed,
 }
@@ -167,6 +183,7 @@ pub struct TransactionClient {
     frame_reader: HFrameReader,
     response_headers_state: ResponseHeadersState,
     conn_events: Http3Events,
+    zero_rtt: ZeroRttStatus,
 }
 
 impl TransactionClient {
@@ -190,6 +207,7 @@ impl TransactionClient {
             response_headers_state: ResponseHeadersState::NoHeaders,
             frame_reader: HFrameReader::new(),
             conn_events,
+            zero_rtt: ZeroRttStatus::NotSent,
         }
     }
 
@@ -198,7 +216,7 @@ impl TransactionClient {
         conn: &mut Connection,
         encoder: &mut QPackEncoder,
     ) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -208,6 +226,9 @@ impl TransactionClient {
             fin,
         } = self.send_state
         {
+            if conn.zero_rtt_enabled() {
+                self.zero_rtt = ZeroRttStatus::Sent;
+            }
             if request.send(conn, encoder, self.stream_id)? {
                 if fin {
                     conn.stream_close_send(self.stream_id)?;
@@ -264,12 +285,63 @@ impl TransactionClient {
                     Err(e) => Err(Error::TransportError(e)),
                 }
             }
-            TransactionSendState::Closed => Err(Error::AlreadyClosed),
+            TransactionSendState::SendingTrailers { .. } | TransactionSendState::Closed => {
+                Err(Error::AlreadyClosed)
+            }
+        }
+    }
+
+    /// Send trailers after the request body.  This closes the send side of
+    /// the stream, once the trailers are sent.
+    pub fn send_request_trailers(
+        &mut self,
+        conn: &mut Connection,
+        encoder: &mut QPackEncoder,
+        trailers: &[Header],
+    ) -> Res<()> {
+        qdebug!([self] "send_request_trailers: send_state={:?}", self.send_state);
+        match self.send_state {
+            TransactionSendState::SendingHeaders { .. } => Err(Error::Unavailable),
+            TransactionSendState::SendingData => {
+                let encoded_trailers = encoder.encode_header_block(trailers, self.stream_id);
+                let f = HFrame::Headers {
+                    len: encoded_trailers.len() as u64,
+                };
+                let mut d = Encoder::default();
+                f.encode(&mut d);
+                d.encode(&encoded_trailers[..]);
+                self.send_state = TransactionSendState::SendingTrailers { buf: d.into() };
+                self.send_pending_trailers(conn)
+            }
+            TransactionSendState::SendingTrailers { .. } | TransactionSendState::Closed => {
+                Err(Error::AlreadyClosed)
+            }
         }
     }
 
+    /// Send whatever is left of the trailers, closing the stream when done.
+    pub fn send_pending_trailers(&mut self, conn: &mut Connection) -> Res<()> {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
+            format!("{}", self)
+        } else {
+            String::new()
+        };
+        if let TransactionSendState::SendingTrailers { ref mut buf } = self.send_state {
+            let sent = conn.stream_send(self.stream_id, &buf)?;
+            qdebug!([label] "{} bytes of trailers sent", sent);
+            if sent == buf.len() {
+                conn.stream_close_send(self.stream_id)?;
+                self.send_state = TransactionSendState::Closed;
+            } else {
+                let b = buf.split_off(sent);
+                *buf = b;
+            }
+        }
+        Ok(())
+    }
+
     pub fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -297,7 +369,7 @@ impl TransactionClient {
                 TransactionRecvState::BlockedDecodingHeaders { ref buf, fin } => {
                     match decoder.decode_header_block(buf, self.stream_id)? {
                         Some(headers) => {
-                            self.add_headers(Some(headers))?;
+                            self.headers_decoded(headers)?;
                             if fin {
                                 self.set_state_to_close_pending();
                                 break Ok(());
@@ -325,7 +397,13 @@ impl TransactionClient {
                     self.conn_events.data_readable(self.stream_id);
                     break Ok(());
                 }
-                // TransactionRecvState::ReadingTrailers => break Ok(()),
+                TransactionRecvState::WaitingForFin => {
+                    if self.recv_frame_header(conn)?.is_some() {
+                        // Nothing may follow trailers.
+                        break Err(Error::UnexpectedFrame);
+                    }
+                    break Ok(());
+                }
                 TransactionRecvState::ClosePending => {
                     panic!("Stream readable after being closed!");
                 }
@@ -364,14 +442,22 @@ impl TransactionClient {
         match frame {
             HFrame::Data { len } => self.handle_data_frame(len, fin),
             HFrame::PushPromise { .. } => Err(Error::UnexpectedFrame),
-            HFrame::Headers { .. } => {
-                // TODO implement trailers!
-                Err(Error::UnexpectedFrame)
-            }
+            HFrame::Headers { len } => self.handle_trailers_frame(len, fin),
             _ => Err(Error::WrongStream),
         }
     }
 
+    fn handle_trailers_frame(&mut self, len: u64, fin: bool) -> Res<()> {
+        if len == 0 || fin {
+            return Err(Error::MalformedFrame(H3_FRAME_TYPE_HEADERS));
+        }
+        self.recv_state = TransactionRecvState::ReadingHeaders {
+            buf: vec![0; len as usize],
+            offset: 0,
+        };
+        Ok(())
+    }
+
     fn handle_data_frame(&mut self, len: u64, fin: bool) -> Res<()> {
         if len > 0 {
             if fin {
@@ -389,18 +475,51 @@ impl TransactionClient {
             return Err(Error::InternalError);
         }
         self.response_headers_state = ResponseHeadersState::Ready(headers);
-        self.conn_events.header_ready(self.stream_id);
+        self.conn_events.header_ready(self.stream_id, self.zero_rtt);
         self.recv_state = TransactionRecvState::WaitingForData;
         Ok(())
     }
 
+    fn add_trailers(&mut self, trailers: Vec<Header>) {
+        qdebug!([self] "received trailers");
+        self.conn_events.trailers(self.stream_id, trailers);
+        self.recv_state = TransactionRecvState::WaitingForFin;
+    }
+
+    /// An interim response can be followed by more of them, and then by the
+    /// final response.
+    fn add_interim_response(&mut self, headers: Vec<Header>) -> Res<()> {
+        // There is no protocol to switch to in HTTP/3.
+        if header_value(&headers, ":status") == Some("101") {
+            return Err(Error::MalformedFrame(H3_FRAME_TYPE_HEADERS));
+        }
+        qdebug!([self] "received an interim response");
+        self.conn_events.interim_response(self.stream_id, headers);
+        self.recv_state = TransactionRecvState::WaitingForResponseHeaders;
+        Ok(())
+    }
+
+    /// A header block is either an interim response, the response headers
+    /// or, if those have arrived already, the trailers.
+    fn headers_decoded(&mut self, headers: Vec<Header>) -> Res<()> {
+        if self.response_headers_state == ResponseHeadersState::NoHeaders {
+            if is_interim_response(&headers) {
+                return self.add_interim_response(headers);
+            }
+            self.add_headers(Some(headers))
+        } else {
+            self.add_trailers(headers);
+            Ok(())
+        }
+    }
+
     fn set_state_to_close_pending(&mut self) {
         // Stream has received fin. Depending on headers state set header_ready
         // or data_readable event so that app can pick up the fin.
-        qdebug!([self] "set_state_to_close_pending:  response_headers_state={:?}", self.response_headers_state);
+        qdebug!([self] "set_state_to_close_pending:  response_headers_state={:?}", Redact(&self.response_headers_state));
         match self.response_headers_state {
             ResponseHeadersState::NoHeaders => {
-                self.conn_events.header_ready(self.stream_id);
+                self.conn_events.header_ready(self.stream_id, self.zero_rtt);
                 self.response_headers_state = ResponseHeadersState::Ready(None);
             }
             // In Ready state we are already waiting for app to pick up headers
@@ -434,7 +553,7 @@ impl TransactionClient {
         conn: &mut Connection,
         decoder: &mut QPackDecoder,
     ) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -459,7 +578,7 @@ impl TransactionClient {
             qdebug!([label] "read_headers: read all headers, try decoding them.");
             match decoder.decode_header_block(buf, self.stream_id)? {
                 Some(headers) => {
-                    self.add_headers(Some(headers))?;
+                    self.headers_decoded(headers)?;
                     if fin {
                         self.set_state_to_close_pending();
                     }
@@ -483,6 +602,8 @@ impl TransactionClient {
             TransactionSendState::SendingHeaders { ref mut fin, .. } => {
                 *fin = true;
             }
+            // The stream is closed once the trailers are sent.
+            TransactionSendState::SendingTrailers { .. } => {}
             _ => {
                 self.send_state = TransactionSendState::Closed;
                 conn.stream_close_send(self.stream_id)?;
@@ -495,6 +616,20 @@ impl TransactionClient {
         self.send_state = TransactionSendState::Closed;
     }
 
+    /// The server accepted 0-RTT, so anything sent in 0-RTT was processed.
+    pub fn zero_rtt_accepted(&mut self) {
+        if self.zero_rtt == ZeroRttStatus::Sent {
+            self.zero_rtt = ZeroRttStatus::Accepted;
+        }
+    }
+
+    /// The server rejected 0-RTT, so anything sent in 0-RTT is being sent again.
+    pub fn zero_rtt_resent(&mut self) {
+        if self.zero_rtt == ZeroRttStatus::Sent {
+            self.zero_rtt = ZeroRttStatus::Resent;
+        }
+    }
+
     pub fn done(&self) -> bool {
         self.send_state == TransactionSendState::Closed
             && self.recv_state == TransactionRecvState::Closed
@@ -512,6 +647,14 @@ impl TransactionClient {
         }
     }
 
+    pub fn is_state_sending_trailers(&self) -> bool {
+        if let TransactionSendState::SendingTrailers { .. } = self.send_state {
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn is_sending_closed(&self) -> bool {
         match self.send_state {
             TransactionSendState::SendingHeaders { fin, .. } => fin,
@@ -4,8 +4,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::hframe::{HFrame, HFrameReader};
-use crate::Header;
+use crate::hframe::{send_data_frame, HFrame, HFrameReader, HFrameType};
+use crate::{header_value, is_interim_response, Header};
 use crate::{Error, Res};
 use neqo_common::{qdebug, Encoder};
 use neqo_qpack::decoder::QPackDecoder;
@@ -13,7 +13,9 @@ use neqo_qpack::encoder::QPackEncoder;
 use neqo_transport::Connection;
 use std::mem;
 
-pub type Response = (Vec<Header>, Vec<u8>, Option<Error>);
+/// The response headers, body, and trailers, which are only sent if there
+/// are any, and an error to end the request with.
+pub type Response = (Vec<Header>, Vec<u8>, Vec<Header>, Option<Error>);
 pub type RequestHandler = Box<dyn FnMut(&[Header], bool) -> Response>;
 
 #[derive(PartialEq, Debug)]
@@ -22,6 +24,8 @@ enum TransactionState {
     ReadingRequestHeaders { buf: Vec<u8>, offset: usize },
     BlockedDecodingHeaders { buf: Vec<u8> },
     ReadingRequestDone,
+    WaitingForResponse,
+    StreamingResponse,
     SendingResponse,
     Error,
     Closed,
@@ -34,6 +38,9 @@ pub struct TransactionServer {
     request_headers: Option<Vec<Header>>,
     response_buf: Option<Vec<u8>>,
     fin: bool,
+    /// Whether the stream stays open after the response, as it does for a
+    /// WebTransport session.
+    keep_open: bool,
 }
 
 impl TransactionServer {
@@ -45,9 +52,24 @@ impl TransactionServer {
             request_headers: None,
             response_buf: None,
             fin: false,
+            keep_open: false,
         }
     }
 
+    /// A transaction for a stream where the type of the first frame was
+    /// read already.
+    pub fn with_frame_type(stream_id: u64, hframe_type: HFrameType) -> TransactionServer {
+        TransactionServer {
+            frame_reader: HFrameReader::with_frame_type(hframe_type),
+            ..TransactionServer::new(stream_id)
+        }
+    }
+
+    /// Don't close the stream after the response is sent.
+    pub fn keep_open(&mut self) {
+        self.keep_open = true;
+    }
+
     pub fn get_request_headers(&self) -> &[Header] {
         if let Some(h) = &self.request_headers {
             h
@@ -56,15 +78,16 @@ impl TransactionServer {
         }
     }
 
-    pub fn set_response(&mut self, headers: &[Header], data: Vec<u8>, encoder: &mut QPackEncoder) {
+    pub fn set_response(
+        &mut self,
+        headers: &[Header],
+        data: Vec<u8>,
+        trailers: &[Header],
+        encoder: &mut QPackEncoder,
+    ) {
         qdebug!([self] "Encoding headers");
-        let encoded_headers = encoder.encode_header_block(&headers, self.stream_id);
-        let hframe = HFrame::Headers {
-            len: encoded_headers.len() as u64,
-        };
         let mut d = Encoder::default();
-        hframe.encode(&mut d);
-        d.encode(&encoded_headers);
+        self.encode_header_block(&mut d, headers, encoder);
         if !data.is_empty() {
             qdebug!([self] "Encoding data");
             let d_frame = HFrame::Data {
@@ -73,24 +96,147 @@ impl TransactionServer {
             d_frame.encode(&mut d);
             d.encode(&data);
         }
+        if !trailers.is_empty() {
+            qdebug!([self] "Encoding trailers");
+            self.encode_header_block(&mut d, trailers, encoder);
+        }
         self.response_buf = Some(d.into());
 
         self.state = TransactionState::SendingResponse;
     }
 
+    /// The request is passed to the application, which will respond with
+    /// `set_response_headers()` and so on.
+    pub fn wait_for_response(&mut self) {
+        self.state = TransactionState::WaitingForResponse;
+    }
+
+    /// Send an interim response, such as 103 Early Hints, before the final
+    /// response.
+    pub fn set_interim_response(
+        &mut self,
+        headers: &[Header],
+        encoder: &mut QPackEncoder,
+    ) -> Res<()> {
+        if self.state != TransactionState::WaitingForResponse {
+            return Err(Error::Unavailable);
+        }
+        if !is_interim_response(headers) || header_value(headers, ":status") == Some("101") {
+            return Err(Error::InvalidHeader);
+        }
+        qdebug!([self] "Encoding interim response");
+        let mut d = Encoder::default();
+        if let Some(buf) = self.response_buf.take() {
+            d.encode(&buf);
+        }
+        self.encode_header_block(&mut d, headers, encoder);
+        self.response_buf = Some(d.into());
+        Ok(())
+    }
+
+    /// Start a response that has its body sent with `send_response_body()`.
+    pub fn set_response_headers(
+        &mut self,
+        headers: &[Header],
+        encoder: &mut QPackEncoder,
+    ) -> Res<()> {
+        if self.state != TransactionState::WaitingForResponse {
+            return Err(Error::Unavailable);
+        }
+        if is_interim_response(headers) {
+            return Err(Error::InvalidHeader);
+        }
+        qdebug!([self] "Encoding headers");
+        let mut d = Encoder::default();
+        // Interim responses might not be sent yet.
+        if let Some(buf) = self.response_buf.take() {
+            d.encode(&buf);
+        }
+        self.encode_header_block(&mut d, headers, encoder);
+        self.response_buf = Some(d.into());
+        self.state = TransactionState::StreamingResponse;
+        Ok(())
+    }
+
+    /// Send part of the response body.  Nothing is sent until the response
+    /// headers are.
+    pub fn send_response_body(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
+        match self.state {
+            TransactionState::StreamingResponse if self.response_buf.is_none() => {
+                send_data_frame(conn, self.stream_id, buf)
+            }
+            TransactionState::StreamingResponse => Ok(0),
+            TransactionState::WaitingForResponse => Err(Error::Unavailable),
+            _ => Err(Error::AlreadyClosed),
+        }
+    }
+
+    /// End a streamed response with trailers.
+    pub fn set_response_trailers(
+        &mut self,
+        trailers: &[Header],
+        encoder: &mut QPackEncoder,
+    ) -> Res<()> {
+        if self.state != TransactionState::StreamingResponse {
+            return Err(Error::Unavailable);
+        }
+        qdebug!([self] "Encoding trailers");
+        let mut d = Encoder::default();
+        if let Some(buf) = self.response_buf.take() {
+            d.encode(&buf);
+        }
+        self.encode_header_block(&mut d, trailers, encoder);
+        self.response_buf = Some(d.into());
+        self.state = TransactionState::SendingResponse;
+        Ok(())
+    }
+
+    /// End a streamed response.  The stream is closed when anything that is
+    /// still buffered is sent.
+    pub fn close_send(&mut self, conn: &mut Connection) -> Res<()> {
+        if self.state != TransactionState::StreamingResponse {
+            return Err(Error::AlreadyClosed);
+        }
+        if self.response_buf.is_some() {
+            self.state = TransactionState::SendingResponse;
+        } else {
+            conn.stream_close_send(self.stream_id)?;
+            self.state = TransactionState::Closed;
+        }
+        Ok(())
+    }
+
+    fn encode_header_block(&self, d: &mut Encoder, headers: &[Header], encoder: &mut QPackEncoder) {
+        let encoded_headers = encoder.encode_header_block(headers, self.stream_id);
+        let hframe = HFrame::Headers {
+            len: encoded_headers.len() as u64,
+        };
+        hframe.encode(d);
+        d.encode(&encoded_headers);
+    }
+
     pub fn send(&mut self, conn: &mut Connection) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
         };
-        if self.state == TransactionState::SendingResponse {
+        if self.state == TransactionState::SendingResponse
+            || self.state == TransactionState::StreamingResponse
+            || self.state == TransactionState::WaitingForResponse
+        {
             if let Some(d) = &mut self.response_buf {
                 let sent = conn.stream_send(self.stream_id, &d[..])?;
                 qdebug!([label] "{} bytes sent", sent);
                 if sent == d.len() {
                     self.response_buf = None;
-                    conn.stream_close_send(self.stream_id)?;
+                    if self.state != TransactionState::SendingResponse {
+                        qdebug!([label] "response headers sent");
+                        return Ok(());
+                    }
+                    if !self.keep_open {
+                        conn.stream_close_send(self.stream_id)?;
+                    }
                     self.state = TransactionState::Closed;
                     qdebug!([label] "done sending request");
                 } else {
@@ -110,7 +256,7 @@ impl TransactionServer {
     }
 
     pub fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
+        let label = if neqo_common::log::enabled(neqo_common::log::Level::Debug, module_path!()) {
             format!("{}", self)
         } else {
             String::new()
@@ -172,8 +318,10 @@ impl TransactionServer {
                     }
                     break Ok(());
                 }
-                TransactionState::ReadingRequestDone => break Ok(()),
-                TransactionState::SendingResponse => break Ok(()),
+                TransactionState::ReadingRequestDone
+                | TransactionState::WaitingForResponse
+                | TransactionState::StreamingResponse
+                | TransactionState::SendingResponse => break Ok(()),
                 TransactionState::Error => break Ok(()),
                 TransactionState::Closed => {
                     panic!("Stream readable after being closed!");
@@ -203,6 +351,19 @@ impl TransactionServer {
     pub fn is_state_sending(&self) -> bool {
         self.state == TransactionState::SendingResponse
     }
+
+    pub fn done(&self) -> bool {
+        self.state == TransactionState::Closed
+    }
+
+    pub fn is_state_streaming(&self) -> bool {
+        self.state == TransactionState::StreamingResponse
+    }
+
+    /// Whether there is buffered response data that hasn't been sent.
+    pub fn has_data_to_send(&self) -> bool {
+        self.response_buf.is_some()
+    }
 }
 
 impl ::std::fmt::Display for TransactionServer {
@@ -0,0 +1,114 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// CONNECT tunnels, from Section 4.4 of the HTTP/3 specification.
+//
+// After a 2xx response to a CONNECT request, DATA frames on the request
+// stream carry the bytes of the tunnel in both directions.  Closing the
+// sending side of the stream closes that direction of the tunnel.
+
+use crate::hframe::{send_data_frame, HFrame, HFrameReader, H3_FRAME_TYPE_DATA};
+use crate::{header_value, Header};
+use crate::{Error, Res};
+use neqo_common::qtrace;
+use neqo_transport::Connection;
+use std::cmp::min;
+
+/// Whether `headers` are for a CONNECT request that opens a tunnel, rather
+/// than an extended CONNECT request.
+pub(crate) fn is_tunnel_request(headers: &[Header]) -> bool {
+    header_value(headers, ":method") == Some("CONNECT")
+        && header_value(headers, ":protocol").is_none()
+}
+
+/// The server end of a tunnel.  A client uses the request and response.
+#[derive(Debug)]
+pub(crate) struct Tunnel {
+    stream_id: u64,
+    frame_reader: HFrameReader,
+    /// How much of the current DATA frame is left to read.
+    remaining: usize,
+    recv_closed: bool,
+    send_closed: bool,
+}
+
+impl Tunnel {
+    pub fn new(stream_id: u64) -> Tunnel {
+        Tunnel {
+            stream_id,
+            frame_reader: HFrameReader::new(),
+            remaining: 0,
+            recv_closed: false,
+            send_closed: false,
+        }
+    }
+
+    pub fn send(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
+        if self.send_closed {
+            return Err(Error::AlreadyClosed);
+        }
+        let sent = send_data_frame(conn, self.stream_id, buf)?;
+        qtrace!("Tunnel {}: sent {} bytes", self.stream_id, sent);
+        Ok(sent)
+    }
+
+    pub fn recv(&mut self, conn: &mut Connection, buf: &mut [u8]) -> Res<(usize, bool)> {
+        if self.recv_closed {
+            return Ok((0, true));
+        }
+        if buf.is_empty() {
+            return Ok((0, false));
+        }
+        while self.remaining == 0 {
+            let fin = self.frame_reader.receive(conn, self.stream_id)?;
+            if !self.frame_reader.done() {
+                self.recv_closed = fin;
+                return Ok((0, fin));
+            }
+            match self.frame_reader.get_frame()? {
+                HFrame::Data { len } => {
+                    if fin && len > 0 {
+                        return Err(Error::MalformedFrame(H3_FRAME_TYPE_DATA));
+                    }
+                    self.remaining = len as usize;
+                }
+                _ => return Err(Error::UnexpectedFrame),
+            }
+            if fin {
+                self.recv_closed = true;
+                return Ok((0, true));
+            }
+        }
+
+        let to_read = min(self.remaining, buf.len());
+        let (amount, fin) = conn.stream_recv(self.stream_id, &mut buf[..to_read])?;
+        self.remaining -= amount;
+        if fin {
+            if self.remaining > 0 {
+                return Err(Error::MalformedFrame(H3_FRAME_TYPE_DATA));
+            }
+            self.recv_closed = true;
+        }
+        Ok((amount, fin))
+    }
+
+    /// The peer asked for no more data, so the sending side was reset.
+    pub fn stop_sending(&mut self) {
+        self.send_closed = true;
+    }
+
+    pub fn close_send(&mut self, conn: &mut Connection) -> Res<()> {
+        if !self.send_closed {
+            self.send_closed = true;
+            conn.stream_close_send(self.stream_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn done(&self) -> bool {
+        self.recv_closed && self.send_closed
+    }
+}
@@ -0,0 +1,122 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// WebTransport over HTTP/3, from draft-ietf-webtrans-http3-02.
+//
+// A session is an extended CONNECT request (RFC 8441) with a `:protocol` of
+// `webtransport`.  The session ID is the ID of that request stream.  Streams
+// in a session start with a header that carries the session ID.  Datagrams
+// belong to the session whose request stream they are sent on.  The transport
+// doesn't support QUIC DATAGRAM frames, which would start with the quarter
+// stream ID of the session, so datagrams are sent as HTTP Datagrams in
+// DATAGRAM capsules on the request stream; see `capsule.rs`.
+
+use crate::{header_value, Header};
+use crate::{Error, Res};
+use neqo_common::Encoder;
+use std::collections::{BTreeSet, HashMap};
+
+pub(crate) const WEBTRANSPORT_UNI_STREAM_TYPE: u64 = 0x54;
+pub(crate) const WEBTRANSPORT_BIDI_STREAM_SIGNAL: u64 = 0x41;
+
+/// The value of `:protocol` in a request for a WebTransport session.
+pub const WEBTRANSPORT_PROTOCOL: &str = "webtransport";
+
+/// The header that starts a stream in the session `session_id`.
+pub(crate) fn stream_header(session_id: u64, bidi: bool) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode_varint(if bidi {
+        WEBTRANSPORT_BIDI_STREAM_SIGNAL
+    } else {
+        WEBTRANSPORT_UNI_STREAM_TYPE
+    });
+    enc.encode_varint(session_id);
+    enc.into()
+}
+
+/// Whether `headers` are for a request that asks for a session.
+pub(crate) fn is_session_request(headers: &[Header]) -> bool {
+    header_value(headers, ":method") == Some("CONNECT")
+        && header_value(headers, ":protocol") == Some(WEBTRANSPORT_PROTOCOL)
+}
+
+/// The sessions on a connection and the streams in each.
+#[derive(Debug, Default)]
+pub(crate) struct WebTransportSessions {
+    /// The streams in each session, by session ID.
+    sessions: HashMap<u64, BTreeSet<u64>>,
+    /// The session of each stream.
+    streams: HashMap<u64, u64>,
+}
+
+impl WebTransportSessions {
+    pub fn add_session(&mut self, session_id: u64) {
+        self.sessions.insert(session_id, BTreeSet::new());
+    }
+
+    pub fn is_session(&self, session_id: u64) -> bool {
+        self.sessions.contains_key(&session_id)
+    }
+
+    pub fn add_stream(&mut self, session_id: u64, stream_id: u64) -> Res<()> {
+        let streams = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or(Error::InvalidStreamId)?;
+        streams.insert(stream_id);
+        self.streams.insert(stream_id, session_id);
+        Ok(())
+    }
+
+    pub fn session_of(&self, stream_id: u64) -> Option<u64> {
+        self.streams.get(&stream_id).cloned()
+    }
+
+    pub fn remove_stream(&mut self, stream_id: u64) {
+        if let Some(session_id) = self.streams.remove(&stream_id) {
+            if let Some(streams) = self.sessions.get_mut(&session_id) {
+                streams.remove(&stream_id);
+            }
+        }
+    }
+
+    /// Remove a session, returning the streams that were in it.
+    pub fn remove_session(&mut self, session_id: u64) -> Option<BTreeSet<u64>> {
+        let streams = self.sessions.remove(&session_id)?;
+        for stream_id in &streams {
+            self.streams.remove(stream_id);
+        }
+        Some(streams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        assert_eq!(stream_header(4, false), vec![0x40, 0x54, 0x04]);
+        assert_eq!(stream_header(8, true), vec![0x40, 0x41, 0x08]);
+    }
+
+    #[test]
+    fn sessions() {
+        let mut wt = WebTransportSessions::default();
+        assert_eq!(wt.add_stream(0, 2), Err(Error::InvalidStreamId));
+        wt.add_session(0);
+        assert!(wt.is_session(0));
+        wt.add_stream(0, 2).unwrap();
+        wt.add_stream(0, 3).unwrap();
+        assert_eq!(wt.session_of(3), Some(0));
+        wt.remove_stream(3);
+        assert_eq!(wt.session_of(3), None);
+        let streams = wt.remove_session(0).unwrap();
+        assert_eq!(streams.into_iter().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(wt.session_of(2), None);
+        assert!(!wt.is_session(0));
+    }
+}
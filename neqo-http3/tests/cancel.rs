@@ -0,0 +1,111 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{CloseReason, Error, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect() -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+fn request(client: &mut Http3Connection, server: &mut Http3Connection) -> u64 {
+    let stream_id = client
+        .fetch("GET", "https", DEFAULT_SERVER_NAME, "/", &[])
+        .unwrap();
+    client.stream_close_send(stream_id).unwrap();
+    exchange_packets(client, server);
+    stream_id
+}
+
+fn resets(conn: &mut Http3Connection) -> Vec<(u64, u64, CloseReason)> {
+    conn.events()
+        .filter_map(|e| match e {
+            Http3Event::Reset {
+                stream_id,
+                error,
+                reason,
+            } => Some((stream_id, error, reason)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn client_cancels() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+    let _ = server.events().count();
+
+    let cancelled = Error::RequestCancelled.code();
+    client.cancel_fetch(stream_id, cancelled).unwrap();
+    assert_eq!(
+        resets(&mut client),
+        vec![(stream_id, cancelled, CloseReason::Local(cancelled))]
+    );
+    assert_eq!(
+        client.cancel_fetch(stream_id, cancelled),
+        Err(Error::InvalidStreamId)
+    );
+
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        resets(&mut server),
+        vec![(
+            stream_id,
+            cancelled,
+            CloseReason::PeerApplication(cancelled)
+        )]
+    );
+    assert_eq!(
+        server.cancel_fetch(stream_id, cancelled),
+        Err(Error::Unavailable)
+    );
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+}
+
+#[test]
+fn server_cancels() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+
+    let cancelled = Error::RequestCancelled.code();
+    server.stream_reset(stream_id, cancelled).unwrap();
+    exchange_packets(&mut client, &mut server);
+
+    // This only ends the request; the connection stays up for others.
+    assert_eq!(
+        resets(&mut client),
+        vec![(
+            stream_id,
+            cancelled,
+            CloseReason::PeerApplication(cancelled)
+        )]
+    );
+    assert_eq!(client.state(), Http3State::Connected);
+    let next = request(&mut client, &mut server);
+    assert_ne!(next, stream_id);
+}
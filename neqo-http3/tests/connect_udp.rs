@@ -0,0 +1,127 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::transaction_server::Response;
+use neqo_http3::{connect_udp_target, Error, Header, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+// Proxy UDP to port 443 and refuse anything else.
+fn handler(request_headers: &[Header], _error: bool) -> Response {
+    let headers = match connect_udp_target(request_headers) {
+        Some((_, 443)) => vec![
+            (String::from(":status"), String::from("200")),
+            (String::from("capsule-protocol"), String::from("?1")),
+        ],
+        _ => vec![(String::from(":status"), String::from("403"))],
+    };
+    (headers, Vec::new(), Vec::new(), None)
+}
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect(server_connect_udp: bool) -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    client.enable_connect_udp().unwrap();
+    let mut server = Http3Connection::new(default_server(), 100, 100, Some(Box::new(handler)));
+    if server_connect_udp {
+        server.enable_connect_udp().unwrap();
+    }
+
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    assert_eq!(client.enable_connect_udp(), Err(Error::Unavailable));
+    (client, server)
+}
+
+#[test]
+fn not_enabled() {
+    let (mut client, _server) = connect(false);
+    assert!(!client.connect_udp_enabled());
+    assert_eq!(
+        client.connect_udp("https", "proxy.example", "192.0.2.6", 443, &[]),
+        Err(Error::Unavailable)
+    );
+}
+
+#[test]
+fn datagrams() {
+    let (mut client, mut server) = connect(true);
+    assert!(client.connect_udp_enabled());
+    let stream_id = client
+        .connect_udp("https", "proxy.example", "2001:db8::42", 443, &[])
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(server.events().any(|e| e
+        == Http3Event::NewUdpTunnel {
+            stream_id,
+            host: String::from("2001:db8::42"),
+            port: 443,
+        }));
+    let (headers, fin) = client.read_response_headers(stream_id).unwrap();
+    assert_eq!(headers[0], (String::from(":status"), String::from("200")));
+    assert!(!fin);
+
+    assert_eq!(client.connect_udp_send(stream_id, &[1, 2, 3]), Ok(true));
+    assert_eq!(client.connect_udp_send(stream_id, &[4, 5]), Ok(true));
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        server.connect_udp_recv(now(), stream_id),
+        Ok((Some(vec![1, 2, 3]), false))
+    );
+    assert_eq!(
+        server.connect_udp_recv(now(), stream_id),
+        Ok((Some(vec![4, 5]), false))
+    );
+    assert_eq!(server.connect_udp_recv(now(), stream_id), Ok((None, false)));
+
+    assert_eq!(server.connect_udp_send(stream_id, &[6]), Ok(true));
+    server.connect_udp_close(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.connect_udp_recv(now(), stream_id),
+        Ok((Some(vec![6]), false))
+    );
+    assert_eq!(client.connect_udp_recv(now(), stream_id), Ok((None, true)));
+    assert_eq!(
+        client.connect_udp_send(stream_id, &[7]),
+        Err(Error::InvalidStreamId)
+    );
+}
+
+#[test]
+fn refused() {
+    let (mut client, mut server) = connect(true);
+    let stream_id = client
+        .connect_udp("https", "proxy.example", "192.0.2.6", 53, &[])
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(!server.events().any(|e| match e {
+        Http3Event::NewUdpTunnel { .. } => true,
+        _ => false,
+    }));
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((vec![(String::from(":status"), String::from("403"))], true))
+    );
+    assert_eq!(
+        server.connect_udp_send(stream_id, &[1]),
+        Err(Error::InvalidStreamId)
+    );
+}
@@ -0,0 +1,84 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Error, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect() -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+fn fetch(client: &mut Http3Connection) -> u64 {
+    let stream_id = client
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    client.stream_close_send(stream_id).unwrap();
+    stream_id
+}
+
+#[test]
+fn graceful_shutdown() {
+    let (mut client, mut server) = connect();
+    assert_eq!(client.send_goaway(), Err(Error::Unavailable));
+
+    let first = fetch(&mut client);
+    exchange_packets(&mut client, &mut server);
+    assert!(server.events().any(|e| match e {
+        Http3Event::Request { stream_id, .. } => stream_id == first,
+        _ => false,
+    }));
+
+    // The server stops taking requests, but the client sends another one
+    // before it sees GOAWAY.
+    server.send_goaway().unwrap();
+    assert_eq!(server.state(), Http3State::GoingAway);
+    let second = fetch(&mut client);
+    exchange_packets(&mut client, &mut server);
+    assert!(!server.events().any(|e| match e {
+        Http3Event::Request { .. } => true,
+        _ => false,
+    }));
+
+    // The second request wasn't processed, so it can be retried.
+    assert_eq!(client.state(), Http3State::GoingAway);
+    assert!(client.events().any(|e| match e {
+        Http3Event::Reset {
+            stream_id, error, ..
+        } => stream_id == second && error == Error::RequestRejected.code(),
+        _ => false,
+    }));
+    assert_eq!(
+        client.fetch("GET", "https", "something.com", "/", &[]),
+        Err(Error::Unavailable)
+    );
+
+    // The first request is still answered.
+    let status = vec![(String::from(":status"), String::from("200"))];
+    server.send_response_headers(first, &status).unwrap();
+    server.stream_close_send(first).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.read_response_headers(first), Ok((status, true)));
+}
@@ -0,0 +1,103 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::transaction_server::Response;
+use neqo_http3::{Error, Header, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+fn handler(_request_headers: &[Header], _error: bool) -> Response {
+    (
+        vec![
+            (String::from(":status"), String::from("200")),
+            (String::from("capsule-protocol"), String::from("?1")),
+        ],
+        Vec::new(),
+        Vec::new(),
+        None,
+    )
+}
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect(server_extended_connect: bool) -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    client.enable_extended_connect().unwrap();
+    let mut server = Http3Connection::new(default_server(), 100, 100, Some(Box::new(handler)));
+    if server_extended_connect {
+        server.enable_extended_connect().unwrap();
+    }
+
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+#[test]
+fn not_enabled() {
+    let (mut client, _server) = connect(false);
+    assert!(!client.extended_connect_enabled());
+    assert_eq!(
+        client.extended_connect("https", "something.com", "/", "test", &[]),
+        Err(Error::Unavailable)
+    );
+}
+
+#[test]
+fn datagrams() {
+    let (mut client, mut server) = connect(true);
+    let stream_id = client
+        .extended_connect("https", "something.com", "/", "test", &[])
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::NewCapsuleStream { stream_id }));
+    let (headers, fin) = client.read_response_headers(stream_id).unwrap();
+    assert_eq!(headers[0], (String::from(":status"), String::from("200")));
+    assert!(!fin);
+
+    assert_eq!(client.http_datagram_send(stream_id, &[1, 2, 3]), Ok(true));
+    assert_eq!(client.http_datagram_send(stream_id, &[]), Ok(true));
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        server.http_datagram_recv(now(), stream_id),
+        Ok((Some(vec![1, 2, 3]), false))
+    );
+    assert_eq!(
+        server.http_datagram_recv(now(), stream_id),
+        Ok((Some(Vec::new()), false))
+    );
+    assert_eq!(
+        server.http_datagram_recv(now(), stream_id),
+        Ok((None, false))
+    );
+
+    assert_eq!(server.http_datagram_send(stream_id, &[4, 5]), Ok(true));
+    server.capsule_stream_close(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.http_datagram_recv(now(), stream_id),
+        Ok((Some(vec![4, 5]), false))
+    );
+    assert_eq!(
+        client.http_datagram_recv(now(), stream_id),
+        Ok((None, true))
+    );
+}
@@ -0,0 +1,74 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus};
+use neqo_http3::{Error, Http3Connection, Http3State};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect() -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+fn covers_all(_: &CertificateInfo, _: &str) -> bool {
+    true
+}
+
+#[test]
+fn coalesce_advertised_origins() {
+    let (mut client, mut server) = connect();
+
+    // Only the name the connection was made for works to start with.
+    assert!(client.can_coalesce(DEFAULT_SERVER_NAME, covers_all));
+    assert!(client.can_coalesce("EXAMPLE.com:443", covers_all));
+    assert!(!client.can_coalesce("other.example.com", covers_all));
+
+    assert_eq!(
+        client.send_origins(&["https://other.example.com"]),
+        Err(Error::Unavailable)
+    );
+    server
+        .send_origins(&["https://other.example.com", "https://alt.example.com:8443"])
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+
+    assert!(client.can_coalesce("other.example.com", covers_all));
+    assert!(client.can_coalesce("alt.example.com:8443", covers_all));
+    assert!(!client.can_coalesce("alt.example.com", covers_all));
+
+    // The certificate has to be valid for the host as well.
+    assert!(!client.can_coalesce("other.example.com", |_: &CertificateInfo, _: &str| false));
+    let mut checked = String::new();
+    client.can_coalesce("alt.example.com:8443", |_: &CertificateInfo, host: &str| {
+        checked = String::from(host);
+        true
+    });
+    assert_eq!(checked, "alt.example.com");
+}
+
+#[test]
+fn no_coalescing_on_server() {
+    let (_, server) = connect();
+    assert!(!server.can_coalesce(DEFAULT_SERVER_NAME, covers_all));
+}
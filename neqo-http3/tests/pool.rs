@@ -0,0 +1,328 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_common::Datagram;
+use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus};
+use neqo_http3::{
+    Connector, Error, Http3Connection, Http3ConnectionPool, Http3Event, PoolEvent, PoolRequestId,
+};
+use neqo_transport::{FixedConnectionIdManager, Output};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use test_fixture::*;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), port)
+}
+
+/// Resolves names from a table and gives each connection its own local port.
+#[derive(Default)]
+struct TestConnector {
+    names: HashMap<(String, u16), Vec<SocketAddr>>,
+    /// The addresses that connections were made to, in order.
+    connected: Rc<RefCell<Vec<SocketAddr>>>,
+    next_port: u16,
+}
+
+impl Connector for TestConnector {
+    fn resolve(&mut self, host: &str, port: u16) -> Vec<SocketAddr> {
+        self.names
+            .get(&(String::from(host), port))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn connect(&mut self, server_name: &str, remote: SocketAddr) -> Result<Http3Connection, Error> {
+        self.connected.borrow_mut().push(remote);
+        self.next_port += 1;
+        let c = neqo_transport::Connection::new_client(
+            server_name,
+            DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            addr(10000 + self.next_port),
+            remote,
+        )?;
+        Ok(Http3Connection::new(c, 100, 100, None))
+    }
+
+    fn authenticate(&mut self, _: &str, _: Option<CertificateInfo>) -> AuthenticationStatus {
+        AuthenticationStatus::Ok
+    }
+}
+
+/// Servers for each connection from the pool, for the addresses that can be
+/// reached.
+struct Servers {
+    reachable: HashSet<SocketAddr>,
+    servers: HashMap<(SocketAddr, SocketAddr), Http3Connection>,
+    to_client: Vec<Datagram>,
+}
+
+impl Servers {
+    fn new(reachable: &[SocketAddr]) -> Self {
+        Self {
+            reachable: reachable.iter().cloned().collect(),
+            servers: HashMap::new(),
+            to_client: Vec::new(),
+        }
+    }
+
+    fn deliver(&mut self, d: Datagram, now: Instant) {
+        if !self.reachable.contains(&d.destination()) {
+            return;
+        }
+        let server = self
+            .servers
+            .entry((d.source(), d.destination()))
+            .or_insert_with(|| Http3Connection::new(default_server(), 100, 100, None));
+        let mut input = Some(d);
+        while let Some(out) = server.process(input.take(), now).dgram() {
+            self.to_client.push(out);
+        }
+    }
+
+    fn flush(&mut self, now: Instant) {
+        for server in self.servers.values_mut() {
+            while let Some(out) = server.process(None, now).dgram() {
+                self.to_client.push(out);
+            }
+        }
+    }
+
+    /// Move datagrams between the pool and the servers until neither has
+    /// anything to send.
+    fn run(&mut self, pool: &mut Http3ConnectionPool, now: Instant) -> Output {
+        self.flush(now);
+        loop {
+            let input = self.to_client.pop();
+            let had_input = input.is_some();
+            match pool.process(input, now) {
+                Output::Datagram(d) => self.deliver(d, now),
+                out => {
+                    if !had_input && self.to_client.is_empty() {
+                        return out;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answer every request that has arrived, adding `extra` to the response.
+    fn respond(&mut self, extra: &[(String, String)]) -> usize {
+        let mut count = 0;
+        for server in self.servers.values_mut() {
+            let requests = server
+                .events()
+                .filter_map(|e| match e {
+                    Http3Event::Request { stream_id, .. } => Some(stream_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            for stream_id in requests {
+                let mut headers = vec![(String::from(":status"), String::from("200"))];
+                headers.extend_from_slice(extra);
+                server.send_response_headers(stream_id, &headers).unwrap();
+                server.stream_close_send(stream_id).unwrap();
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+fn make_pool(
+    names: &[(&str, u16, &[SocketAddr])],
+) -> (Http3ConnectionPool, Rc<RefCell<Vec<SocketAddr>>>) {
+    let mut connector = TestConnector::default();
+    for (host, port, addrs) in names {
+        connector
+            .names
+            .insert((String::from(*host), *port), addrs.to_vec());
+    }
+    let connected = Rc::clone(&connector.connected);
+    (
+        Http3ConnectionPool::new(Box::new(connector), DEFAULT_ALPN),
+        connected,
+    )
+}
+
+fn get(pool: &mut Http3ConnectionPool, authority: &str) -> PoolRequestId {
+    let request = pool.fetch("GET", authority, "/", &[]).unwrap();
+    pool.stream_close_send(request).unwrap();
+    request
+}
+
+/// Check that `request` got a response with status 200.
+fn check_response(pool: &mut Http3ConnectionPool, request: PoolRequestId, now: Instant) {
+    let (headers, fin) = pool.read_response_headers(now, request).unwrap();
+    assert!(headers.contains(&(String::from(":status"), String::from("200"))));
+    if !fin {
+        let mut buf = [0; 10];
+        let (_, fin) = pool.read_response_data(now, request, &mut buf).unwrap();
+        assert!(fin);
+    }
+}
+
+fn header_ready(pool: &mut Http3ConnectionPool) -> Vec<PoolRequestId> {
+    pool.events()
+        .filter_map(|e| match e {
+            PoolEvent::HeaderReady { request } => Some(request),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn requests_share_a_connection() {
+    let (mut pool, connected) = make_pool(&[(DEFAULT_SERVER_NAME, 443, &[addr(443)])]);
+    let mut servers = Servers::new(&[addr(443)]);
+
+    let first = get(&mut pool, DEFAULT_SERVER_NAME);
+    let second = get(&mut pool, "example.com:443");
+    servers.run(&mut pool, now());
+    assert_eq!(servers.respond(&[]), 2);
+    servers.run(&mut pool, now());
+
+    assert_eq!(header_ready(&mut pool), vec![first, second]);
+    check_response(&mut pool, first, now());
+    check_response(&mut pool, second, now());
+    assert_eq!(*connected.borrow(), vec![addr(443)]);
+    assert_eq!(pool.connection_count(), 1);
+}
+
+#[test]
+fn unknown_name() {
+    let (mut pool, _) = make_pool(&[]);
+    let request = get(&mut pool, DEFAULT_SERVER_NAME);
+    assert_eq!(pool.process(None, now()), Output::None);
+    assert_eq!(
+        pool.events().collect::<Vec<_>>(),
+        vec![PoolEvent::Failed {
+            request,
+            reason: None
+        }]
+    );
+    assert_eq!(
+        pool.fetch("GET", "example.com:x", "/", &[]),
+        Err(Error::InvalidInput)
+    );
+}
+
+#[test]
+fn alt_svc_is_used_until_it_expires() {
+    let (mut pool, connected) = make_pool(&[
+        (DEFAULT_SERVER_NAME, 443, &[addr(443)]),
+        ("alt.example.com", 8443, &[addr(8443)]),
+    ]);
+    let mut servers = Servers::new(&[addr(443), addr(8443)]);
+
+    // Alternatives for other protocols are ignored.
+    pool.alt_svc(
+        DEFAULT_SERVER_NAME,
+        "h2=\":443\", alpn=\"alt.example.com:8443\"; ma=60",
+        now(),
+    );
+    let request = get(&mut pool, DEFAULT_SERVER_NAME);
+    servers.run(&mut pool, now());
+    assert_eq!(*connected.borrow(), vec![addr(8443)]);
+    servers.respond(&[]);
+    servers.run(&mut pool, now());
+    assert_eq!(header_ready(&mut pool), vec![request]);
+    check_response(&mut pool, request, now());
+
+    // A new origin doesn't use alternatives for another one, and a response
+    // can clear those it has.
+    let (mut pool, connected) = make_pool(&[
+        (DEFAULT_SERVER_NAME, 443, &[addr(443)]),
+        ("alt.example.com", 8443, &[addr(8443)]),
+    ]);
+    let mut servers = Servers::new(&[addr(443), addr(8443)]);
+    let later = now() + Duration::from_secs(61);
+    pool.alt_svc(
+        DEFAULT_SERVER_NAME,
+        "alpn=\"alt.example.com:8443\"; ma=60",
+        now(),
+    );
+    let request = get(&mut pool, DEFAULT_SERVER_NAME);
+    servers.run(&mut pool, later);
+    assert_eq!(*connected.borrow(), vec![addr(443)]);
+    servers.respond(&[(String::from("alt-svc"), String::from("clear"))]);
+    servers.run(&mut pool, later);
+    assert_eq!(header_ready(&mut pool), vec![request]);
+    check_response(&mut pool, request, later);
+}
+
+#[test]
+fn race_addresses() {
+    // The first address doesn't answer, so the second one is tried after a
+    // short delay.
+    let (mut pool, connected) = make_pool(&[(DEFAULT_SERVER_NAME, 443, &[addr(1443), addr(443)])]);
+    let mut servers = Servers::new(&[addr(443)]);
+
+    let request = get(&mut pool, DEFAULT_SERVER_NAME);
+    let delay = match servers.run(&mut pool, now()) {
+        Output::Callback(delay) => delay,
+        _ => panic!("expected a callback"),
+    };
+    assert!(delay <= Duration::from_millis(250));
+    assert_eq!(*connected.borrow(), vec![addr(1443)]);
+
+    let later = now() + Duration::from_millis(250);
+    servers.run(&mut pool, later);
+    assert_eq!(*connected.borrow(), vec![addr(1443), addr(443)]);
+    servers.respond(&[]);
+    servers.run(&mut pool, later);
+    assert_eq!(header_ready(&mut pool), vec![request]);
+    check_response(&mut pool, request, later);
+}
+
+#[test]
+fn retry_after_goaway() {
+    let (mut pool, connected) = make_pool(&[(DEFAULT_SERVER_NAME, 443, &[addr(443)])]);
+    let mut servers = Servers::new(&[addr(443)]);
+
+    let first = get(&mut pool, DEFAULT_SERVER_NAME);
+    servers.run(&mut pool, now());
+
+    // The server sends GOAWAY before it sees the next two requests.
+    let get_again = get(&mut pool, DEFAULT_SERVER_NAME);
+    let post = pool.fetch("POST", DEFAULT_SERVER_NAME, "/", &[]).unwrap();
+    pool.send_request_body(post, b"body").unwrap();
+    pool.stream_close_send(post).unwrap();
+    let mut sent = Vec::new();
+    while let Output::Datagram(d) = pool.process(None, now()) {
+        sent.push(d);
+    }
+    for server in servers.servers.values_mut() {
+        server.send_goaway().unwrap();
+    }
+    for d in sent {
+        servers.deliver(d, now());
+    }
+    servers.run(&mut pool, now());
+
+    // The GET goes out again on a new connection; the POST can't.
+    let events = pool.events().collect::<Vec<_>>();
+    assert_eq!(events.len(), 1);
+    match &events[0] {
+        PoolEvent::Reset { request, error, .. } => {
+            assert_eq!(*request, post);
+            assert_eq!(*error, Error::RequestRejected.code());
+        }
+        e => panic!("unexpected event {:?}", e),
+    }
+    assert_eq!(*connected.borrow(), vec![addr(443), addr(443)]);
+    assert_eq!(servers.respond(&[]), 2);
+    servers.run(&mut pool, now());
+    let mut ready = header_ready(&mut pool);
+    ready.sort();
+    assert_eq!(ready, vec![first, get_again]);
+    check_response(&mut pool, first, now());
+    check_response(&mut pool, get_again, now());
+}
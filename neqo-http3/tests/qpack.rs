@@ -0,0 +1,85 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Error, Http3Connection, Http3State, QPackEncoderStats};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect(client: &mut Http3Connection, server: &mut Http3Connection) {
+    exchange_packets(client, server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(client, server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+}
+
+/// Send two requests with the same extra header and return the stats.
+fn two_requests<F: FnOnce(&mut Http3Connection)>(setup: F) -> QPackEncoderStats {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    setup(&mut client);
+    connect(&mut client, &mut server);
+    assert_eq!(
+        client.set_qpack_encoder_policy(100, 100),
+        Err(Error::Unavailable)
+    );
+
+    let headers = [(String::from("x-custom"), String::from("value"))];
+    for _ in 0..2 {
+        let stream_id = client
+            .fetch("GET", "https", DEFAULT_SERVER_NAME, "/", &headers)
+            .unwrap();
+        client.stream_close_send(stream_id).unwrap();
+        exchange_packets(&mut client, &mut server);
+    }
+    client.qpack_encoder_stats()
+}
+
+#[test]
+fn dynamic_table_is_used() {
+    let stats = two_requests(|_| {});
+    assert_eq!(stats.headers, 10);
+    assert_eq!(stats.static_table_hits, 8);
+    assert_eq!(stats.dynamic_table_hits, 1);
+    assert_eq!(stats.inserts, 1);
+    assert_eq!(stats.blocked_stream_stalls, 0);
+    assert!((stats.dynamic_table_hit_rate() - 0.1).abs() < 1e-9);
+}
+
+#[test]
+fn never_block() {
+    let stats = two_requests(|c| c.set_qpack_encoder_policy(100, 0).unwrap());
+    assert_eq!(stats.dynamic_table_hits, 0);
+    assert_eq!(stats.inserts, 0);
+    assert_eq!(stats.blocked_stream_stalls, 2);
+}
+
+#[test]
+fn no_dynamic_table() {
+    let stats = two_requests(|c| c.set_qpack_encoder_policy(0, 100).unwrap());
+    assert_eq!(stats.dynamic_table_hits, 0);
+    assert_eq!(stats.inserts, 0);
+    assert_eq!(stats.blocked_stream_stalls, 0);
+}
+
+#[test]
+fn never_indexed() {
+    let stats = two_requests(|c| c.never_index_header("X-Custom"));
+    assert_eq!(stats.dynamic_table_hits, 0);
+    assert_eq!(stats.inserts, 0);
+}
@@ -0,0 +1,74 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Error, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect(client: &mut Http3Connection, server: &mut Http3Connection) {
+    exchange_packets(client, server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(client, server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+}
+
+fn unknown_settings(conn: &mut Http3Connection) -> Vec<Vec<(u64, u64)>> {
+    conn.events()
+        .filter_map(|e| match e {
+            Http3Event::UnknownSettings { settings } => Some(settings),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn extension_settings() {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    client.add_setting(0x4242, 7).unwrap();
+    client.add_setting(0x4243, 1).unwrap();
+    client.add_setting(0x4242, 8).unwrap();
+    server.enable_grease(17).unwrap();
+    connect(&mut client, &mut server);
+
+    assert_eq!(
+        unknown_settings(&mut server),
+        vec![vec![(0x4243, 1), (0x4242, 8)]]
+    );
+    assert_eq!(server.peer_setting(0x4242), Some(8));
+    assert_eq!(server.peer_setting(0x1), Some(100));
+    assert_eq!(server.peer_setting(0x4244), None);
+
+    // The client ignores the reserved setting from the server.
+    assert!(unknown_settings(&mut client).is_empty());
+    assert_eq!(client.peer_setting(0x4242), None);
+
+    // Settings can't change once they are sent.
+    assert_eq!(client.add_setting(0x4244, 1), Err(Error::Unavailable));
+    assert_eq!(server.enable_grease(18), Err(Error::Unavailable));
+}
+
+#[test]
+fn settings_the_crate_uses() {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    // QPACK, extended CONNECT and the settings reserved from HTTP/2.
+    for id in &[0x0, 0x1, 0x2, 0x5, 0x6, 0x7, 0x8] {
+        assert_eq!(client.add_setting(*id, 1), Err(Error::InvalidInput));
+    }
+}
@@ -0,0 +1,203 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Error, Header, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+// A server without a request handler, so that it responds through the API.
+fn connect() -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, None);
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+fn request(client: &mut Http3Connection, server: &mut Http3Connection) -> u64 {
+    let stream_id = client
+        .fetch("GET", "https", "something.com", "/stream", &[])
+        .unwrap();
+    client.stream_close_send(stream_id).unwrap();
+    exchange_packets(client, server);
+    let headers = server
+        .events()
+        .filter_map(|e| match e {
+            Http3Event::Request {
+                stream_id: id,
+                headers,
+            } if id == stream_id => Some(headers),
+            _ => None,
+        })
+        .next()
+        .expect("should have a request");
+    assert_eq!(headers[0], (String::from(":method"), String::from("GET")));
+    assert_eq!(headers[3], (String::from(":path"), String::from("/stream")));
+    stream_id
+}
+
+fn status(s: &str) -> Vec<Header> {
+    vec![(String::from(":status"), String::from(s))]
+}
+
+#[test]
+fn streaming_response() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+
+    // The body can't be sent before the headers.
+    assert_eq!(
+        server.send_response_body(stream_id, b"abc"),
+        Err(Error::Unavailable)
+    );
+    server
+        .send_response_headers(stream_id, &status("200"))
+        .unwrap();
+    assert_eq!(
+        server.send_response_headers(stream_id, &status("200")),
+        Err(Error::Unavailable)
+    );
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((status("200"), false))
+    );
+
+    // Send the body in parts.
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::DataWritable { stream_id }));
+    let mut buf = [0; 100];
+    for part in &[&b"abc"[..], &b"defg"[..]] {
+        assert_eq!(server.send_response_body(stream_id, part), Ok(part.len()));
+        exchange_packets(&mut client, &mut server);
+        assert_eq!(
+            client.read_response_data(now(), stream_id, &mut buf),
+            Ok((part.len(), false))
+        );
+        assert_eq!(&buf[..part.len()], *part);
+    }
+
+    let trailers = vec![(String::from("age"), String::from("0"))];
+    server.send_response_trailers(stream_id, &trailers).unwrap();
+    assert_eq!(
+        server.send_response_body(stream_id, b"h"),
+        Err(Error::AlreadyClosed)
+    );
+    exchange_packets(&mut client, &mut server);
+    assert!(client.events().any(|e| e
+        == Http3Event::Trailers {
+            stream_id,
+            trailers: trailers.clone()
+        }));
+    assert_eq!(
+        client.read_response_data(now(), stream_id, &mut buf),
+        Ok((0, true))
+    );
+}
+
+#[test]
+fn early_hints() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+    let hints = vec![
+        (String::from(":status"), String::from("103")),
+        (
+            String::from("link"),
+            String::from("</style.css>; rel=preload; as=style"),
+        ),
+    ];
+    // Only 1xx responses are interim, and not 101.
+    assert_eq!(
+        server.send_interim_response(stream_id, &status("200")),
+        Err(Error::InvalidHeader)
+    );
+    assert_eq!(
+        server.send_interim_response(stream_id, &status("101")),
+        Err(Error::InvalidHeader)
+    );
+    assert_eq!(
+        server.send_response_headers(stream_id, &status("103")),
+        Err(Error::InvalidHeader)
+    );
+    server.send_interim_response(stream_id, &hints).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(client.events().any(|e| e
+        == Http3Event::InterimResponse {
+            stream_id,
+            headers: hints.clone(),
+        }));
+
+    server
+        .send_response_headers(stream_id, &status("200"))
+        .unwrap();
+    assert_eq!(
+        server.send_interim_response(stream_id, &hints),
+        Err(Error::Unavailable)
+    );
+    server.stream_close_send(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((status("200"), true))
+    );
+}
+
+#[test]
+fn close_response() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+    server
+        .send_response_headers(stream_id, &status("204"))
+        .unwrap();
+    server.stream_close_send(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((status("204"), true))
+    );
+    assert_eq!(
+        server.send_response_body(stream_id, b"abc"),
+        Err(Error::InvalidStreamId)
+    );
+}
+
+#[test]
+fn cancel_response() {
+    let (mut client, mut server) = connect();
+    let stream_id = request(&mut client, &mut server);
+    server
+        .send_response_headers(stream_id, &status("200"))
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    server
+        .stream_reset(stream_id, Error::RequestCancelled.code())
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(client.events().any(|e| match e {
+        Http3Event::Reset { stream_id: id, .. } => id == stream_id,
+        _ => false,
+    }));
+    assert_eq!(
+        server.send_response_body(stream_id, b"abc"),
+        Err(Error::InvalidStreamId)
+    );
+}
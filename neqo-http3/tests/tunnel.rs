@@ -0,0 +1,124 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_common::matches;
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::transaction_server::Response;
+use neqo_http3::{Error, Header, Http3Connection, Http3Event, Http3State};
+use test_fixture::*;
+
+// Open tunnels to "example.com:443" and refuse any other request.
+fn handler(request_headers: &[Header], _error: bool) -> Response {
+    let status = if request_headers
+        == [
+            (String::from(":method"), String::from("CONNECT")),
+            (String::from(":authority"), String::from("example.com:443")),
+        ] {
+        "200"
+    } else {
+        "400"
+    };
+    (
+        vec![(String::from(":status"), String::from(status))],
+        Vec::new(),
+        Vec::new(),
+        None,
+    )
+}
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect() -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    let mut server = Http3Connection::new(default_server(), 100, 100, Some(Box::new(handler)));
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    (client, server)
+}
+
+#[test]
+fn tunnel() {
+    let (mut client, mut server) = connect();
+    let stream_id = client.connect_tunnel("example.com:443", &[]).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::NewTunnel { stream_id }));
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((vec![(String::from(":status"), String::from("200"))], false))
+    );
+
+    // Client to server.
+    assert_eq!(client.tunnel_send(stream_id, b"hello"), Ok(5));
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::DataReadable { stream_id }));
+    let mut buf = [0; 100];
+    assert_eq!(
+        server.tunnel_recv(now(), stream_id, &mut buf),
+        Ok((5, false))
+    );
+    assert_eq!(&buf[..5], b"hello");
+
+    // Server to client.
+    assert_eq!(server.tunnel_send(stream_id, b"world"), Ok(5));
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.tunnel_recv(now(), stream_id, &mut buf),
+        Ok((5, false))
+    );
+    assert_eq!(&buf[..5], b"world");
+
+    // Each side closes its direction.
+    client.tunnel_close_send(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        server.tunnel_recv(now(), stream_id, &mut buf),
+        Ok((0, true))
+    );
+    assert_eq!(server.tunnel_send(stream_id, b"more"), Ok(4));
+    server.tunnel_close_send(stream_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.tunnel_recv(now(), stream_id, &mut buf),
+        Ok((4, true))
+    );
+    assert_eq!(&buf[..4], b"more");
+}
+
+#[test]
+fn refused() {
+    let (mut client, mut server) = connect();
+    let stream_id = client.connect_tunnel("example.org:443", &[]).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(!server
+        .events()
+        .any(|e| matches!(e, Http3Event::NewTunnel { .. })));
+    assert_eq!(
+        client.read_response_headers(stream_id),
+        Ok((vec![(String::from(":status"), String::from("400"))], true))
+    );
+    let mut buf = [0; 10];
+    assert_eq!(
+        server.tunnel_recv(now(), stream_id, &mut buf),
+        Err(Error::InvalidStreamId)
+    );
+}
@@ -0,0 +1,229 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_common::matches;
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::transaction_server::Response;
+use neqo_http3::{Error, Header, Http3Connection, Http3Event, Http3State};
+use neqo_transport::StreamType;
+use test_fixture::*;
+
+// Accept sessions for "/wt" and refuse any other request.
+fn handler(request_headers: &[Header], _error: bool) -> Response {
+    let path = request_headers.iter().find(|(n, _)| n == ":path").unwrap();
+    let status = if path.1 == "/wt" { "200" } else { "404" };
+    (
+        vec![(String::from(":status"), String::from(status))],
+        Vec::new(),
+        Vec::new(),
+        None,
+    )
+}
+
+fn exchange_packets(client: &mut Http3Connection, server: &mut Http3Connection) {
+    let mut out = None;
+    loop {
+        out = client.process(out, now()).dgram();
+        let client_sent = out.is_some();
+        out = server.process(out, now()).dgram();
+        if !client_sent && out.is_none() {
+            break;
+        }
+    }
+}
+
+fn connect(server_webtransport: bool) -> (Http3Connection, Http3Connection) {
+    let mut client = Http3Connection::new(default_client(), 100, 100, None);
+    client.enable_webtransport().unwrap();
+    let mut server = Http3Connection::new(default_server(), 100, 100, Some(Box::new(handler)));
+    if server_webtransport {
+        server.enable_webtransport().unwrap();
+    }
+
+    exchange_packets(&mut client, &mut server);
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    assert_eq!(server.state(), Http3State::Connected);
+    // SETTINGS can't be changed now.
+    assert_eq!(client.enable_webtransport(), Err(Error::Unavailable));
+    (client, server)
+}
+
+fn create_session(client: &mut Http3Connection, server: &mut Http3Connection) -> u64 {
+    let session_id = client
+        .webtransport_create_session("https", "something.com", "/wt", &[])
+        .unwrap();
+    exchange_packets(client, server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::WebTransportNewSession { session_id }));
+    assert_eq!(
+        client.read_response_headers(session_id),
+        Ok((vec![(String::from(":status"), String::from("200"))], false))
+    );
+    session_id
+}
+
+fn new_stream(hconn: &mut Http3Connection, session: u64) -> u64 {
+    hconn
+        .events()
+        .filter_map(|e| match e {
+            Http3Event::WebTransportNewStream {
+                stream_id,
+                session_id,
+            } if session_id == session => Some(stream_id),
+            _ => None,
+        })
+        .next()
+        .expect("should have a new stream")
+}
+
+#[test]
+fn not_enabled() {
+    let (mut client, _server) = connect(false);
+    assert!(!client.webtransport_enabled());
+    assert_eq!(
+        client.webtransport_create_session("https", "something.com", "/wt", &[]),
+        Err(Error::Unavailable)
+    );
+}
+
+#[test]
+fn streams() {
+    let (mut client, mut server) = connect(true);
+    assert!(client.webtransport_enabled());
+    assert!(server.webtransport_enabled());
+    let session_id = create_session(&mut client, &mut server);
+
+    // A unidirectional stream from the client.
+    let uni = client
+        .webtransport_create_stream(session_id, StreamType::UniDi)
+        .unwrap();
+    assert_eq!(client.webtransport_send(uni, b"hello"), Ok(5));
+    client.webtransport_close_send(uni).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(new_stream(&mut server, session_id), uni);
+    let mut buf = [0; 16];
+    assert_eq!(server.webtransport_recv(uni, &mut buf), Ok((5, true)));
+    assert_eq!(&buf[..5], b"hello");
+
+    // A bidirectional stream from the server.
+    let bidi = server
+        .webtransport_create_stream(session_id, StreamType::BiDi)
+        .unwrap();
+    assert_eq!(server.webtransport_send(bidi, b"hi"), Ok(2));
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(new_stream(&mut client, session_id), bidi);
+    assert_eq!(client.webtransport_recv(bidi, &mut buf), Ok((2, false)));
+    assert_eq!(&buf[..2], b"hi");
+    assert_eq!(client.webtransport_send(bidi, b"yo"), Ok(2));
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::WebTransportDataReadable { stream_id: bidi }));
+    assert_eq!(server.webtransport_recv(bidi, &mut buf), Ok((2, false)));
+    assert_eq!(&buf[..2], b"yo");
+
+    // Closing the session resets the streams in it.
+    client.webtransport_close_session(session_id).unwrap();
+    assert_eq!(
+        client.webtransport_send(bidi, b"late"),
+        Err(Error::InvalidStreamId)
+    );
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::WebTransportSessionClosed { session_id }));
+    assert_eq!(
+        server.webtransport_recv(bidi, &mut buf),
+        Err(Error::InvalidStreamId)
+    );
+}
+
+#[test]
+fn refused() {
+    let (mut client, mut server) = connect(true);
+    let session_id = client
+        .webtransport_create_session("https", "something.com", "/other", &[])
+        .unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(!server
+        .events()
+        .any(|e| matches!(e, Http3Event::WebTransportNewSession { .. })));
+    assert_eq!(
+        client.read_response_headers(session_id),
+        Ok((vec![(String::from(":status"), String::from("404"))], true))
+    );
+    assert!(client
+        .events()
+        .any(|e| e == Http3Event::WebTransportSessionClosed { session_id }));
+    assert_eq!(
+        client.webtransport_create_stream(session_id, StreamType::UniDi),
+        Err(Error::InvalidStreamId)
+    );
+}
+
+#[test]
+fn datagrams() {
+    let (mut client, mut server) = connect(true);
+    let session_id = create_session(&mut client, &mut server);
+    assert_eq!(
+        client.webtransport_datagram_send(session_id + 4, &[1]),
+        Err(Error::InvalidStreamId)
+    );
+
+    assert_eq!(
+        client.webtransport_datagram_send(session_id, &[1, 2, 3]),
+        Ok(true)
+    );
+    exchange_packets(&mut client, &mut server);
+    assert!(server.events().any(|e| e
+        == Http3Event::DataReadable {
+            stream_id: session_id
+        }));
+    assert_eq!(
+        server.webtransport_datagram_recv(now(), session_id),
+        Ok((Some(vec![1, 2, 3]), false))
+    );
+    assert_eq!(
+        server.webtransport_datagram_recv(now(), session_id),
+        Ok((None, false))
+    );
+
+    assert_eq!(
+        server.webtransport_datagram_send(session_id, &[4, 5]),
+        Ok(true)
+    );
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(
+        client.webtransport_datagram_recv(now(), session_id),
+        Ok((Some(vec![4, 5]), false))
+    );
+
+    // A datagram that arrives with the end of the session can still be read.
+    assert_eq!(
+        client.webtransport_datagram_send(session_id, &[6]),
+        Ok(true)
+    );
+    client.webtransport_close_session(session_id).unwrap();
+    exchange_packets(&mut client, &mut server);
+    assert!(server
+        .events()
+        .any(|e| e == Http3Event::WebTransportSessionClosed { session_id }));
+    assert_eq!(
+        server.webtransport_datagram_send(session_id, &[7]),
+        Err(Error::InvalidStreamId)
+    );
+    assert_eq!(
+        server.webtransport_datagram_recv(now(), session_id),
+        Ok((Some(vec![6]), false))
+    );
+    assert_eq!(
+        server.webtransport_datagram_recv(now(), session_id),
+        Ok((None, true))
+    );
+}
@@ -86,7 +86,7 @@ fn process_loop(
     let timer = Timer::new(timeout);
 
     loop {
-        if let State::Closed(..) = client.state() {
+        if let State::Closed { .. } = client.state() {
             return Ok(client.state().clone());
         }
 
@@ -136,7 +136,7 @@ impl Handler for PreConnectHandler {
         }
         match client.state() {
             State::Connected => false,
-            State::Closing { .. } => false,
+            State::Closing { .. } | State::Draining { .. } => false,
             _ => true,
         }
     }
@@ -242,7 +242,7 @@ fn process_loop_h3(
     let timer = Timer::new(timeout);
 
     loop {
-        if let State::Closed(..) = handler.h3.conn().state() {
+        if let State::Closed { .. } = handler.h3.conn().state() {
             return Ok(handler.h3.conn().state().clone());
         }
 
@@ -288,7 +288,7 @@ impl H3Handler {
         self.h3.process_http3(Instant::now());
         for event in self.h3.events() {
             match event {
-                Http3Event::HeaderReady { stream_id } => {
+                Http3Event::HeaderReady { stream_id, .. } => {
                     if !self.streams.contains(&stream_id) {
                         eprintln!("Data on unexpected stream: {}", stream_id);
                         return false;
@@ -478,7 +478,7 @@ impl Handler for VnHandler {
     fn handle(&mut self, client: &mut Connection) -> bool {
         match client.state() {
             State::Connected => false,
-            State::Closing { .. } => false,
+            State::Closing { .. } | State::Draining { .. } => false,
             _ => true,
         }
     }
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use neqo_qpack::encoder::QPackEncoder;
+use neqo_qpack::qpack_helper::BufWrapper;
+
+// Decoder instructions are read by the encoder.
+fuzz_target!(|data: &[u8]| {
+    let mut encoder = QPackEncoder::new(true);
+    let mut reader = BufWrapper {
+        buf: data,
+        offset: 0,
+    };
+    let _ = encoder.read_instructions(&mut reader);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::qpack_helper::BufWrapper;
+
+// Encoder instructions are read by the decoder.
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = QPackDecoder::new(300, 100);
+    let mut reader = BufWrapper {
+        buf: data,
+        offset: 0,
+    };
+    let _ = decoder.read_instructions(&mut reader);
+});
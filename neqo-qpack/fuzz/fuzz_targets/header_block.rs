@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use neqo_qpack::decoder::QPackDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = QPackDecoder::new(300, 100);
+    let _ = decoder.decode_header_block(data, 0);
+});
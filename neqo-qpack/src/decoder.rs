@@ -7,13 +7,14 @@
 #![allow(unused_variables, dead_code)]
 use crate::huffman::Huffman;
 use crate::qpack_helper::{
-    read_prefixed_encoded_int_slice, read_prefixed_encoded_int_with_connection, BufWrapper,
+    read_prefixed_encoded_int_slice, read_prefixed_encoded_int_with_reader, BufWrapper,
+    InstructionReader, StreamReader,
 };
 use crate::qpack_send_buf::QPData;
 use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::qdebug;
+use neqo_common::{qdebug, Redact};
 use neqo_transport::Connection;
 use std::{mem, str};
 
@@ -117,7 +118,7 @@ impl QPackDecoder {
 
     // returns a list of unblocked streams
     pub fn receive(&mut self, conn: &mut Connection, stream_id: u64) -> Res<Vec<u64>> {
-        self.read_instructions(conn, stream_id)?;
+        self.read_instructions(&mut StreamReader::new(conn, stream_id))?;
         let base = self.table.base();
         let r = self
             .blocked_streams
@@ -129,16 +130,17 @@ impl QPackDecoder {
         Ok(r)
     }
 
+    /// Read and act on encoder instructions until `reader` runs out of data.
     #[allow(clippy::cognitive_complexity)]
     #[allow(clippy::useless_let_if_seq)]
-    fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+    pub fn read_instructions(&mut self, reader: &mut dyn InstructionReader) -> Res<()> {
         let label = self.to_string();
         qdebug!([self] "reading instructions");
         loop {
             match self.state {
                 QPackDecoderState::ReadInstruction => {
                     let mut b = [0; 1];
-                    match conn.stream_recv(stream_id, &mut b) {
+                    match reader.read_data(&mut b) {
                         Err(_) => break Err(Error::DecoderStreamError),
                         Ok((amount, fin)) => {
                             if fin {
@@ -156,8 +158,8 @@ impl QPackDecoder {
                         let static_t = (b[0] & 0x40) != 0;
                         let mut v: u64 = 0;
                         let mut cnt: u8 = 0;
-                        let name_done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 2, b[0], true,
+                        let name_done = read_prefixed_encoded_int_with_reader_wrap(
+                            reader, &mut v, &mut cnt, 2, b[0], true,
                         )?;
                         self.state = QPackDecoderState::InsertWithNameRef {
                             name_index: v,
@@ -179,8 +181,8 @@ impl QPackDecoder {
                         let huffman = (b[0] & 0x20) != 0;
                         let mut v: u64 = 0;
                         let mut cnt: u8 = 0;
-                        let name_done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
+                        let name_done = read_prefixed_encoded_int_with_reader_wrap(
+                            reader, &mut v, &mut cnt, 3, b[0], true,
                         )?;
                         self.state = QPackDecoderState::InsertWithoutNameRef {
                             name: if name_done {
@@ -205,8 +207,8 @@ impl QPackDecoder {
                         // Duplicate
                         let mut v: u64 = 0;
                         let mut cnt: u8 = 0;
-                        let done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
+                        let done = read_prefixed_encoded_int_with_reader_wrap(
+                            reader, &mut v, &mut cnt, 3, b[0], true,
                         )?;
                         if done {
                             qdebug!([label] "received instruction - duplicate index={}", v);
@@ -223,8 +225,8 @@ impl QPackDecoder {
                         // Set Dynamic Table Capacity
                         let mut v: u64 = 0;
                         let mut cnt: u8 = 0;
-                        let done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
+                        let done = read_prefixed_encoded_int_with_reader_wrap(
+                            reader, &mut v, &mut cnt, 3, b[0], true,
                         )?;
                         if done {
                             self.set_capacity(v)?;
@@ -245,8 +247,8 @@ impl QPackDecoder {
                 } => {
                     match state {
                         QPackWithRefState::GetName { ref mut cnt } => {
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn, stream_id, name_index, cnt, 0, 0x0, false,
+                            let done = read_prefixed_encoded_int_with_reader_wrap(
+                                reader, name_index, cnt, 0, 0x0, false,
                             )?;
                             if !done {
                                 // waiting for more data
@@ -261,7 +263,7 @@ impl QPackDecoder {
                             let mut b = [0; 1];
                             let mut prefix_len = 0;
                             if *cnt == 0 {
-                                match conn.stream_recv(stream_id, &mut b) {
+                                match reader.read_data(&mut b) {
                                     Err(_) => break Err(Error::DecoderStreamError),
                                     Ok((amount, fin)) => {
                                         if fin {
@@ -276,9 +278,8 @@ impl QPackDecoder {
                                 prefix_len = 1;
                                 *value_is_huffman = b[0] & 0x80 != 0;
                             }
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn,
-                                stream_id,
+                            let done = read_prefixed_encoded_int_with_reader_wrap(
+                                reader,
                                 len,
                                 cnt,
                                 prefix_len,
@@ -293,7 +294,7 @@ impl QPackDecoder {
                             *state = QPackWithRefState::GetValue { offset: 0 };
                         }
                         QPackWithRefState::GetValue { ref mut offset } => {
-                            match conn.stream_recv(stream_id, &mut value[*offset..]) {
+                            match reader.read_data(&mut value[*offset..]) {
                                 Err(_) => break Err(Error::DecoderStreamError),
                                 Ok((amount, fin)) => {
                                     if fin {
@@ -310,7 +311,7 @@ impl QPackDecoder {
                                 } else {
                                     mem::swap(&mut value_to_insert, value);
                                 }
-                                qdebug!([label] "received instruction - insert with name ref index={} static={} value={:x?}", name_index, name_static_table, value_to_insert);
+                                qdebug!([label] "received instruction - insert with name ref index={} static={} value={:x?}", name_index, name_static_table, Redact(&value_to_insert));
                                 self.table.insert_with_name_ref(
                                     *name_static_table,
                                     *name_index,
@@ -339,8 +340,8 @@ impl QPackDecoder {
                             ref mut len,
                             ref mut cnt,
                         } => {
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn, stream_id, len, cnt, 0, 0x0, false,
+                            let done = read_prefixed_encoded_int_with_reader_wrap(
+                                reader, len, cnt, 0, 0x0, false,
                             )?;
                             if !done {
                                 // waiting for more data
@@ -350,7 +351,7 @@ impl QPackDecoder {
                             *state = QPackWithoutRefState::GetName { offset: 0 };
                         }
                         QPackWithoutRefState::GetName { offset } => {
-                            match conn.stream_recv(stream_id, &mut name[*offset..]) {
+                            match reader.read_data(&mut name[*offset..]) {
                                 Err(_) => break Err(Error::DecoderStreamError),
                                 Ok((amount, fin)) => {
                                     if fin {
@@ -374,7 +375,7 @@ impl QPackDecoder {
                             let mut b = [0; 1];
                             let mut prefix_len = 0;
                             if *cnt == 0 {
-                                match conn.stream_recv(stream_id, &mut b) {
+                                match reader.read_data(&mut b) {
                                     Err(_) => break Err(Error::DecoderStreamError),
                                     Ok((amount, fin)) => {
                                         if fin {
@@ -389,9 +390,8 @@ impl QPackDecoder {
                                 prefix_len = 1;
                                 *value_is_huffman = b[0] & 0x80 != 0;
                             }
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn,
-                                stream_id,
+                            let done = read_prefixed_encoded_int_with_reader_wrap(
+                                reader,
                                 len,
                                 cnt,
                                 prefix_len,
@@ -406,7 +406,7 @@ impl QPackDecoder {
                             *state = QPackWithoutRefState::GetValue { offset: 0 };
                         }
                         QPackWithoutRefState::GetValue { ref mut offset } => {
-                            match conn.stream_recv(stream_id, &mut value[*offset..]) {
+                            match reader.read_data(&mut value[*offset..]) {
                                 Err(_) => break Err(Error::DecoderStreamError),
                                 Ok((amount, fin)) => {
                                     if fin {
@@ -430,7 +430,7 @@ impl QPackDecoder {
                                 } else {
                                     mem::swap(&mut value_to_insert, value);
                                 }
-                                qdebug!([label] "received instruction - insert with name literal name={:x?} value={:x?}", name_to_insert, value_to_insert);
+                                qdebug!([label] "received instruction - insert with name literal name={:x?} value={:x?}", name_to_insert, Redact(&value_to_insert));
                                 self.table.insert(name_to_insert, value_to_insert)?;
                                 self.total_num_of_inserts += 1;
                                 self.increment += 1;
@@ -446,8 +446,8 @@ impl QPackDecoder {
                     ref mut index,
                     ref mut cnt,
                 } => {
-                    let done = read_prefixed_encoded_int_with_connection_wrap(
-                        conn, stream_id, index, cnt, 0, 0x0, false,
+                    let done = read_prefixed_encoded_int_with_reader_wrap(
+                        reader, index, cnt, 0, 0x0, false,
                     )?;
                     if done {
                         qdebug!([label] "received instruction - duplicate index={}", index);
@@ -464,8 +464,8 @@ impl QPackDecoder {
                     ref mut capacity,
                     ref mut cnt,
                 } => {
-                    let done = read_prefixed_encoded_int_with_connection_wrap(
-                        conn, stream_id, capacity, cnt, 0, 0x0, false,
+                    let done = read_prefixed_encoded_int_with_reader_wrap(
+                        reader, capacity, cnt, 0, 0x0, false,
                     )?;
                     if done {
                         let v = *capacity;
@@ -489,14 +489,26 @@ impl QPackDecoder {
         Ok(())
     }
 
-    fn header_ack(&mut self, stream_id: u64) {
+    fn header_ack(&mut self, stream_id: u64, req_inserts: u64) {
         self.send_buf
             .encode_prefixed_encoded_int(0x80, 1, stream_id);
+        // The ack tells the encoder about every insert that the block needed,
+        // so those don't go in the next insert count increment.
+        let known = self.total_num_of_inserts - self.increment;
+        if req_inserts > known {
+            self.increment -= req_inserts - known;
+        }
     }
 
+    /// Forget a stream that was reset or that won't be read any more.  The
+    /// encoder is told, so that it can stop counting references from the
+    /// stream, unless the dynamic table isn't used.
     pub fn cancel_stream(&mut self, stream_id: u64) {
-        self.send_buf
-            .encode_prefixed_encoded_int(0x40, 1, stream_id);
+        self.blocked_streams.retain(|(id, _)| *id != stream_id);
+        if self.table.capacity() > 0 {
+            self.send_buf
+                .encode_prefixed_encoded_int(0x40, 1, stream_id);
+        }
     }
 
     pub fn send(&mut self, conn: &mut Connection) -> Res<()> {
@@ -553,7 +565,7 @@ impl QPackDecoder {
             if reader.done() {
                 // Send header_ack
                 if req_inserts != 0 {
-                    self.header_ack(stream_id);
+                    self.header_ack(stream_id, req_inserts);
                 }
                 qdebug!([self] "done decoding header block.");
                 break Ok(Some(h));
@@ -674,7 +686,7 @@ impl QPackDecoder {
             buf.slice(value_len)?.to_vec()
         };
 
-        qdebug!([self] "name={:x?} value={:x?}.", name, value);
+        qdebug!([self] "name={:x?} value={:x?}.", name, Redact(&value));
         Ok((to_string(&name)?, to_string(&value)?))
     }
 
@@ -700,7 +712,7 @@ impl QPackDecoder {
             buf.slice(value_len)?.to_vec()
         };
 
-        qdebug!([self] "name={:x?} value={:x?}.", name, value);
+        qdebug!([self] "name={:x?} value={:x?}.", name, Redact(&value));
         Ok((to_string(&name)?, to_string(&value)?))
     }
 
@@ -744,6 +756,14 @@ impl QPackDecoder {
             .write_byte(QPACK_UNI_STREAM_TYPE_DECODER as u8);
     }
 
+    /// Forget the local stream, which the transport discards if the server
+    /// rejects 0-RTT.  Nothing is written to it before the peer's settings
+    /// arrive, so a new stream can be added with `add_send_stream()`.
+    pub fn reset_send_stream(&mut self) {
+        self.local_stream_id = None;
+        self.send_buf = QPData::default();
+    }
+
     pub fn add_recv_stream(&mut self, stream_id: u64) {
         if self.remote_stream_id.is_some() {
             panic!("Adding multiple remote streams");
@@ -762,19 +782,17 @@ impl ::std::fmt::Display for QPackDecoder {
     }
 }
 
-// this wraps read_prefixed_encoded_int_with_connection to return proper error.
-fn read_prefixed_encoded_int_with_connection_wrap(
-    conn: &mut Connection,
-    stream_id: u64,
+// this wraps read_prefixed_encoded_int_with_reader to return proper error.
+fn read_prefixed_encoded_int_with_reader_wrap(
+    reader: &mut dyn InstructionReader,
     val: &mut u64,
     cnt: &mut u8,
     prefix_len: u8,
     first_byte: u8,
     have_first_byte: bool,
 ) -> Res<bool> {
-    match read_prefixed_encoded_int_with_connection(
-        conn,
-        stream_id,
+    match read_prefixed_encoded_int_with_reader(
+        reader,
         val,
         cnt,
         prefix_len,
@@ -826,7 +844,7 @@ mod tests {
         let out = conn_s.process(None, now());
         conn_c.process(out.dgram(), now());
 
-        let res = decoder.read_instructions(&mut conn_c, recv_stream_id);
+        let res = decoder.read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id));
         assert_eq!(err.is_some(), res.is_err());
         if let Some(expected_err) = err {
             assert_eq!(expected_err, res.unwrap_err());
@@ -908,6 +926,25 @@ mod tests {
         );
     }
 
+    // Instructions can be read from a buffer and pick up where they stopped.
+    #[test]
+    fn test_recv_from_buffer() {
+        let mut decoder = QPackDecoder::new(300, 100);
+        let instruction = [0x3f, 0xa9, 0x01];
+        let mut first = BufWrapper {
+            buf: &instruction[..2],
+            offset: 0,
+        };
+        assert!(decoder.read_instructions(&mut first).is_ok());
+        assert_eq!(decoder.capacity(), 0);
+        let mut rest = BufWrapper {
+            buf: &instruction[2..],
+            offset: 0,
+        };
+        assert!(decoder.read_instructions(&mut rest).is_ok());
+        assert_eq!(decoder.capacity(), 200);
+    }
+
     // this test tests header decoding, the header acks command and the insert count increment command.
     #[test]
     fn test_duplicate() {
@@ -926,7 +963,7 @@ mod tests {
         let out = conn_s.process(None, now());
         conn_c.process(out.dgram(), now());
         assert!(decoder
-            .read_instructions(&mut conn_c, recv_stream_id)
+            .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
             .is_ok());
 
         // send the second instruction, a duplicate instruction.
@@ -934,7 +971,7 @@ mod tests {
         let out = conn_s.process(None, now());
         conn_c.process(out.dgram(), now());
         if decoder
-            .read_instructions(&mut conn_c, recv_stream_id)
+            .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
             .is_err()
         {
             panic!("failed to read")
@@ -1031,7 +1068,7 @@ mod tests {
                 let out = conn_s.process(None, now());
                 conn_c.process(out.dgram(), now());
                 assert!(decoder
-                    .read_instructions(&mut conn_c, recv_stream_id)
+                    .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
                     .is_ok());
             }
 
@@ -1042,7 +1079,7 @@ mod tests {
             assert_eq!(h, t.headers);
         }
 
-        // test header acks and the insert count increment command
+        // test header acks, which make the insert count increment unnecessary
         decoder.send(&mut conn_c).unwrap();
         let out = conn_c.process(None, now());
         conn_s.process(out.dgram(), now());
@@ -1053,7 +1090,7 @@ mod tests {
                 let mut buf = [0u8; 100];
                 let (amount, fin) = conn_s.stream_recv(stream_id, &mut buf).unwrap();
                 assert_eq!(fin, false);
-                assert_eq!(buf[..amount], [0x03, 0x82, 0x83, 0x84, 0x1]);
+                assert_eq!(buf[..amount], [0x03, 0x82, 0x83, 0x84]);
                 found_instruction = true;
             }
         }
@@ -1128,7 +1165,7 @@ mod tests {
                 conn_c.process(out.dgram(), now());
                 // read the instruction.
                 assert!(decoder
-                    .read_instructions(&mut conn_c, recv_stream_id)
+                    .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
                     .is_ok());
             }
 
@@ -1138,7 +1175,7 @@ mod tests {
             assert_eq!(headers.unwrap(), t.headers);
         }
 
-        // test header acks and the insert count increment command
+        // test header acks, which make the insert count increment unnecessary
         decoder.send(&mut conn_c).unwrap();
         let out = conn_c.process(None, now());
         conn_s.process(out.dgram(), now());
@@ -1149,10 +1186,28 @@ mod tests {
                 let mut buf = [0u8; 100];
                 let (amount, fin) = conn_s.stream_recv(stream_id, &mut buf).unwrap();
                 assert_eq!(fin, false);
-                assert_eq!(buf[..amount], [0x03, 0x82, 0x83, 0x84, 0x1]);
+                assert_eq!(buf[..amount], [0x03, 0x82, 0x83, 0x84]);
                 found_instruction = true;
             }
         }
         assert!(found_instruction);
     }
+
+    #[test]
+    fn test_cancel_blocked_stream() {
+        let mut decoder = QPackDecoder::new(300, 100);
+        assert!(decoder.set_capacity(200).is_ok());
+        // A header block that needs one insert that hasn't arrived.
+        assert_eq!(decoder.decode_header_block(&[0x02, 0x00], 0), Ok(None));
+        assert_eq!(decoder.blocked_streams.len(), 1);
+        let sent = decoder.send_buf.len();
+        decoder.cancel_stream(0);
+        assert!(decoder.blocked_streams.is_empty());
+        assert_eq!(decoder.send_buf[sent..], [0x40]);
+
+        // Without a dynamic table there is nothing to tell the encoder.
+        let mut decoder = QPackDecoder::new(300, 100);
+        decoder.cancel_stream(0);
+        assert_eq!(decoder.send_buf.len(), 0);
+    }
 }
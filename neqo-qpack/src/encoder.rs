@@ -7,16 +7,23 @@
 #![allow(unused_variables, dead_code)]
 
 use crate::huffman::encode_huffman;
-use crate::qpack_helper::read_prefixed_encoded_int_with_connection;
+use crate::qpack_helper::{read_prefixed_encoded_int_with_reader, InstructionReader, StreamReader};
 use crate::qpack_send_buf::QPData;
+use crate::static_table::HEADER_STATIC_TABLE;
 use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::{qdebug, qtrace};
+use neqo_common::{qdebug, qtrace, Redact};
 use neqo_transport::Connection;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 pub const QPACK_UNI_STREAM_TYPE_ENCODER: u64 = 0x2;
 
+/// Headers that carry credentials.  These are never added to the dynamic
+/// table, so that their values can't be found by watching how well other
+/// headers compress.
+const NEVER_INDEXED_HEADERS: &[&str] = &["authorization", "cookie", "set-cookie"];
+
 #[derive(Debug)]
 enum DecoderInstructions {
     InsertCountIncrement,
@@ -34,6 +41,37 @@ fn get_instruction(b: u8) -> DecoderInstructions {
     }
 }
 
+/// Counters for how well the dynamic table works, to help with picking limits
+/// with `QPackEncoder::set_capacity_limit()` and
+/// `QPackEncoder::set_blocked_streams_limit()`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct QPackEncoderStats {
+    /// Header fields that were encoded.
+    pub headers: u64,
+    /// Header fields that referred to the static table.
+    pub static_table_hits: u64,
+    /// Header fields that referred to the dynamic table.
+    pub dynamic_table_hits: u64,
+    /// Entries added to the dynamic table.
+    pub inserts: u64,
+    /// Entries evicted from the dynamic table.
+    pub evictions: u64,
+    /// Header fields that were sent as literals because using the dynamic
+    /// table would have blocked one stream too many.
+    pub blocked_stream_stalls: u64,
+}
+
+impl QPackEncoderStats {
+    /// The share of header fields that referred to the dynamic table.
+    pub fn dynamic_table_hit_rate(&self) -> f64 {
+        if self.headers == 0 {
+            0.0
+        } else {
+            self.dynamic_table_hits as f64 / self.headers as f64
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct QPackEncoder {
     table: HeaderTable,
@@ -45,8 +83,16 @@ pub struct QPackEncoder {
     local_stream_id: Option<u64>,
     remote_stream_id: Option<u64>,
     max_blocked_streams: u16,
-    blocked_streams: Vec<u64>, // remember request insert counds for blocked streams.
-    // TODO we may also remember stream_id and use stream acks as indication that a stream has beed unblocked.
+    // Limits that this endpoint sets on top of those from the decoder.
+    capacity_limit: u64,
+    blocked_streams_limit: u16,
+    stats: QPackEncoderStats,
+    // Names of headers that are always sent as never-indexed literals.
+    never_indexed: HashSet<Vec<u8>>,
+    // The required insert counts of header blocks that the decoder hasn't
+    // acknowledged yet, oldest first, for each stream.  A stream is blocked
+    // while any of these is above the acknowledged insert count.
+    unacked_blocks: HashMap<u64, VecDeque<u64>>,
     use_huffman: bool,
 }
 
@@ -62,7 +108,14 @@ impl QPackEncoder {
             local_stream_id: None,
             remote_stream_id: None,
             max_blocked_streams: 0,
-            blocked_streams: Vec::new(),
+            capacity_limit: std::u64::MAX,
+            blocked_streams_limit: std::u16::MAX,
+            stats: QPackEncoderStats::default(),
+            never_indexed: NEVER_INDEXED_HEADERS
+                .iter()
+                .map(|n| n.as_bytes().to_vec())
+                .collect(),
+            unacked_blocks: HashMap::new(),
             use_huffman,
         }
     }
@@ -74,11 +127,42 @@ impl QPackEncoder {
         }
         qdebug!([self] "Set max capacity to {}.", cap);
         self.max_entries = (cap as f64 / 32.0).floor() as u64;
-        // we also set our table to the max allowed. TODO we may not want to use max allowed.
-        self.change_capacity(cap);
+        // Use as much of the table as allowed.
+        self.change_capacity(std::cmp::min(cap, self.capacity_limit));
         Ok(())
     }
 
+    /// Use no more than `cap` bytes of the decoder's dynamic table, even if
+    /// the decoder allows more.  This takes effect when the decoder's maximum
+    /// arrives, so it needs to be set before that.
+    pub fn set_capacity_limit(&mut self, cap: u64) {
+        self.capacity_limit = cap;
+    }
+
+    /// Let no more than `blocked_streams` streams wait for dynamic table
+    /// inserts, even if the decoder allows more.  With 0, header blocks only
+    /// refer to entries that the decoder has acknowledged, so they never
+    /// block.
+    pub fn set_blocked_streams_limit(&mut self, blocked_streams: u16) {
+        self.blocked_streams_limit = blocked_streams;
+    }
+
+    /// Always send headers called `name` as literals that intermediaries must
+    /// not index either, as is done for authorization, cookie and
+    /// set-cookie.
+    pub fn never_index(&mut self, name: &str) {
+        self.never_indexed
+            .insert(name.to_ascii_lowercase().into_bytes());
+    }
+
+    pub fn stats(&self) -> QPackEncoderStats {
+        QPackEncoderStats {
+            inserts: self.table.base(),
+            evictions: self.table.evicted(),
+            ..self.stats
+        }
+    }
+
     pub fn set_max_blocked_streams(&mut self, blocked_streams: u64) -> Res<()> {
         if blocked_streams > (1 << 16) - 1 {
             return Err(Error::EncoderStreamError);
@@ -92,7 +176,7 @@ impl QPackEncoder {
         match self.remote_stream_id {
             Some(id) => {
                 if id == stream_id {
-                    self.read_instructions(conn, stream_id)?;
+                    self.read_instructions(&mut StreamReader::new(conn, stream_id))?;
                     Ok(true)
                 } else {
                     Ok(false)
@@ -102,14 +186,15 @@ impl QPackEncoder {
         }
     }
 
-    fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+    /// Read and act on decoder instructions until `reader` runs out of data.
+    pub fn read_instructions(&mut self, reader: &mut dyn InstructionReader) -> Res<()> {
         qdebug!([self] "read a new instraction");
         loop {
             match self.instruction_reader_current_inst {
                 None => {
                     // get new instruction
                     let mut b = [0];
-                    match conn.stream_recv(stream_id, &mut b) {
+                    match reader.read_data(&mut b) {
                         Err(_) => break Err(Error::EncoderStreamError),
                         Ok((amount, fin)) => {
                             if fin {
@@ -125,9 +210,8 @@ impl QPackEncoder {
 
                     // try to read data
                     let prefix_len = if (b[0] & 0x80) != 0 { 1 } else { 2 };
-                    match read_prefixed_encoded_int_with_connection(
-                        conn,
-                        stream_id,
+                    match read_prefixed_encoded_int_with_reader(
+                        reader,
                         &mut self.instruction_reader_value,
                         &mut self.instruction_reader_cnt,
                         prefix_len,
@@ -136,7 +220,9 @@ impl QPackEncoder {
                     ) {
                         Ok(done) => {
                             if done {
-                                self.call_instruction();
+                                if let Err(e) = self.call_instruction() {
+                                    break Err(e);
+                                }
                             } else {
                                 // wait for more data.
                                 break Ok(());
@@ -147,9 +233,8 @@ impl QPackEncoder {
                     }
                 }
                 Some(_) => {
-                    match read_prefixed_encoded_int_with_connection(
-                        conn,
-                        stream_id,
+                    match read_prefixed_encoded_int_with_reader(
+                        reader,
                         &mut self.instruction_reader_value,
                         &mut self.instruction_reader_cnt,
                         0,
@@ -158,7 +243,9 @@ impl QPackEncoder {
                     ) {
                         Ok(done) => {
                             if done {
-                                self.call_instruction();
+                                if let Err(e) = self.call_instruction() {
+                                    break Err(e);
+                                }
                             } else {
                                 // wait for more data.
                                 break Ok(());
@@ -172,27 +259,62 @@ impl QPackEncoder {
         }
     }
 
-    fn call_instruction(&mut self) {
-        if let Some(inst) = &self.instruction_reader_current_inst {
-            qdebug!([self] "call intruction {:?}", inst);
-            match inst {
-                DecoderInstructions::InsertCountIncrement => {
-                    self.table.increment_acked(self.instruction_reader_value);
-                    let inserts = self.table.get_acked_inserts_cnt();
-                    self.blocked_streams.retain(|req| *req <= inserts);
+    fn call_instruction(&mut self) -> Res<()> {
+        let inst = self
+            .instruction_reader_current_inst
+            .take()
+            .expect("We must have a instruction decoded beforewe call call_instruction");
+        let value = self.instruction_reader_value;
+        self.instruction_reader_value = 0;
+        self.instruction_reader_cnt = 0;
+        qdebug!([self] "call intruction {:?} value={}", inst, value);
+        match inst {
+            DecoderInstructions::InsertCountIncrement => {
+                let acked = self.table.get_acked_inserts_cnt();
+                if value == 0 || value > self.table.base() - acked {
+                    return Err(Error::DecoderStreamError);
                 }
-                DecoderInstructions::HeaderAck => {
-                    self.table.header_ack(self.instruction_reader_value)
+                self.table.increment_acked(value);
+            }
+            DecoderInstructions::HeaderAck => {
+                let req_insert_cnt = match self.unacked_blocks.get_mut(&value) {
+                    Some(blocks) => blocks.pop_front(),
+                    None => None,
+                };
+                let req_insert_cnt = req_insert_cnt.ok_or(Error::DecoderStreamError)?;
+                // The decoder has every insert that the block needed.
+                let acked = self.table.get_acked_inserts_cnt();
+                if req_insert_cnt > acked {
+                    self.table.increment_acked(req_insert_cnt - acked);
                 }
-                DecoderInstructions::StreamCancellation => {
-                    self.table.header_ack(self.instruction_reader_value)
+                if self.unacked_blocks[&value].is_empty() {
+                    self.unacked_blocks.remove(&value);
+                    self.table.header_ack(value);
                 }
             }
-            self.instruction_reader_current_inst = None;
-            self.instruction_reader_value = 0;
-            self.instruction_reader_cnt = 0;
-        } else {
-            panic!("We must have a instruction decoded beforewe call call_instruction");
+            DecoderInstructions::StreamCancellation => {
+                self.unacked_blocks.remove(&value);
+                self.table.header_ack(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of streams with header blocks that need inserts the decoder
+    /// hasn't acknowledged.
+    fn blocked_stream_cnt(&self) -> usize {
+        let acked = self.table.get_acked_inserts_cnt();
+        self.unacked_blocks
+            .values()
+            .filter(|blocks| blocks.iter().any(|req| *req > acked))
+            .count()
+    }
+
+    fn is_stream_blocked(&self, stream_id: u64) -> bool {
+        let acked = self.table.get_acked_inserts_cnt();
+        match self.unacked_blocks.get(&stream_id) {
+            Some(blocks) => blocks.iter().any(|req| *req > acked),
+            None => false,
         }
     }
 
@@ -226,7 +348,7 @@ impl QPackEncoder {
     }
 
     pub fn insert_with_name_literal(&mut self, name: Vec<u8>, value: Vec<u8>) -> Res<()> {
-        qdebug!([self] "insert name {:x?}, value={:x?}.", name, value);
+        qdebug!([self] "insert name {:x?}, value={:x?}.", name, Redact(&value));
         // try to insert a new entry
         self.table.insert(name, value)?;
 
@@ -273,18 +395,28 @@ impl QPackEncoder {
         let mut encoded_h = QPData::default();
         let base = self.table.base();
         let mut req_insert_cnt = 0;
-        self.encode_header_block_prefix(&mut encoded_h, false, 0, base, true);
+        // A stream that is already blocked can refer to any entry, otherwise
+        // this block can only wait for inserts if that doesn't take the
+        // number of blocked streams over the limit.
+        let max_blocked_streams =
+            std::cmp::min(self.max_blocked_streams, self.blocked_streams_limit);
+        let can_be_blocked = self.is_stream_blocked(stream_id)
+            || self.blocked_stream_cnt() < max_blocked_streams as usize;
         for iter in h.iter() {
             let name = iter.0.clone().into_bytes();
             let value = iter.1.clone().into_bytes();
-            qtrace!("encoding {:x?} {:x?}.", name, value);
+            qtrace!("encoding {:x?} {:x?}.", name, Redact(&value));
+            self.stats.headers += 1;
+            if self.never_indexed.contains(&name) {
+                self.encode_never_indexed(&mut encoded_h, &name, &value);
+                continue;
+            }
 
             let mut can_use = false;
             let mut index: u64 = 0;
             let mut value_as_well = false;
             let mut is_dynamic = false;
             let acked_inserts_cnt = self.table.get_acked_inserts_cnt(); // we need to read it here because of borrowing problem.
-            let can_be_blocked = self.blocked_streams.len() < self.max_blocked_streams as usize;
             {
                 let label = self.to_string();
                 // this is done in this way because otherwise it is complaining about mut borrow. TODO: look if we can do this better
@@ -314,6 +446,11 @@ impl QPackEncoder {
                 }
             }
             if can_use {
+                if is_dynamic {
+                    self.stats.dynamic_table_hits += 1;
+                } else {
+                    self.stats.static_table_hits += 1;
+                }
                 if value_as_well {
                     if !is_dynamic {
                         self.encode_indexed(&mut encoded_h, true, index);
@@ -351,6 +488,15 @@ impl QPackEncoder {
                 }
             }
 
+            // A new entry can't be used before the decoder acknowledges it, so
+            // only insert one if this block is allowed to wait for it.
+            if !can_be_blocked {
+                if self.table.capacity() > 0 {
+                    self.stats.blocked_stream_stalls += 1;
+                }
+                self.encode_literal_with_name_literal(&mut encoded_h, &name, &value);
+                continue;
+            }
             let name2 = name.clone();
             let value2 = value.clone();
             match self.insert_with_name_literal(name2, value2) {
@@ -371,67 +517,56 @@ impl QPackEncoder {
             }
         }
         if req_insert_cnt > 0 {
-            self.fix_header_block_prefix(&mut encoded_h, base, req_insert_cnt);
+            self.unacked_blocks
+                .entry(stream_id)
+                .or_insert_with(VecDeque::new)
+                .push_back(req_insert_cnt);
         }
-        encoded_h
+
+        // The prefix is only known now, and it can take more than two bytes.
+        let mut block = QPData::default();
+        if req_insert_cnt == 0 {
+            self.encode_header_block_prefix(&mut block, 0, base, true);
+        } else if req_insert_cnt <= base {
+            self.encode_header_block_prefix(
+                &mut block,
+                req_insert_cnt,
+                base - req_insert_cnt,
+                true,
+            );
+        } else {
+            self.encode_header_block_prefix(
+                &mut block,
+                req_insert_cnt,
+                req_insert_cnt - base - 1,
+                false,
+            );
+        }
+        block.write_bytes(&encoded_h);
+        block
     }
 
     fn encode_header_block_prefix(
         &self,
         buf: &mut QPData,
-        fix: bool,
         req_insert_cnt: u64,
         delta: u64,
         positive: bool,
     ) {
         qdebug!(
             [self]
-            "encode header block prefix req_insert_cnt={} delta={} (fix={}).",
+            "encode header block prefix req_insert_cnt={} delta={}.",
             req_insert_cnt,
-            delta,
-            fix
+            delta
         );
         let enc_insert_cnt = if req_insert_cnt != 0 {
             (req_insert_cnt % (2 * self.max_entries)) + 1
         } else {
             0
         };
-
-        let mut offset = 0; // this is for fixing header_block only.
-        if !fix {
-            buf.encode_prefixed_encoded_int(0x0, 0, enc_insert_cnt);
-        } else {
-            // TODO fix for case when there is no enough space!!!
-            offset = buf.encode_prefixed_encoded_int_fix(0, 0x0, 0, enc_insert_cnt);
-        }
+        buf.encode_prefixed_encoded_int(0x0, 0, enc_insert_cnt);
         let prefix = if positive { 0x00 } else { 0x80 };
-        if !fix {
-            buf.encode_prefixed_encoded_int(prefix, 1, delta);
-        } else {
-            let _ = buf.encode_prefixed_encoded_int_fix(offset, prefix, 1, delta);
-        }
-    }
-
-    fn fix_header_block_prefix(&self, buf: &mut QPData, base: u64, req_insert_cnt: u64) {
-        if req_insert_cnt > 0 {
-            if req_insert_cnt <= base {
-                self.encode_header_block_prefix(
-                    buf,
-                    true,
-                    req_insert_cnt,
-                    base - req_insert_cnt,
-                    true,
-                );
-            } else {
-                self.encode_header_block_prefix(
-                    buf,
-                    true,
-                    req_insert_cnt,
-                    req_insert_cnt - base - 1,
-                    false,
-                );
-            }
-        }
+        buf.encode_prefixed_encoded_int(prefix, 1, delta);
     }
 
     fn encode_indexed(&self, buf: &mut QPData, is_static: bool, index: u64) {
@@ -459,6 +594,20 @@ impl QPackEncoder {
         encode_literal(self.use_huffman, buf, 0x0, 0, value);
     }
 
+    // A literal with the N bit set, referring to the static table for the
+    // name if it is there.
+    fn encode_never_indexed(&mut self, buf: &mut QPData, name: &[u8], value: &[u8]) {
+        qdebug!([self] "encode never indexed literal - name={:x?}.", name);
+        match HEADER_STATIC_TABLE.iter().find(|e| e.name() == name) {
+            Some(entry) => {
+                self.stats.static_table_hits += 1;
+                buf.encode_prefixed_encoded_int(0x70, 4, entry.index());
+            }
+            None => encode_literal(self.use_huffman, buf, 0x30, 4, name),
+        }
+        encode_literal(self.use_huffman, buf, 0x0, 0, value);
+    }
+
     fn encode_post_base_index(&self, buf: &mut QPData, index: u64) {
         qdebug!([self] "encode post base index {}.", index);
         buf.encode_prefixed_encoded_int(0x10, 4, index);
@@ -495,6 +644,14 @@ impl QPackEncoder {
             .write_byte(QPACK_UNI_STREAM_TYPE_ENCODER as u8);
     }
 
+    /// Forget the local stream, which the transport discards if the server
+    /// rejects 0-RTT.  Nothing is written to it before the peer's settings
+    /// arrive, so a new stream can be added with `add_send_stream()`.
+    pub fn reset_send_stream(&mut self) {
+        self.local_stream_id = None;
+        self.send_buf = QPData::default();
+    }
+
     pub fn add_recv_stream(&mut self, stream_id: u64) {
         if self.remote_stream_id.is_some() {
             panic!("Adding multiple remote streams");
@@ -507,9 +664,15 @@ impl QPackEncoder {
     }
 }
 
+// Huffman coding only makes some strings shorter, so the literal is sent as
+// it is when that is shorter.
 fn encode_literal(use_huffman: bool, buf: &mut QPData, prefix: u8, prefix_len: u8, value: &[u8]) {
-    if use_huffman {
-        let encoded = encode_huffman(value);
+    let encoded = if use_huffman {
+        Some(encode_huffman(value))
+    } else {
+        None
+    };
+    if let Some(encoded) = encoded.filter(|e| e.len() <= value.len()) {
         buf.encode_prefixed_encoded_int(
             prefix | (0x80 >> prefix_len),
             prefix_len + 1,
@@ -978,7 +1141,7 @@ mod tests {
         let out = conn_s.process(None, now());
         conn_c.process(out.dgram(), now());
         assert!(encoder
-            .read_instructions(&mut conn_c, recv_stream_id)
+            .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
             .is_ok());
 
         // insert "content-length: 12345 again it will succeed.
@@ -1045,7 +1208,7 @@ mod tests {
         let out = conn_s.process(None, now());
         conn_c.process(out.dgram(), now());
         assert!(encoder
-            .read_instructions(&mut conn_c, recv_stream_id)
+            .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
             .is_ok());
 
         // send a header block
@@ -1090,7 +1253,7 @@ mod tests {
             conn_c.process(out.dgram(), now());
         }
         assert!(encoder
-            .read_instructions(&mut conn_c, recv_stream_id)
+            .read_instructions(&mut StreamReader::new(&mut conn_c, recv_stream_id))
             .is_ok());
 
         // insert "content-length: 12345 again it will succeed.
@@ -1123,4 +1286,199 @@ mod tests {
     fn test_stream_canceled() {
         test_insertion_blocked_on_waiting_forheader_ack_or_stream_cancel(1);
     }
+
+    fn recv_instruction(
+        encoder: &mut QPackEncoder,
+        conn_c: &mut Connection,
+        conn_s: &mut Connection,
+        recv_stream_id: u64,
+        decoder_instruction: &[u8],
+    ) -> Res<()> {
+        conn_s
+            .stream_send(recv_stream_id, decoder_instruction)
+            .unwrap();
+        let out = conn_s.process(None, now());
+        conn_c.process(out.dgram(), now());
+        encoder.read_instructions(&mut StreamReader::new(conn_c, recv_stream_id))
+    }
+
+    // Only one stream can wait for inserts, so the second one gets literals
+    // until the decoder acknowledges the first.
+    #[test]
+    fn test_blocked_streams_limit() {
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, send_stream_id) = connect(false);
+
+        encoder.set_max_blocked_streams(1).unwrap();
+        encoder.set_max_capacity(200).unwrap();
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[0x02, 0x3f, 0xa9, 0x01],
+        );
+
+        let header = [(String::from("my-header"), String::from("my-value"))];
+        let buf = encoder.encode_header_block(&header, 1);
+        assert_eq!(&buf[..], &[0x02, 0x80, 0x10]);
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[
+                0x49, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x08, 0x6d, 0x79, 0x2d,
+                0x76, 0x61, 0x6c, 0x75, 0x65,
+            ],
+        );
+
+        let buf = encoder.encode_header_block(&header, 5);
+        assert_eq!(
+            &buf[..],
+            &[
+                0x00, 0x01, 0x27, 0x02, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x08,
+                0x6d, 0x79, 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65,
+            ]
+        );
+
+        // The blocked stream can still use the entry.
+        let buf = encoder.encode_header_block(&header, 1);
+        assert_eq!(&buf[..], &[0x02, 0x00, 0x80]);
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[],
+        );
+
+        // A header ack tells the encoder that the decoder has the entry.
+        assert!(recv_instruction(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            &[0x81]
+        )
+        .is_ok());
+        let buf = encoder.encode_header_block(&header, 5);
+        assert_eq!(&buf[..], &[0x02, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_bad_decoder_instructions() {
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, _) = connect(false);
+        encoder.set_max_blocked_streams(100).unwrap();
+        encoder.set_max_capacity(200).unwrap();
+        let header = [(String::from("my-header"), String::from("my-value"))];
+        let _ = encoder.encode_header_block(&header, 1);
+
+        // Only one insert can be acknowledged.
+        assert_eq!(
+            recv_instruction(
+                &mut encoder,
+                &mut conn_c,
+                &mut conn_s,
+                recv_stream_id,
+                &[0x02]
+            ),
+            Err(Error::DecoderStreamError)
+        );
+
+        // There is one header block to acknowledge on stream 1 and none on
+        // stream 5.
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, _) = connect(false);
+        encoder.set_max_blocked_streams(100).unwrap();
+        encoder.set_max_capacity(200).unwrap();
+        let _ = encoder.encode_header_block(&header, 1);
+        assert!(recv_instruction(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            &[0x81]
+        )
+        .is_ok());
+        assert_eq!(
+            recv_instruction(
+                &mut encoder,
+                &mut conn_c,
+                &mut conn_s,
+                recv_stream_id,
+                &[0x85]
+            ),
+            Err(Error::DecoderStreamError)
+        );
+    }
+
+    #[test]
+    fn test_huffman_only_when_shorter() {
+        let mut buf = QPData::default();
+        encode_literal(true, &mut buf, 0x0, 0, b"gzip");
+        assert_eq!(&buf[..], &[0x83, 0x9b, 0xd9, 0xab]);
+
+        let mut buf = QPData::default();
+        encode_literal(true, &mut buf, 0x0, 0, b"<?\\ >");
+        assert_eq!(&buf[..], &[0x05, 0x3c, 0x3f, 0x5c, 0x20, 0x3e]);
+    }
+
+    #[test]
+    fn test_capacity_limit() {
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, send_stream_id) = connect(false);
+
+        encoder.set_capacity_limit(50);
+        encoder.set_max_capacity(200).unwrap();
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[0x02, 0x3f, 0x13],
+        );
+    }
+
+    #[test]
+    fn test_never_indexed() {
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, send_stream_id) = connect(false);
+
+        encoder.set_max_blocked_streams(100).unwrap();
+        encoder.set_max_capacity(200).unwrap();
+        encoder.never_index("X-Token");
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[0x02, 0x3f, 0xa9, 0x01],
+        );
+
+        // The name of "authorization" is in the static table, "x-token" isn't.
+        let headers = vec![
+            (String::from("authorization"), String::from("secret")),
+            (String::from("x-token"), String::from("t")),
+        ];
+        for _ in 0..2 {
+            let buf = encoder.encode_header_block(&headers, 1);
+            assert_eq!(
+                &buf[..],
+                &[
+                    0x00, 0x00, 0x7f, 0x45, 0x06, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74, 0x37, 0x00,
+                    0x78, 0x2d, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0x01, 0x74,
+                ]
+            );
+            test_sent_instructions(
+                &mut encoder,
+                &mut conn_c,
+                &mut conn_s,
+                recv_stream_id,
+                send_stream_id,
+                &[],
+            );
+        }
+    }
 }
@@ -29,7 +29,7 @@ impl Huffman {
 
         while self.has_more_data(len, read) {
             if let Some(c) =
-                self.decode_huffman_character(HUFFMAN_DECODE_ROOT, input, len, &mut read)?
+                self.decode_huffman_character(HUFFMAN_DECODE_ROOT, false, input, len, &mut read)?
             {
                 output.push(c);
             }
@@ -77,9 +77,12 @@ impl Huffman {
         }
     }
 
+    // `nested` is set once a whole byte has gone into the current character.
+    // Padding can't be that long, so running out of input then is an error.
     fn decode_huffman_character(
         &mut self,
         table: &HuffmanDecodeTable,
+        nested: bool,
         input: &[u8],
         len: usize,
         read: &mut usize,
@@ -88,6 +91,9 @@ impl Huffman {
 
         if table.index_has_a_next_table(self.decoding_byte) {
             if !self.has_more_data(len, *read) {
+                if nested {
+                    return Err(Error::DecompressionFailed);
+                }
                 // This is the last bit and it is padding.
                 return Ok(None);
             }
@@ -95,6 +101,7 @@ impl Huffman {
             self.decoding_bits_left = 0;
             return self.decode_huffman_character(
                 table.next_table(self.decoding_byte),
+                true,
                 input,
                 len,
                 read,
@@ -108,6 +115,9 @@ impl Huffman {
 
         if entry.prefix_len > u16::from(self.decoding_bits_left) {
             assert!(!self.has_more_data(len, *read));
+            if nested {
+                return Err(Error::DecompressionFailed);
+            }
             // This is the last bit and it is padding.
             return Ok(None);
         }
@@ -250,4 +260,27 @@ mod tests {
             assert_eq!(res.unwrap()[..], *e.val);
         }
     }
+
+    #[test]
+    fn test_decoder_bad_padding() {
+        // "no-cache" with padding that isn't all ones.
+        assert_eq!(
+            Huffman::default().decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbe]),
+            Err(Error::DecompressionFailed)
+        );
+        // "no-cache" with more than 7 bits of padding.
+        assert_eq!(
+            Huffman::default().decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf, 0xff]),
+            Err(Error::DecompressionFailed)
+        );
+        assert_eq!(
+            Huffman::default().decode(&[0xff]),
+            Err(Error::DecompressionFailed)
+        );
+        // EOS can't be in a string.
+        assert_eq!(
+            Huffman::default().decode(&[0xff, 0xff, 0xff, 0xff]),
+            Err(Error::DecompressionFailed)
+        );
+    }
 }
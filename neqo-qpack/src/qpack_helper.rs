@@ -11,15 +11,40 @@ pub trait ReadByte {
     fn read_byte(&mut self) -> Res<u8>;
 }
 
-struct ReceiverWrapper<'a> {
+/// A source of QPACK instructions.  This is usually a unidirectional stream,
+/// but instructions can also be read from a buffer.
+pub trait InstructionReader {
+    /// Read into `buf`, returning the amount read and whether the end of the
+    /// stream was reached.
+    fn read_data(&mut self, buf: &mut [u8]) -> Res<(usize, bool)>;
+}
+
+/// Reads instructions from a stream on a connection.
+pub struct StreamReader<'a> {
     conn: &'a mut Connection,
     stream_id: u64,
 }
 
+impl<'a> StreamReader<'a> {
+    pub fn new(conn: &'a mut Connection, stream_id: u64) -> Self {
+        StreamReader { conn, stream_id }
+    }
+}
+
+impl<'a> InstructionReader for StreamReader<'a> {
+    fn read_data(&mut self, buf: &mut [u8]) -> Res<(usize, bool)> {
+        Ok(self.conn.stream_recv(self.stream_id, buf)?)
+    }
+}
+
+struct ReceiverWrapper<'a> {
+    reader: &'a mut dyn InstructionReader,
+}
+
 impl<'a> ReadByte for ReceiverWrapper<'a> {
     fn read_byte(&mut self) -> Res<u8> {
         let mut b = [0];
-        let (amount, fin) = self.conn.stream_recv(self.stream_id, &mut b)?;
+        let (amount, fin) = self.reader.read_data(&mut b)?;
         if fin {
             return Err(Error::ClosedCriticalStream);
         }
@@ -58,6 +83,16 @@ impl<'a> BufWrapper<'a> {
     }
 }
 
+/// A buffer never ends, it just runs out of data.
+impl<'a> InstructionReader for BufWrapper<'a> {
+    fn read_data(&mut self, buf: &mut [u8]) -> Res<(usize, bool)> {
+        let amount = ::std::cmp::min(buf.len(), self.buf.len() - self.offset);
+        buf[..amount].copy_from_slice(&self.buf[self.offset..self.offset + amount]);
+        self.offset += amount;
+        Ok((amount, false))
+    }
+}
+
 impl<'a> ReadByte for BufWrapper<'a> {
     fn read_byte(&mut self) -> Res<u8> {
         if self.offset == self.buf.len() {
@@ -70,16 +105,15 @@ impl<'a> ReadByte for BufWrapper<'a> {
     }
 }
 
-pub fn read_prefixed_encoded_int_with_connection(
-    conn: &mut Connection,
-    stream_id: u64,
+pub fn read_prefixed_encoded_int_with_reader(
+    reader: &mut dyn InstructionReader,
     val: &mut u64,
     cnt: &mut u8,
     prefix_len: u8,
     first_byte: u8,
     have_first_byte: bool,
 ) -> Res<bool> {
-    let mut recv = ReceiverWrapper { conn, stream_id };
+    let mut recv = ReceiverWrapper { reader };
     match read_prefixed_encoded_int(&mut recv, val, cnt, prefix_len, first_byte, have_first_byte) {
         Ok(()) => Ok(true),
         Err(Error::NoMoreData) => Ok(false),
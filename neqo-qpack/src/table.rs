@@ -58,6 +58,8 @@ pub struct HeaderTable {
     // The total number of inserts thus far.
     base: u64,
     acked_inserts_cnt: u64,
+    // The number of entries that were evicted.
+    evicted: u64,
 }
 
 impl HeaderTable {
@@ -73,6 +75,7 @@ impl HeaderTable {
             used: 0,
             base: 0,
             acked_inserts_cnt: if encoder { 0 } else { std::u64::MAX },
+            evicted: 0,
         }
     }
 
@@ -169,6 +172,7 @@ impl HeaderTable {
                 }
                 self.used -= e.size();
                 self.dynamic.pop_back();
+                self.evicted += 1;
             }
         }
         true
@@ -232,6 +236,10 @@ impl HeaderTable {
         self.acked_inserts_cnt
     }
 
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
     pub fn header_ack(&mut self, stream_id: u64) {
         for iter in self.dynamic.iter_mut() {
             iter.remove_ref(stream_id, 1);
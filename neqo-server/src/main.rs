@@ -7,27 +7,52 @@
 #![deny(warnings)]
 
 use neqo_common::Datagram;
-use neqo_crypto::{init_db, AntiReplay};
-use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State};
+use neqo_crypto::{init_db, AntiReplay, KeyLogFile, ZeroRttCheckResult, ZeroRttChecker};
+use neqo_transport::server::{RetryTokenKey, Server, WorkerConnectionIdManager};
+use neqo_transport::{Connection, ConnectionEvent, Output, State};
 use regex::Regex;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+mod selftest;
+#[cfg(feature = "txtime")]
+mod txtime;
+
+/// The length of connection IDs, including the byte that picks the worker.
+const CID_LEN: usize = 10;
+/// How often a worker checks for datagrams that other workers pass on.
+const FORWARD_INTERVAL: Duration = Duration::from_millis(5);
+/// How long a worker waits for a datagram when it has nothing else to do.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How far ahead of time datagrams are given to the kernel when it paces
+/// sending.
+#[cfg(feature = "txtime")]
+const PACING_HORIZON: Duration = Duration::from_millis(20);
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neqo-client", about = "A basic QUIC client.")]
 struct Args {
     #[structopt(short = "h", long)]
     /// Optional local address to bind to, defaults to the unspecified address.
     host: Option<String>,
+    #[structopt(required_unless = "self-test")]
     /// Port number.
-    port: u16,
+    port: Option<u16>,
 
     /// A resource to request.
     request: Vec<String>,
@@ -39,6 +64,10 @@ struct Args {
     /// Name of keys from NSS database.
     key: Vec<String>,
 
+    #[structopt(long)]
+    /// Use a different key for a server name, given as name=key.
+    sni: Vec<String>,
+
     #[structopt(short = "a", long, default_value = "http/0.9")]
     /// ALPN labels to negotiate.
     ///
@@ -51,9 +80,44 @@ struct Args {
     #[structopt(short = "6", long)]
     /// Restrict to IPv6.
     ipv6: bool,
+
+    #[structopt(long)]
+    /// Limit the rate at which each connection sends, in bytes per second.
+    rate: Option<u64>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Serve files from this directory.  Without this, a request for `/N`
+    /// gets N bytes in response.
+    www: Option<PathBuf>,
+
+    #[structopt(name = "zero-rtt", long = "zero-rtt")]
+    /// Accept 0-RTT from clients that resume.
+    zero_rtt: bool,
+
+    #[structopt(long)]
+    /// Send Retry to new clients, so that they have to prove their address.
+    retry: bool,
+
+    #[structopt(name = "qlog-dir", long, parse(from_os_str))]
+    /// Write a qlog trace of each connection to this directory.
+    qlog_dir: Option<PathBuf>,
+
+    #[structopt(short = "w", long, default_value = "1")]
+    /// The number of threads that serve connections.  More than one needs the
+    /// workers feature.
+    workers: u8,
+
+    #[structopt(name = "self-test", long = "self-test")]
+    /// Run a client and server over loopback to check that this build works,
+    /// then exit.
+    self_test: bool,
 }
 
 impl Args {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(0)
+    }
+
     fn bind(&self) -> SocketAddr {
         match (&self.host, self.ipv4, self.ipv6) {
             (Some(..), ..) => self
@@ -61,8 +125,8 @@ impl Args {
                 .expect("Remote address error")
                 .next()
                 .expect("No remote addresses"),
-            (_, false, true) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), self.port),
-            _ => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), self.port),
+            (_, false, true) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), self.port()),
+            _ => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), self.port()),
         }
     }
 }
@@ -77,117 +141,359 @@ impl ToSocketAddrs for Args {
         let h = self.host.as_ref().unwrap_or(&dflt);
         // This is idiotic.  There is no path from hostname: String to IpAddr.
         // And no means of controlling name resolution either.
-        fmt::format(format_args!("{}:{}", h, self.port)).to_socket_addrs()
+        fmt::format(format_args!("{}:{}", h, self.port())).to_socket_addrs()
+    }
+}
+
+/// Accept 0-RTT with any ticket this server issued.
+#[derive(Debug)]
+struct AllowZeroRtt {}
+impl ZeroRttChecker for AllowZeroRtt {
+    fn check(&self, _token: &[u8]) -> ZeroRttCheckResult {
+        ZeroRttCheckResult::Accept
+    }
+}
+
+/// Find the response for `path`, either from the files in `www`, or
+/// generated from the number in the path.
+fn http_response(path: &str, www: Option<&Path>) -> Option<Vec<u8>> {
+    if let Some(dir) = www {
+        if path.split('/').any(|p| p == "..") {
+            return None;
+        }
+        return fs::read(dir.join(path)).ok();
+    }
+    if path.is_empty() {
+        return Some(b"Hello World".to_vec());
+    }
+    path.parse::<usize>().ok().map(|count| vec![0x58; count])
+}
+
+/// Send as much of `data` as flow control allows, then close the stream.
+/// Returns anything that didn't fit.
+fn http_send(server: &mut Connection, stream: u64, data: &[u8]) -> Option<Vec<u8>> {
+    let sent = server.stream_send(stream, data).expect("Successful write");
+    if sent < data.len() {
+        Some(data[sent..].to_vec())
+    } else {
+        server.stream_close_send(stream).expect("Stream closed");
+        None
     }
 }
 
 // World's dumbest HTTP 0.9 server. Assumes that the whole request is
 // in a single write.
 // TODO(ekr@rtfm.com): One imagines we could fix this.
-fn http_serve(server: &mut Connection, stream: u64) {
+fn http_serve(server: &mut Connection, stream: u64, www: Option<&Path>) -> Option<Vec<u8>> {
     println!("Stream ID {}", stream);
     let mut data = vec![0; 4000];
     server
         .stream_recv(stream, &mut data)
         .expect("Read should succeed");
     let msg = String::from_utf8(data.clone()).unwrap();
-    let re = Regex::new(r"GET +/(\d*)(\r)?\n").unwrap();
+    let re = Regex::new(r"GET +/(\S*)(\r)?\n").unwrap();
     let m = re.captures(&msg);
     if m.is_none() {
         println!("Invalid HTTP request: {}", msg);
-        return;
+        return None;
     }
-    let m = m.unwrap();
+    let path = m.unwrap().get(1).map_or("", |p| p.as_str());
+    println!("Path = {}", path);
 
-    let mut resp: Vec<u8> = vec![];
-    if let Some(path) = m.get(1) {
-        let path = path.as_str();
-        println!("Path = {}", path);
-        let count = u32::from_str_radix(path, 10).unwrap();
-        for _i in 0..count {
-            resp.push(0x58);
+    match http_response(path, www) {
+        Some(resp) => http_send(server, stream, &resp),
+        None => {
+            println!("No response for {}", path);
+            server.stream_close_send(stream).expect("Stream closed");
+            None
         }
-    } else {
-        resp = b"Hello World".to_vec();
     }
-    // TODO(ekr@rtfm.com): This won't work with flow control blocks.
-    server.stream_send(stream, &resp).expect("Successful write");
-    server.stream_close_send(stream).expect("Stream closed");
+}
+
+#[cfg(not(feature = "txtime"))]
+fn send_datagram(socket: &UdpSocket, d: &Datagram) -> std::io::Result<usize> {
+    socket.send_to(&d[..], d.destination())
+}
+
+#[cfg(feature = "txtime")]
+fn send_datagram(socket: &UdpSocket, d: &Datagram) -> std::io::Result<usize> {
+    neqo_common::udp::send(socket, d)
 }
 
 fn emit_datagram(socket: &UdpSocket, d: Datagram) {
-    let sent = socket
-        .send_to(&d[..], d.destination())
-        .expect("Error sending datagram");
+    let sent = send_datagram(socket, &d).expect("Error sending datagram");
     if sent != d.len() {
         eprintln!("Unable to send all {} bytes of datagram", d.len());
     }
 }
 
-fn main() {
-    let args = Args::from_args();
-    assert!(!args.key.is_empty(), "Need at least one key");
-
-    init_db(args.db.clone());
-    let anti_replay = AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
+/// Set up a `Server` for worker `index`.
+fn new_server(args: &Args, index: u8, retry_key: RetryTokenKey) -> Server {
+    let anti_replay = AntiReplay::builder()
+        .build(Instant::now())
         .expect("unable to setup anti-replay");
+    let mut server = Server::new(
+        Instant::now(),
+        &args.key,
+        &args.alpn,
+        anti_replay,
+        Rc::new(RefCell::new(WorkerConnectionIdManager::new(index, CID_LEN))),
+    );
+    server.set_retry_required(args.retry);
+    server.set_qlog_dir(args.qlog_dir.clone());
+    // A client can come back with its token to any worker.
+    server.set_retry_token_key(retry_key);
+    for sni in &args.sni {
+        let mut parts = sni.splitn(2, '=');
+        let (name, key) = (parts.next().unwrap(), parts.next().expect("need name=key"));
+        server
+            .set_server_certificates(name, &[key])
+            .expect("can't use key for server name");
+    }
+    // Log secrets to the file named by SSLKEYLOGFILE, if that is set.
+    if let Some(log) = KeyLogFile::from_env() {
+        server.set_secret_listener(Rc::new(log));
+    }
+    if args.zero_rtt {
+        server.enable_zero_rtt(AllowZeroRtt {});
+    }
+    server
+}
 
-    // TODO(mt): listen on both v4 and v6.
-    let socket = UdpSocket::bind(args.bind()).expect("Unable to bind UDP socket");
+#[cfg(feature = "workers")]
+fn bind(args: &Args, addr: &SocketAddr) -> std::io::Result<UdpSocket> {
+    if args.workers > 1 {
+        neqo_common::udp::bind_shared(addr)
+    } else {
+        UdpSocket::bind(addr)
+    }
+}
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
+#[cfg(not(feature = "workers"))]
+fn bind(args: &Args, addr: &SocketAddr) -> std::io::Result<UdpSocket> {
+    assert_eq!(
+        args.workers, 1,
+        "more than one worker needs the workers feature"
+    );
+    UdpSocket::bind(addr)
+}
 
-    println!("Server waiting for connection on: {:?}", local_addr);
+/// A thread that runs a `Server` on its own socket.  Every worker's socket
+/// is bound to the same port, and the kernel picks a socket for each
+/// datagram based on addresses; datagrams for a connection that another
+/// worker has are passed to that worker.
+struct Worker {
+    index: u8,
+    args: Arc<Args>,
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    server: Server,
+    /// Datagrams that arrived at other workers.
+    incoming: Receiver<Datagram>,
+    /// Where to pass datagrams for other workers, by worker index.
+    peers: Vec<Sender<Datagram>>,
+    /// Connections that have been configured.
+    known: HashSet<u64>,
+    /// Response data that is waiting for flow control credit, by connection
+    /// and stream.
+    pending: HashMap<(u64, u64), Vec<u8>>,
+    #[cfg(feature = "txtime")]
+    pacing_offload: Option<Duration>,
+}
 
-    let buf = &mut [0u8; 2048];
-    let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
-    loop {
-        // TODO use timer to set socket.set_read_timeout.
-        let (sz, remote_addr) = socket.recv_from(&mut buf[..]).expect("UDP error");
-        if sz == buf.len() {
-            eprintln!("Discarding packet that might be truncated");
-            continue;
+impl Worker {
+    fn new(
+        index: u8,
+        args: Arc<Args>,
+        socket: UdpSocket,
+        incoming: Receiver<Datagram>,
+        peers: Vec<Sender<Datagram>>,
+        retry_key: RetryTokenKey,
+    ) -> Self {
+        let local_addr = socket.local_addr().expect("Socket local address not bound");
+        #[cfg(feature = "txtime")]
+        let pacing_offload = match txtime::enable(&socket) {
+            Ok(()) => Some(PACING_HORIZON),
+            Err(e) => {
+                eprintln!("Unable to enable SO_TXTIME, pacing in the server: {}", e);
+                None
+            }
+        };
+        let server = new_server(&args, index, retry_key);
+        Self {
+            index,
+            args,
+            socket,
+            local_addr,
+            server,
+            incoming,
+            peers,
+            known: HashSet::new(),
+            pending: HashMap::new(),
+            #[cfg(feature = "txtime")]
+            pacing_offload,
         }
+    }
 
-        let mut server = connections.entry(remote_addr).or_insert_with(|| {
-            println!("New connection from {:?}", remote_addr);
-            Connection::new_server(
-                &args.key,
-                &args.alpn,
-                &anti_replay,
-                Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
-            )
-            .expect("can't create connection")
-        });
-
-        if sz > 0 {
-            let dgram = Datagram::new(remote_addr, local_addr, &buf[..sz]);
-            server.process_input(dgram, Instant::now());
+    /// Read one datagram from the socket, waiting no longer than `timeout`.
+    /// Datagrams for other workers are passed on.
+    fn recv(&mut self, timeout: Duration) -> Option<Datagram> {
+        let buf = &mut [0u8; 2048];
+        self.socket
+            .set_read_timeout(Some(max(timeout, Duration::from_millis(1))))
+            .expect("Unable to set read timeout");
+        let (sz, remote_addr) = match self.socket.recv_from(&mut buf[..]) {
+            Ok(r) => r,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return None;
+            }
+            Err(e) => panic!("UDP error: {}", e),
+        };
+        if sz == buf.len() {
+            eprintln!("Discarding packet that might be truncated");
+            return None;
         }
-        if let State::Closed(e) = server.state() {
-            eprintln!("Closed connection from {:?}: {:?}", remote_addr, e);
-            connections.remove(&remote_addr);
-            continue;
+        let dgram = Datagram::new(remote_addr, self.local_addr, &buf[..sz]);
+        match WorkerConnectionIdManager::worker(&dgram, CID_LEN) {
+            Some(w) if w != self.index && usize::from(w) < self.peers.len() => {
+                // If the worker is gone, so is the connection.
+                let _ = self.peers[usize::from(w)].send(dgram);
+                None
+            }
+            _ => Some(dgram),
         }
-        if let State::Closing { error, .. } = server.state() {
-            eprintln!("Closing connection from {:?}: {:?}", remote_addr, error);
-            // TOOD(ekr@rtfm.com): Do I need to remove?
-            continue;
+    }
+
+    /// Give the server a datagram, if there is one, and send what it
+    /// produces.  Returns how long until the server needs to be called again.
+    fn process(&mut self, dgram: Option<Datagram>) -> Option<Duration> {
+        let mut dgram = dgram;
+        loop {
+            match self.server.process(dgram.take(), Instant::now()) {
+                Output::Datagram(d) => emit_datagram(&self.socket, d),
+                Output::Callback(t) => return Some(t),
+                Output::None => return None,
+            }
         }
-        let mut streams = Vec::new();
-        for event in server.events() {
-            if let ConnectionEvent::RecvStreamReadable { stream_id } = event {
-                streams.push(stream_id)
+    }
+
+    /// Act on events from connections.
+    fn serve(&mut self) {
+        let www = self.args.www.as_ref().map(PathBuf::as_path);
+        for mut active in self.server.active_connections() {
+            let id = active.id();
+            {
+                let mut server = active.borrow_mut();
+                if self.known.insert(id) {
+                    server
+                        .set_max_send_rate(self.args.rate)
+                        .expect("rate must not be zero");
+                    #[cfg(feature = "txtime")]
+                    server.set_pacing_offload(self.pacing_offload);
+                }
+                let events: Vec<_> = server.events().collect();
+                for event in events {
+                    match event {
+                        ConnectionEvent::StateChange(State::Connected) => {
+                            // A ticket lets the client resume, and maybe use 0-RTT.
+                            server
+                                .send_ticket(Instant::now(), &[])
+                                .expect("send a ticket");
+                        }
+                        ConnectionEvent::StateChange(State::Closed { error, .. }) => {
+                            eprintln!("Closed connection {}: {:?}", id, error);
+                            self.known.remove(&id);
+                            self.pending.retain(|(c, _), _| *c != id);
+                        }
+                        ConnectionEvent::RecvStreamReadable { stream_id } => {
+                            if let Some(rest) = http_serve(&mut server, stream_id, www) {
+                                self.pending.insert((id, stream_id), rest);
+                            }
+                        }
+                        ConnectionEvent::SendStreamWritable { stream_id } => {
+                            if let Some(data) = self.pending.remove(&(id, stream_id)) {
+                                if let Some(rest) = http_send(&mut server, stream_id, &data) {
+                                    self.pending.insert((id, stream_id), rest);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
             }
+            self.server.add_to_waiting(active);
         }
+    }
 
-        for stream_id in streams {
-            http_serve(&mut server, stream_id);
+    fn run(&mut self) {
+        let mut timeout = None;
+        loop {
+            // With other workers, check for datagrams they pass on often.
+            let wait = if self.peers.len() > 1 {
+                timeout.map_or(FORWARD_INTERVAL, |t| min(t, FORWARD_INTERVAL))
+            } else {
+                timeout.unwrap_or(IDLE_TIMEOUT)
+            };
+            let mut input: Vec<_> = self.recv(wait).into_iter().collect();
+            input.extend(self.incoming.try_iter());
+            for dgram in input {
+                self.process(Some(dgram));
+            }
+            self.serve();
+            timeout = self.process(None);
         }
+    }
+}
+
+fn main() {
+    let args = Args::from_args();
+    assert!(!args.key.is_empty(), "Need at least one key");
+    assert!(args.workers > 0, "Need at least one worker");
+
+    init_db(args.db.clone());
+    if args.self_test {
+        let anti_replay = AntiReplay::builder()
+            .build(Instant::now())
+            .expect("unable to setup anti-replay");
+        exit(selftest::run(&args, &anti_replay));
+    }
+
+    // TODO(mt): listen on both v4 and v6.
+    let first = bind(&args, &args.bind()).expect("Unable to bind UDP socket");
+    // Binding to port 0 picks a port; the other workers need that one.
+    let local_addr = first.local_addr().expect("Socket local address not bound");
+    let mut sockets = vec![first];
+    for _ in 1..args.workers {
+        sockets.push(bind(&args, &local_addr).expect("Unable to bind UDP socket"));
+    }
+    println!(
+        "Server waiting for connection on: {:?} with {} workers",
+        local_addr, args.workers
+    );
 
-        let out = server.process_output(Instant::now());
-        if let Some(dgram) = out.dgram() {
-            emit_datagram(&socket, dgram);
+    let (peers, receivers): (Vec<_>, Vec<_>) = sockets.iter().map(|_| channel()).unzip();
+    // Workers share the key, and the time that token ages are measured from.
+    let retry_key = RetryTokenKey::generate(Instant::now());
+    let args = Arc::new(args);
+    let threads: Vec<_> = sockets
+        .into_iter()
+        .zip(receivers)
+        .enumerate()
+        .map(|(i, (socket, incoming))| {
+            let args = Arc::clone(&args);
+            let peers = peers.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    // There are fewer than 256 workers, as `workers` is a `u8`.
+                    Worker::new(i as u8, args, socket, incoming, peers, retry_key).run()
+                })
+                .expect("Unable to start worker")
+        })
+        .collect();
+    for t in threads {
+        if t.join().is_err() {
+            exit(1);
         }
     }
 }
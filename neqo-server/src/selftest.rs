@@ -0,0 +1,255 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A self-test that runs a client and a server in this process, talking over
+// loopback.  This checks that this build works with the NSS that it found,
+// without needing anything outside the machine.
+
+use neqo_common::Datagram;
+use neqo_crypto::{AntiReplay, AuthenticationStatus};
+use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType};
+
+use std::cell::RefCell;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{http_serve, Args};
+
+/// How long each check has to finish.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The size of the response that the transfer check asks for.
+const TRANSFER_SIZE: usize = 3000;
+const ALPN: &[&str] = &["http/0.9"];
+
+type Res<T> = Result<T, String>;
+
+/// A connection and the socket that it uses.
+struct Endpoint {
+    conn: Connection,
+    socket: UdpSocket,
+}
+
+impl Endpoint {
+    fn bind() -> Res<UdpSocket> {
+        let socket = UdpSocket::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(socket)
+    }
+
+    fn server(args: &Args, anti_replay: &AntiReplay) -> Res<Self> {
+        let conn = Connection::new_server(
+            &args.key,
+            ALPN,
+            anti_replay,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+        )
+        .map_err(|e| format!("can't make server: {:?}", e))?;
+        Ok(Self {
+            conn,
+            socket: Self::bind()?,
+        })
+    }
+
+    fn client(server: &Endpoint) -> Res<Self> {
+        let socket = Self::bind()?;
+        let local = socket.local_addr().map_err(|e| e.to_string())?;
+        let remote = server.socket.local_addr().map_err(|e| e.to_string())?;
+        let conn = Connection::new_client(
+            "localhost",
+            ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            local,
+            remote,
+        )
+        .map_err(|e| format!("can't make client: {:?}", e))?;
+        Ok(Self { conn, socket })
+    }
+
+    /// Send what the connection has to send, then pass it anything that has
+    /// arrived.  This returns true if any datagrams were sent or received.
+    fn process(&mut self, now: Instant) -> Res<bool> {
+        let mut busy = false;
+        for d in self.conn.process_iter(None, now) {
+            self.socket
+                .send_to(&d[..], d.destination())
+                .map_err(|e| e.to_string())?;
+            busy = true;
+        }
+
+        let local = self.socket.local_addr().map_err(|e| e.to_string())?;
+        let buf = &mut [0u8; 2048];
+        loop {
+            match self.socket.recv_from(&mut buf[..]) {
+                Ok((sz, remote)) => {
+                    let d = Datagram::new(remote, local, &buf[..sz]);
+                    self.conn.process_input(d, now);
+                    busy = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(busy),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Run the client and server until `done` says that the check is complete.
+/// The server answers requests in the same way that it does normally.
+fn exchange(
+    client: &mut Endpoint,
+    server: &mut Endpoint,
+    mut done: impl FnMut(&mut Connection, &Connection) -> Res<bool>,
+) -> Res<()> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let now = Instant::now();
+        let busy = client.process(now)? | server.process(now)?;
+
+        let readable = server
+            .conn
+            .events()
+            .filter_map(|e| match e {
+                ConnectionEvent::RecvStreamReadable { stream_id } => Some(stream_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for stream_id in readable {
+            http_serve(&mut server.conn, stream_id, None);
+        }
+
+        for c in &[&client.conn, &server.conn] {
+            if let State::Closed { error, .. } = c.state() {
+                return Err(format!("connection closed: {:?}", error));
+            }
+        }
+        if done(&mut client.conn, &server.conn)? {
+            return Ok(());
+        }
+        if now > deadline {
+            return Err(String::from("timed out"));
+        }
+        if !busy {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn handshake(client: &mut Endpoint, server: &mut Endpoint) -> Res<()> {
+    exchange(client, server, |c, s| {
+        let auth = |e| e == ConnectionEvent::AuthenticationNeeded;
+        if c.events().any(auth) {
+            // Only the handshake is being tested, not the certificate.
+            c.authenticated(AuthenticationStatus::Ok, Instant::now());
+        }
+        Ok(*c.state() == State::Connected && *s.state() == State::Connected)
+    })
+}
+
+fn transfer(client: &mut Endpoint, server: &mut Endpoint) -> Res<()> {
+    let c = &mut client.conn;
+    let stream_id = c
+        .stream_create(StreamType::BiDi)
+        .map_err(|e| format!("{:?}", e))?;
+    let request = format!("GET /{}\r\n", TRANSFER_SIZE);
+    c.stream_send(stream_id, request.as_bytes())
+        .map_err(|e| format!("{:?}", e))?;
+    c.stream_close_send(stream_id)
+        .map_err(|e| format!("{:?}", e))?;
+
+    let mut response = Vec::new();
+    exchange(client, server, |c, _| {
+        let buf = &mut [0; 4096];
+        loop {
+            let (sz, fin) = c
+                .stream_recv(stream_id, &mut buf[..])
+                .map_err(|e| format!("{:?}", e))?;
+            response.extend_from_slice(&buf[..sz]);
+            if fin {
+                return Ok(true);
+            }
+            if sz == 0 {
+                return Ok(false);
+            }
+        }
+    })?;
+    if response.len() != TRANSFER_SIZE || response.iter().any(|&b| b != 0x58) {
+        return Err(format!("bad response of {} bytes", response.len()));
+    }
+    Ok(())
+}
+
+fn resumption(
+    client: &mut Endpoint,
+    server: &mut Endpoint,
+    args: &Args,
+    anti_replay: &AntiReplay,
+) -> Res<()> {
+    server
+        .conn
+        .send_ticket(Instant::now(), &[])
+        .map_err(|e| format!("can't send ticket: {:?}", e))?;
+    exchange(client, server, |c, _| Ok(c.resumption_token().is_some()))?;
+    let token = client.conn.resumption_token().unwrap();
+
+    let mut server = Endpoint::server(args, anti_replay)?;
+    let mut client = Endpoint::client(&server)?;
+    client
+        .conn
+        .set_resumption_token(Instant::now(), &token)
+        .map_err(|e| format!("can't use token: {:?}", e))?;
+    handshake(&mut client, &mut server)?;
+    if !client.conn.tls_info().map_or(false, |i| i.resumed()) {
+        return Err(String::from("not resumed"));
+    }
+    Ok(())
+}
+
+fn report(name: &str, res: Res<()>) -> bool {
+    match res {
+        Ok(()) => {
+            println!("PASS {}", name);
+            true
+        }
+        Err(e) => {
+            println!("FAIL {}: {}", name, e);
+            false
+        }
+    }
+}
+
+/// Run the checks, and return the exit code for the process.  Later checks
+/// use the connection from the handshake check, so they aren't run if that
+/// fails.
+pub fn run(args: &Args, anti_replay: &AntiReplay) -> i32 {
+    let endpoints = Endpoint::server(args, anti_replay)
+        .and_then(|server| Endpoint::client(&server).map(|client| (client, server)));
+    let (mut client, mut server) = match endpoints {
+        Ok(e) => e,
+        Err(e) => {
+            report("setup", Err(e));
+            return 1;
+        }
+    };
+
+    if !report("handshake", handshake(&mut client, &mut server)) {
+        return 1;
+    }
+    let mut ok = report("transfer", transfer(&mut client, &mut server));
+    ok &= report(
+        "resumption",
+        resumption(&mut client, &mut server, args, anti_replay),
+    );
+    // There is nothing to check until key updates are supported.
+    println!("SKIP key update: not supported");
+
+    if ok {
+        0
+    } else {
+        1
+    }
+}
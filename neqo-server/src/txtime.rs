@@ -0,0 +1,47 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sending with SO_TXTIME, so that the kernel holds each datagram until its
+// release time rather than the server sleeping until then.  This needs Linux,
+// and the ETF qdisc on the interface for the times to be honored precisely.
+// Sending itself is done by `neqo_common::udp`.
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+
+// This isn't in all versions of libc.
+const SO_TXTIME: libc::c_int = 61;
+
+/// `struct sock_txtime` from linux/net_tstamp.h.
+#[repr(C)]
+struct SockTxtime {
+    clockid: libc::clockid_t,
+    flags: u32,
+}
+
+/// Turn on SO_TXTIME for `socket`.  This fails if the kernel doesn't
+/// support it.
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    let cfg = SockTxtime {
+        clockid: libc::CLOCK_MONOTONIC,
+        flags: 0,
+    };
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_TXTIME,
+            &cfg as *const SockTxtime as *const libc::c_void,
+            mem::size_of::<SockTxtime>() as libc::socklen_t,
+        )
+    };
+    usize::try_from(rv)
+        .map(|_| ())
+        .map_err(|_| io::Error::last_os_error())
+}
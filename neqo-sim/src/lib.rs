@@ -0,0 +1,217 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A simulated network for tests.  This connects a client `Connection` to a
+// `Server` through a pair of links that add delay, jitter, loss, reordering
+// and a bandwidth limit.  Time is virtual and all randomness comes from a
+// seed, so a run with the same inputs always has the same outcome, however
+// fast the machine running it is.  That makes it possible to write tests for
+// congestion control and loss recovery that check timing.
+
+#![deny(warnings)]
+
+mod link;
+mod rng;
+
+pub use self::link::{Link, LinkConfig, LinkStats};
+pub use self::rng::Random;
+
+use neqo_common::Datagram;
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::server::Server;
+use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, Output, State};
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Create a server with the default test configuration.
+pub fn default_server() -> Server {
+    Server::new(
+        test_fixture::now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(7))),
+    )
+}
+
+pub struct Simulator {
+    start: Instant,
+    now: Instant,
+    client: Connection,
+    server: Server,
+    /// From client to server.
+    up: Link,
+    /// From server to client.
+    down: Link,
+    client_timer: Option<Instant>,
+    server_timer: Option<Instant>,
+}
+
+impl Simulator {
+    /// Connect `client` and `server` with links that behave as `up` (client
+    /// to server) and `down` (server to client) describe.  `seed` determines
+    /// which datagrams are lost and how they are delayed.
+    pub fn new(
+        client: Connection,
+        server: Server,
+        up: LinkConfig,
+        down: LinkConfig,
+        seed: u64,
+    ) -> Self {
+        let now = test_fixture::now();
+        Self {
+            start: now,
+            now,
+            client,
+            server,
+            up: Link::new(up, seed),
+            down: Link::new(down, seed.wrapping_add(1)),
+            client_timer: None,
+            server_timer: None,
+        }
+    }
+
+    /// Use the default client and server from the test fixture.
+    pub fn with_links(up: LinkConfig, down: LinkConfig, seed: u64) -> Self {
+        Self::new(
+            test_fixture::default_client(),
+            default_server(),
+            up,
+            down,
+            seed,
+        )
+    }
+
+    /// The current virtual time.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// How much virtual time has passed since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    pub fn client(&mut self) -> &mut Connection {
+        &mut self.client
+    }
+
+    /// After acting on a connection from `Server::active_connections()`,
+    /// use `Server::add_to_waiting()` so that its output is collected.
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    pub fn uplink(&self) -> &Link {
+        &self.up
+    }
+
+    pub fn downlink(&self) -> &Link {
+        &self.down
+    }
+
+    fn drive_client(&mut self, dgram: Option<Datagram>) {
+        let mut dgram = dgram;
+        loop {
+            match self.client.process(dgram.take(), self.now) {
+                Output::Datagram(d) => self.up.send(d, self.now),
+                Output::Callback(t) => {
+                    self.client_timer = Some(self.now + t);
+                    break;
+                }
+                Output::None => {
+                    self.client_timer = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn drive_server(&mut self, dgram: Option<Datagram>) {
+        let mut dgram = dgram;
+        loop {
+            match self.server.process(dgram.take(), self.now) {
+                Output::Datagram(d) => self.down.send(d, self.now),
+                Output::Callback(t) => {
+                    self.server_timer = Some(self.now + t);
+                    break;
+                }
+                Output::None => {
+                    self.server_timer = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Collect anything that either end has to send, then move time forward
+    /// to the next arrival or timer and act on everything that is due.
+    /// Returns false if there is nothing left to happen.
+    pub fn step(&mut self) -> bool {
+        self.drive_client(None);
+        self.drive_server(None);
+
+        let next = [
+            self.up.next_arrival(),
+            self.down.next_arrival(),
+            self.client_timer,
+            self.server_timer,
+        ]
+        .iter()
+        .filter_map(|t| *t)
+        .min();
+        let next = match next {
+            Some(t) => t,
+            None => return false,
+        };
+        self.now = max(self.now, next);
+
+        while let Some(d) = self.up.receive(self.now) {
+            self.drive_server(Some(d));
+        }
+        while let Some(d) = self.down.receive(self.now) {
+            self.drive_client(Some(d));
+        }
+        if self.client_timer.map_or(false, |t| t <= self.now) {
+            self.drive_client(None);
+        }
+        if self.server_timer.map_or(false, |t| t <= self.now) {
+            self.drive_server(None);
+        }
+        true
+    }
+
+    /// Step until `done` returns true, which is checked before each step.
+    /// Returns false if `limit` of virtual time passes first, or if nothing
+    /// is left to happen.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        let end = self.now + limit;
+        while !done(self) {
+            if self.now > end || !self.step() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Run until the client is connected, authenticating the server when
+    /// asked.  This consumes any client events raised during the handshake.
+    pub fn connect(&mut self, limit: Duration) -> bool {
+        self.run_until(limit, |sim| {
+            let auth = sim
+                .client
+                .events()
+                .any(|e| e == ConnectionEvent::AuthenticationNeeded);
+            if auth {
+                sim.client.authenticated(AuthenticationStatus::Ok, sim.now);
+            }
+            *sim.client.state() == State::Connected
+        })
+    }
+}
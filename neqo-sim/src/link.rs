@@ -0,0 +1,125 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::rng::Random;
+use neqo_common::Datagram;
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How a link treats the datagrams it carries.  The default is a perfect
+/// link: no delay, no loss and unlimited bandwidth.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    /// The one-way propagation delay.
+    pub delay: Duration,
+    /// Up to this much extra delay is added to each datagram.  Jitter alone
+    /// doesn't reorder datagrams; see `reorder`.
+    pub jitter: Duration,
+    /// The chance of a datagram being dropped, per thousand.
+    pub loss: u32,
+    /// The chance of a datagram being held back for one more `delay`, so
+    /// that datagrams sent after it overtake it, per thousand.
+    pub reorder: u32,
+    /// The rate of the link in bits per second, or 0 for no limit.  Datagrams
+    /// queue behind each other for as long as they take to transmit.
+    pub bandwidth: u64,
+}
+
+/// Counts of what happened to datagrams on a link.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    pub sent: usize,
+    pub lost: usize,
+    pub reordered: usize,
+    pub delivered: usize,
+}
+
+/// One direction of a simulated network path.
+#[derive(Debug)]
+pub struct Link {
+    config: LinkConfig,
+    rng: Random,
+    /// Datagrams in flight, by arrival time and then the order they were sent.
+    in_flight: BTreeMap<(Instant, u64), Datagram>,
+    next_seq: u64,
+    /// When the link finishes transmitting what it has been given.
+    busy_until: Option<Instant>,
+    /// When the last datagram that wasn't held back arrives.
+    last_arrival: Option<Instant>,
+    stats: LinkStats,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: Random::new(seed),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            busy_until: None,
+            last_arrival: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    fn transmission_time(&self, len: usize) -> Duration {
+        if self.config.bandwidth == 0 {
+            Duration::from_nanos(0)
+        } else {
+            let bits = len as u64 * 8;
+            Duration::from_nanos(bits * 1_000_000_000 / self.config.bandwidth)
+        }
+    }
+
+    /// Put a datagram on the link at `now`.
+    pub fn send(&mut self, d: Datagram, now: Instant) {
+        self.stats.sent += 1;
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let start = self.busy_until.map_or(now, |t| max(t, now));
+        let done = start + self.transmission_time(d.len());
+        self.busy_until = Some(done);
+
+        let mut arrival = done + self.config.delay + self.rng.duration(self.config.jitter);
+        if self.rng.chance(self.config.reorder) {
+            arrival += max(self.config.delay, Duration::from_millis(1));
+            self.stats.reordered += 1;
+        } else {
+            if let Some(last) = self.last_arrival {
+                arrival = max(arrival, last);
+            }
+            self.last_arrival = Some(arrival);
+        }
+
+        self.in_flight
+            .insert((arrival, self.next_seq), d.with_received_time(arrival));
+        self.next_seq += 1;
+    }
+
+    /// When the next datagram arrives, if there is one in flight.
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.keys().next().map(|(t, _)| *t)
+    }
+
+    /// Take the next datagram that has arrived by `now`.
+    pub fn receive(&mut self, now: Instant) -> Option<Datagram> {
+        let key = *self.in_flight.keys().next()?;
+        if key.0 > now {
+            return None;
+        }
+        self.stats.delivered += 1;
+        self.in_flight.remove(&key)
+    }
+
+    pub fn stats(&self) -> &LinkStats {
+        &self.stats
+    }
+}
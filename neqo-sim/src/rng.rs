@@ -0,0 +1,41 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+/// A small seeded pseudo-random number generator (splitmix64).  The same seed
+/// always produces the same sequence, which is what makes a simulation repeat.
+#[derive(Debug, Clone)]
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns true with a probability of `per_mille` in a thousand.
+    pub fn chance(&mut self, per_mille: u32) -> bool {
+        per_mille > 0 && self.next_u64() % 1000 < u64::from(per_mille)
+    }
+
+    /// A duration between zero and `max`, inclusive.
+    pub fn duration(&mut self, max: Duration) -> Duration {
+        let max = max.as_nanos() as u64;
+        if max == 0 {
+            Duration::from_nanos(0)
+        } else {
+            Duration::from_nanos(self.next_u64() % (max + 1))
+        }
+    }
+}
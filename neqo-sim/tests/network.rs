@@ -0,0 +1,92 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![deny(warnings)]
+
+use neqo_sim::{LinkConfig, Simulator};
+use neqo_transport::{ConnectionEvent, StreamType};
+
+use std::time::Duration;
+
+const LIMIT: Duration = Duration::from_secs(30);
+
+fn delayed(ms: u64) -> LinkConfig {
+    LinkConfig {
+        delay: Duration::from_millis(ms),
+        ..LinkConfig::default()
+    }
+}
+
+#[test]
+fn handshake_takes_a_round_trip() {
+    let mut sim = Simulator::with_links(delayed(50), delayed(50), 0);
+    assert!(sim.connect(LIMIT));
+    assert!(sim.elapsed() >= Duration::from_millis(100));
+    assert_eq!(sim.uplink().stats().lost, 0);
+}
+
+fn lossy_handshake(seed: u64) -> (Duration, usize) {
+    let lossy = LinkConfig {
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(5),
+        loss: 200,
+        reorder: 100,
+        ..LinkConfig::default()
+    };
+    let mut sim = Simulator::with_links(lossy.clone(), lossy, seed);
+    assert!(sim.connect(LIMIT));
+    (sim.elapsed(), sim.uplink().stats().sent)
+}
+
+#[test]
+fn same_seed_same_result() {
+    assert_eq!(lossy_handshake(7), lossy_handshake(7));
+}
+
+#[test]
+fn bandwidth_limits_transfer() {
+    const SIZE: usize = 30_000;
+    let slow = LinkConfig {
+        delay: Duration::from_millis(10),
+        bandwidth: 1_000_000,
+        ..LinkConfig::default()
+    };
+    let mut sim = Simulator::with_links(slow.clone(), slow, 0);
+    assert!(sim.connect(LIMIT));
+    let start = sim.now();
+
+    let data = vec![0x5a; SIZE];
+    let stream_id = sim.client().stream_create(StreamType::UniDi).unwrap();
+    let mut sent = 0;
+    let mut received = 0;
+    let mut buf = vec![0; 4096];
+    assert!(sim.run_until(LIMIT, |sim| {
+        if sent < SIZE {
+            sent += sim.client().stream_send(stream_id, &data[sent..]).unwrap();
+        }
+        for mut active in sim.server().active_connections() {
+            {
+                let mut c = active.borrow_mut();
+                let events: Vec<_> = c.events().collect();
+                for e in events {
+                    if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+                        loop {
+                            let (n, _) = c.stream_recv(stream_id, &mut buf).unwrap();
+                            if n == 0 {
+                                break;
+                            }
+                            received += n;
+                        }
+                    }
+                }
+            }
+            sim.server().add_to_waiting(active);
+        }
+        received == SIZE
+    }));
+    // 30,000 bytes at 1Mbps takes at least 240ms, before any overhead.
+    assert!(sim.now() - start >= Duration::from_millis(240));
+}
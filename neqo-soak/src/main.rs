@@ -0,0 +1,277 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A soak test.  This runs many connections through a `Server` over an
+// in-memory loopback for a long time, watching for resources that only grow.
+// Leaks of reference-counted state don't show up in unit tests, which only
+// run a few connections, so this is where they get caught.
+
+#![deny(warnings)]
+
+use neqo_common::{matches, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::server::Server;
+use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType};
+use test_fixture::{self, anti_replay, fixture_init, loopback};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "neqo-soak", about = "A QUIC soak test.")]
+struct Args {
+    #[structopt(short = "t", long, default_value = "3600")]
+    /// How long to run for, in seconds.
+    duration: u64,
+
+    #[structopt(short = "c", long, default_value = "10")]
+    /// The number of connections to run at the same time.
+    /// Use 1 to run connections sequentially.
+    concurrency: usize,
+
+    #[structopt(short = "i", long, default_value = "10")]
+    /// How often to sample resource usage, in seconds.
+    interval: u64,
+
+    #[structopt(short = "w", long, default_value = "12")]
+    /// Fail if this many consecutive samples each show growth.
+    window: usize,
+}
+
+const MESSAGE: &[u8] = b"The quick brown fox jumps over the lazy dog.";
+/// The first port number that is used for clients.
+const FIRST_PORT: u16 = 1024;
+
+/// A client connection that sends `MESSAGE` on one stream, waits for the
+/// server to echo it back, then closes.
+struct SoakClient {
+    conn: Connection,
+    stream_id: Option<u64>,
+    received: usize,
+}
+
+impl SoakClient {
+    fn new(addr: SocketAddr) -> Self {
+        let conn = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(4))),
+            addr,
+            loopback(),
+        )
+        .expect("create a client");
+        Self {
+            conn,
+            stream_id: None,
+            received: 0,
+        }
+    }
+
+    fn handle_events(&mut self, now: Instant) {
+        let mut buf = [0; 256];
+        for e in self.conn.events() {
+            match e {
+                ConnectionEvent::AuthenticationNeeded => {
+                    self.conn.authenticated(AuthenticationStatus::Ok, now);
+                }
+                ConnectionEvent::StateChange(State::Connected) => {
+                    let stream_id = self
+                        .conn
+                        .stream_create(StreamType::BiDi)
+                        .expect("create a stream");
+                    let sent = self.conn.stream_send(stream_id, MESSAGE).expect("send");
+                    assert_eq!(sent, MESSAGE.len());
+                    self.conn.stream_close_send(stream_id).expect("close");
+                    self.stream_id = Some(stream_id);
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    let (sz, fin) = self.conn.stream_recv(stream_id, &mut buf).expect("recv");
+                    self.received += sz;
+                    if fin {
+                        assert_eq!(self.received, MESSAGE.len());
+                        self.conn.close(now, 0, "done");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn closed(&self) -> bool {
+        matches!(self.conn.state(), State::Closed { .. })
+    }
+}
+
+/// Echo anything that clients send.
+fn serve(server: &mut Server) {
+    let mut buf = [0; 256];
+    for mut active in server.active_connections() {
+        let mut c = active.borrow_mut();
+        let events: Vec<_> = c.events().collect();
+        for e in events {
+            if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+                let (sz, fin) = c.stream_recv(stream_id, &mut buf).expect("recv");
+                let sent = c.stream_send(stream_id, &buf[..sz]).expect("send");
+                assert_eq!(sent, sz);
+                if fin {
+                    c.stream_close_send(stream_id).expect("close");
+                }
+            }
+        }
+    }
+}
+
+/// Resource usage at a point in time.
+#[derive(Debug, Default, Clone, Copy)]
+struct Sample {
+    rss_kb: usize,
+    fds: usize,
+    connections: usize,
+    timers: usize,
+}
+
+impl Sample {
+    fn take(server: &Server) -> Self {
+        Self {
+            rss_kb: rss_kb(),
+            fds: open_fds(),
+            connections: server.connection_table_len(),
+            timers: server.timer_count(),
+        }
+    }
+}
+
+/// Resident set size, in kilobytes.  This only works on Linux.
+fn rss_kb() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|l| l.starts_with("VmRSS:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+/// The number of open file descriptors.  This only works on Linux.
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").map_or(0, |d| d.count())
+}
+
+/// Determine if the last `window` samples each show growth.
+fn growing(samples: &[Sample], window: usize, f: impl Fn(&Sample) -> usize) -> bool {
+    if samples.len() <= window {
+        return false;
+    }
+    samples[samples.len() - window - 1..]
+        .windows(2)
+        .all(|w| f(&w[1]) > f(&w[0]))
+}
+
+fn check(samples: &[Sample], window: usize) {
+    let checks: &[(&str, fn(&Sample) -> usize)] = &[
+        ("RSS", |s| s.rss_kb),
+        ("file descriptors", |s| s.fds),
+        ("connection table", |s| s.connections),
+        ("timers", |s| s.timers),
+    ];
+    for (name, f) in checks {
+        if growing(samples, window, f) {
+            eprintln!("FAIL: {} grew over {} samples", name, window);
+            eprintln!("{:?}", &samples[samples.len() - window - 1..]);
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args = Args::from_args();
+    assert!(args.concurrency > 0);
+    fixture_init();
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(args.duration);
+    let interval = Duration::from_secs(args.interval);
+    let mut next_sample = start + interval;
+    let mut samples = Vec::new();
+
+    let mut server = Server::new(
+        start,
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+    );
+    let mut clients: HashMap<SocketAddr, SoakClient> = HashMap::new();
+    let mut next_port = FIRST_PORT;
+    let mut completed = 0_u64;
+
+    loop {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+
+        while clients.len() < args.concurrency {
+            let mut addr = loopback();
+            addr.set_port(next_port);
+            next_port = next_port.checked_add(1).unwrap_or(FIRST_PORT);
+            clients.insert(addr, SoakClient::new(addr));
+        }
+
+        let mut to_server: Vec<Datagram> = Vec::new();
+        for c in clients.values_mut() {
+            c.handle_events(now);
+            to_server.extend(c.conn.process_iter(None, now));
+        }
+
+        let mut to_clients: Vec<Datagram> = Vec::new();
+        for d in to_server.drain(..) {
+            to_clients.extend(server.process_iter(Some(d), now));
+        }
+        serve(&mut server);
+        to_clients.extend(server.process_iter(None, now));
+
+        let idle = to_clients.is_empty();
+        for d in to_clients {
+            if let Some(c) = clients.get_mut(&d.destination()) {
+                c.conn.process_input(d, now);
+            }
+        }
+
+        let before = clients.len();
+        clients.retain(|_, c| !c.closed());
+        completed += (before - clients.len()) as u64;
+
+        if now >= next_sample {
+            let s = Sample::take(&server);
+            println!(
+                "{:?}: {} connections completed, {:?}",
+                now - start,
+                completed,
+                s
+            );
+            samples.push(s);
+            check(&samples, args.window);
+            next_sample += interval;
+        }
+
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    println!("Completed {} connections", completed);
+}
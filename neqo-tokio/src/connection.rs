@@ -0,0 +1,337 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A connection that is driven by tokio.
+
+use crate::driver::{drive, Endpoint};
+use crate::stream::Stream;
+use crate::{Error, Res};
+use futures::future::poll_fn;
+use neqo_common::{matches, qdebug, qinfo, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::server::ActiveConnectionRef;
+use neqo_transport::{
+    AppError, Connection, ConnectionEvent, FixedConnectionIdManager, Output, State, StreamType,
+};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Instant;
+
+pub(crate) type SharedRef = Rc<RefCell<Shared>>;
+
+/// Decides whether to accept the certificate that the server presented,
+/// which is available from `Connection::peer_certificate()`.
+pub type Verifier = Box<dyn Fn(&Connection) -> AuthenticationStatus>;
+
+/// How a connection is held.  A server owns its connections, so handles to
+/// them go through the server's reference.
+pub(crate) enum ConnRef {
+    Client(Connection),
+    Server(ActiveConnectionRef),
+}
+
+/// The state that a connection shares with its handles.
+pub(crate) struct Shared {
+    conn: ConnRef,
+    notify: Rc<Notify>,
+    /// Set when the application did something that might need sending.
+    /// A server uses this to find connections that need attention.
+    kicked: bool,
+    readable: HashMap<u64, Waker>,
+    writable: HashMap<u64, Waker>,
+    /// Streams opened by the peer that haven't been accepted.
+    incoming: VecDeque<u64>,
+    accept: Option<Waker>,
+    state: Vec<Waker>,
+    /// How a client checks the server certificate.  A server has none.
+    verifier: Option<Verifier>,
+}
+
+impl Shared {
+    pub(crate) fn new(conn: ConnRef, notify: Rc<Notify>, verifier: Option<Verifier>) -> Self {
+        Self {
+            conn,
+            notify,
+            kicked: false,
+            readable: HashMap::new(),
+            writable: HashMap::new(),
+            incoming: VecDeque::new(),
+            accept: None,
+            state: Vec::new(),
+            verifier,
+        }
+    }
+
+    pub(crate) fn with_conn<R>(&mut self, f: impl FnOnce(&mut Connection) -> R) -> R {
+        match &mut self.conn {
+            ConnRef::Client(c) => f(c),
+            ConnRef::Server(c) => f(&mut *c.borrow_mut()),
+        }
+    }
+
+    pub(crate) fn state(&mut self) -> State {
+        self.with_conn(|c| c.state().clone())
+    }
+
+    /// True if the connection can no longer be used.
+    pub(crate) fn closing(&mut self) -> bool {
+        matches!(
+            self.state(),
+            State::Closing { .. } | State::Draining { .. } | State::Closed { .. }
+        )
+    }
+
+    /// Let the driver know that there might be something to send.
+    pub(crate) fn kick(&mut self) {
+        self.kicked = true;
+        self.notify.notify();
+    }
+
+    /// Clear the flag that `kick()` sets, returning the connection if it was set.
+    pub(crate) fn take_kicked(&mut self) -> Option<ActiveConnectionRef> {
+        match (&self.conn, self.kicked) {
+            (ConnRef::Server(c), true) => {
+                self.kicked = false;
+                Some(c.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn wait_readable(&mut self, stream_id: u64, waker: &Waker) {
+        self.readable.insert(stream_id, waker.clone());
+    }
+
+    pub(crate) fn wait_writable(&mut self, stream_id: u64, waker: &Waker) {
+        self.writable.insert(stream_id, waker.clone());
+    }
+
+    fn wake_all(&mut self) {
+        let wakers = self
+            .readable
+            .drain()
+            .chain(self.writable.drain())
+            .map(|(_, w)| w)
+            .chain(self.accept.take())
+            .chain(self.state.drain(..));
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    /// Wake handles that are waiting on connection events.  Returns true
+    /// if the connection became established.
+    pub(crate) fn handle_events(&mut self) -> bool {
+        let mut established = false;
+        let events: Vec<_> = self.with_conn(|c| c.events().collect());
+        for e in events {
+            match e {
+                ConnectionEvent::AuthenticationNeeded => {
+                    let verifier = self.verifier.take();
+                    self.with_conn(|c| {
+                        // Without a verifier, nothing can be trusted.
+                        let status = verifier
+                            .as_ref()
+                            .map_or(AuthenticationStatus::CertUntrusted, |v| v(c));
+                        qdebug!("Certificate authentication: {:?}", status);
+                        c.authenticated(status, Instant::now());
+                    });
+                    self.verifier = verifier;
+                    self.kick();
+                }
+                ConnectionEvent::NewStream { stream_id, .. } => {
+                    self.incoming.push_back(stream_id);
+                    if let Some(w) = self.accept.take() {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id }
+                | ConnectionEvent::RecvStreamReset { stream_id, .. } => {
+                    if let Some(w) = self.readable.remove(&stream_id) {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::SendStreamWritable { stream_id }
+                | ConnectionEvent::SendStreamStopSending { stream_id, .. } => {
+                    if let Some(w) = self.writable.remove(&stream_id) {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::StateChange(state) => {
+                    qdebug!("Connection state {:?}", state);
+                    established |= state == State::Connected;
+                    for w in self.state.drain(..) {
+                        w.wake();
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.closing() {
+            self.wake_all();
+        }
+        established
+    }
+}
+
+/// The client connection's own driver runs it directly.
+impl Endpoint for Shared {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        self.kicked = false;
+        self.with_conn(|c| c.process(dgram, now))
+    }
+
+    fn dispatch(&mut self) {
+        self.handle_events();
+    }
+
+    fn done(&self) -> bool {
+        let closed = |state: &State| matches!(state, State::Closed { .. });
+        match &self.conn {
+            ConnRef::Client(c) => closed(c.state()),
+            ConnRef::Server(c) => closed(c.borrow().state()),
+        }
+    }
+}
+
+/// A QUIC connection.  This can be cloned; all clones refer to the same
+/// connection.  A connection keeps running until it closes, so call
+/// `close()` once it is no longer needed.
+#[derive(Clone)]
+pub struct AsyncConnection {
+    shared: SharedRef,
+}
+
+impl AsyncConnection {
+    pub(crate) fn from_shared(shared: SharedRef) -> Self {
+        Self { shared }
+    }
+
+    /// Connect to `server` from a newly bound socket.
+    /// The task that drives the connection is started with
+    /// `tokio::task::spawn_local()`.  `verifier` decides whether the server
+    /// certificate is acceptable; the connection fails if it isn't.
+    pub async fn connect(
+        server_name: &str,
+        protocols: &[impl AsRef<str>],
+        server: SocketAddr,
+        verifier: Verifier,
+    ) -> Res<Self> {
+        let any = match server {
+            SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::from([0; 4])),
+            SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::from([0; 16])),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+        let conn = Connection::new_client(
+            server_name,
+            protocols,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+            socket.local_addr()?,
+            server,
+        )?;
+        Ok(Self::new(conn, socket, verifier))
+    }
+
+    /// Run a client connection that was made elsewhere on `socket`.
+    pub fn new(conn: Connection, socket: UdpSocket, verifier: Verifier) -> Self {
+        let notify = Rc::new(Notify::new());
+        let shared = Rc::new(RefCell::new(Shared::new(
+            ConnRef::Client(conn),
+            notify.clone(),
+            Some(verifier),
+        )));
+        let driver = drive(shared.clone(), socket, notify);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = driver.await {
+                qinfo!("Connection driver failed: {}", e);
+            }
+        });
+        Self { shared }
+    }
+
+    pub fn state(&self) -> State {
+        self.shared.borrow_mut().state()
+    }
+
+    /// Wait for the state of the connection to satisfy `f`.
+    async fn wait_state(&self, f: impl Fn(&State) -> bool) -> State {
+        poll_fn(|cx| {
+            let mut s = self.shared.borrow_mut();
+            let state = s.state();
+            if f(&state) {
+                Poll::Ready(state)
+            } else {
+                s.state.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Wait for the handshake to complete.
+    pub async fn established(&self) -> Res<()> {
+        let state = self
+            .wait_state(|s| {
+                matches!(
+                    s,
+                    State::Connected
+                        | State::Closing { .. }
+                        | State::Draining { .. }
+                        | State::Closed { .. }
+                )
+            })
+            .await;
+        match state {
+            State::Connected => Ok(()),
+            State::Closing { error, .. }
+            | State::Draining { error, .. }
+            | State::Closed { error, .. } => Err(Error::Closed(error)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Wait for the connection to close completely.
+    pub async fn closed(&self) {
+        self.wait_state(|s| matches!(s, State::Closed { .. })).await;
+    }
+
+    /// Open a new stream.
+    pub fn open_stream(&self, st: StreamType) -> Res<Stream> {
+        let mut s = self.shared.borrow_mut();
+        let stream_id = s.with_conn(|c| c.stream_create(st))?;
+        Ok(Stream::new(self.shared.clone(), stream_id))
+    }
+
+    /// Wait for the peer to open a stream.  This returns `None` once the
+    /// connection is closing.
+    pub async fn accept_stream(&self) -> Option<Stream> {
+        poll_fn(|cx| {
+            let mut s = self.shared.borrow_mut();
+            if let Some(stream_id) = s.incoming.pop_front() {
+                Poll::Ready(Some(Stream::new(self.shared.clone(), stream_id)))
+            } else if s.closing() {
+                Poll::Ready(None)
+            } else {
+                s.accept = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Close the connection.
+    pub fn close(&self, error: AppError, msg: &str) {
+        let mut s = self.shared.borrow_mut();
+        s.with_conn(|c| c.close(Instant::now(), error, msg));
+        s.kick();
+    }
+}
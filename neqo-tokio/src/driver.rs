@@ -0,0 +1,77 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The task that moves datagrams between a socket and an endpoint.
+
+use neqo_common::{qdebug, qwarn, Datagram};
+use neqo_transport::{Output, OutputIter};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time::{delay_until, Instant as TokioInstant};
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Larger than any datagram that neqo will accept.
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Something that a driver can run: a client connection or a server.
+pub(crate) trait Endpoint {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output;
+    /// Pass on events to any handles that are waiting for them.
+    fn dispatch(&mut self);
+    /// True once there is nothing more to do.
+    fn done(&self) -> bool;
+}
+
+/// Run `endpoint` until it is done.  `notify` is used by handles to wake the
+/// driver after the application does something that might need sending.
+pub(crate) async fn drive<E: Endpoint>(
+    endpoint: Rc<RefCell<E>>,
+    mut socket: UdpSocket,
+    notify: Rc<Notify>,
+) -> io::Result<()> {
+    let local = socket.local_addr()?;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut input = None;
+    loop {
+        let now = Instant::now();
+        let (out, ready_at) = {
+            let mut e = endpoint.borrow_mut();
+            let mut iter = OutputIter::new(|| e.process(input.take(), now));
+            let out: Vec<_> = iter.by_ref().collect();
+            (out, iter.ready_at(now))
+        };
+        endpoint.borrow_mut().dispatch();
+
+        for d in out {
+            let sent = socket.send_to(&d[..], &d.destination()).await?;
+            if sent != d.len() {
+                qwarn!("Unable to send all {} bytes of datagram", d.len());
+            }
+        }
+        if endpoint.borrow().done() {
+            qdebug!("Driver finished");
+            return Ok(());
+        }
+
+        let timer = delay_until(TokioInstant::from_std(ready_at.unwrap_or(now)));
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (sz, remote) = res?;
+                if sz == buf.len() {
+                    qwarn!("Discarding datagram that might be truncated");
+                } else {
+                    input = Some(Datagram::new(remote, local, &buf[..sz]));
+                }
+            }
+            _ = timer, if ready_at.is_some() => {}
+            _ = notify.notified() => {}
+        }
+    }
+}
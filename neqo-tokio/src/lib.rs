@@ -0,0 +1,68 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An adapter that runs neqo connections on tokio.
+//
+// `AsyncConnection` and `AsyncServer` each own a UDP socket and a task that
+// moves datagrams between that socket and the connection, running timers as
+// needed.  Streams are exposed as `Stream`, which implements `AsyncRead` and
+// `AsyncWrite`.
+//
+// Connections use `Rc` internally, so everything here has to run on a single
+// thread.  Use a `tokio::task::LocalSet`; the driver tasks are started with
+// `tokio::task::spawn_local()`.
+
+#![deny(warnings)]
+
+mod connection;
+mod driver;
+mod server;
+mod stream;
+
+pub use connection::{AsyncConnection, Verifier};
+pub use server::AsyncServer;
+pub use stream::Stream;
+
+use neqo_transport::ConnectionError;
+use std::io;
+
+pub type Res<T> = Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Transport(neqo_transport::Error),
+    /// The connection closed; this is why.
+    Closed(ConnectionError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<neqo_transport::Error> for Error {
+    fn from(err: neqo_transport::Error) -> Self {
+        Error::Transport(err)
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Transport(e) => Some(e),
+            Error::Closed(_) => None,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "neqo-tokio error: {:?}", self)
+    }
+}
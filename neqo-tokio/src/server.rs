@@ -0,0 +1,132 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A server that is driven by tokio.
+
+use crate::connection::{AsyncConnection, ConnRef, Shared, SharedRef};
+use crate::driver::{drive, Endpoint};
+use crate::Res;
+use futures::future::poll_fn;
+use neqo_common::{qinfo, Datagram};
+use neqo_transport::server::Server;
+use neqo_transport::Output;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Instant;
+
+struct ServerEndpoint {
+    server: Server,
+    notify: Rc<Notify>,
+    /// Connections that the application might hold handles for, by ID.
+    connections: HashMap<u64, SharedRef>,
+    /// Established connections that haven't been accepted.
+    incoming: VecDeque<SharedRef>,
+    accept: Option<Waker>,
+}
+
+impl Endpoint for ServerEndpoint {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        for c in self.connections.values() {
+            if let Some(active) = c.borrow_mut().take_kicked() {
+                self.server.add_to_waiting(active);
+            }
+        }
+        self.server.process(dgram, now)
+    }
+
+    fn dispatch(&mut self) {
+        let notify = &self.notify;
+        for active in self.server.active_connections() {
+            let id = active.id();
+            let c = self
+                .connections
+                .entry(id)
+                .or_insert_with(|| {
+                    Rc::new(RefCell::new(Shared::new(
+                        ConnRef::Server(active),
+                        notify.clone(),
+                        None,
+                    )))
+                })
+                .clone();
+            let mut s = c.borrow_mut();
+            if s.handle_events() {
+                qinfo!("Server connection {} established", id);
+                self.incoming.push_back(c.clone());
+                if let Some(w) = self.accept.take() {
+                    w.wake();
+                }
+            }
+            if s.done() {
+                self.connections.remove(&id);
+            }
+        }
+    }
+
+    fn done(&self) -> bool {
+        false
+    }
+}
+
+/// A QUIC server.  This can be cloned; all clones refer to the same server.
+/// The server runs until the task that drives it is dropped.
+#[derive(Clone)]
+pub struct AsyncServer {
+    endpoint: Rc<RefCell<ServerEndpoint>>,
+    local_addr: SocketAddr,
+}
+
+impl AsyncServer {
+    /// Run `server` on a socket bound to `addr`.
+    /// The task that drives the server is started with
+    /// `tokio::task::spawn_local()`.
+    pub async fn bind(addr: SocketAddr, server: Server) -> Res<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        let notify = Rc::new(Notify::new());
+        let endpoint = Rc::new(RefCell::new(ServerEndpoint {
+            server,
+            notify: notify.clone(),
+            connections: HashMap::new(),
+            incoming: VecDeque::new(),
+            accept: None,
+        }));
+        let driver = drive(endpoint.clone(), socket, notify);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = driver.await {
+                qinfo!("Server driver failed: {}", e);
+            }
+        });
+        Ok(Self {
+            endpoint,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait for a connection to be established.
+    pub async fn accept(&self) -> AsyncConnection {
+        poll_fn(|cx| {
+            let mut e = self.endpoint.borrow_mut();
+            if let Some(c) = e.incoming.pop_front() {
+                Poll::Ready(AsyncConnection::from_shared(c))
+            } else {
+                e.accept = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
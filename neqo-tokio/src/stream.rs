@@ -0,0 +1,118 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Streams as `AsyncRead` and `AsyncWrite`.
+
+use crate::connection::SharedRef;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+fn io_error(e: neqo_transport::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")
+}
+
+/// A stream on an `AsyncConnection`.  Dropping this doesn't do anything to
+/// the stream; use `shutdown()` to end the sending side.
+pub struct Stream {
+    shared: SharedRef,
+    id: u64,
+    /// Set once the end of the stream has been read.
+    fin: bool,
+}
+
+impl Stream {
+    pub(crate) fn new(shared: SharedRef, id: u64) -> Self {
+        Self {
+            shared,
+            id,
+            fin: false,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.fin || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let id = this.id;
+        let mut s = this.shared.borrow_mut();
+        match s.with_conn(|c| c.stream_recv(id, buf)) {
+            Ok((0, false)) => {
+                if s.closing() {
+                    return Poll::Ready(Err(closed()));
+                }
+                s.wait_readable(id, cx.waker());
+                Poll::Pending
+            }
+            Ok((sz, fin)) => {
+                this.fin = fin;
+                // Reading might release flow control credit.
+                s.kick();
+                Poll::Ready(Ok(sz))
+            }
+            Err(e) => Poll::Ready(Err(io_error(e))),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let id = self.id;
+        let mut s = self.shared.borrow_mut();
+        if s.closing() {
+            return Poll::Ready(Err(closed()));
+        }
+        match s.with_conn(|c| c.stream_send(id, buf)) {
+            Ok(0) => {
+                s.wait_writable(id, cx.waker());
+                Poll::Pending
+            }
+            Ok(sz) => {
+                s.kick();
+                Poll::Ready(Ok(sz))
+            }
+            Err(e) => Poll::Ready(Err(io_error(e))),
+        }
+    }
+
+    /// Data is sent as soon as the connection allows, so this doesn't wait.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// End the sending side of the stream.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let id = self.id;
+        let mut s = self.shared.borrow_mut();
+        let res = s.with_conn(|c| c.stream_close_send(id));
+        s.kick();
+        Poll::Ready(res.map_err(io_error))
+    }
+}
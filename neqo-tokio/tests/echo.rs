@@ -0,0 +1,111 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![deny(warnings)]
+
+use neqo_common::matches;
+use neqo_crypto::AuthenticationStatus;
+use neqo_tokio::{AsyncConnection, AsyncServer, Error};
+use neqo_transport::server::Server;
+use neqo_transport::{FixedConnectionIdManager, State, StreamType};
+use test_fixture::{self, anti_replay, fixture_init};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime;
+use tokio::task::{self, LocalSet};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+fn run(f: impl std::future::Future<Output = ()>) {
+    fixture_init();
+    let mut rt = runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("build a runtime");
+    LocalSet::new().block_on(&mut rt, f);
+}
+
+async fn echo_server() -> AsyncServer {
+    let server = Server::new(
+        Instant::now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+    );
+    let server = AsyncServer::bind("127.0.0.1:0".parse().unwrap(), server)
+        .await
+        .expect("bind server");
+    let s = server.clone();
+    task::spawn_local(async move {
+        loop {
+            let conn = s.accept().await;
+            task::spawn_local(async move {
+                while let Some(mut stream) = conn.accept_stream().await {
+                    task::spawn_local(async move {
+                        let mut buf = Vec::new();
+                        stream.read_to_end(&mut buf).await.expect("read");
+                        stream.write_all(&buf).await.expect("write");
+                        stream.shutdown().await.expect("shutdown");
+                    });
+                }
+            });
+        }
+    });
+    server
+}
+
+#[test]
+fn echo() {
+    run(async {
+        let server = echo_server().await;
+        let client = AsyncConnection::connect(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            server.local_addr(),
+            // The test certificate isn't issued by a trusted CA.
+            Box::new(|_| AuthenticationStatus::Ok),
+        )
+        .await
+        .expect("connect");
+        client.established().await.expect("established");
+
+        // Enough data to need more than one packet each way.
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let mut stream = client.open_stream(StreamType::BiDi).expect("open");
+        stream.write_all(&data).await.expect("write");
+        stream.shutdown().await.expect("shutdown");
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.expect("read");
+        assert_eq!(buf, data);
+
+        client.close(0, "done");
+        client.closed().await;
+        assert!(matches!(client.state(), State::Closed { .. }));
+    });
+}
+
+#[test]
+fn untrusted_server() {
+    run(async {
+        let server = echo_server().await;
+        let client = AsyncConnection::connect(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            server.local_addr(),
+            Box::new(|c| {
+                assert!(c.peer_certificate().is_some());
+                AuthenticationStatus::CertUntrusted
+            }),
+        )
+        .await
+        .expect("connect");
+        let res = client.established().await;
+        assert!(matches!(res, Err(Error::Closed(..))));
+    });
+}
@@ -0,0 +1,159 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A chat room.  Each client sends a few messages to the server, which passes
+// them on to every other client, including those that join later.
+//
+// Messages would suit the QUIC DATAGRAM extension, but neqo doesn't support
+// it, so each message is sent on its own unidirectional stream instead.  That
+// costs little more than a datagram, but messages are delivered reliably.
+
+#![deny(warnings)]
+
+mod common;
+
+use common::{client_events, closing, read_stream, Outgoing};
+use neqo_common::{matches, qinfo};
+use neqo_transport::server::{ActiveConnectionRef, Server};
+use neqo_transport::{Connection, ConnectionEvent, State, StreamType};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Instant;
+
+const CLIENTS: usize = 3;
+const MESSAGES: usize = 3;
+
+/// Send one message.  Messages are small enough that all of a message is
+/// accepted straight away.
+fn send_message(c: &mut Connection, msg: &[u8]) {
+    let mut out = Outgoing::open(c, StreamType::UniDi, msg.to_vec());
+    assert!(out.write(c), "message fits in the stream");
+}
+
+/// The chat server.  This stops once `count` clients have left.
+fn chat_server(socket: &UdpSocket, count: usize) {
+    let mut server = common::server();
+    // Every message so far, with the connection that sent it.
+    let mut history: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut members: HashMap<u64, ActiveConnectionRef> = HashMap::new();
+    let mut partial: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
+    let mut left = HashSet::new();
+    common::run(socket, &mut server, |server: &mut Server| {
+        let mut received = Vec::new();
+        for mut active in server.active_connections() {
+            let id = active.id();
+            let joined = !members.contains_key(&id) && *active.borrow().state() == State::Connected;
+            if joined {
+                qinfo!("Client {} joined", id);
+                members.insert(id, active.clone());
+            }
+            let mut c = active.borrow_mut();
+            let events: Vec<_> = c.events().collect();
+            if closing(c.state()) {
+                if members.remove(&id).is_some() {
+                    qinfo!("Client {} left", id);
+                }
+                left.insert(id);
+                continue;
+            }
+            if joined {
+                // Catch up on what was said before this client arrived.
+                for (_, msg) in history.iter().filter(|(from, _)| *from != id) {
+                    send_message(&mut c, msg);
+                }
+            }
+            for e in events {
+                if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+                    let buf = partial.entry((id, stream_id)).or_default();
+                    if read_stream(&mut c, stream_id, buf) {
+                        let msg = partial.remove(&(id, stream_id)).unwrap();
+                        received.push((id, msg));
+                    }
+                }
+            }
+        }
+
+        // Pass on new messages to everyone else.
+        for (from, msg) in received {
+            qinfo!("Message from {}: {}", from, String::from_utf8_lossy(&msg));
+            for (id, member) in &mut members {
+                if *id != from {
+                    send_message(&mut member.borrow_mut(), &msg);
+                }
+            }
+            history.push((from, msg));
+        }
+        for member in members.values() {
+            server.add_to_waiting(member.clone());
+        }
+        left.len() < count
+    })
+    .expect("run server");
+}
+
+/// A client that sends some messages, then leaves once it has seen all the
+/// messages from the other clients.  Returns the messages it saw.
+fn chat_client(server: SocketAddr, name: &str, expected: usize) -> Vec<String> {
+    let socket = common::bind();
+    let mut client = common::client(&socket, server);
+    let mut partial: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut seen = Vec::new();
+    common::run(&socket, &mut client, |c| {
+        for e in client_events(c) {
+            match e {
+                ConnectionEvent::StateChange(State::Connected) => {
+                    for i in 0..MESSAGES {
+                        send_message(c, format!("{}: message {}", name, i).as_bytes());
+                    }
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    let buf = partial.entry(stream_id).or_default();
+                    if read_stream(c, stream_id, buf) {
+                        let msg = partial.remove(&stream_id).unwrap();
+                        seen.push(String::from_utf8(msg).expect("messages are text"));
+                        if seen.len() == expected {
+                            c.close(Instant::now(), 0, "bye");
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        !matches!(c.state(), State::Closed { .. })
+    })
+    .expect("run client");
+    seen
+}
+
+fn main() {
+    let socket = common::bind();
+    let addr = socket.local_addr().unwrap();
+    let server = thread::spawn(move || chat_server(&socket, CLIENTS));
+
+    let expected = (CLIENTS - 1) * MESSAGES;
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            let name = format!("client{}", i);
+            thread::spawn(move || (chat_client(addr, &name, expected), name))
+        })
+        .collect();
+    for c in clients {
+        let (seen, name) = c.join().unwrap();
+        for msg in &seen {
+            println!("{} saw {}", name, msg);
+        }
+        assert_eq!(seen.len(), expected);
+        assert!(seen.iter().all(|m| !m.starts_with(&format!("{}:", name))));
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn run_chat() {
+    main();
+}
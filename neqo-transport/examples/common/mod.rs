@@ -0,0 +1,276 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The event loop that the examples share.  This only uses the public API of
+// neqo-transport and a blocking socket from std.
+
+#![allow(dead_code)]
+
+use neqo_common::{qinfo, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::server::Server;
+use neqo_transport::{
+    Connection, ConnectionEvent, FixedConnectionIdManager, Output, State, StreamType,
+};
+use test_fixture::{self, anti_replay, fixture_init};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Something that turns datagrams into more datagrams: a `Connection` or a
+/// `Server`.
+pub trait Endpoint {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output;
+}
+
+impl Endpoint for Connection {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        Connection::process(self, dgram, now)
+    }
+}
+
+impl Endpoint for Server {
+    fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        Server::process(self, dgram, now)
+    }
+}
+
+/// Pass `dgram` to the endpoint, then send everything it has to send.
+/// Returns how long the endpoint can wait for the next datagram.
+fn flush(
+    socket: &UdpSocket,
+    endpoint: &mut impl Endpoint,
+    dgram: Option<Datagram>,
+) -> io::Result<Option<Duration>> {
+    let mut dgram = dgram;
+    loop {
+        match endpoint.process(dgram.take(), Instant::now()) {
+            Output::Datagram(d) => {
+                socket.send_to(&d[..], d.destination())?;
+            }
+            Output::Callback(t) => return Ok(Some(t)),
+            Output::None => return Ok(None),
+        }
+    }
+}
+
+/// Run `endpoint` on `socket` until `app` returns false.
+///
+/// This is the usual shape of a loop that drives neqo: feed a datagram in,
+/// send everything that comes out, let the application act on any events,
+/// then wait until either another datagram arrives or the timer expires.
+/// The application is called after every datagram and timeout, and whatever
+/// it does is sent before the loop waits again.
+pub fn run<E: Endpoint>(
+    socket: &UdpSocket,
+    endpoint: &mut E,
+    mut app: impl FnMut(&mut E) -> bool,
+) -> io::Result<()> {
+    let local = socket.local_addr()?;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut dgram = None;
+    loop {
+        flush(socket, endpoint, dgram.take())?;
+        if !app(endpoint) {
+            return Ok(());
+        }
+        let timeout = flush(socket, endpoint, None)?;
+
+        // A zero timeout means "don't wait", which `set_read_timeout`
+        // doesn't allow, so wait for as little as possible instead.
+        socket.set_read_timeout(timeout.map(|t| t.max(Duration::from_millis(1))))?;
+        match socket.recv_from(&mut buf) {
+            Ok((sz, remote)) => {
+                dgram = Some(Datagram::new(remote, local, &buf[..sz]));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Bind a socket on the loopback address.
+pub fn bind() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").expect("bind a socket")
+}
+
+pub fn server() -> Server {
+    fixture_init();
+    Server::new(
+        Instant::now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+    )
+}
+
+pub fn client(socket: &UdpSocket, server: SocketAddr) -> Connection {
+    fixture_init();
+    Connection::new_client(
+        test_fixture::DEFAULT_SERVER_NAME,
+        test_fixture::DEFAULT_ALPN,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+        socket.local_addr().expect("socket has an address"),
+        server,
+    )
+    .expect("create a client")
+}
+
+pub fn closing(state: &State) -> bool {
+    match state {
+        State::Closing { .. } | State::Draining { .. } | State::Closed { .. } => true,
+        _ => false,
+    }
+}
+
+/// Handle the events that every client needs to.  The examples trust the
+/// test certificate; a real client checks `peer_certificate()` here.
+/// Returns the events that the caller might be interested in.
+pub fn client_events(c: &mut Connection) -> Vec<ConnectionEvent> {
+    let mut events = Vec::new();
+    for e in c.events().collect::<Vec<_>>() {
+        if e == ConnectionEvent::AuthenticationNeeded {
+            c.authenticated(AuthenticationStatus::Ok, Instant::now());
+        } else {
+            events.push(e);
+        }
+    }
+    events
+}
+
+/// Read everything that is available on a stream into `buf`.
+/// Returns true if the end of the stream was read.
+pub fn read_stream(c: &mut Connection, stream_id: u64, buf: &mut Vec<u8>) -> bool {
+    let mut chunk = [0; 4096];
+    loop {
+        let (sz, fin) = c.stream_recv(stream_id, &mut chunk).expect("read");
+        buf.extend_from_slice(&chunk[..sz]);
+        if fin {
+            return true;
+        }
+        if sz == 0 {
+            return false;
+        }
+    }
+}
+
+/// Data that is waiting to be written to a stream.  Streams only take as
+/// much as flow control allows, so the rest is kept until the stream
+/// becomes writable.
+pub struct Outgoing {
+    pub stream_id: u64,
+    data: Vec<u8>,
+    offset: usize,
+    fin: bool,
+}
+
+impl Outgoing {
+    pub fn new(stream_id: u64, data: Vec<u8>, fin: bool) -> Self {
+        Self {
+            stream_id,
+            data,
+            offset: 0,
+            fin,
+        }
+    }
+
+    /// Open a new stream to send `data` on.
+    pub fn open(c: &mut Connection, st: StreamType, data: Vec<u8>) -> Self {
+        let stream_id = c.stream_create(st).expect("open a stream");
+        Self::new(stream_id, data, true)
+    }
+
+    /// Write as much as possible.  Returns true once everything is written.
+    pub fn write(&mut self, c: &mut Connection) -> bool {
+        if self.offset < self.data.len() {
+            self.offset += c
+                .stream_send(self.stream_id, &self.data[self.offset..])
+                .expect("write");
+        }
+        let done = self.offset == self.data.len();
+        if done && self.fin {
+            c.stream_close_send(self.stream_id).expect("close");
+            self.fin = false;
+        }
+        done
+    }
+
+    /// The number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.offset
+    }
+}
+
+/// A request stream on the server.  The whole request is read before the
+/// response is made.
+#[derive(Default)]
+struct Exchange {
+    request: Vec<u8>,
+    response: Option<Outgoing>,
+}
+
+/// Run a server on `socket` that answers each bidirectional stream with
+/// whatever `respond` makes from the request on it.  This stops once `count`
+/// connections have closed.
+pub fn serve(socket: &UdpSocket, count: usize, mut respond: impl FnMut(&[u8]) -> Vec<u8>) {
+    let mut server = server();
+    let mut streams: HashMap<(u64, u64), Exchange> = HashMap::new();
+    let mut closed = HashSet::new();
+    run(socket, &mut server, |server| {
+        for mut active in server.active_connections() {
+            let id = active.id();
+            let mut c = active.borrow_mut();
+            let events: Vec<_> = c.events().collect();
+            if closing(c.state()) {
+                // Anything that is still being sent is abandoned.
+                closed.insert(id);
+                streams.retain(|(conn, _), _| *conn != id);
+                continue;
+            }
+            for e in events {
+                let (stream_id, s) = match e {
+                    ConnectionEvent::RecvStreamReadable { stream_id } => {
+                        (stream_id, streams.entry((id, stream_id)).or_default())
+                    }
+                    ConnectionEvent::SendStreamWritable { stream_id } => {
+                        match streams.get_mut(&(id, stream_id)) {
+                            Some(s) => (stream_id, s),
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                if s.response.is_none() && read_stream(&mut c, stream_id, &mut s.request) {
+                    qinfo!(
+                        "Request of {} bytes on stream {}",
+                        s.request.len(),
+                        stream_id
+                    );
+                    let response = respond(&s.request);
+                    s.response = Some(Outgoing::new(stream_id, response, true));
+                }
+                if let Some(response) = &mut s.response {
+                    if response.write(&mut c) {
+                        streams.remove(&(id, stream_id));
+                    }
+                }
+            }
+            drop(c);
+            // Writing happened outside of `process()`, so tell the server
+            // that this connection has something to send.
+            server.add_to_waiting(active);
+        }
+        closed.len() < count
+    })
+    .expect("run server");
+}
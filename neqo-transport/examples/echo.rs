@@ -0,0 +1,72 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A stream echo server and client.  The client opens a bidirectional stream,
+// sends a message and closes the stream; the server sends everything back.
+// Both run in this process, each with its own socket and thread.
+
+#![deny(warnings)]
+
+mod common;
+
+use common::{client_events, read_stream, Outgoing};
+use neqo_common::matches;
+use neqo_transport::{ConnectionEvent, State, StreamType};
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Instant;
+
+/// Send `msg` to the server and return what comes back.
+fn echo(server: SocketAddr, msg: &[u8]) -> Vec<u8> {
+    let socket = common::bind();
+    let mut client = common::client(&socket, server);
+    let mut out: Option<Outgoing> = None;
+    let mut echoed = Vec::new();
+    common::run(&socket, &mut client, |c| {
+        for e in client_events(c) {
+            match e {
+                ConnectionEvent::StateChange(State::Connected) => {
+                    out = Some(Outgoing::open(c, StreamType::BiDi, msg.to_vec()));
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    if read_stream(c, stream_id, &mut echoed) {
+                        c.close(Instant::now(), 0, "done");
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(o) = &mut out {
+            o.write(c);
+        }
+        !matches!(c.state(), State::Closed { .. })
+    })
+    .expect("run client");
+    echoed
+}
+
+/// A message that needs several packets in each direction.
+fn message() -> Vec<u8> {
+    (0..20_000).map(|i| (i % 251) as u8).collect()
+}
+
+fn main() {
+    let socket = common::bind();
+    let addr = socket.local_addr().unwrap();
+    let server = thread::spawn(move || common::serve(&socket, 1, |request| request.to_vec()));
+
+    let msg = message();
+    let echoed = echo(addr, &msg);
+    assert_eq!(echoed, msg);
+    println!("Echoed {} bytes", echoed.len());
+    server.join().unwrap();
+}
+
+#[test]
+fn run_echo() {
+    main();
+}
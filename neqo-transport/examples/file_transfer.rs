@@ -0,0 +1,128 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A file transfer that can be resumed.  The client asks for the file from an
+// offset, so when a connection is lost partway through, a new connection
+// picks up where the last one stopped.  Progress is reported using the
+// connection statistics.
+//
+// Run with a file name to serve that file; otherwise some generated data is
+// used.  The first download is cut short to show resumption.
+
+#![deny(warnings)]
+
+mod common;
+
+use common::{client_events, read_stream, Outgoing};
+use neqo_common::matches;
+use neqo_transport::{ConnectionEvent, State, StatsDelta, StreamType};
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Instant;
+
+/// Report progress every time this much more data arrives.
+const PROGRESS_INTERVAL: usize = 64 * 1024;
+
+/// A request is the offset to start from, as 8 bytes in network byte order.
+fn request(offset: u64) -> Vec<u8> {
+    offset.to_be_bytes().to_vec()
+}
+
+fn respond(file: &[u8], request: &[u8]) -> Vec<u8> {
+    let offset = <[u8; 8]>::try_from(request).map_or(0, u64::from_be_bytes);
+    let offset = usize::try_from(offset).unwrap_or(usize::max_value());
+    file.get(offset..).unwrap_or(&[]).to_vec()
+}
+
+/// Download the file starting from `offset`.  If `limit` is set, the
+/// connection is closed once that many bytes have arrived, as though it
+/// failed.  Returns the data that arrived and whether the file is complete.
+fn download(server: SocketAddr, offset: u64, limit: Option<usize>) -> (Vec<u8>, bool) {
+    let socket = common::bind();
+    let mut client = common::client(&socket, server);
+    let mut out: Option<Outgoing> = None;
+    let mut data = Vec::new();
+    let mut complete = false;
+    let mut last = client.stats();
+    let mut reported = 0;
+    common::run(&socket, &mut client, |c| {
+        for e in client_events(c) {
+            match e {
+                ConnectionEvent::StateChange(State::Connected) => {
+                    out = Some(Outgoing::open(c, StreamType::BiDi, request(offset)));
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    complete = read_stream(c, stream_id, &mut data);
+                    if complete || limit.map_or(false, |l| data.len() >= l) {
+                        c.close(Instant::now(), 0, "done");
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(o) = &mut out {
+            o.write(c);
+        }
+
+        if data.len() >= reported + PROGRESS_INTERVAL || (complete && data.len() > reported) {
+            let now = c.stats();
+            let delta = StatsDelta::between(&last, &now).expect("snapshots are in order");
+            println!(
+                "{} bytes from offset {}: {} packets received, {} sent, {} lost",
+                data.len(),
+                offset,
+                delta.packets_rx,
+                delta.packets_tx,
+                delta.lost
+            );
+            last = now;
+            reported = data.len();
+        }
+        !matches!(c.state(), State::Closed { .. })
+    })
+    .expect("run client");
+    (data, complete)
+}
+
+fn transfer(file: Vec<u8>) {
+    let socket = common::bind();
+    let addr = socket.local_addr().unwrap();
+    let served = file.clone();
+    let server = thread::spawn(move || {
+        // One connection is cut short, the next one finishes.
+        common::serve(&socket, 2, |req| respond(&served, req))
+    });
+
+    let (mut received, _) = download(addr, 0, Some(file.len() / 3));
+    println!("Stopped after {} bytes, resuming", received.len());
+    let (rest, complete) = download(addr, received.len() as u64, None);
+    assert!(complete);
+    received.extend_from_slice(&rest);
+    assert_eq!(received, file);
+    println!("Received all {} bytes", received.len());
+    server.join().unwrap();
+}
+
+fn generated() -> Vec<u8> {
+    (0..1_000_000).map(|i| (i % 251) as u8).collect()
+}
+
+fn main() {
+    let file = match env::args().nth(1) {
+        Some(name) => fs::read(name).expect("read the file"),
+        None => generated(),
+    };
+    transfer(file);
+}
+
+#[test]
+fn run_file_transfer() {
+    transfer(generated());
+}
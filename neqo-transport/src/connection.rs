@@ -7,41 +7,67 @@
 // The class implementing a QUIC connection.
 
 #![allow(dead_code)]
-use std::cell::RefCell;
-use std::cmp::{max, Ordering};
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min, Ordering};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fmt::{self, Debug};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use smallvec::SmallVec;
 
-use neqo_common::{hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Datagram, Decoder, Encoder};
+use neqo_common::{
+    hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, BufferPool, Datagram, DatagramBatch,
+    Decoder, Ecn, Encoder, Redact,
+};
 use neqo_crypto::agent::CertificateInfo;
+#[cfg(feature = "keepalive-offload")]
+use neqo_crypto::Cipher;
 use neqo_crypto::{
-    Agent, AntiReplay, AuthenticationStatus, Client, Epoch, HandshakeState, Record, RecordList,
-    SecretAgentInfo, Server,
+    Agent, AlpnSelector, AntiReplay, AuthenticationStatus, CertificateCompressor,
+    CertificateStatus, CertificateVerifier, Client, Epoch, HandshakeState, Record, RecordList,
+    SecretAgentInfo, SecretListener, Server, ZeroRttChecker,
 };
 
-use crate::crypto::Crypto;
+#[cfg(feature = "pq-hybrid")]
+use crate::crypto::PQ_HYBRID_GROUPS;
+use crate::crypto::{whole_messages, Crypto};
 use crate::dump::*;
-use crate::events::{ConnectionEvent, ConnectionEvents};
+use crate::events::{
+    ConnectionEvent, ConnectionEvents, EventFilter, EventSubscription, HandshakeRecord,
+};
 use crate::flow_mgr::FlowMgr;
-use crate::frame::{decode_frame, AckRange, Frame, FrameType, StreamType, TxMode};
+use crate::frame::{decode_frame, AckRange, CloseError, Frame, FrameType, StreamType, TxMode};
+use crate::grease::Grease;
+use crate::multipath::{
+    Multipath, MultipathPath, PathCrypto, PathId, PathInfo, PathStatus, LOCAL_CIDS, MAX_PATH_ID,
+    MAX_PATH_PTOS,
+};
 use crate::packet::{
-    decode_packet_hdr, decrypt_packet, encode_packet, ConnectionId, ConnectionIdDecoder, PacketHdr,
-    PacketNumberDecoder, PacketType,
+    decode_packet_hdr, decrypt_packet, retry_tag_valid, ConnectionId, ConnectionIdDecoder,
+    DatagramBuilder, FixedLengthConnectionIdDecoder, LengthPrefixConnectionIdDecoder, PacketHdr,
+    PacketNumberDecoder, PacketType, MAX_CONNECTION_ID_LEN,
+};
+#[cfg(feature = "keepalive-offload")]
+use crate::packet::{pn_length, short_header_prefix};
+use crate::qlog::Qlog;
+use crate::ratelimit::RateLimiter;
+use crate::recovery::{
+    LossRecovery, LossRecoveryMode, LossRecoveryState, RecoveryInfo, RecoveryToken, SentPacket,
+    TimerKind,
 };
-use crate::recovery::{LossRecovery, LossRecoveryMode, LossRecoveryState, RecoveryToken};
-use crate::recv_stream::{RecvStream, RecvStreams, RX_STREAM_DATA_WINDOW};
-use crate::send_stream::{SendStream, SendStreams};
+use crate::recv_stream::{RecvStream, RecvStreams, StreamObserver, RX_STREAM_DATA_WINDOW};
+use crate::send_stream::{SendStream, SendStreamSource, SendStreams};
+use crate::stateless_reset::reset_token;
 use crate::stats::Stats;
+use crate::stream_group::StreamGroupId;
 use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
 use crate::tparams::consts as tp_const;
-use crate::tparams::{TransportParameters, TransportParametersHandler};
+use crate::tparams::{TpZeroRttChecker, TransportParameters, TransportParametersHandler};
 use crate::tracking::{AckTracker, PNSpace};
 use crate::QUIC_VERSION;
 use crate::{AppError, ConnectionError, Error, Res};
@@ -61,6 +87,14 @@ const LOCAL_MAX_DATA: u64 = 0x3FFF; // 16,383
 
 const LOCAL_IDLE_TIMEOUT: Duration = Duration::from_secs(60); // 1 minute
 
+/// The size that datagrams containing a client Initial are padded to.
+const MIN_INITIAL_DATAGRAM_SIZE: usize = 1200;
+/// A conservative estimate of packet header and AEAD expansion, used to
+/// determine how much padding can be added to a packet when greasing.
+const PACKET_OVERHEAD: usize = 64;
+/// The number of packets that are held while waiting for keys.
+const MAX_SAVED_PACKETS: usize = 8;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 /// Client or Server.
 pub enum Role {
@@ -90,13 +124,26 @@ pub enum State {
     WaitInitial,
     Handshaking,
     Connected,
+    /// This endpoint closed the connection.  CONNECTION_CLOSE is sent in
+    /// response to any packet that arrives until `timeout`.
     Closing {
         error: ConnectionError,
         frame_type: FrameType,
         msg: String,
         timeout: Instant,
     },
-    Closed(ConnectionError),
+    /// The peer closed the connection.  Nothing is sent until `timeout`.
+    Draining {
+        error: ConnectionError,
+        reason: String,
+        timeout: Instant,
+    },
+    /// The connection is closed.  If the peer closed the connection,
+    /// `reason` holds the reason phrase that it sent.
+    Closed {
+        error: ConnectionError,
+        reason: Option<String>,
+    },
 }
 
 // Implement Ord so that we can enforce monotonic state progression.
@@ -117,7 +164,9 @@ impl PartialOrd for State {
             (_, State::Connected) => Ordering::Greater,
             (State::Closing { .. }, _) => Ordering::Less,
             (_, State::Closing { .. }) => Ordering::Greater,
-            (State::Closed(_), _) => unreachable!(),
+            (State::Draining { .. }, _) => Ordering::Less,
+            (_, State::Draining { .. }) => Ordering::Greater,
+            (State::Closed { .. }, _) => unreachable!(),
         })
     }
 }
@@ -131,12 +180,19 @@ enum ZeroRttState {
     Rejected,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Path {
-    local: SocketAddr,
-    remote: SocketAddr,
+#[derive(Clone, PartialEq)]
+pub(crate) struct Path {
+    pub(crate) local: SocketAddr,
+    pub(crate) remote: SocketAddr,
     local_cids: Vec<ConnectionId>,
     remote_cid: ConnectionId,
+    /// The DSCP that datagrams on this path are marked with.
+    dscp: u8,
+    /// The IPv6 flow label that datagrams on this path are sent with, if any.
+    flow_label: Option<u32>,
+    /// The local interface that datagrams on this path are sent from, if the
+    /// I/O layer said which one they arrived on.
+    interface: Option<u32>,
 }
 
 impl Path {
@@ -147,6 +203,21 @@ impl Path {
             remote: d.source(),
             local_cids: Vec::new(),
             remote_cid,
+            dscp: 0,
+            flow_label: None,
+            interface: d.interface(),
+        }
+    }
+
+    /// Make a datagram for sending on this path.
+    fn datagram(&self, d: Vec<u8>) -> Datagram {
+        let mut dgram = Datagram::new(self.local, self.remote, d).with_dscp(self.dscp);
+        if let Some(index) = self.interface {
+            dgram = dgram.with_interface(index);
+        }
+        match self.flow_label {
+            Some(label) => dgram.with_flow_label(label),
+            None => dgram,
         }
     }
 
@@ -155,6 +226,20 @@ impl Path {
     }
 }
 
+impl Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Path")
+            .field("local", &Redact(self.local))
+            .field("remote", &Redact(self.remote))
+            .field("local_cids", &self.local_cids)
+            .field("remote_cid", &self.remote_cid)
+            .field("dscp", &self.dscp)
+            .field("flow_label", &self.flow_label)
+            .field("interface", &self.interface)
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 /// Type returned from process() and `process_output()`. Users are required to
 /// call these repeatedly until `Callback` or `None` is returned.
@@ -184,11 +269,97 @@ impl Output {
             _ => None,
         }
     }
+
+    /// The time at which the caller needs to act, given that this output
+    /// was produced at `now`.  A datagram needs to be sent immediately.
+    /// `None` means that there is no need to call again until more input
+    /// arrives.
+    pub fn ready_at(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Output::None => None,
+            Output::Datagram(_) => Some(now),
+            Output::Callback(t) => Some(now + *t),
+        }
+    }
+}
+
+/// An iterator over the datagrams produced by repeated calls to a function
+/// that returns `Output`, which is usually `process()` or `process_output()`.
+/// This stops at the first output that isn't a datagram; that output can be
+/// retrieved with `last()` afterwards.
+pub struct OutputIter<F> {
+    source: F,
+    last: Option<Output>,
+    /// A datagram that didn't fit in the last batch.
+    pending: Option<Datagram>,
+}
+
+impl<F: FnMut() -> Output> OutputIter<F> {
+    pub fn new(source: F) -> Self {
+        Self {
+            source,
+            last: None,
+            pending: None,
+        }
+    }
+
+    /// Collect up to `max` datagrams into a batch that can be sent with
+    /// UDP_SEGMENT.  A batch ends early at a datagram that can't be added to
+    /// it; that datagram starts the next batch.  This returns `None` once
+    /// there are no more datagrams.
+    pub fn batch(&mut self, max: usize) -> Option<DatagramBatch> {
+        let mut batch = DatagramBatch::new(self.next()?);
+        while batch.count() < max {
+            match self.next() {
+                Some(d) => {
+                    if let Err(d) = batch.push(d) {
+                        self.pending = Some(d);
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Some(batch)
+    }
+
+    /// The output that ended iteration, either `Output::None` or
+    /// `Output::Callback`.  This is `None` until the iterator is exhausted.
+    pub fn last(&self) -> Option<&Output> {
+        self.last.as_ref()
+    }
+
+    /// Like `Output::ready_at()` for the output that ended iteration.
+    pub fn ready_at(&self, now: Instant) -> Option<Instant> {
+        self.last.as_ref().and_then(|o| o.ready_at(now))
+    }
+}
+
+impl<F: FnMut() -> Output> Iterator for OutputIter<F> {
+    type Item = Datagram;
+    fn next(&mut self) -> Option<Datagram> {
+        if let Some(d) = self.pending.take() {
+            return Some(d);
+        }
+        if self.last.is_some() {
+            return None;
+        }
+        match (self.source)() {
+            Output::Datagram(d) => Some(d),
+            o => {
+                self.last = Some(o);
+                None
+            }
+        }
+    }
 }
 
-pub trait ConnectionIdManager: ConnectionIdDecoder {
+pub trait ConnectionIdManager {
     fn generate_cid(&mut self) -> ConnectionId;
-    fn as_decoder(&self) -> &dyn ConnectionIdDecoder;
+    /// The decoder for connection IDs that this makes.  A connection takes
+    /// this when it is created and uses it for every packet, without
+    /// borrowing the manager.
+    fn decoder(&self) -> Arc<dyn ConnectionIdDecoder>;
 }
 /// Alias the common form for ConnectionIdManager.
 type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
@@ -202,17 +373,47 @@ impl FixedConnectionIdManager {
         Self { len }
     }
 }
-impl ConnectionIdDecoder for FixedConnectionIdManager {
-    fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId> {
-        dec.decode(self.len).map(ConnectionId::from)
-    }
-}
 impl ConnectionIdManager for FixedConnectionIdManager {
     fn generate_cid(&mut self) -> ConnectionId {
         ConnectionId::generate(self.len)
     }
-    fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
-        self
+    fn decoder(&self) -> Arc<dyn ConnectionIdDecoder> {
+        Arc::new(FixedLengthConnectionIdDecoder::new(self.len))
+    }
+}
+
+/// A LengthPrefixConnectionIdManager produces random connection IDs of a
+/// length that can be changed, with the first byte holding the length of the
+/// rest.  Peers can keep using connection IDs of the old length.
+pub struct LengthPrefixConnectionIdManager {
+    len: usize,
+}
+impl LengthPrefixConnectionIdManager {
+    /// `len` includes the byte that holds the length.
+    pub fn new(len: usize) -> Self {
+        let mut m = Self { len: 0 };
+        m.set_len(len);
+        m
+    }
+
+    /// Change the length of connection IDs that are made from now on.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(
+            len > 0 && len <= MAX_CONNECTION_ID_LEN,
+            "connection ID length out of range"
+        );
+        self.len = len;
+    }
+}
+impl ConnectionIdManager for LengthPrefixConnectionIdManager {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut cid = ConnectionId::generate(self.len);
+        // This can't truncate: the length is at most MAX_CONNECTION_ID_LEN.
+        cid.0[0] = (self.len - 1) as u8;
+        cid
+    }
+    fn decoder(&self) -> Arc<dyn ConnectionIdDecoder> {
+        Arc::new(LengthPrefixConnectionIdDecoder::default())
     }
 }
 
@@ -268,6 +469,50 @@ impl IdleTimeout {
     }
 }
 
+/// What is needed to send keepalive packets for a connection while the
+/// application is suspended.  Each keepalive packet is a short header packet
+/// that contains only a PING frame.  Packets are protected as usual for 1-RTT
+/// packets, using keys derived from `secret` with the labels "quic key",
+/// "quic iv", and "quic hp".
+#[cfg(feature = "keepalive-offload")]
+pub struct KeepaliveOffload {
+    /// The local address.
+    pub local: SocketAddr,
+    /// The remote address.
+    pub remote: SocketAddr,
+    /// The cipher suite.
+    pub cipher: Cipher,
+    /// The current 1-RTT secret for sending.
+    pub secret: Vec<u8>,
+    /// The short header up to the packet number, before header protection.
+    pub header: Vec<u8>,
+    /// The length of the packet number in the header.
+    pub pn_len: usize,
+    /// The first packet number that can be used.
+    pub first_pn: u64,
+    /// How many packet numbers, starting from `first_pn`, can be used.
+    pub count: u64,
+    /// How often to send a keepalive packet.
+    pub interval: Duration,
+}
+
+/// This leaves out `secret`, so that logging this doesn't reveal keys.
+#[cfg(feature = "keepalive-offload")]
+impl Debug for KeepaliveOffload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeepaliveOffload")
+            .field("local", &Redact(self.local))
+            .field("remote", &Redact(self.remote))
+            .field("cipher", &self.cipher)
+            .field("header", &hex(&self.header))
+            .field("pn_len", &self.pn_len)
+            .field("first_pn", &self.first_pn)
+            .field("count", &self.count)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
 /// A QUIC Connection
 ///
 /// First, create a new connection using `new_client()` or `new_server()`.
@@ -290,10 +535,17 @@ pub struct Connection {
     tps: Rc<RefCell<TransportParametersHandler>>,
     /// What we are doing with 0-RTT.
     zero_rtt_state: ZeroRttState,
+    /// Whether to keep streams and send their data again if 0-RTT is rejected.
+    resend_0rtt: bool,
     /// This object will generate connection IDs for the connection.
     cid_manager: CidMgr,
+    /// Decodes connection IDs from packets, taken from `cid_manager`.
+    cid_decoder: Arc<dyn ConnectionIdDecoder>,
     /// Network paths.  Right now, this tracks at most one path, so it uses `Option`.
+    /// Any other paths are in `multipath`.
     paths: Option<Path>,
+    /// Set once the multipath extension is negotiated.
+    multipath: Option<Multipath>,
     /// The connection IDs that we will accept.
     /// This includes any we advertise in NEW_CONNECTION_ID that haven't been bound to a path yet.
     /// During the handshake at the server, it also includes the randomized DCID pick by the client.
@@ -301,18 +553,57 @@ pub struct Connection {
     retry_info: Option<RetryInfo>,
     pub(crate) crypto: Crypto,
     pub(crate) acks: AckTracker,
+    /// The first packet number that was sent with 1-RTT keys.  The handshake
+    /// is confirmed once a packet from then on is acknowledged.
+    first_1rtt_pn: Option<u64>,
+    handshake_confirmed: bool,
     idle_timeout: IdleTimeout,
+    /// When a server received the first packet from the client.
+    handshake_start: Option<Instant>,
     pub(crate) indexes: StreamIndexes,
     connection_ids: HashMap<u64, (Vec<u8>, [u8; 16])>, // (sequence number, (connection id, reset token))
     pub(crate) send_streams: SendStreams,
     pub(crate) recv_streams: RecvStreams,
     pmtu: usize,
+    /// If set, this limits the rate at which stream data is sent.
+    rate_limit: Option<RateLimiter>,
+    /// If set, datagrams can be produced this far ahead of when the rate
+    /// limit allows, with a release time for the I/O layer to honor.
+    pacing_offload: Option<Duration>,
+    /// The size that a client pads datagrams containing Initial packets to.
+    initial_padding: usize,
     pub(crate) flow_mgr: Rc<RefCell<FlowMgr>>,
     loss_recovery: LossRecovery,
     loss_recovery_state: LossRecoveryState,
+    /// If set, the connection is abandoned when the probe timeout fires this
+    /// many times in a row.
+    max_ptos: Option<u32>,
+    /// If set, the connection is abandoned when the probe timeout fires this
+    /// many times while any one packet is outstanding.
+    max_packet_ptos: Option<u32>,
     events: ConnectionEvents,
     token: Option<Vec<u8>>,
     stats: Stats,
+    /// The number of snapshots taken by `stats()`.
+    stats_seq: Cell<u64>,
+    /// Buffers for datagrams, if the application provided them.
+    buffers: Option<BufferPool>,
+    /// Packets that arrived before the keys to decrypt them, with their epoch.
+    saved_packets: Vec<(Epoch, Datagram)>,
+    /// How many more packets can be decoded before `process_with_budget()`
+    /// has to return.
+    work_left: usize,
+    /// Whether some of the saved packets were put off because the budget
+    /// for processing them was spent, rather than for lack of keys.
+    deferred: bool,
+    /// If set, randomize some choices about what is sent.
+    grease: Option<Grease>,
+    /// The key that a server uses to make its stateless reset token.
+    reset_key: Option<Vec<u8>>,
+    /// Where packets are recorded, if the application asked for a qlog.
+    qlog: Option<Qlog>,
+    /// Identifies the connection in logs.
+    log_id: usize,
 }
 
 impl Debug for Connection {
@@ -346,9 +637,15 @@ impl Connection {
                 remote: remote_addr,
                 local_cids,
                 remote_cid: dcid.clone(),
+                dscp: 0,
+                flow_label: None,
+                interface: None,
             }),
         );
-        c.crypto.states[0] = Some(c.crypto.create_initial_state(Role::Client, &dcid));
+        c.crypto.states[0] = Some(
+            c.crypto
+                .create_initial_state(c.version, Role::Client, &dcid),
+        );
         Ok(c)
     }
 
@@ -387,6 +684,7 @@ impl Connection {
             LOCAL_IDLE_TIMEOUT.as_millis().try_into().unwrap(),
         );
         tps.set_empty(tp_const::DISABLE_MIGRATION);
+        tps.set_version_information(QUIC_VERSION, &[QUIC_VERSION]);
     }
 
     fn new(
@@ -409,26 +707,46 @@ impl Connection {
                 Role::Client => State::Init,
                 Role::Server => State::WaitInitial,
             },
+            cid_decoder: cid_manager.borrow().decoder(),
             cid_manager,
             paths,
+            multipath: None,
             valid_cids: Vec::new(),
             tps: tphandler,
             zero_rtt_state: ZeroRttState::Init,
+            resend_0rtt: false,
             retry_info: None,
             crypto,
             acks: AckTracker::default(),
+            first_1rtt_pn: None,
+            handshake_confirmed: false,
             idle_timeout: IdleTimeout::default(),
+            handshake_start: None,
             indexes: StreamIndexes::new(),
             connection_ids: HashMap::new(),
             send_streams: SendStreams::default(),
             recv_streams: RecvStreams::default(),
             pmtu: 1280,
+            rate_limit: None,
+            pacing_offload: None,
+            initial_padding: MIN_INITIAL_DATAGRAM_SIZE,
             flow_mgr: Rc::new(RefCell::new(FlowMgr::default())),
             loss_recovery: LossRecovery::new(),
             loss_recovery_state: LossRecoveryState::default(),
+            max_ptos: None,
+            max_packet_ptos: None,
             events: ConnectionEvents::default(),
             token: None,
             stats: Stats::default(),
+            stats_seq: Cell::new(0),
+            buffers: None,
+            saved_packets: Vec::new(),
+            work_left: usize::max_value(),
+            deferred: false,
+            grease: None,
+            reset_key: None,
+            qlog: None,
+            log_id: neqo_common::log::next_log_id(),
         }
     }
 
@@ -441,6 +759,374 @@ impl Connection {
             .set_bytes(tp_const::ORIGINAL_CONNECTION_ID, odcid.to_vec());
     }
 
+    /// Limit the rate at which this connection sends, in bytes per second;
+    /// `None` removes any limit.  The limit applies where datagrams are
+    /// admitted for sending, so everything counts against it, including
+    /// ACKs, the handshake and retransmissions.  Only CONNECTION_CLOSE is
+    /// sent regardless.  The limit permits bursts of up to one packet, or
+    /// 10ms at the chosen rate if that is larger.
+    pub fn set_max_send_rate(&mut self, rate: Option<u64>) -> Res<()> {
+        self.rate_limit = match rate {
+            Some(0) => return Err(Error::InvalidInput),
+            Some(rate) => {
+                let burst = max(self.pmtu, usize::try_from(rate / 100).unwrap_or(self.pmtu));
+                Some(RateLimiter::new(rate, burst))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Abandon the connection when the probe timeout fires `ptos` times in a
+    /// row, or `packet_ptos` times while any one packet is waiting to be
+    /// acknowledged, even if other packets are acknowledged in the meantime.
+    /// `None` removes a limit.  The connection then moves straight to
+    /// `State::Closed` with `Error::TooLossy`, without sending anything, so
+    /// that the application can try another server rather than waiting for
+    /// the idle timeout.
+    pub fn set_retransmission_limits(
+        &mut self,
+        ptos: Option<u32>,
+        packet_ptos: Option<u32>,
+    ) -> Res<()> {
+        if ptos == Some(0) || packet_ptos == Some(0) {
+            return Err(Error::InvalidInput);
+        }
+        self.max_ptos = ptos;
+        self.max_packet_ptos = packet_ptos;
+        Ok(())
+    }
+
+    /// Use buffers from `pool` for the datagrams that this connection makes,
+    /// and to encode frames and to encrypt and decrypt packets in.  Packets
+    /// are encrypted and decrypted in place.  Datagrams that are passed to
+    /// this connection have their buffers given back to `pool` once they have
+    /// been processed, so if the application also gives back the buffers of
+    /// datagrams it has sent and receives into buffers from `pool`, packet
+    /// data doesn't need new allocations.  The state kept for each packet,
+    /// such as for loss recovery, is still allocated.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffers = Some(pool);
+    }
+
+    /// Record the packets that this connection sends and receives in `qlog`.
+    pub fn set_qlog(&mut self, qlog: Qlog) {
+        self.qlog = Some(qlog);
+    }
+
+    /// Let the I/O layer pace sending, such as with SO_TXTIME on Linux.
+    /// Instead of holding back datagrams until the send rate limit allows
+    /// them, the connection produces datagrams up to `horizon` early, and sets
+    /// `Datagram::release_time()` to when each is to be sent.  `None` turns
+    /// this off.  This has no effect unless `set_max_send_rate()` is used.
+    pub fn set_pacing_offload(&mut self, horizon: Option<Duration>) {
+        self.pacing_offload = horizon;
+    }
+
+    /// Mark the datagrams that this connection sends with the Differentiated
+    /// Services codepoint `dscp`, which is six bits.  See `Datagram::dscp()`.
+    /// A server connection has no path until it receives a packet.
+    pub fn set_dscp(&mut self, dscp: u8) -> Res<()> {
+        if dscp > 0x3f {
+            return Err(Error::InvalidInput);
+        }
+        let path = self.paths.as_mut().ok_or(Error::ConnectionState)?;
+        path.dscp = dscp;
+        Ok(())
+    }
+
+    /// Send datagrams with the IPv6 flow label `label`, which is 20 bits, or
+    /// without one.  See `Datagram::flow_label()`.  As with `set_dscp()`,
+    /// this needs a path.
+    pub fn set_flow_label(&mut self, label: Option<u32>) -> Res<()> {
+        if label.map_or(false, |l| l > 0xf_ffff) {
+            return Err(Error::InvalidInput);
+        }
+        let path = self.paths.as_mut().ok_or(Error::ConnectionState)?;
+        path.flow_label = label;
+        Ok(())
+    }
+
+    /// The current limit on the send rate, if any.
+    pub fn max_send_rate(&self) -> Option<u64> {
+        self.rate_limit.as_ref().map(RateLimiter::rate)
+    }
+
+    /// Get what is needed to send keepalive packets on behalf of this
+    /// connection, reserving `count` packet numbers for those packets.
+    /// The keepalive packets need to stop before the connection is used again.
+    #[cfg(feature = "keepalive-offload")]
+    pub fn keepalive_offload(&mut self, count: u64) -> Res<KeepaliveOffload> {
+        if self.state != State::Connected {
+            return Err(Error::ConnectionState);
+        }
+        let path = self.paths.as_ref().ok_or(Error::ConnectionState)?;
+        let cipher = match self.crypto.tls.info() {
+            Some(info) => info.cipher_suite(),
+            None => return Err(Error::ConnectionState),
+        };
+        let secret = match self.crypto.tls.write_secret(3) {
+            Some(s) => s.as_bytes()?.to_vec(),
+            None => return Err(Error::KeysNotFound),
+        };
+        let first_pn = self
+            .loss_recovery
+            .reserve_pns(PNSpace::ApplicationData, count);
+        let pn_len = pn_length(first_pn);
+        // Keepalives need to reach the peer well within the shorter of the
+        // two idle timeouts.  A peer that sends 0 has no idle timeout.
+        let peer_idle = self
+            .tps
+            .borrow()
+            .remote()
+            .get_integer(tp_const::IDLE_TIMEOUT);
+        let idle = if peer_idle == 0 {
+            LOCAL_IDLE_TIMEOUT
+        } else {
+            min(LOCAL_IDLE_TIMEOUT, Duration::from_millis(peer_idle))
+        };
+        Ok(KeepaliveOffload {
+            local: path.local,
+            remote: path.remote,
+            cipher,
+            secret,
+            header: short_header_prefix(&path.remote_cid, pn_len).into(),
+            pn_len,
+            first_pn,
+            count,
+            interval: idle / 2,
+        })
+    }
+
+    /// The CRYPTO stream data that was sent at `epoch`.  This is the TLS
+    /// handshake as it was written, which can be fed to a TLS decoder when a
+    /// handshake fails.
+    #[cfg(feature = "crypto-dump")]
+    pub fn crypto_stream_sent(&self, epoch: Epoch) -> &[u8] {
+        self.crypto
+            .streams
+            .get(usize::from(epoch))
+            .map_or(&[], |s| &s.sent[..])
+    }
+
+    /// The CRYPTO stream data that was received at `epoch`, reassembled.
+    /// This only includes data that has been passed to TLS, so it stops at
+    /// the first gap in the stream.
+    #[cfg(feature = "crypto-dump")]
+    pub fn crypto_stream_received(&self, epoch: Epoch) -> &[u8] {
+        self.crypto
+            .streams
+            .get(usize::from(epoch))
+            .map_or(&[], |s| &s.received[..])
+    }
+
+    /// Set the size that datagrams containing Initial packets are padded to.
+    /// This only applies to clients.  The value can't be less than the 1200
+    /// bytes that the spec requires, or more than the path MTU.
+    pub fn set_initial_padding(&mut self, size: usize) -> Res<()> {
+        if self.role != Role::Client {
+            return Err(Error::WrongRole);
+        }
+        if size < MIN_INITIAL_DATAGRAM_SIZE || size > self.pmtu {
+            return Err(Error::InvalidInput);
+        }
+        self.initial_padding = size;
+        Ok(())
+    }
+
+    /// Limit each Initial packet to `size` bytes of the ClientHello, so that
+    /// a ClientHello that is larger is split across several datagrams, each
+    /// of which is padded as set by `set_initial_padding()`.  A ClientHello
+    /// that doesn't fit in one packet is always split; this makes the pieces
+    /// smaller.  The server waits for all of the ClientHello before it starts
+    /// the handshake.  This only applies to clients, before the handshake
+    /// starts.
+    pub fn set_client_hello_split(&mut self, size: Option<usize>) -> Res<()> {
+        if self.role != Role::Client {
+            return Err(Error::WrongRole);
+        }
+        if self.state != State::Init {
+            return Err(Error::ConnectionState);
+        }
+        if size == Some(0) {
+            return Err(Error::InvalidInput);
+        }
+        self.crypto.initial_split = size;
+        Ok(())
+    }
+
+    /// Send the client a stateless reset token that is made from `key` and
+    /// the connection ID that the server chooses, with `reset_token()`.
+    /// This can only be used before the handshake starts.
+    pub fn server_set_stateless_reset_key(&mut self, key: &[u8]) -> Res<()> {
+        if self.role != Role::Server {
+            return Err(Error::WrongRole);
+        }
+        if self.state != State::WaitInitial {
+            return Err(Error::ConnectionState);
+        }
+        self.reset_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Offer, or accept, hybrid post-quantum key exchange.  A client that does
+    /// this sends a larger ClientHello that spans several Initial packets.
+    /// This can only be used before the handshake starts.
+    #[cfg(feature = "pq-hybrid")]
+    pub fn enable_pq_hybrid(&mut self) -> Res<()> {
+        if !matches!(self.state, State::Init | State::WaitInitial) {
+            return Err(Error::ConnectionState);
+        }
+        self.crypto.tls.set_groups(PQ_HYBRID_GROUPS)?;
+        Ok(())
+    }
+
+    /// Enable certificate compression with `T`, which makes the server's
+    /// first flight smaller when its certificate chain is large.  Call this
+    /// for each algorithm, in order of preference, before the handshake
+    /// starts.
+    pub fn enable_certificate_compression<T: CertificateCompressor>(&mut self) -> Res<()> {
+        if !matches!(self.state, State::Init | State::WaitInitial) {
+            return Err(Error::ConnectionState);
+        }
+        self.crypto.tls.enable_certificate_compression::<T>()?;
+        Ok(())
+    }
+
+    /// Enable greasing, which introduces randomized, but legal, variation into
+    /// what the connection sends: the length of the client's initial connection
+    /// ID, the order of transport parameters (plus a reserved parameter),
+    /// padding, and whether packets are coalesced.  The `seed` makes a run
+    /// reproducible.  This can only be used before the handshake starts.
+    pub fn enable_grease(&mut self, seed: u64) -> Res<()> {
+        if !matches!(self.state, State::Init | State::WaitInitial) {
+            qerror!([self] "enable grease in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        let mut grease = Grease::new(seed);
+        self.tps.borrow_mut().grease = Some(Grease::new(grease.next_seed()));
+        if self.state == State::Init {
+            let dcid = ConnectionId::generate(grease.initial_cid_len());
+            qinfo!([self] "greased initial DCID {}", dcid);
+            if let Some(p) = self.paths.as_mut() {
+                p.remote_cid = dcid.clone();
+            }
+            self.crypto.states[0] = Some(self.crypto.create_initial_state(
+                self.version,
+                self.role,
+                &dcid,
+            ));
+        }
+        self.grease = Some(grease);
+        Ok(())
+    }
+
+    /// Enable the experimental multipath extension, so that `add_path()` can
+    /// be used once the handshake completes.  This allows the peer to migrate
+    /// the connection, as the extension needs.  This can only be used before
+    /// the handshake starts, and not by a client that uses zero-length
+    /// connection IDs.
+    pub fn enable_multipath(&mut self) -> Res<()> {
+        match (self.role, &self.state) {
+            (Role::Client, State::Init) | (Role::Server, State::WaitInitial) => {}
+            _ => return Err(Error::ConnectionState),
+        }
+        if self
+            .paths
+            .as_ref()
+            .map_or(false, |p| p.local_cids.iter().any(|c| c.is_empty()))
+        {
+            return Err(Error::InvalidInput);
+        }
+        let mut tph = self.tps.borrow_mut();
+        tph.local.set_empty(tp_const::ENABLE_MULTIPATH);
+        tph.local.remove(tp_const::DISABLE_MIGRATION);
+        Ok(())
+    }
+
+    /// Whether the multipath extension was negotiated.
+    pub fn multipath(&self) -> bool {
+        self.multipath.is_some()
+    }
+
+    /// Start using another path, from `local` to `remote`, alongside the one
+    /// that the handshake used.  This needs multipath to be negotiated and a
+    /// connection ID from the server that no other path uses.  The path only
+    /// carries data once it is validated.
+    pub fn add_path(&mut self, local: SocketAddr, remote: SocketAddr) -> Res<PathId> {
+        if self.role != Role::Client {
+            return Err(Error::WrongRole);
+        }
+        let mp = match (&self.state, &self.multipath) {
+            (State::Connected, Some(mp)) => mp,
+            _ => return Err(Error::ConnectionState),
+        };
+        if self
+            .paths
+            .iter()
+            .chain(mp.paths.iter().map(|p| &p.path))
+            .any(|p| p.local == local && p.remote == remote)
+        {
+            return Err(Error::InvalidInput);
+        }
+        let (id, remote_cid) = self.unused_remote_cid().ok_or(Error::ConnectionState)?;
+        let path = Path {
+            local,
+            remote,
+            local_cids: Vec::new(),
+            remote_cid,
+            dscp: self.paths.as_ref().map_or(0, |p| p.dscp),
+            flow_label: None,
+            interface: None,
+        };
+        self.multipath.as_mut().unwrap().add(path, id, None);
+        Ok(id)
+    }
+
+    /// Stop using a path, which either end added.  Whatever is outstanding
+    /// on the path is sent again on other paths.  The path that the
+    /// handshake used, path 0, can't be removed.
+    pub fn remove_path(&mut self, id: PathId) -> Res<()> {
+        let known = self
+            .multipath
+            .as_ref()
+            .map_or(false, |mp| mp.find(id).is_some());
+        if id == 0 || !known {
+            return Err(Error::InvalidInput);
+        }
+        self.abandon_path(id);
+        Ok(())
+    }
+
+    /// Ask the peer to use a path, or to only use it when no other path is
+    /// available.  This includes path 0.
+    pub fn set_path_status(&mut self, id: PathId, status: PathStatus) -> Res<()> {
+        self.multipath
+            .as_mut()
+            .ok_or(Error::ConnectionState)?
+            .set_status(id, status)
+    }
+
+    /// Describe the paths that the connection uses, starting with path 0.
+    /// This is empty for a server that hasn't received a packet.
+    pub fn paths(&self) -> Vec<PathInfo> {
+        match (&self.paths, &self.multipath) {
+            (Some(p), Some(mp)) => mp.info(p),
+            (Some(p), None) => Multipath::new().info(p),
+            (None, _) => Vec::new(),
+        }
+    }
+
+    /// Describe the state of loss recovery on an extra path, as
+    /// `recovery_info()` does for path 0.
+    pub fn path_recovery_info(&self, id: PathId) -> Option<RecoveryInfo> {
+        let mp = self.multipath.as_ref()?;
+        let recovery = &mp.paths[mp.find(id)?].recovery;
+        let mut info = recovery.info();
+        info.next_timer = recovery.get_timer(&self.state).timer();
+        Some(info)
+    }
+
     /// Set ALPN preferences. Strings that appear earlier in the list are given
     /// higher preference.
     pub fn set_alpn(&mut self, protocols: &[impl AsRef<str>]) -> Res<()> {
@@ -449,6 +1135,9 @@ impl Connection {
     }
 
     /// Access the latest resumption token on the connection.
+    /// This includes the TLS session ticket, the transport parameters from the
+    /// server, and any token from a NEW_TOKEN frame.  These can be kept in a
+    /// `ResumptionStore`.
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if self.state != State::Connected {
             return None;
@@ -456,7 +1145,7 @@ impl Connection {
         match self.crypto.tls {
             Agent::Client(ref c) => match c.resumption_token() {
                 Some(ref t) => {
-                    qtrace!("TLS token {}", hex(&t));
+                    qtrace!("TLS token {}", Redact(hex(&t)));
                     let mut enc = Encoder::default();
                     enc.encode_vvec_with(|enc_inner| {
                         self.tps
@@ -466,8 +1155,9 @@ impl Connection {
                             .expect("should have transport parameters")
                             .encode(enc_inner);
                     });
+                    enc.encode_vvec(self.token.as_ref().map_or(&[][..], |t| &t[..]));
                     enc.encode(&t[..]);
-                    qinfo!("resumption token {}", hex(&enc[..]));
+                    qinfo!("resumption token {}", Redact(hex(&enc[..])));
                     Some(enc.into())
                 }
                 None => None,
@@ -476,6 +1166,33 @@ impl Connection {
         }
     }
 
+    /// Export keying material from the TLS exporter (RFC 8446, Section 7.5).
+    /// This is only available once the connection is established.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Res<Vec<u8>> {
+        if self.state != State::Connected {
+            return Err(Error::ConnectionState);
+        }
+        Ok(self
+            .crypto
+            .tls
+            .export_keying_material(label, context, len)?)
+    }
+
+    /// Get the `tls-exporter` channel binding value (RFC 9266).  This can be
+    /// used to bind authentication that runs over the connection to it.
+    /// This is only available once the connection is established.
+    pub fn channel_binding(&self) -> Res<Vec<u8>> {
+        if self.state != State::Connected {
+            return Err(Error::ConnectionState);
+        }
+        Ok(self.crypto.tls.channel_binding()?)
+    }
+
     /// Enable resumption, using a token previously provided.
     /// This can only be called once and only on the client.
     /// After calling the function, it should be possible to attempt 0-RTT
@@ -485,23 +1202,32 @@ impl Connection {
             qerror!([self] "set token in state {:?}", self.state);
             return Err(Error::ConnectionState);
         }
-        qinfo!([self] "resumption token {}", hex(token));
+        qinfo!([self] "resumption token {}", Redact(hex(token)));
         let mut dec = Decoder::from(token);
         let tp_slice = match dec.decode_vvec() {
             Some(v) => v,
             _ => return Err(Error::InvalidResumptionToken),
         };
-        qtrace!([self] "  transport parameters {}", hex(&tp_slice));
+        qtrace!([self] "  transport parameters {}", Redact(hex(&tp_slice)));
         let mut dec_tp = Decoder::from(tp_slice);
         let tp = TransportParameters::decode(&mut dec_tp)?;
 
+        let new_token = match dec.decode_vvec() {
+            Some(v) => v,
+            _ => return Err(Error::InvalidResumptionToken),
+        };
+        qtrace!([self] "  NEW_TOKEN {}", Redact(hex(&new_token)));
+
         let tok = dec.decode_remainder();
-        qtrace!([self] "  TLS token {}", hex(&tok));
+        qtrace!([self] "  TLS token {}", Redact(hex(&tok)));
         match self.crypto.tls {
             Agent::Client(ref mut c) => c.set_resumption_token(&tok)?,
             Agent::Server(_) => return Err(Error::WrongRole),
         }
 
+        if !new_token.is_empty() {
+            self.token = Some(new_token.to_vec());
+        }
         self.tps.borrow_mut().remote_0rtt = Some(tp);
         self.set_initial_limits();
         // Start up TLS, which has the effect of setting up all the necessary
@@ -509,6 +1235,119 @@ impl Connection {
         self.client_start(now)
     }
 
+    /// Whether the client is able to send 0-RTT.  This is true once a
+    /// resumption token that permits early data has been set, and remains
+    /// true until the server accepts or rejects 0-RTT.
+    pub fn zero_rtt_enabled(&self) -> bool {
+        matches!(
+            self.zero_rtt_state,
+            ZeroRttState::Enabled | ZeroRttState::Sending
+        )
+    }
+
+    /// Choose what happens to streams if the server rejects 0-RTT.
+    /// By default, all streams are discarded and `ConnectionEvent::ZeroRttRejected`
+    /// is generated; any data needs to be written again.  If this is enabled,
+    /// streams are kept and anything that was sent in 0-RTT is sent again in
+    /// 1-RTT packets.  This only happens if the transport parameters from
+    /// the server allow at least as much as the ones that were remembered;
+    /// otherwise streams are discarded as before.
+    pub fn set_0rtt_resend(&mut self, resend: bool) {
+        self.resend_0rtt = resend;
+    }
+
+    /// Set the policy for accepting 0-RTT on a server.  Early data is only
+    /// accepted if the transport parameters in the session ticket are
+    /// compatible with the current ones and `checker` accepts the
+    /// application part of the ticket, which is the `extra` value that was
+    /// passed to `send_ticket()`.
+    pub fn server_enable_0rtt(
+        &mut self,
+        anti_replay: &AntiReplay,
+        checker: Rc<dyn ZeroRttChecker>,
+    ) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => {
+                s.enable_0rtt(
+                    anti_replay,
+                    0xffff_ffff,
+                    TpZeroRttChecker::wrap(self.tps.clone(), Some(checker)),
+                )?;
+                Ok(())
+            }
+            Agent::Client(_) => Err(Error::WrongRole),
+        }
+    }
+
+    /// Choose the application protocol with `selector`, instead of the list
+    /// that the server was created with.  See `AlpnSelector`.
+    pub fn server_set_alpn_selector(&mut self, selector: Rc<dyn AlpnSelector>) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => {
+                s.set_alpn_selector(selector)?;
+                Ok(())
+            }
+            Agent::Client(_) => Err(Error::WrongRole),
+        }
+    }
+
+    /// Check the server certificate with `verifier`.  Authentication then
+    /// happens during the handshake, so the connection doesn't produce
+    /// `ConnectionEvent::AuthenticationNeeded`.  See `CertificateVerifier`.
+    pub fn client_set_certificate_verifier(
+        &mut self,
+        verifier: Rc<dyn CertificateVerifier>,
+    ) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Client(ref mut c) => {
+                c.set_certificate_verifier(verifier)?;
+                Ok(())
+            }
+            Agent::Server(_) => Err(Error::WrongRole),
+        }
+    }
+
+    /// Use the certificates with the nicknames in `certificates` when the
+    /// client indicates `server_name`.  See `Server::set_server_certificates`
+    /// in neqo-crypto.
+    pub fn server_set_certificates(
+        &mut self,
+        server_name: impl AsRef<str>,
+        certificates: &[impl AsRef<str>],
+    ) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => {
+                s.set_server_certificates(server_name, certificates)?;
+                Ok(())
+            }
+            Agent::Client(_) => Err(Error::WrongRole),
+        }
+    }
+
+    /// Send `status` with the certificate that has the DER encoding
+    /// `certificate`.  See `Server::set_certificate_status` in neqo-crypto.
+    pub fn server_set_certificate_status(
+        &mut self,
+        certificate: &[u8],
+        status: CertificateStatus,
+    ) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => {
+                s.set_certificate_status(certificate, status)?;
+                Ok(())
+            }
+            Agent::Client(_) => Err(Error::WrongRole),
+        }
+    }
+
+    /// Pass the TLS secrets of this connection to `listener` as they are
+    /// made, so that traffic can be decrypted for debugging.  Call this
+    /// before the handshake starts; for a client, that is before the first
+    /// call to `process()`.
+    pub fn set_secret_listener(&mut self, listener: Rc<dyn SecretListener>) {
+        self.crypto.tls.set_secret_listener(listener);
+    }
+
     /// Send a TLS session ticket.
     pub fn send_ticket(&mut self, now: Instant, extra: &[u8]) -> Res<()> {
         let tps = &self.tps;
@@ -520,7 +1359,7 @@ impl Connection {
                 });
                 enc.encode(extra);
                 let records = s.send_ticket(now, &enc)?;
-                qinfo!([self] "send session ticket {}", hex(&enc));
+                qinfo!([self] "send session ticket {}", Redact(hex(&enc)));
                 self.buffer_crypto_records(records);
                 Ok(())
             }
@@ -537,10 +1376,21 @@ impl Connection {
         self.crypto.tls.peer_certificate()
     }
 
+    /// The name that the server certificate is for: the name a client
+    /// connected to, or the name a client indicated to a server.
+    pub fn server_name(&self) -> Option<&str> {
+        match self.crypto.tls {
+            Agent::Client(ref c) => Some(c.server_name()),
+            Agent::Server(ref s) => s.server_name(),
+        }
+    }
+
     /// Call by application when the peer cert has been verified
     pub fn authenticated(&mut self, status: AuthenticationStatus, now: Instant) {
         self.crypto.tls.authenticated(status);
-        let res = self.handshake(now, 0, None);
+        let res = self
+            .handshake(now, 0, None)
+            .and_then(|_| self.process_saved(now));
         self.absorb_error(now, res);
     }
 
@@ -554,9 +1404,14 @@ impl Connection {
         &self.state
     }
 
-    /// Get collected statistics.
-    pub fn stats(&self) -> &Stats {
-        &self.stats
+    /// Get a snapshot of collected statistics.  Use `StatsDelta` to compare
+    /// two snapshots.
+    pub fn stats(&self) -> Stats {
+        self.stats_seq.set(self.stats_seq.get() + 1);
+        Stats {
+            seq: self.stats_seq.get(),
+            ..self.stats
+        }
     }
 
     // This function wraps a call to another function and sets the connection state
@@ -567,7 +1422,10 @@ impl Connection {
             let msg = format!("{:?}", v);
             #[cfg(not(debug_assertions))]
             let msg = String::from("");
-            if let State::Closed(err) | State::Closing { error: err, .. } = &self.state {
+            if let State::Closing { error: err, .. }
+            | State::Draining { error: err, .. }
+            | State::Closed { error: err, .. } = &self.state
+            {
                 qwarn!([self] "Closing again after error {:?}", err);
             } else {
                 self.set_state(State::Closing {
@@ -587,19 +1445,37 @@ impl Connection {
         let _ = self.capture_error(now, 0, res);
     }
 
-    pub fn process_timer(&mut self, now: Instant) {
-        if let State::Closing { error, .. } = self.state().clone() {
-            self.set_state(State::Closed(error));
+    /// Move to `State::Closed` from the closing or draining state.
+    fn set_closed(&mut self) {
+        let st = match &self.state {
+            State::Closing { error, .. } => State::Closed {
+                error: error.clone(),
+                reason: None,
+            },
+            State::Draining { error, reason, .. } => State::Closed {
+                error: error.clone(),
+                reason: Some(reason.clone()),
+            },
+            _ => unreachable!(),
+        };
+        self.set_state(st);
+    }
+
+    pub fn process_timer(&mut self, now: Instant) {
+        if matches!(self.state, State::Closing { .. } | State::Draining { .. }) {
+            self.set_closed();
             return;
         }
 
         if self.idle_timeout.expired(now) {
             qinfo!("idle timeout expired");
-            self.set_state(State::Closed(ConnectionError::Transport(
-                Error::IdleTimeout,
-            )));
+            self.set_state(State::Closed {
+                error: ConnectionError::Transport(Error::IdleTimeout),
+                reason: None,
+            });
         } else {
             self.check_loss_detection_timeout(now);
+            self.check_path_timeouts(now);
         }
     }
 
@@ -611,33 +1487,76 @@ impl Connection {
         self.cleanup_streams();
     }
 
-    /// Get the time that we next need to be called back, relative to `now`.
-    fn next_delay(&mut self, now: Instant) -> Duration {
-        self.loss_recovery_state = self.loss_recovery.get_timer(&self.state);
+    /// Process datagrams that were received together, such as with GRO.
+    pub fn process_input_batch(&mut self, batch: &DatagramBatch, now: Instant) {
+        for d in batch.iter() {
+            self.process_input(d, now);
+        }
+    }
 
-        let mut delays = SmallVec::<[_; 4]>::new();
+    /// The timer that fires next, given the state of loss recovery.
+    fn next_timer(&self, lr: &LossRecoveryState) -> Option<(TimerKind, Instant)> {
+        let mut timers = SmallVec::<[_; 4]>::new();
+        timers.extend(lr.timer());
 
-        if let Some(lr_time) = self.loss_recovery_state.callback_time() {
-            delays.push(lr_time);
+        // While the send rate limit holds sending back, an ACK has to wait
+        // for it too.
+        let rate_time = self.rate_limit.as_ref().and_then(RateLimiter::next_time);
+        if let Some(ack_time) = self.acks.ack_time() {
+            if rate_time.is_none() {
+                timers.push((TimerKind::Ack, ack_time));
+            }
         }
 
-        if let Some(ack_time) = self.acks.ack_time() {
-            delays.push(ack_time);
+        if let Some(mp) = &self.multipath {
+            for p in &mp.paths {
+                timers.extend(p.recovery.get_timer(&self.state).timer());
+                if rate_time.is_none() {
+                    timers.extend(p.acks.ack_time().map(|t| (TimerKind::Ack, t)));
+                }
+            }
+        }
+
+        if let Some(rate_time) = rate_time {
+            timers.push((TimerKind::RateLimit, rate_time));
         }
 
         if let Some(idle_time) = self.idle_timeout.as_instant() {
-            delays.push(idle_time);
+            timers.push((TimerKind::Idle, idle_time));
         }
 
+        timers.into_iter().min_by_key(|(_, t)| *t)
+    }
+
+    /// Get the time that we next need to be called back, relative to `now`.
+    fn next_delay(&mut self, now: Instant) -> Duration {
+        self.loss_recovery_state = self.loss_recovery.get_timer(&self.state);
+
         // Should always at least have idle timeout, once connected
-        assert!(!delays.is_empty());
-        let earliest = delays.into_iter().min().unwrap();
+        let (_, earliest) = self
+            .next_timer(&self.loss_recovery_state)
+            .expect("a timer is running");
 
         // TODO(agrover, mt) - need to analyze and fix #47
         // rather than just clamping to zero here.
         max(now, earliest).duration_since(now)
     }
 
+    /// Describe the state of loss recovery and which timer the connection is
+    /// waiting for.  This is for diagnostics, such as finding out why a
+    /// connection has stopped sending.
+    pub fn recovery_info(&self) -> RecoveryInfo {
+        let mut info = self.loss_recovery.info();
+        info.next_timer = match &self.state {
+            State::Closing { timeout, .. } | State::Draining { timeout, .. } => {
+                Some((TimerKind::Closing, *timeout))
+            }
+            State::Closed { .. } => None,
+            _ => self.next_timer(&self.loss_recovery.get_timer(&self.state)),
+        };
+        info
+    }
+
     /// Get output packets, as a result of receiving packets, or actions taken
     /// by the application.
     /// Returns datagrams to send, and how long to wait before calling again
@@ -649,25 +1568,24 @@ impl Connection {
                 self.absorb_error(now, res);
                 self.output(now)
             }
-            State::Closing { error, timeout, .. } => {
-                if *timeout > now {
-                    self.output(now)
-                } else {
-                    // Close timeout expired, move to Closed
-                    let st = State::Closed(error.clone());
-                    self.set_state(st);
-                    None
-                }
+            State::Closing { timeout, .. } | State::Draining { timeout, .. } if *timeout <= now => {
+                // Close timeout expired, move to Closed
+                self.set_closed();
+                None
             }
-            State::Closed(..) => None,
+            State::Closing { .. } => self.output(now),
+            State::Draining { .. } | State::Closed { .. } => None,
             _ => self.output(now),
         };
 
         match pkt {
             Some(pkt) => Output::Datagram(pkt),
             None => match self.state {
-                State::Closed(_) => Output::None,
-                State::Closing { timeout, .. } => Output::Callback(timeout - now),
+                State::Closed { .. } => Output::None,
+                State::Closing { timeout, .. } | State::Draining { timeout, .. } => {
+                    Output::Callback(timeout - now)
+                }
+                _ if self.deferred => Output::Callback(Duration::new(0, 0)),
                 _ => Output::Callback(self.next_delay(now)),
             },
         }
@@ -675,15 +1593,60 @@ impl Connection {
 
     /// Process input and generate output.
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        self.process_with_budget(dgram, now, usize::max_value())
+    }
+
+    /// Whether `process_with_budget()` put off some packets.
+    pub(crate) fn has_deferred_packets(&self) -> bool {
+        self.deferred
+    }
+
+    /// Like `process()`, but decode no more than `budget` packets, so that a
+    /// flood of packets can't hold up the caller.  Packets over the budget
+    /// are put aside, along with packets that are waiting for keys, and the
+    /// connection asks to be called again immediately with a zero
+    /// `Output::Callback`.  Those packets are processed first on the next
+    /// call.  As with packets waiting for keys, only a few are kept; any
+    /// more are dropped, as though they were lost.
+    pub fn process_with_budget(
+        &mut self,
+        dgram: Option<Datagram>,
+        now: Instant,
+        budget: usize,
+    ) -> Output {
+        self.work_left = max(budget, 1);
+        if self.deferred {
+            self.deferred = false;
+            let res = self.process_saved(now);
+            self.absorb_error(now, res);
+        }
         if let Some(d) = dgram {
             self.process_input(d, now);
         }
+        self.work_left = usize::max_value();
         self.process_timer(now);
         self.process_output(now)
     }
 
+    /// Process an optional input datagram, then produce all output.
+    /// The returned iterator yields datagrams until there are none left to
+    /// send; after that, its `last()` and `ready_at()` say when to call again.
+    pub fn process_iter<'a>(
+        &'a mut self,
+        dgram: Option<Datagram>,
+        now: Instant,
+    ) -> OutputIter<impl FnMut() -> Output + 'a> {
+        let mut dgram = dgram;
+        OutputIter::new(move || self.process(dgram.take(), now))
+    }
+
     fn is_valid_cid(&self, cid: &ConnectionId) -> bool {
-        self.valid_cids.contains(cid) || self.paths.iter().any(|p| p.local_cids.contains(cid))
+        self.valid_cids.contains(cid)
+            || self.paths.iter().any(|p| p.local_cids.contains(cid))
+            || self
+                .multipath
+                .as_ref()
+                .map_or(false, |mp| mp.local_cid_seq(cid).is_some())
     }
 
     fn is_valid_initial(&self, hdr: &PacketHdr) -> bool {
@@ -708,7 +1671,16 @@ impl Connection {
         }
     }
 
-    fn handle_retry(&mut self, scid: &ConnectionId, odcid: &ConnectionId, token: &[u8]) -> Res<()> {
+    /// Act on a Retry, which is all of `packet`.  `retry_odcid` is the
+    /// original destination connection ID from the Retry, for versions that
+    /// include it instead of an integrity tag.
+    fn handle_retry(
+        &mut self,
+        scid: &ConnectionId,
+        retry_odcid: Option<&ConnectionId>,
+        token: &[u8],
+        packet: &[u8],
+    ) -> Res<()> {
         qdebug!([self] "received Retry");
         if self.retry_info.is_some() {
             qinfo!([self] "Dropping extra Retry");
@@ -718,63 +1690,146 @@ impl Connection {
             qinfo!([self] "Dropping Retry without a token");
             return Ok(());
         }
-        match self.paths.iter_mut().find(|p| p.remote_cid == *odcid) {
-            None => {
-                qinfo!([self] "Ignoring Retry with mismatched ODCID");
-                return Ok(());
-            }
-            Some(path) => {
-                path.remote_cid = scid.clone();
-            }
+        let path = match self.paths.as_mut() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // The tag, or the connection ID in the Retry, covers the connection
+        // ID that the client first used, which shows that the Retry came from
+        // something that saw the Initial.
+        let odcid = path.remote_cid.clone();
+        let valid = match retry_odcid {
+            Some(retry_odcid) => *retry_odcid == odcid,
+            None => retry_tag_valid(self.version, &odcid, packet),
+        };
+        if !valid {
+            qinfo!([self] "Dropping Retry that doesn't match the original connection ID");
+            return Ok(());
         }
+        path.remote_cid = scid.clone();
         qinfo!([self] "Valid Retry received, restarting with provided token");
         self.retry_info = Some(RetryInfo {
             token: token.to_vec(),
-            odcid: odcid.clone(),
+            odcid,
         });
         // Reset the crypto streams and any 0-RTT.
         self.crypto.retry();
         self.send_streams.retry();
+        // Anything that was saved came from before the Retry.
+        self.saved_packets.clear();
 
         // Switching crypto state here might not happen eventually.
         // https://github.com/quicwg/base-drafts/issues/2823
-        self.crypto.states[0] = Some(self.crypto.create_initial_state(self.role, scid));
+        self.crypto.states[0] = Some(self.crypto.create_initial_state(
+            self.version,
+            self.role,
+            scid,
+        ));
         Ok(())
     }
 
     fn input(&mut self, d: Datagram, now: Instant) -> Res<()> {
+        let res = self.input_datagram(&d, false, now);
+        if let Some(pool) = &self.buffers {
+            pool.recycle_datagram(d);
+        }
+        if res? {
+            self.process_saved(now)?;
+        }
+        Ok(())
+    }
+
+    /// Process packets that were saved because keys weren't available.
+    /// Packets are only replayed once keys for their epoch are available.
+    /// This repeats until no more packets can be decrypted, because
+    /// processing one saved packet can produce the keys for another.
+    fn process_saved(&mut self, now: Instant) -> Res<()> {
+        loop {
+            if self.work_left == 0 {
+                return Ok(());
+            }
+            let saved = mem::replace(&mut self.saved_packets, Vec::new());
+            let role = self.role;
+            let crypto = &mut self.crypto;
+            let (ready, waiting): (Vec<_>, Vec<_>) = saved
+                .into_iter()
+                .partition(|(epoch, _)| crypto.obtain_crypto_state(role, *epoch).is_ok());
+            self.saved_packets = waiting;
+            if ready.is_empty() {
+                return Ok(());
+            }
+            qdebug!([self] "Processing {} saved packets", ready.len());
+            for (_, d) in ready {
+                self.input_datagram(&d, true, now)?;
+            }
+        }
+    }
+
+    /// Hold on to a packet until the keys needed to decrypt it are available.
+    fn save_packet(&mut self, epoch: Epoch, d: &Datagram, packet: &[u8]) {
+        if self.saved_packets.len() < MAX_SAVED_PACKETS {
+            qdebug!([self] "Saving epoch {} packet for later, {} bytes", epoch, packet.len());
+            self.saved_packets.push((epoch, d.copy_with(packet)));
+        } else {
+            qinfo!([self] "Too many saved packets, dropping packet");
+        }
+    }
+
+    /// Process each packet in a datagram.  Returns true if any packet
+    /// could be decrypted.  `saved` is set when the datagram holds a packet
+    /// that was saved, which was already counted when it arrived.
+    fn input_datagram(&mut self, d: &Datagram, saved: bool, now: Instant) -> Res<bool> {
         let mut slc = &d[..];
+        let mut decrypted = false;
+        // Delays are measured from when the datagram arrived, which the I/O
+        // layer might know better.
+        let received = d.received_time().map_or(now, |t| min(t, now));
 
-        qinfo!([self] "input {}", hex( &**d));
+        qinfo!([self] "input {}", Redact(hex(&d[..])));
 
         // Handle each packet in the datagram
         while !slc.is_empty() {
-            let res = decode_packet_hdr(self.cid_manager.borrow().as_decoder(), slc);
+            let res = decode_packet_hdr(&*self.cid_decoder, slc);
             let mut hdr = match res {
                 Ok(h) => h,
                 Err(e) => {
-                    qinfo!([self] "Received indecipherable packet header {} {}", hex(slc), e);
-                    return Ok(()); // Drop the remainder of the datagram.
+                    qinfo!([self] "Received indecipherable packet header {} {}", Redact(hex(slc)), e);
+                    return Ok(decrypted); // Drop the remainder of the datagram.
                 }
             };
-            self.stats.packets_rx += 1;
+            if !saved {
+                self.stats.packets_rx += 1;
+            }
             match (&hdr.tipe, &self.state, &self.role) {
-                (PacketType::VN(_), State::WaitInitial, Role::Client) => {
-                    self.set_state(State::Closed(ConnectionError::Transport(
-                        Error::VersionNegotiation,
-                    )));
+                (PacketType::VN(versions), State::WaitInitial, Role::Client) => {
+                    if !self.is_valid_vn(&hdr, versions) {
+                        qinfo!([self] "Dropping invalid VN {:?}", versions);
+                        return Ok(decrypted);
+                    }
+                    self.set_state(State::Closed {
+                        error: ConnectionError::Transport(Error::VersionNegotiation),
+                        reason: None,
+                    });
                     return Err(Error::VersionNegotiation);
                 }
-                (PacketType::Retry { odcid, token }, State::WaitInitial, Role::Client) => {
-                    return self.handle_retry(hdr.scid.as_ref().unwrap(), odcid, token);
+                (PacketType::Retry { odcid, token }, State::WaitInitial, Role::Client)
+                    if hdr.version == Some(self.version) =>
+                {
+                    self.handle_retry(hdr.scid.as_ref().unwrap(), odcid.as_ref(), token, slc)?;
+                    return Ok(false);
                 }
                 (PacketType::VN(_), ..) | (PacketType::Retry { .. }, ..) => {
                     qwarn!("dropping {:?}", hdr.tipe);
-                    return Ok(());
+                    return Ok(decrypted);
                 }
                 _ => {}
             };
 
+            // The length of the packet is known now, so any problem from here
+            // on only affects this packet and not the rest of the datagram.
+            let packet = &slc[..hdr.hdr_len + hdr.body_len()];
+            slc = &slc[packet.len()..];
+
             if let Some(version) = hdr.version {
                 if version != self.version {
                     qwarn!(
@@ -782,85 +1837,174 @@ impl Connection {
                         hdr.version.unwrap(),
                         self.version,
                     );
-                    return Ok(());
+                    continue;
                 }
             }
 
             match self.state {
                 State::Init => {
                     qinfo!([self] "Received message while in Init state");
-                    return Ok(());
+                    return Ok(decrypted);
                 }
                 State::WaitInitial => {
                     qinfo!([self] "Received packet in WaitInitial");
                     if self.role == Role::Server {
                         if !self.is_valid_initial(&hdr) {
-                            return Ok(());
+                            continue;
                         }
-                        self.crypto.states[0] =
-                            Some(self.crypto.create_initial_state(self.role, &hdr.dcid));
+                        self.crypto.states[0] = Some(self.crypto.create_initial_state(
+                            self.version,
+                            self.role,
+                            &hdr.dcid,
+                        ));
                     }
                 }
                 State::Handshaking | State::Connected => {
                     if !self.is_valid_cid(&hdr.dcid) {
                         qinfo!([self] "Ignoring packet with CID {:?}", hdr.dcid);
-                        return Ok(());
+                        continue;
                     }
                 }
                 State::Closing { .. } => {
                     // Don't bother processing the packet. Instead ask to get a
                     // new close frame.
                     self.flow_mgr.borrow_mut().set_need_close_frame(true);
-                    return Ok(());
+                    return Ok(decrypted);
                 }
-                State::Closed(..) => {
+                State::Draining { .. } | State::Closed { .. } => {
                     // Do nothing.
-                    return Ok(());
+                    return Ok(decrypted);
                 }
             }
 
             qdebug!([self] "Received unverified packet {:?}", hdr);
 
-            let body = self.decrypt_body(&mut hdr, slc);
-            slc = &slc[hdr.hdr_len + hdr.body_len()..];
+            if self.work_left == 0 {
+                qdebug!([self] "Work budget spent, deferring packet");
+                self.save_packet(hdr.epoch, d, packet);
+                self.deferred = true;
+                continue;
+            }
+            self.work_left -= 1;
+
+            // Packets on extra paths use the connection IDs issued for them.
+            let rx_path = match (&hdr.tipe, &self.multipath) {
+                (PacketType::Short, Some(mp)) => mp.local_cid_seq(&hdr.dcid).unwrap_or(0),
+                _ => 0,
+            };
+            let body = match self.decrypt_body(&mut hdr, packet, rx_path) {
+                Ok(body) => body,
+                Err(Error::KeysNotFound) => {
+                    self.save_packet(hdr.epoch, d, packet);
+                    continue;
+                }
+                Err(_) => None,
+            };
             if let Some(body) = body {
+                if rx_path != 0 && !self.input_path(rx_path, d) {
+                    qinfo!([self] "Dropping packet on unknown path {}", rx_path);
+                    if let Some(pool) = &self.buffers {
+                        pool.recycle(body);
+                    }
+                    continue;
+                }
                 // TODO(ekr@rtfm.com): Have the server blow away the initial
                 // crypto state if this fails? Otherwise, we will get a panic
                 // on the assert for doesn't exist.
                 // OK, we have a valid packet.
+                decrypted = true;
                 self.idle_timeout.on_packet_received(now);
+                // A server has no more use for Initial keys once it reads a
+                // Handshake packet.
+                if hdr.epoch == 2 && self.role == Role::Server {
+                    self.discard_keys(0);
+                }
+                match d.ecn() {
+                    Ecn::Ect0 => self.stats.ect0_rx += 1,
+                    Ecn::Ect1 => self.stats.ect1_rx += 1,
+                    Ecn::Ce => self.stats.ce_rx += 1,
+                    Ecn::NotEct => {}
+                }
                 dump_packet(self, "<- RX", &hdr, &body);
-                if self.process_packet(&hdr, body, now)? {
+                if let Some(qlog) = &mut self.qlog {
+                    qlog.packet(false, &hdr, &body, now);
+                }
+                let res = self.process_packet(&hdr, &body, rx_path, received, now);
+                if let Some(pool) = &self.buffers {
+                    pool.recycle(body);
+                }
+                if res? {
                     continue;
                 }
             }
-            self.start_handshake(hdr, &d)?;
-            self.process_migrations(&d)?;
+            self.start_handshake(hdr, d, now)?;
+            self.process_migrations(d)?;
         }
-        Ok(())
+        Ok(decrypted)
     }
 
-    fn decrypt_body(&mut self, mut hdr: &mut PacketHdr, slc: &[u8]) -> Option<Vec<u8>> {
-        // Decryption failure, or not having keys is not fatal.
-        // If the state isn't available, or we can't decrypt the packet, drop
-        // the rest of the datagram on the floor, but don't generate an error.
-        let largest_acknowledged = self
-            .loss_recovery
-            .largest_acknowledged(PNSpace::from(hdr.epoch));
+    /// Decrypt the body of a packet.  This fails with `Error::KeysNotFound`
+    /// if keys for the packet aren't available yet, in which case the packet
+    /// might be able to be decrypted later.  Any other decryption failure, or
+    /// not having keys for an epoch we never read, results in `Ok(None)`.
+    /// `rx_path` is the extra path that the packet is for, or 0.
+    fn decrypt_body(
+        &mut self,
+        mut hdr: &mut PacketHdr,
+        slc: &[u8],
+        rx_path: PathId,
+    ) -> Res<Option<Vec<u8>>> {
+        let largest_acknowledged = if rx_path == 0 {
+            self.loss_recovery
+                .largest_acknowledged(PNSpace::from(hdr.epoch))
+        } else {
+            self.multipath.as_ref().and_then(|mp| {
+                let i = mp.find_rx(rx_path)?;
+                mp.paths[i].acks.largest_pn()
+            })
+        };
         match self.crypto.obtain_crypto_state(self.role, hdr.epoch) {
             Ok(cs) => match cs.rx.as_ref() {
                 Some(rx) => {
                     let pn_decoder = PacketNumberDecoder::new(largest_acknowledged);
-                    decrypt_packet(rx, pn_decoder, &mut hdr, slc).ok()
+                    let mut body = match &self.buffers {
+                        Some(pool) => pool.take(),
+                        None => Vec::new(),
+                    };
+                    let res = if rx_path == 0 {
+                        decrypt_packet(rx, pn_decoder, &mut hdr, slc, &mut body)
+                    } else {
+                        let crypto = PathCrypto {
+                            inner: rx,
+                            path: rx_path,
+                        };
+                        decrypt_packet(&crypto, pn_decoder, &mut hdr, slc, &mut body)
+                    };
+                    if res.is_ok() {
+                        Ok(Some(body))
+                    } else {
+                        if let Some(pool) = &self.buffers {
+                            pool.recycle(body);
+                        }
+                        Ok(None)
+                    }
                 }
-                _ => None,
+                _ => Ok(None),
             },
-            _ => None,
+            Err(Error::KeysNotFound) => Err(Error::KeysNotFound),
+            _ => Ok(None),
         }
     }
 
     /// Ok(true) if the packet is a duplicate
-    fn process_packet(&mut self, hdr: &PacketHdr, body: Vec<u8>, now: Instant) -> Res<bool> {
+    fn process_packet(
+        &mut self,
+        hdr: &PacketHdr,
+        body: &[u8],
+        rx_path: PathId,
+        received: Instant,
+        now: Instant,
+    ) -> Res<bool> {
         // TODO(ekr@rtfm.com): Have the server blow away the initial
         // crypto state if this fails? Otherwise, we will get a panic
         // on the assert for doesn't exist.
@@ -868,19 +2012,29 @@ impl Connection {
 
         // TODO(ekr@rtfm.com): Filter for valid for this epoch.
 
-        let ack_eliciting = self.input_packet(hdr.epoch, Decoder::from(&body[..]), now)?;
+        let ack_eliciting = self.input_packet(hdr.epoch, rx_path, Decoder::from(body), now)?;
         let space = PNSpace::from(hdr.epoch);
-        if self.acks[space].is_duplicate(hdr.pn) {
+        let acks = if rx_path == 0 {
+            &mut self.acks[space]
+        } else {
+            let mp = self.multipath.as_mut().unwrap();
+            match mp.find_rx(rx_path) {
+                Some(i) => &mut mp.paths[i].acks,
+                // A frame in the packet abandoned the path.
+                None => return Ok(false),
+            }
+        };
+        let duplicate = acks.is_duplicate(hdr.pn);
+        if !duplicate {
+            acks.set_received(received, hdr.pn, ack_eliciting);
+        } else {
             qdebug!([self] "Received duplicate packet epoch={} pn={}", hdr.epoch, hdr.pn);
             self.stats.dups_rx += 1;
-            Ok(true)
-        } else {
-            self.acks[space].set_received(now, hdr.pn, ack_eliciting);
-            Ok(false)
         }
+        Ok(duplicate)
     }
 
-    fn start_handshake(&mut self, hdr: PacketHdr, d: &Datagram) -> Res<()> {
+    fn start_handshake(&mut self, hdr: PacketHdr, d: &Datagram, now: Instant) -> Res<()> {
         // No handshake to process.
         if !matches!(self.state, State::WaitInitial) {
             return Ok(());
@@ -889,11 +2043,19 @@ impl Connection {
             assert!(matches!(hdr.tipe, PacketType::Initial(..)));
             // A server needs to accept the client's selected CID during the handshake.
             self.valid_cids.push(hdr.dcid.clone());
+            self.handshake_start = Some(now);
             // Install a path.
             assert!(self.paths.is_none());
             let mut p = Path::new(&d, hdr.scid.unwrap());
-            p.local_cids
-                .push(self.cid_manager.borrow_mut().generate_cid());
+            let cid = self.cid_manager.borrow_mut().generate_cid();
+            if let Some(key) = &self.reset_key {
+                let token = reset_token(key, &cid)?;
+                self.tps
+                    .borrow_mut()
+                    .local
+                    .set_bytes(tp_const::STATELESS_RESET_TOKEN, token.to_vec());
+            }
+            p.local_cids.push(cid);
             self.paths = Some(p);
 
             // SecretAgentPreinfo::early_data() always returns false for a server,
@@ -917,7 +2079,11 @@ impl Connection {
     }
 
     fn process_migrations(&self, d: &Datagram) -> Res<()> {
-        if self.paths.iter().any(|p| p.received_on(&d)) {
+        let on_extra_path = self
+            .multipath
+            .as_ref()
+            .map_or(false, |mp| mp.paths.iter().any(|p| p.path.received_on(d)));
+        if on_extra_path || self.paths.iter().any(|p| p.received_on(&d)) {
             Ok(())
         } else {
             // Right now, we don't support any form of migration.
@@ -926,8 +2092,118 @@ impl Connection {
         }
     }
 
-    // Return whether the packet had ack-eliciting frames.
-    fn input_packet(&mut self, epoch: Epoch, mut d: Decoder, now: Instant) -> Res<(bool)> {
+    /// Find the extra path that a packet for `rx_path` arrived on, adding it
+    /// if this is the first packet on it.  Returns false if the packet
+    /// doesn't belong on any path.
+    fn input_path(&mut self, rx_path: PathId, d: &Datagram) -> bool {
+        let unused = self.unused_remote_cid();
+        let role = self.role;
+        let mp = match self.multipath.as_mut() {
+            Some(mp) => mp,
+            None => return false,
+        };
+        if let Some(i) = mp.find_rx(rx_path) {
+            // Migrating an extra path isn't supported.
+            return mp.paths[i].path.received_on(d);
+        }
+        if role == Role::Client {
+            // The server picked which connection ID to use on a path that
+            // the client added.
+            match mp
+                .paths
+                .iter_mut()
+                .find(|p| p.rx_id.is_none() && p.path.received_on(d))
+            {
+                Some(p) => {
+                    p.rx_id = Some(rx_path);
+                    true
+                }
+                None => false,
+            }
+        } else if let Some((id, remote_cid)) = unused {
+            mp.add(Path::new(d, remote_cid), id, Some(rx_path));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The peer's connection ID with the lowest sequence number that no path
+    /// uses.
+    fn unused_remote_cid(&self) -> Option<(PathId, ConnectionId)> {
+        let mp = self.multipath.as_ref()?;
+        self.connection_ids
+            .iter()
+            .filter(|(seq, _)| **seq > 0 && **seq <= MAX_PATH_ID && mp.find(**seq).is_none())
+            .min_by_key(|(seq, _)| **seq)
+            .map(|(seq, (cid, _))| (*seq, ConnectionId::from(&cid[..])))
+    }
+
+    /// Abandon an extra path, telling the peer.
+    fn abandon_path(&mut self, id: PathId) {
+        if let Some(p) = self.multipath.as_mut().and_then(|mp| mp.abandon(id)) {
+            self.forget_path(p);
+        }
+    }
+
+    /// Stop tracking an extra path that either end abandoned.  Anything that
+    /// was outstanding on it is treated as lost, so that it is sent again on
+    /// other paths.
+    fn forget_path(&mut self, mut p: MultipathPath) {
+        qinfo!([self] "Forget path {} {:?}", p.id, p.path);
+        self.connection_ids.remove(&p.id);
+        let outstanding = p.recovery.drain(PNSpace::ApplicationData);
+        self.lost_packets(outstanding);
+    }
+
+    /// Issue a connection ID that the peer can use for an extra path.
+    fn issue_path_cid(&mut self) -> Res<()> {
+        let cid = self.cid_manager.borrow_mut().generate_cid();
+        let token = match &self.reset_key {
+            Some(key) => Some(reset_token(key, &cid)?),
+            None => None,
+        };
+        self.multipath.as_mut().unwrap().issue_cid(cid, token);
+        Ok(())
+    }
+
+    /// The peer retired a connection ID that was issued for extra paths, so
+    /// issue another in its place.
+    fn retire_path_cid(&mut self, seq: u64) -> Res<()> {
+        let mp = self.multipath.as_mut().unwrap();
+        if mp.retire_local(seq) && mp.can_issue_cid() {
+            self.issue_path_cid()?;
+        }
+        Ok(())
+    }
+
+    /// Whether stream data can be sent on a path now.  This is up to the
+    /// congestion controller and the status of each path, but path 0 is
+    /// always allowed when there are no extra paths.
+    fn data_allowed(&self, id: PathId) -> bool {
+        let mp = match &self.multipath {
+            Some(mp) if !mp.paths.is_empty() => mp,
+            _ => return id == 0,
+        };
+        if !mp.carries_data(id) {
+            false
+        } else if id == 0 {
+            self.loss_recovery.can_send()
+        } else {
+            mp.find(id)
+                .map_or(false, |i| mp.paths[i].recovery.can_send())
+        }
+    }
+
+    // Return whether the packet had ack-eliciting frames.  `path` is the
+    // extra path that the packet arrived on, or 0.
+    fn input_packet(
+        &mut self,
+        epoch: Epoch,
+        path: PathId,
+        mut d: Decoder,
+        now: Instant,
+    ) -> Res<(bool)> {
         let mut ack_eliciting = false;
 
         // Handle each frame in the packet
@@ -935,7 +2211,7 @@ impl Connection {
             let f = decode_frame(&mut d)?;
             ack_eliciting |= f.ack_eliciting();
             let t = f.get_type();
-            let res = self.input_frame(epoch, f, now);
+            let res = self.input_frame(epoch, path, f, now);
             self.capture_error(now, t, res)?;
         }
 
@@ -971,20 +2247,52 @@ impl Connection {
             };
         }
         self.paths = paths;
+        if out.is_none() && self.state == State::Connected {
+            let count = self.multipath.as_ref().map_or(0, |mp| mp.paths.len());
+            out = (0..count).find_map(|i| self.output_mp_path(i, now));
+        }
         out
     }
 
+    /// Whether the send rate limit admits a datagram now.  If it does, this
+    /// has the release time for a datagram that is admitted early.
+    fn admit_datagram(&mut self, now: Instant) -> Option<Option<Instant>> {
+        match (self.rate_limit.as_mut(), self.pacing_offload) {
+            _ if matches!(self.state, State::Closing { .. }) => Some(None),
+            (None, _) => Some(None),
+            (Some(r), Some(horizon)) => match r.release_time(now, horizon) {
+                Some(t) if t > now => Some(Some(t)),
+                Some(_) => Some(None),
+                None => None,
+            },
+            (Some(r), None) if r.allowed(now) => Some(None),
+            (Some(_), None) => None,
+        }
+    }
+
     /// Build a datagram, possibly from multiple packets (for different PN
     /// spaces) and each containing 1+ frames.
     fn output_path(&mut self, path: &Path, now: Instant) -> Res<Option<Datagram>> {
-        let mut out_bytes = Vec::new();
+        // The send rate limit admits whole datagrams, as a pacer would.  A
+        // datagram that is admitted early gets a release time instead.
+        let release = match self.admit_datagram(now) {
+            Some(release) => release,
+            None => return Ok(None),
+        };
+        let mut builder = match &self.buffers {
+            Some(pool) => DatagramBuilder::with_pool(pool),
+            None => DatagramBuilder::default(),
+        };
+        let mut encoder = builder.frame_buffer();
         let mut needs_padding = false;
+        let mut split_datagram = false;
+        let mut sent_handshake = false;
 
         // Frames for different epochs must go in different packets, but then these
         // packets can go in a single datagram
         for epoch in 0..NUM_EPOCHS {
             let space = PNSpace::from(epoch);
-            let mut encoder = Encoder::default();
+            encoder.clear();
             let mut tokens = Vec::new();
 
             // Try to make our own crypo state and if we can't, skip this epoch.
@@ -1000,8 +2308,10 @@ impl Connection {
             let mut ack_eliciting = false;
             match &self.state {
                 State::Init | State::WaitInitial | State::Handshaking | State::Connected => {
+                    // With extra paths, the congestion window limits stream data.
+                    let data_ok = self.data_allowed(0);
                     loop {
-                        let remaining = self.pmtu - out_bytes.len() - encoder.len();
+                        let remaining = self.pmtu - builder.len() - encoder.len();
 
                         // Check sources in turn for available frames
                         if let Some((frame, token)) = self
@@ -1009,26 +2319,52 @@ impl Connection {
                             .get_frame(now, epoch)
                             .or_else(|| self.crypto.get_frame(epoch, TxMode::Normal, remaining))
                             .or_else(|| self.flow_mgr.borrow_mut().get_frame(epoch, remaining))
+                            .or_else(|| match self.multipath.as_mut() {
+                                Some(mp) if epoch == 3 => mp.get_frame(remaining),
+                                _ => None,
+                            })
                             .or_else(|| {
-                                self.send_streams
-                                    .get_frame(epoch, TxMode::Normal, remaining)
+                                if data_ok {
+                                    self.send_streams
+                                        .get_frame(epoch, TxMode::Normal, remaining)
+                                } else {
+                                    None
+                                }
                             })
                         {
                             ack_eliciting |= frame.ack_eliciting();
                             frame.marshal(&mut encoder);
+                            let split = epoch == 0
+                                && self.crypto.initial_split.is_some()
+                                && matches!(token, Some(RecoveryToken::Crypto(_)));
                             if let Some(t) = token {
                                 tokens.push(t);
                             }
                             assert!(encoder.len() <= self.pmtu);
-                            if out_bytes.len() + encoder.len() == self.pmtu {
+                            if builder.len() + encoder.len() == self.pmtu {
                                 // No more space for frames.
                                 break;
                             }
+                            if split {
+                                // Each piece of a split ClientHello gets its own datagram.
+                                split_datagram = true;
+                                break;
+                            }
                         } else {
                             // No more frames to send.
                             break;
                         }
                     }
+                    if let Some(grease) = self.grease.as_mut() {
+                        if encoder.len() > 0 {
+                            let space = self
+                                .pmtu
+                                .saturating_sub(builder.len() + encoder.len() + PACKET_OVERHEAD);
+                            for _ in 0..grease.extra_padding(space) {
+                                Frame::Padding.marshal(&mut encoder);
+                            }
+                        }
+                    }
                 }
                 State::Closing {
                     error,
@@ -1036,21 +2372,28 @@ impl Connection {
                     msg,
                     ..
                 } => {
-                    if epoch != 3 {
+                    if !self.flow_mgr.borrow().need_close_frame() {
                         continue;
                     }
-
-                    if self.flow_mgr.borrow().need_close_frame() {
-                        self.flow_mgr.borrow_mut().set_need_close_frame(false);
-                        let frame = Frame::ConnectionClose {
-                            error_code: error.clone().into(),
-                            frame_type: *frame_type,
-                            reason_phrase: Vec::from(msg.clone()),
-                        };
-                        frame.marshal(&mut encoder);
+                    // Send in Initial and Handshake packets until the handshake is
+                    // done, in case the peer can't read 1-RTT packets yet.  The
+                    // draft that this implements has no APPLICATION_ERROR code and
+                    // allows an application close in these packets, so the same
+                    // close is sent in each.
+                    match epoch {
+                        0 | 2 if !self.crypto.tls.state().connected() => {}
+                        3 => {}
+                        _ => continue,
                     }
+                    let frame = Frame::ConnectionClose {
+                        error_code: error.clone().into(),
+                        frame_type: *frame_type,
+                        reason_phrase: Vec::from(msg.clone()),
+                    };
+                    frame.marshal(&mut encoder);
                 }
-                State::Closed { .. } => unimplemented!(),
+                // Nothing is sent once draining or closed.
+                State::Draining { .. } | State::Closed { .. } => return Ok(None),
             }
 
             if encoder.len() == 0 {
@@ -1058,19 +2401,19 @@ impl Connection {
             }
 
             qdebug!([self] "Need to send a packet");
-            match epoch {
-                // Packets containing Initial packets need padding.
-                0 => needs_padding = true,
-                1 => (),
-                // ...unless they include higher epochs.
-                _ => needs_padding = false,
+            // Datagrams containing Initial packets need padding, even if
+            // they also include packets from other epochs.  The server only
+            // needs to pad if the Initial packet is ack-eliciting.
+            if epoch == 0 && (self.role == Role::Client || ack_eliciting) {
+                needs_padding = true;
             }
             let hdr = PacketHdr::new(
                 0,
                 match epoch {
                     0 => {
-                        let token = match &self.retry_info {
-                            Some(v) => v.token.clone(),
+                        let token = match (&self.retry_info, &self.token) {
+                            (Some(v), _) => v.token.clone(),
+                            (None, Some(t)) if self.role == Role::Client => t.clone(),
                             _ => Vec::new(),
                         };
                         PacketType::Initial(token)
@@ -1091,36 +2434,190 @@ impl Connection {
                 epoch,
             );
             self.stats.packets_tx += 1;
+            match epoch {
+                2 => sent_handshake = true,
+                3 if self.first_1rtt_pn.is_none() => self.first_1rtt_pn = Some(hdr.pn),
+                _ => {}
+            }
 
             if ack_eliciting {
                 self.idle_timeout.on_packet_sent(now);
             }
-            self.loss_recovery
-                .on_packet_sent(space, hdr.pn, ack_eliciting, tokens, now);
+            let pn = hdr.pn;
 
+            dump_packet(self, "TX ->", &hdr, &encoder);
+            if let Some(qlog) = &mut self.qlog {
+                qlog.packet(true, &hdr, &encoder, now);
+            }
             let cs = self
                 .crypto
                 .obtain_crypto_state(self.role, hdr.epoch)
                 .unwrap();
-            let tx = cs.tx.as_ref().unwrap();
-            let mut packet = encode_packet(tx, &hdr, &encoder);
-            dump_packet(self, "TX ->", &hdr, &encoder);
-            out_bytes.append(&mut packet);
-            if out_bytes.len() >= self.pmtu {
+            let size = builder.add(cs.tx.as_ref().unwrap(), hdr, &encoder);
+            self.loss_recovery
+                .on_packet_sent(space, pn, ack_eliciting, tokens, size, now);
+            if builder.len() >= self.pmtu || split_datagram {
                 break;
             }
+            if let Some(grease) = self.grease.as_mut() {
+                if !grease.coalesce() {
+                    qdebug!([self] "grease: not coalescing");
+                    break;
+                }
+            }
+        }
+        builder.recycle(encoder);
+
+        if matches!(self.state, State::Closing { .. }) {
+            self.flow_mgr.borrow_mut().set_need_close_frame(false);
         }
 
-        if out_bytes.is_empty() {
+        if builder.is_empty() {
+            if let Some(pool) = &self.buffers {
+                pool.recycle(builder.build());
+            }
             return Ok(None);
         }
 
-        // Pad Initial packets sent by the client to 1200 bytes.
-        if self.role == Role::Client && needs_padding {
-            qdebug!([self] "pad Initial to 1200");
-            out_bytes.resize(1200, 0);
+        if needs_padding {
+            let target = match self.grease.as_mut() {
+                Some(grease) => grease.padded_size(self.initial_padding, self.pmtu),
+                None => self.initial_padding,
+            };
+            if builder.len() < target {
+                qdebug!([self] "pad Initial to {}", target);
+                let epoch = builder.last_epoch().unwrap();
+                let cs = self.crypto.obtain_crypto_state(self.role, epoch).unwrap();
+                builder.pad(cs.tx.as_ref().unwrap(), target);
+            }
+        }
+        // A client has no more use for Initial keys once it sends a
+        // Handshake packet.  A server that has confirmed the handshake has
+        // now acknowledged the client's Finished.
+        if sent_handshake {
+            if self.role == Role::Client {
+                self.discard_keys(0);
+            } else if self.handshake_confirmed {
+                self.discard_keys(2);
+            }
+        }
+        let out_bytes = builder.build();
+        if let Some(r) = self.rate_limit.as_mut() {
+            r.sent(out_bytes.len());
+        }
+        let dgram = path.datagram(out_bytes);
+        Ok(Some(match release {
+            Some(t) => dgram.with_release_time(t),
+            None => dgram,
+        }))
+    }
+
+    /// Build a datagram for the extra path at `index`.  These paths only
+    /// carry 1-RTT packets, and only the frames that validate the path until
+    /// it is validated.
+    fn output_mp_path(&mut self, index: usize, now: Instant) -> Option<Datagram> {
+        let (path, id, validated) = {
+            let p = &self.multipath.as_ref()?.paths[index];
+            (p.path.clone(), p.id, p.validated())
+        };
+        let data_ok = validated && self.data_allowed(id);
+        match self.crypto.obtain_crypto_state(self.role, 3) {
+            Ok(cs) if cs.tx.is_some() => {}
+            _ => return None,
+        }
+        let release = self.admit_datagram(now)?;
+        let mut builder = match &self.buffers {
+            Some(pool) => DatagramBuilder::with_pool(pool),
+            None => DatagramBuilder::default(),
+        };
+        let mut encoder = builder.frame_buffer();
+        let mut tokens = Vec::new();
+        let mut ack_eliciting = false;
+        loop {
+            let remaining = self.pmtu - encoder.len();
+            let mp = self.multipath.as_mut().unwrap();
+            let mut next = mp.path_frame(index, now, remaining);
+            if next.is_none() && validated {
+                next = mp.get_frame(remaining);
+            }
+            if next.is_none() && validated {
+                next = self
+                    .crypto
+                    .get_frame(3, TxMode::Normal, remaining)
+                    .or_else(|| self.flow_mgr.borrow_mut().get_frame(3, remaining));
+            }
+            if next.is_none() && data_ok {
+                next = self.send_streams.get_frame(3, TxMode::Normal, remaining);
+            }
+            let (frame, token) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            ack_eliciting |= frame.ack_eliciting();
+            frame.marshal(&mut encoder);
+            if let Some(t) = token {
+                tokens.push(t);
+            }
+            assert!(encoder.len() <= self.pmtu);
+            if encoder.len() == self.pmtu {
+                break;
+            }
+        }
+        if encoder.len() == 0 {
+            builder.recycle(encoder);
+            if let Some(pool) = &self.buffers {
+                pool.recycle(builder.build());
+            }
+            return None;
+        }
+
+        let pn = self.multipath.as_mut().unwrap().paths[index]
+            .recovery
+            .next_pn(PNSpace::ApplicationData);
+        let hdr = PacketHdr::new(
+            0,
+            PacketType::Short,
+            Some(self.version),
+            path.remote_cid.clone(),
+            None,
+            pn,
+            3,
+        );
+        self.stats.packets_tx += 1;
+        if ack_eliciting {
+            self.idle_timeout.on_packet_sent(now);
+        }
+        dump_packet(self, "TX ->", &hdr, &encoder);
+        if let Some(qlog) = &mut self.qlog {
+            qlog.packet(true, &hdr, &encoder, now);
+        }
+        let cs = self.crypto.obtain_crypto_state(self.role, 3).unwrap();
+        let crypto = PathCrypto {
+            inner: cs.tx.as_ref().unwrap(),
+            path: id,
+        };
+        let size = builder.add(&crypto, hdr, &encoder);
+        builder.recycle(encoder);
+        self.multipath.as_mut().unwrap().paths[index]
+            .recovery
+            .on_packet_sent(
+                PNSpace::ApplicationData,
+                pn,
+                ack_eliciting,
+                tokens,
+                size,
+                now,
+            );
+
+        let out_bytes = builder.build();
+        if let Some(r) = self.rate_limit.as_mut() {
+            r.sent(out_bytes.len());
         }
-        Ok(Some(Datagram::new(path.local, path.remote, out_bytes)))
+        let dgram = path.datagram(out_bytes);
+        Some(match release {
+            Some(t) => dgram.with_release_time(t),
+            None => dgram,
+        })
     }
 
     fn client_start(&mut self, now: Instant) -> Res<()> {
@@ -1139,8 +2636,16 @@ impl Connection {
         now + (self.loss_recovery.pto() * 3)
     }
 
-    /// Close the connection.
+    /// Close the connection with an application error code and a reason
+    /// phrase.  This does nothing if the connection is already closing.
     pub fn close(&mut self, now: Instant, error: AppError, msg: &str) {
+        if matches!(
+            self.state,
+            State::Closing { .. } | State::Draining { .. } | State::Closed { .. }
+        ) {
+            qdebug!([self] "Already closing, ignoring close({})", error);
+            return;
+        }
         self.set_state(State::Closing {
             error: ConnectionError::Application(error),
             frame_type: 0,
@@ -1153,8 +2658,15 @@ impl Connection {
     fn buffer_crypto_records(&mut self, records: RecordList) {
         for r in records {
             assert_eq!(r.ct, 22);
-            qdebug!([self] "Adding CRYPTO data {:?}", r);
-            self.crypto.streams[r.epoch as usize].tx.send(&r.data);
+            if self.crypto.is_discarded(r.epoch) {
+                qwarn!([self] "Dropping CRYPTO data for discarded epoch {}", r.epoch);
+                continue;
+            }
+            qdebug!([self] "Adding CRYPTO data {:?}", Redact(&r));
+            let stream = &mut self.crypto.streams[r.epoch as usize];
+            stream.tx.send(&r.data);
+            #[cfg(feature = "crypto-dump")]
+            stream.sent.extend_from_slice(&r.data);
         }
     }
 
@@ -1192,6 +2704,96 @@ impl Connection {
         }
     }
 
+    /// A Version Negotiation packet is only acted on if it echoes the
+    /// connection IDs that were sent and doesn't list the version that is in
+    /// use.  Anything else is forged or corrupted, and acting on it would let
+    /// an attacker close the connection or force a different version.
+    fn is_valid_vn(&self, hdr: &PacketHdr, versions: &[u32]) -> bool {
+        let path = self.paths.as_ref().unwrap();
+        hdr.scid.as_ref() == Some(&path.remote_cid)
+            && self.is_valid_cid(&hdr.dcid)
+            && !versions.contains(&self.version)
+    }
+
+    /// Check that the version information from the peer agrees with the
+    /// version in use.  This detects a downgrade, where an attacker removes
+    /// versions from the handshake or forges Version Negotiation.
+    fn validate_versions(&self) -> Res<()> {
+        let tph = self.tps.borrow();
+        if let Some((chosen, available)) = tph.remote().get_version_information() {
+            if chosen != self.version {
+                qwarn!([self] "Peer chose version {:x}, not {:x}", chosen, self.version);
+                return Err(Error::VersionNegotiationError);
+            }
+            // The server lists what it supports, which has to include the
+            // version that the client picked.
+            if self.role == Role::Client && !available.contains(&self.version) {
+                qwarn!([self] "Server versions {:x?} exclude {:x}", available, self.version);
+                return Err(Error::VersionNegotiationError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe the connection for `ConnectionEvent::HandshakeComplete`.
+    /// This only works for a server, and has to be called before the
+    /// randomized client CID is forgotten.
+    /// Start using the multipath extension if both ends enabled it, and issue
+    /// connection IDs for the peer to use on extra paths.
+    fn negotiate_multipath(&mut self) -> Res<()> {
+        let enabled = {
+            let tph = self.tps.borrow();
+            tph.local.was_sent(tp_const::ENABLE_MULTIPATH)
+                && tph
+                    .remote
+                    .as_ref()
+                    .map_or(false, |r| r.was_sent(tp_const::ENABLE_MULTIPATH))
+        };
+        if !enabled || self.multipath.is_some() {
+            return Ok(());
+        }
+        let path = self.paths.as_ref().ok_or(Error::ConnectionState)?;
+        if path.remote_cid.is_empty() || path.local_cids.iter().any(|c| c.is_empty()) {
+            qwarn!([self] "Multipath needs connection IDs at both ends");
+            return Err(Error::TransportParameterError);
+        }
+        qinfo!([self] "Multipath negotiated");
+        self.multipath = Some(Multipath::new());
+        for _ in 0..LOCAL_CIDS {
+            self.issue_path_cid()?;
+        }
+        Ok(())
+    }
+
+    fn handshake_record(&self, now: Instant) -> Option<HandshakeRecord> {
+        let server_name = match &self.crypto.tls {
+            Agent::Server(s) => s.server_name().map(String::from),
+            Agent::Client(_) => return None,
+        };
+        let info = self.crypto.tls.info()?;
+        let odcid = match self
+            .tps
+            .borrow()
+            .local
+            .get_bytes(tp_const::ORIGINAL_CONNECTION_ID)
+        {
+            Some(odcid) => odcid,
+            None => self.valid_cids.first()?.to_vec(),
+        };
+        Some(HandshakeRecord {
+            server_name,
+            alpn: info.alpn().cloned(),
+            cipher: info.cipher_suite(),
+            peer: self.paths.as_ref()?.remote,
+            odcid,
+            duration: self
+                .handshake_start
+                .map_or(Duration::new(0, 0), |t| max(t, now) - t),
+            resumed: info.resumed(),
+            early_data: info.early_data_accepted(),
+        })
+    }
+
     fn handshake(&mut self, now: Instant, epoch: u16, data: Option<&[u8]>) -> Res<()> {
         qdebug!("Handshake epoch={} data={:0x?}", epoch, data);
         let mut rec: Option<Record> = None;
@@ -1230,7 +2832,13 @@ impl Connection {
             }
 
             self.validate_odcid()?;
+            self.validate_versions()?;
+            self.negotiate_multipath()?;
+            let record = self.handshake_record(now);
             self.set_state(State::Connected);
+            if let Some(record) = record {
+                self.events.handshake_complete(record);
+            }
             self.set_initial_limits();
         }
         Ok(())
@@ -1254,7 +2862,7 @@ impl Connection {
         }
     }
 
-    fn input_frame(&mut self, epoch: Epoch, frame: Frame, now: Instant) -> Res<()> {
+    fn input_frame(&mut self, epoch: Epoch, path: PathId, frame: Frame, now: Instant) -> Res<()> {
         match frame {
             Frame::Padding => {
                 // Ignore
@@ -1303,14 +2911,26 @@ impl Connection {
                     "Crypto frame on epoch={} offset={}, data={:0x?}",
                     epoch,
                     offset,
-                    &data
+                    Redact(&data)
                 );
-                let rx = &mut self.crypto.streams[epoch as usize].rx;
-                rx.inbound_frame(offset, data)?;
-                if rx.data_ready() {
+                let stream = &mut self.crypto.streams[epoch as usize];
+                stream.rx.inbound_frame(offset, data)?;
+                if stream.rx.data_ready() {
                     let mut buf = Vec::new();
-                    let read = rx.read_to_end(&mut buf)?;
+                    let read = stream.rx.read_to_end(&mut buf)?;
                     qdebug!("Read {} bytes", read);
+                    #[cfg(feature = "crypto-dump")]
+                    stream.received.extend_from_slice(&buf);
+                    if self.role == Role::Server && epoch == 0 {
+                        // A ClientHello can arrive in several Initial packets.
+                        // Wait for all of it before driving TLS.
+                        stream.held.extend_from_slice(&buf);
+                        if !whole_messages(&stream.held) {
+                            qdebug!([self] "Holding {} bytes of ClientHello", stream.held.len());
+                            return Ok(());
+                        }
+                        buf = mem::replace(&mut stream.held, Vec::new());
+                    }
                     self.handshake(now, epoch, Some(&buf))?;
                 }
             }
@@ -1388,26 +3008,97 @@ impl Connection {
                     .insert(sequence_number, (connection_id, stateless_reset_token));
             }
             Frame::RetireConnectionId { sequence_number } => {
-                self.connection_ids.remove(&sequence_number);
+                if self.multipath.is_some() {
+                    self.retire_path_cid(sequence_number)?;
+                } else {
+                    self.connection_ids.remove(&sequence_number);
+                }
             }
-            Frame::PathChallenge { data } => self.flow_mgr.borrow_mut().path_response(data),
-            Frame::PathResponse { .. } => {
-                // Should never see this, we don't support migration atm and
-                // do not send path challenges
-                qwarn!([self] "Received Path Response");
+            Frame::PathChallenge { data } => match self.multipath.as_mut() {
+                Some(mp) if path != 0 => mp.path_challenge(path, data),
+                _ => self.flow_mgr.borrow_mut().path_response(data),
+            },
+            Frame::PathResponse { data } => {
+                // Only extra paths are ever challenged.
+                if !self
+                    .multipath
+                    .as_mut()
+                    .map_or(false, |mp| mp.path_response(data))
+                {
+                    qwarn!([self] "Received unexpected Path Response");
+                }
             }
-            Frame::ConnectionClose {
-                error_code,
-                frame_type,
-                reason_phrase,
-            } => {
-                let reason_phrase = String::from_utf8_lossy(&reason_phrase);
-                qinfo!([self]
+            Frame::AckMp { .. } | Frame::PathAbandon { .. } | Frame::PathStatus { .. }
+                if self.multipath.is_none() || epoch != 3 =>
+            {
+                qwarn!([self] "Received multipath frame without negotiating multipath");
+                return Err(Error::ProtocolViolation);
+            }
+            Frame::AckMp {
+                space_identifier,
+                largest_acknowledged,
+                ack_delay,
+                first_ack_range,
+                ack_ranges,
+            } => {
+                if space_identifier == 0 {
+                    self.handle_ack(
+                        epoch,
+                        largest_acknowledged,
+                        ack_delay,
+                        first_ack_range,
+                        ack_ranges,
+                        now,
+                    )?;
+                } else {
+                    self.handle_mp_ack(
+                        space_identifier,
+                        largest_acknowledged,
+                        ack_delay,
+                        first_ack_range,
+                        ack_ranges,
+                        now,
+                    )?;
+                }
+            }
+            Frame::PathAbandon {
+                dcid_sequence_number,
+                ..
+            } => {
+                if dcid_sequence_number == 0 {
+                    qwarn!([self] "Peer abandoned path 0");
+                    return Err(Error::ProtocolViolation);
+                }
+                let mp = self.multipath.as_mut().unwrap();
+                if let Some(p) = mp.peer_abandoned(dcid_sequence_number) {
+                    self.forget_path(p);
+                }
+            }
+            Frame::PathStatus {
+                dcid_sequence_number,
+                status_sequence_number,
+                status,
+            } => self.multipath.as_mut().unwrap().peer_status(
+                dcid_sequence_number,
+                status_sequence_number,
+                status,
+            )?,
+            Frame::ConnectionClose {
+                error_code,
+                frame_type,
+                reason_phrase,
+            } => {
+                let reason_phrase = String::from_utf8_lossy(&reason_phrase);
+                qinfo!([self]
                        "ConnectionClose received. Error code: {:?} frame type {:x} reason {}",
                        error_code,
                        frame_type,
                        reason_phrase);
-                self.set_state(State::Closed(error_code.into()));
+                self.set_state(State::Draining {
+                    error: error_code.into(),
+                    reason: reason_phrase.into_owned(),
+                    timeout: self.get_closing_period_time(now),
+                });
             }
         };
 
@@ -1441,7 +3132,74 @@ impl Connection {
             Duration::from_millis(ack_delay),
             now,
         );
-        for acked in acked_packets {
+        self.update_loss_stats();
+        if !acked_packets.is_empty()
+            && self.state == State::Connected
+            && PNSpace::from(epoch) == PNSpace::ApplicationData
+            && self
+                .first_1rtt_pn
+                .map_or(false, |pn| largest_acknowledged >= pn)
+        {
+            self.confirm_handshake();
+        }
+        self.acked_packets(acked_packets);
+        self.lost_packets(lost_packets);
+
+        Ok(())
+    }
+
+    /// Handle an ACK_MP for packets that were sent on an extra path.
+    fn handle_mp_ack(
+        &mut self,
+        id: PathId,
+        largest_acknowledged: u64,
+        ack_delay: u64,
+        first_ack_range: u64,
+        ack_ranges: Vec<AckRange>,
+        now: Instant,
+    ) -> Res<()> {
+        qinfo!(
+            [self]
+            "Rx ACK_MP path={}, largest_acked={}, first_ack_range={}, ranges={:?}",
+            id,
+            largest_acknowledged,
+            first_ack_range,
+            ack_ranges
+        );
+
+        let acked_ranges =
+            Frame::decode_ack_frame(largest_acknowledged, first_ack_range, ack_ranges)?;
+        let mp = self.multipath.as_mut().unwrap();
+        let i = match mp.find(id) {
+            Some(i) => i,
+            // The path was abandoned.
+            None => return Ok(()),
+        };
+        let (acked_packets, lost_packets) = mp.paths[i].recovery.on_ack_received(
+            PNSpace::ApplicationData,
+            largest_acknowledged,
+            acked_ranges,
+            Duration::from_millis(ack_delay),
+            now,
+        );
+        self.update_loss_stats();
+        self.acked_packets(acked_packets);
+        self.lost_packets(lost_packets);
+        Ok(())
+    }
+
+    fn update_loss_stats(&mut self) {
+        let (lost, spurious) = self
+            .multipath
+            .as_ref()
+            .map_or((0, 0), |mp| (mp.lost_count(), mp.spurious_count()));
+        self.stats.lost = self.loss_recovery.lost_count() + lost;
+        self.stats.spurious_lost = self.loss_recovery.spurious_count() + spurious;
+    }
+
+    /// Tell everything that was in packets that were acknowledged.
+    fn acked_packets(&mut self, packets: Vec<SentPacket>) {
+        for acked in packets {
             for token in acked.tokens {
                 match token {
                     RecoveryToken::Ack(at) => self.acks.acked(&at),
@@ -1450,10 +3208,20 @@ impl Connection {
                     RecoveryToken::Flow(ft) => {
                         self.flow_mgr.borrow_mut().acked(ft, &mut self.send_streams)
                     }
+                    RecoveryToken::Multipath(mt) => {
+                        if let Some(mp) = self.multipath.as_mut() {
+                            mp.acked(mt);
+                        }
+                    }
                 }
             }
         }
-        for lost in lost_packets {
+    }
+
+    /// Tell everything that was in packets that were lost, so that it can be
+    /// sent again if need be.
+    fn lost_packets(&mut self, packets: Vec<SentPacket>) {
+        for lost in packets {
             for token in lost.tokens {
                 match token {
                     RecoveryToken::Ack(_) => {}
@@ -1465,11 +3233,76 @@ impl Connection {
                         &mut self.recv_streams,
                         &mut self.indexes,
                     ),
+                    RecoveryToken::Multipath(mt) => {
+                        if let Some(mp) = self.multipath.as_mut() {
+                            mp.lost(mt);
+                        }
+                    }
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Throw away the keys for `epoch`, which is either Initial or Handshake,
+    /// and stop sending, acknowledging, or retransmitting packets in its
+    /// packet number space.
+    fn discard_keys(&mut self, epoch: Epoch) {
+        if self.crypto.is_discarded(epoch) {
+            return;
+        }
+        let space = PNSpace::from(epoch);
+        qinfo!([self] "Discard keys for {:?}", space);
+        self.crypto.discard(epoch);
+        self.loss_recovery.discard(space);
+        self.acks.drop_space(space);
+        self.saved_packets.retain(|(e, _)| *e != epoch);
+    }
+
+    /// The handshake is confirmed once the peer won't need anything more in
+    /// Handshake packets.  For a client, that is when the server acknowledges
+    /// a 1-RTT packet.  For a server, that is when the handshake completes,
+    /// but the client's Finished still needs to be acknowledged, so Handshake
+    /// keys are kept until the next Handshake packet is sent.
+    fn confirm_handshake(&mut self) {
+        if self.handshake_confirmed {
+            return;
+        }
+        qinfo!([self] "Handshake confirmed");
+        self.handshake_confirmed = true;
+        self.discard_keys(0);
+        if self.role == Role::Client {
+            self.discard_keys(2);
+        }
+        self.events.handshake_confirmed();
+    }
+
+    /// Whether the handshake is confirmed, after which only 1-RTT packets are
+    /// sent or accepted.
+    pub fn handshake_confirmed(&self) -> bool {
+        self.handshake_confirmed
+    }
+
+    /// Determine whether streams created during 0-RTT can continue after
+    /// 0-RTT is rejected.  This requires that the server not have reduced any
+    /// of the limits that were used when creating and writing to the streams.
+    fn can_resend_0rtt(&self) -> bool {
+        const LIMITS: &[tp_const::TransportParameterId] = &[
+            tp_const::INITIAL_MAX_DATA,
+            tp_const::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+            tp_const::INITIAL_MAX_STREAM_DATA_UNI,
+            tp_const::INITIAL_MAX_STREAMS_BIDI,
+            tp_const::INITIAL_MAX_STREAMS_UNI,
+        ];
+        if !self.resend_0rtt {
+            return false;
+        }
+        let tph = self.tps.borrow();
+        match (tph.remote.as_ref(), tph.remote_0rtt.as_ref()) {
+            (Some(remote), Some(remembered)) => LIMITS
+                .iter()
+                .all(|&l| remote.get_integer(l) >= remembered.get_integer(l)),
+            _ => false,
+        }
     }
 
     /// When the server rejects 0-RTT we need to drop a bunch of stuff.
@@ -1477,28 +3310,32 @@ impl Connection {
         if self.zero_rtt_state != ZeroRttState::Sending {
             return;
         }
-
-        // Tell 0-RTT packets that they were "lost".
-        // TODO(mt) remove these from "bytes in flight" when we
-        // have a congestion controller.
-        for dropped in self.loss_recovery.drop_0rtt() {
-            for token in dropped.tokens {
-                match token {
-                    RecoveryToken::Ack(_) => {}
-                    RecoveryToken::Stream(st) => self.send_streams.lost(&st),
-                    RecoveryToken::Crypto(ct) => self.crypto.lost(ct),
-                    RecoveryToken::Flow(ft) => self.flow_mgr.borrow_mut().lost(
-                        ft,
-                        &mut self.send_streams,
-                        &mut self.recv_streams,
-                        &mut self.indexes,
-                    ),
-                }
+        let resend = self.can_resend_0rtt();
+
+        // Tell 0-RTT packets that they were "lost".  They no longer count
+        // toward bytes in flight.
+        let dropped = self.loss_recovery.drop_0rtt();
+        self.lost_packets(dropped);
+        if resend {
+            qinfo!([self] "0-RTT rejected, resending stream data in 1-RTT");
+            let (bidi, uni) = {
+                let tph = self.tps.borrow();
+                let remote = tph.remote.as_ref().unwrap();
+                (
+                    remote.get_integer(tp_const::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE),
+                    remote.get_integer(tp_const::INITIAL_MAX_STREAM_DATA_UNI),
+                )
+            };
+            for (id, ss) in &mut self.send_streams {
+                ss.set_max_stream_data(if id.is_bidi() { bidi } else { uni });
             }
+            self.events.client_0rtt_resent();
+        } else {
+            self.send_streams.clear();
+            self.recv_streams.clear();
+            self.events.stream_groups().borrow_mut().retain(|_| false);
+            self.events.client_0rtt_rejected();
         }
-        self.send_streams.clear();
-        self.recv_streams.clear();
-        self.events.client_0rtt_rejected();
     }
 
     fn set_state(&mut self, state: State) {
@@ -1511,9 +3348,11 @@ impl Connection {
                         // Remove the randomized client CID from the list of acceptable CIDs.
                         assert_eq!(1, self.valid_cids.len());
                         self.valid_cids.clear();
+                        self.confirm_handshake();
                     } else {
                         self.zero_rtt_state =
                             if self.crypto.tls.info().unwrap().early_data_accepted() {
+                                self.events.client_0rtt_accepted();
                                 ZeroRttState::Accepted
                             } else {
                                 self.client_0rtt_rejected();
@@ -1524,12 +3363,14 @@ impl Connection {
                 State::Closing { .. } => {
                     self.send_streams.clear();
                     self.recv_streams.clear();
+                    self.saved_packets.clear();
                     self.flow_mgr.borrow_mut().set_need_close_frame(true);
                 }
-                State::Closed(..) => {
-                    // Equivalent to spec's "draining" state -- never send anything.
+                State::Draining { .. } | State::Closed { .. } => {
+                    // Never send anything.
                     self.send_streams.clear();
                     self.recv_streams.clear();
+                    self.saved_packets.clear();
                 }
                 _ => {}
             }
@@ -1575,6 +3416,11 @@ impl Connection {
         }
 
         self.send_streams.clear_terminal();
+        let (send, recv) = (&self.send_streams, &self.recv_streams);
+        self.events
+            .stream_groups()
+            .borrow_mut()
+            .retain(|id| send.get(id).is_ok() || recv.contains_key(&id));
     }
 
     /// Get or make a stream, and implicitly open additional streams as
@@ -1674,7 +3520,9 @@ impl Connection {
     pub fn stream_create(&mut self, st: StreamType) -> Res<u64> {
         // Can't make streams while closing, otherwise rely on the stream limits.
         match self.state {
-            State::Closing { .. } | State::Closed { .. } => return Err(Error::ConnectionState),
+            State::Closing { .. } | State::Draining { .. } | State::Closed { .. } => {
+                return Err(Error::ConnectionState)
+            }
             State::WaitInitial | State::Handshaking => {
                 if matches!(
                     self.zero_rtt_state,
@@ -1780,6 +3628,32 @@ impl Connection {
         self.send_streams.get_mut(stream_id.into())?.send(data)
     }
 
+    /// Send data and close the stream in one call.  The stream is only closed
+    /// if all of `data` can be sent; otherwise this is the same as
+    /// `stream_send()`, and the rest needs to be sent before closing.
+    pub fn stream_send_with_fin(&mut self, stream_id: u64, data: &[u8]) -> Res<usize> {
+        let stream = self.send_streams.get_mut(stream_id.into())?;
+        let sent = stream.send(data)?;
+        if sent == data.len() {
+            stream.close();
+        }
+        Ok(sent)
+    }
+
+    /// Send the data from `source` on a stream, after any data that was
+    /// already sent, and then close it.  Data is read from `source` when
+    /// there is room for it in a packet.  `stream_send()` can't be used on the
+    /// stream after this.  See `SendStreamSource`.
+    pub fn stream_send_source(
+        &mut self,
+        stream_id: u64,
+        source: Box<dyn SendStreamSource>,
+    ) -> Res<()> {
+        self.send_streams
+            .get_mut(stream_id.into())?
+            .set_source(source)
+    }
+
     /// Bytes that stream_send() is guaranteed to accept for sending.
     /// i.e. that will not be blocked by flow credits or send buffer max
     /// capacity.
@@ -1811,6 +3685,18 @@ impl Connection {
         Ok((rb.0 as usize, rb.1))
     }
 
+    /// Watch what the application reads from a stream, without affecting
+    /// it.  The observer gets a copy of everything that `stream_recv()`
+    /// returns for the stream, and holds up to `limit` bytes that it hasn't
+    /// taken.  A stream can have any number of observers.
+    pub fn stream_observe(&mut self, stream_id: u64, limit: usize) -> Res<StreamObserver> {
+        let stream = self
+            .recv_streams
+            .get_mut(&stream_id.into())
+            .ok_or_else(|| Error::InvalidStreamId)?;
+        Ok(stream.observe(limit))
+    }
+
     /// Application is no longer interested in this stream.
     pub fn stream_stop_sending(&mut self, stream_id: u64, err: AppError) -> Res<()> {
         let stream = self
@@ -1822,16 +3708,121 @@ impl Connection {
         Ok(())
     }
 
+    /// Set the priority of a stream.  Data is sent from streams with a
+    /// higher priority first.  Streams start with a priority of 0.
+    pub fn stream_set_priority(&mut self, stream_id: u64, priority: u8) -> Res<()> {
+        self.send_streams
+            .get_mut(stream_id.into())?
+            .set_priority(priority);
+        Ok(())
+    }
+
+    /// Make an empty group of streams, so that streams can be acted on
+    /// together.  When any stream in a group becomes readable or writable,
+    /// there is a `StreamGroupReadable` or `StreamGroupWritable` event for
+    /// the group as well as the event for the stream.
+    pub fn stream_group_create(&mut self) -> StreamGroupId {
+        self.events.stream_groups().borrow_mut().create()
+    }
+
+    /// Remove a group.  This doesn't affect the streams that were in it.
+    pub fn stream_group_delete(&mut self, group: StreamGroupId) -> Res<()> {
+        self.events.stream_groups().borrow_mut().delete(group)
+    }
+
+    /// Add a stream to a group.  A stream is in at most one group, so this
+    /// moves it out of any other group.  Streams leave their group once they
+    /// are finished.
+    pub fn stream_group_add(&mut self, group: StreamGroupId, stream_id: u64) -> Res<()> {
+        let id = StreamId::from(stream_id);
+        if self.send_streams.get(id).is_err() && !self.recv_streams.contains_key(&id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.events.stream_groups().borrow_mut().add(group, id)
+    }
+
+    /// Take a stream out of its group, if it is in one.
+    pub fn stream_group_remove(&mut self, stream_id: u64) {
+        self.events
+            .stream_groups()
+            .borrow_mut()
+            .remove(stream_id.into());
+    }
+
+    /// The streams in a group, in order.
+    pub fn stream_group_streams(&self, group: StreamGroupId) -> Res<Vec<u64>> {
+        let groups = self.events.stream_groups().borrow();
+        Ok(groups
+            .streams(group)?
+            .iter()
+            .map(|id| id.as_u64())
+            .collect())
+    }
+
+    /// Apply `f` to the sending side of every stream in a group.
+    fn stream_group_send(
+        &mut self,
+        group: StreamGroupId,
+        mut f: impl FnMut(&mut SendStream),
+    ) -> Res<()> {
+        let groups = self.events.stream_groups().borrow();
+        for id in groups.streams(group)? {
+            if let Ok(stream) = self.send_streams.get_mut(*id) {
+                f(stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the priority of every stream in a group.
+    pub fn stream_group_set_priority(&mut self, group: StreamGroupId, priority: u8) -> Res<()> {
+        self.stream_group_send(group, |s| s.set_priority(priority))
+    }
+
+    /// Close every stream in a group, as `stream_close_send()` does.
+    pub fn stream_group_close_send(&mut self, group: StreamGroupId) -> Res<()> {
+        self.stream_group_send(group, SendStream::close)
+    }
+
+    /// Reset every stream in a group, as `stream_reset_send()` does.
+    pub fn stream_group_reset_send(&mut self, group: StreamGroupId, err: AppError) -> Res<()> {
+        self.stream_group_send(group, |s| s.reset(err))
+    }
+
     /// Get events that indicate state changes on the connection.
     pub fn events(&mut self) -> impl Iterator<Item = ConnectionEvent> {
         self.events.events()
     }
 
+    /// Take the next event, in the same order as `events()`.  Events are
+    /// taken one at a time from where they are kept, so calling this until
+    /// it returns `None` collects events without making a new list.  Only
+    /// events that carry data, such as a close reason, are copied to find
+    /// them, which can allocate.
+    pub fn next_event(&mut self) -> Option<ConnectionEvent> {
+        self.events.next_event()
+    }
+
+    /// Subscribe to events that match `filter`.  A subscription gets its own
+    /// copy of events, so this can be used alongside `events()`.
+    pub fn subscribe(&self, filter: EventFilter) -> EventSubscription {
+        self.events.subscribe(filter)
+    }
+
     /// Return true if there are outstanding events.
     pub fn has_events(&self) -> bool {
         self.events.has_events()
     }
 
+    fn retransmission_limit_reached(&self) -> bool {
+        let exceeds = |limit: Option<u32>, count| limit.map_or(false, |l| count >= l);
+        exceeds(self.max_ptos, self.loss_recovery.pto_count())
+            || exceeds(
+                self.max_packet_ptos,
+                self.loss_recovery.max_packet_pto_count(),
+            )
+    }
+
     fn check_loss_detection_timeout(&mut self, now: Instant) {
         qdebug!([self] "check_loss_timeouts");
 
@@ -1855,23 +3846,10 @@ impl Connection {
                     .get_earliest_loss_time()
                     .expect("must be sent packets if in LostPackets mode");
                 let packets = self.loss_recovery.detect_lost_packets(pn_space, now);
+                self.update_loss_stats();
 
                 qinfo!("lost packets: {}", packets.len());
-                for lost in packets {
-                    for token in lost.tokens {
-                        match token {
-                            RecoveryToken::Ack(_) => {} // Do nothing
-                            RecoveryToken::Stream(st) => self.send_streams.lost(&st),
-                            RecoveryToken::Crypto(ct) => self.crypto.lost(ct),
-                            RecoveryToken::Flow(ft) => self.flow_mgr.borrow_mut().lost(
-                                ft,
-                                &mut self.send_streams,
-                                &mut self.recv_streams,
-                                &mut self.indexes,
-                            ),
-                        }
-                    }
-                }
+                self.lost_packets(packets);
             }
             LossRecoveryMode::PTO => {
                 qinfo!(
@@ -1879,6 +3857,15 @@ impl Connection {
                     "check_loss_detection_timeout -send_one_or_two_packets"
                 );
                 self.loss_recovery.increment_pto_count();
+                self.stats.pto += 1;
+                if self.retransmission_limit_reached() {
+                    qinfo!([self] "retransmission limit reached");
+                    self.set_state(State::Closed {
+                        error: ConnectionError::Transport(Error::TooLossy),
+                        reason: None,
+                    });
+                    return;
+                }
                 // TODO
                 // if (has unacknowledged crypto data):
                 //   RetransmitUnackedCryptoData()
@@ -1895,11 +3882,53 @@ impl Connection {
             }
         }
     }
+
+    /// Run loss detection for extra paths.  There are no probes on these
+    /// paths, so a path that keeps hitting PTO is abandoned instead.
+    fn check_path_timeouts(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        let mp = match self.multipath.as_mut() {
+            Some(mp) => mp,
+            None => return,
+        };
+        let mut lost = Vec::new();
+        let mut dead = Vec::new();
+        for p in &mut mp.paths {
+            let lr = p.recovery.get_timer(&self.state);
+            if lr.callback_time().map_or(true, |t| t > now) {
+                continue;
+            }
+            match lr.mode() {
+                LossRecoveryMode::None => {}
+                LossRecoveryMode::LostPackets => {
+                    lost.extend(
+                        p.recovery
+                            .detect_lost_packets(PNSpace::ApplicationData, now),
+                    );
+                }
+                LossRecoveryMode::PTO => {
+                    p.recovery.increment_pto_count();
+                    self.stats.pto += 1;
+                    if p.recovery.pto_count() >= MAX_PATH_PTOS {
+                        dead.push(p.id);
+                    }
+                }
+            }
+        }
+        self.update_loss_stats();
+        self.lost_packets(lost);
+        for id in dead {
+            qinfo!([self] "Abandoning path {} after repeated PTO", id);
+            self.abandon_path(id);
+        }
+    }
 }
 
 impl ::std::fmt::Display for Connection {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?} {:p}", self.role, self as *const Connection)
+        write!(f, "{:?} #{}", self.role, self.log_id)
     }
 }
 
@@ -1907,6 +3936,9 @@ impl ::std::fmt::Display for Connection {
 mod tests {
     use super::*;
     use crate::frame::StreamType;
+    use crate::packet::encode_packet_vn;
+    use crate::tracking::ACK_DELAY;
+    use crate::{LruResumptionStore, ResumptionStore, StatsDelta};
     use test_fixture::{self, assertions, fixture_init, loopback, now};
 
     // This is fabulous: because test_fixture uses the public API for Connection,
@@ -2205,7 +4237,10 @@ mod tests {
         let mut datagram = None;
         let is_done = |c: &mut Connection| match c.state() {
             // TODO(mt): Finish on Closed and not Closing.
-            State::Connected | State::Closing { .. } | State::Closed(..) => true,
+            State::Connected
+            | State::Closing { .. }
+            | State::Draining { .. }
+            | State::Closed { .. } => true,
             _ => false,
         };
         while !is_done(a) {
@@ -2226,111 +4261,419 @@ mod tests {
     fn assert_error(c: &Connection, err: ConnectionError) {
         match c.state() {
             // TODO(mt): Finish on Closed and not Closing.
-            State::Closing { error, .. } | State::Closed(error) => {
+            State::Closing { error, .. }
+            | State::Draining { error, .. }
+            | State::Closed { error, .. } => {
                 assert_eq!(*error, err);
             }
             _ => panic!("bad state {:?}", c.state()),
         }
     }
 
+    #[cfg(feature = "pq-hybrid")]
     #[test]
-    fn test_no_alpn() {
-        fixture_init();
-        let mut client = Connection::new_client(
-            "example.com",
-            &["bad-alpn"],
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(9))),
-            loopback(),
-            loopback(),
-        )
-        .unwrap();
+    fn pq_hybrid() {
+        let mut client = default_client();
+        client.enable_pq_hybrid().unwrap();
         let mut server = default_server();
+        server.enable_pq_hybrid().unwrap();
 
-        handshake(&mut client, &mut server);
-        // TODO (mt): errors are immediate, which means that we never send CONNECTION_CLOSE
-        // and the client never sees the server's rejection of its handshake.
-        //assert_error(&client, ConnectionError::Transport(Error::CryptoAlert(120)));
-        assert_error(&server, ConnectionError::Transport(Error::CryptoAlert(120)));
+        // The ClientHello doesn't fit in one packet if a hybrid group is used.
+        connect(&mut client, &mut server);
+        let group = client.crypto.tls.info().unwrap().key_exchange();
+        assert_eq!(server.crypto.tls.info().unwrap().key_exchange(), group);
+        assert!(PQ_HYBRID_GROUPS.contains(&group));
+        assert_eq!(client.enable_pq_hybrid(), Err(Error::ConnectionState));
     }
 
     #[test]
-    fn test_dup_server_flight1() {
-        qdebug!("---- client: generate CH");
+    fn stateless_reset_token() {
+        let key = [1; 32];
         let mut client = default_client();
-        let out = client.process(None, now());
-        assert!(out.as_dgram_ref().is_some());
-        assert_eq!(out.as_dgram_ref().unwrap().len(), 1200);
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
-
-        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
         let mut server = default_server();
-        let out_to_rep = server.process(out.dgram(), now());
-        assert!(out_to_rep.as_dgram_ref().is_some());
-        qdebug!("Output={:0x?}", out_to_rep.as_dgram_ref());
-
-        qdebug!("---- client: cert verification");
-        let out = client.process(Some(out_to_rep.as_dgram_ref().unwrap().clone()), now());
-        assert!(out.as_dgram_ref().is_some());
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
-
-        let out = server.process(out.dgram(), now());
-        assert!(out.as_dgram_ref().is_none());
-
-        assert!(maybe_authenticate(&mut client));
-
-        qdebug!("---- client: SH..FIN -> FIN");
-        let out = client.process(None, now());
-        assert!(out.as_dgram_ref().is_some());
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
-
-        assert_eq!(2, client.stats().packets_rx);
-        assert_eq!(0, client.stats().dups_rx);
-
-        qdebug!("---- Dup, ignored");
-        let out = client.process(out_to_rep.dgram().clone(), now());
-        assert!(out.as_dgram_ref().is_none());
-        qdebug!("Output={:0x?}", out.as_dgram_ref());
+        assert_eq!(
+            client.server_set_stateless_reset_key(&key),
+            Err(Error::WrongRole)
+        );
+        server.server_set_stateless_reset_key(&key).unwrap();
+        connect(&mut client, &mut server);
 
-        // Four packets total received, two of them are dups
-        assert_eq!(4, client.stats().packets_rx);
-        assert_eq!(2, client.stats().dups_rx);
+        let cid = server.paths.as_ref().unwrap().local_cids[0].clone();
+        let token = client
+            .tps
+            .borrow()
+            .remote
+            .as_ref()
+            .unwrap()
+            .get_bytes(tp_const::STATELESS_RESET_TOKEN);
+        assert_eq!(token, Some(reset_token(&key, &cid).unwrap().to_vec()));
+        assert_eq!(
+            server.server_set_stateless_reset_key(&key),
+            Err(Error::ConnectionState)
+        );
     }
 
-    fn exchange_ticket(client: &mut Connection, server: &mut Connection) -> Vec<u8> {
-        server.send_ticket(now(), &[]).expect("can send ticket");
-        let out = server.process_output(now());
-        assert!(out.as_dgram_ref().is_some());
-        client.process_input(out.dgram().unwrap(), now());
-        assert_eq!(*client.state(), State::Connected);
-        client.resumption_token().expect("should have token")
+    #[test]
+    fn grease_handshake() {
+        for seed in 0..10 {
+            let mut client = default_client();
+            client.enable_grease(seed).expect("enable grease on client");
+            let mut server = default_server();
+            server
+                .enable_grease(seed + 1)
+                .expect("enable grease on server");
+
+            let out = client.process(None, now());
+            let len = out.as_dgram_ref().expect("client sends Initial").len();
+            assert!(len >= MIN_INITIAL_DATAGRAM_SIZE && len <= client.pmtu);
+            let remote_cid_len = client.paths.as_ref().unwrap().remote_cid.len();
+            assert!(remote_cid_len >= 8 && remote_cid_len <= 20);
+            let _ = server.process(out.dgram(), now());
+
+            handshake(&mut client, &mut server);
+            assert_eq!(*client.state(), State::Connected);
+            assert_eq!(*server.state(), State::Connected);
+        }
     }
 
     #[test]
-    fn resume() {
+    fn grease_after_start() {
         let mut client = default_client();
-        let mut server = default_server();
-        connect(&mut client, &mut server);
+        let _ = client.process(None, now());
+        assert_eq!(client.enable_grease(1), Err(Error::ConnectionState));
+    }
 
-        let token = exchange_ticket(&mut client, &mut server);
+    #[test]
+    fn initial_padding_ack() {
         let mut client = default_client();
-        client
-            .set_resumption_token(now(), &token[..])
-            .expect("should set token");
         let mut server = default_server();
-        connect(&mut client, &mut server);
-        assert!(client.crypto.tls.info().unwrap().resumed());
-        assert!(server.crypto.tls.info().unwrap().resumed());
+        let out = client.process(None, now());
+        assert_eq!(out.as_dgram_ref().unwrap().len(), MIN_INITIAL_DATAGRAM_SIZE);
+        let out = server.process(out.dgram(), now());
+        // The client only has ACKs to send, but these include an Initial packet.
+        let out = client.process(out.dgram(), now());
+        assert_eq!(out.as_dgram_ref().unwrap().len(), MIN_INITIAL_DATAGRAM_SIZE);
     }
 
     #[test]
-    fn zero_rtt_negotiate() {
-        // Note that the two servers in this test will get different anti-replay filters.
-        // That's OK because we aren't testing anti-replay.
+    fn initial_padding_retransmit() {
         let mut client = default_client();
-        let mut server = default_server();
-        connect(&mut client, &mut server);
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some()); // Drop the Initial.
+        let when = client.process(None, now()).ready_at(now()).unwrap();
+        let out = client.process(None, when);
+        assert_eq!(out.as_dgram_ref().unwrap().len(), MIN_INITIAL_DATAGRAM_SIZE);
+    }
 
-        let token = exchange_ticket(&mut client, &mut server);
+    #[test]
+    fn initial_padding_setting() {
+        let mut client = default_client();
+        assert_eq!(
+            client.set_initial_padding(MIN_INITIAL_DATAGRAM_SIZE - 1),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(
+            client.set_initial_padding(client.pmtu + 1),
+            Err(Error::InvalidInput)
+        );
+        client.set_initial_padding(client.pmtu).unwrap();
+        let out = client.process(None, now());
+        assert_eq!(out.as_dgram_ref().unwrap().len(), client.pmtu);
+
+        let mut server = default_server();
+        assert_eq!(
+            server.set_initial_padding(MIN_INITIAL_DATAGRAM_SIZE),
+            Err(Error::WrongRole)
+        );
+    }
+
+    #[test]
+    fn client_hello_split() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(
+            server.set_client_hello_split(Some(100)),
+            Err(Error::WrongRole)
+        );
+        assert_eq!(
+            client.set_client_hello_split(Some(0)),
+            Err(Error::InvalidInput)
+        );
+        client.set_client_hello_split(Some(100)).unwrap();
+
+        let first = client.process(None, now()).dgram().unwrap();
+        assert_eq!(first.len(), MIN_INITIAL_DATAGRAM_SIZE);
+        let mut rest = Vec::new();
+        while let Some(d) = client.process(None, now()).dgram() {
+            assert_eq!(d.len(), MIN_INITIAL_DATAGRAM_SIZE);
+            rest.push(d);
+        }
+        assert!(!rest.is_empty());
+        assert_eq!(
+            client.set_client_hello_split(None),
+            Err(Error::ConnectionState)
+        );
+
+        // The server holds the first piece until it has all of them.
+        let _ = server.process(Some(first), now());
+        assert_eq!(*server.crypto.tls.state(), HandshakeState::New);
+        for d in rest {
+            let _ = server.process(Some(d), now());
+        }
+        assert_ne!(*server.crypto.tls.state(), HandshakeState::New);
+    }
+
+    #[test]
+    fn channel_binding() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.channel_binding(), Err(Error::ConnectionState));
+        connect(&mut client, &mut server);
+
+        let cb = client.channel_binding().expect("client channel binding");
+        assert_eq!(cb.len(), 32);
+        assert_eq!(server.channel_binding(), Ok(cb));
+        assert_eq!(
+            client.export_keying_material("EXPORTER-test", None, 16),
+            server.export_keying_material("EXPORTER-test", None, 16)
+        );
+    }
+
+    #[test]
+    fn process_iter() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        let mut it = client.process_iter(None, now());
+        let initial: Vec<_> = it.by_ref().collect();
+        assert_eq!(initial.len(), 1);
+        assert!(matches!(it.last(), Some(Output::Callback(_))));
+        assert!(it.ready_at(now()).unwrap() > now());
+        // An exhausted iterator stays exhausted.
+        assert!(it.next().is_none());
+
+        let mut it = server.process_iter(initial.into_iter().next(), now());
+        assert!(it.by_ref().count() > 0);
+        assert!(matches!(it.last(), Some(Output::Callback(_))));
+    }
+
+    #[test]
+    fn output_batch() {
+        let d = |len| Datagram::new(loopback(), loopback(), vec![0; len]);
+        let mut outputs = vec![d(100), d(100), d(100), d(50), d(100)].into_iter();
+        let mut it = OutputIter::new(move || outputs.next().map_or(Output::None, Output::Datagram));
+        let b = it.batch(2).unwrap();
+        assert_eq!((b.count(), b.segment_size()), (2, 100));
+        // A short datagram ends a batch.
+        let b = it.batch(10).unwrap();
+        assert_eq!((b.count(), b.len()), (2, 150));
+        let b = it.batch(10).unwrap();
+        assert_eq!(b.count(), 1);
+        assert!(it.batch(10).is_none());
+        assert_eq!(it.last(), Some(&Output::None));
+    }
+
+    #[test]
+    fn input_batch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let initial = client.process(None, now()).dgram();
+        let mut it = server.process_iter(initial, now());
+        let batches: Vec<_> = std::iter::from_fn(|| it.batch(10)).collect();
+        assert!(!batches.is_empty());
+        for b in &batches {
+            client.process_input_batch(b, now());
+        }
+        assert!(maybe_authenticate(&mut client));
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let pool = BufferPool::new(2048, 4);
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_buffer_pool(pool.clone());
+        server.set_buffer_pool(pool.clone());
+
+        let initial = client.process(None, now()).dgram().unwrap();
+        assert!(initial.capacity() >= pool.buffer_size());
+        // The buffers that frames were encoded in go back to the pool.
+        assert_eq!(pool.available(), 2);
+        // The server decrypts in a buffer from the pool, and gives that and
+        // the datagram back once it has processed the datagram, then takes
+        // three buffers to build a datagram and gives two back.
+        let out = server.process(Some(initial), now()).dgram().unwrap();
+        assert_eq!(pool.available(), 2);
+        client.process_input(out, now());
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[test]
+    fn recovery_info() {
+        let mut client = default_client();
+        assert_eq!(client.recovery_info().next_timer, None);
+        client.process(None, now());
+        let info = client.recovery_info();
+        assert_eq!(info.pto_count, 0);
+        assert_eq!(info.in_flight, [1, 0, 0]);
+        let (kind, deadline) = info.next_timer.unwrap();
+        assert_eq!(kind, TimerKind::Pto);
+        assert!(deadline > now());
+
+        // Nothing arrives, so the PTO fires.
+        client.process(None, deadline);
+        assert_eq!(client.recovery_info().pto_count, 1);
+
+        client.close(deadline, 0, "");
+        let (kind, _) = client.recovery_info().next_timer.unwrap();
+        assert_eq!(kind, TimerKind::Closing);
+    }
+
+    #[test]
+    fn output_ready_at() {
+        assert_eq!(Output::None.ready_at(now()), None);
+        assert_eq!(
+            Output::Callback(Duration::from_millis(10)).ready_at(now()),
+            Some(now() + Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn test_no_alpn() {
+        fixture_init();
+        let mut client = Connection::new_client(
+            "example.com",
+            &["bad-alpn"],
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(9))),
+            loopback(),
+            loopback(),
+        )
+        .unwrap();
+        let mut server = default_server();
+
+        handshake(&mut client, &mut server);
+        // TODO (mt): errors are immediate, which means that we never send CONNECTION_CLOSE
+        // and the client never sees the server's rejection of its handshake.
+        //assert_error(&client, ConnectionError::Transport(Error::CryptoAlert(120)));
+        assert_error(&server, ConnectionError::Transport(Error::CryptoAlert(120)));
+    }
+
+    #[test]
+    fn test_dup_server_flight1() {
+        qdebug!("---- client: generate CH");
+        let mut client = default_client();
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+        assert_eq!(out.as_dgram_ref().unwrap().len(), 1200);
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
+
+        qdebug!("---- server: CH -> SH, EE, CERT, CV, FIN");
+        let mut server = default_server();
+        let out_to_rep = server.process(out.dgram(), now());
+        assert!(out_to_rep.as_dgram_ref().is_some());
+        qdebug!("Output={:0x?}", out_to_rep.as_dgram_ref());
+
+        qdebug!("---- client: cert verification");
+        let out = client.process(Some(out_to_rep.as_dgram_ref().unwrap().clone()), now());
+        assert!(out.as_dgram_ref().is_some());
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
+
+        let out = server.process(out.dgram(), now());
+        assert!(out.as_dgram_ref().is_none());
+
+        assert!(maybe_authenticate(&mut client));
+
+        qdebug!("---- client: SH..FIN -> FIN");
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
+
+        assert_eq!(2, client.stats().packets_rx);
+        assert_eq!(0, client.stats().dups_rx);
+
+        qdebug!("---- Dup, ignored");
+        let out = client.process(out_to_rep.dgram().clone(), now());
+        assert!(out.as_dgram_ref().is_none());
+        qdebug!("Output={:0x?}", out.as_dgram_ref());
+
+        // Four packets total received, two of them are dups
+        assert_eq!(4, client.stats().packets_rx);
+        assert_eq!(2, client.stats().dups_rx);
+    }
+
+    #[test]
+    fn stats_snapshots() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let before = client.stats();
+        connect(&mut client, &mut server);
+        let after = client.stats();
+        assert!(after.seq > before.seq);
+
+        let delta = StatsDelta::between(&before, &after).unwrap();
+        assert_eq!(delta.packets_rx, after.packets_rx);
+        assert!(delta.packets_tx > 0);
+        assert!(StatsDelta::between(&after, &before).is_none());
+    }
+
+    fn exchange_ticket(client: &mut Connection, server: &mut Connection) -> Vec<u8> {
+        server.send_ticket(now(), &[]).expect("can send ticket");
+        let out = server.process_output(now());
+        assert!(out.as_dgram_ref().is_some());
+        client.process_input(out.dgram().unwrap(), now());
+        assert_eq!(*client.state(), State::Connected);
+        client.resumption_token().expect("should have token")
+    }
+
+    #[test]
+    fn resume() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().resumed());
+        assert!(server.crypto.tls.info().unwrap().resumed());
+    }
+
+    #[test]
+    fn resume_from_store() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        // Pretend that the server sent NEW_TOKEN.
+        client.token = Some(vec![1, 2, 3]);
+
+        let mut store = LruResumptionStore::default();
+        store.insert("example.com", exchange_ticket(&mut client, &mut server));
+
+        let mut client = default_client();
+        let token = store.take("example.com").expect("token is stored");
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        assert_eq!(client.token, Some(vec![1, 2, 3]));
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().resumed());
+        assert!(store.take("example.com").is_none());
+    }
+
+    #[test]
+    fn zero_rtt_negotiate() {
+        // Note that the two servers in this test will get different anti-replay filters.
+        // That's OK because we aren't testing anti-replay.
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
         let mut client = default_client();
         client
             .set_resumption_token(now(), &token[..])
@@ -2379,6 +4722,12 @@ mod tests {
             })
             .expect("should have received a new stream event");
         assert_eq!(client_stream_id, server_stream_id);
+
+        assert!(client.zero_rtt_enabled());
+        let _ = client.process(server_hs.dgram(), now());
+        let accepted = |e| e == ConnectionEvent::ZeroRttAccepted;
+        assert!(client.events().any(accepted));
+        assert!(!client.zero_rtt_enabled());
     }
 
     #[test]
@@ -2476,6 +4825,61 @@ mod tests {
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
     }
 
+    #[test]
+    fn zero_rtt_send_reject_resend() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
+        let mut client = default_client();
+        client.set_0rtt_resend(true);
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        // A fresh anti-replay context causes the server to reject 0-RTT.
+        let ar = AntiReplay::new(now(), test_fixture::ANTI_REPLAY_WINDOW, 1, 3)
+            .expect("setup anti-replay");
+        let mut server = Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &ar,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+        )
+        .unwrap();
+
+        let client_hs = client.process(None, now());
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let msg = &[1, 2, 3];
+        client.stream_send(stream_id, msg).unwrap();
+        let client_0rtt = client.process(None, now());
+        assert!(client_0rtt.as_dgram_ref().is_some());
+
+        let server_hs = server.process(client_hs.dgram(), now());
+        let _ = server.process(client_0rtt.dgram(), now());
+
+        // The client learns that 0-RTT was rejected, but keeps the stream.
+        let client_out: Vec<_> = client.process_iter(server_hs.dgram(), now()).collect();
+        let resent = |e| e == ConnectionEvent::ZeroRttResent;
+        assert!(client.events().any(resent));
+        assert!(client.stream_send(stream_id, &[]).is_ok());
+
+        // The data arrives at the server in 1-RTT.
+        for d in client_out {
+            server.process_input(d, now());
+        }
+        let new_stream = |e| {
+            e == ConnectionEvent::NewStream {
+                stream_id,
+                stream_type: StreamType::UniDi,
+            }
+        };
+        assert!(server.events().any(new_stream));
+        let mut buf = [0; 10];
+        let (len, _) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(&buf[..len], msg);
+    }
+
     #[test]
     // Send fin even if a peer closes a reomte bidi send stream before sending any data.
     fn report_fin_when_stream_closed_wo_data() {
@@ -2516,7 +4920,61 @@ mod tests {
         client.process_timer(now + Duration::from_secs(60));
 
         // Not connected after 60 seconds.
-        assert!(matches!(client.state(), State::Closed(_)));
+        assert!(matches!(client.state(), State::Closed { .. }));
+    }
+
+    /// Send stream data from a client that never hears back, following the
+    /// timers that the client asks for, until the connection is closed.
+    fn lose_everything(client: &mut Connection) -> Instant {
+        let mut now = now();
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"hello").unwrap(), 5);
+        loop {
+            match client.process(None, now) {
+                Output::Callback(t) => now += t,
+                Output::Datagram(_) => {}
+                Output::None => return now,
+            }
+        }
+    }
+
+    #[test]
+    fn too_lossy() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            client.set_retransmission_limits(Some(0), None),
+            Err(Error::InvalidInput)
+        );
+        client.set_retransmission_limits(Some(3), None).unwrap();
+
+        let closed = lose_everything(&mut client);
+        assert!(closed < now() + Duration::from_secs(60));
+        assert_eq!(client.stats().pto, 3);
+        assert!(matches!(
+            client.state(),
+            State::Closed {
+                error: ConnectionError::Transport(Error::TooLossy),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn no_retransmission_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        lose_everything(&mut client);
+        assert!(matches!(
+            client.state(),
+            State::Closed {
+                error: ConnectionError::Transport(Error::IdleTimeout),
+                ..
+            }
+        ));
     }
 
     #[test]
@@ -2543,7 +5001,7 @@ mod tests {
 
         // Not connected after 70 seconds.
         client.process_timer(now + Duration::from_secs(70));
-        assert!(matches!(client.state(), State::Closed(_)));
+        assert!(matches!(client.state(), State::Closed { .. }));
     }
 
     #[test]
@@ -2572,7 +5030,7 @@ mod tests {
         // Not connected after 70 seconds because timer not reset by second
         // outgoing packet
         client.process_timer(now + Duration::from_secs(70));
-        assert!(matches!(client.state(), State::Closed(_)));
+        assert!(matches!(client.state(), State::Closed { .. }));
     }
 
     #[test]
@@ -2607,22 +5065,629 @@ mod tests {
 
         // Not connected after 80 seconds.
         client.process_timer(now + Duration::from_secs(80));
-        assert!(matches!(client.state(), State::Closed(_)));
+        assert!(matches!(client.state(), State::Closed { .. }));
     }
 
     #[test]
-    fn max_data() {
+    fn close_graceful() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
-        assert_eq!(stream_id, 2);
-        assert_eq!(
-            client.stream_avail_send_space(stream_id).unwrap(),
-            LOCAL_MAX_DATA // 16383, when cfg(test)
-        );
-        assert_eq!(
+        client.close(now(), 0, "bye");
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+        server.process_input(out.dgram().unwrap(), now());
+        match server.state() {
+            State::Draining { error, reason, .. } => {
+                assert_eq!(*error, ConnectionError::Application(0));
+                assert_eq!(reason, "bye");
+            }
+            _ => panic!("bad state {:?}", server.state()),
+        }
+        // A draining connection sends nothing.
+        let out = server.process(None, now());
+        assert!(out.as_dgram_ref().is_none());
+
+        let later = now() + Duration::from_secs(10);
+        let _ = server.process(None, later);
+        assert_eq!(
+            *server.state(),
+            State::Closed {
+                error: ConnectionError::Application(0),
+                reason: Some(String::from("bye")),
+            }
+        );
+        let _ = client.process(None, later);
+        assert_eq!(
+            *client.state(),
+            State::Closed {
+                error: ConnectionError::Application(0),
+                reason: None,
+            }
+        );
+    }
+
+    #[test]
+    fn close_twice() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        client.close(now(), 1, "first");
+        client.close(now(), 2, "second");
+        assert_error(&client, ConnectionError::Application(1));
+    }
+
+    #[test]
+    fn close_during_handshake() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        let out = client.process(None, now());
+        let _ = server.process(out.dgram(), now()); // Drop the server handshake.
+        server.close(now(), 7, "go away");
+        let out = server.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+
+        // The application close is sent in Initial packets.
+        client.process_input(out.dgram().unwrap(), now());
+        match client.state() {
+            State::Draining { error, reason, .. } => {
+                assert_eq!(*error, ConnectionError::Application(7));
+                assert_eq!(reason, "go away");
+            }
+            _ => panic!("bad state {:?}", client.state()),
+        }
+    }
+
+    #[test]
+    fn max_send_rate() {
+        const RATE: u64 = 10_000;
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        assert_eq!(client.set_max_send_rate(Some(0)), Err(Error::InvalidInput));
+        client.set_max_send_rate(Some(RATE)).unwrap();
+        assert_eq!(client.max_send_rate(), Some(RATE));
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[0; 10_000]).unwrap(), 10_000);
+
+        // Run for half a second, delivering everything to the server.
+        let end = now() + Duration::from_millis(500);
+        let mut t = now();
+        let mut sent = 0;
+        while t < end {
+            match client.process(None, t) {
+                Output::Datagram(d) => {
+                    sent += d.len();
+                    server.process_input(d, t);
+                }
+                Output::Callback(delay) => t += delay,
+                Output::None => panic!("connection closed"),
+            }
+            if let Some(ack) = server.process(None, t).dgram() {
+                client.process_input(ack, t);
+            }
+        }
+        // That is 5,000 bytes, plus a burst of one packet, plus the packet
+        // that goes over the limit.
+        assert!(sent > 5_000);
+        assert!(sent <= 5_000 + 2 * client.pmtu);
+
+        // Without a limit, everything else goes at once.
+        client.set_max_send_rate(None).unwrap();
+        let mut rest = 0;
+        while let Output::Datagram(d) = client.process_output(t) {
+            rest += d.len();
+        }
+        assert!(sent + rest > 10_000);
+    }
+
+    #[test]
+    fn max_send_rate_holds_acks() {
+        const RATE: u64 = 10_000;
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.set_max_send_rate(Some(RATE)).unwrap();
+
+        // Use up the burst.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[0; 2_000]).unwrap(), 2_000);
+        while let Output::Datagram(d) = client.process_output(now()) {
+            server.process_input(d, now());
+        }
+
+        // The client has to acknowledge this, but the limit holds it back.
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(server.stream_send(stream_id, b"hello").unwrap(), 5);
+        let data = server.process_output(now()).dgram().unwrap();
+        client.process_input(data, now());
+        let delay = match client.process_output(now()) {
+            Output::Callback(delay) => delay,
+            o => panic!("unexpected output {:?}", o),
+        };
+        assert!(delay > Duration::from_millis(0));
+        assert!(client.process_output(now() + delay).dgram().is_some());
+    }
+
+    #[test]
+    fn pacing_offload() {
+        const RATE: u64 = 10_000;
+        let horizon = Duration::from_millis(500);
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.set_max_send_rate(Some(RATE)).unwrap();
+        client.set_pacing_offload(Some(horizon));
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[0; 10_000]).unwrap(), 10_000);
+
+        // Everything that can be sent in the next half second comes out
+        // straight away, with release times that are spread out.
+        let mut sent = 0;
+        let mut last = None;
+        while let Output::Datagram(d) = client.process_output(now()) {
+            sent += d.len();
+            let release = d.release_time();
+            assert!(release >= last);
+            assert!(release.map_or(true, |t| t <= now() + horizon));
+            last = release;
+        }
+        assert!(last.unwrap() > now());
+        assert!(sent > 5_000);
+        assert!(sent <= 5_000 + 2 * client.pmtu);
+
+        // Without offload, nothing more is sent until the limit allows it.
+        client.set_pacing_offload(None);
+        assert!(client.process_output(now()).dgram().is_none());
+    }
+
+    #[test]
+    fn dscp_and_flow_label() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(server.set_dscp(46), Err(Error::ConnectionState));
+        assert_eq!(client.set_dscp(64), Err(Error::InvalidInput));
+        assert_eq!(
+            client.set_flow_label(Some(0x10_0000)),
+            Err(Error::InvalidInput)
+        );
+        client.set_dscp(46).unwrap();
+        client.set_flow_label(Some(0x1234)).unwrap();
+
+        let d = client.process(None, now()).dgram().unwrap();
+        assert_eq!(d.dscp(), 46);
+        assert_eq!(d.flow_label(), Some(0x1234));
+        // The server doesn't copy the marking.
+        let d = server.process(Some(d), now()).dgram().unwrap();
+        assert_eq!(d.dscp(), 0);
+        assert_eq!(d.flow_label(), None);
+    }
+
+    #[test]
+    fn receive_metadata() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let d = client.process(None, now()).dgram().unwrap();
+        let d = d.with_ecn(Ecn::Ect0).with_ttl(60).with_interface(4);
+        let d = server.process(Some(d), now()).dgram().unwrap();
+        // The server answers from the interface that the client reached.
+        assert_eq!(d.interface(), Some(4));
+        assert_eq!(server.stats().ect0_rx, 1);
+        assert_eq!(server.stats().ce_rx, 0);
+
+        // A receive time in the future is treated as now.
+        let d = d.with_received_time(now() + Duration::from_secs(1));
+        client.process_input(d, now());
+        assert!(client.stats().packets_rx > 0);
+        assert_eq!(client.stats().ect0_rx, 0);
+    }
+
+    #[test]
+    fn discard_keys() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        // Both endpoints have moved past Initial.
+        assert!(client.crypto.is_discarded(0));
+        assert!(server.crypto.is_discarded(0));
+        // The server confirms the handshake when it completes, and discards
+        // Handshake keys after acknowledging the client's Finished.
+        assert!(server.handshake_confirmed());
+        assert!(server.crypto.is_discarded(2));
+        assert!(!client.handshake_confirmed());
+        assert!(!client.crypto.is_discarded(2));
+
+        // An acknowledgment of a 1-RTT packet confirms the handshake.
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[6; 10]).unwrap();
+        let d = client.process(None, now()).dgram();
+        assert!(d.is_some());
+        server.process_input(d.unwrap(), now());
+        let d = server.process(None, now() + ACK_DELAY).dgram();
+        assert!(d.is_some());
+        client.process_input(d.unwrap(), now() + ACK_DELAY);
+        assert!(client.handshake_confirmed());
+        assert!(client.crypto.is_discarded(2));
+        assert!(client
+            .events()
+            .any(|e| e == ConnectionEvent::HandshakeConfirmed));
+    }
+
+    #[test]
+    fn lost_client_finished() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+
+        // With 0-RTT accepted, the server can send 1-RTT packets before the
+        // handshake completes.
+        let client_hs = client.process(None, now());
+        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
+        let client_0rtt = client.process(None, now());
+        let server_hs = server.process(client_hs.dgram(), now());
+        assert!(server_hs.as_dgram_ref().is_some());
+        server.process_input(client_0rtt.dgram().unwrap(), now());
+        let server_stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        server.stream_send(server_stream_id, &[4, 5, 6]).unwrap();
+        let server_1rtt = server.process(None, now()).dgram();
+        assert!(server_1rtt.is_some());
+
+        // The client's Finished is lost.
+        client.process_input(server_hs.dgram().unwrap(), now());
+        let lost = client.process(None, now()).dgram();
+        assert!(lost.is_some());
+        assert_eq!(*client.state(), State::Connected);
+
+        // An acknowledgment of a 1-RTT packet doesn't confirm the handshake
+        // for a server that hasn't read the client's Finished.
+        client.process_input(server_1rtt.unwrap(), now());
+        let ack = client.process(None, now() + ACK_DELAY).dgram();
+        assert!(ack.is_some());
+        server.process_input(ack.unwrap(), now() + ACK_DELAY);
+        assert_eq!(*server.state(), State::Handshaking);
+        assert!(!server.handshake_confirmed());
+        assert!(!server.crypto.is_discarded(2));
+
+        // The server can still read the client's Finished when it is sent
+        // again.
+        let mut now = now() + ACK_DELAY;
+        let resent = loop {
+            match client.process(None, now) {
+                Output::Callback(t) => now += t,
+                Output::Datagram(d) => break d,
+                Output::None => panic!("the client should send Finished again"),
+            }
+        };
+        server.process_input(resent, now);
+        assert_eq!(*server.state(), State::Connected);
+        assert!(server.handshake_confirmed());
+        let ack = server.process(None, now).dgram();
+        assert!(ack.is_some());
+        assert!(server.crypto.is_discarded(2));
+    }
+
+    #[cfg(feature = "crypto-dump")]
+    #[test]
+    fn crypto_stream_dump() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Session tickets might still be in flight, so skip 1-RTT.
+        for &epoch in &[0, 2] {
+            assert_eq!(
+                client.crypto_stream_sent(epoch),
+                server.crypto_stream_received(epoch)
+            );
+            assert_eq!(
+                server.crypto_stream_sent(epoch),
+                client.crypto_stream_received(epoch)
+            );
+        }
+        // The first byte is the TLS handshake message type.
+        assert_eq!(client.crypto_stream_sent(0)[0], 1); // ClientHello
+        assert_eq!(server.crypto_stream_sent(0)[0], 2); // ServerHello
+        assert_eq!(server.crypto_stream_sent(2)[0], 8); // EncryptedExtensions
+        assert_eq!(client.crypto_stream_sent(2)[0], 20); // Finished
+        assert!(client.crypto_stream_sent(NUM_EPOCHS).is_empty());
+    }
+
+    #[cfg(feature = "keepalive-offload")]
+    #[test]
+    fn keepalive_offload() {
+        use crate::crypto::{CryptoDxDirection, CryptoDxState};
+        use crate::packet::encode_packet;
+        use neqo_crypto::{hkdf, TLS_VERSION_1_3};
+
+        let mut client = default_client();
+        let mut server = default_server();
+        // The server has a shorter idle timeout than the client.
+        server
+            .tps
+            .borrow_mut()
+            .local
+            .set_integer(tp_const::IDLE_TIMEOUT, 10_000);
+        assert_eq!(
+            client.keepalive_offload(10).unwrap_err(),
+            Error::ConnectionState
+        );
+        connect(&mut client, &mut server);
+
+        let ka = client.keepalive_offload(10).unwrap();
+        assert_eq!(ka.interval, Duration::from_secs(5));
+        assert!(!format!("{:?}", ka).contains("secret"));
+        let dcid = ConnectionId::from(&ka.header[1..]);
+        assert_eq!(dcid, client.paths.as_ref().unwrap().remote_cid);
+        assert_eq!(
+            client.loss_recovery.next_pn(PNSpace::ApplicationData),
+            ka.first_pn + 10
+        );
+
+        // Build a keepalive in the same way that an offload would.
+        let secret = hkdf::import_key(TLS_VERSION_1_3, ka.cipher, &ka.secret).unwrap();
+        let tx = CryptoDxState::new(CryptoDxDirection::Write, 3, &secret, ka.cipher);
+        let pn = ka.first_pn + 3;
+        let hdr = PacketHdr::new(0, PacketType::Short, None, dcid, None, pn, 3);
+        let mut body = Encoder::default();
+        Frame::Ping.marshal(&mut body);
+        let ping = encode_packet(&tx, &hdr, &body);
+
+        server.process_input(Datagram::new(ka.local, ka.remote, ping), now());
+        assert!(server.acks[PNSpace::ApplicationData].is_duplicate(pn));
+        assert_eq!(*server.state(), State::Connected);
+    }
+
+    /// Split the first packet from a datagram.
+    fn split_datagram(c: &Connection, d: Datagram) -> (Datagram, Datagram) {
+        let hdr = decode_packet_hdr(&*c.cid_decoder, &d[..]).unwrap();
+        let len = hdr.hdr_len + hdr.body_len();
+        (
+            Datagram::new(d.source(), d.destination(), &d[..len]),
+            Datagram::new(d.source(), d.destination(), &d[len..]),
+        )
+    }
+
+    #[test]
+    fn reordered_handshake() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let (initial, handshake) = split_datagram(&client, out.dgram().unwrap());
+
+        // The Handshake packet can't be decrypted until the Initial arrives.
+        client.process_input(handshake, now());
+        assert_eq!(client.saved_packets.len(), 1);
+        assert_eq!(*client.state(), State::WaitInitial);
+
+        client.process_input(initial, now());
+        assert!(client.saved_packets.is_empty());
+        // The saved packet is only counted once.
+        assert_eq!(client.stats().packets_rx, 2);
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
+    }
+
+    #[test]
+    fn saved_packet_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let (_, handshake) = split_datagram(&client, out.dgram().unwrap());
+
+        for _ in 0..=MAX_SAVED_PACKETS {
+            client.process_input(handshake.clone(), now());
+        }
+        assert_eq!(client.saved_packets.len(), MAX_SAVED_PACKETS);
+    }
+
+    #[test]
+    fn work_budget() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+
+        // The server sends Initial and Handshake packets together.
+        // With a budget of one packet, the Handshake packet waits.
+        let mut out = client.process_with_budget(out.dgram(), now(), 1);
+        assert_eq!(client.saved_packets.len(), 1);
+        while let Output::Datagram(d) = out {
+            let _ = server.process(Some(d), now());
+            out = client.process_output(now());
+        }
+        assert_eq!(out, Output::Callback(Duration::new(0, 0)));
+        assert_eq!(*client.state(), State::Handshaking);
+
+        // It is processed on the next call.
+        let _ = client.process_with_budget(None, now(), 1);
+        assert!(client.saved_packets.is_empty());
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
+    }
+
+    #[test]
+    fn reordered_1rtt() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let out = client.process(out.dgram(), now());
+        let _ = server.process(out.dgram(), now());
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, b"hello").unwrap(), 5);
+        let mut d = client.process(None, now()).dgram().unwrap();
+        let mut packets = Vec::new();
+        while !d.is_empty() {
+            let (p, rest) = split_datagram(&server, d);
+            packets.push(p);
+            d = rest;
+        }
+        // The short header packet is last; deliver it first.
+        let short = packets.pop().unwrap();
+        assert!(!packets.is_empty());
+        server.process_input(short, now());
+        assert_eq!(server.saved_packets.len(), 1);
+        assert_eq!(*server.state(), State::Handshaking);
+
+        // Once the handshake completes, the saved packet is processed.
+        for p in packets {
+            server.process_input(p, now());
+        }
+        assert!(server.saved_packets.is_empty());
+        assert_eq!(*server.state(), State::Connected);
+        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable { .. });
+        assert!(server.events().any(stream_readable));
+    }
+
+    /// Make a Version Negotiation packet in response to `d`, which is a
+    /// client Initial.
+    fn forge_vn(d: &Datagram, versions: Vec<u32>) -> Datagram {
+        let hdr = decode_packet_hdr(&FixedLengthConnectionIdDecoder::new(0), &d[..]).unwrap();
+        let vn = encode_packet_vn(&PacketHdr::new(
+            0,
+            PacketType::VN(versions),
+            Some(0),
+            hdr.scid.unwrap(),
+            Some(hdr.dcid),
+            0,
+            0,
+        ));
+        Datagram::new(d.destination(), d.source(), vn)
+    }
+
+    #[test]
+    fn vn_listing_version_in_use() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let initial = client.process(None, now()).dgram().unwrap();
+
+        // A VN that includes the version the client chose is dropped.
+        let vn = forge_vn(&initial, vec![0x1a2a_3a4a, QUIC_VERSION]);
+        client.process_input(vn, now());
+        assert_eq!(*client.state(), State::WaitInitial);
+
+        let out = server.process(Some(initial), now());
+        let out = client.process(out.dgram(), now());
+        let _ = server.process(out.dgram(), now());
+        assert!(maybe_authenticate(&mut client));
+        assert_eq!(*client.state(), State::Connected);
+    }
+
+    #[test]
+    fn coalesced_oversized_length() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let initial = client.process(None, now()).dgram().unwrap();
+
+        // Follow the Initial with a Handshake packet that claims to be longer
+        // than the rest of the datagram.
+        let hdr = decode_packet_hdr(&FixedLengthConnectionIdDecoder::new(0), &initial[..]).unwrap();
+        let mut dgram = initial[..hdr.hdr_len + hdr.body_len()].to_vec();
+        let mut enc = Encoder::default();
+        enc.encode_byte(0xe0);
+        enc.encode_uint(4, QUIC_VERSION);
+        enc.encode_vec(1, &hdr.dcid.0);
+        enc.encode_vec(1, &hdr.scid.unwrap().0);
+        enc.encode_varint(1000_u64);
+        enc.encode(&[0; 20]);
+        dgram.extend_from_slice(&enc[..]);
+        let dgram = Datagram::new(initial.source(), initial.destination(), dgram);
+
+        // The server drops the bad packet, but still handles the Initial.
+        let out = server.process(Some(dgram), now());
+        assert!(out.as_dgram_ref().is_some());
+        assert_eq!(*server.state(), State::Handshaking);
+    }
+
+    #[test]
+    fn vn_wrong_cid() {
+        let mut client = default_client();
+        let initial = client.process(None, now()).dgram().unwrap();
+
+        // Damage the CIDs in the VN, which now doesn't match this connection.
+        let mut vn = forge_vn(&initial, vec![0x1a2a_3a4a]).to_vec();
+        vn[6] ^= 0xff;
+        let vn = Datagram::new(initial.destination(), initial.source(), vn);
+        client.process_input(vn, now());
+        assert_eq!(*client.state(), State::WaitInitial);
+
+        // A VN that echoes the connection IDs and offers only other versions
+        // causes the connection to fail.
+        let vn = forge_vn(&initial, vec![0x1a2a_3a4a]);
+        client.process_input(vn, now());
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::VersionNegotiation),
+        );
+    }
+
+    #[test]
+    fn version_information_client_mismatch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        // The client says that it chose a different version.
+        client
+            .tps
+            .borrow_mut()
+            .local
+            .set_version_information(0x1a2a_3a4a, &[0x1a2a_3a4a, QUIC_VERSION]);
+        handshake(&mut client, &mut server);
+        assert_error(
+            &server,
+            ConnectionError::Transport(Error::VersionNegotiationError),
+        );
+    }
+
+    #[test]
+    fn version_information_server_mismatch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        // The server doesn't list the version in use, as if it had been
+        // removed from what the server sent.
+        server
+            .tps
+            .borrow_mut()
+            .local
+            .set_version_information(QUIC_VERSION, &[0x1a2a_3a4a]);
+        handshake(&mut client, &mut server);
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::VersionNegotiationError),
+        );
+    }
+
+    #[test]
+    fn max_data() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(stream_id, 2);
+        assert_eq!(
+            client.stream_avail_send_space(stream_id).unwrap(),
+            LOCAL_MAX_DATA // 16383, when cfg(test)
+        );
+        assert_eq!(
             client
                 .stream_send(stream_id, &[b'a'; RX_STREAM_DATA_WINDOW as usize])
                 .unwrap(),
@@ -2647,6 +5712,138 @@ mod tests {
         assert!(matches!(evts[0], ConnectionEvent::SendStreamWritable{..}));
     }
 
+    #[test]
+    fn stream_groups() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let groups = client.subscribe(EventFilter::default().class(crate::EventClass::StreamGroup));
+
+        let group = client.stream_group_create();
+        let s1 = client.stream_create(StreamType::BiDi).unwrap();
+        let s2 = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_group_add(group, s1).unwrap();
+        client.stream_group_add(group, s2).unwrap();
+        assert_eq!(client.stream_group_streams(group).unwrap(), vec![s1, s2]);
+        assert_eq!(
+            client.stream_group_add(group, 400),
+            Err(Error::InvalidStreamId)
+        );
+
+        client.stream_send(s1, b"one").unwrap();
+        client.stream_send(s2, b"two").unwrap();
+        client.stream_group_close_send(group).unwrap();
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
+        let mut buf = [0; 10];
+        for id in &[s1, s2] {
+            assert_eq!(server.stream_recv(*id, &mut buf).unwrap(), (3, true));
+            server.stream_send(*id, b"reply").unwrap();
+        }
+
+        // Replies on either stream are reported for the group.
+        let out = server.process(None, now());
+        client.process(out.dgram(), now());
+        let readable = ConnectionEvent::StreamGroupReadable { group };
+        assert!(groups.events().any(|e| e == readable));
+
+        // Reset the streams in another group.
+        let other = client.stream_group_create();
+        client.stream_group_add(other, s2).unwrap();
+        assert_eq!(client.stream_group_streams(group).unwrap(), vec![s1]);
+        let s3 = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(s3, b"three").unwrap();
+        client.stream_group_add(other, s3).unwrap();
+        client.stream_group_reset_send(other, 77).unwrap();
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
+        let reset = ConnectionEvent::RecvStreamReset {
+            stream_id: s3,
+            app_error: 77,
+        };
+        assert!(server.events().any(|e| e == reset));
+
+        client.stream_group_delete(other).unwrap();
+        assert_eq!(client.stream_group_streams(other), Err(Error::InvalidInput));
+    }
+
+    #[test]
+    fn stream_send_with_fin() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send_with_fin(stream_id, b"hello").unwrap(), 5);
+        let out = client.process(None, now());
+        server.process(out.dgram(), now());
+        let mut buf = [0; 10];
+        assert_eq!(server.stream_recv(stream_id, &mut buf).unwrap(), (5, true));
+    }
+
+    /// A source that has all of its data, but gives it out slowly.
+    #[derive(Debug)]
+    struct SlowSource {
+        data: Vec<u8>,
+        read: usize,
+    }
+
+    impl SendStreamSource for SlowSource {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let len = buf.len().min(700).min(self.data.len() - self.read);
+            buf[..len].copy_from_slice(&self.data[self.read..self.read + len]);
+            self.read += len;
+            len
+        }
+
+        fn done(&self) -> bool {
+            self.read == self.data.len()
+        }
+    }
+
+    #[test]
+    fn stream_send_source() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, b"head").unwrap(), 4);
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let source = SlowSource {
+            data: data.clone(),
+            read: 0,
+        };
+        client
+            .stream_send_source(stream_id, Box::new(source))
+            .unwrap();
+        assert_eq!(
+            client.stream_send(stream_id, b"x"),
+            Err(Error::InvalidInput)
+        );
+
+        while let Output::Datagram(d) = client.process(None, now()) {
+            server.process_input(d, now());
+        }
+        let mut buf = [0; 4000];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (3004, true)
+        );
+        assert_eq!(&buf[..4], b"head");
+        assert_eq!(&buf[4..3004], &data[..]);
+
+        // A closed stream can't take a source.
+        let source = SlowSource {
+            data: Vec::new(),
+            read: 0,
+        };
+        assert_eq!(
+            client.stream_send_source(stream_id, Box::new(source)),
+            Err(Error::FinalSizeError)
+        );
+    }
+
     // Test that we split crypto data if they cannot fit into one packet.
     // To test this we will use a long server certificate.
     #[test]
@@ -2702,4 +5899,217 @@ mod tests {
         assert_eq!(*client.state(), State::Connected);
         assert_eq!(*server.state(), State::Connected);
     }
+
+    fn second_addr() -> SocketAddr {
+        SocketAddr::new(loopback().ip(), 44444)
+    }
+
+    /// Deliver datagrams both ways until neither end has anything to send.
+    /// Datagrams that `lose` picks are dropped.  This returns the datagrams
+    /// that were delivered.
+    fn mp_exchange(
+        client: &mut Connection,
+        server: &mut Connection,
+        now: Instant,
+        lose: impl Fn(&Datagram) -> bool,
+    ) -> Vec<Datagram> {
+        let mut delivered = Vec::new();
+        for _ in 0..100 {
+            let mut sent = false;
+            while let Some(d) = client.process(None, now).dgram() {
+                sent = true;
+                if !lose(&d) {
+                    server.process_input(d.clone(), now);
+                    delivered.push(d);
+                }
+            }
+            while let Some(d) = server.process(None, now).dgram() {
+                sent = true;
+                if !lose(&d) {
+                    client.process_input(d.clone(), now);
+                    delivered.push(d);
+                }
+            }
+            if !sent {
+                return delivered;
+            }
+        }
+        panic!("datagrams kept coming");
+    }
+
+    fn mp_connect() -> (Connection, Connection) {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.enable_multipath().unwrap();
+        server.enable_multipath().unwrap();
+        connect(&mut client, &mut server);
+        assert!(client.multipath());
+        assert!(server.multipath());
+        // Swap connection IDs for extra paths.
+        mp_exchange(&mut client, &mut server, now(), |_| false);
+        (client, server)
+    }
+
+    /// Connect and add a validated path from `second_addr()`.
+    fn mp_connect_two_paths() -> (Connection, Connection, PathId) {
+        let (mut client, mut server) = mp_connect();
+        let id = client.add_path(second_addr(), loopback()).unwrap();
+        assert_ne!(id, 0);
+        mp_exchange(&mut client, &mut server, now(), |_| false);
+        for paths in &[client.paths(), server.paths()] {
+            assert_eq!(paths.len(), 2);
+            assert!(paths[1].validated);
+        }
+        assert_eq!(client.paths()[1].id, id);
+        assert_eq!(client.paths()[1].local, second_addr());
+        assert_eq!(server.paths()[1].remote, second_addr());
+        (client, server, id)
+    }
+
+    #[test]
+    fn multipath_negotiation() {
+        mp_connect();
+
+        // Both ends have to enable it.
+        let mut client = default_client();
+        let mut server = default_server();
+        client.enable_multipath().unwrap();
+        connect(&mut client, &mut server);
+        assert!(!client.multipath());
+        assert!(!server.multipath());
+        assert_eq!(client.paths().len(), 1);
+
+        // It's too late once the handshake has started.
+        assert_eq!(client.enable_multipath(), Err(Error::ConnectionState));
+        assert_eq!(
+            client.set_path_status(0, PathStatus::Standby),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    #[test]
+    fn add_path_errors() {
+        let (mut client, mut server) = mp_connect();
+        assert_eq!(
+            server.add_path(loopback(), second_addr()),
+            Err(Error::WrongRole)
+        );
+        assert_eq!(
+            client.add_path(loopback(), loopback()),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(client.remove_path(0), Err(Error::InvalidInput));
+        assert_eq!(client.remove_path(7), Err(Error::InvalidInput));
+
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            client.add_path(second_addr(), loopback()),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    #[test]
+    fn multipath_data() {
+        let (mut client, mut server, id) = mp_connect_two_paths();
+
+        // This is more than the congestion window of one path.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[7; 16000]).unwrap(), 16000);
+        client.stream_close_send(stream_id).unwrap();
+        let delivered = mp_exchange(&mut client, &mut server, now(), |_| false);
+        assert!(delivered
+            .iter()
+            .any(|d| d.source() == second_addr() && d.len() > 1000));
+
+        let mut buf = vec![0; 20000];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (16000, true)
+        );
+        let info = client.path_recovery_info(id).unwrap();
+        assert!(info.congestion_window > 0);
+    }
+
+    #[test]
+    fn multipath_remove() {
+        let (mut client, mut server, id) = mp_connect_two_paths();
+        client.remove_path(id).unwrap();
+        assert_eq!(client.paths().len(), 1);
+        mp_exchange(&mut client, &mut server, now(), |_| false);
+        assert_eq!(server.paths().len(), 1);
+        assert!(client.path_recovery_info(id).is_none());
+    }
+
+    #[test]
+    fn multipath_status() {
+        let (mut client, mut server, _) = mp_connect_two_paths();
+        client.set_path_status(0, PathStatus::Standby).unwrap();
+        mp_exchange(&mut client, &mut server, now(), |_| false);
+        assert_eq!(server.paths()[0].peer_status, PathStatus::Standby);
+
+        // Now the server only sends data on the other path.
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        server.stream_send(stream_id, &[7; 5000]).unwrap();
+        let delivered = mp_exchange(&mut client, &mut server, now(), |_| false);
+        let from_server = |d: &&Datagram| d.source() == loopback();
+        assert!(delivered
+            .iter()
+            .filter(from_server)
+            .any(|d| d.destination() == second_addr() && d.len() > 1000));
+        assert!(delivered
+            .iter()
+            .filter(from_server)
+            .all(|d| d.destination() == second_addr() || d.len() < 200));
+    }
+
+    #[test]
+    fn multipath_dead_path() {
+        let (mut client, mut server, _) = mp_connect_two_paths();
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[7; 16000]).unwrap(), 16000);
+        client.stream_close_send(stream_id).unwrap();
+
+        // Nothing gets through on the second path, so the client abandons it
+        // after a few PTOs and sends everything on the first path.
+        let lose = |d: &Datagram| d.source() == second_addr() || d.destination() == second_addr();
+        let mut t = now();
+        for _ in 0..20 {
+            mp_exchange(&mut client, &mut server, t, lose);
+            if client.paths().len() == 1 {
+                break;
+            }
+            t += match client.process(None, t) {
+                Output::Callback(delay) => delay,
+                _ => panic!("expected a timer"),
+            };
+        }
+        assert_eq!(client.paths().len(), 1);
+        assert!(client.stats().pto >= u64::from(MAX_PATH_PTOS));
+        mp_exchange(&mut client, &mut server, t, lose);
+        assert_eq!(server.paths().len(), 1);
+
+        let mut buf = vec![0; 20000];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (16000, true)
+        );
+    }
+
+    #[test]
+    fn multipath_frames_without_negotiation() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let frame = Frame::PathStatus {
+            dcid_sequence_number: 0,
+            status_sequence_number: 0,
+            status: 1,
+        };
+        assert_eq!(
+            server.input_frame(3, 0, frame, now()),
+            Err(Error::ProtocolViolation)
+        );
+    }
 }
@@ -9,30 +9,249 @@ use std::cmp::min;
 use std::convert::TryInto;
 use std::rc::Rc;
 
-use neqo_common::{hex, qdebug, qinfo, qtrace};
+use neqo_common::{hex, qdebug, qinfo, qtrace, Decoder};
 use neqo_crypto::aead::Aead;
-use neqo_crypto::hp::{extract_hp, HpKey};
+use neqo_crypto::hp::{extract_hp, HpKey, MAX_MASK_LEN};
 use neqo_crypto::{
     hkdf, Agent, AntiReplay, Cipher, Epoch, SymKey, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384,
     TLS_VERSION_1_3,
 };
+#[cfg(feature = "pq-hybrid")]
+use neqo_crypto::{
+    Group, TLS_GRP_EC_SECP256R1, TLS_GRP_EC_SECP384R1, TLS_GRP_EC_X25519,
+    TLS_GRP_KEM_MLKEM768X25519, TLS_GRP_KEM_XYBER768D00,
+};
 
 use crate::connection::Role;
 use crate::frame::{crypto_frame_hdr_len, Frame, TxMode};
-use crate::packet::{CryptoCtx, PacketNumber};
+use crate::packet::{CryptoCtx, PacketNumber, Version, MASK_LEN};
 use crate::recovery::RecoveryToken;
 use crate::recv_stream::RxStreamOrderer;
 use crate::send_stream::TxBuffer;
 use crate::tparams::{TpZeroRttChecker, TransportParametersHandler};
 use crate::{Error, Res};
 
-const MAX_AUTH_TAG: usize = 32;
+/// The groups that are used with `Connection::enable_pq_hybrid()`, in order of
+/// preference.  NSS ignores the hybrid groups if it doesn't support them.
+/// The hybrid key shares are more than 1kB, so a ClientHello that includes one
+/// needs more than one Initial packet.
+#[cfg(feature = "pq-hybrid")]
+pub(crate) const PQ_HYBRID_GROUPS: &[Group] = &[
+    TLS_GRP_KEM_MLKEM768X25519,
+    TLS_GRP_KEM_XYBER768D00,
+    TLS_GRP_EC_X25519,
+    TLS_GRP_EC_SECP256R1,
+    TLS_GRP_EC_SECP384R1,
+];
+
+const CLIENT_INITIAL_LABEL: &str = "client in";
+const SERVER_INITIAL_LABEL: &str = "server in";
+
+/// How Initial packets are protected in some versions of QUIC.
+struct InitialProtection {
+    /// The first and last versions that this applies to.
+    versions: (Version, Version),
+    /// The salt for the Initial secret.
+    salt: &'static [u8],
+    /// The prefix of the labels for packet protection keys.
+    prefix: &'static str,
+}
+
+/// Initial packet protection for the versions of QUIC that are known, which
+/// are more than the versions that can be used for a connection.
+const INITIAL_PROTECTION: &[InitialProtection] = &[
+    // QUIC version 1, RFC 9001.
+    InitialProtection {
+        versions: (0x0000_0001, 0x0000_0001),
+        salt: &[
+            0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+            0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+        ],
+        prefix: "quic ",
+    },
+    // QUIC version 2, RFC 9369.
+    InitialProtection {
+        versions: (0x6b33_43cf, 0x6b33_43cf),
+        salt: &[
+            0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+            0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+        ],
+        prefix: "quicv2 ",
+    },
+    // Drafts 29 to 32.
+    InitialProtection {
+        versions: (0xff00_001d, 0xff00_0020),
+        salt: &[
+            0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61,
+            0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
+        ],
+        prefix: "quic ",
+    },
+    // Drafts 23 to 28.
+    InitialProtection {
+        versions: (0xff00_0017, 0xff00_001c),
+        salt: &[
+            0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4,
+            0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
+        ],
+        prefix: "quic ",
+    },
+    // Drafts 21 and 22.
+    InitialProtection {
+        versions: (0xff00_0015, 0xff00_0016),
+        salt: &[
+            0x7f, 0xbc, 0xdb, 0x0e, 0x7c, 0x66, 0xbb, 0xe9, 0x19, 0x3a, 0x96, 0xcd, 0x21, 0x51,
+            0x9e, 0xbd, 0x7a, 0x02, 0x64, 0x4a,
+        ],
+        prefix: "quic ",
+    },
+    // Drafts 17 to 20.
+    InitialProtection {
+        versions: (0xff00_0011, 0xff00_0014),
+        salt: &[
+            0xef, 0x4f, 0xb0, 0xab, 0xb4, 0x74, 0x70, 0xc4, 0x1b, 0xef, 0xcf, 0x80, 0x31, 0x33,
+            0x4f, 0xae, 0x48, 0x5e, 0x09, 0xa0,
+        ],
+        prefix: "quic ",
+    },
+];
+
+/// How Retry packets are formed and protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RetryFormat {
+    /// The Retry carries the original destination connection ID after the
+    /// source connection ID, and has no integrity tag.
+    Odcid,
+    /// The Retry ends with an integrity tag, made with a fixed key and nonce,
+    /// that covers the original destination connection ID.
+    Tag {
+        key: &'static [u8],
+        nonce: &'static [u8],
+    },
+}
+
+/// How Retry packets are protected in some versions of QUIC.
+struct RetryProtection {
+    /// The first and last versions that this applies to.
+    versions: (Version, Version),
+    format: RetryFormat,
+}
+
+/// Retry packet formats for the versions of QUIC that are known.  This only
+/// includes versions from draft 22, which has the long header layout that
+/// this crate decodes.
+const RETRY_PROTECTION: &[RetryProtection] = &[
+    // QUIC version 1, RFC 9001.
+    RetryProtection {
+        versions: (0x0000_0001, 0x0000_0001),
+        format: RetryFormat::Tag {
+            key: &[
+                0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68,
+                0xc8, 0x4e,
+            ],
+            nonce: &[
+                0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+            ],
+        },
+    },
+    // QUIC version 2, RFC 9369.
+    RetryProtection {
+        versions: (0x6b33_43cf, 0x6b33_43cf),
+        format: RetryFormat::Tag {
+            key: &[
+                0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad, 0x7c,
+                0xcc, 0x92,
+            ],
+            nonce: &[
+                0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+            ],
+        },
+    },
+    // Drafts 29 to 32.
+    RetryProtection {
+        versions: (0xff00_001d, 0xff00_0020),
+        format: RetryFormat::Tag {
+            key: &[
+                0xcc, 0xce, 0x18, 0x7e, 0xd0, 0x9a, 0x09, 0xd0, 0x57, 0x28, 0x15, 0x5a, 0x6c, 0xb9,
+                0x6b, 0xe1,
+            ],
+            nonce: &[
+                0xe5, 0x49, 0x30, 0xf9, 0x7f, 0x21, 0x36, 0xf0, 0x53, 0x0a, 0x8c, 0x1c,
+            ],
+        },
+    },
+    // Drafts 25 to 28.
+    RetryProtection {
+        versions: (0xff00_0019, 0xff00_001c),
+        format: RetryFormat::Tag {
+            key: &[
+                0x4d, 0x32, 0xec, 0xdb, 0x2a, 0x21, 0x33, 0xc8, 0x41, 0xe4, 0x04, 0x3d, 0xf2, 0x7d,
+                0x44, 0x30,
+            ],
+            nonce: &[
+                0x4d, 0x16, 0x11, 0xd0, 0x55, 0x13, 0xa5, 0x52, 0xc5, 0x87, 0xd5, 0x75,
+            ],
+        },
+    },
+    // Drafts 22 to 24.
+    RetryProtection {
+        versions: (0xff00_0016, 0xff00_0018),
+        format: RetryFormat::Odcid,
+    },
+];
+
+/// The Retry packet format for QUIC `version`, if it is known.
+pub(crate) fn retry_format(version: Version) -> Option<RetryFormat> {
+    RETRY_PROTECTION
+        .iter()
+        .find(|p| p.versions.0 <= version && version <= p.versions.1)
+        .map(|p| p.format)
+}
+
+/// The keys that protect Initial packets in one direction.
+#[derive(Debug)]
+pub struct InitialKeys {
+    pub aead: Aead,
+    pub hp: HpKey,
+}
+
+/// Make the keys that protect the Initial packets that `sender` sends with
+/// QUIC `version`, where `dcid` is the destination connection ID of the
+/// client's first Initial packet.  This works for any version in the table
+/// of salts, so it can be used to read Initial packets from other versions.
+pub fn initial_keys(version: Version, sender: Role, dcid: &[u8]) -> Res<InitialKeys> {
+    let p = INITIAL_PROTECTION
+        .iter()
+        .find(|p| p.versions.0 <= version && version <= p.versions.1)
+        .ok_or(Error::VersionNegotiation)?;
+    let cipher = TLS_AES_128_GCM_SHA256;
+    let initial_secret = hkdf::extract(
+        TLS_VERSION_1_3,
+        cipher,
+        Some(&hkdf::import_key(TLS_VERSION_1_3, cipher, p.salt)?),
+        &hkdf::import_key(TLS_VERSION_1_3, cipher, dcid)?,
+    )?;
+    let label = match sender {
+        Role::Client => CLIENT_INITIAL_LABEL,
+        Role::Server => SERVER_INITIAL_LABEL,
+    };
+    let secret = hkdf::expand_label(TLS_VERSION_1_3, cipher, &initial_secret, &[], label)?;
+    Ok(InitialKeys {
+        aead: Aead::new(TLS_VERSION_1_3, cipher, &secret, p.prefix)?,
+        hp: extract_hp(TLS_VERSION_1_3, cipher, &secret, format!("{}hp", p.prefix))?,
+    })
+}
 
 #[derive(Debug)]
 pub(crate) struct Crypto {
     pub(crate) tls: Agent,
     pub(crate) streams: [CryptoStream; 4],
     pub(crate) states: [Option<CryptoState>; 4],
+    /// Epochs whose keys were discarded, which can't be used again.
+    discarded: [bool; 4],
+    /// The most ClientHello bytes that each Initial packet carries, if that
+    /// is limited; see `Connection::set_client_hello_split()`.
+    pub(crate) initial_split: Option<usize>,
 }
 
 impl Crypto {
@@ -51,7 +270,7 @@ impl Crypto {
             Agent::Server(s) => s.enable_0rtt(
                 anti_replay.unwrap(),
                 0xffff_ffff,
-                TpZeroRttChecker::wrap(tphandler.clone()),
+                TpZeroRttChecker::wrap(tphandler.clone(), None),
             )?,
         }
         agent.extension_handler(0xffa5, tphandler)?;
@@ -59,30 +278,30 @@ impl Crypto {
             tls: agent,
             streams: Default::default(),
             states: Default::default(),
+            discarded: Default::default(),
+            initial_split: None,
         })
     }
 
     // Create the initial crypto state.
-    pub fn create_initial_state(&mut self, role: Role, dcid: &[u8]) -> CryptoState {
-        const CLIENT_INITIAL_LABEL: &str = "client in";
-        const SERVER_INITIAL_LABEL: &str = "server in";
-
+    pub fn create_initial_state(
+        &mut self,
+        version: Version,
+        role: Role,
+        dcid: &[u8],
+    ) -> CryptoState {
         qinfo!(
             [self]
-            "Creating initial cipher state role={:?} dcid={}",
+            "Creating initial cipher state version={:x} role={:?} dcid={}",
+            version,
             role,
             hex(dcid)
         );
 
-        let (write_label, read_label) = match role {
-            Role::Client => (CLIENT_INITIAL_LABEL, SERVER_INITIAL_LABEL),
-            Role::Server => (SERVER_INITIAL_LABEL, CLIENT_INITIAL_LABEL),
-        };
-
         CryptoState {
             epoch: 0,
-            tx: CryptoDxState::new_initial(CryptoDxDirection::Write, write_label, dcid),
-            rx: CryptoDxState::new_initial(CryptoDxDirection::Read, read_label, dcid),
+            tx: CryptoDxState::new_initial(version, CryptoDxDirection::Write, role, dcid),
+            rx: CryptoDxState::new_initial(version, CryptoDxDirection::Read, role.remote(), dcid),
         }
     }
 
@@ -93,6 +312,9 @@ impl Crypto {
         #[cfg(not(debug_assertions))]
         let label = "";
 
+        if self.discarded[epoch as usize] {
+            return Err(Error::KeysDiscarded);
+        }
         let cs = &mut self.states[epoch as usize];
         if cs.is_none() {
             qtrace!([label] "Build crypto state for epoch {}", epoch);
@@ -136,6 +358,23 @@ impl Crypto {
         Ok(cs.as_mut().unwrap())
     }
 
+    /// Throw away the keys for `epoch` along with anything that is buffered
+    /// for sending or receiving in it.  Packets for the epoch can't be sent
+    /// or read after this.
+    pub fn discard(&mut self, epoch: Epoch) {
+        qdebug!([self] "Discard keys for epoch {}", epoch);
+        self.discarded[epoch as usize] = true;
+        self.states[epoch as usize] = None;
+        let stream = &mut self.streams[epoch as usize];
+        stream.tx = TxBuffer::default();
+        stream.rx = RxStreamOrderer::default();
+        stream.held = Vec::new();
+    }
+
+    pub fn is_discarded(&self, epoch: Epoch) -> bool {
+        self.discarded[epoch as usize]
+    }
+
     pub fn acked(&mut self, token: CryptoRecoveryToken) {
         qinfo!(
             "Acked crypto frame epoch={} offset={} length={}",
@@ -178,7 +417,10 @@ impl Crypto {
         let tx_stream = &mut self.streams[epoch as usize].tx;
         if let Some((offset, data)) = tx_stream.next_bytes(mode) {
             let frame_hdr_len = crypto_frame_hdr_len(offset, remaining);
-            let length = min(data.len(), remaining - frame_hdr_len);
+            let mut length = min(data.len(), remaining - frame_hdr_len);
+            if let (0, Some(split)) = (epoch, self.initial_split) {
+                length = min(length, split);
+            }
             let frame = Frame::Crypto {
                 offset,
                 data: data[..length].to_vec(),
@@ -246,45 +488,40 @@ impl CryptoDxState {
         }
     }
 
-    pub fn new_initial<S: Into<String>>(
+    /// Make the state for Initial packets that `sender` sends.  This is
+    /// `None` if `version` isn't known.
+    pub fn new_initial(
+        version: Version,
         direction: CryptoDxDirection,
-        label: S,
+        sender: Role,
         dcid: &[u8],
     ) -> Option<CryptoDxState> {
-        const INITIAL_SALT: &[u8] = &[
-            0x7f, 0xbc, 0xdb, 0x0e, 0x7c, 0x66, 0xbb, 0xe9, 0x19, 0x3a, 0x96, 0xcd, 0x21, 0x51,
-            0x9e, 0xbd, 0x7a, 0x02, 0x64, 0x4a,
-        ];
-        let cipher = TLS_AES_128_GCM_SHA256;
-        let initial_secret = hkdf::extract(
-            TLS_VERSION_1_3,
-            cipher,
-            Some(
-                hkdf::import_key(TLS_VERSION_1_3, cipher, INITIAL_SALT)
-                    .as_ref()
-                    .unwrap(),
-            ),
-            hkdf::import_key(TLS_VERSION_1_3, cipher, dcid)
-                .as_ref()
-                .unwrap(),
-        )
-        .unwrap();
-
-        let secret =
-            hkdf::expand_label(TLS_VERSION_1_3, cipher, &initial_secret, &[], label).unwrap();
-
-        Some(CryptoDxState::new(direction, 0, &secret, cipher))
+        qinfo!(
+            "Making {:?} initial CryptoDxState, version={:x}",
+            direction,
+            version
+        );
+        let keys = initial_keys(version, sender, dcid).ok()?;
+        Some(CryptoDxState {
+            direction,
+            epoch: 0,
+            aead: keys.aead,
+            hpkey: keys.hp,
+        })
     }
 }
 
 impl CryptoCtx for CryptoDxState {
-    fn compute_mask(&self, sample: &[u8]) -> Res<Vec<u8>> {
-        let mask = self.hpkey.mask(sample)?;
-        qdebug!("HP sample={} mask={}", hex(sample), hex(&mask));
+    fn compute_mask(&self, sample: &[u8]) -> Res<[u8; MASK_LEN]> {
+        let mut output = [0; MAX_MASK_LEN];
+        let full = self.hpkey.mask_into(sample, &mut output)?;
+        qdebug!("HP sample={} mask={}", hex(sample), hex(full));
+        let mut mask = [0; MASK_LEN];
+        mask.copy_from_slice(&full[..MASK_LEN]);
         Ok(mask)
     }
 
-    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<usize> {
         qinfo!(
             [self]
             "aead_decrypt pn={} hdr={} body={}",
@@ -292,27 +529,24 @@ impl CryptoCtx for CryptoDxState {
             hex(hdr),
             hex(body)
         );
-        let mut out = vec![0; body.len()];
-        let res = self.aead.decrypt(pn, hdr, body, &mut out)?;
-        Ok(res.to_vec())
+        Ok(self.aead.decrypt_in_place(pn, &[hdr], body)?.len())
     }
 
-    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<()> {
+        let pt_len = body.len() - self.aead.expansion();
         qdebug!(
             [self]
             "aead_encrypt pn={} hdr={} body={}",
             pn,
             hex(hdr),
-            hex(body)
+            hex(&body[..pt_len])
         );
 
-        let size = body.len() + MAX_AUTH_TAG;
-        let mut out = vec![0; size];
-        let res = self.aead.encrypt(pn, hdr, body, &mut out)?;
+        self.aead.encrypt_in_place(pn, &[hdr], body)?;
 
-        qdebug!([self] "aead_encrypt ct={}", hex(res),);
+        qdebug!([self] "aead_encrypt ct={}", hex(body),);
 
-        Ok(res.to_vec())
+        Ok(())
     }
 }
 
@@ -329,10 +563,35 @@ pub(crate) struct CryptoState {
     pub(crate) rx: Option<CryptoDxState>,
 }
 
+/// Whether `buf` holds only complete handshake messages.
+pub(crate) fn whole_messages(buf: &[u8]) -> bool {
+    let mut dec = Decoder::from(buf);
+    while dec.remaining() > 0 {
+        // Each message has a one byte type and a three byte length.
+        let len = match dec.decode_uint(4) {
+            Some(v) => v & 0xff_ffff,
+            None => return false,
+        };
+        if dec.decode(len as usize).is_none() {
+            return false;
+        }
+    }
+    true
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct CryptoStream {
     pub(crate) tx: TxBuffer,
     pub(crate) rx: RxStreamOrderer,
+    /// Received data that is held until it ends with a complete handshake
+    /// message, so that TLS only sees whole messages.
+    pub(crate) held: Vec<u8>,
+    /// Everything that was sent on this stream.
+    #[cfg(feature = "crypto-dump")]
+    pub(crate) sent: Vec<u8>,
+    /// Everything that was received on this stream, in order.
+    #[cfg(feature = "crypto-dump")]
+    pub(crate) received: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -341,3 +600,62 @@ pub(crate) struct CryptoRecoveryToken {
     offset: u64,
     length: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neqo_common::Encoder;
+    use test_fixture::fixture_init;
+
+    const DCID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    const V2: Version = 0x6b33_43cf;
+
+    fn mask(version: Version, sender: Role, sample: &str) -> Vec<u8> {
+        let keys = initial_keys(version, sender, DCID).expect("version is known");
+        let mut mask = keys.hp.mask(&Encoder::from_hex(sample)).unwrap();
+        mask.truncate(5);
+        mask
+    }
+
+    /// The examples in Appendix A of RFC 9001.
+    #[test]
+    fn initial_keys_v1() {
+        fixture_init();
+        assert_eq!(
+            mask(1, Role::Client, "d1b1c98dd7689fb8ec11d242b123dc9b"),
+            Encoder::from_hex("437b9aec36").to_vec()
+        );
+        assert_eq!(
+            mask(1, Role::Server, "2cd0991cd25b0aac406a5816b6394100"),
+            Encoder::from_hex("2ec0d8356a").to_vec()
+        );
+    }
+
+    /// The examples in Appendix A of RFC 9369.
+    #[test]
+    fn initial_keys_v2() {
+        fixture_init();
+        assert_eq!(
+            mask(V2, Role::Client, "ffe67b6abcdb4298b485dd04de806071"),
+            Encoder::from_hex("94a0c95e80").to_vec()
+        );
+        assert_eq!(
+            mask(V2, Role::Server, "6f05d8a4398c47089698baeea26b91eb"),
+            Encoder::from_hex("4dd92e91ea").to_vec()
+        );
+    }
+
+    #[test]
+    fn initial_keys_by_version() {
+        fixture_init();
+        let sample = "d1b1c98dd7689fb8ec11d242b123dc9b";
+        let v1 = mask(1, Role::Client, sample);
+        assert_ne!(mask(V2, Role::Client, sample), v1);
+        assert_ne!(mask(0xff00_001d, Role::Client, sample), v1);
+        assert_eq!(
+            mask(0xff00_001d, Role::Client, sample),
+            mask(0xff00_0020, Role::Client, sample)
+        );
+        assert!(initial_keys(0x1a2a_3a4a, Role::Client, DCID).is_err());
+    }
+}
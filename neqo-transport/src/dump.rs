@@ -9,8 +9,8 @@
 
 use crate::connection::Connection;
 use crate::frame::decode_frame;
-use crate::packet::PacketHdr;
-use neqo_common::{qdebug, Decoder};
+use crate::packet::{PacketHdr, PacketType};
+use neqo_common::{hex, qdebug, Decoder, Redact};
 
 #[allow(clippy::module_name_repetitions)]
 pub fn dump_packet(conn: &Connection, dir: &str, hdr: &PacketHdr, payload: &[u8]) {
@@ -28,5 +28,10 @@ pub fn dump_packet(conn: &Connection, dir: &str, hdr: &PacketHdr, payload: &[u8]
             s.push_str(&format!("\n  {} {}", dir, &x));
         }
     }
-    qdebug!([conn] "pn={} type={:?}{}", hdr.pn, hdr.tipe, s);
+    // Tokens can identify the client, so they are redacted like addresses.
+    let tipe = match &hdr.tipe {
+        PacketType::Initial(token) => format!("Initial({})", Redact(hex(token))),
+        t => format!("{:?}", t),
+    };
+    qdebug!([conn] "pn={} type={}{}", hdr.pn, tipe, s);
 }
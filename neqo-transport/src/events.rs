@@ -8,14 +8,20 @@
 
 use std::cell::RefCell;
 use std::collections::BTreeSet;
-use std::rc::Rc;
+use std::fmt::{self, Debug};
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use neqo_crypto::Cipher;
 
 use crate::connection::State;
 use crate::frame::StreamType;
+use crate::stream_group::{StreamGroupId, StreamGroups};
 use crate::stream_id::StreamId;
 use crate::AppError;
 
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub enum ConnectionEvent {
     /// Cert authentication needed
     AuthenticationNeeded,
@@ -36,18 +42,197 @@ pub enum ConnectionEvent {
     SendStreamComplete { stream_id: u64 },
     /// Peer increased MAX_STREAMS
     SendStreamCreatable { stream_type: StreamType },
+    /// A stream in the group has space available for writing.  This comes
+    /// with the `SendStreamWritable` event for the stream.
+    StreamGroupWritable { group: StreamGroupId },
+    /// A stream in the group has new bytes available for reading.  This
+    /// comes with the `RecvStreamReadable` event for the stream.
+    StreamGroupReadable { group: StreamGroupId },
     /// Connection state change.
     StateChange(State),
+    /// A server completed the handshake.  This happens once for each
+    /// connection, and describes the connection for access logs.
+    HandshakeComplete(HandshakeRecord),
+    /// The handshake is confirmed, so Handshake keys are no longer needed.
+    /// A client confirms when a 1-RTT packet is acknowledged and a server
+    /// when the handshake completes.
+    HandshakeConfirmed,
     /// The server rejected 0-RTT.
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// The server rejected 0-RTT, but streams were kept.
+    /// Any data written to streams will be sent again.
+    /// This is only generated if `Connection::set_0rtt_resend()` is enabled.
+    ZeroRttResent,
+    /// The server accepted 0-RTT.
+    ZeroRttAccepted,
+}
+
+impl ConnectionEvent {
+    /// The class of event, for use with `EventFilter`.
+    pub fn class(&self) -> EventClass {
+        match self {
+            ConnectionEvent::AuthenticationNeeded => EventClass::Authentication,
+            ConnectionEvent::NewStream { .. } | ConnectionEvent::SendStreamCreatable { .. } => {
+                EventClass::NewStream
+            }
+            ConnectionEvent::SendStreamWritable { .. }
+            | ConnectionEvent::SendStreamStopSending { .. }
+            | ConnectionEvent::SendStreamComplete { .. } => EventClass::SendStream,
+            ConnectionEvent::RecvStreamReadable { .. }
+            | ConnectionEvent::RecvStreamReset { .. } => EventClass::RecvStream,
+            ConnectionEvent::StreamGroupWritable { .. }
+            | ConnectionEvent::StreamGroupReadable { .. } => EventClass::StreamGroup,
+            ConnectionEvent::StateChange(_)
+            | ConnectionEvent::HandshakeComplete(_)
+            | ConnectionEvent::HandshakeConfirmed => EventClass::State,
+            ConnectionEvent::ZeroRttRejected
+            | ConnectionEvent::ZeroRttResent
+            | ConnectionEvent::ZeroRttAccepted => EventClass::ZeroRtt,
+        }
+    }
+
+    /// The stream that the event relates to, if any.
+    pub fn stream_id(&self) -> Option<u64> {
+        match self {
+            ConnectionEvent::NewStream { stream_id, .. }
+            | ConnectionEvent::SendStreamWritable { stream_id }
+            | ConnectionEvent::RecvStreamReadable { stream_id }
+            | ConnectionEvent::RecvStreamReset { stream_id, .. }
+            | ConnectionEvent::SendStreamStopSending { stream_id, .. }
+            | ConnectionEvent::SendStreamComplete { stream_id } => Some(*stream_id),
+            _ => None,
+        }
+    }
+}
+
+/// What a server knows about a connection when the handshake completes.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
+pub struct HandshakeRecord {
+    /// The server name that the client indicated, if any.
+    pub server_name: Option<String>,
+    /// The application protocol that was negotiated.
+    pub alpn: Option<String>,
+    /// The cipher suite that was negotiated.
+    pub cipher: Cipher,
+    /// The address of the client.
+    pub peer: SocketAddr,
+    /// The connection ID that the client chose for its first Initial packet.
+    /// After a Retry, this is the one from before the Retry.
+    pub odcid: Vec<u8>,
+    /// The time from when the first packet from the client arrived until
+    /// the handshake completed.
+    pub duration: Duration,
+    /// Whether the handshake resumed an earlier session.
+    pub resumed: bool,
+    /// Whether 0-RTT was accepted.
+    pub early_data: bool,
+}
+
+/// Broad groupings of `ConnectionEvent`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EventClass {
+    /// `AuthenticationNeeded`.
+    Authentication,
+    /// `NewStream` and `SendStreamCreatable`.
+    NewStream,
+    /// Events for the sending side of a stream.
+    SendStream,
+    /// Events for the receiving side of a stream.
+    RecvStream,
+    /// Events for a group of streams.  Subscribe to these to learn which
+    /// groups need attention, without an event for every stream.
+    StreamGroup,
+    /// `StateChange`, `HandshakeComplete` and `HandshakeConfirmed`.
+    State,
+    /// Events about the fate of 0-RTT.
+    ZeroRtt,
+}
+
+/// Selects the events that an `EventSubscription` receives.
+/// The default selects all events.
+#[derive(Debug, Default, Clone)]
+pub struct EventFilter {
+    stream_id: Option<u64>,
+    classes: Vec<EventClass>,
+}
+
+impl EventFilter {
+    /// Only select events for the identified stream.
+    pub fn stream(mut self, stream_id: u64) -> Self {
+        self.stream_id = Some(stream_id);
+        self
+    }
+
+    /// Select events of the given class.  This can be used more than once
+    /// to select multiple classes.  If it isn't used, all classes are selected.
+    pub fn class(mut self, class: EventClass) -> Self {
+        self.classes.push(class);
+        self
+    }
+
+    fn matches(&self, event: &ConnectionEvent) -> bool {
+        if self.stream_id.is_some() && self.stream_id != event.stream_id() {
+            return false;
+        }
+        self.classes.is_empty() || self.classes.contains(&event.class())
+    }
+}
+
+struct Subscriber {
+    filter: EventFilter,
+    events: BTreeSet<ConnectionEvent>,
+    waker: Option<Rc<dyn Fn()>>,
+}
+
+/// A subscription to connection events.  This receives copies of the events
+/// that match its filter, so it doesn't take events from `Connection::events()`
+/// or from any other subscription.  Like other events, duplicate events are
+/// coalesced until they are collected.  Dropping this ends the subscription.
+pub struct EventSubscription {
+    s: Rc<RefCell<Subscriber>>,
+}
+
+impl EventSubscription {
+    /// Collect the events that were received since this was last called.
+    pub fn events(&self) -> impl Iterator<Item = ConnectionEvent> {
+        let events = std::mem::replace(&mut self.s.borrow_mut().events, BTreeSet::new());
+        events.into_iter()
+    }
+
+    pub fn has_events(&self) -> bool {
+        !self.s.borrow().events.is_empty()
+    }
+
+    /// Set a function that is called when events become available.  This is
+    /// only called when the first event arrives after events are collected.
+    pub fn set_waker(&self, waker: impl Fn() + 'static) {
+        self.s.borrow_mut().waker = Some(Rc::new(waker));
+    }
+}
+
+impl Debug for EventSubscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventSubscription {:?}", self.s.borrow().filter)
+    }
+}
+
+#[derive(Default)]
+struct Subscribers(Vec<Weak<RefCell<Subscriber>>>);
+
+impl Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} subscribers", self.0.len())
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ConnectionEvents {
     events: Rc<RefCell<BTreeSet<ConnectionEvent>>>,
+    subscribers: Rc<RefCell<Subscribers>>,
+    stream_groups: Rc<RefCell<StreamGroups>>,
 }
 
 impl ConnectionEvents {
@@ -66,12 +251,20 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::SendStreamWritable {
             stream_id: stream_id.as_u64(),
         });
+        let group = self.stream_groups.borrow().group_of(stream_id);
+        if let Some(group) = group {
+            self.insert(ConnectionEvent::StreamGroupWritable { group });
+        }
     }
 
     pub fn recv_stream_readable(&self, stream_id: StreamId) {
         self.insert(ConnectionEvent::RecvStreamReadable {
             stream_id: stream_id.as_u64(),
         });
+        let group = self.stream_groups.borrow().group_of(stream_id);
+        if let Some(group) = group {
+            self.insert(ConnectionEvent::StreamGroupReadable { group });
+        }
     }
 
     pub fn recv_stream_reset(&self, stream_id: StreamId, app_error: AppError) {
@@ -102,8 +295,27 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::StateChange(state));
     }
 
+    pub fn handshake_complete(&self, record: HandshakeRecord) {
+        self.insert(ConnectionEvent::HandshakeComplete(record));
+    }
+
+    pub fn handshake_confirmed(&self) {
+        self.insert(ConnectionEvent::HandshakeConfirmed);
+    }
+
+    pub fn client_0rtt_accepted(&self) {
+        self.insert(ConnectionEvent::ZeroRttAccepted);
+    }
+
+    pub fn client_0rtt_resent(&self) {
+        self.insert(ConnectionEvent::ZeroRttResent);
+    }
+
     pub fn client_0rtt_rejected(&self) {
         self.events.borrow_mut().clear();
+        for s in self.subscribers.borrow().0.iter().filter_map(Weak::upgrade) {
+            s.borrow_mut().events.clear();
+        }
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
@@ -111,11 +323,125 @@ impl ConnectionEvents {
         self.events.replace(BTreeSet::new()).into_iter()
     }
 
+    /// Take the first event, leaving the rest where they are.
+    pub fn next_event(&self) -> Option<ConnectionEvent> {
+        let mut events = self.events.borrow_mut();
+        let first = events.iter().next()?.clone();
+        events.take(&first)
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> EventSubscription {
+        let s = Rc::new(RefCell::new(Subscriber {
+            filter,
+            events: BTreeSet::new(),
+            waker: None,
+        }));
+        self.subscribers.borrow_mut().0.push(Rc::downgrade(&s));
+        EventSubscription { s }
+    }
+
     fn insert(&self, event: ConnectionEvent) {
+        let mut wakers = Vec::new();
+        self.subscribers.borrow_mut().0.retain(|s| {
+            if let Some(s) = s.upgrade() {
+                let mut s = s.borrow_mut();
+                if s.filter.matches(&event) {
+                    let was_empty = s.events.is_empty();
+                    if s.events.insert(event.clone()) && was_empty {
+                        wakers.extend(s.waker.clone());
+                    }
+                }
+                true
+            } else {
+                false
+            }
+        });
         self.events.borrow_mut().insert(event);
+        // Wake subscribers last, in case they look at events.
+        for w in wakers {
+            w();
+        }
     }
 
     pub fn has_events(&self) -> bool {
         !self.events.borrow().is_empty()
     }
+
+    /// The groups that streams are in, which decide the group events.
+    pub(crate) fn stream_groups(&self) -> &RefCell<StreamGroups> {
+        &self.stream_groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn subscribe() {
+        let events = ConnectionEvents::default();
+        let all = events.subscribe(EventFilter::default());
+        let stream4 = events.subscribe(EventFilter::default().stream(4));
+        let readable = events.subscribe(EventFilter::default().class(EventClass::RecvStream));
+
+        events.recv_stream_readable(StreamId::from(4));
+        events.recv_stream_readable(StreamId::from(4));
+        events.send_stream_writable(StreamId::from(4));
+        events.recv_stream_readable(StreamId::from(8));
+        events.authentication_needed();
+
+        assert_eq!(all.events().count(), 4);
+        assert_eq!(stream4.events().count(), 2);
+        assert_eq!(readable.events().count(), 2);
+        // Subscriptions don't take events from the connection.
+        assert_eq!(events.events().count(), 4);
+        assert!(!all.has_events());
+    }
+
+    #[test]
+    fn next_event() {
+        let events = ConnectionEvents::default();
+        events.recv_stream_readable(StreamId::from(4));
+        events.authentication_needed();
+        events.recv_stream_readable(StreamId::from(4));
+
+        assert_eq!(
+            events.next_event(),
+            Some(ConnectionEvent::AuthenticationNeeded)
+        );
+        assert_eq!(
+            events.next_event(),
+            Some(ConnectionEvent::RecvStreamReadable { stream_id: 4 })
+        );
+        assert_eq!(events.next_event(), None);
+    }
+
+    #[test]
+    fn waker() {
+        let events = ConnectionEvents::default();
+        let sub = events.subscribe(EventFilter::default().stream(4));
+        let woken = Rc::new(Cell::new(0));
+        let w = Rc::clone(&woken);
+        sub.set_waker(move || w.set(w.get() + 1));
+
+        events.recv_stream_readable(StreamId::from(8));
+        assert_eq!(woken.get(), 0);
+        events.recv_stream_readable(StreamId::from(4));
+        events.send_stream_writable(StreamId::from(4));
+        assert_eq!(woken.get(), 1);
+
+        assert_eq!(sub.events().count(), 2);
+        events.send_stream_writable(StreamId::from(4));
+        assert_eq!(woken.get(), 2);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let events = ConnectionEvents::default();
+        let sub = events.subscribe(EventFilter::default());
+        drop(sub);
+        events.authentication_needed();
+        assert!(events.subscribers.borrow().0.is_empty());
+    }
 }
@@ -6,7 +6,7 @@
 
 // Directly relating to QUIC frames.
 
-use neqo_common::{qdebug, Decoder, Encoder};
+use neqo_common::{hex, qdebug, Decoder, Encoder, Redact};
 
 use crate::stream_id::StreamIndex;
 use crate::{AppError, TransportError};
@@ -39,6 +39,12 @@ const FRAME_TYPE_PATH_CHALLENGE: FrameType = 0x1a;
 const FRAME_TYPE_PATH_RESPONSE: FrameType = 0x1b;
 const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
 const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
+// From the multipath extension, draft-ietf-quic-multipath-04.  These are
+// only allowed once multipath is negotiated; see `Connection::enable_multipath()`.
+const FRAME_TYPE_ACK_MP: FrameType = 0x1522_8c00;
+const FRAME_TYPE_ACK_MP_ECN: FrameType = 0x1522_8c01;
+const FRAME_TYPE_PATH_ABANDON: FrameType = 0x1522_8c05;
+const FRAME_TYPE_PATH_STATUS: FrameType = 0x1522_8c06;
 
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
@@ -185,6 +191,32 @@ pub enum Frame {
         frame_type: u64,
         reason_phrase: Vec<u8>,
     },
+    /// ACK_MP from the multipath extension.  This is an ACK for the packet
+    /// number space of one path, which `space_identifier` names with the
+    /// sequence number of the connection ID that the path uses.
+    AckMp {
+        space_identifier: u64,
+        largest_acknowledged: u64,
+        ack_delay: u64,
+        first_ack_range: u64,
+        ack_ranges: Vec<AckRange>,
+    },
+    /// PATH_ABANDON from the multipath extension.  The path is the one that
+    /// the sender of the frame sends to the connection ID with
+    /// `dcid_sequence_number`.
+    PathAbandon {
+        dcid_sequence_number: u64,
+        error_code: u64,
+        reason_phrase: Vec<u8>,
+    },
+    /// PATH_STATUS from the multipath extension, which says whether a path
+    /// is to be used (2) or kept on standby (1).  The path is identified in
+    /// the same way as for PATH_ABANDON.
+    PathStatus {
+        dcid_sequence_number: u64,
+        status_sequence_number: u64,
+        status: u64,
+    },
 }
 
 impl Frame {
@@ -225,6 +257,9 @@ impl Frame {
             Frame::ConnectionClose { error_code, .. } => {
                 FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT + error_code.frame_type_bit()
             }
+            Frame::AckMp { .. } => FRAME_TYPE_ACK_MP, // No ECN here either.
+            Frame::PathAbandon { .. } => FRAME_TYPE_PATH_ABANDON,
+            Frame::PathStatus { .. } => FRAME_TYPE_PATH_STATUS,
         }
     }
 
@@ -238,16 +273,13 @@ impl Frame {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
-            } => {
-                enc.encode_varint(*largest_acknowledged);
-                enc.encode_varint(*ack_delay);
-                enc.encode_varint(ack_ranges.len() as u64);
-                enc.encode_varint(*first_ack_range);
-                for r in ack_ranges {
-                    enc.encode_varint(r.gap);
-                    enc.encode_varint(r.range);
-                }
-            }
+            } => marshal_ack(
+                enc,
+                *largest_acknowledged,
+                *ack_delay,
+                *first_ack_range,
+                ack_ranges,
+            ),
             Frame::ResetStream {
                 stream_id,
                 application_error_code,
@@ -341,12 +373,46 @@ impl Frame {
                 enc.encode_varint(*frame_type);
                 enc.encode_vvec(reason_phrase);
             }
+            Frame::AckMp {
+                space_identifier,
+                largest_acknowledged,
+                ack_delay,
+                first_ack_range,
+                ack_ranges,
+            } => {
+                enc.encode_varint(*space_identifier);
+                marshal_ack(
+                    enc,
+                    *largest_acknowledged,
+                    *ack_delay,
+                    *first_ack_range,
+                    ack_ranges,
+                );
+            }
+            Frame::PathAbandon {
+                dcid_sequence_number,
+                error_code,
+                reason_phrase,
+            } => {
+                enc.encode_varint(*dcid_sequence_number);
+                enc.encode_varint(*error_code);
+                enc.encode_vvec(reason_phrase);
+            }
+            Frame::PathStatus {
+                dcid_sequence_number,
+                status_sequence_number,
+                status,
+            } => {
+                enc.encode_varint(*dcid_sequence_number);
+                enc.encode_varint(*status_sequence_number);
+                enc.encode_varint(*status);
+            }
         }
     }
 
     pub fn ack_eliciting(&self) -> bool {
         match self {
-            Frame::Ack { .. } | Frame::Padding => false,
+            Frame::Ack { .. } | Frame::AckMp { .. } | Frame::Padding => false,
             _ => true,
         }
     }
@@ -413,12 +479,72 @@ impl Frame {
                 data.len(),
                 fin,
             )),
+            Frame::NewToken { token } => {
+                Some(format!("NewToken {{ token: {} }}", Redact(hex(token))))
+            }
+            Frame::NewConnectionId {
+                sequence_number,
+                retire_prior,
+                connection_id,
+                stateless_reset_token,
+            } => Some(format!(
+                "NewConnectionId {{ sequence_number: {}, retire_prior: {}, connection_id: {}, stateless_reset_token: {} }}",
+                sequence_number,
+                retire_prior,
+                Redact(hex(connection_id)),
+                Redact(hex(stateless_reset_token)),
+            )),
             Frame::Padding => None,
             _ => Some(format!("{:?}", self)),
         }
     }
 }
 
+/// Encode what follows the type of an ACK frame, which is also the end of an
+/// ACK_MP frame.
+fn marshal_ack(
+    enc: &mut Encoder,
+    largest_acknowledged: u64,
+    ack_delay: u64,
+    first_ack_range: u64,
+    ack_ranges: &[AckRange],
+) {
+    enc.encode_varint(largest_acknowledged);
+    enc.encode_varint(ack_delay);
+    enc.encode_varint(ack_ranges.len() as u64);
+    enc.encode_varint(first_ack_range);
+    for r in ack_ranges {
+        enc.encode_varint(r.gap);
+        enc.encode_varint(r.range);
+    }
+}
+
+/// Decode what `marshal_ack()` encodes, skipping the ECN counts if `ecn` is
+/// set.  This returns the largest acknowledged, the ACK delay, the first ACK
+/// range and the other ranges, or `None` if `dec` runs out.
+fn decode_ack(dec: &mut Decoder, ecn: bool) -> Option<(u64, u64, u64, Vec<AckRange>)> {
+    let la = dec.decode_varint()?;
+    let ad = dec.decode_varint()?;
+    let nr = dec.decode_varint()?;
+    let fa = dec.decode_varint()?;
+    let mut arr: Vec<AckRange> = Vec::with_capacity(nr as usize);
+    for _ in 0..nr {
+        let ar = AckRange {
+            gap: dec.decode_varint()?,
+            range: dec.decode_varint()?,
+        };
+        arr.push(ar);
+    }
+
+    // Now check for the values for ACK_ECN.
+    if ecn {
+        dec.decode_varint()?;
+        dec.decode_varint()?;
+        dec.decode_varint()?;
+    }
+    Some((la, ad, fa, arr))
+}
+
 /// Calculate the crypto frame header size so we know how much data we can fit
 pub fn crypto_frame_hdr_len(offset: u64, remaining: usize) -> usize {
     let mut hdr_len = 1; // for frame type
@@ -457,26 +583,7 @@ pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
             },
         }),
         FRAME_TYPE_ACK | FRAME_TYPE_ACK_ECN => {
-            let la = dv!(dec);
-            let ad = dv!(dec);
-            let nr = dv!(dec);
-            let fa = dv!(dec);
-            let mut arr: Vec<AckRange> = Vec::with_capacity(nr as usize);
-            for _ in 0..nr {
-                let ar = AckRange {
-                    gap: dv!(dec),
-                    range: dv!(dec),
-                };
-                arr.push(ar);
-            }
-
-            // Now check for the values for ACK_ECN.
-            if t == FRAME_TYPE_ACK_ECN {
-                dv!(dec);
-                dv!(dec);
-                dv!(dec);
-            }
-
+            let (la, ad, fa, arr) = d!(decode_ack(dec, t == FRAME_TYPE_ACK_ECN));
             Ok(Frame::Ack {
                 largest_acknowledged: la,
                 ack_delay: ad,
@@ -584,6 +691,27 @@ pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
                 reason_phrase: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
             })
         }
+        FRAME_TYPE_ACK_MP | FRAME_TYPE_ACK_MP_ECN => {
+            let space_identifier = dv!(dec);
+            let (la, ad, fa, arr) = d!(decode_ack(dec, t == FRAME_TYPE_ACK_MP_ECN));
+            Ok(Frame::AckMp {
+                space_identifier,
+                largest_acknowledged: la,
+                ack_delay: ad,
+                first_ack_range: fa,
+                ack_ranges: arr,
+            })
+        }
+        FRAME_TYPE_PATH_ABANDON => Ok(Frame::PathAbandon {
+            dcid_sequence_number: dv!(dec),
+            error_code: dv!(dec),
+            reason_phrase: d!(dec.decode_vvec()).to_vec(),
+        }),
+        FRAME_TYPE_PATH_STATUS => Ok(Frame::PathStatus {
+            dcid_sequence_number: dv!(dec),
+            status_sequence_number: dv!(dec),
+            status: dv!(dec),
+        }),
         _ => Err(Error::UnknownFrameType),
     }
 }
@@ -839,6 +967,47 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn test_ack_mp() {
+        let f = Frame::AckMp {
+            space_identifier: 2,
+            largest_acknowledged: 0x1234,
+            ack_delay: 0x1235,
+            first_ack_range: 0x1236,
+            ack_ranges: vec![AckRange { gap: 1, range: 2 }],
+        };
+
+        enc_dec(&f, "95228c0002523452350152360102");
+
+        // ACK_MP_ECN has ECN counts at the end.
+        let enc = Encoder::from_hex("95228c0102523452350152360102010203");
+        let mut dec = enc.as_decoder();
+        assert_eq!(decode_frame(&mut dec).unwrap(), f);
+        assert!(!f.ack_eliciting());
+    }
+
+    #[test]
+    fn test_path_abandon() {
+        let f = Frame::PathAbandon {
+            dcid_sequence_number: 1,
+            error_code: 0x1234,
+            reason_phrase: vec![0x01, 0x02, 0x03],
+        };
+
+        enc_dec(&f, "95228c0501523403010203");
+    }
+
+    #[test]
+    fn test_path_status() {
+        let f = Frame::PathStatus {
+            dcid_sequence_number: 2,
+            status_sequence_number: 3,
+            status: 1,
+        };
+
+        enc_dec(&f, "95228c06020301");
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
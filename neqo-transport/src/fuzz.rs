@@ -0,0 +1,44 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Entry points for the fuzzing harnesses.  These expose the decoders that
+// are otherwise internal to the crate; they only exist when the `fuzzing`
+// feature is enabled.
+
+use neqo_common::{Decoder, Encoder};
+
+use crate::frame::decode_frame;
+use crate::packet::{decode_packet_hdr, FixedLengthConnectionIdDecoder};
+use crate::tparams::TransportParameters;
+
+/// Decode a packet header.  The first byte of `data` sets the length of
+/// short header connection IDs; the rest is the packet.
+pub fn packet_header(data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    let cid_decoder = FixedLengthConnectionIdDecoder::new(usize::from(data[0] % 21));
+    let _ = decode_packet_hdr(&cid_decoder, &data[1..]);
+}
+
+/// Decode frames until the data runs out or a frame fails to decode.
+pub fn frames(data: &[u8]) {
+    let mut dec = Decoder::from(data);
+    while dec.remaining() > 0 {
+        if decode_frame(&mut dec).is_err() {
+            break;
+        }
+    }
+}
+
+/// Decode transport parameters, and encode them again if that succeeds.
+pub fn transport_parameters(data: &[u8]) {
+    let mut dec = Decoder::from(data);
+    if let Ok(tps) = TransportParameters::decode(&mut dec) {
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+    }
+}
@@ -0,0 +1,117 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Randomized, but legal, variation in what a connection sends.  This is
+// intended for shaking out intolerance in peers and middleboxes, so all the
+// choices here are driven from a seed that can be used to reproduce a run.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::tparams::consts::TransportParameterId;
+
+/// The largest connection ID that can be used as the client's initial
+/// Destination Connection ID.
+const MAX_INITIAL_CID_LEN: usize = 20;
+/// The smallest initial Destination Connection ID a server has to accept.
+const MIN_INITIAL_CID_LEN: usize = 8;
+/// The longest value that is included in a greased transport parameter.
+const MAX_GREASE_TP_LEN: usize = 16;
+/// How many PADDING frames might be added to a packet.
+const MAX_EXTRA_PADDING: usize = 16;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Grease {
+    rng: StdRng,
+}
+
+impl Grease {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Produce a seed for another instance, so that different consumers
+    /// don't interfere with each other's sequence of choices.
+    pub fn next_seed(&mut self) -> u64 {
+        self.rng.gen()
+    }
+
+    /// Pick a length for the client's initial Destination Connection ID.
+    pub fn initial_cid_len(&mut self) -> usize {
+        self.rng
+            .gen_range(MIN_INITIAL_CID_LEN, MAX_INITIAL_CID_LEN + 1)
+    }
+
+    /// A transport parameter with a reserved identifier (31 * N + 27) and a
+    /// random value.  Peers are required to ignore these.
+    pub fn transport_parameter(&mut self) -> (TransportParameterId, Vec<u8>) {
+        let n: TransportParameterId = self.rng.gen_range(0, 2114);
+        let mut v = vec![0; self.rng.gen_range(0, MAX_GREASE_TP_LEN + 1)];
+        self.rng.fill(&mut v[..]);
+        (n * 31 + 27, v)
+    }
+
+    /// Reorder items.  Used for transport parameters.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        items.shuffle(&mut self.rng);
+    }
+
+    /// How many PADDING frames to add to a packet, given `space` bytes
+    /// remain available in the datagram.
+    pub fn extra_padding(&mut self, space: usize) -> usize {
+        self.rng
+            .gen_range(0, std::cmp::min(space, MAX_EXTRA_PADDING) + 1)
+    }
+
+    /// The size to pad a datagram containing an Initial to.  This is between
+    /// `min` and `max` inclusive.
+    pub fn padded_size(&mut self, min: usize, max: usize) -> usize {
+        if max <= min {
+            min
+        } else {
+            self.rng.gen_range(min, max + 1)
+        }
+    }
+
+    /// Whether to add another packet to the datagram.
+    pub fn coalesce(&mut self) -> bool {
+        self.rng.gen_bool(0.5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible() {
+        let mut a = Grease::new(17);
+        let mut b = Grease::new(17);
+        for _ in 0..20 {
+            assert_eq!(a.initial_cid_len(), b.initial_cid_len());
+            assert_eq!(a.transport_parameter(), b.transport_parameter());
+            assert_eq!(a.padded_size(1200, 1280), b.padded_size(1200, 1280));
+        }
+    }
+
+    #[test]
+    fn legal_values() {
+        let mut g = Grease::new(0);
+        for _ in 0..1000 {
+            let len = g.initial_cid_len();
+            assert!(len >= MIN_INITIAL_CID_LEN && len <= MAX_INITIAL_CID_LEN);
+            let (id, v) = g.transport_parameter();
+            assert_eq!(id % 31, 27);
+            assert!(v.len() <= MAX_GREASE_TP_LEN);
+            let sz = g.padded_size(1200, 1280);
+            assert!(sz >= 1200 && sz <= 1280);
+            assert!(g.extra_padding(3) <= 3);
+        }
+    }
+}
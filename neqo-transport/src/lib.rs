@@ -15,22 +15,52 @@ mod dump;
 mod events;
 mod flow_mgr;
 mod frame;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+mod grease;
+mod multipath;
 mod packet;
+mod pool;
+mod qlog;
+mod ratelimit;
 mod recovery;
 mod recv_stream;
+mod resumption;
 mod send_stream;
 pub mod server;
+mod stateless_reset;
 mod stats;
+mod stream_group;
 mod stream_id;
 mod tparams;
 mod tracking;
 
+#[cfg(feature = "keepalive-offload")]
+pub use self::connection::KeepaliveOffload;
 pub use self::connection::{
-    Connection, ConnectionIdManager, FixedConnectionIdManager, Output, Role, State,
+    Connection, ConnectionIdManager, FixedConnectionIdManager, LengthPrefixConnectionIdManager,
+    Output, OutputIter, Role, State,
+};
+pub use self::crypto::{initial_keys, InitialKeys};
+pub use self::events::{
+    ConnectionEvent, ConnectionEvents, EventClass, EventFilter, EventSubscription, HandshakeRecord,
 };
-pub use self::events::{ConnectionEvent, ConnectionEvents};
 pub use self::frame::CloseError;
 pub use self::frame::StreamType;
+pub use self::multipath::{PathId, PathInfo, PathStatus};
+pub use self::packet::{
+    classify_packet, ConnectionId, ConnectionIdDecoder, FixedLengthConnectionIdDecoder,
+    LengthPrefixConnectionIdDecoder, PacketClass, Version, MAX_CONNECTION_ID_LEN,
+};
+pub use self::pool::{ConnectionHandle, ConnectionPool};
+pub use self::qlog::Qlog;
+pub use self::recovery::{RecoveryInfo, TimerKind};
+pub use self::recv_stream::StreamObserver;
+pub use self::resumption::{LruResumptionStore, ResumptionStore};
+pub use self::send_stream::SendStreamSource;
+pub use self::stateless_reset::{reset_token, RESET_TOKEN_LEN};
+pub use self::stats::{Stats, StatsDelta};
+pub use self::stream_group::StreamGroupId;
 
 /// The supported version of the QUIC protocol.
 pub const QUIC_VERSION: u32 = 0xff00_0016;
@@ -64,13 +94,21 @@ pub enum Error {
     UnexpectedMessage,
     HandshakeFailed,
     KeysNotFound,
+    /// The keys for an epoch were discarded once they weren't needed.
+    KeysDiscarded,
     ConnectionState,
     AckedUnsentPacket,
     VersionNegotiation,
+    /// The version information from the peer is inconsistent with the
+    /// version that is in use.
+    VersionNegotiationError,
     InvalidResumptionToken,
     WrongRole,
     InvalidInput,
     IdleTimeout,
+    /// The connection was abandoned because it hit a retransmission limit.
+    /// See `Connection::set_retransmission_limits()`.
+    TooLossy,
     PeerError(TransportError),
     InvalidRetry,
 }
@@ -88,6 +126,7 @@ impl Error {
             Error::TransportParameterError => 8,
             Error::ProtocolViolation => 10,
             Error::InvalidMigration => 12,
+            Error::VersionNegotiationError => 0x11,
             Error::CryptoAlert(a) => 0x100 + u64::from(*a),
             Error::PeerError(a) => *a,
             // TODO(ekr@rtfm.com): Map these errors.
@@ -103,6 +142,7 @@ impl Error {
             | Error::UnexpectedMessage
             | Error::HandshakeFailed
             | Error::KeysNotFound
+            | Error::KeysDiscarded
             | Error::ConnectionState
             | Error::AckedUnsentPacket
             | Error::VersionNegotiation
@@ -111,6 +151,7 @@ impl Error {
             | Error::InvalidInput
             | Error::InvalidRetry
             | Error::IdleTimeout
+            | Error::TooLossy
             | Error::InternalError => 1,
         }
     }
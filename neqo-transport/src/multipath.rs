@@ -0,0 +1,661 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Experimental support for the multipath extension, after
+// draft-ietf-quic-multipath-04.  Each extra path has its own packet number
+// space, loss recovery, congestion window and RTT estimate.  The path that
+// the handshake used is path 0, and its state stays in `Connection`.
+//
+// This departs from the draft in ways that mean it only works between neqo
+// endpoints:
+// * The transport parameter uses a private codepoint.
+// * The path goes in the top byte of the packet number that forms the AEAD
+//   nonce, rather than in the 96-bit nonce, so a path uses a connection ID
+//   with a sequence number of at most 255.
+// * Nothing is sent when the probe timeout fires on a path.  A path that
+//   gets no acknowledgment after a few probe timeouts is abandoned.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use neqo_common::{qinfo, Encoder};
+use rand::Rng;
+
+use crate::connection::Path;
+use crate::frame::Frame;
+use crate::packet::{ConnectionId, CryptoCtx, PacketNumber, MASK_LEN};
+use crate::recovery::{LossRecovery, RecoveryToken};
+use crate::tracking::{AckToken, PNSpace, RecvdPackets};
+use crate::{Error, Res};
+
+/// Identifies a path.  This is the sequence number of the peer's connection
+/// ID that packets on the path are sent to; path 0 is the path that the
+/// handshake used.
+pub type PathId = u64;
+
+/// The number of connection IDs that are issued for extra paths.
+pub(crate) const LOCAL_CIDS: usize = 3;
+/// The largest connection ID sequence number that a path can use.
+pub(crate) const MAX_PATH_ID: PathId = 0xff;
+/// Where the path goes in the packet number used for the AEAD nonce.
+const PATH_NONCE_SHIFT: u32 = 56;
+/// The number of probe timeouts in a row after which a path is abandoned.
+pub(crate) const MAX_PATH_PTOS: u32 = 3;
+
+/// What an endpoint asks its peer to do with a path, using PATH_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStatus {
+    /// Only use the path if no other path is available.
+    Standby,
+    /// Use the path.
+    Available,
+}
+
+impl PathStatus {
+    fn code(self) -> u64 {
+        match self {
+            PathStatus::Standby => 1,
+            PathStatus::Available => 2,
+        }
+    }
+
+    fn from_code(code: u64) -> Res<Self> {
+        match code {
+            1 => Ok(PathStatus::Standby),
+            2 => Ok(PathStatus::Available),
+            _ => Err(Error::FrameEncodingError),
+        }
+    }
+}
+
+/// The status of a path in each direction.
+#[derive(Debug)]
+struct StatusState {
+    /// What we asked the peer, and the sequence number of the last
+    /// PATH_STATUS we sent for the path.
+    local: PathStatus,
+    local_seq: u64,
+    /// What the peer asked of us, and the sequence number of the
+    /// PATH_STATUS that said so.
+    peer: PathStatus,
+    peer_seq: Option<u64>,
+}
+
+impl Default for StatusState {
+    fn default() -> Self {
+        Self {
+            local: PathStatus::Available,
+            local_seq: 0,
+            peer: PathStatus::Available,
+            peer_seq: None,
+        }
+    }
+}
+
+/// A path, as `Connection::paths()` describes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathInfo {
+    pub id: PathId,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    /// Whether the peer answered a PATH_CHALLENGE on the path.  Only
+    /// validated paths carry anything but the frames that validate them.
+    pub validated: bool,
+    /// What we asked the peer to do with the path.
+    pub status: PathStatus,
+    /// What the peer asked us to do with the path.
+    pub peer_status: PathStatus,
+}
+
+/// The packet number used to form the AEAD nonce for packet `pn` on `path`.
+pub(crate) fn nonce_count(path: PathId, pn: PacketNumber) -> PacketNumber {
+    debug_assert!(path <= MAX_PATH_ID);
+    debug_assert!(pn < (1 << PATH_NONCE_SHIFT));
+    (path << PATH_NONCE_SHIFT) | pn
+}
+
+/// Packet protection for an extra path.  This uses the 1-RTT keys, with the
+/// path mixed into the nonce so that packet numbers can repeat across paths.
+pub(crate) struct PathCrypto<'a> {
+    pub inner: &'a dyn CryptoCtx,
+    pub path: PathId,
+}
+
+impl<'a> CryptoCtx for PathCrypto<'a> {
+    fn compute_mask(&self, sample: &[u8]) -> Res<[u8; MASK_LEN]> {
+        self.inner.compute_mask(sample)
+    }
+
+    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<usize> {
+        self.inner
+            .aead_decrypt(nonce_count(self.path, pn), hdr, body)
+    }
+
+    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<()> {
+        self.inner
+            .aead_encrypt(nonce_count(self.path, pn), hdr, body)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum MultipathRecoveryToken {
+    /// An ACK_MP for packets that arrived with our connection ID that has
+    /// this sequence number.
+    Ack(PathId, AckToken),
+    Frame(Frame),
+}
+
+/// An extra path.
+#[derive(Debug)]
+pub(crate) struct MultipathPath {
+    pub path: Path,
+    /// The sequence number of the peer's connection ID that is used on
+    /// this path.
+    pub id: PathId,
+    /// The sequence number of our connection ID that the peer uses on this
+    /// path, once a packet arrives on it.
+    pub rx_id: Option<PathId>,
+    pub recovery: LossRecovery,
+    pub acks: RecvdPackets,
+    challenge: [u8; 8],
+    send_challenge: bool,
+    response: Option<[u8; 8]>,
+    validated: bool,
+    status: StatusState,
+}
+
+impl MultipathPath {
+    pub fn validated(&self) -> bool {
+        self.validated
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Multipath {
+    /// Our connection IDs for extra paths, with their sequence numbers.
+    local_cids: Vec<(u64, ConnectionId)>,
+    next_cid_seq: u64,
+    pub paths: Vec<MultipathPath>,
+    /// The status of path 0.
+    primary: StatusState,
+    /// Frames that can be sent on any path.
+    frames: VecDeque<Frame>,
+    /// Losses on paths that are gone, so that the totals don't go backward.
+    lost_count: u64,
+    spurious_count: u64,
+}
+
+impl Multipath {
+    pub fn new() -> Self {
+        Self {
+            local_cids: Vec::new(),
+            // The connection ID from the handshake is number 0.
+            next_cid_seq: 1,
+            paths: Vec::new(),
+            primary: StatusState::default(),
+            frames: VecDeque::new(),
+            lost_count: 0,
+            spurious_count: 0,
+        }
+    }
+
+    /// Whether another connection ID can be issued for a path.
+    pub fn can_issue_cid(&self) -> bool {
+        self.next_cid_seq <= MAX_PATH_ID
+    }
+
+    /// Issue a connection ID that the peer can use for a new path.  Without
+    /// a stateless reset token, a random one is used.
+    pub fn issue_cid(&mut self, cid: ConnectionId, reset_token: Option<[u8; 16]>) {
+        assert!(self.can_issue_cid());
+        let reset_token = reset_token.unwrap_or_else(|| {
+            let mut token = [0; 16];
+            rand::thread_rng().fill(&mut token[..]);
+            token
+        });
+        let seq = self.next_cid_seq;
+        self.next_cid_seq += 1;
+        self.frames.push_back(Frame::NewConnectionId {
+            sequence_number: seq,
+            retire_prior: 0,
+            connection_id: cid.to_vec(),
+            stateless_reset_token: reset_token,
+        });
+        self.local_cids.push((seq, cid));
+    }
+
+    /// The sequence number of one of our connection IDs for extra paths.
+    pub fn local_cid_seq(&self, cid: &ConnectionId) -> Option<u64> {
+        self.local_cids
+            .iter()
+            .find(|(_, c)| c == cid)
+            .map(|(seq, _)| *seq)
+    }
+
+    /// The peer stopped using one of our connection IDs.
+    pub fn retire_local(&mut self, seq: u64) -> bool {
+        let before = self.local_cids.len();
+        self.local_cids.retain(|(s, _)| *s != seq);
+        self.local_cids.len() != before
+    }
+
+    pub fn find(&self, id: PathId) -> Option<usize> {
+        self.paths.iter().position(|p| p.id == id)
+    }
+
+    pub fn find_rx(&self, rx_id: PathId) -> Option<usize> {
+        self.paths.iter().position(|p| p.rx_id == Some(rx_id))
+    }
+
+    /// Add a path, which then needs to be validated.
+    pub fn add(&mut self, path: Path, id: PathId, rx_id: Option<PathId>) {
+        qinfo!("multipath: add path {} {:?}", id, path);
+        let mut challenge = [0; 8];
+        rand::thread_rng().fill(&mut challenge[..]);
+        self.paths.push(MultipathPath {
+            path,
+            id,
+            rx_id,
+            recovery: LossRecovery::new(),
+            acks: RecvdPackets::new(PNSpace::ApplicationData),
+            challenge,
+            send_challenge: true,
+            response: None,
+            validated: false,
+            status: StatusState::default(),
+        });
+    }
+
+    fn remove(&mut self, index: usize) -> MultipathPath {
+        let p = self.paths.remove(index);
+        self.lost_count += p.recovery.lost_count();
+        self.spurious_count += p.recovery.spurious_count();
+        p
+    }
+
+    /// Stop using a path, telling the peer.  The caller deals with the
+    /// packets that are still outstanding on the path.
+    pub fn abandon(&mut self, id: PathId) -> Option<MultipathPath> {
+        let i = self.find(id)?;
+        qinfo!("multipath: abandon path {}", id);
+        self.frames.push_back(Frame::PathAbandon {
+            dcid_sequence_number: id,
+            error_code: 0,
+            reason_phrase: Vec::new(),
+        });
+        self.frames.push_back(Frame::RetireConnectionId {
+            sequence_number: id,
+        });
+        Some(self.remove(i))
+    }
+
+    /// The peer abandoned the path that it sends to our connection ID with
+    /// sequence number `rx_id`.
+    pub fn peer_abandoned(&mut self, rx_id: PathId) -> Option<MultipathPath> {
+        let i = self.find_rx(rx_id)?;
+        let p = self.remove(i);
+        qinfo!("multipath: peer abandoned path {}", p.id);
+        self.frames.push_back(Frame::RetireConnectionId {
+            sequence_number: p.id,
+        });
+        Some(p)
+    }
+
+    /// Ask the peer to use a path, or to keep it on standby.
+    pub fn set_status(&mut self, id: PathId, status: PathStatus) -> Res<()> {
+        let state = if id == 0 {
+            &mut self.primary
+        } else {
+            let i = self.find(id).ok_or(Error::InvalidInput)?;
+            &mut self.paths[i].status
+        };
+        state.local = status;
+        state.local_seq += 1;
+        let frame = Frame::PathStatus {
+            dcid_sequence_number: id,
+            status_sequence_number: state.local_seq,
+            status: status.code(),
+        };
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    /// The peer sent PATH_STATUS for the path that it sends to our
+    /// connection ID with sequence number `rx_id`.
+    pub fn peer_status(&mut self, rx_id: PathId, seq: u64, status: u64) -> Res<()> {
+        let status = PathStatus::from_code(status)?;
+        let state = if rx_id == 0 {
+            &mut self.primary
+        } else if let Some(i) = self.find_rx(rx_id) {
+            &mut self.paths[i].status
+        } else {
+            qinfo!("multipath: PATH_STATUS for unknown path {}", rx_id);
+            return Ok(());
+        };
+        if state.peer_seq.map_or(true, |s| seq > s) {
+            state.peer = status;
+            state.peer_seq = Some(seq);
+        }
+        Ok(())
+    }
+
+    /// Answer a PATH_CHALLENGE that arrived on an extra path.
+    pub fn path_challenge(&mut self, rx_id: PathId, data: [u8; 8]) {
+        if let Some(i) = self.find_rx(rx_id) {
+            self.paths[i].response = Some(data);
+        }
+    }
+
+    /// A PATH_RESPONSE arrived.  Returns true if it validated a path.
+    pub fn path_response(&mut self, data: [u8; 8]) -> bool {
+        match self
+            .paths
+            .iter_mut()
+            .find(|p| !p.validated && p.challenge == data)
+        {
+            Some(p) => {
+                qinfo!("multipath: path {} validated", p.id);
+                p.validated = true;
+                p.send_challenge = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get a frame that can be sent on any path.
+    pub fn get_frame(&mut self, remaining: usize) -> Option<(Frame, Option<RecoveryToken>)> {
+        let mut enc = Encoder::default();
+        self.frames.front()?.marshal(&mut enc);
+        if enc.len() > remaining {
+            return None;
+        }
+        let frame = self.frames.pop_front().unwrap();
+        let token = RecoveryToken::Multipath(MultipathRecoveryToken::Frame(frame.clone()));
+        Some((frame, Some(token)))
+    }
+
+    /// Get a frame that has to be sent on the path at `index`: an ACK_MP for
+    /// the packets that arrived on it, or one that validates the path.
+    pub fn path_frame(
+        &mut self,
+        index: usize,
+        now: Instant,
+        remaining: usize,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        let p = &mut self.paths[index];
+        if let Some(rx_id) = p.rx_id {
+            if let Some((ack, token)) = p.acks.get_frame(now) {
+                if let Frame::Ack {
+                    largest_acknowledged,
+                    ack_delay,
+                    first_ack_range,
+                    ack_ranges,
+                } = ack
+                {
+                    let frame = Frame::AckMp {
+                        space_identifier: rx_id,
+                        largest_acknowledged,
+                        ack_delay,
+                        first_ack_range,
+                        ack_ranges,
+                    };
+                    let token = RecoveryToken::Multipath(MultipathRecoveryToken::Ack(rx_id, token));
+                    return Some((frame, Some(token)));
+                }
+            }
+        }
+        // PATH_CHALLENGE and PATH_RESPONSE take 9 bytes.
+        if remaining < 9 {
+            return None;
+        }
+        let frame = if let Some(data) = p.response.take() {
+            Frame::PathResponse { data }
+        } else if p.send_challenge {
+            p.send_challenge = false;
+            Frame::PathChallenge { data: p.challenge }
+        } else {
+            return None;
+        };
+        let token = RecoveryToken::Multipath(MultipathRecoveryToken::Frame(frame.clone()));
+        Some((frame, Some(token)))
+    }
+
+    pub fn acked(&mut self, token: MultipathRecoveryToken) {
+        if let MultipathRecoveryToken::Ack(rx_id, token) = token {
+            if let Some(i) = self.find_rx(rx_id) {
+                self.paths[i].acks.acked(&token);
+            }
+        }
+    }
+
+    pub fn lost(&mut self, token: MultipathRecoveryToken) {
+        let frame = match token {
+            MultipathRecoveryToken::Ack(..) => return,
+            MultipathRecoveryToken::Frame(frame) => frame,
+        };
+        let resend = match &frame {
+            Frame::NewConnectionId {
+                sequence_number, ..
+            } => self.local_cids.iter().any(|(s, _)| s == sequence_number),
+            Frame::PathStatus {
+                dcid_sequence_number,
+                status_sequence_number,
+                ..
+            } => {
+                let state = if *dcid_sequence_number == 0 {
+                    Some(&self.primary)
+                } else {
+                    self.find(*dcid_sequence_number)
+                        .map(|i| &self.paths[i].status)
+                };
+                state.map_or(false, |s| s.local_seq == *status_sequence_number)
+            }
+            Frame::PathAbandon { .. } | Frame::RetireConnectionId { .. } => true,
+            Frame::PathChallenge { data } => {
+                if let Some(p) = self
+                    .paths
+                    .iter_mut()
+                    .find(|p| !p.validated && p.challenge == *data)
+                {
+                    p.send_challenge = true;
+                }
+                false
+            }
+            _ => false,
+        };
+        if resend {
+            self.frames.push_back(frame);
+        }
+    }
+
+    /// Whether stream data can be sent on a path.  That is any validated
+    /// path that the peer hasn't put on standby, or any validated path if
+    /// the peer put them all on standby.
+    pub fn carries_data(&self, id: PathId) -> bool {
+        let available = |s: &StatusState| s.peer == PathStatus::Available;
+        let any_available = available(&self.primary)
+            || self
+                .paths
+                .iter()
+                .any(|p| p.validated && available(&p.status));
+        if id == 0 {
+            !any_available || available(&self.primary)
+        } else {
+            self.find(id).map_or(false, |i| {
+                let p = &self.paths[i];
+                p.validated && (!any_available || available(&p.status))
+            })
+        }
+    }
+
+    /// The number of packets declared lost on extra paths.
+    pub fn lost_count(&self) -> u64 {
+        self.lost_count
+            + self
+                .paths
+                .iter()
+                .map(|p| p.recovery.lost_count())
+                .sum::<u64>()
+    }
+
+    /// The number of packets declared lost on extra paths that were later
+    /// acknowledged.
+    pub fn spurious_count(&self) -> u64 {
+        self.spurious_count
+            + self
+                .paths
+                .iter()
+                .map(|p| p.recovery.spurious_count())
+                .sum::<u64>()
+    }
+
+    /// Describe path 0, which lives elsewhere, and then the extra paths.
+    pub fn info(&self, primary: &Path) -> Vec<PathInfo> {
+        let describe = |id, p: &Path, validated, s: &StatusState| PathInfo {
+            id,
+            local: p.local,
+            remote: p.remote,
+            validated,
+            status: s.local,
+            peer_status: s.peer,
+        };
+        let mut info = vec![describe(0, primary, true, &self.primary)];
+        info.extend(
+            self.paths
+                .iter()
+                .map(|p| describe(p.id, &p.path, p.validated, &p.status)),
+        );
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neqo_common::{matches, Datagram};
+    use test_fixture::loopback;
+
+    fn path() -> Path {
+        let d = Datagram::new(loopback(), loopback(), Vec::new());
+        Path::new(&d, ConnectionId::from(&[1, 2, 3][..]))
+    }
+
+    fn frames(mp: &mut Multipath) -> Vec<Frame> {
+        let mut out = Vec::new();
+        while let Some((f, _)) = mp.get_frame(1000) {
+            out.push(f);
+        }
+        out
+    }
+
+    #[test]
+    fn nonce() {
+        assert_eq!(nonce_count(0, 7), 7);
+        assert_eq!(nonce_count(2, 7), 0x0200_0000_0000_0007);
+    }
+
+    #[test]
+    fn issue_and_retire() {
+        let mut mp = Multipath::new();
+        mp.issue_cid(ConnectionId::from(&[9; 4][..]), None);
+        let f = frames(&mut mp);
+        assert!(matches!(
+            f[..],
+            [Frame::NewConnectionId {
+                sequence_number: 1,
+                ..
+            }]
+        ));
+        assert_eq!(mp.local_cid_seq(&ConnectionId::from(&[9; 4][..])), Some(1));
+        assert!(mp.retire_local(1));
+        assert!(!mp.retire_local(1));
+        assert_eq!(mp.local_cid_seq(&ConnectionId::from(&[9; 4][..])), None);
+    }
+
+    #[test]
+    fn validate() {
+        let mut mp = Multipath::new();
+        mp.add(path(), 1, None);
+        let now = Instant::now();
+        let data = match mp.path_frame(0, now, 1000) {
+            Some((Frame::PathChallenge { data }, _)) => data,
+            f => panic!("unexpected {:?}", f),
+        };
+        assert!(mp.path_frame(0, now, 1000).is_none());
+        assert!(!mp.carries_data(1));
+
+        // The challenge is sent again if it is lost.
+        mp.lost(MultipathRecoveryToken::Frame(Frame::PathChallenge { data }));
+        assert!(mp.path_frame(0, now, 1000).is_some());
+
+        assert!(mp.path_response(data));
+        assert!(!mp.path_response(data));
+        assert!(mp.paths[0].validated());
+        assert!(mp.carries_data(0));
+        assert!(mp.carries_data(1));
+    }
+
+    #[test]
+    fn status() {
+        let mut mp = Multipath::new();
+        mp.add(path(), 1, Some(2));
+        mp.paths[0].validated = true;
+
+        // The peer puts path 0 on standby.
+        mp.peer_status(0, 1, 1).unwrap();
+        assert!(!mp.carries_data(0));
+        assert!(mp.carries_data(1));
+        // An older status is ignored.
+        mp.peer_status(0, 0, 2).unwrap();
+        assert!(!mp.carries_data(0));
+        // With everything on standby, everything can be used.
+        mp.peer_status(2, 1, 1).unwrap();
+        assert!(mp.carries_data(0));
+        assert!(mp.carries_data(1));
+        assert_eq!(mp.peer_status(2, 2, 3), Err(Error::FrameEncodingError));
+
+        // Only the latest PATH_STATUS is sent again.
+        mp.set_status(1, PathStatus::Standby).unwrap();
+        mp.set_status(1, PathStatus::Available).unwrap();
+        let f = frames(&mut mp);
+        assert_eq!(f.len(), 2);
+        mp.lost(MultipathRecoveryToken::Frame(f[0].clone()));
+        assert!(frames(&mut mp).is_empty());
+        mp.lost(MultipathRecoveryToken::Frame(f[1].clone()));
+        assert_eq!(frames(&mut mp), vec![f[1].clone()]);
+        assert_eq!(
+            mp.set_status(7, PathStatus::Standby),
+            Err(Error::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn abandon() {
+        let mut mp = Multipath::new();
+        mp.add(path(), 1, Some(1));
+        mp.add(path(), 2, Some(3));
+        assert_eq!(mp.abandon(1).unwrap().id, 1);
+        assert!(mp.abandon(1).is_none());
+        assert!(matches!(
+            frames(&mut mp)[..],
+            [
+                Frame::PathAbandon {
+                    dcid_sequence_number: 1,
+                    ..
+                },
+                Frame::RetireConnectionId { sequence_number: 1 }
+            ]
+        ));
+        assert_eq!(mp.peer_abandoned(3).unwrap().id, 2);
+        assert!(matches!(
+            frames(&mut mp)[..],
+            [Frame::RetireConnectionId { sequence_number: 2 }]
+        ));
+        assert!(mp.paths.is_empty());
+    }
+}
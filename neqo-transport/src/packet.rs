@@ -12,17 +12,25 @@
 use derive_more::Deref;
 use rand::Rng;
 
-use neqo_common::{hex, matches, qtrace, Decoder, Encoder};
-use neqo_crypto::Epoch;
+use neqo_common::{hex, matches, qtrace, BufferPool, Decoder, Encoder, Redact};
+use neqo_crypto::aead::Aead;
+use neqo_crypto::{Epoch, TLS_AES_128_GCM_SHA256};
 
-use crate::{Error, Res};
+use crate::crypto::{retry_format, RetryFormat};
+use crate::{Error, Res, QUIC_VERSION};
+
+use std::convert::TryFrom;
+use std::mem;
 
 const PACKET_TYPE_INITIAL: u8 = 0x0;
 const PACKET_TYPE_0RTT: u8 = 0x01;
-const PACKET_TYPE_HANDSHAKE: u8 = 0x2;
+pub(crate) const PACKET_TYPE_HANDSHAKE: u8 = 0x2;
 const PACKET_TYPE_RETRY: u8 = 0x03;
 
-const PACKET_BIT_LONG: u8 = 0x80;
+/// The length of the Retry Integrity Tag.
+pub const RETRY_TAG_LEN: usize = 16;
+
+pub(crate) const PACKET_BIT_LONG: u8 = 0x80;
 const PACKET_BIT_SHORT: u8 = 0x00;
 const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
 
@@ -30,6 +38,10 @@ const SAMPLE_SIZE: usize = 16;
 
 const AUTH_TAG_LEN: usize = 16;
 
+/// The part of a header protection mask that is used: one byte for the
+/// first byte of the header and up to four for the packet number.
+pub const MASK_LEN: usize = 5;
+
 #[derive(Debug, PartialEq)]
 pub enum PacketType {
     Short,
@@ -37,7 +49,12 @@ pub enum PacketType {
     Handshake,
     VN(Vec<Version>), // List of versions
     Initial(Vec<u8>), // Token
-    Retry { odcid: ConnectionId, token: Vec<u8> },
+    /// The original destination connection ID is only in Retry packets from
+    /// versions without the Retry Integrity Tag.
+    Retry {
+        odcid: Option<ConnectionId>,
+        token: Vec<u8>,
+    },
 }
 
 impl Default for PacketType {
@@ -61,12 +78,15 @@ impl PacketType {
 pub type Version = u32;
 pub type PacketNumber = u64;
 
+/// The longest connection ID that QUIC allows.
+pub const MAX_CONNECTION_ID_LEN: usize = 20;
+
 #[derive(Clone, Default, Deref, Eq, Hash, PartialEq)]
 pub struct ConnectionId(pub Vec<u8>);
 
 impl ConnectionId {
     pub fn generate(len: usize) -> Self {
-        assert!(matches!(len, 0..=20));
+        assert!(len <= MAX_CONNECTION_ID_LEN);
         let mut v = vec![0; len];
         rand::thread_rng().fill(&mut v[..]);
         Self(v)
@@ -84,13 +104,13 @@ impl ConnectionId {
 
 impl ::std::fmt::Debug for ConnectionId {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "CID {}", hex(&self.0))
+        write!(f, "CID {}", Redact(hex(&self.0)))
     }
 }
 
 impl ::std::fmt::Display for ConnectionId {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{}", hex(&self.0))
+        write!(f, "{}", Redact(hex(&self.0)))
     }
 }
 
@@ -100,10 +120,49 @@ impl From<&[u8]> for ConnectionId {
     }
 }
 
-pub trait ConnectionIdDecoder {
+/// Decodes the connection ID from a short header packet, which doesn't carry
+/// its length.  The length has to come from the connection ID itself or from
+/// configuration, not from a record of connection IDs that were made: a
+/// decoder is shared between connections and threads, and it is used without
+/// taking any lock.
+pub trait ConnectionIdDecoder: Send + Sync {
     fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId>;
 }
 
+/// Decodes connection IDs that all have the same length.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedLengthConnectionIdDecoder {
+    len: usize,
+}
+
+impl FixedLengthConnectionIdDecoder {
+    pub fn new(len: usize) -> Self {
+        Self { len }
+    }
+}
+
+impl ConnectionIdDecoder for FixedLengthConnectionIdDecoder {
+    fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId> {
+        dec.decode(self.len).map(ConnectionId::from)
+    }
+}
+
+/// Decodes connection IDs where the first byte is the number of bytes that
+/// follow it, so that connection IDs of different lengths can be in use at
+/// the same time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LengthPrefixConnectionIdDecoder {}
+
+impl ConnectionIdDecoder for LengthPrefixConnectionIdDecoder {
+    fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId> {
+        let len = usize::from(dec.peek_byte()?) + 1;
+        if len > MAX_CONNECTION_ID_LEN {
+            return None;
+        }
+        dec.decode(len).map(ConnectionId::from)
+    }
+}
+
 #[derive(Default, Debug)]
 #[allow(clippy::module_name_repetitions)]
 pub struct PacketHdr {
@@ -149,10 +208,16 @@ impl PacketHdr {
     }
 }
 
+/// Packet protection.  Packets are encrypted and decrypted in place, in the
+/// buffer that holds them, so that this doesn't allocate.
 pub trait CryptoCtx {
-    fn compute_mask(&self, sample: &[u8]) -> Res<Vec<u8>>;
-    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>>;
-    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>>;
+    fn compute_mask(&self, sample: &[u8]) -> Res<[u8; MASK_LEN]>;
+    /// Decrypt `body` in place, with `hdr` as the AAD.  Returns the length of
+    /// the plaintext, which is at the start of `body`.
+    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<usize>;
+    /// Encrypt `body` in place, with `hdr` as the AAD.  The last
+    /// `AUTH_TAG_LEN` bytes of `body` are space for the tag.
+    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<()>;
 }
 
 pub struct PacketNumberDecoder {
@@ -264,12 +329,21 @@ fn decode_pnl(u: u8) -> usize {
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                 Source Connection ID (0..160)               ...
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  | ODCID Len (8) |
+  | ODCID Len (8) |  (drafts up to 24 only)
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |          Original Destination Connection ID (0..160)        ...
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                        Retry Token (*)                      ...
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                                               |
+  +                                                               +
+  |                                                               |
+  +                   Retry Integrity Tag (128)                   +
+  |                                                               |
+  +                                                               +
+  |                                                               |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+                                          (draft 25 and later only)
 */
 
 pub fn decode_packet_hdr(cid_parser: &dyn ConnectionIdDecoder, pd: &[u8]) -> Res<PacketHdr> {
@@ -336,9 +410,25 @@ pub fn decode_packet_hdr(cid_parser: &dyn ConnectionIdDecoder, pd: &[u8]) -> Res
                 PacketType::Handshake
             }
             PACKET_TYPE_RETRY => {
-                let odcid = ConnectionId(d!(d.decode_vec(1)).to_vec()); // TODO(mt) unnecessary copy
-                let token = d.decode_remainder().to_vec(); // TODO(mt) unnecessary copy
-                p.tipe = PacketType::Retry { odcid, token };
+                p.tipe = match retry_format(version) {
+                    Some(RetryFormat::Odcid) => {
+                        // TODO(mt) unnecessary copies
+                        let odcid = ConnectionId(d!(d.decode_vec(1)).to_vec());
+                        let token = d.decode_remainder().to_vec();
+                        PacketType::Retry {
+                            odcid: Some(odcid),
+                            token,
+                        }
+                    }
+                    // The tag is checked with `retry_tag_valid()`, which needs
+                    // the original destination connection ID.
+                    Some(RetryFormat::Tag { .. }) => {
+                        let token_len = d!(d.remaining().checked_sub(RETRY_TAG_LEN));
+                        let token = d!(d.decode(token_len)).to_vec(); // TODO(mt) unnecessary copy
+                        PacketType::Retry { odcid: None, token }
+                    }
+                    None => return Err(Error::InvalidPacket),
+                };
                 return Ok(p);
             }
             _ => unreachable!(),
@@ -346,17 +436,115 @@ pub fn decode_packet_hdr(cid_parser: &dyn ConnectionIdDecoder, pd: &[u8]) -> Res
     }
 
     p.body_len = d!(d.decode_varint()) as usize;
+    if p.body_len > d.remaining() {
+        return Err(Error::NoMoreData);
+    }
     p.hdr_len = pd.len() - d.remaining();
 
     Ok(p)
 }
 
+/// What a dispatcher can learn about a packet without keys or any state,
+/// so that it can route the packet.  See `classify_packet()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketClass<'a> {
+    version: Option<Version>,
+    dcid: &'a [u8],
+    scid: Option<&'a [u8]>,
+    token: Option<&'a [u8]>,
+}
+
+impl<'a> PacketClass<'a> {
+    /// Whether this is a long header packet.
+    pub fn is_long(&self) -> bool {
+        self.version.is_some()
+    }
+
+    /// The version of a long header packet.  Version 0 means that this is a
+    /// Version Negotiation packet.
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    /// The destination connection ID.
+    pub fn dcid(&self) -> &'a [u8] {
+        self.dcid
+    }
+
+    /// The source connection ID of a long header packet.
+    pub fn scid(&self) -> Option<&'a [u8]> {
+        self.scid
+    }
+
+    /// The token of an Initial packet, which might be empty.  This is only
+    /// available for the QUIC version that this crate implements, because
+    /// the packet types and layout after the connection IDs depend on the
+    /// version.
+    pub fn token(&self) -> Option<&'a [u8]> {
+        self.token
+    }
+
+    /// Whether this is an Initial packet that carries a token.
+    pub fn has_token(&self) -> bool {
+        self.token.map_or(false, |t| !t.is_empty())
+    }
+}
+
+/// Take a connection ID with a one-byte length from the front of `buf`.
+fn take_cid(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = buf.split_first()?;
+    let len = usize::from(*len);
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Look at the first packet in `dgram` without decrypting it or copying
+/// anything.  Only the parts of the header that all QUIC versions share are
+/// used, except for the token.  Short header packets don't say how long their
+/// connection ID is, so that has to be `short_cid_len`.  This returns `None`
+/// if the packet is too short.
+pub fn classify_packet(dgram: &[u8], short_cid_len: usize) -> Option<PacketClass> {
+    let first = *dgram.first()?;
+    if first & PACKET_BIT_LONG == 0 {
+        return Some(PacketClass {
+            version: None,
+            dcid: dgram.get(1..1 + short_cid_len)?,
+            scid: None,
+            token: None,
+        });
+    }
+
+    let version = Decoder::from(dgram.get(1..5)?).decode_uint(4)? as Version;
+    let (dcid, rest) = take_cid(&dgram[5..])?;
+    let (scid, rest) = take_cid(rest)?;
+    let token = if version == QUIC_VERSION && (first >> 4) & 0x3 == PACKET_TYPE_INITIAL {
+        let mut d = Decoder::from(rest);
+        let len = usize::try_from(d.decode_varint()?).ok()?;
+        let start = rest.len() - d.remaining();
+        Some(rest.get(start..start + len)?)
+    } else {
+        None
+    };
+    Some(PacketClass {
+        version: Some(version),
+        dcid,
+        scid: Some(scid),
+        token,
+    })
+}
+
+/// Decrypt `pkt`, putting the body in `out`, which is cleared first.  The
+/// header and the ciphertext are copied to `out` and decrypted there, so if
+/// `out` is big enough, this doesn't allocate.
 pub fn decrypt_packet(
     crypto: &dyn CryptoCtx,
     pn: PacketNumberDecoder,
     hdr: &mut PacketHdr,
     pkt: &[u8],
-) -> Res<Vec<u8>> {
+    out: &mut Vec<u8>,
+) -> Res<()> {
     assert!(!matches!(
         hdr.tipe,
         PacketType::Retry{..} | PacketType::VN(_)
@@ -372,7 +560,9 @@ pub fn decrypt_packet(
 
     // Now put together a raw header to work on.
     let pn_len = decode_pnl((hdr.tbyte ^ mask[0]) & 0x3);
-    let mut hdrbytes = pkt[0..(hdr.hdr_len + pn_len)].to_vec();
+    out.clear();
+    out.extend_from_slice(&pkt[0..(hdr.hdr_len + pn_len)]);
+    let hdrbytes = &mut out[..];
 
     qtrace!("unmask hdr={}", hex(&hdrbytes));
     // Un-mask the leading byte.
@@ -390,29 +580,47 @@ pub fn decrypt_packet(
         pn_encoded += u64::from(hdrbytes[hdr.hdr_len + i]);
     }
     qtrace!("unmasked hdr={}", hex(&hdrbytes));
+    if hdr.body_len < pn_len {
+        return Err(Error::NoMoreData);
+    }
     hdr.hdr_len += pn_len;
     hdr.body_len -= pn_len;
 
     // Now call out to expand the PN.
     hdr.pn = pn.decode_pn(pn_encoded, pn_len);
 
-    // Finally, decrypt.
-    Ok(crypto.aead_decrypt(
-        hdr.pn,
-        &hdrbytes,
-        &pkt[hdr.hdr_len..hdr.hdr_len + hdr.body_len()],
-    )?)
+    // Finally, decrypt, then move the plaintext to the start of `out`.
+    out.extend_from_slice(&pkt[hdr.hdr_len..hdr.hdr_len + hdr.body_len()]);
+    let (aad, body) = out.split_at_mut(hdr.hdr_len);
+    let len = crypto.aead_decrypt(hdr.pn, aad, body)?;
+    out.truncate(hdr.hdr_len + len);
+    out.drain(..hdr.hdr_len);
+    Ok(())
 }
 
-fn encode_packet_short(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
+/// The part of a short header that comes before the packet number, without
+/// header protection.
+pub(crate) fn short_header_prefix(dcid: &ConnectionId, pnl: usize) -> Encoder {
     let mut enc = Encoder::default();
     // Leading byte.
+    enc.encode_byte(PACKET_BIT_SHORT | PACKET_BIT_FIXED_QUIC | encode_pnl(pnl));
+    enc.encode(&dcid.0);
+    enc
+}
+
+fn encode_packet_short(
+    crypto: &dyn CryptoCtx,
+    hdr: &PacketHdr,
+    body: &[u8],
+    mut enc: Encoder,
+) -> Encoder {
+    let start = enc.len();
     let pnl = pn_length(hdr.pn);
     enc.encode_byte(PACKET_BIT_SHORT | PACKET_BIT_FIXED_QUIC | encode_pnl(pnl));
     enc.encode(&hdr.dcid.0);
     enc.encode_uint(pnl, hdr.pn);
 
-    encrypt_packet(crypto, hdr, enc, body)
+    encrypt_packet(crypto, hdr, enc, start, body)
 }
 
 pub fn encode_packet_vn(hdr: &PacketHdr) -> Vec<u8> {
@@ -434,9 +642,13 @@ pub fn encode_packet_vn(hdr: &PacketHdr) -> Vec<u8> {
 }
 
 /* Handle Initial, 0-RTT, Handshake. */
-fn encode_packet_long(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
-    let mut enc = Encoder::default();
-
+fn encode_packet_long(
+    crypto: &dyn CryptoCtx,
+    hdr: &PacketHdr,
+    body: &[u8],
+    mut enc: Encoder,
+) -> Encoder {
+    let start = enc.len();
     let pnl = pn_length(hdr.pn);
     enc.encode_byte(
         PACKET_BIT_LONG | PACKET_BIT_FIXED_QUIC | hdr.tipe.code() << 4 | encode_pnl(pnl),
@@ -451,70 +663,254 @@ fn encode_packet_long(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> V
     enc.encode_varint((pnl + body.len() + AUTH_TAG_LEN) as u64);
     enc.encode_uint(pnl, hdr.pn);
 
-    encrypt_packet(crypto, hdr, enc, body)
+    encrypt_packet(crypto, hdr, enc, start, body)
 }
 
+/// Add `body` and space for the tag after the header, which starts at
+/// `start`, then encrypt it and protect the header, all in place.
 fn encrypt_packet(
     crypto: &dyn CryptoCtx,
     hdr: &PacketHdr,
     mut enc: Encoder,
+    start: usize,
     body: &[u8],
-) -> Vec<u8> {
-    let hdr_len = enc.len();
-    // Encrypt the packet. This has too many copies.
-    let ct = crypto.aead_encrypt(hdr.pn, &enc, body).unwrap();
-    enc.encode(&ct);
-    qtrace!("mask hdr={}", hex(&enc[0..hdr_len]));
+) -> Encoder {
+    let hdr_len = enc.len() - start;
+    enc.encode(body);
+    enc.encode(&[0; AUTH_TAG_LEN]);
+    {
+        let (aad, body) = enc[start..].split_at_mut(hdr_len);
+        crypto.aead_encrypt(hdr.pn, aad, body).unwrap();
+    }
+    let packet = &mut enc[start..];
+    qtrace!("mask hdr={}", hex(&packet[0..hdr_len]));
     let pn_start = hdr_len - pn_length(hdr.pn);
     let mask = crypto
-        .compute_mask(&enc[pn_start + 4..pn_start + SAMPLE_SIZE + 4])
+        .compute_mask(&packet[pn_start + 4..pn_start + SAMPLE_SIZE + 4])
         .unwrap();
-    enc[0] ^= mask[0]
+    packet[0] ^= mask[0]
         & match hdr.tipe {
             PacketType::Short => 0x1f,
             _ => 0x0f,
         };
     for i in 0..pn_length(hdr.pn) {
-        enc[pn_start + i] ^= mask[i + 1];
+        packet[pn_start + i] ^= mask[i + 1];
     }
-    qtrace!("masked hdr={}", hex(&enc[0..hdr_len]));
-    enc.into()
+    qtrace!("masked hdr={}", hex(&packet[0..hdr_len]));
+    enc
 }
 
 // TODO(ekr@rtfm.com): Minimal packet number lengths.
-fn pn_length(_pn: PacketNumber) -> usize {
+pub(crate) fn pn_length(_pn: PacketNumber) -> usize {
     3
 }
 
-pub fn encode_retry(hdr: &PacketHdr) -> Vec<u8> {
+/// The AEAD for the Retry Integrity Tag of `version`, if Retry packets for
+/// `version` have a tag.
+fn retry_aead(version: Version) -> Res<Aead> {
+    match retry_format(version) {
+        Some(RetryFormat::Tag { key, nonce }) => {
+            Ok(Aead::with_key(TLS_AES_128_GCM_SHA256, key, nonce)?)
+        }
+        _ => Err(Error::InvalidPacket),
+    }
+}
+
+/// The Retry pseudo-packet, which is what the Retry Integrity Tag covers: the
+/// original destination connection ID, with its length, then the Retry
+/// packet without the tag.
+fn retry_pseudo_packet(odcid: &ConnectionId, retry: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::with_capacity(1 + odcid.len() + retry.len());
+    enc.encode_vec(1, odcid);
+    enc.encode(retry);
+    enc.into()
+}
+
+/// Make the Retry Integrity Tag for `retry`, a Retry packet of `version`
+/// without a tag, that is sent in response to an Initial packet with a
+/// destination connection ID of `odcid`.
+pub fn retry_integrity_tag(
+    version: Version,
+    odcid: &ConnectionId,
+    retry: &[u8],
+) -> Res<[u8; RETRY_TAG_LEN]> {
+    let mut tag = [0; RETRY_TAG_LEN];
+    let len = retry_aead(version)?
+        .encrypt(0, &retry_pseudo_packet(odcid, retry), &[], &mut tag)?
+        .len();
+    debug_assert_eq!(len, RETRY_TAG_LEN);
+    Ok(tag)
+}
+
+/// Check the Retry Integrity Tag at the end of `retry`, a Retry packet of
+/// `version`, for a client that sent an Initial packet with a destination
+/// connection ID of `odcid`.
+pub fn retry_tag_valid(version: Version, odcid: &ConnectionId, retry: &[u8]) -> bool {
+    if retry.len() < RETRY_TAG_LEN {
+        return false;
+    }
+    let (packet, tag) = retry.split_at(retry.len() - RETRY_TAG_LEN);
+    let aead = match retry_aead(version) {
+        Ok(aead) => aead,
+        Err(_) => return false,
+    };
+    let mut out = [0; RETRY_TAG_LEN];
+    aead.decrypt(0, &retry_pseudo_packet(odcid, packet), tag, &mut out)
+        .is_ok()
+}
+
+/// Encode a Retry packet in response to an Initial packet that was sent to
+/// `odcid`.  Depending on the version, this either includes `odcid` or ends
+/// with the Retry Integrity Tag.
+pub fn encode_retry(hdr: &PacketHdr, odcid: &ConnectionId) -> Res<Vec<u8>> {
     let mut rand_byte: [u8; 1] = [0; 1];
     rand::thread_rng().fill(&mut rand_byte);
-    if let PacketType::Retry { odcid, token } = &hdr.tipe {
+    if let PacketType::Retry { token, .. } = &hdr.tipe {
+        let version = hdr.version.unwrap();
+        let format = retry_format(version).ok_or(Error::VersionNegotiation)?;
         let mut enc = Encoder::default();
         let b0 = PACKET_BIT_LONG
             | PACKET_BIT_FIXED_QUIC
             | (PACKET_TYPE_RETRY << 4)
             | (rand_byte[0] & 0xf);
         enc.encode_byte(b0);
-        enc.encode_uint(4, hdr.version.unwrap());
+        enc.encode_uint(4, version);
         enc.encode_vec(1, &hdr.dcid);
         enc.encode_vec(1, &hdr.scid.as_ref().unwrap());
-        enc.encode_vec(1, odcid);
-        enc.encode(token);
-        enc.into()
+        if format == RetryFormat::Odcid {
+            enc.encode_vec(1, odcid);
+            enc.encode(token);
+        } else {
+            enc.encode(token);
+            let tag = retry_integrity_tag(version, odcid, &enc)?;
+            enc.encode(&tag);
+        }
+        Ok(enc.into())
     } else {
         unreachable!()
     }
 }
 
 pub fn encode_packet(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
-    match &hdr.tipe {
-        PacketType::Short => encode_packet_short(crypto, hdr, body),
-        PacketType::VN(_) => encode_packet_vn(hdr),
-        PacketType::Retry { .. } => encode_retry(hdr),
+    if let PacketType::VN(_) = &hdr.tipe {
+        return encode_packet_vn(hdr);
+    }
+    let mut out = Vec::new();
+    encode_packet_into(crypto, hdr, body, &mut out);
+    out
+}
+
+/// Encode and encrypt a packet at the end of `out`.  If `out` has room, this
+/// doesn't allocate.
+pub fn encode_packet_into(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8], out: &mut Vec<u8>) {
+    let enc = Encoder::from(mem::replace(out, Vec::new()));
+    let enc = match &hdr.tipe {
+        PacketType::Short => encode_packet_short(crypto, hdr, body, enc),
+        PacketType::VN(_) => unreachable!("use encode_packet_vn()"),
+        PacketType::Retry { .. } => unreachable!("use encode_retry()"),
         PacketType::Initial(..) | PacketType::ZeroRTT | PacketType::Handshake => {
-            encode_packet_long(crypto, hdr, body)
+            encode_packet_long(crypto, hdr, body, enc)
+        }
+    };
+    *out = enc.into();
+}
+
+/// Builds a datagram out of packets, which can be from different epochs.
+/// Packets are encrypted as they are added, except that the last packet is
+/// also kept so that it can be rebuilt with padding.  Padding has to go in the
+/// last packet, because a short header packet runs to the end of the datagram.
+#[derive(Debug, Default)]
+pub struct DatagramBuilder {
+    buf: Vec<u8>,
+    /// The offset and header of the last packet.
+    last: Option<(usize, PacketHdr)>,
+    /// The body of the last packet.
+    body: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl DatagramBuilder {
+    /// Build a datagram in buffers from `pool`.  `build()` gives back all but
+    /// the buffer that holds the datagram.
+    pub fn with_pool(pool: &BufferPool) -> Self {
+        Self {
+            buf: pool.take(),
+            last: None,
+            body: pool.take(),
+            pool: Some(pool.clone()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The epoch of the last packet that was added.
+    pub fn last_epoch(&self) -> Option<Epoch> {
+        self.last.as_ref().map(|(_, hdr)| hdr.epoch)
+    }
+
+    /// A buffer to encode the frames of a packet in, from the pool if there
+    /// is one.  Give it back with `recycle()`.
+    pub fn frame_buffer(&self) -> Encoder {
+        match &self.pool {
+            Some(pool) => Encoder::from(pool.take()),
+            None => Encoder::default(),
+        }
+    }
+
+    /// Give back a buffer from `frame_buffer()`.
+    pub fn recycle(&self, enc: Encoder) {
+        if let Some(pool) = &self.pool {
+            pool.recycle(enc.into());
+        }
+    }
+
+    /// Encrypt and add a packet.  Returns the size of the packet.
+    pub fn add(&mut self, crypto: &dyn CryptoCtx, hdr: PacketHdr, body: &[u8]) -> usize {
+        let offset = self.buf.len();
+        encode_packet_into(crypto, &hdr, body, &mut self.buf);
+        self.body.clear();
+        self.body.extend_from_slice(body);
+        self.last = Some((offset, hdr));
+        self.buf.len() - offset
+    }
+
+    /// Add PADDING frames to the last packet so that the datagram is at least
+    /// `size` bytes.  `crypto` needs to be for the epoch of the last packet.
+    /// This might make the datagram one byte larger than `size` if the length
+    /// field of a long header packet needs to grow.
+    pub fn pad(&mut self, crypto: &dyn CryptoCtx, size: usize) {
+        if self.buf.len() >= size {
+            return;
+        }
+        let (offset, hdr) = self.last.as_ref().expect("a packet to pad");
+        let extra = size - self.buf.len();
+        self.body.resize(self.body.len() + extra, 0); // PADDING frames are zero.
+        self.buf.truncate(*offset);
+        encode_packet_into(crypto, hdr, &self.body, &mut self.buf);
+        if self.buf.len() > size {
+            // The packet length grew; try with one less byte of padding.
+            self.body.pop();
+            self.buf.truncate(*offset);
+            encode_packet_into(crypto, hdr, &self.body, &mut self.buf);
+            if self.buf.len() < size {
+                self.body.push(0);
+                self.buf.truncate(*offset);
+                encode_packet_into(crypto, hdr, &self.body, &mut self.buf);
+            }
+        }
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        if let Some(pool) = &self.pool {
+            pool.recycle(self.body);
         }
+        self.buf
     }
 }
 
@@ -522,6 +918,7 @@ pub fn encode_packet(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Ve
 #[allow(unused_variables)]
 mod tests {
     use super::*;
+    use test_fixture::fixture_init;
 
     const TEST_BODY: [u8; 6] = [0x01, 0x23, 0x45, 0x67, 0x89, 0x10];
 
@@ -536,36 +933,32 @@ mod tests {
     }
 
     impl CryptoCtx for TestFixture {
-        fn compute_mask(&self, sample: &[u8]) -> Res<Vec<u8>> {
-            Ok(vec![0xa5, 0xa5, 0xa5, 0xa5, 0xa5])
+        fn compute_mask(&self, sample: &[u8]) -> Res<[u8; MASK_LEN]> {
+            Ok([0xa5; MASK_LEN])
         }
 
-        fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
-            let mut pt = body.to_vec();
-
-            for i in &mut pt {
+        fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<usize> {
+            for i in body.iter_mut() {
                 *i ^= AEAD_MASK;
             }
-            let pt_len = pt.len() - AUTH_TAG_LEN;
-            let at = TestFixture::auth_tag(hdr, &pt[0..pt_len]);
+            let pt_len = body.len() - AUTH_TAG_LEN;
+            let at = TestFixture::auth_tag(hdr, &body[0..pt_len]);
             for i in 0..16 {
-                if at[i] != pt[pt_len + i] {
+                if at[i] != body[pt_len + i] {
                     return Err(Error::DecryptError);
                 }
             }
-            Ok(pt[0..pt_len].to_vec())
+            Ok(pt_len)
         }
 
-        fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
-            let tag = TestFixture::auth_tag(hdr, body);
-            let mut enc = Encoder::with_capacity(body.len() + tag.len());
-            enc.encode(body);
-            enc.encode(&tag);
-            for i in 0..enc.len() {
-                enc[i] ^= AEAD_MASK;
+        fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &mut [u8]) -> Res<()> {
+            let pt_len = body.len() - AUTH_TAG_LEN;
+            let tag = TestFixture::auth_tag(hdr, &body[..pt_len]);
+            body[pt_len..].copy_from_slice(&tag);
+            for i in body.iter_mut() {
+                *i ^= AEAD_MASK;
             }
-
-            Ok(enc.into())
+            Ok(())
         }
     }
 
@@ -598,7 +991,14 @@ mod tests {
 
     fn test_decrypt_packet(f: &TestFixture, packet: Vec<u8>) -> Res<(PacketHdr, Vec<u8>)> {
         let mut phdr = decode_packet_hdr(f, &packet)?;
-        let body = decrypt_packet(f, PacketNumberDecoder::new(Some(0)), &mut phdr, &packet)?;
+        let mut body = Vec::new();
+        decrypt_packet(
+            f,
+            PacketNumberDecoder::new(Some(0)),
+            &mut phdr,
+            &packet,
+            &mut body,
+        )?;
         Ok((phdr, body))
     }
 
@@ -670,21 +1070,199 @@ mod tests {
         assert!(test_decrypt_packet(&f, packet).is_err());
     }
 
-    #[test]
-    fn test_retry() {
+    fn retry_hdr(version: Version) -> PacketHdr {
         let mut hdr = default_hdr();
         hdr.tipe = PacketType::Retry {
-            odcid: ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]),
+            odcid: None,
             token: vec![99, 88, 77, 66, 55, 44, 33],
         };
+        hdr.version = Some(version);
         hdr.scid = Some(ConnectionId(vec![1, 2, 3, 4, 5]));
-        let packet = encode_retry(&hdr);
+        hdr
+    }
+
+    #[test]
+    fn test_retry() {
+        fixture_init();
+        let odcid = ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]);
+        let hdr = retry_hdr(1);
+        let mut packet = encode_retry(&hdr, &odcid).expect("should encode");
         let f = TestFixture {};
         let decoded = decode_packet_hdr(&f, &packet).expect("should decode");
         assert_eq!(decoded.tipe, hdr.tipe);
         assert_eq!(decoded.version, hdr.version);
         assert_eq!(decoded.dcid, hdr.dcid);
         assert_eq!(decoded.scid, hdr.scid);
+
+        assert!(retry_tag_valid(1, &odcid, &packet));
+        assert!(!retry_tag_valid(1, &ConnectionId(vec![9, 8, 7]), &packet));
+        assert!(!retry_tag_valid(0xff00_001d, &odcid, &packet));
+        let plen = packet.len();
+        packet[plen - 1] ^= 0x7;
+        assert!(!retry_tag_valid(1, &odcid, &packet));
+        assert!(!retry_tag_valid(1, &odcid, &packet[..RETRY_TAG_LEN - 1]));
+    }
+
+    /// Drafts up to 24 put the original destination connection ID in the
+    /// Retry and have no tag.
+    #[test]
+    fn retry_odcid() {
+        let odcid = ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]);
+        let hdr = retry_hdr(QUIC_VERSION);
+        let packet = encode_retry(&hdr, &odcid).expect("should encode");
+        let f = TestFixture {};
+        let decoded = decode_packet_hdr(&f, &packet).expect("should decode");
+        assert_eq!(
+            decoded.tipe,
+            PacketType::Retry {
+                odcid: Some(odcid),
+                token: vec![99, 88, 77, 66, 55, 44, 33],
+            }
+        );
+        assert_eq!(decoded.version, hdr.version);
+        assert_eq!(decoded.dcid, hdr.dcid);
+        assert_eq!(decoded.scid, hdr.scid);
+    }
+
+    #[test]
+    fn retry_unknown_version() {
+        let odcid = ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]);
+        assert!(encode_retry(&retry_hdr(31), &odcid).is_err());
+    }
+
+    /// The example in Appendix A.4 of RFC 9001.
+    #[test]
+    fn retry_integrity_tag_rfc9001() {
+        fixture_init();
+        let odcid = ConnectionId(vec![0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
+        let retry = Encoder::from_hex(
+            "ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba",
+        );
+        let (packet, tag) = retry.split_at(retry.len() - RETRY_TAG_LEN);
+        assert_eq!(&retry_integrity_tag(1, &odcid, packet).unwrap()[..], tag);
+        assert!(retry_tag_valid(1, &odcid, &retry));
+    }
+
+    /// The example in Appendix A.4 of RFC 9369.
+    #[test]
+    fn retry_integrity_tag_rfc9369() {
+        fixture_init();
+        let odcid = ConnectionId(vec![0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
+        let retry = Encoder::from_hex(
+            "cf6b3343cf0008f067a5502a4262b5746f6b656ec8646ce8bfe33952d955543665dcc7b6",
+        );
+        let (packet, tag) = retry.split_at(retry.len() - RETRY_TAG_LEN);
+        assert_eq!(
+            &retry_integrity_tag(0x6b33_43cf, &odcid, packet).unwrap()[..],
+            tag
+        );
+        assert!(retry_tag_valid(0x6b33_43cf, &odcid, &retry));
+    }
+
+    #[test]
+    fn builder_pad_short() {
+        let f = TestFixture {};
+        let mut initial = default_hdr();
+        initial.tipe = PacketType::Initial(Vec::new());
+        initial.scid = Some(ConnectionId(vec![9, 8, 7, 6, 5]));
+
+        let mut builder = DatagramBuilder::default();
+        let first = builder.add(&f, initial, &TEST_BODY);
+        builder.add(&f, default_hdr(), &TEST_BODY);
+        assert_eq!(builder.last_epoch(), Some(0));
+        builder.pad(&f, 1200);
+        let dgram = builder.build();
+        assert_eq!(dgram.len(), 1200);
+
+        // Both packets can be decrypted, and the padding is in the last one.
+        let (hdr, body) = test_decrypt_packet(&f, dgram[..first].to_vec()).unwrap();
+        assert_eq!(hdr.tipe, PacketType::Initial(Vec::new()));
+        assert_eq!(body, TEST_BODY.to_vec());
+        let (hdr, body) = test_decrypt_packet(&f, dgram[first..].to_vec()).unwrap();
+        assert_eq!(hdr.tipe, PacketType::Short);
+        assert_eq!(&body[..TEST_BODY.len()], &TEST_BODY[..]);
+        assert!(body[TEST_BODY.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn builder_pad_long() {
+        let f = TestFixture {};
+        for size in 50..200 {
+            let mut hdr = default_hdr();
+            hdr.tipe = PacketType::Handshake;
+            hdr.scid = Some(ConnectionId(vec![9, 8, 7, 6, 5]));
+            let mut builder = DatagramBuilder::default();
+            builder.add(&f, hdr, &TEST_BODY);
+            builder.pad(&f, size);
+            let dgram = builder.build();
+            // The length field might need to grow by a byte.
+            assert!(dgram.len() == size || dgram.len() == size + 1);
+            let (_, body) = test_decrypt_packet(&f, dgram).unwrap();
+            assert_eq!(&body[..TEST_BODY.len()], &TEST_BODY[..]);
+        }
+    }
+
+    #[test]
+    fn builder_pool() {
+        let f = TestFixture {};
+        let pool = BufferPool::new(256, 4);
+        let mut builder = DatagramBuilder::with_pool(&pool);
+        let mut frames = builder.frame_buffer();
+        frames.encode(&TEST_BODY);
+        builder.add(&f, default_hdr(), &frames);
+        builder.recycle(frames);
+        builder.pad(&f, 100);
+        let dgram = builder.build();
+        assert_eq!(dgram.len(), 100);
+        // Only the buffer that holds the datagram is still in use.
+        assert_eq!(pool.available(), 2);
+
+        // The next datagram reuses the buffers.
+        let ptr = dgram.as_ptr();
+        pool.recycle(dgram);
+        let mut builder = DatagramBuilder::with_pool(&pool);
+        builder.add(&f, default_hdr(), &TEST_BODY);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(builder.build().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn classify() {
+        let short = [0x40, 1, 2, 3, 4, 5, 6];
+        let class = classify_packet(&short, 4).unwrap();
+        assert!(!class.is_long());
+        assert_eq!(class.dcid(), &[1, 2, 3, 4]);
+        assert_eq!(class.scid(), None);
+        assert!(classify_packet(&short, 7).is_none());
+
+        let mut hdr = PacketHdr::new(
+            0,
+            PacketType::Initial(vec![9; 5]),
+            Some(QUIC_VERSION),
+            ConnectionId(vec![1, 2, 3]),
+            Some(ConnectionId(vec![4, 5])),
+            0,
+            0,
+        );
+        let f = TestFixture {};
+        let packet = encode_packet(&f, &hdr, &TEST_BODY);
+        let class = classify_packet(&packet, 4).unwrap();
+        assert!(class.is_long());
+        assert_eq!(class.version(), Some(QUIC_VERSION));
+        assert_eq!(class.dcid(), &[1, 2, 3]);
+        assert_eq!(class.scid(), Some(&[4, 5][..]));
+        assert_eq!(class.token(), Some(&[9; 5][..]));
+        assert!(class.has_token());
+        assert!(classify_packet(&packet[..10], 4).is_none());
+
+        // The token isn't known for other versions.
+        hdr.version = Some(0x0a0a_0a0a);
+        let packet = encode_packet(&f, &hdr, &TEST_BODY);
+        let class = classify_packet(&packet, 4).unwrap();
+        assert_eq!(class.version(), Some(0x0a0a_0a0a));
+        assert_eq!(class.dcid(), &[1, 2, 3]);
+        assert_eq!(class.token(), None);
+        assert!(!class.has_token());
     }
 
     #[test]
@@ -696,4 +1274,38 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn fixed_length_cid() {
+        let decoder = FixedLengthConnectionIdDecoder::new(3);
+        let mut dec = Decoder::from(&[1, 2, 3, 4][..]);
+        assert_eq!(
+            decoder.decode_cid(&mut dec),
+            Some(ConnectionId(vec![1, 2, 3]))
+        );
+        assert_eq!(dec.remaining(), 1);
+        assert_eq!(decoder.decode_cid(&mut dec), None);
+    }
+
+    #[test]
+    fn length_prefix_cid() {
+        let decoder = LengthPrefixConnectionIdDecoder::default();
+        let mut dec = Decoder::from(&[2, 7, 8, 9][..]);
+        assert_eq!(
+            decoder.decode_cid(&mut dec),
+            Some(ConnectionId(vec![2, 7, 8]))
+        );
+        assert_eq!(dec.remaining(), 1);
+
+        // Too short.
+        let mut dec = Decoder::from(&[4, 1, 2][..]);
+        assert_eq!(decoder.decode_cid(&mut dec), None);
+
+        // Too long for QUIC.
+        let buf = [MAX_CONNECTION_ID_LEN as u8; 30];
+        assert_eq!(decoder.decode_cid(&mut Decoder::from(&buf[..])), None);
+        let buf = [(MAX_CONNECTION_ID_LEN - 1) as u8; 30];
+        let cid = decoder.decode_cid(&mut Decoder::from(&buf[..])).unwrap();
+        assert_eq!(cid.len(), MAX_CONNECTION_ID_LEN);
+    }
 }
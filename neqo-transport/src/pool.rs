@@ -0,0 +1,205 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Driving a set of connections with a single timer, without doing any I/O.
+
+use neqo_common::{matches, qtrace, timer::Timer, Datagram};
+
+use crate::connection::{Connection, Output, OutputIter, State};
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
+const TIMER_CAPACITY: usize = 16384;
+
+/// Identifies a connection in a `ConnectionPool`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionHandle(u64);
+
+#[derive(Debug)]
+struct PoolEntry {
+    c: Connection,
+    /// The time of the timer for this connection, if one is set.
+    timer: Option<Instant>,
+}
+
+/// A set of connections that share one timer wheel, in the same way that a
+/// `Server` manages its connections.  This is for applications that make
+/// many connections, such as a pool of client connections.  It does no I/O;
+/// the application passes received datagrams to `process()`, along with the
+/// connection they are for, and sends the datagrams that it returns.  Each
+/// datagram comes from the local address of the connection that produced it.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    connections: HashMap<ConnectionHandle, PoolEntry>,
+    /// Connections that need immediate processing.
+    waiting: VecDeque<ConnectionHandle>,
+    /// Outstanding timers for connections.
+    timers: Timer<ConnectionHandle>,
+    /// Connections that have new events.
+    active: HashSet<ConnectionHandle>,
+    next_id: u64,
+}
+
+impl ConnectionPool {
+    /// Make an empty pool.  `now` is the time that the pool is created.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            connections: HashMap::new(),
+            waiting: VecDeque::new(),
+            timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
+            active: HashSet::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Add a connection to the pool.  It is processed on the next call to
+    /// `process()`, which is how a client sends its first packet.
+    pub fn add(&mut self, c: Connection) -> ConnectionHandle {
+        let h = ConnectionHandle(self.next_id);
+        self.next_id += 1;
+        self.connections.insert(h, PoolEntry { c, timer: None });
+        self.waiting.push_back(h);
+        h
+    }
+
+    /// Remove a connection from the pool.  Closed connections stay in the
+    /// pool until they are removed.
+    pub fn remove(&mut self, h: ConnectionHandle) -> Option<Connection> {
+        let e = self.connections.remove(&h)?;
+        if let Some(t) = e.timer {
+            self.timers.remove(t, |x| *x == h);
+        }
+        self.waiting.retain(|x| *x != h);
+        self.active.remove(&h);
+        Some(e.c)
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// The number of connections with outstanding timers.
+    pub fn timer_count(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn connection(&self, h: ConnectionHandle) -> Option<&Connection> {
+        self.connections.get(&h).map(|e| &e.c)
+    }
+
+    /// Get a connection so that it can be used, such as to write to a stream.
+    /// This marks the connection as having something to send, so that the
+    /// next call to `process()` collects its output.
+    pub fn connection_mut(&mut self, h: ConnectionHandle) -> Option<&mut Connection> {
+        let e = self.connections.get_mut(&h)?;
+        if !self.waiting.contains(&h) {
+            self.waiting.push_back(h);
+        }
+        Some(&mut e.c)
+    }
+
+    /// Process a connection and update its place in the timers.
+    /// Returns a datagram if the connection has one to send.
+    fn process_connection(
+        &mut self,
+        h: ConnectionHandle,
+        dgram: Option<Datagram>,
+        now: Instant,
+    ) -> Option<Datagram> {
+        let e = self.connections.get_mut(&h)?;
+        qtrace!("Process pooled connection {:?}", h);
+        let out = e.c.process(dgram, now);
+        let next = match out {
+            Output::Callback(delay) => Some(now + delay),
+            _ => None,
+        };
+        if next != e.timer {
+            if let Some(t) = e.timer {
+                self.timers.remove(t, |x| *x == h);
+            }
+            if let Some(t) = next {
+                qtrace!("Change timer for {:?} to {:?}", h, t);
+                self.timers.add(t, h);
+            }
+            e.timer = next;
+        }
+        if let Output::Datagram(_) = out {
+            self.waiting.push_back(h);
+        }
+        if e.c.has_events() || matches!(e.c.state(), State::Closed { .. }) {
+            self.active.insert(h);
+        }
+        out.dgram()
+    }
+
+    /// Iterate through the waiting connections, then those with expired
+    /// timers, looking for one that wants to send a datagram.
+    fn process_next_output(&mut self, now: Instant) -> Option<Datagram> {
+        while let Some(h) = self.waiting.pop_front() {
+            if let Some(d) = self.process_connection(h, None, now) {
+                return Some(d);
+            }
+        }
+        while let Some(h) = self.timers.take_next(now) {
+            if let Some(e) = self.connections.get_mut(&h) {
+                e.timer = None;
+            }
+            if let Some(d) = self.process_connection(h, None, now) {
+                return Some(d);
+            }
+        }
+        None
+    }
+
+    fn next_time(&self, now: Instant) -> Option<Duration> {
+        if self.waiting.is_empty() {
+            self.timers.next_time().map(|t| max(t, now) - now)
+        } else {
+            Some(Duration::new(0, 0))
+        }
+    }
+
+    /// Deliver an optional datagram to the connection that it is for, then
+    /// get the next datagram to send from any connection.  If there is
+    /// nothing to send, this returns how long to wait before calling this
+    /// again, or `Output::None` if no connection has a timer running.
+    pub fn process(&mut self, input: Option<(ConnectionHandle, Datagram)>, now: Instant) -> Output {
+        let out = input.and_then(|(h, d)| self.process_connection(h, Some(d), now));
+        match out.or_else(|| self.process_next_output(now)) {
+            Some(d) => Output::Datagram(d),
+            None => match self.next_time(now) {
+                Some(delay) => Output::Callback(delay),
+                None => Output::None,
+            },
+        }
+    }
+
+    /// Process an optional input datagram, then produce all output.
+    /// See `Connection::process_iter()`.
+    pub fn process_iter<'a>(
+        &'a mut self,
+        input: Option<(ConnectionHandle, Datagram)>,
+        now: Instant,
+    ) -> OutputIter<impl FnMut() -> Output + 'a> {
+        let mut input = input;
+        OutputIter::new(move || self.process(input.take(), now))
+    }
+
+    /// This lists the connections that have new events, or have closed,
+    /// as a result of processing.
+    pub fn active_connections(&mut self) -> Vec<ConnectionHandle> {
+        let mut v: Vec<_> = self.active.drain().collect();
+        v.sort();
+        v
+    }
+}
@@ -0,0 +1,266 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Writing qlog traces, as the interop runner collects them.  This only
+// records packets and the frames in them, which is enough to follow a
+// connection in qvis.  The format is JSON-SEQ, as in qlog 0.3.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use neqo_common::{qwarn, Decoder};
+
+use crate::connection::Role;
+use crate::frame::{decode_frame, Frame};
+use crate::packet::{PacketHdr, PacketType};
+
+/// JSON-SEQ puts this before each record.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+fn packet_type(t: &PacketType) -> &'static str {
+    match t {
+        PacketType::Short => "1RTT",
+        PacketType::ZeroRTT => "0RTT",
+        PacketType::Handshake => "handshake",
+        PacketType::VN(..) => "version_negotiation",
+        PacketType::Initial(..) => "initial",
+        PacketType::Retry { .. } => "retry",
+    }
+}
+
+fn frame_json(f: &Frame) -> String {
+    match f {
+        Frame::Padding => String::from(r#"{"frame_type":"padding","payload_length":1}"#),
+        Frame::Ping => String::from(r#"{"frame_type":"ping"}"#),
+        Frame::Ack {
+            largest_acknowledged,
+            ack_delay,
+            ..
+        } => format!(
+            r#"{{"frame_type":"ack","largest_acknowledged":{},"ack_delay":{}}}"#,
+            largest_acknowledged, ack_delay
+        ),
+        Frame::AckMp {
+            space_identifier,
+            largest_acknowledged,
+            ack_delay,
+            ..
+        } => format!(
+            r#"{{"frame_type":"ack_mp","path_id":{},"largest_acknowledged":{},"ack_delay":{}}}"#,
+            space_identifier, largest_acknowledged, ack_delay
+        ),
+        Frame::ResetStream {
+            stream_id,
+            final_size,
+            ..
+        } => format!(
+            r#"{{"frame_type":"reset_stream","stream_id":{},"final_size":{}}}"#,
+            stream_id, final_size
+        ),
+        Frame::StopSending { stream_id, .. } => format!(
+            r#"{{"frame_type":"stop_sending","stream_id":{}}}"#,
+            stream_id
+        ),
+        Frame::Crypto { offset, data } => format!(
+            r#"{{"frame_type":"crypto","offset":{},"length":{}}}"#,
+            offset,
+            data.len()
+        ),
+        Frame::NewToken { .. } => String::from(r#"{"frame_type":"new_token"}"#),
+        Frame::Stream {
+            fin,
+            stream_id,
+            offset,
+            data,
+        } => format!(
+            r#"{{"frame_type":"stream","stream_id":{},"offset":{},"length":{},"fin":{}}}"#,
+            stream_id,
+            offset,
+            data.len(),
+            fin
+        ),
+        Frame::MaxData { maximum_data } => {
+            format!(r#"{{"frame_type":"max_data","maximum":{}}}"#, maximum_data)
+        }
+        Frame::MaxStreamData {
+            stream_id,
+            maximum_stream_data,
+        } => format!(
+            r#"{{"frame_type":"max_stream_data","stream_id":{},"maximum":{}}}"#,
+            stream_id, maximum_stream_data
+        ),
+        Frame::MaxStreams { .. } => String::from(r#"{"frame_type":"max_streams"}"#),
+        Frame::DataBlocked { data_limit } => {
+            format!(r#"{{"frame_type":"data_blocked","limit":{}}}"#, data_limit)
+        }
+        Frame::StreamDataBlocked {
+            stream_id,
+            stream_data_limit,
+        } => format!(
+            r#"{{"frame_type":"stream_data_blocked","stream_id":{},"limit":{}}}"#,
+            stream_id, stream_data_limit
+        ),
+        Frame::StreamsBlocked { .. } => String::from(r#"{"frame_type":"streams_blocked"}"#),
+        Frame::NewConnectionId {
+            sequence_number, ..
+        } => format!(
+            r#"{{"frame_type":"new_connection_id","sequence_number":{}}}"#,
+            sequence_number
+        ),
+        Frame::RetireConnectionId { sequence_number } => format!(
+            r#"{{"frame_type":"retire_connection_id","sequence_number":{}}}"#,
+            sequence_number
+        ),
+        Frame::PathChallenge { .. } => String::from(r#"{"frame_type":"path_challenge"}"#),
+        Frame::PathResponse { .. } => String::from(r#"{"frame_type":"path_response"}"#),
+        Frame::ConnectionClose { .. } => String::from(r#"{"frame_type":"connection_close"}"#),
+        Frame::PathAbandon { .. } => String::from(r#"{"frame_type":"path_abandon"}"#),
+        Frame::PathStatus { .. } => String::from(r#"{"frame_type":"path_status"}"#),
+    }
+}
+
+fn push_padding(frames: &mut Vec<String>, padding: &mut usize) {
+    if *padding > 0 {
+        frames.push(format!(
+            r#"{{"frame_type":"padding","payload_length":{}}}"#,
+            padding
+        ));
+        *padding = 0;
+    }
+}
+
+/// A qlog trace for one connection.  See `Connection::set_qlog()`.
+pub struct Qlog {
+    /// `None` once writing has failed, so that a broken trace doesn't get
+    /// in the way of the connection.
+    out: Option<Box<dyn Write>>,
+    start: Instant,
+}
+
+impl Qlog {
+    /// Start a trace, writing the header to `out`.  Event times are relative
+    /// to `now`.  `title` names the trace, such as the name of the program.
+    pub fn new(mut out: Box<dyn Write>, title: &str, role: Role, now: Instant) -> io::Result<Self> {
+        let reference = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let vantage = match role {
+            Role::Client => "client",
+            Role::Server => "server",
+        };
+        out.write_all(&[RECORD_SEPARATOR])?;
+        writeln!(
+            out,
+            r#"{{"qlog_version":"0.3","qlog_format":"JSON-SEQ","title":"{}","trace":{{"vantage_point":{{"type":"{}"}},"common_fields":{{"time_format":"relative","reference_time":{:.3}}}}}}}"#,
+            title.escape_default(),
+            vantage,
+            millis(reference)
+        )?;
+        Ok(Self {
+            out: Some(out),
+            start: now,
+        })
+    }
+
+    /// Record that a packet was sent or received.  `payload` is the
+    /// decrypted payload of the packet.
+    pub(crate) fn packet(&mut self, sent: bool, hdr: &PacketHdr, payload: &[u8], now: Instant) {
+        let time = millis(now.duration_since(self.start));
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => return,
+        };
+        let mut frames = Vec::new();
+        let mut padding = 0;
+        let mut d = Decoder::from(payload);
+        while d.remaining() > 0 {
+            match decode_frame(&mut d) {
+                // Runs of PADDING are recorded as one frame.
+                Ok(Frame::Padding) => padding += 1,
+                Ok(f) => {
+                    push_padding(&mut frames, &mut padding);
+                    frames.push(frame_json(&f));
+                }
+                Err(_) => break,
+            }
+        }
+        push_padding(&mut frames, &mut padding);
+        let res = out.write_all(&[RECORD_SEPARATOR]).and_then(|_| {
+            writeln!(
+                out,
+                r#"{{"time":{:.3},"name":"transport:{}","data":{{"header":{{"packet_type":"{}","packet_number":{}}},"frames":[{}]}}}}"#,
+                time,
+                if sent { "packet_sent" } else { "packet_received" },
+                packet_type(&hdr.tipe),
+                hdr.pn,
+                frames.join(",")
+            )
+        });
+        if let Err(e) = res {
+            qwarn!("Stopping qlog after a write error: {}", e);
+            self.out = None;
+        }
+    }
+}
+
+impl ::std::fmt::Debug for Qlog {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Qlog")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records() {
+        let buf = Shared::default();
+        let now = Instant::now();
+        let mut qlog = Qlog::new(Box::new(buf.clone()), "test", Role::Client, now).unwrap();
+        let hdr = PacketHdr::new(
+            0,
+            PacketType::Handshake,
+            Some(crate::QUIC_VERSION),
+            crate::packet::ConnectionId::from(&[][..]),
+            None,
+            7,
+            2,
+        );
+        // PING then three PADDING.
+        qlog.packet(true, &hdr, &[1, 0, 0, 0], now + Duration::from_millis(5));
+
+        let text = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let records: Vec<_> = text.split('\x1e').skip(1).collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].starts_with(r#"{"qlog_version":"0.3","qlog_format":"JSON-SEQ""#));
+        assert!(records[0].contains(r#""vantage_point":{"type":"client"}"#));
+        assert_eq!(
+            records[1],
+            "{\"time\":5.000,\"name\":\"transport:packet_sent\",\"data\":{\"header\":\
+             {\"packet_type\":\"handshake\",\"packet_number\":7},\"frames\":\
+             [{\"frame_type\":\"ping\"},{\"frame_type\":\"padding\",\"payload_length\":3}]}}\n"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A limit on the rate at which a connection sends.
+
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A token bucket.  Credit accrues at `rate` bytes per second, up to `burst`
+/// bytes.  Sending is allowed while there is any credit, so the credit can go
+/// negative when a packet is larger than what remains; sending then has to
+/// wait until that debt is repaid.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// The rate, in bytes per second.
+    rate: u64,
+    /// The maximum credit.
+    burst: i64,
+    /// The available credit, in bytes.
+    credit: i64,
+    /// The time up to which credit has been added.
+    t: Option<Instant>,
+    /// Whether `allowed()` last said no.
+    blocked: bool,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: usize) -> Self {
+        assert!(rate > 0);
+        let burst = i64::try_from(burst).unwrap();
+        Self {
+            rate,
+            burst,
+            credit: burst,
+            t: None,
+            blocked: false,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let t = match self.t {
+            Some(t) if now > t => t,
+            Some(_) => return,
+            None => {
+                self.t = Some(now);
+                return;
+            }
+        };
+        let earned = (now - t).as_nanos() * u128::from(self.rate) / NANOS_PER_SEC;
+        let earned = i64::try_from(earned).unwrap_or(i64::max_value());
+        let credit = self.credit.saturating_add(earned);
+        if credit >= self.burst {
+            self.credit = self.burst;
+            self.t = Some(now);
+        } else {
+            // Only advance by the time that was needed to earn whole bytes,
+            // so that frequent calls don't lose credit to rounding.
+            self.credit = credit;
+            self.t = Some(t + self.time_to_earn(earned));
+        }
+    }
+
+    fn time_to_earn(&self, bytes: i64) -> Duration {
+        let bytes = u128::try_from(bytes).unwrap_or(0);
+        let nanos = (bytes * NANOS_PER_SEC + u128::from(self.rate) - 1) / u128::from(self.rate);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::max_value()))
+    }
+
+    /// Whether sending is allowed at `now`.
+    pub fn allowed(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.blocked = self.credit <= 0;
+        !self.blocked
+    }
+
+    /// The time at which sending is allowed, for when something else holds
+    /// packets until then, such as the kernel with SO_TXTIME.  This returns
+    /// `None` if that time is more than `horizon` after `now`.
+    pub fn release_time(&mut self, now: Instant, horizon: Duration) -> Option<Instant> {
+        self.refill(now);
+        let t = if self.credit > 0 {
+            now
+        } else {
+            self.t.unwrap() + self.time_to_earn(1 - self.credit)
+        };
+        self.blocked = t > now + horizon;
+        if self.blocked {
+            None
+        } else {
+            Some(t)
+        }
+    }
+
+    /// Whether there is as much credit as there can be, which means that
+    /// nothing has been sent for a while.
+    pub fn full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.credit >= self.burst
+    }
+
+    /// Record that `bytes` were sent.
+    pub fn sent(&mut self, bytes: usize) {
+        let bytes = i64::try_from(bytes).unwrap_or(i64::max_value());
+        self.credit = self.credit.saturating_sub(bytes);
+    }
+
+    /// If sending was blocked, the time at which it will be allowed again.
+    pub fn next_time(&self) -> Option<Instant> {
+        if self.blocked {
+            self.t
+                .map(|t| t + self.time_to_earn(min(1 - self.credit, self.burst)))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_fixture::now;
+
+    const RATE: u64 = 10_000;
+    const BURST: usize = 1_000;
+
+    #[test]
+    fn burst() {
+        let mut r = RateLimiter::new(RATE, BURST);
+        assert!(r.allowed(now()));
+        r.sent(BURST);
+        assert!(!r.allowed(now()));
+        // One byte at 10,000 bytes per second takes 100us.
+        assert_eq!(r.next_time(), Some(now() + Duration::from_nanos(100_000)));
+        assert!(r.allowed(now() + Duration::from_millis(1)));
+        assert_eq!(r.next_time(), None);
+    }
+
+    #[test]
+    fn debt() {
+        let mut r = RateLimiter::new(RATE, BURST);
+        assert!(r.allowed(now()));
+        r.sent(BURST * 2);
+        assert!(!r.allowed(now() + Duration::from_millis(50)));
+        // The debt is 500 bytes after 50ms, so it takes 50ms more to repay.
+        assert_eq!(
+            r.next_time(),
+            Some(now() + Duration::from_nanos(100_100_000))
+        );
+        assert!(!r.allowed(now() + Duration::from_millis(100)));
+        assert!(r.allowed(now() + Duration::from_millis(101)));
+    }
+
+    #[test]
+    fn no_rounding_loss() {
+        let mut r = RateLimiter::new(RATE, BURST);
+        assert!(r.allowed(now()));
+        r.sent(BURST);
+        // Call often enough that each call earns less than a byte.
+        let mut t = now();
+        for _ in 0..1000 {
+            t += Duration::from_micros(50);
+            r.allowed(t);
+        }
+        // 50ms at 10,000 bytes per second is 500 bytes.
+        assert_eq!(r.credit, 500);
+    }
+
+    #[test]
+    fn capped() {
+        let mut r = RateLimiter::new(RATE, BURST);
+        assert!(r.allowed(now()));
+        r.sent(1);
+        assert!(r.allowed(now() + Duration::from_secs(10)));
+        assert_eq!(r.credit, i64::try_from(BURST).unwrap());
+    }
+
+    #[test]
+    fn release_time() {
+        let horizon = Duration::from_millis(100);
+        let mut r = RateLimiter::new(RATE, BURST);
+        assert_eq!(r.release_time(now(), horizon), Some(now()));
+        r.sent(BURST * 2);
+        // The debt of 1000 bytes, plus one byte, takes 100.1ms to repay.
+        assert_eq!(r.release_time(now(), horizon), None);
+        assert!(r.next_time().is_some());
+        let t = now() + Duration::from_millis(50);
+        assert_eq!(
+            r.release_time(t, horizon),
+            Some(now() + Duration::from_nanos(100_100_000))
+        );
+        assert_eq!(r.next_time(), None);
+    }
+}
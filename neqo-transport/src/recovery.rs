@@ -17,6 +17,7 @@ use neqo_common::{qdebug, qinfo};
 
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
+use crate::multipath::MultipathRecoveryToken;
 use crate::send_stream::StreamRecoveryToken;
 use crate::tracking::{AckToken, PNSpace};
 use crate::State;
@@ -27,6 +28,21 @@ const GRANULARITY: Duration = Duration::from_millis(20);
 const INITIAL_RTT: Duration = Duration::from_millis(100);
 
 const PACKET_THRESHOLD: u64 = 3;
+/// The largest packet reordering threshold that spurious loss detection can
+/// raise the threshold to.
+const MAX_PACKET_THRESHOLD: u64 = 20;
+/// The time threshold for loss detection is expressed in eighths of an RTT.
+/// This starts at 9/8 and can increase to 2 as spurious losses are detected.
+const TIME_THRESHOLD: u32 = 9;
+const MAX_TIME_THRESHOLD: u32 = 16;
+/// The number of lost packets to remember in each space, for detecting
+/// spurious retransmissions.
+const MAX_TRACKED_LOST: usize = 256;
+
+/// The datagram size that the congestion window is counted in.
+const MAX_DATAGRAM_SIZE: usize = 1280;
+const INITIAL_WINDOW: usize = 10 * MAX_DATAGRAM_SIZE;
+const MINIMUM_WINDOW: usize = 2 * MAX_DATAGRAM_SIZE;
 
 #[derive(Debug)]
 pub(crate) enum RecoveryToken {
@@ -34,14 +50,19 @@ pub(crate) enum RecoveryToken {
     Stream(StreamRecoveryToken),
     Crypto(CryptoRecoveryToken),
     Flow(FlowControlRecoveryToken),
+    Multipath(MultipathRecoveryToken),
 }
 
 #[derive(Debug)]
 pub struct SentPacket {
     ack_eliciting: bool,
-    //in_flight: bool, // TODO needed only for cc
-    //size: u64, // TODO needed only for cc
+    /// The size of the datagram, which counts toward bytes in flight if
+    /// the packet is ack-eliciting.
+    size: usize,
     time_sent: Instant,
+    /// The number of times the probe timeout fired while this packet was
+    /// outstanding.
+    ptos: u32,
     pub(crate) tokens: Vec<RecoveryToken>,
 }
 
@@ -114,6 +135,15 @@ impl LossRecoveryState {
     pub fn mode(&self) -> LossRecoveryMode {
         self.mode
     }
+
+    /// The timer that loss recovery needs, if any.
+    pub fn timer(&self) -> Option<(TimerKind, Instant)> {
+        let kind = match self.mode {
+            LossRecoveryMode::LostPackets => TimerKind::LossDetection,
+            _ => TimerKind::Pto,
+        };
+        self.callback_time.map(|t| (kind, t))
+    }
 }
 
 impl Default for LossRecoveryState {
@@ -132,11 +162,143 @@ pub(crate) enum LossRecoveryMode {
     PTO,
 }
 
+/// A timer that a connection runs.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimerKind {
+    /// Packets will be declared lost because of the time threshold.
+    LossDetection,
+    /// The probe timeout, which fires if nothing is acknowledged.
+    Pto,
+    /// An acknowledgment is due.
+    Ack,
+    /// The send rate limit allows more to be sent.
+    RateLimit,
+    /// The connection will close because it was idle.
+    Idle,
+    /// The connection is closing or draining, and will then be closed.
+    Closing,
+}
+
+/// The state of loss recovery, for diagnosing a connection that appears to
+/// have stopped.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecoveryInfo {
+    /// The number of times the probe timeout fired since something was
+    /// last acknowledged.
+    pub pto_count: u32,
+    /// The probe timeout, before backoff is applied.
+    pub pto: Duration,
+    /// The smoothed round trip time.
+    pub rtt: Duration,
+    /// The number of packets that are waiting to be acknowledged in the
+    /// Initial, Handshake, and ApplicationData packet number spaces.
+    pub in_flight: [usize; 3],
+    /// The congestion window, in bytes.
+    pub congestion_window: usize,
+    /// The number of bytes in ack-eliciting packets that are waiting to be
+    /// acknowledged.
+    pub bytes_in_flight: usize,
+    /// The timer that fires next, and when.  If this is `None`, the
+    /// connection is dormant until something arrives or the application
+    /// does something.
+    pub next_timer: Option<(TimerKind, Instant)>,
+}
+
+/// A NewReno congestion controller, as in -recovery 7.
+#[derive(Debug)]
+struct CongestionControl {
+    congestion_window: usize,
+    bytes_in_flight: usize,
+    ssthresh: usize,
+    /// When the current recovery period started.  Losses of packets sent
+    /// before this don't reduce the window again.
+    recovery_start: Option<Instant>,
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW,
+            bytes_in_flight: 0,
+            ssthresh: usize::max_value(),
+            recovery_start: None,
+        }
+    }
+}
+
+impl CongestionControl {
+    fn in_recovery(&self, time_sent: Instant) -> bool {
+        self.recovery_start
+            .map_or(false, |start| time_sent <= start)
+    }
+
+    fn on_packet_sent(&mut self, packet: &SentPacket) {
+        if packet.ack_eliciting {
+            self.bytes_in_flight += packet.size;
+        }
+    }
+
+    /// Stop counting a packet that won't be acknowledged or declared lost.
+    fn discard(&mut self, packet: &SentPacket) {
+        if packet.ack_eliciting {
+            assert!(self.bytes_in_flight >= packet.size);
+            self.bytes_in_flight -= packet.size;
+        }
+    }
+
+    fn on_packets_acked(&mut self, packets: &[SentPacket]) {
+        for packet in packets.iter().filter(|p| p.ack_eliciting) {
+            self.discard(packet);
+            if self.in_recovery(packet.time_sent) {
+                continue;
+            }
+            if self.congestion_window < self.ssthresh {
+                // Slow start.
+                self.congestion_window += packet.size;
+            } else {
+                // Congestion avoidance.
+                self.congestion_window += MAX_DATAGRAM_SIZE * packet.size / self.congestion_window;
+            }
+        }
+    }
+
+    fn on_packets_lost(&mut self, now: Instant, packets: &[SentPacket]) {
+        for packet in packets {
+            self.discard(packet);
+        }
+        let last_lost = packets
+            .iter()
+            .filter(|p| p.ack_eliciting)
+            .map(|p| p.time_sent)
+            .max();
+        if let Some(last_lost) = last_lost {
+            if !self.in_recovery(last_lost) {
+                self.recovery_start = Some(now);
+                self.congestion_window = max(self.congestion_window / 2, MINIMUM_WINDOW);
+                self.ssthresh = self.congestion_window;
+                qinfo!("congestion window reduced to {}", self.congestion_window);
+            }
+        }
+    }
+}
+
+/// A record of a packet that was declared lost.
+#[derive(Debug, Clone, Copy)]
+struct LostPacket {
+    /// The largest acknowledged packet number at the time the loss was declared.
+    largest_acked: u64,
+    /// Whether the packet was declared lost because of the time threshold,
+    /// rather than the packet threshold.
+    by_time: bool,
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct LossRecoverySpace {
     tx_pn: u64,
     largest_acked: Option<u64>,
     sent_packets: BTreeMap<u64, SentPacket>,
+    /// Packets that were recently declared lost.
+    lost_packets: BTreeMap<u64, LostPacket>,
 }
 
 impl LossRecoverySpace {
@@ -170,12 +332,39 @@ impl LossRecoverySpace {
         (acked_packets, eliciting)
     }
 
+    /// Remember that a packet was declared lost.
+    fn declare_lost(&mut self, pn: u64, lost: LostPacket) {
+        self.lost_packets.insert(pn, lost);
+        if self.lost_packets.len() > MAX_TRACKED_LOST {
+            let oldest = *self.lost_packets.keys().next().unwrap();
+            self.lost_packets.remove(&oldest);
+        }
+    }
+
+    /// Find any packets that were previously declared lost that are now acknowledged.
+    fn remove_spurious(&mut self, acked_ranges: &[(u64, u64)]) -> Vec<(u64, LostPacket)> {
+        let mut spurious = Vec::new();
+        for &(end, start) in acked_ranges {
+            let found: Vec<u64> = self
+                .lost_packets
+                .range(start..=end)
+                .map(|(pn, _)| *pn)
+                .collect();
+            for pn in found {
+                qdebug!("spurious loss of {}", pn);
+                spurious.push((pn, self.lost_packets.remove(&pn).unwrap()));
+            }
+        }
+        spurious
+    }
+
     /// Remove all tracked packets from the space.
     /// This is called when 0-RTT packets are dropped at a client.
     fn remove_ignored(&mut self) -> impl Iterator<Item = SentPacket> {
         // The largest acknowledged or loss_time should still be unset.
         // The client should not have received any ACK frames when it drops 0-RTT.
         assert!(self.largest_acked.is_none());
+        self.lost_packets.clear();
         std::mem::replace(&mut self.sent_packets, Default::default())
             .into_iter()
             .map(|(_, v)| v)
@@ -219,6 +408,20 @@ pub(crate) struct LossRecovery {
 
     enable_timed_loss_detection: bool,
     spaces: LossRecoverySpaces,
+
+    /// The packet reordering threshold, which increases if reordering is
+    /// mistaken for loss.
+    packet_threshold: u64,
+    /// The time threshold, in eighths of an RTT.
+    time_threshold: u32,
+    /// The number of packets that were declared lost.
+    lost_count: u64,
+    /// The number of packets that were declared lost, but were later acknowledged.
+    spurious_count: u64,
+    cc: CongestionControl,
+    /// Whether a probe can be sent regardless of the congestion window,
+    /// because the probe timeout fired.
+    probe: bool,
 }
 
 impl LossRecovery {
@@ -230,6 +433,8 @@ impl LossRecovery {
                 latest_rtt: INITIAL_RTT,
                 ..RttVals::default()
             },
+            packet_threshold: PACKET_THRESHOLD,
+            time_threshold: TIME_THRESHOLD,
 
             ..LossRecovery::default()
         }
@@ -241,8 +446,101 @@ impl LossRecovery {
         val
     }
 
+    /// Reserve `count` packet numbers for use elsewhere, returning the first.
+    #[cfg(feature = "keepalive-offload")]
+    pub fn reserve_pns(&mut self, pn_space: PNSpace, count: u64) -> u64 {
+        let val = self.spaces[pn_space].tx_pn;
+        self.spaces[pn_space].tx_pn += count;
+        val
+    }
+
     pub fn increment_pto_count(&mut self) {
         self.pto_count += 1;
+        self.probe = true;
+        for space in &mut self.spaces.0 {
+            for packet in space.sent_packets.values_mut() {
+                if packet.ack_eliciting {
+                    packet.ptos += 1;
+                }
+            }
+        }
+    }
+
+    /// The number of times the probe timeout fired since something was last
+    /// acknowledged.
+    pub fn pto_count(&self) -> u32 {
+        self.pto_count
+    }
+
+    /// The largest number of times the probe timeout fired while any one
+    /// packet that is still outstanding was waiting to be acknowledged.
+    pub fn max_packet_pto_count(&self) -> u32 {
+        self.spaces
+            .0
+            .iter()
+            .flat_map(|space| space.sent_packets.values())
+            .map(|packet| packet.ptos)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The total number of packets declared lost.
+    pub fn lost_count(&self) -> u64 {
+        self.lost_count
+    }
+
+    /// The number of packets declared lost that were later acknowledged.
+    pub fn spurious_count(&self) -> u64 {
+        self.spurious_count
+    }
+
+    /// Whether the congestion window allows an ack-eliciting packet to be
+    /// sent.
+    pub fn can_send(&self) -> bool {
+        self.cc.bytes_in_flight < self.cc.congestion_window || self.probe
+    }
+
+    /// The current packet reordering threshold.
+    pub fn packet_threshold(&self) -> u64 {
+        self.packet_threshold
+    }
+
+    /// Describe the state of loss recovery.  The caller fills in `next_timer`.
+    pub fn info(&self) -> RecoveryInfo {
+        let in_flight = |space| self.spaces[space].sent_packets.len();
+        RecoveryInfo {
+            pto_count: self.pto_count,
+            pto: self.rtt_vals.pto(),
+            rtt: self.rtt_vals.rtt(),
+            in_flight: [
+                in_flight(PNSpace::Initial),
+                in_flight(PNSpace::Handshake),
+                in_flight(PNSpace::ApplicationData),
+            ],
+            congestion_window: self.cc.congestion_window,
+            bytes_in_flight: self.cc.bytes_in_flight,
+            next_timer: None,
+        }
+    }
+
+    /// Handle packets that were declared lost, but have since been acknowledged.
+    /// This means that the thresholds for declaring loss are too aggressive.
+    /// Adjust them so that the same amount of reordering is tolerated in future.
+    fn on_spurious_loss(&mut self, spurious: Vec<(u64, LostPacket)>) {
+        for (pn, lost) in spurious {
+            self.spurious_count += 1;
+            if lost.by_time {
+                self.time_threshold = min(self.time_threshold + 1, MAX_TIME_THRESHOLD);
+                qinfo!([self] "spurious loss of {}, time threshold now {}/8",
+                       pn, self.time_threshold);
+            } else {
+                let reordering = lost.largest_acked.saturating_sub(pn) + 1;
+                self.packet_threshold =
+                    min(max(self.packet_threshold, reordering), MAX_PACKET_THRESHOLD);
+                qinfo!([self] "spurious loss of {}, packet threshold now {}",
+                       pn, self.packet_threshold);
+            }
+        }
     }
 
     pub fn largest_acknowledged(&self, pn_space: PNSpace) -> Option<u64> {
@@ -253,8 +551,42 @@ impl LossRecovery {
         self.rtt_vals.pto()
     }
 
-    pub fn drop_0rtt(&mut self) -> impl Iterator<Item = SentPacket> {
-        self.spaces[PNSpace::ApplicationData].remove_ignored()
+    pub fn drop_0rtt(&mut self) -> Vec<SentPacket> {
+        let dropped: Vec<_> = self.spaces[PNSpace::ApplicationData]
+            .remove_ignored()
+            .collect();
+        for packet in &dropped {
+            self.cc.discard(packet);
+        }
+        dropped
+    }
+
+    /// Stop tracking packets in a space whose keys were discarded, so that
+    /// nothing in it is declared lost or retransmitted.
+    pub fn discard(&mut self, pn_space: PNSpace) {
+        qdebug!([self] "discard {:?}", pn_space);
+        let space = &mut self.spaces[pn_space];
+        for packet in space.sent_packets.values() {
+            self.cc.discard(packet);
+        }
+        space.sent_packets.clear();
+        space.lost_packets.clear();
+        self.pto_count = 0;
+    }
+
+    /// Remove everything that is outstanding in a space, so that it can be
+    /// sent elsewhere.  This is used when a path is abandoned.
+    pub fn drain(&mut self, pn_space: PNSpace) -> Vec<SentPacket> {
+        let space = &mut self.spaces[pn_space];
+        space.lost_packets.clear();
+        let drained: Vec<_> = std::mem::replace(&mut space.sent_packets, Default::default())
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        for packet in &drained {
+            self.cc.discard(packet);
+        }
+        drained
     }
 
     pub fn on_packet_sent(
@@ -263,21 +595,24 @@ impl LossRecovery {
         packet_number: u64,
         ack_eliciting: bool,
         tokens: Vec<RecoveryToken>,
+        size: usize,
         now: Instant,
     ) {
         qdebug!([self] "packet {:?}-{} sent.", pn_space, packet_number);
-        self.spaces[pn_space].sent_packets.insert(
-            packet_number,
-            SentPacket {
-                time_sent: now,
-                ack_eliciting,
-                tokens,
-            },
-        );
+        let packet = SentPacket {
+            time_sent: now,
+            ack_eliciting,
+            size,
+            ptos: 0,
+            tokens,
+        };
+        self.cc.on_packet_sent(&packet);
+        self.spaces[pn_space]
+            .sent_packets
+            .insert(packet_number, packet);
         if ack_eliciting {
             self.time_of_last_sent_ack_eliciting_packet = Some(now);
-            // TODO implement cc
-            //     cc.on_packet_sent(sent_bytes)
+            self.probe = false;
         }
     }
 
@@ -293,6 +628,9 @@ impl LossRecovery {
         qdebug!([self] "ack received for {:?} - largest_acked={}.",
                 pn_space, largest_acked);
 
+        let spurious = self.spaces[pn_space].remove_spurious(&acked_ranges);
+        self.on_spurious_loss(spurious);
+
         let (acked_packets, any_ack_eliciting) = self.spaces[pn_space].remove_acked(acked_ranges);
         if acked_packets.is_empty() {
             // No new information.
@@ -323,19 +661,20 @@ impl LossRecovery {
             .into_iter()
             .map(|(_k, v)| v)
             .collect::<Vec<_>>();
+        self.cc.on_packets_acked(&acked_packets);
 
         (acked_packets, lost_packets)
     }
 
     fn loss_delay(&self) -> Duration {
-        // kTimeThreshold = 9/8
+        // kTimeThreshold = 9/8, unless spurious loss has been detected
         // loss_delay = kTimeThreshold * max(latest_rtt, smoothed_rtt)
         // loss_delay = max(loss_delay, kGranularity)
         let rtt = match self.rtt_vals.smoothed_rtt {
             None => self.rtt_vals.latest_rtt,
             Some(smoothed_rtt) => max(self.rtt_vals.latest_rtt, smoothed_rtt),
         };
-        max(rtt * 9 / 8, GRANULARITY)
+        max(rtt * self.time_threshold / 8, GRANULARITY)
     }
 
     pub fn detect_lost_packets(&mut self, pn_space: PNSpace, now: Instant) -> Vec<SentPacket> {
@@ -349,6 +688,7 @@ impl LossRecovery {
             now, loss_delay, lost_deadline
         );
 
+        let packet_threshold = self.packet_threshold;
         let packet_space = &mut self.spaces[pn_space];
 
         let mut lost_pns = SmallVec::<[_; 8]>::new();
@@ -365,39 +705,46 @@ impl LossRecovery {
                     packet.time_sent,
                     lost_deadline
                 );
-                lost_pns.push(*pn);
-            } else if packet_space.largest_acked >= Some(*pn + PACKET_THRESHOLD) {
-                // Packets with packet numbers more than PACKET_THRESHOLD
+                lost_pns.push((*pn, true));
+            } else if packet_space.largest_acked >= Some(*pn + packet_threshold) {
+                // Packets with packet numbers more than packet_threshold
                 // before largest acked are deemed lost.
                 qdebug!(
                     "lost={}, is >= {} from largest acked {:?}",
                     pn,
-                    PACKET_THRESHOLD,
+                    packet_threshold,
                     packet_space.largest_acked
                 );
-                lost_pns.push(*pn);
+                lost_pns.push((*pn, false));
             } else {
                 // OOO but not quite lost yet. Set the timed loss detect timer
                 self.enable_timed_loss_detection = true;
             }
         }
 
+        let largest_acked = packet_space.largest_acked.unwrap_or(0);
         let mut lost_packets = Vec::with_capacity(lost_pns.len());
-        for pn in lost_pns {
+        for (pn, by_time) in lost_pns {
             let lost_packet = packet_space
                 .sent_packets
                 .remove(&pn)
                 .expect("PN must be in sent_packets");
+            packet_space.declare_lost(
+                pn,
+                LostPacket {
+                    largest_acked,
+                    by_time,
+                },
+            );
             lost_packets.push(lost_packet);
         }
-
-        // TODO
-        // Inform the congestion controller of lost packets.
+        self.lost_count += lost_packets.len() as u64;
+        self.cc.on_packets_lost(now, &lost_packets);
 
         lost_packets
     }
 
-    pub fn get_timer(&mut self, conn_state: &State) -> LossRecoveryState {
+    pub fn get_timer(&self, conn_state: &State) -> LossRecoveryState {
         qdebug!([self] "get_loss_detection_timer.");
 
         let has_ack_eliciting_out = self
@@ -543,13 +890,21 @@ mod tests {
 
     // In most of the tests below, packets are sent at a fixed cadence, with PACING between each.
     const PACING: Duration = ms!(7);
+    const PACKET_SIZE: usize = 1000;
     fn pn_time(pn: u64) -> Instant {
         ::test_fixture::now() + (PACING * pn.try_into().unwrap())
     }
 
     fn pace(lr: &mut LossRecovery, count: u64) {
         for pn in 0..count {
-            lr.on_packet_sent(PNSpace::ApplicationData, pn, true, Vec::new(), pn_time(pn));
+            lr.on_packet_sent(
+                PNSpace::ApplicationData,
+                pn,
+                true,
+                Vec::new(),
+                PACKET_SIZE,
+                pn_time(pn),
+            );
         }
     }
 
@@ -663,12 +1018,20 @@ mod tests {
         // So send two packets with 1/4 RTT between them.  Acknowledge pn 1 after 1 RTT.
         // pn 0 should then be marked lost because it is then outstanding for 5RTT/4
         // the loss time for packets is 9RTT/8.
-        lr.on_packet_sent(PNSpace::ApplicationData, 0, true, Vec::new(), pn_time(0));
+        lr.on_packet_sent(
+            PNSpace::ApplicationData,
+            0,
+            true,
+            Vec::new(),
+            PACKET_SIZE,
+            pn_time(0),
+        );
         lr.on_packet_sent(
             PNSpace::ApplicationData,
             1,
             true,
             Vec::new(),
+            PACKET_SIZE,
             pn_time(0) + INITIAL_RTT / 4,
         );
         let (_, lost) = lr.on_ack_received(
@@ -719,6 +1082,57 @@ mod tests {
         assert_no_sent_times(&lr);
     }
 
+    #[test]
+    fn spurious_packet_threshold() {
+        let mut lr = setup_lr(6);
+        // Acknowledge 5, which causes 1 and 2 to be declared lost.
+        let (_, lost) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            5,
+            vec![(5, 5)],
+            ACK_DELAY,
+            pn_time(5),
+        );
+        assert_eq!(lost.len(), 2);
+        assert_eq!(lr.lost_count(), 2);
+        assert_eq!(lr.spurious_count(), 0);
+
+        // Now acknowledge 1, which was only reordered.
+        let (acked, lost) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            5,
+            vec![(1, 1)],
+            ACK_DELAY,
+            pn_time(5),
+        );
+        assert!(acked.is_empty());
+        assert!(lost.is_empty());
+        assert_eq!(lr.spurious_count(), 1);
+        // 1 was declared lost when 5 was acknowledged, so tolerate that much reordering.
+        assert_eq!(lr.packet_threshold(), 5);
+    }
+
+    #[test]
+    fn spurious_time_threshold() {
+        let mut lr = setup_lr(3);
+        let before = lr.loss_delay();
+        let (_, lost) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            2,
+            vec![(2, 2)],
+            ACK_DELAY,
+            pn_time(2) + INITIAL_RTT,
+        );
+        assert!(lost.is_empty());
+        let packets =
+            lr.detect_lost_packets(PNSpace::ApplicationData, pn_time(1) + INITIAL_RTT * 2);
+        assert_eq!(packets.len(), 1);
+
+        let _ = ack(&mut lr, 1, INITIAL_RTT * 2);
+        assert_eq!(lr.spurious_count(), 1);
+        assert!(lr.loss_delay() > before);
+    }
+
     #[test]
     fn big_gap_loss() {
         let mut lr = setup_lr(5); // This sends packets 0-4 and acknowledges pn 0.
@@ -733,4 +1147,55 @@ mod tests {
         );
         assert_eq!(lost.len(), 1);
     }
+
+    #[test]
+    fn congestion_window() {
+        let mut lr = setup_lr(5);
+        // Acknowledging pn 0 grew the window in slow start.
+        assert_eq!(lr.info().congestion_window, INITIAL_WINDOW + PACKET_SIZE);
+        assert_eq!(lr.info().bytes_in_flight, PACKET_SIZE * 4);
+
+        // Losing pn 1 halves the window, and acknowledging packets that were
+        // sent before then doesn't grow it again.
+        let (acked, lost) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            4,
+            vec![(4, 2)],
+            ACK_DELAY,
+            pn_time(4),
+        );
+        assert_eq!(acked.len(), 3);
+        assert_eq!(lost.len(), 1);
+        let cwnd = (INITIAL_WINDOW + PACKET_SIZE) / 2;
+        assert_eq!(lr.info().congestion_window, cwnd);
+        assert_eq!(lr.info().bytes_in_flight, 0);
+
+        // Fill the window.
+        let mut pn = 5;
+        while lr.can_send() {
+            lr.on_packet_sent(
+                PNSpace::ApplicationData,
+                pn,
+                true,
+                Vec::new(),
+                PACKET_SIZE,
+                pn_time(pn),
+            );
+            pn += 1;
+        }
+        assert!(lr.info().bytes_in_flight >= cwnd);
+
+        // A probe can be sent when the probe timeout fires.
+        lr.increment_pto_count();
+        assert!(lr.can_send());
+        lr.on_packet_sent(
+            PNSpace::ApplicationData,
+            pn,
+            true,
+            Vec::new(),
+            PACKET_SIZE,
+            pn_time(pn),
+        );
+        assert!(!lr.can_send());
+    }
 }
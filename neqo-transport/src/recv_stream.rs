@@ -9,7 +9,7 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryInto;
 use std::mem;
 use std::ops::Bound::{Included, Unbounded};
@@ -334,6 +334,69 @@ impl RecvStreamState {
     }
 }
 
+#[derive(Debug)]
+struct ObservedData {
+    data: VecDeque<u8>,
+    limit: usize,
+    /// The number of bytes seen, including any that were dropped.
+    seen: u64,
+    dropped: u64,
+    fin: bool,
+}
+
+/// A copy of the data that the application reads from a stream, for
+/// something that only watches, like a logging proxy.  Data is held until it
+/// is taken, up to a limit; beyond that, the oldest data is dropped and
+/// counted.  Clones share the same data.
+#[derive(Clone, Debug)]
+pub struct StreamObserver {
+    inner: Rc<RefCell<ObservedData>>,
+}
+
+impl StreamObserver {
+    fn new(limit: usize) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(ObservedData {
+                data: VecDeque::new(),
+                limit,
+                seen: 0,
+                dropped: 0,
+                fin: false,
+            })),
+        }
+    }
+
+    fn observe(&self, data: &[u8], fin: bool) {
+        let mut o = self.inner.borrow_mut();
+        o.seen += data.len() as u64;
+        o.data.extend(data);
+        let excess = o.data.len().saturating_sub(o.limit);
+        if excess > 0 {
+            o.data.drain(..excess);
+            o.dropped += excess as u64;
+        }
+        o.fin |= fin;
+    }
+
+    /// Take the data that has been observed so far, along with the offset
+    /// in the stream of the first byte.
+    pub fn take(&self) -> (u64, Vec<u8>) {
+        let mut o = self.inner.borrow_mut();
+        let offset = o.seen - o.data.len() as u64;
+        (offset, o.data.drain(..).collect())
+    }
+
+    /// The number of bytes that were dropped because too much was held.
+    pub fn dropped(&self) -> u64 {
+        self.inner.borrow().dropped
+    }
+
+    /// Whether the end of the stream has been read.
+    pub fn fin(&self) -> bool {
+        self.inner.borrow().fin
+    }
+}
+
 /// Implement a QUIC receive stream.
 #[derive(Debug)]
 pub struct RecvStream {
@@ -341,6 +404,7 @@ pub struct RecvStream {
     state: RecvStreamState,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
+    observers: Vec<StreamObserver>,
 }
 
 impl RecvStream {
@@ -355,9 +419,18 @@ impl RecvStream {
             state: RecvStreamState::new(max_stream_data),
             flow_mgr,
             conn_events,
+            observers: Vec::new(),
         }
     }
 
+    /// Add an observer that gets a copy of everything that is read, and can
+    /// hold up to `limit` bytes.
+    pub fn observe(&mut self, limit: usize) -> StreamObserver {
+        let observer = StreamObserver::new(limit);
+        self.observers.push(observer.clone());
+        observer
+    }
+
     pub fn inbound_stream_frame(&mut self, fin: bool, offset: u64, data: Vec<u8>) -> Res<()> {
         let new_end = offset + data.len() as u64;
 
@@ -498,6 +571,11 @@ impl RecvStream {
             }
             RecvStreamState::DataRead | RecvStreamState::ResetRecvd => Err(Error::NoMoreData),
         };
+        if let Ok((bytes_read, fin)) = &res {
+            for o in &self.observers {
+                o.observe(&buf[..*bytes_read as usize], *fin);
+            }
+        }
         self.maybe_send_flowc_update();
         res
     }
@@ -572,6 +650,28 @@ mod tests {
         s.read(&mut buf).unwrap_err();
     }
 
+    #[test]
+    fn observer() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let conn_events = ConnectionEvents::default();
+        let mut s = RecvStream::new(4.into(), 1024, flow_mgr, conn_events);
+        let o = s.observe(8);
+        let mut buf = [0; 100];
+
+        s.inbound_stream_frame(false, 0, vec![1; 6]).unwrap();
+        assert_eq!(s.read(&mut buf).unwrap(), (6, false));
+        assert_eq!(o.take(), (0, vec![1; 6]));
+        assert_eq!(o.take(), (6, Vec::new()));
+
+        // Only the last 8 bytes are held if the observer falls behind.
+        s.inbound_stream_frame(true, 6, vec![2; 10]).unwrap();
+        assert_eq!(s.read(&mut buf).unwrap(), (10, true));
+        assert_eq!(buf[..10], [2; 10]);
+        assert_eq!(o.take(), (8, vec![2; 8]));
+        assert_eq!(o.dropped(), 2);
+        assert!(o.fin());
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_stream_rx_dedupe() {
@@ -0,0 +1,105 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Storage for resumption tokens.
+//
+// A resumption token is obtained from `Connection::resumption_token()` once a
+// client connection is established and a session ticket has arrived.  It
+// holds the TLS session ticket, the transport parameters of the server, and
+// any token that the server provided in a NEW_TOKEN frame.  Passing that to
+// `Connection::set_resumption_token()` on a new connection enables resumption
+// and 0-RTT.
+
+use std::collections::VecDeque;
+
+/// The default number of tokens held by `LruResumptionStore`.
+const DEFAULT_CAPACITY: usize = 32;
+
+/// Somewhere to keep resumption tokens, keyed by server name.
+/// Implement this to persist tokens across runs.
+pub trait ResumptionStore {
+    /// Save a token for the named server.
+    fn insert(&mut self, server_name: &str, token: Vec<u8>);
+    /// Remove and return the newest token for the named server.  Tokens are
+    /// taken rather than copied, because reusing a token allows connections
+    /// to be linked.
+    fn take(&mut self, server_name: &str) -> Option<Vec<u8>>;
+}
+
+/// An in-memory `ResumptionStore` that holds a limited number of tokens.
+/// When full, the token that was least recently added is discarded.
+#[derive(Debug)]
+pub struct LruResumptionStore {
+    capacity: usize,
+    /// Newest first.
+    tokens: VecDeque<(String, Vec<u8>)>,
+}
+
+impl LruResumptionStore {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity,
+            tokens: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl Default for LruResumptionStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl ResumptionStore for LruResumptionStore {
+    fn insert(&mut self, server_name: &str, token: Vec<u8>) {
+        self.tokens.push_front((server_name.to_string(), token));
+        self.tokens.truncate(self.capacity);
+    }
+
+    fn take(&mut self, server_name: &str) -> Option<Vec<u8>> {
+        let idx = self.tokens.iter().position(|(n, _)| n == server_name)?;
+        self.tokens.remove(idx).map(|(_, t)| t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_newest() {
+        let mut store = LruResumptionStore::default();
+        store.insert("a", vec![1]);
+        store.insert("b", vec![2]);
+        store.insert("a", vec![3]);
+        assert_eq!(store.take("a"), Some(vec![3]));
+        assert_eq!(store.take("a"), Some(vec![1]));
+        assert_eq!(store.take("a"), None);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let mut store = LruResumptionStore::new(2);
+        store.insert("a", vec![1]);
+        store.insert("b", vec![2]);
+        store.insert("c", vec![3]);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.take("a"), None);
+        assert_eq!(store.take("b"), Some(vec![2]));
+        assert_eq!(store.take("c"), Some(vec![3]));
+        assert!(store.is_empty());
+    }
+}
@@ -10,6 +10,8 @@ use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::collections::{hash_map::IterMut, BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
+use std::fmt::Debug;
+use std::iter;
 use std::mem;
 use std::rc::Rc;
 
@@ -299,6 +301,17 @@ impl TxBuffer {
         can_buffer
     }
 
+    /// Add up to `len` bytes from `source` to the TxBuffer, reading them
+    /// straight into the buffer.
+    fn send_from(&mut self, source: &mut dyn SendStreamSource, len: usize) -> usize {
+        let start = self.buffered();
+        let len = min(len, TxBuffer::BUFFER_SIZE - start);
+        self.send_buf.extend(iter::repeat(0).take(len));
+        let read = min(source.read(&mut self.send_buf[start..]), len);
+        self.send_buf.truncate(start + read);
+        read
+    }
+
     pub fn next_bytes(&self, _mode: TxMode) -> Option<(u64, &[u8])> {
         let (start, maybe_len) = self.ranges.first_unmarked_range();
         match (usize::try_from(start), usize::try_from(self.retired)) {
@@ -453,12 +466,30 @@ impl SendStreamState {
     }
 }
 
+/// Where a send stream gets its data from, if the application doesn't write
+/// it with `Connection::stream_send()`.  Data is read from the source as
+/// packets are built, so it can come straight from a file or a memory-mapped
+/// region without being buffered by the application first.
+/// See `Connection::stream_send_source()`.
+pub trait SendStreamSource: Debug {
+    /// Copy the next part of the stream into `buf`, and return how many
+    /// bytes were copied.  Return 0 if nothing is available yet; the source
+    /// is asked again the next time the connection has room to send.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Whether everything has been read.  The stream is closed once this is
+    /// true, so the end of the stream is sent with the last of the data.
+    fn done(&self) -> bool;
+}
+
 /// Implement a QUIC send stream.
 #[derive(Debug)]
 pub struct SendStream {
     stream_id: StreamId,
     max_stream_data: u64,
     state: SendStreamState,
+    priority: u8,
+    source: Option<Box<dyn SendStreamSource>>,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
 }
@@ -474,6 +505,8 @@ impl SendStream {
             stream_id,
             max_stream_data,
             state: SendStreamState::Ready,
+            priority: 0,
+            source: None,
             flow_mgr,
             conn_events,
         };
@@ -601,6 +634,15 @@ impl SendStream {
         self.max_stream_data
     }
 
+    /// Streams with a higher priority send their data first.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
     pub fn set_max_stream_data(&mut self, value: u64) {
         let stream_was_blocked = self.avail() == 0;
         self.max_stream_data = max(self.max_stream_data, value);
@@ -634,6 +676,10 @@ impl SendStream {
             qerror!("zero-length send on stream {}", self.stream_id.as_u64());
             return Err(Error::InvalidInput);
         }
+        if self.source.is_some() {
+            qerror!("send on stream {} with a source", self.stream_id.as_u64());
+            return Err(Error::InvalidInput);
+        }
 
         if let SendStreamState::Ready = self.state {
             self.state.transition(SendStreamState::Send {
@@ -662,6 +708,41 @@ impl SendStream {
         Ok(sent)
     }
 
+    /// Send the data from `source` after anything that was already sent,
+    /// then end the stream.
+    pub fn set_source(&mut self, source: Box<dyn SendStreamSource>) -> Res<()> {
+        match self.state {
+            SendStreamState::Ready => self.state.transition(SendStreamState::Send {
+                send_buf: TxBuffer::new(),
+            }),
+            SendStreamState::Send { .. } => (),
+            _ => return Err(Error::FinalSizeError),
+        }
+        self.source = Some(source);
+        Ok(())
+    }
+
+    /// Take up to `len` bytes from the source, if there is one and the
+    /// stream has nothing else to send.
+    fn read_source(&mut self, mode: TxMode, len: usize) {
+        if self.source.is_none() || self.next_bytes(mode).is_some() {
+            return;
+        }
+        let len = min(usize::try_from(self.avail()).unwrap_or(len), len);
+        let source = self.source.as_mut().unwrap();
+        if let SendStreamState::Send { send_buf } = &mut self.state {
+            let read = send_buf.send_from(source.as_mut(), len);
+            self.flow_mgr
+                .borrow_mut()
+                .conn_increase_credit_used(read as u64);
+        }
+        if source.done() {
+            qtrace!("source for stream {} is done", self.stream_id.as_u64());
+            self.source = None;
+            self.close();
+        }
+    }
+
     pub fn close(&mut self) {
         match &mut self.state {
             SendStreamState::Ready => {
@@ -688,6 +769,7 @@ impl SendStream {
     }
 
     pub fn reset(&mut self, err: AppError) {
+        self.source = None;
         match &self.state {
             SendStreamState::Ready => {
                 self.flow_mgr
@@ -781,43 +863,51 @@ impl SendStreams {
             return None;
         }
 
-        for (stream_id, stream) in self {
-            let fin = stream.final_size();
-            if let Some((offset, data)) = stream.next_bytes(mode) {
-                qtrace!(
-                    "Stream {} sending bytes {}-{}, epoch {}, mode {:?}, remaining {}",
-                    stream_id.as_u64(),
-                    offset,
-                    offset + data.len() as u64,
-                    epoch,
-                    mode,
-                    remaining
-                );
-                let frame_hdr_len = stream_frame_hdr_len(*stream_id, offset, remaining);
-                let length = min(data.len(), remaining - frame_hdr_len);
-                let fin = match fin {
-                    None => false,
-                    Some(fin) => fin == offset + length as u64,
-                };
-                let frame = Frame::Stream {
-                    fin,
-                    stream_id: stream_id.as_u64(),
-                    offset,
-                    data: data[..length].to_vec(),
-                };
-                stream.mark_as_sent(offset, length, fin);
-                return Some((
-                    frame,
-                    Some(RecoveryToken::Stream(StreamRecoveryToken {
-                        id: *stream_id,
-                        offset,
-                        length,
-                        fin,
-                    })),
-                ));
+        // Take data from the stream with the highest priority.
+        let mut next: Option<(StreamId, u8)> = None;
+        for (stream_id, stream) in self.0.iter_mut() {
+            stream.read_source(mode, remaining);
+            if stream.next_bytes(mode).is_some()
+                && next.map_or(true, |(_, priority)| stream.priority > priority)
+            {
+                next = Some((*stream_id, stream.priority));
             }
         }
-        None
+        let (stream_id, _) = next?;
+        let stream = self.0.get_mut(&stream_id).unwrap();
+        let fin = stream.final_size();
+        let (offset, data) = stream.next_bytes(mode).unwrap();
+        qtrace!(
+            "Stream {} sending bytes {}-{}, epoch {}, mode {:?}, remaining {}",
+            stream_id.as_u64(),
+            offset,
+            offset + data.len() as u64,
+            epoch,
+            mode,
+            remaining
+        );
+        let frame_hdr_len = stream_frame_hdr_len(stream_id, offset, remaining);
+        let length = min(data.len(), remaining - frame_hdr_len);
+        let fin = match fin {
+            None => false,
+            Some(fin) => fin == offset + length as u64,
+        };
+        let frame = Frame::Stream {
+            fin,
+            stream_id: stream_id.as_u64(),
+            offset,
+            data: data[..length].to_vec(),
+        };
+        stream.mark_as_sent(offset, length, fin);
+        Some((
+            frame,
+            Some(RecoveryToken::Stream(StreamRecoveryToken {
+                id: stream_id,
+                offset,
+                length,
+                fin,
+            })),
+        ))
     }
 }
 
@@ -1047,4 +1137,28 @@ mod tests {
         assert_eq!(evts.len(), 1);
         assert!(matches!(evts[0], ConnectionEvent::SendStreamWritable{..}));
     }
+
+    #[test]
+    fn send_priority() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+
+        let mut streams = SendStreams::default();
+        for id in &[2, 6, 10] {
+            let mut s = SendStream::new((*id).into(), 100, flow_mgr.clone(), conn_events.clone());
+            assert_eq!(s.send(b"hi").unwrap(), 2);
+            streams.insert((*id).into(), s);
+        }
+        streams.get_mut(6.into()).unwrap().set_priority(2);
+        streams.get_mut(10.into()).unwrap().set_priority(1);
+
+        let mut sent = Vec::new();
+        while let Some((frame, _)) = streams.get_frame(3, TxMode::Normal, 100) {
+            if let Frame::Stream { stream_id, .. } = frame {
+                sent.push(stream_id);
+            }
+        }
+        assert_eq!(sent, vec![6, 10, 2]);
+    }
 }
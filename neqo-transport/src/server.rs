@@ -6,21 +6,43 @@
 
 // This file implements a server that can handle multiple connections.
 
-use neqo_common::{hex, matches, qinfo, qtrace, qwarn, timer::Timer, Datagram, Decoder};
-use neqo_crypto::AntiReplay;
+use neqo_common::{
+    hex, matches, qdebug, qinfo, qtrace, qwarn,
+    timer::{Timer, TimerToken},
+    BufferPool, Datagram, Decoder, Encoder, Redact,
+};
+use neqo_crypto::aead::Aead;
+use neqo_crypto::{
+    AlpnSelector, AntiReplay, CertificateStatus, SecretListener, ZeroRttChecker,
+    TLS_AES_128_GCM_SHA256,
+};
+use rand::Rng;
 
-use crate::connection::{Connection, ConnectionIdManager, Output, State};
+use crate::connection::{Connection, ConnectionIdManager, Output, OutputIter, Role, State};
+use crate::crypto::{CryptoDxDirection, CryptoDxState};
+use crate::grease::Grease;
 use crate::packet::{
-    decode_packet_hdr, encode_packet_vn, encode_retry, ConnectionId, ConnectionIdDecoder,
-    PacketHdr, PacketType, Version,
+    classify_packet, decode_packet_hdr, decrypt_packet, encode_packet_vn, encode_retry,
+    ConnectionId, ConnectionIdDecoder, FixedLengthConnectionIdDecoder, PacketHdr,
+    PacketNumberDecoder, PacketType, Version, PACKET_BIT_LONG, PACKET_TYPE_HANDSHAKE,
 };
-use crate::QUIC_VERSION;
+use crate::qlog::Qlog;
+use crate::ratelimit::RateLimiter;
+use crate::stateless_reset::{reset_token, RESET_TOKEN_LEN};
+use crate::{Error, Res, QUIC_VERSION};
 
 use std::cell::RefCell;
+use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::fs::File;
 use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 pub enum InitialResult {
@@ -34,7 +56,18 @@ pub enum InitialResult {
 const MIN_INITIAL_PACKET_SIZE: usize = 1200;
 const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
 const TIMER_CAPACITY: usize = 16384;
-const FIXED_TOKEN: &[u8] = &[1, 2, 3];
+/// How long a client has to use the token from a Retry packet.
+const RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+/// The length of the counter that starts a Retry token, which is the nonce
+/// for the rest of the token.
+const RETRY_TOKEN_COUNTER_LEN: usize = 8;
+/// The most networks that connection attempts are counted for.
+const MAX_RETRY_PREFIXES: usize = 4096;
+/// The largest stateless reset that is sent.  Resets are always smaller than
+/// the packet that prompted them, so that two endpoints can't loop.
+const MAX_STATELESS_RESET: usize = 42;
+/// The smallest packet that a stateless reset is sent for.
+const MIN_STATELESS_RESET_TRIGGER: usize = 22;
 
 type StateRef = Rc<RefCell<ServerConnectionState>>;
 type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
@@ -43,7 +76,15 @@ type ConnectionTableRef = Rc<RefCell<HashMap<ConnectionId, StateRef>>>;
 #[derive(Debug)]
 struct ServerConnectionState {
     c: Connection,
-    last_timer: Instant,
+    /// An identifier that is unique for this server.
+    id: u64,
+    /// The entry for this connection in the server timers, if any.
+    timer: Option<TimerToken>,
+    /// The connection ID manager, which knows which connection IDs
+    /// route to this connection.
+    cid_mgr: Rc<RefCell<ServerConnectionIdManager>>,
+    /// Set once the server has forgotten this connection.
+    removed: bool,
 }
 
 impl Deref for ServerConnectionState {
@@ -59,6 +100,122 @@ impl DerefMut for ServerConnectionState {
     }
 }
 
+/// The network that an address is in, for counting connection attempts:
+/// a /24 for IPv4 and a /48 for IPv6.
+fn source_prefix(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(a) => {
+            let o = a.octets();
+            IpAddr::V4(Ipv4Addr::new(o[0], o[1], o[2], 0))
+        }
+        IpAddr::V6(a) => {
+            let s = a.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Limits on connection attempts that haven't been validated.  Attempts
+/// over the limits are sent a Retry.  Each limit allows bursts of up to a
+/// second's worth of attempts.
+struct AttemptLimiter {
+    total: RateLimiter,
+    per_prefix: u64,
+    prefixes: HashMap<IpAddr, RateLimiter>,
+    /// Whether the total limit was exceeded by the last attempt.
+    flooded: bool,
+}
+
+impl AttemptLimiter {
+    fn limiter(rate: u64) -> RateLimiter {
+        RateLimiter::new(rate, usize::try_from(rate).unwrap_or(usize::max_value()))
+    }
+
+    fn new(total: u64, per_prefix: u64) -> Self {
+        Self {
+            total: Self::limiter(total),
+            per_prefix,
+            prefixes: HashMap::new(),
+            flooded: false,
+        }
+    }
+
+    /// Count an attempt from `src`.  Returns false if it is over a limit.
+    fn admit(&mut self, src: IpAddr, now: Instant) -> bool {
+        let flooded = !self.total.allowed(now);
+        if flooded != self.flooded {
+            if flooded {
+                qinfo!("Too many connection attempts, sending Retry");
+            } else {
+                qinfo!("Connection attempts have subsided, no longer sending Retry");
+            }
+            self.flooded = flooded;
+        }
+        if flooded {
+            return false;
+        }
+
+        let prefix = source_prefix(src);
+        if !self.prefixes.contains_key(&prefix) && self.prefixes.len() >= MAX_RETRY_PREFIXES {
+            // Forget networks that have been quiet for a while.
+            self.prefixes.retain(|_, r| !r.full(now));
+            if self.prefixes.len() >= MAX_RETRY_PREFIXES {
+                return false;
+            }
+        }
+        let per_prefix = self.per_prefix;
+        let limit = self
+            .prefixes
+            .entry(prefix)
+            .or_insert_with(|| Self::limiter(per_prefix));
+        if !limit.allowed(now) {
+            qtrace!("Too many connection attempts from {}", prefix);
+            return false;
+        }
+        limit.sent(1);
+        self.total.sent(1);
+        true
+    }
+}
+
+/// Check that the Initial packet at the start of `dgram` can be decrypted,
+/// without making a connection.
+fn initial_decrypts(mut hdr: PacketHdr, dgram: &[u8]) -> bool {
+    let version = match hdr.version {
+        Some(v) => v,
+        None => return false,
+    };
+    let rx =
+        match CryptoDxState::new_initial(version, CryptoDxDirection::Read, Role::Client, &hdr.dcid)
+        {
+            Some(rx) => rx,
+            None => return false,
+        };
+    let mut body = Vec::new();
+    decrypt_packet(
+        &rx,
+        PacketNumberDecoder::new(None),
+        &mut hdr,
+        dgram,
+        &mut body,
+    )
+    .is_ok()
+}
+
+/// A `RetryTokenChecker` adds its own data to the tokens in Retry packets,
+/// and checks that data when a client returns the token.  Tokens are already
+/// sealed with a key that only the server has, bound to the client address,
+/// and only valid for a short time.  This could add a cheap puzzle or a proof
+/// of some sort, so that clients have to do more than receive packets at
+/// their address to connect.
+pub trait RetryTokenChecker: Debug {
+    /// Make the data to put in a token for the client at `peer`.
+    fn generate(&self, peer: SocketAddr, now: Instant) -> Vec<u8>;
+    /// Check the data that the client at `peer` returned in a token.  The
+    /// Initial packet is dropped if this returns false.
+    fn check(&self, data: &[u8], peer: SocketAddr, now: Instant) -> bool;
+}
+
 enum RetryTokenResult {
     Pass,
     Valid(ConnectionId),
@@ -66,36 +223,200 @@ enum RetryTokenResult {
     Invalid,
 }
 
-// TODO(mt) self-encrypt tokens
-#[derive(Default)]
+/// The key that Retry tokens are sealed with.  Each server makes its own, so
+/// servers that can get Initial packets from each other's clients, such as
+/// the workers of one process, need to be given the same key.
+#[derive(Clone, Copy)]
+pub struct RetryTokenKey {
+    key: [u8; 16],
+    /// Tokens record when they were made as a time since this, so that any
+    /// server with the key agrees on how old a token is.
+    epoch: Instant,
+}
+
+impl RetryTokenKey {
+    /// Make a new key, for tokens that are made from `now` on.
+    pub fn generate(now: Instant) -> Self {
+        let mut key = [0; 16];
+        rand::thread_rng().fill(&mut key[..]);
+        Self { key, epoch: now }
+    }
+}
+
+impl Debug for RetryTokenKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "RetryTokenKey")
+    }
+}
+
 struct RetryToken {
     require_retry: bool,
+    /// If set, Retry is only required when there are too many attempts.
+    limits: Option<AttemptLimiter>,
+    /// Adds to tokens and checks them, if set.
+    checker: Option<Rc<dyn RetryTokenChecker>>,
+    /// The key that tokens are sealed with, so that only this server, and
+    /// others that were given the key, can make tokens that it accepts.
+    key: RetryTokenKey,
+    /// The nonce for the next token.  This starts at a random value so that
+    /// servers that share a key don't use the same nonces.
+    counter: u64,
 }
 
 impl RetryToken {
-    pub fn generate_token(&mut self, dcid: &ConnectionId) -> Vec<u8> {
-        let mut token = Vec::from(FIXED_TOKEN);
-        token.extend_from_slice(dcid);
-        token
+    pub fn new(now: Instant) -> Self {
+        Self {
+            require_retry: false,
+            limits: None,
+            checker: None,
+            key: RetryTokenKey::generate(now),
+            counter: rand::thread_rng().gen(),
+        }
+    }
+
+    fn aead(&self) -> Res<Aead> {
+        // The nonce is the counter, so the IV can be fixed.
+        Ok(Aead::with_key(
+            TLS_AES_128_GCM_SHA256,
+            &self.key.key,
+            &[0; 12],
+        )?)
+    }
+
+    /// The address of the client, which a token is bound to.
+    fn peer_aad(peer: SocketAddr) -> Vec<u8> {
+        let mut aad = match peer.ip() {
+            IpAddr::V4(a) => a.octets().to_vec(),
+            IpAddr::V6(a) => a.octets().to_vec(),
+        };
+        aad.extend_from_slice(&peer.port().to_be_bytes());
+        aad
+    }
+
+    /// A token is a counter, which is the nonce, then the rest of the token
+    /// sealed with AES-GCM, using the client address as AAD.  Inside is the
+    /// time the token was made, relative to the epoch of the key, the
+    /// original destination connection ID, and anything from the checker.
+    pub fn generate_token(
+        &mut self,
+        dcid: &ConnectionId,
+        peer: SocketAddr,
+        now: Instant,
+    ) -> Res<Vec<u8>> {
+        let made = if now > self.key.epoch {
+            now - self.key.epoch
+        } else {
+            Duration::from_secs(0)
+        };
+        let mut enc = Encoder::default();
+        enc.encode_uint(8, u64::try_from(made.as_millis())?);
+        enc.encode_vec(1, dcid);
+        if let Some(c) = &self.checker {
+            enc.encode(&c.generate(peer, now));
+        }
+
+        let aead = self.aead()?;
+        let counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+        let mut token = vec![0; RETRY_TOKEN_COUNTER_LEN + enc.len() + aead.expansion()];
+        token[..RETRY_TOKEN_COUNTER_LEN].copy_from_slice(&counter.to_be_bytes());
+        let len = aead
+            .encrypt(
+                counter,
+                &Self::peer_aad(peer),
+                &enc[..],
+                &mut token[RETRY_TOKEN_COUNTER_LEN..],
+            )?
+            .len();
+        token.truncate(RETRY_TOKEN_COUNTER_LEN + len);
+        Ok(token)
+    }
+
+    pub fn set_checker(&mut self, checker: Rc<dyn RetryTokenChecker>) {
+        self.checker = Some(checker);
+    }
+
+    pub fn set_key(&mut self, key: RetryTokenKey) {
+        self.key = key;
+    }
+
+    /// Open a token, which only works if it came from a server with the same
+    /// key and is used from the address that it was given to.
+    fn open_token(&self, token: &[u8], peer: SocketAddr) -> Option<Vec<u8>> {
+        if token.len() < RETRY_TOKEN_COUNTER_LEN {
+            return None;
+        }
+        let (counter, sealed) = token.split_at(RETRY_TOKEN_COUNTER_LEN);
+        let counter = Decoder::from(counter).decode_uint(RETRY_TOKEN_COUNTER_LEN)?;
+        let aead = self.aead().ok()?;
+        let mut buf = vec![0; sealed.len()];
+        let len = aead
+            .decrypt(counter, &Self::peer_aad(peer), sealed, &mut buf)
+            .ok()?
+            .len();
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    /// Check an opened token: that it hasn't expired, and anything that the
+    /// checker added.
+    fn decode_token(&self, plain: &[u8], peer: SocketAddr, now: Instant) -> Option<ConnectionId> {
+        let mut dec = Decoder::from(plain);
+        let issued = self.key.epoch + Duration::from_millis(dec.decode_uint(8)?);
+        if now >= issued + RETRY_TOKEN_LIFETIME {
+            qtrace!("Retry token from {} has expired", Redact(peer));
+            return None;
+        }
+        let cid = ConnectionId::from(dec.decode_vec(1)?);
+        let data = dec.decode_remainder();
+        let ok = match &self.checker {
+            Some(c) => c.check(data, peer, now),
+            None => data.is_empty(),
+        };
+        if ok {
+            Some(cid)
+        } else {
+            qtrace!("Retry token from {} was rejected", Redact(peer));
+            None
+        }
     }
 
     pub fn set_retry_required(&mut self, retry: bool) {
         self.require_retry = retry;
+        self.limits = None;
+    }
+
+    pub fn set_retry_limits(&mut self, total: u64, per_prefix: u64) {
+        self.require_retry = false;
+        self.limits = Some(AttemptLimiter::new(total, per_prefix));
     }
 
-    pub fn validate(&self, hdr: &PacketHdr) -> RetryTokenResult {
+    pub fn retry_required(&self) -> bool {
+        self.require_retry || self.limits.as_ref().map_or(false, |l| l.flooded)
+    }
+
+    pub fn validate(&mut self, hdr: &PacketHdr, src: SocketAddr, now: Instant) -> RetryTokenResult {
         if let PacketType::Initial(token) = &hdr.tipe {
-            if token.is_empty() {
-                if self.require_retry {
-                    RetryTokenResult::Validate
-                } else {
-                    RetryTokenResult::Pass
-                }
-            } else if token[0..FIXED_TOKEN.len()] == FIXED_TOKEN[..] {
-                let cid = ConnectionId::from(&token[FIXED_TOKEN.len()..]);
-                RetryTokenResult::Valid(cid)
+            // A token that can't be opened might be from NEW_TOKEN, from
+            // another server, or from before a restart.  That is treated as
+            // if there were no token; see Section 8.1.3 of RFC 9000.
+            if let Some(plain) = self.open_token(token, src) {
+                return match self.decode_token(&plain, src, now) {
+                    Some(cid) => RetryTokenResult::Valid(cid),
+                    None => RetryTokenResult::Invalid,
+                };
+            }
+            if !token.is_empty() {
+                qtrace!("Retry token from {} is not recognized", Redact(src));
+            }
+            let admit = match &mut self.limits {
+                Some(l) => l.admit(src.ip(), now),
+                None => !self.require_retry,
+            };
+            if admit {
+                RetryTokenResult::Pass
             } else {
-                RetryTokenResult::Invalid
+                RetryTokenResult::Validate
             }
         } else {
             RetryTokenResult::Invalid
@@ -103,6 +424,55 @@ impl RetryToken {
     }
 }
 
+/// Connection IDs for a server that runs a `Server` on each of several
+/// threads.  The first byte of every connection ID is the index of the worker
+/// that made it, so that packets can be passed to the worker that has the
+/// connection if they arrive at another, such as when the client address
+/// changes.
+#[derive(Debug)]
+pub struct WorkerConnectionIdManager {
+    worker: u8,
+    len: usize,
+}
+
+impl WorkerConnectionIdManager {
+    /// `len` includes the byte that identifies the worker.
+    pub fn new(worker: u8, len: usize) -> Self {
+        assert!(
+            len > 1,
+            "connection IDs need space for more than the worker"
+        );
+        Self { worker, len }
+    }
+
+    /// The worker that has the connection `dgram` is for, where connection
+    /// IDs are `len` bytes long.  This is `None` for Initial and 0-RTT
+    /// packets, which use a connection ID that the client picked; any worker
+    /// can take those.
+    pub fn worker(dgram: &[u8], len: usize) -> Option<u8> {
+        let first = *dgram.first()?;
+        if first & PACKET_BIT_LONG != 0 && (first >> 4) & 0x3 != PACKET_TYPE_HANDSHAKE {
+            return None;
+        }
+        let dcid = classify_packet(dgram, len)?.dcid();
+        if dcid.len() != len {
+            return None;
+        }
+        dcid.first().cloned()
+    }
+}
+
+impl ConnectionIdManager for WorkerConnectionIdManager {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut cid = ConnectionId::generate(self.len);
+        cid.0[0] = self.worker;
+        cid
+    }
+    fn decoder(&self) -> Arc<dyn ConnectionIdDecoder> {
+        Arc::new(FixedLengthConnectionIdDecoder::new(self.len))
+    }
+}
+
 pub struct Server {
     /// The version this server supports (currently just one).
     version: Version,
@@ -110,9 +480,15 @@ pub struct Server {
     certs: Vec<String>,
     /// The ALPN values that the server supports.
     protocols: Vec<String>,
+    /// The names of certificates to use for particular server names.
+    server_certs: HashMap<String, Vec<String>>,
+    /// The status to send with certificates, keyed by their DER encoding.
+    certificate_status: HashMap<Vec<u8>, CertificateStatus>,
     anti_replay: AntiReplay,
     /// A connection ID manager.
     cid_manager: CidMgr,
+    /// Decodes connection IDs from packets, taken from `cid_manager`.
+    cid_decoder: Arc<dyn ConnectionIdDecoder>,
     /// All connections, keyed by ConnectionId.
     connections: ConnectionTableRef,
     /// The connections that have new events.
@@ -124,6 +500,32 @@ pub struct Server {
     /// Whether a Retry packet will be sent in response to new
     /// Initial packets.
     retry: RetryToken,
+    /// If set, this is used to seed greasing for each new connection.
+    grease: Option<Grease>,
+    /// The application policy for accepting 0-RTT, if any.
+    zero_rtt_checker: Option<Rc<dyn ZeroRttChecker>>,
+    /// The application policy for choosing a protocol, if any.
+    alpn_selector: Option<Rc<dyn AlpnSelector>>,
+    /// Where the secrets of new connections go, if anywhere.
+    secret_listener: Option<Rc<dyn SecretListener>>,
+    /// Whether connections are only made once the client address is
+    /// validated and its Initial packet can be decrypted.
+    stateless: bool,
+    /// Buffers for datagrams, shared by all connections.
+    buffers: Option<BufferPool>,
+    /// Where qlog traces of new connections are written, if anywhere.
+    qlog_dir: Option<PathBuf>,
+    /// The key for stateless reset tokens, if any.
+    reset_key: Option<Vec<u8>>,
+    /// Whether new connections accept hybrid post-quantum key exchange.
+    #[cfg(feature = "pq-hybrid")]
+    pq_hybrid: bool,
+    /// How much work `process_with_budget()` has been allowed to do.
+    work_budget: usize,
+    /// The identifier for the next connection.
+    next_id: u64,
+    /// The number of connections that haven't closed yet.
+    connection_count: usize,
 }
 
 impl Server {
@@ -145,13 +547,29 @@ impl Server {
             version: QUIC_VERSION,
             certs: certs.iter().map(|x| String::from(x.as_ref())).collect(),
             protocols: protocols.iter().map(|x| String::from(x.as_ref())).collect(),
+            server_certs: HashMap::new(),
+            certificate_status: HashMap::new(),
             anti_replay,
+            cid_decoder: cid_manager.borrow().decoder(),
             cid_manager,
             connections: Rc::new(RefCell::new(Default::default())),
             active: Default::default(),
             waiting: Default::default(),
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
-            retry: Default::default(),
+            retry: RetryToken::new(now),
+            grease: None,
+            zero_rtt_checker: None,
+            alpn_selector: None,
+            secret_listener: None,
+            stateless: false,
+            buffers: None,
+            qlog_dir: None,
+            reset_key: None,
+            #[cfg(feature = "pq-hybrid")]
+            pq_hybrid: false,
+            work_budget: usize::max_value(),
+            next_id: 0,
+            connection_count: 0,
         }
     }
 
@@ -173,9 +591,190 @@ impl Server {
         self.retry.set_retry_required(require_retry);
     }
 
+    /// Only send Retry when connections are attempted too quickly: more
+    /// than `total` attempts per second overall, or more than `per_prefix`
+    /// from any one network (a /24 for IPv4 or a /48 for IPv6).  Bursts of
+    /// up to a second's worth of attempts are allowed.  Once attempts slow
+    /// down, connections are accepted without Retry again.  This replaces
+    /// any setting from `set_retry_required()`.
+    pub fn set_retry_limits(&mut self, total: u64, per_prefix: u64) -> Res<()> {
+        if total == 0 || per_prefix == 0 {
+            return Err(Error::InvalidInput);
+        }
+        self.retry.set_retry_limits(total, per_prefix);
+        Ok(())
+    }
+
+    /// Whether all new connections are sent a Retry, either because that
+    /// was required, or because there have been too many attempts.
+    pub fn retry_required(&self) -> bool {
+        self.retry.retry_required()
+    }
+
+    /// Don't keep any state for a client until it has shown that it can
+    /// receive packets at its address, and sent an Initial packet that can
+    /// be decrypted.  All new connections are sent a Retry, and Initial
+    /// packets that come back with a token are decrypted before a
+    /// `Connection` is made for them; packets that fail are dropped.  This
+    /// makes it hard to exhaust server memory with bogus connection attempts,
+    /// at the cost of an extra round trip for every connection.
+    pub fn set_stateless(&mut self, stateless: bool) {
+        self.stateless = stateless;
+    }
+
+    /// Add data from `checker` to Retry tokens, and only accept connections
+    /// when `checker` is happy with what comes back.  This doesn't change
+    /// when Retry is sent, see `set_retry_required()` and `set_retry_limits()`.
+    pub fn set_retry_checker(&mut self, checker: impl RetryTokenChecker + 'static) {
+        self.retry.set_checker(Rc::new(checker));
+    }
+
+    /// Seal Retry tokens with `key` instead of a key that this server made,
+    /// so that tokens from other servers with the same key are accepted.
+    pub fn set_retry_token_key(&mut self, key: RetryTokenKey) {
+        self.retry.set_key(key);
+    }
+
+    /// Give new connections a stateless reset token that is made from `key`,
+    /// and send a stateless reset in response to short header packets for
+    /// connections that the server doesn't have.  Tokens are made with
+    /// `reset_token()`, so anything else with the same key can reset these
+    /// connections too.
+    pub fn set_stateless_reset_key(&mut self, key: &[u8]) {
+        self.reset_key = Some(key.to_vec());
+    }
+
+    /// Accept hybrid post-quantum key exchange on new connections.
+    /// See `Connection::enable_pq_hybrid()`.
+    #[cfg(feature = "pq-hybrid")]
+    pub fn enable_pq_hybrid(&mut self) {
+        self.pq_hybrid = true;
+    }
+
+    /// Enable greasing on new connections.  Each connection is seeded
+    /// from a sequence that is determined by `seed`.
+    /// See `Connection::enable_grease()`.
+    pub fn enable_grease(&mut self, seed: u64) {
+        self.grease = Some(Grease::new(seed));
+    }
+
+    /// Set the application policy for accepting 0-RTT on new connections.
+    /// Early data is only accepted when the transport parameters that were
+    /// remembered with the session ticket are compatible with the current
+    /// ones and `checker` accepts the application part of the ticket.
+    /// Each connection uses the anti-replay context of the server.
+    pub fn enable_zero_rtt(&mut self, checker: impl ZeroRttChecker + 'static) {
+        self.zero_rtt_checker = Some(Rc::new(checker));
+    }
+
+    /// Choose the application protocol for new connections with `selector`,
+    /// instead of using the protocols that the server was created with.
+    /// The selector sees what each client offers and the server name it
+    /// used, so one server can support multiple protocols.
+    pub fn set_alpn_selector(&mut self, selector: impl AlpnSelector + 'static) {
+        self.alpn_selector = Some(Rc::new(selector));
+    }
+
+    /// Use the certificates with the nicknames in `certs` for new
+    /// connections that indicate `server_name`, rather than those the server
+    /// was created with.  This lets one server answer for several names.
+    pub fn set_server_certificates(
+        &mut self,
+        server_name: impl AsRef<str>,
+        certs: &[impl AsRef<str>],
+    ) {
+        self.server_certs.insert(
+            String::from(server_name.as_ref()),
+            certs.iter().map(|x| String::from(x.as_ref())).collect(),
+        );
+    }
+
+    /// Send `status`, such as a stapled OCSP response, with the certificate
+    /// that has the DER encoding `certificate`.  Call this again to refresh
+    /// the status, such as before an OCSP response expires; new connections
+    /// use the latest status, while existing ones are left alone.
+    pub fn set_certificate_status(&mut self, certificate: &[u8], status: CertificateStatus) {
+        self.certificate_status.insert(certificate.to_vec(), status);
+    }
+
+    /// Pass the TLS secrets of new connections to `listener`.
+    /// See `Connection::set_secret_listener()`.
+    pub fn set_secret_listener(&mut self, listener: Rc<dyn SecretListener>) {
+        self.secret_listener = Some(listener);
+    }
+
+    /// Have connections use buffers from `pool` for datagrams.
+    /// See `Connection::set_buffer_pool()`.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffers = Some(pool);
+    }
+
+    /// Write a qlog trace of each new connection to a file in `dir`, named
+    /// for the connection ID that the client first chose, or stop with `None`.
+    pub fn set_qlog_dir(&mut self, dir: Option<PathBuf>) {
+        self.qlog_dir = dir;
+    }
+
+    /// Start a qlog trace for a new connection, if there is a directory.
+    fn start_qlog(&self, c: &mut Connection, odcid: &ConnectionId, now: Instant) {
+        let dir = match &self.qlog_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let name: String = odcid.iter().map(|b| format!("{:02x}", b)).collect();
+        let path = dir.join(format!("{}.sqlog", name));
+        let qlog = File::create(&path)
+            .and_then(|f| Qlog::new(Box::new(f), "neqo-server", Role::Server, now));
+        match qlog {
+            Ok(qlog) => c.set_qlog(qlog),
+            Err(e) => qwarn!([self] "Unable to write qlog to {}: {}", path.display(), e),
+        }
+    }
+
+    /// The number of entries in the connection table.  Each connection
+    /// appears once for each connection ID that is in use.
+    pub fn connection_table_len(&self) -> usize {
+        self.connections.borrow().len()
+    }
+
+    /// The number of connections that the server has, not counting those
+    /// that have closed.
+    pub fn connection_count(&self) -> usize {
+        self.connection_count
+    }
+
+    /// The number of connections with outstanding timers.
+    pub fn timer_count(&self) -> usize {
+        self.timers.len()
+    }
+
     fn remove_timer(&mut self, c: &StateRef) {
-        let last = c.borrow().last_timer;
-        self.timers.remove(last, |t| Rc::ptr_eq(t, c));
+        let token = c.borrow_mut().timer.take();
+        if let Some(t) = token {
+            self.timers.cancel(t);
+        }
+    }
+
+    /// Forget a connection that has closed: remove every connection ID that
+    /// was made for it, cancel its timer, and stop processing it.
+    fn remove_connection(&mut self, c: &StateRef) {
+        if mem::replace(&mut c.borrow_mut().removed, true) {
+            return;
+        }
+        let cids = mem::replace(&mut c.borrow().cid_mgr.borrow_mut().cids, Vec::new());
+        qdebug!([self] "Remove connection {}", c.borrow().id);
+        let mut connections = self.connections.borrow_mut();
+        for cid in &cids {
+            if let Some(v) = connections.remove(cid) {
+                debug_assert!(Rc::ptr_eq(&v, c));
+            }
+        }
+        // Catch any connection ID that was added some other way.
+        connections.retain(|_, v| !Rc::ptr_eq(v, c));
+        drop(connections);
+        self.remove_timer(c);
+        self.waiting.retain(|w| !Rc::ptr_eq(w, c));
+        self.connection_count -= 1;
     }
 
     fn process_connection(
@@ -185,19 +784,24 @@ impl Server {
         now: Instant,
     ) -> Option<Datagram> {
         qtrace!([self] "Process connection {:?}", c);
-        let out = c.borrow_mut().process(dgram, now);
+        let budget = self.work_budget;
+        let out = c.borrow_mut().process_with_budget(dgram, now, budget);
         match out {
             Output::Datagram(_) => {
                 qtrace!([self] "Sending packet, added to waiting connections");
                 self.waiting.push_back(c.clone());
             }
+            Output::Callback(_) if c.borrow().has_deferred_packets() => {
+                qtrace!([self] "Packets deferred, added to waiting connections");
+                self.waiting.push_back(c.clone());
+            }
             Output::Callback(delay) => {
                 let next = now + delay;
-                if next != c.borrow().last_timer {
+                if c.borrow().timer.map(|t| t.time()) != Some(next) {
                     qtrace!([self] "Change timer to {:?}", next);
                     self.remove_timer(&c);
-                    c.borrow_mut().last_timer = next;
-                    self.timers.add(next, c.clone());
+                    let token = self.timers.add(next, c.clone());
+                    c.borrow_mut().timer = Some(token);
                 }
             }
             _ => {
@@ -208,10 +812,8 @@ impl Server {
             qtrace!([self] "Connection active: {:?}", c);
             self.active.insert(ActiveConnectionRef { c: c.clone() });
         }
-        if matches!(c.borrow().state(), State::Closed(_)) {
-            self.connections
-                .borrow_mut()
-                .retain(|_, v| !Rc::ptr_eq(v, &c));
+        if matches!(c.borrow().state(), State::Closed { .. }) {
+            self.remove_connection(&c);
         }
         out.dgram()
     }
@@ -230,34 +832,67 @@ impl Server {
         dgram: Datagram,
         now: Instant,
     ) -> Option<Datagram> {
-        match self.retry.validate(&hdr) {
+        let result = if self.stateless {
+            // Every Initial without a valid token gets a Retry.  Attempts
+            // are still counted against any limits from `set_retry_limits()`,
+            // so that `retry_required()` reports a flood.
+            match self.retry.validate(&hdr, dgram.source(), now) {
+                RetryTokenResult::Pass => RetryTokenResult::Validate,
+                r => r,
+            }
+        } else {
+            self.retry.validate(&hdr, dgram.source(), now)
+        };
+        match result {
             RetryTokenResult::Invalid => None,
-            RetryTokenResult::Pass => self.accept_connection(None, dgram, now),
-            RetryTokenResult::Valid(dcid) => self.accept_connection(Some(dcid), dgram, now),
+            RetryTokenResult::Pass => self.accept_connection(None, &hdr.dcid, dgram, now),
+            RetryTokenResult::Valid(dcid) => {
+                if self.stateless && !initial_decrypts(hdr, &dgram) {
+                    qtrace!([self] "Dropping Initial that can't be decrypted");
+                    None
+                } else {
+                    self.accept_connection(Some(dcid), &hdr.dcid, dgram, now)
+                }
+            }
             RetryTokenResult::Validate => {
                 qinfo!([self] "Send retry for {:?}", hdr.dcid);
-                let token = self.retry.generate_token(&hdr.dcid);
-                let payload = encode_retry(&PacketHdr::new(
-                    0, // tbyte (unused on encode)
-                    PacketType::Retry {
-                        odcid: hdr.dcid.clone(),
-                        token,
-                    },
-                    Some(self.version),
-                    hdr.scid.as_ref().unwrap().clone(),
-                    Some(self.cid_manager.borrow_mut().generate_cid()),
-                    0, // Packet number
-                    0, // Epoch
-                ));
-                let retry = Datagram::new(dgram.destination(), dgram.source(), payload);
-                Some(retry)
+                let cid_manager = &self.cid_manager;
+                let version = self.version;
+                let res = self
+                    .retry
+                    .generate_token(&hdr.dcid, dgram.source(), now)
+                    .and_then(|token| {
+                        encode_retry(
+                            &PacketHdr::new(
+                                0, // tbyte (unused on encode)
+                                PacketType::Retry { odcid: None, token },
+                                Some(version),
+                                hdr.scid.as_ref().unwrap().clone(),
+                                Some(cid_manager.borrow_mut().generate_cid()),
+                                0, // Packet number
+                                0, // Epoch
+                            ),
+                            &hdr.dcid,
+                        )
+                    });
+                match res {
+                    Ok(payload) => {
+                        Some(Datagram::new(dgram.destination(), dgram.source(), payload))
+                    }
+                    Err(e) => {
+                        qwarn!([self] "Unable to make Retry: {}", e);
+                        None
+                    }
+                }
             }
         }
     }
 
+    /// Make a connection for an Initial packet that was sent to `dcid`.
     fn accept_connection(
         &mut self,
         odcid: Option<ConnectionId>,
+        dcid: &ConnectionId,
         dgram: Datagram,
         now: Instant,
     ) -> Option<Datagram> {
@@ -268,6 +903,7 @@ impl Server {
             c: None,
             cid_manager: self.cid_manager.clone(),
             connections: self.connections.clone(),
+            cids: Vec::new(),
         }));
         let sconn = Connection::new_server(
             &self.certs,
@@ -276,11 +912,80 @@ impl Server {
             cid_mgr.clone(),
         );
         if let Ok(mut c) = sconn {
-            if let Some(odcid) = odcid {
-                c.original_connection_id(&odcid);
+            if let Some(odcid) = &odcid {
+                c.original_connection_id(odcid);
+            }
+            if let Some(checker) = &self.zero_rtt_checker {
+                if c.server_enable_0rtt(&self.anti_replay, checker.clone())
+                    .is_err()
+                {
+                    qwarn!([self] "Unable to enable 0-RTT");
+                    return None;
+                }
+            }
+            if let Some(selector) = &self.alpn_selector {
+                if c.server_set_alpn_selector(selector.clone()).is_err() {
+                    qwarn!([self] "Unable to set ALPN selector");
+                    return None;
+                }
             }
-            let c = Rc::new(RefCell::new(ServerConnectionState { c, last_timer: now }));
-            cid_mgr.borrow_mut().c = Some(c.clone());
+            for (name, certs) in &self.server_certs {
+                if c.server_set_certificates(name, certs).is_err() {
+                    qwarn!([self] "Unable to set certificates for {}", name);
+                    return None;
+                }
+            }
+            for (cert, status) in &self.certificate_status {
+                if c.server_set_certificate_status(cert, status.clone())
+                    .is_err()
+                {
+                    qwarn!([self] "Unable to set certificate status");
+                    return None;
+                }
+            }
+            if let Some(listener) = &self.secret_listener {
+                c.set_secret_listener(listener.clone());
+            }
+            if let Some(key) = &self.reset_key {
+                if c.server_set_stateless_reset_key(key).is_err() {
+                    qwarn!([self] "Unable to set stateless reset key");
+                    return None;
+                }
+            }
+            if let Some(pool) = &self.buffers {
+                c.set_buffer_pool(pool.clone());
+            }
+            self.start_qlog(&mut c, odcid.as_ref().unwrap_or(dcid), now);
+            #[cfg(feature = "pq-hybrid")]
+            {
+                if self.pq_hybrid && c.enable_pq_hybrid().is_err() {
+                    qwarn!([self] "Unable to enable hybrid key exchange");
+                    return None;
+                }
+            }
+            if let Some(grease) = self.grease.as_mut() {
+                let seed = grease.next_seed();
+                qtrace!([self] "Grease new connection with seed {}", seed);
+                if c.enable_grease(seed).is_err() {
+                    qwarn!([self] "Unable to grease connection");
+                }
+            }
+            let c = Rc::new(RefCell::new(ServerConnectionState {
+                c,
+                id: self.next_id,
+                timer: None,
+                cid_mgr: cid_mgr.clone(),
+                removed: false,
+            }));
+            self.next_id += 1;
+            self.connection_count += 1;
+            cid_mgr.borrow_mut().c = Some(Rc::downgrade(&c));
+            // A large ClientHello is sent in several Initial packets, so the
+            // rest of them need to reach this connection.
+            self.connections
+                .borrow_mut()
+                .insert(dcid.clone(), c.clone());
+            cid_mgr.borrow_mut().cids.push(dcid.clone());
             self.process_connection(c, Some(dgram), now)
         } else {
             qwarn!([self] "Unable to create connection");
@@ -289,11 +994,11 @@ impl Server {
     }
 
     fn process_input(&mut self, dgram: Datagram, now: Instant) -> Option<Datagram> {
-        qtrace!("Process datagram: {}", hex(&dgram[..]));
+        qtrace!("Process datagram: {}", Redact(hex(&dgram[..])));
 
         // This is only looking at the first packet header in the datagram.
         // All packets in the datagram are routed to the same connection.
-        let res = decode_packet_hdr(self.cid_manager.borrow().as_decoder(), &dgram[..]);
+        let res = decode_packet_hdr(&*self.cid_decoder, &dgram[..]);
         let hdr = match res {
             Ok(h) => h,
             _ => {
@@ -308,8 +1013,14 @@ impl Server {
         }
 
         if hdr.tipe == PacketType::Short {
-            // TODO send a stateless reset here.
             qtrace!([self] "Short header packet for an unknown connection");
+            return self.stateless_reset(&hdr.dcid, &dgram);
+        }
+
+        if let PacketType::VN(_) = hdr.tipe {
+            // Never respond to Version Negotiation; a server doesn't send
+            // anything that would cause one.
+            qtrace!([self] "Discarding VN for an unknown connection");
             return None;
         }
 
@@ -325,17 +1036,54 @@ impl Server {
         self.handle_initial(hdr, dgram, now)
     }
 
+    /// Make a stateless reset for a packet that was sent to `dcid`, if there
+    /// is a key for that.
+    fn stateless_reset(&self, dcid: &ConnectionId, dgram: &Datagram) -> Option<Datagram> {
+        let key = self.reset_key.as_ref()?;
+        if dgram.len() < MIN_STATELESS_RESET_TRIGGER {
+            return None;
+        }
+        let token = match reset_token(key, dcid) {
+            Ok(t) => t,
+            Err(e) => {
+                qwarn!([self] "Unable to make a stateless reset token: {:?}", e);
+                return None;
+            }
+        };
+        let len = min(dgram.len() - 1, MAX_STATELESS_RESET);
+        let mut reset = vec![0; len];
+        rand::thread_rng().fill(&mut reset[..len - RESET_TOKEN_LEN]);
+        // This looks like a short header packet.
+        reset[0] = (reset[0] & 0x3f) | 0x40;
+        reset[len - RESET_TOKEN_LEN..].copy_from_slice(&token);
+        qdebug!([self] "Send stateless reset for {}", dcid);
+        Some(Datagram::new(dgram.destination(), dgram.source(), reset))
+    }
+
     /// Iterate through the pending connections looking for any that might want
-    /// to send a datagram.  Stop at the first one that does.
+    /// to send a datagram.  Stop at the first one that does, or once the work
+    /// budget allows no more connections to be looked at.
     fn process_next_output(&mut self, now: Instant) -> Option<Datagram> {
+        let mut budget = self.work_budget;
         qtrace!([self] "No packet to send, look at waiting connections");
-        while let Some(c) = self.waiting.pop_front() {
+        while budget > 0 {
+            let c = match self.waiting.pop_front() {
+                Some(c) => c,
+                None => break,
+            };
+            budget -= 1;
             if let Some(d) = self.process_connection(c, None, now) {
                 return Some(d);
             }
         }
         qtrace!([self] "No packet to send still, run timers");
-        while let Some(c) = self.timers.take_next(now) {
+        while budget > 0 {
+            let c = match self.timers.take_next(now) {
+                Some(c) => c,
+                None => break,
+            };
+            c.borrow_mut().timer = None;
+            budget -= 1;
             if let Some(d) = self.process_connection(c, None, now) {
                 return Some(d);
             }
@@ -345,19 +1093,37 @@ impl Server {
 
     fn next_time(&mut self, now: Instant) -> Option<Duration> {
         if self.waiting.is_empty() {
-            self.timers.next_time().map(|x| x - now)
+            // Timers that are due can be left over if the budget ran out.
+            self.timers.next_time().map(|x| max(x, now) - now)
         } else {
             Some(Duration::new(0, 0))
         }
     }
 
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        self.process_with_budget(dgram, now, usize::max_value())
+    }
+
+    /// Like `process()`, but limit how much work is done, so that a busy
+    /// server doesn't starve the rest of the caller's event loop.  No more
+    /// than `budget` connections are looked at for output or timers, and
+    /// each connection decodes no more than `budget` packets; see
+    /// `Connection::process_with_budget()`.  If work is left over, this
+    /// returns a zero `Output::Callback`, so call again soon.
+    pub fn process_with_budget(
+        &mut self,
+        dgram: Option<Datagram>,
+        now: Instant,
+        budget: usize,
+    ) -> Output {
+        self.work_budget = max(budget, 1);
         let out = if let Some(d) = dgram {
             self.process_input(d, now)
         } else {
             None
         };
         let out = out.or_else(|| self.process_next_output(now));
+        self.work_budget = usize::max_value();
         match out {
             Some(d) => {
                 qtrace!([self] "Send packet: {:?}", d);
@@ -376,12 +1142,38 @@ impl Server {
         }
     }
 
+    /// Process an optional input datagram, then produce all output.
+    /// See `Connection::process_iter()`.
+    pub fn process_iter<'a>(
+        &'a mut self,
+        dgram: Option<Datagram>,
+        now: Instant,
+    ) -> OutputIter<impl FnMut() -> Output + 'a> {
+        let mut dgram = dgram;
+        OutputIter::new(move || self.process(dgram.take(), now))
+    }
+
+    /// Mark a connection as having something to send.  Use this after
+    /// acting on a connection outside of `process()`, such as writing to a
+    /// stream, so that the next call to `process()` collects its output.
+    pub fn add_to_waiting(&mut self, c: ActiveConnectionRef) {
+        self.waiting.push_back(c.c);
+    }
+
     /// This lists the connections that have received new events
     /// as a result of calling `process()`.
     pub fn active_connections(&mut self) -> Vec<ActiveConnectionRef> {
-        mem::replace(&mut self.active, Default::default())
-            .into_iter()
-            .collect()
+        self.active.drain().collect()
+    }
+
+    /// Take the connections that have received new events, like
+    /// `active_connections()`, but without making a list.  The set of active
+    /// connections is emptied in place, so its storage is reused and polling
+    /// doesn't allocate.  The server can't be used until the iterator is
+    /// dropped; use `active_connections()` to act on the server, such as with
+    /// `add_to_waiting()`, while going through the connections.
+    pub fn drain_active_connections(&mut self) -> impl Iterator<Item = ActiveConnectionRef> + '_ {
+        self.active.drain()
     }
 }
 
@@ -398,6 +1190,20 @@ impl ActiveConnectionRef {
     pub fn borrow_mut<'a>(&'a mut self) -> impl DerefMut<Target = Connection> + 'a {
         std::cell::RefMut::map(self.c.borrow_mut(), |c| &mut c.c)
     }
+
+    /// An identifier for the connection that is unique for the server that
+    /// created it.  This doesn't change for the life of the connection.
+    pub fn id(&self) -> u64 {
+        self.c.borrow().id
+    }
+
+    /// Get a reference to the connection that doesn't keep it alive.
+    pub fn downgrade(&self) -> WeakConnectionRef {
+        WeakConnectionRef {
+            c: Rc::downgrade(&self.c),
+            id: self.id(),
+        }
+    }
 }
 
 impl std::hash::Hash for ActiveConnectionRef {
@@ -414,32 +1220,75 @@ impl PartialEq for ActiveConnectionRef {
 }
 impl Eq for ActiveConnectionRef {}
 
+/// A reference to a server connection that doesn't keep the connection alive.
+/// Once a connection is closed, the server releases it, so these can be kept
+/// in places where they might outlive the connection.
+#[derive(Clone, Debug)]
+pub struct WeakConnectionRef {
+    c: Weak<RefCell<ServerConnectionState>>,
+    id: u64,
+}
+
+impl WeakConnectionRef {
+    /// The identifier of the connection; see `ActiveConnectionRef::id()`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the connection, unless it has closed.
+    pub fn upgrade(&self) -> Option<ActiveConnectionRef> {
+        let c = self.c.upgrade()?;
+        if matches!(c.borrow().state(), State::Closed { .. }) {
+            None
+        } else {
+            Some(ActiveConnectionRef { c })
+        }
+    }
+}
+
+impl std::hash::Hash for WeakConnectionRef {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl PartialEq for WeakConnectionRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for WeakConnectionRef {}
+
 struct ServerConnectionIdManager {
-    c: Option<StateRef>,
+    /// This is weak so that the connection isn't kept alive by its own
+    /// connection ID manager.
+    c: Option<Weak<RefCell<ServerConnectionState>>>,
     connections: ConnectionTableRef,
     cid_manager: CidMgr,
+    /// Every connection ID that was made for the connection.
+    cids: Vec<ConnectionId>,
 }
 
-impl ConnectionIdDecoder for ServerConnectionIdManager {
-    fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId> {
-        self.cid_manager.borrow_mut().decode_cid(dec)
+impl ::std::fmt::Debug for ServerConnectionIdManager {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "ServerConnectionIdManager {:?}", self.cids)
     }
 }
+
 impl ConnectionIdManager for ServerConnectionIdManager {
     fn generate_cid(&mut self) -> ConnectionId {
         let cid = self.cid_manager.borrow_mut().generate_cid();
         assert!(!cid.is_empty());
-        let v = self
-            .connections
-            .borrow_mut()
-            .insert(cid.clone(), self.c.as_ref().unwrap().clone());
+        let c = self.c.as_ref().and_then(Weak::upgrade).unwrap();
+        let v = self.connections.borrow_mut().insert(cid.clone(), c.clone());
         if let Some(v) = v {
-            debug_assert!(Rc::ptr_eq(&v, self.c.as_ref().unwrap()));
+            debug_assert!(Rc::ptr_eq(&v, &c));
         }
+        self.cids.push(cid.clone());
         cid
     }
-    fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
-        self
+    fn decoder(&self) -> Arc<dyn ConnectionIdDecoder> {
+        self.cid_manager.borrow().decoder()
     }
 }
 
@@ -448,3 +1297,41 @@ impl ::std::fmt::Display for Server {
         write!(f, "Server")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::FixedConnectionIdManager;
+    use test_fixture::{self, now};
+
+    #[test]
+    fn remove_connection_without_cids() {
+        let mut server = Server::new(
+            now(),
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+        );
+        let cid_mgr = Rc::new(RefCell::new(ServerConnectionIdManager {
+            c: None,
+            cid_manager: server.cid_manager.clone(),
+            connections: server.connections.clone(),
+            cids: Vec::new(),
+        }));
+        let c = Rc::new(RefCell::new(ServerConnectionState {
+            c: test_fixture::default_server(),
+            id: 0,
+            timer: None,
+            cid_mgr,
+            removed: false,
+        }));
+        server.connection_count += 1;
+
+        server.remove_connection(&c);
+        assert_eq!(server.connection_count(), 0);
+        // Removing it again changes nothing.
+        server.remove_connection(&c);
+        assert_eq!(server.connection_count(), 0);
+    }
+}
@@ -0,0 +1,58 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Stateless reset tokens that are derived from a static key, so that anything
+// with the key can make the token for any connection ID.
+
+use neqo_crypto::{hkdf, TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3};
+
+use crate::Res;
+
+/// The length of a stateless reset token.
+pub const RESET_TOKEN_LEN: usize = 16;
+const RESET_TOKEN_LABEL: &str = "quic reset";
+
+/// Compute the stateless reset token for the connection ID `cid` using `key`.
+///
+/// The token is
+/// `HKDF-Expand-Label(HKDF-Extract(0, key), "quic reset", cid, 16)`,
+/// using SHA-256 and the TLS 1.3 form of HKDF-Expand-Label.  A server
+/// with the same key (see `Server::set_stateless_reset_key()`) uses this
+/// token for its connections, so a load balancer or another server process
+/// with the key can reset connections that it didn't create.
+pub fn reset_token(key: &[u8], cid: &[u8]) -> Res<[u8; RESET_TOKEN_LEN]> {
+    // The cipher suite only picks the hash function.
+    let cipher = TLS_AES_128_GCM_SHA256;
+    let ikm = hkdf::import_key(TLS_VERSION_1_3, cipher, key)?;
+    let prk = hkdf::extract(TLS_VERSION_1_3, cipher, None, &ikm)?;
+    let secret = hkdf::expand_label_raw(
+        TLS_VERSION_1_3,
+        cipher,
+        &prk,
+        cid,
+        RESET_TOKEN_LABEL,
+        RESET_TOKEN_LEN,
+    )?;
+    let mut token = [0; RESET_TOKEN_LEN];
+    token.copy_from_slice(&secret);
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_fixture::fixture_init;
+
+    #[test]
+    fn tokens() {
+        fixture_init();
+        let key = [7; 32];
+        let token = reset_token(&key, &[1, 2, 3]).unwrap();
+        assert_eq!(reset_token(&key, &[1, 2, 3]).unwrap(), token);
+        assert_ne!(reset_token(&key, &[1, 2, 4]).unwrap(), token);
+        assert_ne!(reset_token(&[8; 32], &[1, 2, 3]).unwrap(), token);
+    }
+}
@@ -6,13 +6,100 @@
 
 // Tracking of some useful statistics.
 
-#[derive(Default, Debug)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 /// Connection statistics
 pub struct Stats {
+    /// Counts the snapshots taken with `Connection::stats()`, so that a
+    /// later snapshot always has a larger value.
+    pub seq: u64,
     /// Total packets received
     pub packets_rx: u64,
     /// Total packets sent
     pub packets_tx: u64,
     /// Duplicate packets received
     pub dups_rx: u64,
+    /// Packets that were declared lost
+    pub lost: u64,
+    /// Packets that were declared lost, but were later acknowledged
+    pub spurious_lost: u64,
+    /// Number of times the probe timeout fired
+    pub pto: u64,
+    /// Packets received that were marked ECT(0)
+    pub ect0_rx: u64,
+    /// Packets received that were marked ECT(1)
+    pub ect1_rx: u64,
+    /// Packets received that were marked Congestion Experienced
+    pub ce_rx: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+/// The change in connection statistics between two snapshots
+pub struct StatsDelta {
+    pub packets_rx: u64,
+    pub packets_tx: u64,
+    pub dups_rx: u64,
+    pub lost: u64,
+    pub spurious_lost: u64,
+    pub pto: u64,
+    pub ect0_rx: u64,
+    pub ect1_rx: u64,
+    pub ce_rx: u64,
+}
+
+impl StatsDelta {
+    /// The change from `earlier` to `later`, which need to be snapshots of
+    /// the same connection.  This returns `None` if `earlier` was taken after
+    /// `later`.
+    pub fn between(earlier: &Stats, later: &Stats) -> Option<Self> {
+        if earlier.seq > later.seq {
+            return None;
+        }
+        Some(Self {
+            packets_rx: later.packets_rx.saturating_sub(earlier.packets_rx),
+            packets_tx: later.packets_tx.saturating_sub(earlier.packets_tx),
+            dups_rx: later.dups_rx.saturating_sub(earlier.dups_rx),
+            lost: later.lost.saturating_sub(earlier.lost),
+            spurious_lost: later.spurious_lost.saturating_sub(earlier.spurious_lost),
+            pto: later.pto.saturating_sub(earlier.pto),
+            ect0_rx: later.ect0_rx.saturating_sub(earlier.ect0_rx),
+            ect1_rx: later.ect1_rx.saturating_sub(earlier.ect1_rx),
+            ce_rx: later.ce_rx.saturating_sub(earlier.ce_rx),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta() {
+        let a = Stats {
+            seq: 1,
+            packets_rx: 10,
+            packets_tx: 12,
+            lost: 1,
+            ..Stats::default()
+        };
+        let b = Stats {
+            seq: 2,
+            packets_rx: 15,
+            packets_tx: 20,
+            lost: 3,
+            pto: 1,
+            ..Stats::default()
+        };
+        assert_eq!(
+            StatsDelta::between(&a, &b),
+            Some(StatsDelta {
+                packets_rx: 5,
+                packets_tx: 8,
+                lost: 2,
+                pto: 1,
+                ..StatsDelta::default()
+            })
+        );
+        assert_eq!(StatsDelta::between(&b, &a), None);
+        assert_eq!(StatsDelta::between(&a, &a), Some(StatsDelta::default()));
+    }
 }
@@ -0,0 +1,122 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Groups of streams, so that an application can act on several streams at once.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::stream_id::StreamId;
+use crate::{Error, Res};
+
+/// Identifies a group of streams.  See `Connection::stream_group_create()`.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct StreamGroupId(u64);
+
+impl StreamGroupId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Which group each stream is in.  A stream is in at most one group.
+#[derive(Debug, Default)]
+pub(crate) struct StreamGroups {
+    next: u64,
+    members: HashMap<StreamGroupId, BTreeSet<StreamId>>,
+    group_of: HashMap<StreamId, StreamGroupId>,
+}
+
+impl StreamGroups {
+    pub fn create(&mut self) -> StreamGroupId {
+        let group = StreamGroupId(self.next);
+        self.next += 1;
+        self.members.insert(group, BTreeSet::new());
+        group
+    }
+
+    /// Remove a group.  Its streams are left alone.
+    pub fn delete(&mut self, group: StreamGroupId) -> Res<()> {
+        let members = self.members.remove(&group).ok_or(Error::InvalidInput)?;
+        for id in members {
+            self.group_of.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Put a stream in a group, taking it out of any group it was in.
+    pub fn add(&mut self, group: StreamGroupId, id: StreamId) -> Res<()> {
+        if !self.members.contains_key(&group) {
+            return Err(Error::InvalidInput);
+        }
+        self.remove(id);
+        self.members.get_mut(&group).unwrap().insert(id);
+        self.group_of.insert(id, group);
+        Ok(())
+    }
+
+    /// Take a stream out of its group, if it is in one.
+    pub fn remove(&mut self, id: StreamId) {
+        if let Some(group) = self.group_of.remove(&id) {
+            self.members.get_mut(&group).unwrap().remove(&id);
+        }
+    }
+
+    pub fn group_of(&self, id: StreamId) -> Option<StreamGroupId> {
+        self.group_of.get(&id).cloned()
+    }
+
+    /// The streams in a group, in order.
+    pub fn streams(&self, group: StreamGroupId) -> Res<&BTreeSet<StreamId>> {
+        self.members.get(&group).ok_or(Error::InvalidInput)
+    }
+
+    /// Only keep the streams for which `f` returns true.  The groups are kept,
+    /// even if they become empty.
+    pub fn retain(&mut self, mut f: impl FnMut(StreamId) -> bool) {
+        if self.group_of.is_empty() {
+            return;
+        }
+        let members = &mut self.members;
+        self.group_of.retain(|id, group| {
+            let keep = f(*id);
+            if !keep {
+                members.get_mut(group).unwrap().remove(id);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn membership() {
+        let mut groups = StreamGroups::default();
+        let a = groups.create();
+        let b = groups.create();
+        assert_ne!(a, b);
+
+        groups.add(a, StreamId::from(0)).unwrap();
+        groups.add(a, StreamId::from(4)).unwrap();
+        assert_eq!(groups.group_of(StreamId::from(4)), Some(a));
+        // Moving a stream takes it out of its old group.
+        groups.add(b, StreamId::from(4)).unwrap();
+        assert_eq!(groups.group_of(StreamId::from(4)), Some(b));
+        assert_eq!(groups.streams(a).unwrap().len(), 1);
+        assert_eq!(groups.streams(b).unwrap().len(), 1);
+
+        groups.retain(|id| id != StreamId::from(0));
+        assert!(groups.streams(a).unwrap().is_empty());
+        assert_eq!(groups.group_of(StreamId::from(0)), None);
+
+        groups.delete(b).unwrap();
+        assert_eq!(groups.group_of(StreamId::from(4)), None);
+        assert_eq!(groups.add(b, StreamId::from(8)), Err(Error::InvalidInput));
+        assert_eq!(groups.delete(b), Err(Error::InvalidInput));
+    }
+}
@@ -7,6 +7,7 @@
 // Transport parameters. See -transport section 7.3.
 
 #![allow(dead_code)]
+use crate::grease::Grease;
 use crate::{Error, Res};
 use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Decoder, Encoder};
 use neqo_crypto::constants::{TLS_HS_CLIENT_HELLO, TLS_HS_ENCRYPTED_EXTENSIONS};
@@ -43,6 +44,10 @@ pub mod consts {
         MAX_ACK_DELAY = 11,
         DISABLE_MIGRATION = 12,
         PREFERRED_ADDRESS = 13,
+        VERSION_INFORMATION = 0x11,
+        // The multipath extension uses a codepoint that doesn't fit in the
+        // 16 bits that this version has, so this is a private one.
+        ENABLE_MULTIPATH = 0x0f73,
     }
 }
 
@@ -114,7 +119,15 @@ impl TransportParameter {
                 _ => return Err(Error::TransportParameterError),
             },
 
-            DISABLE_MIGRATION => TransportParameter::Empty,
+            DISABLE_MIGRATION | ENABLE_MULTIPATH => TransportParameter::Empty,
+
+            // The chosen version, then any number of available versions.
+            VERSION_INFORMATION => {
+                if d.remaining() < 4 || d.remaining() % 4 != 0 {
+                    return Err(Error::TransportParameterError);
+                }
+                TransportParameter::Bytes(d.decode_remainder().to_vec())
+            }
             // Skip.
             _ => return Ok(None),
         };
@@ -173,6 +186,21 @@ impl TransportParameters {
         });
     }
 
+    /// Encode, but shuffle the order of parameters and add a reserved
+    /// parameter that the peer is required to ignore.
+    pub fn encode_greased(&self, enc: &mut Encoder, grease: &mut Grease) {
+        let (grease_id, grease_value) = grease.transport_parameter();
+        let grease_tp = TransportParameter::Bytes(grease_value);
+        let mut params: Vec<_> = self.params.iter().map(|(k, v)| (*k, v)).collect();
+        params.push((grease_id, &grease_tp));
+        grease.shuffle(&mut params);
+        enc.encode_vec_with(2, |mut enc_inner| {
+            for (tipe, tp) in params {
+                tp.encode(&mut enc_inner, tipe);
+            }
+        });
+    }
+
     // Get an integer type or a default.
     pub fn get_integer(&self, tipe: u16) -> u64 {
         let default = match tipe {
@@ -216,7 +244,7 @@ impl TransportParameters {
 
     pub fn get_bytes(&self, tipe: u16) -> Option<Vec<u8>> {
         match tipe {
-            ORIGINAL_CONNECTION_ID | STATELESS_RESET_TOKEN => {}
+            ORIGINAL_CONNECTION_ID | STATELESS_RESET_TOKEN | VERSION_INFORMATION => {}
             _ => panic!("Transport parameter not known or not type bytes"),
         }
 
@@ -229,7 +257,7 @@ impl TransportParameters {
 
     pub fn set_bytes(&mut self, tipe: u16, value: Vec<u8>) {
         match tipe {
-            ORIGINAL_CONNECTION_ID | STATELESS_RESET_TOKEN => {
+            ORIGINAL_CONNECTION_ID | STATELESS_RESET_TOKEN | VERSION_INFORMATION => {
                 self.set(tipe, TransportParameter::Bytes(value));
             }
             _ => panic!("Transport parameter not known or not type bytes"),
@@ -238,13 +266,35 @@ impl TransportParameters {
 
     pub fn set_empty(&mut self, tipe: u16) {
         match tipe {
-            DISABLE_MIGRATION => {
+            DISABLE_MIGRATION | ENABLE_MULTIPATH => {
                 self.set(tipe, TransportParameter::Empty);
             }
             _ => panic!("Transport parameter not known or not type empty"),
         }
     }
 
+    /// Set the version that is in use and the versions that are available.
+    pub fn set_version_information(&mut self, chosen: u32, available: &[u32]) {
+        let mut enc = Encoder::default();
+        enc.encode_uint(4, chosen);
+        for v in available {
+            enc.encode_uint(4, *v);
+        }
+        self.set_bytes(VERSION_INFORMATION, enc.into());
+    }
+
+    /// Get the chosen and available versions, if the parameter was sent.
+    pub fn get_version_information(&self) -> Option<(u32, Vec<u32>)> {
+        let v = self.get_bytes(VERSION_INFORMATION)?;
+        let mut d = Decoder::from(&v[..]);
+        let chosen = d.decode_uint(4)? as u32;
+        let mut available = Vec::new();
+        while d.remaining() > 0 {
+            available.push(d.decode_uint(4)? as u32);
+        }
+        Some((chosen, available))
+    }
+
     /// Return true if the remembered transport parameters are OK for 0-RTT.
     /// Generally this means that any value that is currently in effect is greater than
     /// or equal to the promised value.
@@ -255,9 +305,11 @@ impl TransportParameters {
                 *k,
                 ORIGINAL_CONNECTION_ID
                     | STATELESS_RESET_TOKEN
+                    | VERSION_INFORMATION
                     | IDLE_TIMEOUT
                     | ACK_DELAY_EXPONENT
                     | MAX_ACK_DELAY
+                    | ENABLE_MULTIPATH
             ) {
                 continue;
             }
@@ -277,7 +329,9 @@ impl TransportParameters {
         true
     }
 
-    fn was_sent(&self, tipe: u16) -> bool {
+    /// Whether a parameter was sent.  This is how parameters with no value
+    /// are read.
+    pub fn was_sent(&self, tipe: u16) -> bool {
         self.params.contains_key(&tipe)
     }
 }
@@ -287,6 +341,8 @@ pub struct TransportParametersHandler {
     pub local: TransportParameters,
     pub remote: Option<TransportParameters>,
     pub remote_0rtt: Option<TransportParameters>,
+    /// If set, vary the encoding of local transport parameters.
+    pub grease: Option<Grease>,
 }
 
 impl TransportParametersHandler {
@@ -308,7 +364,10 @@ impl ExtensionHandler for TransportParametersHandler {
 
         // TODO(ekr@rtfm.com): Modify to avoid a copy.
         let mut enc = Encoder::default();
-        self.local.encode(&mut enc);
+        match self.grease.as_mut() {
+            Some(grease) => self.local.encode_greased(&mut enc, grease),
+            None => self.local.encode(&mut enc),
+        }
         assert!(enc.len() <= d.len());
         d[..enc.len()].copy_from_slice(&enc);
         ExtensionWriterResult::Write(enc.len())
@@ -336,14 +395,24 @@ impl ExtensionHandler for TransportParametersHandler {
     }
 }
 
+/// This checks that the transport parameters in a session ticket are
+/// compatible with the current values.  If they are, the rest of the token
+/// is passed to the application checker, if there is one.
 #[derive(Debug)]
 pub struct TpZeroRttChecker {
     handler: Rc<RefCell<TransportParametersHandler>>,
+    app_checker: Option<Rc<dyn ZeroRttChecker>>,
 }
 
 impl TpZeroRttChecker {
-    pub fn wrap(handler: Rc<RefCell<TransportParametersHandler>>) -> Box<dyn ZeroRttChecker> {
-        Box::new(Self { handler })
+    pub fn wrap(
+        handler: Rc<RefCell<TransportParametersHandler>>,
+        app_checker: Option<Rc<dyn ZeroRttChecker>>,
+    ) -> Box<dyn ZeroRttChecker> {
+        Box::new(Self {
+            handler,
+            app_checker,
+        })
     }
 }
 
@@ -368,12 +437,17 @@ impl ZeroRttChecker for TpZeroRttChecker {
             qinfo!("0-RTT: transport parameter decode error");
             return ZeroRttCheckResult::Fail;
         };
-        if self.handler.borrow().local.ok_for_0rtt(&remembered) {
+        if !self.handler.borrow().local.ok_for_0rtt(&remembered) {
+            qinfo!("0-RTT: transport parameters bad, rejecting");
+            return ZeroRttCheckResult::Reject;
+        }
+        if let Some(app_checker) = &self.app_checker {
+            let res = app_checker.check(dec.decode_remainder());
+            qinfo!("0-RTT: transport parameters OK, application says {:?}", res);
+            res
+        } else {
             qinfo!("0-RTT: transport parameters OK, accepting");
             ZeroRttCheckResult::Accept
-        } else {
-            qinfo!("0-RTT: transport parameters bad, rejecting");
-            ZeroRttCheckResult::Reject
         }
     }
 }
@@ -418,6 +492,35 @@ mod tests {
         let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
     }
 
+    #[test]
+    fn version_information() {
+        let mut tps = TransportParameters::default();
+        tps.set_version_information(0xff00_0016, &[0xff00_0016, 0x1a2a_3a4a]);
+
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+        let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
+        assert_eq!(
+            tps2.get_version_information(),
+            Some((0xff00_0016, vec![0xff00_0016, 0x1a2a_3a4a]))
+        );
+        assert_eq!(
+            TransportParameters::default().get_version_information(),
+            None
+        );
+
+        // A length that isn't a multiple of 4 is an error.
+        let mut enc = Encoder::default();
+        enc.encode_vec_with(2, |enc_inner| {
+            enc_inner.encode_uint(2, VERSION_INFORMATION);
+            enc_inner.encode_vec(2, &[0xff, 0, 0, 0x16, 0x1a]);
+        });
+        assert_eq!(
+            TransportParameters::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::TransportParameterError
+        );
+    }
+
     #[test]
     fn compatible_0rtt_ignored_values() {
         let mut tps_a = TransportParameters::default();
@@ -483,6 +586,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn greased_tps() {
+        let mut tps = TransportParameters::default();
+        tps.set_integer(INITIAL_MAX_DATA, 1_000_000);
+        tps.set_integer(INITIAL_MAX_STREAMS_BIDI, 10);
+        tps.set_empty(DISABLE_MIGRATION);
+
+        let mut grease = Grease::new(1);
+        for _ in 0..10 {
+            let mut enc = Encoder::default();
+            tps.encode_greased(&mut enc, &mut grease);
+            // The reserved parameter is dropped when decoding.
+            let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("decodes");
+            assert_eq!(tps, tps2);
+        }
+    }
+
     #[test]
     fn test_apple_tps() {
         let enc = Encoder::from_hex("0049000100011e00020010449aeef472626f18a5bba2d51ae473be0003000244b0000400048015f9000005000480015f900006000480015f90000700048004000000080001080009000108");
@@ -157,6 +157,11 @@ impl RecvdPackets {
         self.ack_time
     }
 
+    /// The largest packet number that was received, if any.
+    pub fn largest_pn(&self) -> Option<u64> {
+        self.ranges.front().map(|r| r.largest)
+    }
+
     /// Returns true if an ACK frame should be sent now.
     fn ack_now(&self, now: Instant) -> bool {
         match self.ack_time {
@@ -234,6 +239,12 @@ impl RecvdPackets {
         false
     }
 
+    /// Mark the ranges that an ACK carried as acknowledged, now that the
+    /// packet with the ACK was acknowledged.
+    pub fn acked(&mut self, token: &AckToken) {
+        self.acknowledged(&token.ranges);
+    }
+
     /// Mark the given range as having been acknowledged.
     pub fn acknowledged(&mut self, acked: &[PacketRange]) {
         let mut range_iter = self.ranges.iter_mut();
@@ -248,31 +259,6 @@ impl RecvdPackets {
             cur.acknowledged(&ack);
         }
     }
-}
-
-impl ::std::fmt::Display for RecvdPackets {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "Recvd{:?}", self.space)
-    }
-}
-
-#[derive(Debug)]
-pub struct AckTracker {
-    spaces: [RecvdPackets; 3],
-}
-
-impl AckTracker {
-    pub fn ack_time(&self) -> Option<Instant> {
-        let mut iter = self.spaces.iter().filter_map(RecvdPackets::ack_time);
-        match iter.next() {
-            Some(v) => Some(iter.fold(v, min)),
-            _ => None,
-        }
-    }
-
-    pub fn acked(&mut self, token: &AckToken) {
-        self.spaces[token.space as usize].acknowledged(&token.ranges);
-    }
 
     /// Generate an ACK frame.
     ///
@@ -284,12 +270,8 @@ impl AckTracker {
     ///
     /// We don't send ranges that have been acknowledged, but they still need
     /// to be tracked so that duplicates can be detected.
-    pub(crate) fn get_frame(
-        &mut self,
-        now: Instant,
-        epoch: Epoch,
-    ) -> Option<(Frame, Option<RecoveryToken>)> {
-        let space = &mut self[PNSpace::from(epoch)];
+    pub(crate) fn get_frame(&mut self, now: Instant) -> Option<(Frame, AckToken)> {
+        let space = self;
 
         // Check that we aren't delaying ACKs.
         if !space.ack_now(now) {
@@ -339,10 +321,10 @@ impl AckTracker {
             };
             Some((
                 ack,
-                Some(RecoveryToken::Ack(AckToken {
-                    space: PNSpace::from(epoch),
+                AckToken {
+                    space: space.space,
                     ranges,
-                })),
+                },
             ))
         } else {
             qwarn!(
@@ -354,6 +336,49 @@ impl AckTracker {
     }
 }
 
+impl ::std::fmt::Display for RecvdPackets {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Recvd{:?}", self.space)
+    }
+}
+
+#[derive(Debug)]
+pub struct AckTracker {
+    spaces: [RecvdPackets; 3],
+}
+
+impl AckTracker {
+    pub fn ack_time(&self) -> Option<Instant> {
+        let mut iter = self.spaces.iter().filter_map(RecvdPackets::ack_time);
+        match iter.next() {
+            Some(v) => Some(iter.fold(v, min)),
+            _ => None,
+        }
+    }
+
+    pub fn acked(&mut self, token: &AckToken) {
+        self.spaces[token.space as usize].acknowledged(&token.ranges);
+    }
+
+    /// Forget what was received in a space whose keys were discarded, so
+    /// that no ACK is scheduled for it.
+    pub fn drop_space(&mut self, space: PNSpace) {
+        self.spaces[space as usize] = RecvdPackets::new(space);
+    }
+
+    /// Generate an ACK frame for the space of `epoch`.  See
+    /// `RecvdPackets::get_frame()`.
+    pub(crate) fn get_frame(
+        &mut self,
+        now: Instant,
+        epoch: Epoch,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
+        self[PNSpace::from(epoch)]
+            .get_frame(now)
+            .map(|(frame, token)| (frame, Some(RecoveryToken::Ack(token))))
+    }
+}
+
 impl Default for AckTracker {
     fn default() -> Self {
         Self {
@@ -387,8 +412,8 @@ mod tests {
     use std::collections::HashSet;
 
     fn now() -> Instant {
-        static mut NOW_ONCE: OnceResult<Instant> = OnceResult::new();
-        *unsafe { NOW_ONCE.call_once(Instant::now) }
+        static NOW_ONCE: OnceResult<Instant> = OnceResult::new();
+        *NOW_ONCE.call_once(Instant::now)
     }
 
     fn test_ack_range(pns: &[u64], nranges: usize) {
@@ -0,0 +1,224 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A benchmark for collecting events from a busy server.  Clients connect to
+// the server and then leave, a batch at a time, all in memory.  After every
+// datagram, the server is polled for active connections and their events,
+// and the time and the number of allocations that polling takes is counted.
+// This is done with `active_connections()`, then again with
+// `drain_active_connections()` and `next_event()`, one after the other.
+//
+// Allocations are counted for each thread, so other tests running at the
+// same time don't change the numbers.  To see the numbers for 10000
+// connections:
+//   CHURN_CONNECTIONS=10000 cargo test --release --test churn -- --nocapture
+
+#![deny(warnings)]
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::server::Server;
+use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, Output, State};
+use test_fixture::{self, anti_replay, fixture_init};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How many clients connect at the same time.
+const BATCH: usize = 100;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+}
+
+/// How many allocations this thread has made.
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// The system allocator, except that allocations are counted.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // This fails while the thread is exiting, which doesn't matter.
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// What polling the server cost.
+#[derive(Debug, Default)]
+struct Polling {
+    polls: usize,
+    events: usize,
+    time: Duration,
+    allocations: usize,
+}
+
+/// Take the active connections from the server and all of their events.
+/// Without `drain`, this uses the calls that collect into new lists.
+fn poll(server: &mut Server, drain: bool, polling: &mut Polling) {
+    let before = allocations();
+    let start = Instant::now();
+    let mut events = 0;
+    if drain {
+        for mut c in server.drain_active_connections() {
+            while c.borrow_mut().next_event().is_some() {
+                events += 1;
+            }
+        }
+    } else {
+        for mut c in server.active_connections() {
+            events += c.borrow_mut().events().count();
+        }
+    }
+    polling.time += start.elapsed();
+    polling.allocations += allocations() - before;
+    polling.polls += 1;
+    polling.events += events;
+}
+
+/// Pass datagrams between the clients and the server until neither has
+/// anything more to send.  The server is polled after each datagram.
+fn exchange(
+    server: &mut Server,
+    clients: &mut HashMap<SocketAddr, Connection>,
+    now: Instant,
+    drain: bool,
+    polling: &mut Polling,
+) {
+    loop {
+        let mut to_server = Vec::new();
+        for c in clients.values_mut() {
+            if c.events()
+                .any(|e| e == ConnectionEvent::AuthenticationNeeded)
+            {
+                c.authenticated(AuthenticationStatus::Ok, now);
+            }
+            while let Output::Datagram(d) = c.process(None, now) {
+                to_server.push(d);
+            }
+        }
+
+        let mut to_clients = Vec::new();
+        let mut dgram = to_server.pop();
+        loop {
+            // Send everything before taking the next datagram.
+            match server.process(dgram.take(), now) {
+                Output::Datagram(d) => to_clients.push(d),
+                _ => match to_server.pop() {
+                    Some(d) => dgram = Some(d),
+                    None => break,
+                },
+            }
+            poll(server, drain, polling);
+        }
+
+        if to_clients.is_empty() {
+            return;
+        }
+        for d in to_clients {
+            if let Some(c) = clients.get_mut(&d.destination()) {
+                c.process_input(d, now);
+            }
+        }
+    }
+}
+
+fn server() -> Server {
+    fixture_init();
+    Server::new(
+        Instant::now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+    )
+}
+
+fn client(local: SocketAddr, server: SocketAddr) -> Connection {
+    Connection::new_client(
+        test_fixture::DEFAULT_SERVER_NAME,
+        test_fixture::DEFAULT_ALPN,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+        local,
+        server,
+    )
+    .expect("create a client")
+}
+
+/// Connect `count` clients to a server, a batch at a time.  Each batch
+/// leaves before the next one connects.
+fn churn(count: usize, drain: bool) -> Polling {
+    let mut server = server();
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443);
+    let mut polling = Polling::default();
+    let mut now = Instant::now();
+    let mut made = 0;
+    while made < count {
+        let batch = min(BATCH, count - made);
+        let mut clients = (made..made + batch)
+            .map(|i| {
+                let ip = Ipv4Addr::from(0x0a00_0000 | u32::try_from(i).unwrap());
+                let addr = SocketAddr::new(IpAddr::V4(ip), 443);
+                (addr, client(addr, server_addr))
+            })
+            .collect::<HashMap<_, _>>();
+        made += batch;
+
+        exchange(&mut server, &mut clients, now, drain, &mut polling);
+        assert!(clients.values().all(|c| *c.state() == State::Connected));
+        assert_eq!(server.connection_count(), batch);
+
+        for c in clients.values_mut() {
+            c.close(now, 0, "bye");
+        }
+        exchange(&mut server, &mut clients, now, drain, &mut polling);
+        // Wait for the connections to finish closing.
+        now += Duration::from_secs(2);
+        exchange(&mut server, &mut clients, now, drain, &mut polling);
+        assert_eq!(server.connection_count(), 0);
+    }
+    polling
+}
+
+fn report(name: &str, p: &Polling) {
+    println!(
+        "{}: {} polls, {} events, {:?} per poll, {:.2} allocations per poll",
+        name,
+        p.polls,
+        p.events,
+        p.time / u32::try_from(p.polls.max(1)).unwrap_or(u32::max_value()),
+        p.allocations as f64 / p.polls.max(1) as f64
+    );
+}
+
+#[test]
+fn churn_allocates_less_when_draining() {
+    let count = env::var("CHURN_CONNECTIONS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(2 * BATCH);
+    let collect = churn(count, false);
+    report("active_connections", &collect);
+    let drain = churn(count, true);
+    report("drain_active_connections", &drain);
+    assert!(drain.allocations < collect.allocations);
+}
@@ -0,0 +1,142 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![deny(warnings)]
+
+use neqo_common::{matches, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{
+    server::Server, Connection, ConnectionEvent, ConnectionHandle, ConnectionPool,
+    FixedConnectionIdManager, Output, State,
+};
+use test_fixture::{self, fixture_init, loopback, now};
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+fn client_on(addr: SocketAddr) -> Connection {
+    fixture_init();
+    Connection::new_client(
+        test_fixture::DEFAULT_SERVER_NAME,
+        test_fixture::DEFAULT_ALPN,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+        addr,
+        loopback(),
+    )
+    .expect("create a client")
+}
+
+fn default_server() -> Server {
+    let mut server = Server::new(
+        now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(7))),
+    );
+    server.set_retry_required(false);
+    server
+}
+
+/// Make a pool of clients, each with their own port.
+fn pool_of(count: u16) -> (ConnectionPool, Vec<(SocketAddr, ConnectionHandle)>) {
+    let mut pool = ConnectionPool::new(now());
+    let clients = (0..count)
+        .map(|i| {
+            let addr = SocketAddr::new(loopback().ip(), 10000 + i);
+            (addr, pool.add(client_on(addr)))
+        })
+        .collect();
+    (pool, clients)
+}
+
+fn route(clients: &[(SocketAddr, ConnectionHandle)], d: &Datagram) -> ConnectionHandle {
+    clients
+        .iter()
+        .find(|(addr, _)| *addr == d.destination())
+        .expect("datagram for a known client")
+        .1
+}
+
+fn authenticate(pool: &mut ConnectionPool) {
+    for h in pool.active_connections() {
+        let c = pool.connection_mut(h).unwrap();
+        let auth = c
+            .events()
+            .any(|e| e == ConnectionEvent::AuthenticationNeeded);
+        if auth {
+            c.authenticated(AuthenticationStatus::Ok, now());
+        }
+    }
+}
+
+/// Pass datagrams between the pool and the server until neither has anything to send.
+fn exchange(
+    pool: &mut ConnectionPool,
+    clients: &[(SocketAddr, ConnectionHandle)],
+    server: &mut Server,
+) {
+    let mut to_server: Vec<_> = pool.process_iter(None, now()).collect();
+    while !to_server.is_empty() {
+        let to_clients: Vec<_> = to_server
+            .drain(..)
+            .flat_map(|d| server.process_iter(Some(d), now()).collect::<Vec<_>>())
+            .collect();
+        for d in to_clients {
+            let h = route(clients, &d);
+            to_server.extend(pool.process_iter(Some((h, d)), now()));
+        }
+        authenticate(pool);
+        to_server.extend(pool.process_iter(None, now()));
+    }
+}
+
+#[test]
+fn pool_connects() {
+    let (mut pool, clients) = pool_of(3);
+    let mut server = default_server();
+    exchange(&mut pool, &clients, &mut server);
+
+    for (_, h) in &clients {
+        assert_eq!(*pool.connection(*h).unwrap().state(), State::Connected);
+    }
+    assert_eq!(server.active_connections().len(), clients.len());
+    // Each connection has a timer running.
+    assert_eq!(pool.timer_count(), clients.len());
+    assert!(matches!(pool.process(None, now()), Output::Callback(_)));
+}
+
+#[test]
+fn pool_remove() {
+    let (mut pool, clients) = pool_of(2);
+    let mut server = default_server();
+    exchange(&mut pool, &clients, &mut server);
+
+    let c = pool
+        .remove(clients[0].1)
+        .expect("connection is in the pool");
+    assert_eq!(*c.state(), State::Connected);
+    assert!(pool.remove(clients[0].1).is_none());
+    assert!(pool.connection(clients[0].1).is_none());
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.timer_count(), 1);
+}
+
+#[test]
+fn pool_close() {
+    let (mut pool, clients) = pool_of(2);
+    let mut server = default_server();
+    exchange(&mut pool, &clients, &mut server);
+    pool.active_connections();
+
+    let h = clients[1].1;
+    pool.connection_mut(h).unwrap().close(now(), 0, "bye");
+    let d = pool.process(None, now()).dgram();
+    assert!(d.is_some());
+    assert!(pool.active_connections().contains(&h));
+    assert!(matches!(pool.process(None, now()), Output::Callback(_)));
+}
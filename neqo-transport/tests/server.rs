@@ -6,17 +6,20 @@
 
 #![deny(warnings)]
 
-use neqo_common::{qtrace, Datagram, Decoder};
-use neqo_crypto::AuthenticationStatus;
+use neqo_common::{matches, qtrace, Datagram, Decoder};
+use neqo_crypto::{AlpnSelector, AuthenticationStatus, ZeroRttCheckResult, ZeroRttChecker};
 use neqo_transport::{
-    server::ActiveConnectionRef, server::Server, Connection, ConnectionError, Error,
-    FixedConnectionIdManager, Output, State, StreamType, QUIC_VERSION,
+    reset_token, server::ActiveConnectionRef, server::RetryTokenChecker, server::RetryTokenKey,
+    server::Server, server::WorkerConnectionIdManager, Connection, ConnectionError,
+    ConnectionEvent, Error, FixedConnectionIdManager, HandshakeRecord,
+    LengthPrefixConnectionIdManager, Output, State, StreamType, QUIC_VERSION, RESET_TOKEN_LEN,
 };
 use test_fixture::{self, assertions, default_client, now};
 
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Different than the one in the fixture, which is a single connection.
 fn default_server() -> Server {
@@ -96,6 +99,251 @@ fn retry() {
     connected_server(&mut server);
 }
 
+#[test]
+fn retry_wrong_odcid() {
+    let mut server = default_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let retry = server.process(dgram, now()).dgram().unwrap(); // Retry
+    assertions::assert_retry(&retry);
+
+    // This version puts the original destination connection ID in the Retry,
+    // after the other connection IDs.  A Retry for a different connection ID
+    // is ignored.
+    let mut damaged = retry[..].to_vec();
+    let dcid_len = usize::from(damaged[5]);
+    let scid_len = usize::from(damaged[6 + dcid_len]);
+    damaged[7 + dcid_len + scid_len + 1] ^= 0x1;
+    let damaged = Datagram::new(retry.source(), retry.destination(), damaged);
+    let dgram = client.process(Some(damaged), now()).dgram();
+    assert!(dgram.is_none());
+
+    // The real one still works.
+    let dgram = client.process(Some(retry), now()).dgram(); // Initial w/token
+    assert!(dgram.is_some());
+    let dgram = server.process(dgram, now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+}
+
+#[test]
+fn stateless() {
+    let mut server = default_server();
+    server.set_stateless(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    assert_eq!(server.connection_table_len(), 0);
+
+    // An Initial with a token that can't be decrypted is dropped.
+    let dgram = client.process(dgram, now()).dgram().unwrap(); // Initial w/token
+    let mut damaged = dgram.to_vec();
+    *damaged.last_mut().unwrap() ^= 1;
+    let damaged = Datagram::new(dgram.source(), dgram.destination(), damaged);
+    assert!(server.process(Some(damaged), now()).dgram().is_none());
+    assert_eq!(server.connection_table_len(), 0);
+
+    let dgram = server.process(Some(dgram), now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    assert!(server.connection_table_len() > 0);
+    let _ = client.process(dgram, now()).dgram(); // Ingest, drop any ACK.
+    client.authenticated(AuthenticationStatus::Ok, now());
+    let dgram = client.process(None, now()).dgram(); // Send Finished
+    assert_eq!(*client.state(), State::Connected);
+    server.process(dgram, now());
+    connected_server(&mut server);
+}
+
+#[test]
+fn retry_token_other_address() {
+    let mut server = default_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+
+    // The token is bound to the address that the Retry was sent to.  From
+    // anywhere else, it is treated as if there were no token.
+    let dgram = client.process(dgram, now()).dgram().unwrap(); // Initial w/token
+    let moved = "192.0.2.1:443".parse::<SocketAddr>().unwrap();
+    let moved = Datagram::new(moved, dgram.destination(), &dgram[..]);
+    let retry = server.process(Some(moved), now()).dgram();
+    assertions::assert_retry(retry.as_ref().unwrap());
+    assert_eq!(server.connection_table_len(), 0);
+
+    let dgram = server.process(Some(dgram), now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+}
+
+#[test]
+fn retry_token_expired() {
+    let mut server = default_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+
+    // A token that is returned too late is rejected.
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    let later = now() + Duration::from_secs(60);
+    assert!(server.process(dgram, later).dgram().is_none());
+    assert_eq!(server.connection_table_len(), 0);
+}
+
+#[test]
+fn retry_token_shared_key() {
+    let key = RetryTokenKey::generate(now());
+    let mut server1 = default_server();
+    server1.set_retry_required(true);
+    server1.set_retry_token_key(key);
+    let mut server2 = default_server();
+    server2.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server1.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    let dgram = client.process(dgram, now()).dgram().unwrap(); // Initial w/token
+
+    // Another server only takes the token once it has the same key.  Until
+    // then, it doesn't recognize the token and sends its own Retry.
+    let retry = server2.process(Some(dgram.clone()), now()).dgram();
+    assertions::assert_retry(retry.as_ref().unwrap());
+    assert_eq!(server2.connection_table_len(), 0);
+    server2.set_retry_token_key(key);
+    let dgram = server2.process(Some(dgram), now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+}
+
+/// Servers that share a key agree on how old a token is, even if they
+/// started at different times.
+#[test]
+fn retry_token_shared_key_different_start() {
+    let server_at = |start| {
+        let mut server = Server::new(
+            start,
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(7))),
+        );
+        server.set_retry_required(true);
+        server
+    };
+    let key = RetryTokenKey::generate(now());
+    let issued = now() + Duration::from_secs(20);
+    let mut server1 = server_at(issued);
+    server1.set_retry_token_key(key);
+    let mut server2 = server_at(now());
+    server2.set_retry_token_key(key);
+    let mut client = default_client();
+
+    let dgram = client.process(None, issued).dgram(); // Initial
+    let dgram = server1.process(dgram, issued).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    let dgram = client.process(dgram, issued).dgram(); // Initial w/token
+    let used = issued + Duration::from_secs(1);
+    let dgram = server2.process(dgram, used).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    assert_eq!(server2.connection_count(), 1);
+}
+
+/// A token that the server can't open, such as one from a server with
+/// another key, is treated as absent when Retry isn't required.
+#[test]
+fn retry_token_unrecognized() {
+    let mut server1 = default_server();
+    server1.set_retry_required(true);
+    let mut server2 = default_server();
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server1.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    let dgram = server2.process(dgram, now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    assert_eq!(server2.connection_count(), 1);
+}
+
+/// Only accepts a token from the port that it was sent to.
+#[derive(Debug)]
+struct PortChecker;
+
+impl RetryTokenChecker for PortChecker {
+    fn generate(&self, peer: SocketAddr, _now: Instant) -> Vec<u8> {
+        peer.port().to_be_bytes().to_vec()
+    }
+
+    fn check(&self, data: &[u8], peer: SocketAddr, _now: Instant) -> bool {
+        data == &peer.port().to_be_bytes()[..]
+    }
+}
+
+#[test]
+fn retry_checker() {
+    let mut server = default_server();
+    server.set_retry_required(true);
+    server.set_retry_checker(PortChecker);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+
+    // The token is rejected if it comes back from another port.
+    let dgram = client.process(dgram, now()).dgram().unwrap(); // Initial w/token
+    let src = dgram.source();
+    let moved = SocketAddr::new(src.ip(), src.port().wrapping_add(1));
+    let moved = Datagram::new(moved, dgram.destination(), &dgram[..]);
+    assert!(server.process(Some(moved), now()).dgram().is_none());
+    assert_eq!(server.connection_table_len(), 0);
+
+    let dgram = server.process(Some(dgram), now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    assert!(server.connection_table_len() > 0);
+}
+
+/// Start a connection from `src` and check whether the server sends Retry.
+fn sends_retry(server: &mut Server, src: &str, now: Instant) -> bool {
+    let src = src.parse::<SocketAddr>().unwrap();
+    let mut client = default_client();
+    let dgram = client.process(None, now).dgram().unwrap(); // Initial
+    let dgram = Datagram::new(src, dgram.destination(), &dgram[..]);
+    let response = server.process(Some(dgram), now).dgram().unwrap();
+    response[0] & 0b1111_0000 == 0b1111_0000
+}
+
+#[test]
+fn retry_limits() {
+    let mut server = default_server();
+    assert_eq!(server.set_retry_limits(0, 1), Err(Error::InvalidInput));
+    server.set_retry_limits(3, 1).unwrap();
+
+    // One attempt from each network is fine, but not two.
+    assert!(!sends_retry(&mut server, "[2001:db8:1::1]:443", now()));
+    assert!(sends_retry(&mut server, "[2001:db8:1::2]:443", now()));
+    assert!(!server.retry_required());
+    assert!(!sends_retry(&mut server, "[2001:db8:2::1]:443", now()));
+    assert!(!sends_retry(&mut server, "[2001:db8:3::1]:443", now()));
+
+    // That's all three attempts for this second, so everyone gets Retry.
+    assert!(sends_retry(&mut server, "[2001:db8:4::1]:443", now()));
+    assert!(server.retry_required());
+
+    // Once things calm down, connections are accepted again.
+    let later = now() + Duration::from_secs(1);
+    assert!(!sends_retry(&mut server, "[2001:db8:1::2]:443", later));
+    assert!(!server.retry_required());
+}
+
 // attempt a retry with 0-RTT, and have 0-RTT packets sent with the second ClientHello
 #[test]
 fn retry_0rtt() {
@@ -147,6 +395,223 @@ fn retry_0rtt() {
     assert!(client.tls_info().unwrap().resumed());
 }
 
+/// Accepts 0-RTT only if the application part of the ticket matches.
+#[derive(Debug)]
+struct TicketChecker(&'static [u8]);
+
+impl ZeroRttChecker for TicketChecker {
+    fn check(&self, token: &[u8]) -> ZeroRttCheckResult {
+        if token == self.0 {
+            ZeroRttCheckResult::Accept
+        } else {
+            ZeroRttCheckResult::Reject
+        }
+    }
+}
+
+/// Connect to the server and get a resumption token with `extra` in the ticket.
+fn resumption_token(server: &mut Server, extra: &[u8]) -> Vec<u8> {
+    let mut client = default_client();
+    let mut server_conn = connect(&mut client, server);
+    server_conn
+        .borrow_mut()
+        .send_ticket(now(), extra)
+        .expect("ticket should go out");
+    let dgram = server.process(None, now()).dgram();
+    client.process_input(dgram.unwrap(), now()); // Consume ticket, ignore output.
+    let token = client.resumption_token().expect("should get token");
+    // Calling active_connections clears the set of active connections.
+    assert_eq!(server.active_connections().len(), 1);
+    token
+}
+
+/// Resume using `token` and send 0-RTT.  Returns the connected client.
+fn connect_0rtt(server: &mut Server, token: &[u8]) -> Connection {
+    let mut client = default_client();
+    client
+        .set_resumption_token(now(), token)
+        .expect("should set token");
+    let client_stream = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(client_stream, &[1, 2, 3]).unwrap();
+
+    let dgram = client.process(None, now()).dgram(); // Initial w/0-RTT
+    assert!(dgram.is_some());
+    assertions::assert_coalesced_0rtt(dgram.as_ref().unwrap());
+    let dgram = server.process(dgram, now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    client.process_input(dgram.unwrap(), now());
+    assert_eq!(*client.state(), State::Connected);
+    client
+}
+
+/// Find the `HandshakeComplete` event for a server connection.
+fn handshake_record(server_conn: &ActiveConnectionRef) -> HandshakeRecord {
+    let mut records = server_conn.borrow_mut().events().filter_map(|e| {
+        if let ConnectionEvent::HandshakeComplete(record) = e {
+            Some(record)
+        } else {
+            None
+        }
+    });
+    let record = records.next().expect("should have a handshake record");
+    assert!(records.next().is_none());
+    record
+}
+
+#[test]
+fn handshake_complete() {
+    let mut server = default_server();
+    let mut client = default_client();
+    let server_conn = connect(&mut client, &mut server);
+
+    let record = handshake_record(&server_conn);
+    assert_eq!(
+        record.server_name.as_ref().map(String::as_str),
+        Some(test_fixture::DEFAULT_SERVER_NAME)
+    );
+    assert_eq!(
+        record.alpn.as_ref().map(String::as_str),
+        Some(test_fixture::DEFAULT_ALPN[0])
+    );
+    assert_eq!(record.cipher, client.tls_info().unwrap().cipher_suite());
+    assert_eq!(record.peer, test_fixture::loopback());
+    assert!(record.odcid.len() >= 8);
+    assert_eq!(record.duration, Duration::new(0, 0));
+    assert!(!record.resumed);
+    assert!(!record.early_data);
+
+    // The client doesn't get one.
+    assert!(!client
+        .events()
+        .any(|e| matches!(e, ConnectionEvent::HandshakeComplete(..))));
+}
+
+#[test]
+fn handshake_complete_0rtt() {
+    let mut server = default_server();
+    server.enable_zero_rtt(TicketChecker(b"ok"));
+    let token = resumption_token(&mut server, b"ok");
+
+    let mut client = connect_0rtt(&mut server, &token);
+    let dgram = client.process(None, now()).dgram(); // Finished
+    server.process(dgram, now());
+    let record = handshake_record(&connected_server(&mut server));
+    assert!(record.resumed);
+    assert!(record.early_data);
+}
+
+#[test]
+fn zero_rtt_checker_accept() {
+    let mut server = default_server();
+    server.enable_zero_rtt(TicketChecker(b"ok"));
+    let token = resumption_token(&mut server, b"ok");
+
+    let mut client = connect_0rtt(&mut server, &token);
+    assert!(client
+        .events()
+        .any(|e| e == ConnectionEvent::ZeroRttAccepted));
+}
+
+#[test]
+fn zero_rtt_checker_reject() {
+    let mut server = default_server();
+    server.enable_zero_rtt(TicketChecker(b"ok"));
+    let token = resumption_token(&mut server, b"not ok");
+
+    let mut client = connect_0rtt(&mut server, &token);
+    assert!(client
+        .events()
+        .any(|e| e == ConnectionEvent::ZeroRttRejected));
+    assert!(client.tls_info().unwrap().resumed());
+}
+
+#[test]
+fn coalesced_server_flight() {
+    let mut server = default_server();
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram();
+    let dgram = server.process(dgram, now()).dgram();
+    assertions::assert_coalesced_handshake(dgram.as_ref().unwrap());
+}
+
+#[test]
+fn initial_retransmission() {
+    let mut server = default_server();
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial, which is lost.
+    assert!(dgram.is_some());
+    let when = client.process(None, now()).ready_at(now()).unwrap();
+    let dgram = client.process(None, when).dgram(); // Initial again.
+    assert!(dgram.is_some());
+    // The server doesn't drop the retransmission for being too small.
+    let dgram = server.process(dgram, when).dgram();
+    assert!(dgram.is_some());
+}
+
+#[test]
+fn initial_padding_larger() {
+    let mut server = default_server();
+    let mut client = default_client();
+    client
+        .set_initial_padding(1280)
+        .expect("padding can be increased");
+
+    let dgram = client.process(None, now()).dgram();
+    assert_eq!(dgram.as_ref().unwrap().len(), 1280);
+    let dgram = server.process(dgram, now()).dgram();
+    assert!(dgram.is_some());
+}
+
+#[test]
+fn large_client_hello() {
+    let mut server = default_server();
+    let mut client = default_client();
+    // Offer enough protocols that the ClientHello needs two Initial packets.
+    let mut protocols: Vec<String> = (0..60).map(|i| format!("unused-protocol-{}", i)).collect();
+    protocols.extend(test_fixture::DEFAULT_ALPN.iter().map(|p| String::from(*p)));
+    client.set_alpn(&protocols).unwrap();
+
+    let dgram1 = client.process(None, now()).dgram();
+    assert!(dgram1.is_some());
+    let dgram2 = client.process(None, now()).dgram();
+    assert!(dgram2.is_some());
+
+    server.process(dgram1, now());
+    let dgram = server.process(dgram2, now()).dgram(); // ServerHello...
+    assert!(dgram.is_some());
+    // Both packets went to the same connection.
+    assert_eq!(server.connection_count(), 1);
+}
+
+#[test]
+fn stateless_reset() {
+    let key = [3; 32];
+    let mut server = default_server();
+    let cid = [1, 2, 3, 4, 5, 6, 7];
+    let mut packet = vec![0x40];
+    packet.extend_from_slice(&cid);
+    packet.resize(100, 0xaa);
+    let dgram = Datagram::new(test_fixture::loopback(), test_fixture::loopback(), packet);
+
+    // Nothing is sent without a key.
+    assert!(server.process(Some(dgram.clone()), now()).dgram().is_none());
+
+    server.set_stateless_reset_key(&key);
+    let reset = server.process(Some(dgram.clone()), now()).dgram().unwrap();
+    assert!(reset.len() < dgram.len());
+    assert_eq!(reset[0] & 0xc0, 0x40);
+    assert_eq!(
+        &reset[reset.len() - RESET_TOKEN_LEN..],
+        &reset_token(&key, &cid).unwrap()[..]
+    );
+
+    // Small packets don't get a reset.
+    let small = Datagram::new(dgram.source(), dgram.destination(), &dgram[..20]);
+    assert!(server.process(Some(small), now()).dgram().is_none());
+}
+
 #[test]
 fn retry_bad_token() {
     // TODO(mt) - attempt a retry but get a bad token
@@ -187,16 +652,102 @@ fn version_negotiation() {
     }
     assert!(found, "valid version not found");
 
+    // The VN lists the version that the client chose, so it is ignored.
+    client.process_input(vn.clone(), now());
+    assert_eq!(*client.state(), State::WaitInitial);
+
+    // Replace the two versions with one that the client doesn't support.
+    let mut other = vn[..vn.len() - 8].to_vec();
+    other.extend_from_slice(&[0x1a, 0x2a, 0x3a, 0x4a]);
+    let vn = Datagram::new(vn.source(), vn.destination(), other);
     let res = client.process(Some(vn), now());
     assert_eq!(res, Output::None);
     match client.state() {
-        State::Closed(err) => {
-            assert_eq!(*err, ConnectionError::Transport(Error::VersionNegotiation))
+        State::Closed { error, .. } => {
+            assert_eq!(
+                *error,
+                ConnectionError::Transport(Error::VersionNegotiation)
+            )
         }
         _ => panic!("Invalid client state"),
     }
 }
 
+#[test]
+fn vn_not_answered() {
+    let mut server = default_server();
+    let mut client = default_client();
+
+    // Turn a client Initial into something that looks like a VN packet.
+    // The server should never respond to that.
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let mut input = dgram.to_vec();
+    input[1..5].copy_from_slice(&[0; 4]);
+    let forged = Datagram::new(dgram.source(), dgram.destination(), input);
+    assert_eq!(server.process(Some(forged), now()), Output::None);
+}
+
+/// Choose the first protocol, but only for the default server name.
+#[derive(Debug)]
+struct AlpnForName;
+impl AlpnSelector for AlpnForName {
+    fn select(&self, server_name: Option<&str>, offered: &[String]) -> Option<String> {
+        if server_name == Some(test_fixture::DEFAULT_SERVER_NAME) {
+            offered.first().cloned()
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    let mut server = default_server();
+    server.set_alpn_selector(AlpnForName);
+    let mut client = Connection::new_client(
+        test_fixture::DEFAULT_SERVER_NAME,
+        &["other"],
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+        test_fixture::loopback(),
+        test_fixture::loopback(),
+    )
+    .expect("create a client");
+    let server_conn = connect(&mut client, &mut server);
+    let alpn = Some(String::from("other"));
+    assert_eq!(client.tls_info().unwrap().alpn(), alpn.as_ref());
+    assert_eq!(
+        server_conn.borrow().tls_info().unwrap().alpn(),
+        alpn.as_ref()
+    );
+}
+
+#[test]
+fn alpn_selector_reject() {
+    let mut server = default_server();
+    server.set_retry_required(false);
+    server.set_alpn_selector(AlpnForName);
+    let mut client = Connection::new_client(
+        "other.example",
+        test_fixture::DEFAULT_ALPN,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+        test_fixture::loopback(),
+        test_fixture::loopback(),
+    )
+    .expect("create a client");
+
+    let dgram = client.process(None, now()).dgram();
+    let dgram = server.process(dgram, now()).dgram();
+    assert!(dgram.is_some()); // CONNECTION_CLOSE
+    let server_connections = server.active_connections();
+    assert_eq!(server_connections.len(), 1);
+    match server_connections[0].borrow().state() {
+        State::Closing { error, .. } => {
+            assert_eq!(*error, ConnectionError::Transport(Error::CryptoAlert(120)))
+        }
+        s => panic!("unexpected state {:?}", s),
+    }
+}
+
 #[test]
 fn closed() {
     // Let a server connection idle and it should be removed.
@@ -211,3 +762,178 @@ fn closed() {
     let res = server.process(None, now() + Duration::from_secs(60));
     assert_eq!(res, Output::None);
 }
+
+#[test]
+fn weak_connection_ref() {
+    let mut server = default_server();
+    let mut client = default_client();
+    let server_conn = connect(&mut client, &mut server);
+    let weak = server_conn.downgrade();
+    assert_eq!(weak.id(), server_conn.id());
+    assert_eq!(weak.upgrade(), Some(server_conn.clone()));
+
+    let mut client2 = default_client();
+    let server_conn2 = connect(&mut client2, &mut server);
+    assert_ne!(server_conn2.id(), server_conn.id());
+    assert_ne!(server_conn2.downgrade(), weak);
+    drop(server_conn);
+
+    // Once the connection idles out, it can't be reached.
+    let res = server.process(None, now() + Duration::from_secs(60));
+    assert_eq!(res, Output::None);
+    assert!(weak.upgrade().is_none());
+    assert!(server_conn2.downgrade().upgrade().is_none());
+}
+
+#[test]
+fn drain_active_connections() {
+    let mut server = default_server();
+    let mut client1 = default_client();
+    let mut client2 = default_client();
+    let conn1 = connect(&mut client1, &mut server);
+    let conn2 = connect(&mut client2, &mut server);
+
+    // Both clients leave, which the server sees as an event.
+    for client in &mut [client1, client2] {
+        client.close(now(), 0, "bye");
+        let dgram = client.process(None, now()).dgram();
+        server.process(dgram, now());
+    }
+    let mut drained: Vec<_> = server.drain_active_connections().collect();
+    drained.sort_by_key(ActiveConnectionRef::id);
+    let mut expected = vec![conn1, conn2];
+    expected.sort_by_key(ActiveConnectionRef::id);
+    assert_eq!(drained, expected);
+    assert_eq!(server.drain_active_connections().count(), 0);
+    assert!(server.active_connections().is_empty());
+}
+
+#[test]
+fn churn() {
+    // Connections that close in different ways all leave the server.
+    const CLIENTS: usize = 6;
+    let mut server = default_server();
+    let mut conns = Vec::new();
+    for _ in 0..CLIENTS {
+        let mut client = default_client();
+        conns.push(connect(&mut client, &mut server));
+    }
+    assert_eq!(server.connection_count(), CLIENTS);
+    assert!(server.connection_table_len() >= CLIENTS);
+
+    // The server closes every other connection; the rest idle out.
+    for c in conns.iter_mut().step_by(2) {
+        c.borrow_mut().close(now(), 0, "churn");
+        server.add_to_waiting(c.clone());
+    }
+    // Drop the CONNECTION_CLOSE frames; the clients just go quiet.
+    while server.process(None, now()).dgram().is_some() {}
+    assert_eq!(server.connection_count(), CLIENTS);
+
+    let res = server.process(None, now() + Duration::from_secs(60));
+    assert_eq!(res, Output::None);
+    assert_eq!(server.connection_count(), 0);
+    assert_eq!(server.connection_table_len(), 0);
+    assert_eq!(server.timer_count(), 0);
+}
+
+#[test]
+fn server_certificates() {
+    // The handshake in `connect()` only works if the whole server flight fits
+    // in one datagram, which it doesn't with the long certificate.  This only
+    // works if the certificate for the server name is used instead.
+    let mut server = Server::new(
+        now(),
+        test_fixture::LONG_CERT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(7))),
+    );
+    server.set_server_certificates(
+        test_fixture::DEFAULT_SERVER_NAME,
+        test_fixture::DEFAULT_KEYS,
+    );
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+}
+
+#[test]
+fn work_budget() {
+    // Two connections idle out at the same time, but the budget only
+    // allows one connection to be looked at on each call.
+    let mut server = default_server();
+    let mut client1 = default_client();
+    let mut client2 = default_client();
+    connect(&mut client1, &mut server);
+    connect(&mut client2, &mut server);
+    assert_eq!(server.connection_count(), 2);
+
+    let later = now() + Duration::from_secs(60);
+    let res = server.process_with_budget(None, later, 1);
+    assert_eq!(res, Output::Callback(Duration::new(0, 0)));
+    assert_eq!(server.connection_count(), 1);
+
+    let res = server.process_with_budget(None, later, 1);
+    assert_eq!(res, Output::None);
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[test]
+fn worker_connection_ids() {
+    const CID_LEN: usize = 8;
+    let mut server = Server::new(
+        now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        Rc::new(RefCell::new(WorkerConnectionIdManager::new(3, CID_LEN))),
+    );
+    let mut client = default_client();
+
+    // Any worker can take the first packet.
+    let dgram = client.process(None, now()).dgram().unwrap();
+    assert_eq!(WorkerConnectionIdManager::worker(&dgram, CID_LEN), None);
+    let dgram = server.process(Some(dgram), now()).dgram();
+    assert!(dgram.is_some());
+    let dgram = client.process(dgram, now()).dgram();
+    assert!(dgram.is_some());
+    server.process(dgram, now());
+
+    // Once connected, packets go to the worker that made the connection ID.
+    client.authenticated(AuthenticationStatus::Ok, now());
+    let dgram = client.process(None, now()).dgram().unwrap();
+    assert_eq!(WorkerConnectionIdManager::worker(&dgram, CID_LEN), Some(3));
+    server.process(Some(dgram), now());
+    connected_server(&mut server);
+}
+
+#[test]
+fn length_prefix_connection_ids() {
+    let cid_manager = Rc::new(RefCell::new(LengthPrefixConnectionIdManager::new(6)));
+    let mut server = Server::new(
+        now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        cid_manager.clone(),
+    );
+    let mut client1 = default_client();
+    connect(&mut client1, &mut server);
+
+    // Connection IDs get longer, but the first connection still works.
+    cid_manager.borrow_mut().set_len(12);
+    let mut client2 = default_client();
+    connect(&mut client2, &mut server);
+
+    let stream_id = client1.stream_create(StreamType::UniDi).unwrap();
+    client1.stream_send(stream_id, &[1, 2, 3]).unwrap();
+    let dgram = client1.process(None, now()).dgram();
+    assert!(dgram.is_some());
+    server.process(dgram, now());
+    let mut active = server.active_connections();
+    assert_eq!(active.len(), 1);
+    assert!(active[0]
+        .borrow_mut()
+        .events()
+        .any(|e| matches!(e, ConnectionEvent::RecvStreamReadable { .. })));
+}
@@ -9,8 +9,9 @@ use std::convert::TryInto;
 use neqo_common::Decoder;
 use neqo_transport::QUIC_VERSION;
 
-// Do a simple decode of the datagram to verify that it is coalesced.
-pub fn assert_coalesced_0rtt(payload: &[u8]) {
+// Do a simple decode of the datagram to verify that it starts with an Initial
+// packet, then return the type byte of the packet that follows.
+fn type_after_initial(payload: &[u8]) -> u8 {
     assert!(payload.len() >= 1200);
     let mut dec = Decoder::from(payload);
     let initial_type = dec.decode_byte().unwrap(); // Initial
@@ -22,10 +23,21 @@ pub fn assert_coalesced_0rtt(payload: &[u8]) {
     dec.skip_vvec();
     let initial_len = dec.decode_varint().unwrap();
     dec.skip(initial_len.try_into().unwrap());
-    let zrtt_type = dec.decode_byte().unwrap();
+    dec.decode_byte().unwrap()
+}
+
+// Verify that a datagram has an Initial packet followed by 0-RTT.
+pub fn assert_coalesced_0rtt(payload: &[u8]) {
+    let zrtt_type = type_after_initial(payload);
     assert_eq!(zrtt_type & 0b1111_0000, 0b1101_0000);
 }
 
+// Verify that a datagram has an Initial packet followed by Handshake.
+pub fn assert_coalesced_handshake(payload: &[u8]) {
+    let hs_type = type_after_initial(payload);
+    assert_eq!(hs_type & 0b1111_0000, 0b1110_0000);
+}
+
 pub fn assert_retry(payload: &[u8]) {
     assert_eq!(payload[0] & 0b1111_0000, 0b1111_0000);
 }
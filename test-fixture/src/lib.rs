@@ -35,8 +35,8 @@ pub const ANTI_REPLAY_WINDOW: Duration = Duration::from_millis(10);
 
 fn earlier() -> Instant {
     fixture_init();
-    static mut BASE_TIME: OnceResult<Instant> = OnceResult::new();
-    *unsafe { BASE_TIME.call_once(Instant::now) }
+    static BASE_TIME: OnceResult<Instant> = OnceResult::new();
+    *BASE_TIME.call_once(Instant::now)
 }
 
 /// The current time for the test.  Which is in the future,
@@ -102,7 +102,15 @@ pub fn handshake(client: &mut Connection, server: &mut Connection) {
     let mut a = client;
     let mut b = server;
     let mut datagram = None;
-    let is_done = |c: &Connection| matches!(c.state(), State::Connected | State::Closing { .. } | State::Closed(..));
+    let is_done = |c: &Connection| {
+        matches!(
+            c.state(),
+            State::Connected
+                | State::Closing { .. }
+                | State::Draining { .. }
+                | State::Closed { .. }
+        )
+    };
     while !is_done(a) {
         let _ = maybe_autenticate(a);
         let d = a.process(datagram, now());